@@ -10,6 +10,7 @@
 mod predict;
 mod transform;
 mod me;
+mod cdef;
 
 use rav1e::bench::api::*;
 use rav1e::bench::encoder::*;
@@ -65,7 +66,7 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
     for &mode in RAV1E_INTRA_MODES {
       let sbo = SuperBlockOffset { x: sbx, y: sby };
       for p in 1..3 {
-        ts.qc.update(fi.base_q_idx, tx_size, mode.is_intra(), 8, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+        ts.qc.update(fi.base_q_idx, tx_size, mode.is_intra(), 8, fi.dc_delta_q[p], fi.ac_delta_q[p], None);
         for by in 0..8 {
           for bx in 0..8 {
             // For ex, 8x8 tx should be applied to even numbered (bx,by)
@@ -92,6 +93,8 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
               false,
               ac,
               0,
+              None,
+              None,
               RDOType::PixelDistRealRate,
               true
             );
@@ -181,11 +184,11 @@ fn update_cdf_4_sse2(b: &mut Bencher) {
 criterion_group!(intra_prediction, predict::pred_bench,);
 
 criterion_group!(cfl, cfl_rdo);
-criterion_group!(cdef, cdef_frame);
+criterion_group!(cdef, cdef_frame, cdef::cdef_filter_block);
 criterion_group!(write_block, write_b);
 criterion_group!{ name = me;
                   config = Criterion::default().warm_up_time(Duration::new(1,0));
-                  targets = me::get_sad
+                  targets = me::get_sad, me::get_satd, me::diamond_me_search
 }
 
 criterion_group!(ec, ec_bench);
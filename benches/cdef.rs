@@ -0,0 +1,60 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use criterion::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaChaRng;
+use rav1e::bench::cdef;
+use rav1e::Pixel;
+
+fn run_filter_block_bench<T: Pixel>(
+  b: &mut Bencher, &(ysize, bit_depth): &(isize, usize),
+) {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let coeff_shift = bit_depth as i32 - 8;
+  // The input starts at the upper left corner of a 2-pixel padding
+  let istride = 16;
+  let input: Vec<u16> =
+    (0..16 * 12).map(|_| ra.gen_range(0, 1 << bit_depth)).collect();
+  let mut dst = vec![T::cast_from(0u8); 8 * 8];
+
+  b.iter(|| unsafe {
+    cdef::cdef_filter_block(
+      dst.as_mut_ptr(),
+      8,
+      input.as_ptr(),
+      istride,
+      4 << coeff_shift,
+      2 << coeff_shift,
+      2,
+      5 + coeff_shift,
+      8,
+      ysize,
+      coeff_shift,
+    );
+  })
+}
+
+fn bench_filter_block(b: &mut Bencher, &(ysize, bit_depth): &(isize, usize)) {
+  if bit_depth <= 8 {
+    run_filter_block_bench::<u8>(b, &(ysize, bit_depth))
+  } else {
+    run_filter_block_bench::<u16>(b, &(ysize, bit_depth))
+  }
+}
+
+pub fn cdef_filter_block(c: &mut Criterion) {
+  let blocks = vec![(4, 8), (8, 8), (4, 10), (8, 10), (8, 12)];
+
+  c.bench_function_over_inputs(
+    "cdef_filter_block",
+    bench_filter_block,
+    blocks,
+  );
+}
@@ -13,6 +13,10 @@ use rav1e::bench::partition::BlockSize::*;
 use rav1e::bench::frame::*;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaChaRng;
+use rav1e::bench::api::*;
+use rav1e::bench::context::MvRates;
+use rav1e::bench::encoder::*;
+use rav1e::bench::mc::MotionVector;
 use rav1e::bench::me;
 use rav1e::Pixel;
 
@@ -112,3 +116,121 @@ pub fn get_sad(c: &mut Criterion) {
 
   c.bench_function_over_inputs("get_sad", bench_get_sad, blocks);
 }
+
+fn run_satd_bench<T: Pixel>(b: &mut Bencher, &(bs, _): &(BlockSize, usize)) {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let bsw = bs.width();
+  let bsh = bs.height();
+  let w = 640;
+  let h = 480;
+  let input_plane = new_plane::<T>(&mut ra, w, h);
+  let rec_plane = new_plane::<T>(&mut ra, w, h);
+
+  let plane_org = input_plane.as_region();
+  let plane_ref = rec_plane.as_region();
+
+  b.iter(|| {
+    let _ = black_box(me::get_satd(&plane_org, &plane_ref, bsw, bsh));
+  })
+}
+
+fn bench_get_satd(b: &mut Bencher, &(bs, bit_depth): &(BlockSize, usize)) {
+  if bit_depth <= 8 {
+    run_satd_bench::<u8>(b, &(bs, bit_depth))
+  }
+  else {
+    run_satd_bench::<u16>(b, &(bs, bit_depth))
+  }
+}
+
+pub fn get_satd(c: &mut Criterion) {
+  let blocks = vec![
+    (BLOCK_4X4, 8),
+    (BLOCK_8X8, 8),
+    (BLOCK_16X16, 8),
+    (BLOCK_32X32, 8),
+    (BLOCK_64X64, 8),
+    (BLOCK_16X8, 8),
+    (BLOCK_8X32, 8),
+
+    (BLOCK_4X4, 10),
+    (BLOCK_8X8, 10),
+    (BLOCK_16X16, 10),
+    (BLOCK_32X32, 10),
+    (BLOCK_64X64, 10),
+    (BLOCK_16X8, 10),
+    (BLOCK_8X32, 10)
+  ];
+
+  c.bench_function_over_inputs("get_satd", bench_get_satd, blocks);
+}
+
+fn run_diamond_me_bench<T: Pixel>(
+  b: &mut Bencher, &(bs, bit_depth): &(BlockSize, usize)
+) {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let w = 640;
+  let h = 480;
+  let config = EncoderConfig {
+    width: w,
+    height: h,
+    bit_depth,
+    speed_settings: SpeedSettings::from_preset(10),
+    ..Default::default()
+  };
+  let sequence = Sequence::new(&config);
+  let fi = FrameInvariants::<T>::new(config, sequence);
+  let input_plane = new_plane::<T>(&mut ra, w, h);
+  let rec_plane = new_plane::<T>(&mut ra, w, h);
+  let mv_rates = MvRates::default();
+
+  let po = PlaneOffset { x: 256, y: 192 };
+  // Full pixel vectors, in 1/8 pel units
+  let predictors = vec![
+    MotionVector::default(),
+    MotionVector { row: 16, col: -24 },
+    MotionVector { row: -40, col: 8 }
+  ];
+  let range = 64 * 8;
+
+  b.iter(|| {
+    let mut best_mv = MotionVector::default();
+    let mut lowest_cost = std::u64::MAX;
+    me::diamond_me_search(
+      &fi, po, &input_plane, &rec_plane, &predictors, bit_depth,
+      [MotionVector::default(); 2], 128, &mv_rates,
+      -range, range, -range, range, bs.width(), bs.height(),
+      &mut best_mv, &mut lowest_cost, false, None, RefType::LAST_FRAME
+    );
+    black_box((best_mv, lowest_cost))
+  })
+}
+
+fn bench_diamond_me_search(
+  b: &mut Bencher, &(bs, bit_depth): &(BlockSize, usize)
+) {
+  if bit_depth <= 8 {
+    run_diamond_me_bench::<u8>(b, &(bs, bit_depth))
+  }
+  else {
+    run_diamond_me_bench::<u16>(b, &(bs, bit_depth))
+  }
+}
+
+pub fn diamond_me_search(c: &mut Criterion) {
+  let blocks = vec![
+    (BLOCK_8X8, 8),
+    (BLOCK_16X16, 8),
+    (BLOCK_32X32, 8),
+    (BLOCK_64X64, 8),
+
+    (BLOCK_8X8, 10),
+    (BLOCK_16X16, 10),
+    (BLOCK_32X32, 10),
+    (BLOCK_64X64, 10)
+  ];
+
+  c.bench_function_over_inputs(
+    "diamond_me_search", bench_diamond_me_search, blocks
+  );
+}
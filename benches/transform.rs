@@ -10,7 +10,10 @@
 use criterion::*;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaChaRng;
+use rav1e::bench::frame::Plane;
 use rav1e::bench::transform;
+use rav1e::bench::transform::{TxSize, TxType};
+use rav1e::bench::util::*;
 
 fn bench_idct4(b: &mut Bencher, bit_depth: &usize) {
   let mut ra = ChaChaRng::from_seed([0; 32]);
@@ -48,4 +51,103 @@ pub fn av1_idct8(c: &mut Criterion) {
   c.bench_functions("av1_idct8_8", funcs, 8);
 }
 
-criterion_group!(transform, av1_idct4, av1_idct8);
+const TXFM_SIZES: [TxSize; 4] = [
+  TxSize::TX_4X4,
+  TxSize::TX_8X8,
+  TxSize::TX_16X16,
+  TxSize::TX_32X32,
+];
+
+fn bench_fwd_txfm(b: &mut Bencher, tx_size: TxSize, bit_depth: usize) {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let max = (1i16 << bit_depth) - 1;
+  let input: Vec<i16> =
+    (0..tx_size.area()).map(|_| ra.gen_range(-max, max + 1)).collect();
+  let mut output = vec![0i32; tx_size.area()];
+
+  b.iter(|| {
+    transform::forward_transform(
+      &input,
+      &mut output,
+      tx_size.width(),
+      tx_size,
+      TxType::DCT_DCT,
+      bit_depth,
+    );
+  });
+}
+
+pub fn fwd_txfm(c: &mut Criterion) {
+  for &tx_size in &TXFM_SIZES {
+    for &bit_depth in &[8, 10] {
+      let n = format!("fwd_txfm({:?}, {})", tx_size, bit_depth);
+      c.bench_function(&n, move |b| bench_fwd_txfm(b, tx_size, bit_depth));
+    }
+  }
+}
+
+fn bench_inv_txfm_add<T: Pixel>(
+  b: &mut Bencher, tx_size: TxSize, bit_depth: usize,
+) {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  // 64-point transforms only carry 32x32 coefficients
+  let coeffs = tx_size.width().min(32) * tx_size.height().min(32);
+  let input: Vec<i32> = (0..coeffs).map(|_| ra.gen_range(-256, 256)).collect();
+  let mut plane =
+    Plane::<T>::wrap(vec![T::cast_from(0u8); tx_size.area()], tx_size.width());
+
+  b.iter(|| {
+    transform::inverse_transform_add(
+      &input,
+      &mut plane.as_region_mut(),
+      tx_size,
+      TxType::DCT_DCT,
+      bit_depth,
+    );
+  });
+}
+
+pub fn inv_txfm_add(c: &mut Criterion) {
+  for &tx_size in &TXFM_SIZES {
+    let n = format!("inv_txfm_add({:?}, 8)", tx_size);
+    c.bench_function(&n, move |b| bench_inv_txfm_add::<u8>(b, tx_size, 8));
+    let n = format!("inv_txfm_add({:?}, 10)", tx_size);
+    c.bench_function(&n, move |b| bench_inv_txfm_add::<u16>(b, tx_size, 10));
+  }
+}
+
+fn bench_inv_txfm_add_rs(b: &mut Bencher, tx_size: TxSize) {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let coeffs = tx_size.width().min(32) * tx_size.height().min(32);
+  let input: Vec<i32> = (0..coeffs).map(|_| ra.gen_range(-256, 256)).collect();
+  let mut plane =
+    Plane::<u16>::wrap(vec![0; tx_size.area()], tx_size.width());
+
+  b.iter(|| {
+    transform::inverse_transform_add_rs(
+      &input,
+      &mut plane.as_region_mut(),
+      tx_size,
+      TxType::DCT_DCT,
+      10,
+    );
+  });
+}
+
+/// The scalar inverse transforms, to compare with the SIMD ones that
+/// `inv_txfm_add` runs at 10 bits
+pub fn inv_txfm_add_rs(c: &mut Criterion) {
+  for &tx_size in &TXFM_SIZES {
+    let n = format!("inv_txfm_add_rs({:?}, 10)", tx_size);
+    c.bench_function(&n, move |b| bench_inv_txfm_add_rs(b, tx_size));
+  }
+}
+
+criterion_group!(
+  transform,
+  av1_idct4,
+  av1_idct8,
+  fwd_txfm,
+  inv_txfm_add,
+  inv_txfm_add_rs
+);
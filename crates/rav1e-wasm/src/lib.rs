@@ -0,0 +1,142 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! JavaScript bindings to the encoder, built with wasm-bindgen
+//!
+//! ```js
+//! const encoder = new Encoder(width, height, 10, 100);
+//! encoder.send_frame(i420);
+//! let packet;
+//! while ((packet = encoder.receive_packet()) !== undefined) {
+//!   output.push(packet.data);
+//! }
+//! ```
+//!
+//! Only 8-bit 4:2:0 input is supported, passed as the Y, U and V planes
+//! packed one after the other without padding.
+
+use rav1e::prelude::*;
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+
+/// An 8-bit 4:2:0 encoder
+#[wasm_bindgen]
+pub struct Encoder {
+  ctx: Context<u8>,
+  width: usize,
+  height: usize,
+}
+
+#[wasm_bindgen]
+impl Encoder {
+  /// `speed` is the speed preset, from 0 to 10, and `quantizer` the base
+  /// quantizer, from 0 to 255
+  #[wasm_bindgen(constructor)]
+  pub fn new(
+    width: u32, height: u32, speed: u32, quantizer: u32,
+  ) -> Result<Encoder, JsValue> {
+    let mut enc = EncoderConfig::with_speed_preset(speed as usize);
+    enc.width = width as usize;
+    enc.height = height as usize;
+    enc.quantizer = quantizer as usize;
+    let cfg = Config { enc, threads: 1 };
+    let ctx = cfg.new_context().map_err(|e| JsValue::from(e.to_string()))?;
+
+    Ok(Encoder { ctx, width: width as usize, height: height as usize })
+  }
+
+  /// Queue a frame, throws if `data` is not exactly one I420 frame
+  pub fn send_frame(&mut self, data: &[u8]) -> Result<(), JsValue> {
+    let chroma_width = (self.width + 1) / 2;
+    let luma_size = self.width * self.height;
+    let chroma_size = chroma_width * ((self.height + 1) / 2);
+    if data.len() != luma_size + 2 * chroma_size {
+      return Err(JsValue::from(format!(
+        "Expected {} bytes of I420 data, got {}",
+        luma_size + 2 * chroma_size,
+        data.len()
+      )));
+    }
+    let (y, chroma) = data.split_at(luma_size);
+    let (u, v) = chroma.split_at(chroma_size);
+
+    let mut frame = self.ctx.new_frame();
+    {
+      // Frames fresh from the context are not shared yet
+      let f = Arc::get_mut(&mut frame).unwrap();
+      f.planes[0].copy_from_raw_u8(y, self.width, 1);
+      f.planes[1].copy_from_raw_u8(u, chroma_width, 1);
+      f.planes[2].copy_from_raw_u8(v, chroma_width, 1);
+    }
+
+    self.ctx.send_frame(frame).map_err(status_error)
+  }
+
+  /// Signal the end of the input, for the remaining frames to be encoded
+  pub fn flush(&mut self) {
+    self.ctx.flush();
+  }
+
+  /// The next packet, `undefined` if more frames must be sent first or once
+  /// the encode is over
+  pub fn receive_packet(&mut self) -> Result<Option<Packet>, JsValue> {
+    loop {
+      match self.ctx.receive_packet() {
+        Ok(pkt) => {
+          return Ok(Some(Packet {
+            data: pkt.data,
+            input_frameno: pkt.input_frameno as u32,
+            is_keyframe: pkt.frame_type == FrameType::KEY,
+          }))
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(EncoderStatus::NeedMoreData) | Err(EncoderStatus::LimitReached) => {
+          return Ok(None)
+        }
+        Err(e) => return Err(status_error(e)),
+      }
+    }
+  }
+
+  /// The AV1 codec configuration record, for muxing into MP4 or WebM
+  pub fn container_sequence_header(&mut self) -> Vec<u8> {
+    self.ctx.container_sequence_header()
+  }
+}
+
+fn status_error(status: EncoderStatus) -> JsValue {
+  JsValue::from(format!("{:?}", status))
+}
+
+/// A temporal unit of encoded data
+#[wasm_bindgen]
+pub struct Packet {
+  data: Vec<u8>,
+  input_frameno: u32,
+  is_keyframe: bool,
+}
+
+#[wasm_bindgen]
+impl Packet {
+  #[wasm_bindgen(getter)]
+  pub fn data(&self) -> Vec<u8> {
+    self.data.clone()
+  }
+
+  /// The number of the input frame shown by this packet
+  #[wasm_bindgen(getter)]
+  pub fn input_frameno(&self) -> u32 {
+    self.input_frameno
+  }
+
+  #[wasm_bindgen(getter)]
+  pub fn is_keyframe(&self) -> bool {
+    self.is_keyframe
+  }
+}
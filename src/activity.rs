@@ -0,0 +1,113 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::context::BlockOffset;
+use crate::frame::Plane;
+use crate::rdo::variance_wxh;
+use crate::tiling::Area;
+use crate::util::Pixel;
+
+/// Variance added to each block before comparing it to the others, for
+/// 8-bit content, so that flat blocks do not get unbounded weights
+const ACTIVITY_VARIANCE_OFFSET: f64 = 64.0;
+
+/// Exponent of the variance ratio scaling lambda, as in x264's AQ
+const ACTIVITY_EXPONENT: f64 = 1.0 / 3.0;
+
+/// Bounds of the lambda scale of a block
+const ACTIVITY_SCALE_MIN: f64 = 0.5;
+const ACTIVITY_SCALE_MAX: f64 = 2.0;
+
+/// Distortion weight of each 8x8 luma block of the source, applied in the
+/// RDO instead of scaling lambda per block. Textured blocks mask coding
+/// artifacts, so their lambda is raised relative to the flat ones; the
+/// scales have a geometric mean of 1 over the frame, to keep its rate.
+#[derive(Clone, Debug, Default)]
+pub struct ActivityMask {
+  cols: usize,
+  rows: usize,
+  weights: Vec<f32>,
+}
+
+impl ActivityMask {
+  pub fn from_plane<T: Pixel>(
+    plane: &Plane<T>, width: usize, height: usize, bit_depth: usize
+  ) -> Self {
+    let cols = (width + 7) / 8;
+    let rows = (height + 7) / 8;
+    let offset =
+      ACTIVITY_VARIANCE_OFFSET * (1 << (2 * (bit_depth - 8))) as f64;
+    let mut log_variances = Vec::with_capacity(cols * rows);
+    for by in 0..rows {
+      for bx in 0..cols {
+        // Pixels past the frame edge are read from the padding
+        let block = plane.region(Area::StartingAt {
+          x: (bx * 8) as isize,
+          y: (by * 8) as isize
+        });
+        let variance = variance_wxh(&block, 8, 8);
+        log_variances.push((variance.max(0.0) + offset).ln());
+      }
+    }
+    let log_mean =
+      log_variances.iter().sum::<f64>() / log_variances.len().max(1) as f64;
+    let weights = log_variances
+      .iter()
+      .map(|&log_variance| {
+        let scale = ((log_variance - log_mean) * ACTIVITY_EXPONENT).exp();
+        (1.0 / scale.max(ACTIVITY_SCALE_MIN).min(ACTIVITY_SCALE_MAX)) as f32
+      })
+      .collect();
+    ActivityMask { cols, rows, weights }
+  }
+
+  /// Mean distortion weight over the 8x8 blocks covered by a block of
+  /// `w`x`h` luma pixels at `bo`. 1 when the mask is not in use.
+  pub fn weight(&self, bo: BlockOffset, w: usize, h: usize) -> f64 {
+    if self.weights.is_empty() {
+      return 1.0;
+    }
+    let x0 = (bo.x >> 1).min(self.cols - 1);
+    let y0 = (bo.y >> 1).min(self.rows - 1);
+    let x1 = (x0 + (w + 7) / 8).min(self.cols);
+    let y1 = (y0 + (h + 7) / 8).min(self.rows);
+    let mut sum = 0.0;
+    for y in y0..y1 {
+      for x in x0..x1 {
+        sum += self.weights[y * self.cols + x] as f64;
+      }
+    }
+    sum / ((x1 - x0) * (y1 - y0)) as f64
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn flat_blocks_weigh_more() {
+    let mut plane = Plane::new(16, 8, 0, 0, 0, 0);
+    for y in 0..8 {
+      for x in 0..16 {
+        let v = if x >= 8 && (x + y) % 2 == 0 { 200 } else { 50 };
+        plane.data[y * plane.cfg.stride + x] = v as u8;
+      }
+    }
+    let mask = ActivityMask::from_plane(&plane, 16, 8, 8);
+    let flat = mask.weight(BlockOffset { x: 0, y: 0 }, 8, 8);
+    let textured = mask.weight(BlockOffset { x: 2, y: 0 }, 8, 8);
+    assert!(flat > 1.0 && textured < 1.0);
+    // Both blocks scale lambda by inverse amounts
+    assert!((flat * textured - 1.0).abs() < 1e-3);
+
+    let unused = ActivityMask::default();
+    assert_eq!(unused.weight(BlockOffset { x: 0, y: 0 }, 8, 8), 1.0);
+  }
+}
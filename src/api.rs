@@ -9,31 +9,49 @@
 
 use arg_enum_proc_macro::ArgEnum;
 use bitstream_io::*;
+use log::{debug, info};
 use num_derive::*;
 use serde_derive::{Serialize, Deserialize};
 
+use crate::block_log::BlockDecision;
+use crate::denoise::Denoiser;
+use crate::dist::{Distortion, Weighted};
 use crate::encoder::*;
-use crate::frame::Frame;
-use crate::metrics::calculate_frame_psnr;
+use crate::film_grain::{FilmGrainParams, FilmGrainTable};
+use crate::frame::{Frame, PlaneConfig};
+use crate::header::ObuType;
+use crate::levels::select_level;
+use crate::lrf::SUPERRES_NUM;
+use crate::metrics::{
+  calculate_frame_ms_ssim, calculate_frame_psnr, calculate_frame_ssim
+};
 use crate::partition::*;
+use crate::quantize::QM_LEVEL_FLAT;
 use crate::rate::RCState;
+use crate::rate::DQP_STEP;
 use crate::rate::FRAME_NSUBTYPES;
 use crate::rate::FRAME_SUBTYPE_I;
 use crate::rate::FRAME_SUBTYPE_P;
 use crate::rate::FRAME_SUBTYPE_SEF;
-use crate::scenechange::SceneChangeDetector;
-use crate::util::Pixel;
+use crate::scenechange::{SceneChangeDetector, DEFAULT_SENSITIVITY};
+use crate::screen_content::is_screen_content;
+use crate::threading::{ThreadPool, ThreadPoolBuilder};
+use crate::timing::{self, StageTimes};
+use crate::util::{CastFromPrimitive, Fixed, Pixel};
 
 use std::{cmp, fmt, io};
 use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::collections::BTreeSet;
+use std::collections::VecDeque;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 const LOOKAHEAD_FRAMES: u64 = 10;
 
 // TODO: use the num crate?
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub struct Rational {
   pub num: u64,
@@ -47,6 +65,7 @@ impl Rational {
 }
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub struct Point {
   pub x: u16,
@@ -55,7 +74,12 @@ pub struct Point {
 
 
 /// Encoder Settings impacting the bitstream produced
+///
+/// When deserialized, e.g. from a configuration file, missing settings take
+/// the values of `EncoderConfig::default()`.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(default))]
 pub struct EncoderConfig {
   // output size
   pub width: usize,
@@ -65,7 +89,11 @@ pub struct EncoderConfig {
   pub bit_depth: usize,
   pub chroma_sampling: ChromaSampling,
   pub chroma_sample_position: ChromaSamplePosition,
+  /// Signaled in the sequence header color config; Y4M input is limited
+  /// range unless its header says otherwise
   pub pixel_range: PixelRange,
+  /// Color primaries, transfer characteristics and matrix coefficients for
+  /// the sequence header color config; `None` leaves them unspecified
   pub color_description: Option<ColorDescription>,
   pub mastering_display: Option<MasteringDisplay>,
   pub content_light: Option<ContentLight>,
@@ -76,15 +104,150 @@ pub struct EncoderConfig {
   pub min_key_frame_interval: u64,
   /// The *maximum* interval between two keyframes
   pub max_key_frame_interval: u64,
+  /// Sensitivity of the scene change detection, which places key frames at
+  /// the cuts between the minimum and the maximum interval, from 1 to 100.
+  /// 0 disables it, so that key frames are only placed at the maximum
+  /// interval.
+  pub scenecut_sensitivity: u8,
+  /// Code the frames at scene changes as intra-only frames, which keep the
+  /// references and the frame group structure but give no random access
+  /// point, instead of key frames. Key frames are then only placed at the
+  /// maximum interval, and cuts are detected within the minimum interval
+  /// too.
+  pub intra_only_scene_changes: bool,
+  /// Refresh the frame gradually with a wave of intra coded superblocks
+  /// instead of key frames, which are then only placed at the start and
+  /// where forced. Implies `low_latency`.
+  pub intra_refresh: Option<IntraRefresh>,
+  /// Code every frame as a key frame, e.g. for the intermediates of video
+  /// editing, which seek to any frame. Implies `zero_delay`, and skips the
+  /// scene change detection and the motion search. In bitrate mode, every
+  /// frame targets the same size and the quantizer only drifts slowly from
+  /// frame to frame. See `EncoderConfig::all_intra_with_speed_preset()`.
+  pub all_intra: bool,
   /// The number of temporal units over which to distribute the reservoir
   ///  usage.
   pub reservoir_frame_delay: Option<i32>,
   pub low_latency: bool,
+  /// Return the packet of each frame as soon as the frame is sent, without
+  /// any lookahead. Implies `low_latency`.
+  pub zero_delay: bool,
   pub quantizer: usize,
-  /// The minimum allowed base quantizer to use in bitrate mode.
+  /// The minimum quantizer index of any block, which is also the minimum
+  /// base quantizer in bitrate mode.
   pub min_quantizer: u8,
+  /// The maximum quantizer index of any block, whatever the rate control,
+  /// the quantizer offsets of the frame types and the adaptive quantization
+  /// pick. It sets a quality floor, e.g. for archival encodes, without the
+  /// cost of lossless coding.
+  pub max_quantizer: u8,
   pub bitrate: i32,
+  /// Maximum number of times a key frame overshooting its target size is
+  /// re-encoded at a higher quantizer in bitrate mode. 0 disables it.
+  pub keyframe_max_reencodes: u8,
+  /// How much larger than its target, in percent, a key frame must be to
+  /// be re-encoded
+  pub keyframe_overshoot_pct: u32,
+  /// Quantizer of each type of frame relative to the base quantizer, which
+  /// shapes the quality across the frame groups
+  pub quantizer_offsets: QuantizerOffsets,
+  /// Quantizer matrix levels of the Y, U and V planes, from 0 to 15, which
+  /// weigh the coefficients by their frequency. Only the flat level 15 is
+  /// available for now. `None` codes without quantizer matrices.
+  pub qm_levels: Option<[u8; 3]>,
+  /// Signal timing info and a decoder model in the sequence header, with
+  /// buffer delays derived from the reservoir. Only used in bitrate mode.
+  pub decoder_model_info: bool,
   pub tune: Tune,
+  /// Strength of the psychovisual rate-distortion term of
+  /// `Tune::Psychovisual`, which favors the blocks keeping the texture energy
+  /// of the source, in 1/8 units. 0 disables it.
+  pub psy_rd_strength: u8,
+  /// Weight of the chroma planes in the distortion of the rate-distortion
+  /// decisions relative to the luma plane, in 1/8 units. 8 weighs all
+  /// planes equally.
+  pub chroma_distortion_weight: u8,
+  /// Include the weighted chroma SAD in the cost of the sub-pixel motion
+  /// vector search, which otherwise looks at luma only
+  pub chroma_me: bool,
+  /// Distortion metric of the motion vector search
+  pub me_distortion: Distortion,
+  /// Distortion metric of the rate-distortion decisions, where `tune` does
+  /// not impose its own. Anything but `Distortion::Sse` disables
+  /// `SpeedSettings::tx_domain_distortion`.
+  pub rdo_distortion: Distortion,
+  /// Weight of the motion vector search distortion against the rate of the
+  /// motion vectors, in 1/8 units. 8 leaves the metric unscaled.
+  pub me_distortion_weight: u8,
+  /// Weight of the rate-distortion decisions distortion against the rate,
+  /// in 1/8 units. Anything but 8 disables
+  /// `SpeedSettings::tx_domain_distortion`.
+  pub rdo_distortion_weight: u8,
+  /// Record the coding decisions of every block in
+  /// `FrameEncodeStats::blocks`, e.g. for a `BlockDecisionLog`
+  pub log_block_decisions: bool,
+  /// Return the prediction of every shown frame in `Packet::prediction`,
+  /// e.g. to debug the prediction and filters against a decoder
+  pub output_prediction: bool,
+  /// Measure the time spent in each stage of the encoder, reported in
+  /// `FrameEncodeStats::stage_times`
+  pub time_stages: bool,
+  /// Strength of the pre-encode denoiser, in 8-bit pixel units. The removed
+  /// noise is signaled as film grain parameters and re-synthesized by the
+  /// decoder. 0 disables denoising.
+  pub denoise_strength: u8,
+  /// Film grain to signal for the frames instead of the one the denoiser
+  /// removes, e.g. read from an aomenc film grain table file. The frames
+  /// are looked up by their presentation time.
+  pub film_grain_table: Option<FilmGrainTable>,
+  /// Allow key frame blocks to be copied from already coded areas of the
+  /// same frame (Intra Block Copy). Mostly useful for screen content.
+  pub enable_intrabc: bool,
+  /// Enable the screen content coding tools (palettes and integer motion
+  /// vectors) on the key frames whose segment looks like screen content,
+  /// judged over the frames of the lookahead. `Tune::Screen` always enables
+  /// them, and IntraBC as well.
+  pub detect_screen_content: bool,
+  /// Segment frames by block variance and lower the quantizer of the flat
+  /// segments, where coding artifacts are the most visible
+  pub variance_aq: bool,
+  /// Apply variance AQ and region of interest maps with a quantizer delta
+  /// per superblock instead of segmentation
+  pub aq_delta_q: bool,
+  /// Scale lambda per block by the variance of the source, so that the flat
+  /// areas, where artifacts are the most visible, take bits from the
+  /// textured ones. Stacks with variance AQ.
+  pub activity_masking: bool,
+  /// Code inter frames in error resilient mode, so that they do not inherit
+  /// entropy coding state nor motion vectors from their references
+  pub error_resilient: bool,
+  /// Keep the motion vectors of each tile from predicting from the area of
+  /// the other tiles in the references, so that tiles transported apart
+  /// decode independently of each other. Along with `error_resilient`, no
+  /// state is then shared between tiles.
+  pub tile_constrained_mvs: bool,
+  /// Reference structure of low latency encodes
+  pub reference_pattern: ReferencePattern,
+  /// Number of temporal layers of low latency encodes, up to 4. Frames
+  /// only predict from their own and lower layers, and the sequence header
+  /// has an operating point for each set of lower layers, so that
+  /// `filter_temporal_layers` can drop the upper ones, e.g. to lower the
+  /// frame rate of a stream. 1 disables the layers, which replace the
+  /// `reference_pattern` and the references requested per frame.
+  pub temporal_layers: usize,
+  /// Code key frames at a reduced width, upscaled back by the decoder
+  pub superres: SuperResMode,
+  /// Width the decoded frames should be displayed at, to signal a
+  /// non-square sample aspect ratio. `None` for the frame width.
+  pub render_width: Option<usize>,
+  /// Height the decoded frames should be displayed at. `None` for the frame
+  /// height.
+  pub render_height: Option<usize>,
+  /// AV1 level and tier to conform to, checked by `Config::new_context`
+  pub level: Level,
+  /// Encode only the first frame, as a key frame with a reduced still
+  /// picture header, e.g. for AVIF
+  pub still_picture: bool,
   /// log2(tile columns). If tiles is also specified, this acts
   /// as a minimum.
   pub tile_cols_log2: usize,
@@ -98,6 +261,13 @@ pub struct EncoderConfig {
   /// `None` for one-pass encode. `Some(1)` or `Some(2)` for two-pass encoding.
   pub pass: Option<u8>,
   pub show_psnr: bool,
+  /// Compute the SSIM and MS-SSIM of each shown frame, reported in its
+  /// `Packet`
+  pub show_ssim: bool,
+  /// Input frames whose samples all lie within this distance of the
+  /// previous input frame are coded as skip-only frames copying it.
+  /// `Some(0)` catches exact repeats only; `None` disables the detection.
+  pub duplicate_frame_threshold: Option<u16>,
   pub stats_file: Option<PathBuf>,
   pub train_rdo: bool,
 }
@@ -115,6 +285,18 @@ impl Default for EncoderConfig {
 
 impl EncoderConfig {
 
+  /// The settings of `with_speed_preset()` for an all-intra encode, with the
+  /// speed settings of [`all_intra_preset()`].
+  ///
+  /// [`all_intra_preset()`]: struct.SpeedSettings.html#method.all_intra_preset
+  pub fn all_intra_with_speed_preset(speed: usize) -> Self {
+    EncoderConfig {
+      all_intra: true,
+      speed_settings: SpeedSettings::all_intra_preset(speed),
+      ..Self::with_speed_preset(speed)
+    }
+  }
+
   /// This is a preset which provides default settings according to a speed value in the specific range 0-10.
   /// For each speed value it is having different preset. See [`from_preset()`].
   /// If the input value is greater than 10, it will result in the same settings of 10.
@@ -136,40 +318,267 @@ impl EncoderConfig {
       time_base: Rational { num: 1, den: 30 },
       min_key_frame_interval: 12,
       max_key_frame_interval: 240,
+      scenecut_sensitivity: DEFAULT_SENSITIVITY,
+      intra_only_scene_changes: false,
+      intra_refresh: None,
+      all_intra: false,
       min_quantizer: 0,
+      max_quantizer: 255,
       reservoir_frame_delay: None,
       low_latency: false,
+      zero_delay: false,
       quantizer: 100,
       bitrate: 0,
+      keyframe_max_reencodes: 0,
+      keyframe_overshoot_pct: 100,
+      quantizer_offsets: QuantizerOffsets::default(),
+      qm_levels: None,
+      decoder_model_info: false,
       tune: Tune::default(),
+      psy_rd_strength: 0,
+      chroma_distortion_weight: 8,
+      chroma_me: false,
+      me_distortion: Distortion::Sad,
+      rdo_distortion: Distortion::Sse,
+      me_distortion_weight: 8,
+      rdo_distortion_weight: 8,
+      log_block_decisions: false,
+      output_prediction: false,
+      time_stages: false,
+      denoise_strength: 0,
+      film_grain_table: None,
+      enable_intrabc: false,
+      detect_screen_content: false,
+      variance_aq: false,
+      aq_delta_q: false,
+      activity_masking: false,
+      error_resilient: false,
+      tile_constrained_mvs: false,
+      reference_pattern: ReferencePattern::default(),
+      temporal_layers: 1,
+      superres: SuperResMode::None,
+      render_width: None,
+      render_height: None,
+      level: Level::Unconstrained,
+      still_picture: false,
       tile_cols_log2: 0,
       tile_rows_log2: 0,
       tiles: 0,
       speed_settings: SpeedSettings::from_preset(speed),
       pass: None,
       show_psnr: false,
+      show_ssim: false,
+      duplicate_frame_threshold: None,
       stats_file: None,
       train_rdo: false
     }
   }
+
+  /// Size the decoded frames should be displayed at.
+  pub fn render_size(&self) -> (usize, usize) {
+    (
+      self.render_width.unwrap_or(self.width),
+      self.render_height.unwrap_or(self.height)
+    )
+  }
+
+  /// Distortion metric of the motion vector search, with its weight.
+  pub fn me_metric(&self) -> Weighted<Distortion> {
+    Weighted {
+      metric: self.me_distortion,
+      weight: self.me_distortion_weight as u32
+    }
+  }
+
+  /// Distortion metric of the rate-distortion decisions, with its weight.
+  pub fn rdo_metric(&self) -> Weighted<Distortion> {
+    Weighted {
+      metric: self.rdo_distortion,
+      weight: self.rdo_distortion_weight as u32
+    }
+  }
+
+  /// Check that the settings can be encoded, independently of the level.
+  ///
+  /// `Config::new_context` runs these checks, returning the first failure.
+  pub fn validate(&self) -> Result<(), InvalidConfig> {
+    if self.width == 0 || self.width > 0xffff {
+      return Err(InvalidConfig::InvalidWidth(self.width));
+    }
+    if self.height == 0 || self.height > 0xffff {
+      return Err(InvalidConfig::InvalidHeight(self.height));
+    }
+    if ![8, 10, 12].contains(&self.bit_depth) {
+      return Err(InvalidConfig::InvalidBitDepth(self.bit_depth));
+    }
+    if self.quantizer > 255 {
+      return Err(InvalidConfig::InvalidQuantizer(self.quantizer));
+    }
+    if self.bitrate < 0 {
+      return Err(InvalidConfig::InvalidBitrate(self.bitrate));
+    }
+    if self.min_quantizer > self.max_quantizer {
+      return Err(InvalidConfig::InvalidQuantizerRange {
+        min: self.min_quantizer,
+        max: self.max_quantizer
+      });
+    }
+    if self.time_base.num == 0 || self.time_base.den == 0 {
+      return Err(InvalidConfig::InvalidTimeBase {
+        num: self.time_base.num,
+        den: self.time_base.den
+      });
+    }
+    if self.min_key_frame_interval > self.max_key_frame_interval {
+      return Err(InvalidConfig::InvalidKeyFrameInterval {
+        min: self.min_key_frame_interval,
+        max: self.max_key_frame_interval
+      });
+    }
+    if self.me_distortion_weight == 0 || self.rdo_distortion_weight == 0 {
+      return Err(InvalidConfig::InvalidDistortionWeight);
+    }
+    if self.scenecut_sensitivity > 100 {
+      return Err(InvalidConfig::InvalidScenecutSensitivity(
+        self.scenecut_sensitivity
+      ));
+    }
+    if let Some(refresh) = self.intra_refresh {
+      if refresh.period == 0 {
+        return Err(InvalidConfig::InvalidIntraRefreshPeriod(refresh.period));
+      }
+    }
+    if let Some(table) = self.film_grain_table.as_ref() {
+      let subsampled = self.chroma_sampling == ChromaSampling::Cs420;
+      for segment in &table.segments {
+        if let Some(params) = segment.params.as_ref() {
+          if !params.is_valid(subsampled) {
+            return Err(InvalidConfig::InvalidFilmGrainTable {
+              start_time: segment.start_time
+            });
+          }
+        }
+      }
+    }
+    if let SuperResMode::Fixed(denom) = self.superres {
+      if denom < 9 || denom > 16 {
+        return Err(InvalidConfig::InvalidSuperresDenominator(denom));
+      }
+    }
+    let (render_width, render_height) = self.render_size();
+    if render_width == 0 || render_width > 0x10000 || render_height == 0
+      || render_height > 0x10000
+    {
+      return Err(InvalidConfig::InvalidRenderSize {
+        width: render_width,
+        height: render_height
+      });
+    }
+    if self.tile_cols_log2 > 6 || self.tile_rows_log2 > 6 {
+      return Err(InvalidConfig::InvalidTilesLog2 {
+        cols: self.tile_cols_log2,
+        rows: self.tile_rows_log2
+      });
+    }
+    let min = self.speed_settings.min_block_size;
+    let max = self.speed_settings.max_block_size;
+    if !min.is_sqr() || !max.is_sqr() || min.width() > max.width() {
+      return Err(InvalidConfig::InvalidPartitionRange { min, max });
+    }
+    if self.speed_settings.cdef_strengths_log2 > 3 {
+      return Err(InvalidConfig::InvalidCdefStrengths(
+        self.speed_settings.cdef_strengths_log2
+      ));
+    }
+    if !self.quantizer_offsets.is_valid() {
+      return Err(InvalidConfig::InvalidQuantizerOffsets(
+        self.quantizer_offsets
+      ));
+    }
+    if let Some(levels) = self.qm_levels {
+      if levels.iter().any(|&level| level != QM_LEVEL_FLAT) {
+        return Err(InvalidConfig::InvalidQmLevels(levels));
+      }
+    }
+    let layered_structure = (self.low_latency || self.zero_delay)
+      && !self.all_intra && self.intra_refresh.is_none();
+    if self.temporal_layers == 0 || self.temporal_layers > 4
+      || (self.temporal_layers > 1 && !layered_structure)
+    {
+      return Err(InvalidConfig::InvalidTemporalLayers(self.temporal_layers));
+    }
+    Ok(())
+  }
 }
 
 /// Contains all the speed settings
+///
+/// Each field controls one heuristic on its own. The speed presets only pick
+/// values for them, and any field can be changed after applying a preset.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(default))]
 pub struct SpeedSettings {
+  /// Smallest partition tried by the partition search. Must be square.
   pub min_block_size: BlockSize,
+  /// Largest partition tried by the partition search, up to 64x64. Must be
+  /// square; larger blocks are always split.
+  pub max_block_size: BlockSize,
+  /// Search the references of multiple frames in reordered encodes
   pub multiref: bool,
+  /// Pick the deblocking filter levels from the quantizer rather than
+  /// searching them
   pub fast_deblock: bool,
+  /// Limit the transform types to the reduced set
   pub reduced_tx_set: bool,
+  /// Estimate the distortion in the transform domain, with `Tune::Psnr`
   pub tx_domain_distortion: bool,
+  /// Estimate the rate in the transform domain instead of coding it
   pub tx_domain_rate: bool,
+  /// Decide partitions from the smallest blocks up, comparing every split,
+  /// rather than from the superblock down
   pub encode_bottomup: bool,
+  /// Skip partitions unlikely to win in the top-down search: splits of
+  /// blocks coded as skip whose neighbors are not split either, and
+  /// rectangular partitions of blocks with a uniform texture
+  pub partition_pruning: bool,
+  /// Try the partitions into three blocks (`HORZ_A`/`B`, `VERT_A`/`B`) and
+  /// into four strips (`HORZ_4`, `VERT_4`) in the top-down search
+  pub ext_partition_types: bool,
+  /// Search each superblock twice, the second time with the CDFs adapted
+  /// by the first. Only applies to the bottom-up search.
+  pub superblock_second_pass: bool,
+  /// Search the transform type and, up to `tx_search_depth`, the transform
+  /// size of each block
   pub rdo_tx_decision: bool,
+  /// Number of transform splits tried below the largest transform of a
+  /// block, from 0 to 2, when `rdo_tx_decision` is set
+  pub tx_search_depth: usize,
+  /// Which frames try every intra and inter prediction mode
   pub prediction_modes: PredictionModesSetting,
+  /// Try every near motion vector candidate of the stack rather than only
+  /// the first one
   pub include_near_mvs: bool,
+  /// Only place key frames at the maximum key frame interval
   pub no_scene_detection: bool,
+  /// Motion estimation method: a diamond search when set, a full search of
+  /// the motion vector range otherwise
   pub diamond_me: bool,
-  pub cdef: bool
+  /// Enable the constrained directional enhancement filter
+  pub cdef: bool,
+  /// log2 of the number of CDEF strengths searched for each superblock,
+  /// from 0 to 3
+  pub cdef_strengths_log2: u8,
+  /// Try wedge and difference-weighted blending of compound predictions
+  pub masked_compound: bool,
+  /// Try weighting compound predictions by the distance to their references
+  pub dist_wtd_compound: bool,
+  /// Try the recursive filter intra modes on blocks up to 32x32
+  pub filter_intra: bool,
+  /// Adjust quantized coefficient levels by their rate-distortion cost
+  pub rdoq: bool,
+  /// Signal a deblocking filter level delta for each superblock
+  pub deblock_deltas: bool
 }
 
 /// Default values for the speed settings.
@@ -177,18 +586,29 @@ impl Default for SpeedSettings {
   fn default() -> Self {
     SpeedSettings {
       min_block_size: BlockSize::BLOCK_16X16,
+      max_block_size: BlockSize::BLOCK_64X64,
       multiref: false,
       fast_deblock: false,
       reduced_tx_set: false,
       tx_domain_distortion: false,
       tx_domain_rate: false,
       encode_bottomup: false,
+      partition_pruning: false,
+      ext_partition_types: false,
+      superblock_second_pass: false,
       rdo_tx_decision: false,
+      tx_search_depth: 0,
       prediction_modes: PredictionModesSetting::Simple,
       include_near_mvs: false,
       no_scene_detection: false,
       diamond_me: false,
       cdef: false,
+      cdef_strengths_log2: 3,
+      masked_compound: false,
+      dist_wtd_compound: false,
+      filter_intra: false,
+      rdoq: false,
+      deblock_deltas: false,
     }
   }
 }
@@ -196,32 +616,66 @@ impl Default for SpeedSettings {
 impl SpeedSettings {
   /// Set the speed setting according to a numeric speed preset.
   /// The speed settings vary depending on speed value from 0 to 10:
-  ///  - speed - 10, fastest, Min block size 64x64, TX domain distortion, fast deblock, no scenechange detection,
-  ///  - speed - 9, Min block size 64x64, TX domain distortion, fast deblock,
-  ///  - speed - 8, Min block size 8x8, reduced TX set, TX domain distortion, fast deblock,
-  ///  - speed - 7, Min block size 8x8, reduced TX set, TX domain distortion,
-  ///  - speed - 6, Min block size 8x8, reduced TX set, TX domain distortion,
-  ///  - speed - 5, default, Min block size 8x8, reduced TX set, TX domain distortion, complex pred modes for keyframes,
-  ///  - speed - 4, Min block size 8x8, TX domain distortion, complex pred modes for keyframes,
-  ///  - speed - 3, Min block size 8x8, TX domain distortion, complex pred modes for keyframes, RDO TX decision,
-  ///  - speed - 2, Min block size 8x8, TX domain distortion, complex pred modes for keyframes, RDO TX decision, include near MVs,
-  ///  - speed - 1, Min block size 8x8, TX domain distortion, complex pred modes, RDO TX decision, include near MVs,
-  ///  - speed - 0, slowest,  Min block size 4x4, TX domain distortion, complex pred modes, RDO TX decision, include near MVs, bottom-up encoding.
+  ///  - speed - 10, fastest, Min block size 64x64, TX domain distortion, partition pruning, fast deblock, no scenechange detection,
+  ///  - speed - 9, Min block size 64x64, TX domain distortion, partition pruning, fast deblock,
+  ///  - speed - 8, Min block size 8x8, reduced TX set, TX domain distortion, partition pruning, fast deblock,
+  ///  - speed - 7, Min block size 8x8, reduced TX set, TX domain distortion, partition pruning,
+  ///  - speed - 6, Min block size 8x8, reduced TX set, TX domain distortion, partition pruning,
+  ///  - speed - 5, default, Min block size 8x8, reduced TX set, TX domain distortion, partition pruning, complex pred modes for keyframes, RDOQ,
+  ///  - speed - 4, Min block size 8x8, TX domain distortion, partition pruning, complex pred modes for keyframes, filter intra, RDOQ,
+  ///  - speed - 3, Min block size 8x8, TX domain distortion, partition pruning, complex pred modes for keyframes, RDO TX decision, distance-weighted compound, filter intra, RDOQ,
+  ///  - speed - 2, Min block size 8x8, TX domain distortion, partition pruning, complex pred modes for keyframes, RDO TX decision, include near MVs, masked compound, distance-weighted compound, filter intra, RDOQ, extended partitions,
+  ///  - speed - 1, Min block size 8x8, TX domain distortion, complex pred modes, RDO TX decision, include near MVs, masked compound, distance-weighted compound, filter intra, RDOQ, superblock deblock deltas, extended partitions,
+  ///  - speed - 0, slowest,  Min block size 4x4, TX domain distortion, complex pred modes, RDO TX decision, include near MVs, masked compound, distance-weighted compound, filter intra, RDOQ, superblock deblock deltas, bottom-up encoding, superblock second pass.
+  ///
+  /// All speeds search partitions up to 64x64, transform splits up to depth
+  /// 2 when deciding transforms by RDO, and 8 CDEF strengths.
   pub fn from_preset(speed: usize) -> Self {
     SpeedSettings {
       min_block_size: Self::min_block_size_preset(speed),
+      max_block_size: Self::max_block_size_preset(speed),
       multiref: Self::multiref_preset(speed),
       fast_deblock: Self::fast_deblock_preset(speed),
       reduced_tx_set: Self::reduced_tx_set_preset(speed),
       tx_domain_distortion: Self::tx_domain_distortion_preset(speed),
       tx_domain_rate: Self::tx_domain_rate_preset(speed),
       encode_bottomup: Self::encode_bottomup_preset(speed),
+      partition_pruning: Self::partition_pruning_preset(speed),
+      ext_partition_types: Self::ext_partition_types_preset(speed),
+      superblock_second_pass: Self::superblock_second_pass_preset(speed),
       rdo_tx_decision: Self::rdo_tx_decision_preset(speed),
+      tx_search_depth: Self::tx_search_depth_preset(speed),
       prediction_modes: Self::prediction_modes_preset(speed),
       include_near_mvs: Self::include_near_mvs_preset(speed),
       no_scene_detection: Self::no_scene_detection_preset(speed),
       diamond_me: Self::diamond_me_preset(speed),
       cdef: Self::cdef_preset(speed),
+      cdef_strengths_log2: Self::cdef_strengths_log2_preset(speed),
+      masked_compound: Self::masked_compound_preset(speed),
+      dist_wtd_compound: Self::dist_wtd_compound_preset(speed),
+      filter_intra: Self::filter_intra_preset(speed),
+      rdoq: Self::rdoq_preset(speed),
+      deblock_deltas: Self::deblock_deltas_preset(speed),
+    }
+  }
+
+  /// The settings of `from_preset()` for encodes with `all_intra`, which
+  /// turn off the inter tools and the scene change detection, and try the
+  /// complex intra modes up to speed 7 rather than 5, as no time goes to the
+  /// motion search
+  pub fn all_intra_preset(speed: usize) -> Self {
+    SpeedSettings {
+      multiref: false,
+      include_near_mvs: false,
+      no_scene_detection: true,
+      masked_compound: false,
+      dist_wtd_compound: false,
+      prediction_modes: if speed <= 7 {
+        PredictionModesSetting::ComplexKeyframes
+      } else {
+        PredictionModesSetting::Simple
+      },
+      ..Self::from_preset(speed)
     }
   }
 
@@ -241,6 +695,10 @@ impl SpeedSettings {
     min_block_size
   }
 
+  fn max_block_size_preset(_speed: usize) -> BlockSize {
+    BlockSize::BLOCK_64X64
+  }
+
   /// Multiref is enabled automatically if low_latency is false,
   /// but if someone is setting low_latency to true manually,
   /// multiref has a large speed penalty with low quality gain.
@@ -270,10 +728,28 @@ impl SpeedSettings {
     speed == 0
   }
 
+  fn partition_pruning_preset(speed: usize) -> bool {
+    speed >= 2
+  }
+
+  /// Speed 0 searches partitions bottom-up, which does not try these
+  fn ext_partition_types_preset(speed: usize) -> bool {
+    speed == 1 || speed == 2
+  }
+
+  /// Doubles the time spent searching partitions and modes
+  fn superblock_second_pass_preset(speed: usize) -> bool {
+    speed == 0
+  }
+
   fn rdo_tx_decision_preset(speed: usize) -> bool {
     speed <= 3
   }
 
+  fn tx_search_depth_preset(_speed: usize) -> usize {
+    2
+  }
+
   fn prediction_modes_preset(speed: usize) -> PredictionModesSetting {
     if speed <= 1 {
       PredictionModesSetting::ComplexAll
@@ -304,6 +780,39 @@ impl SpeedSettings {
   fn cdef_preset(_speed: usize) -> bool {
     true
   }
+
+  /// Every candidate strength filters the whole superblock, so faster
+  /// presets search a smaller set of strengths
+  fn cdef_strengths_log2_preset(speed: usize) -> u8 {
+    if speed <= 5 {
+      3
+    } else if speed <= 8 {
+      2
+    } else {
+      1
+    }
+  }
+
+  /// Every wedge shape is tried on compound blocks, which is slow
+  fn masked_compound_preset(speed: usize) -> bool {
+    speed <= 2
+  }
+
+  fn dist_wtd_compound_preset(speed: usize) -> bool {
+    speed <= 3
+  }
+
+  fn filter_intra_preset(speed: usize) -> bool {
+    speed <= 4
+  }
+
+  fn rdoq_preset(speed: usize) -> bool {
+    speed <= 5
+  }
+
+  fn deblock_deltas_preset(speed: usize) -> bool {
+    speed <= 1
+  }
 }
 
 #[allow(dead_code, non_camel_case_types)]
@@ -328,7 +837,321 @@ impl fmt::Display for FrameType {
   }
 }
 
+/// An explicit segmentation of a frame, such as a region of interest map.
+/// Each segment has its own quantizer and deblocking filter offsets.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct SegmentationMap {
+  /// Number of columns of the grid
+  pub cols: usize,
+  /// Number of rows of the grid
+  pub rows: usize,
+  /// Segment (0-7) of each grid cell, in raster order. The grid is stretched
+  /// over the frame, so it may have any resolution.
+  pub segments: Vec<u8>,
+  /// Offset added to the quantizer index of each segment
+  pub qindex_offsets: [i16; 8],
+  /// Offset added to the deblocking filter levels of each segment
+  pub deblock_offsets: [i8; 8],
+}
+
+impl SegmentationMap {
+  fn is_valid(&self) -> bool {
+    self.cols > 0
+      && self.rows > 0
+      && self.segments.len() == self.cols * self.rows
+      && self.segments.iter().all(|&s| s < 8)
+  }
+}
+
+/// Region of interest map of a frame. The grid is stretched over the frame,
+/// so it may have any resolution, and its cells are grouped into segments.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct RoiMap {
+  /// Number of columns of the grid
+  pub cols: usize,
+  /// Number of rows of the grid
+  pub rows: usize,
+  pub values: RoiValues,
+}
+
+/// Values of the cells of a `RoiMap`, in raster order
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum RoiValues {
+  /// Importance of each cell. 128 keeps the frame quantizer, 255 lowers it
+  /// by a quarter and 0 raises it by a quarter.
+  Importance(Vec<u8>),
+  /// Offset added to the quantizer index of each cell
+  QindexOffsets(Vec<i16>),
+}
+
+impl RoiMap {
+  fn is_valid(&self) -> bool {
+    let len = match self.values {
+      RoiValues::Importance(ref v) => v.len(),
+      RoiValues::QindexOffsets(ref v) => v.len()
+    };
+    self.cols > 0 && self.rows > 0 && len == self.cols * self.rows
+  }
+}
+
+/// Encoding parameters supplied along with a single input frame
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FrameParameters {
+  /// Segmentation to use instead of the one the encoder would pick
+  pub segmentation: Option<SegmentationMap>,
+  /// Regions to code at a higher or lower quality. Ignored if an explicit
+  /// segmentation is given.
+  pub roi_map: Option<RoiMap>,
+  /// Keep this frame as a long-term reference, for later frames to recover
+  /// from with `reference_frameno`, until the next key frame or long-term
+  /// reference
+  pub long_term_reference: bool,
+  /// Predict this frame only from the earlier input frame with this number,
+  /// e.g. the last one a receiver reported as correctly decoded after a
+  /// packet loss. Ignored if that frame is no longer held as a reference.
+  pub reference_frameno: Option<u64>,
+  /// ITU-T T.35 payloads, e.g. HDR10+ dynamic metadata, to emit as metadata
+  /// OBUs in the temporal unit displaying this frame
+  pub t35_metadata: Vec<T35>,
+  /// Presentation time stamp of the frame in `time_base` units, passed
+  /// through to the packet displaying it. Defaults to the input frame
+  /// number, i.e. a constant frame rate.
+  pub pts: Option<u64>,
+  /// Frame type to use instead of the one picked from the key frame
+  /// intervals and the scene change detection. The first frame is always a
+  /// key frame.
+  pub frame_type_override: Option<FrameTypeOverride>,
+}
+
+/// Frame type requested by the caller for an input frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum FrameTypeOverride {
+  /// Start a new sequence of frames at this one, e.g. at a chapter point or
+  /// at the start of a segment
+  Key,
+  /// Predict this frame from the previous ones, even across a scene change.
+  /// `max_key_frame_interval` still applies.
+  NoKey,
+}
+
+/// An ITU-T T.35 metadata payload
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct T35 {
+  /// Country code, as in ITU-T T.35. 0xB5 is the United States.
+  pub country_code: u8,
+  /// Extra country code byte, only written if `country_code` is 0xFF
+  pub country_code_extension_byte: u8,
+  /// The payload bytes following the country code, e.g. starting with the
+  /// terminal provider code for HDR10+
+  pub data: Box<[u8]>,
+}
+
+impl FrameParameters {
+  fn is_valid(&self) -> bool {
+    self.segmentation.as_ref().map_or(true, SegmentationMap::is_valid)
+      && self.roi_map.as_ref().map_or(true, RoiMap::is_valid)
+  }
+}
+
+/// Anything that can be sent to the encoder with `Context::send_frame`: a
+/// frame, a frame with its parameters, or `None` to flush the encoder
+pub trait IntoFrame<T: Pixel> {
+  fn into_frame(self) -> (Option<Arc<Frame<T>>>, FrameParameters);
+}
+
+impl<T: Pixel> IntoFrame<T> for Option<Arc<Frame<T>>> {
+  fn into_frame(self) -> (Option<Arc<Frame<T>>>, FrameParameters) {
+    (self, FrameParameters::default())
+  }
+}
+
+impl<T: Pixel> IntoFrame<T> for Arc<Frame<T>> {
+  fn into_frame(self) -> (Option<Arc<Frame<T>>>, FrameParameters) {
+    (Some(self), FrameParameters::default())
+  }
+}
+
+impl<T: Pixel> IntoFrame<T> for (Arc<Frame<T>>, FrameParameters) {
+  fn into_frame(self) -> (Option<Arc<Frame<T>>>, FrameParameters) {
+    (Some(self.0), self.1)
+  }
+}
+
+/// Long-term reference structure for real time encodes, only used with
+/// `low_latency`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ReferencePattern {
+  /// Refresh a golden reference, kept apart from the short-term ones, every
+  /// `golden_interval` frames after a key frame. 0 disables it.
+  pub golden_interval: u64,
+  /// Predict only from the previous frame and the golden frame, so that a
+  /// frame loss only corrupts the following frames up to the next golden
+  /// frame
+  pub last_and_golden_only: bool,
+}
+
+/// Quantizers of the types of frames relative to the base quantizer, as the
+/// base-2 logarithm of their ratio, so that 1.0 doubles the quantizer
+///
+/// Both the fixed quantizer and the bitrate modes apply them, the rate
+/// control picking the base quantizer. The default steps of about 0.39 are
+/// close to 15 quantizer indices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct QuantizerOffsets {
+  /// Key frames, which the frames up to the next one all predict from
+  pub key_frame: f64,
+  /// Inter frames at the base of the pyramid: the alt-ref frames, or every
+  /// inter frame with `low_latency`
+  pub alt_ref: f64,
+  /// Inter frames at the first and at the second level of the pyramid
+  pub pyramid_levels: [f64; 2],
+}
+
+impl Default for QuantizerOffsets {
+  fn default() -> Self {
+    QuantizerOffsets {
+      key_frame: -DQP_STEP,
+      alt_ref: 0.0,
+      pyramid_levels: [DQP_STEP, 2.0 * DQP_STEP],
+    }
+  }
+}
+
+impl QuantizerOffsets {
+  fn is_valid(&self) -> bool {
+    [self.key_frame, self.alt_ref, self.pyramid_levels[0], self.pyramid_levels[1]]
+      .iter()
+      .all(|offset| offset.abs() <= 8.0)
+  }
+}
+
+/// Direction in which the intra refresh wave sweeps the frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum IntraRefreshDirection {
+  /// Superblock columns, from left to right
+  Columns,
+  /// Superblock rows, from top to bottom
+  Rows,
+}
+
+/// Gradual decoder refresh of ultra-low latency streams, which have no key
+/// frame after the first one to spike their bitrate
+///
+/// Each inter frame codes a band of superblocks intra, the band moving
+/// across the frame from one frame to the next. The superblocks the wave
+/// has already passed only predict from the refreshed area of the previous
+/// frame, so that a decoder recovers from a loss, or starts decoding, at
+/// most `period` frames after the start of a wave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct IntraRefresh {
+  pub direction: IntraRefreshDirection,
+  /// Number of frames the wave takes to sweep the frame, at least 1
+  pub period: u64,
+}
+
+impl std::str::FromStr for IntraRefresh {
+  type Err = ();
+
+  /// Parses `columns:PERIOD` or `rows:PERIOD`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.to_lowercase();
+    let mut parts = s.splitn(2, ':');
+    let direction = match parts.next() {
+      Some("columns") => IntraRefreshDirection::Columns,
+      Some("rows") => IntraRefreshDirection::Rows,
+      _ => return Err(())
+    };
+    match parts.next().map(|period| period.parse()) {
+      Some(Ok(period)) if period > 0 => Ok(IntraRefresh { direction, period }),
+      _ => Err(())
+    }
+  }
+}
+
+/// When to code key frames at a reduced width, for the decoder to upscale
+/// them back with the normative superres filter
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum SuperResMode {
+  None,
+  /// Scale the width by `8 / denominator`, with a denominator in 9..=16
+  Fixed(u8),
+  /// Downscale when the rate control is starved and has to pick a high
+  /// quantizer
+  Auto,
+}
+
+impl Default for SuperResMode {
+  fn default() -> Self {
+    SuperResMode::None
+  }
+}
+
+/// Parses "none", "auto" or a fixed denominator
+impl std::str::FromStr for SuperResMode {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "none" => Ok(SuperResMode::None),
+      "auto" => Ok(SuperResMode::Auto),
+      denom => match denom.parse() {
+        Ok(d) if d >= 9 && d <= 16 => Ok(SuperResMode::Fixed(d)),
+        _ => Err(())
+      }
+    }
+  }
+}
+
+/// AV1 level of the sequence, bounding its picture size, frame rate, bitrate
+/// and tiling
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum Level {
+  /// The lowest level the configuration fits in, in the main tier if possible
+  Auto,
+  /// No level constraints
+  Unconstrained,
+  /// Level `major.minor`, e.g. 5.1, in the main or high tier
+  Fixed { major: u8, minor: u8, high_tier: bool },
+}
+
+impl Default for Level {
+  fn default() -> Self {
+    Level::Unconstrained
+  }
+}
+
+/// Parses "auto", "none" or a main tier level such as "5.1"
+impl std::str::FromStr for Level {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "auto" => Ok(Level::Auto),
+      "none" => Ok(Level::Unconstrained),
+      level => {
+        let mut parts = level.splitn(2, '.');
+        let major = parts.next().and_then(|p| p.parse().ok()).ok_or(())?;
+        let minor = parts.next().map_or(Ok(0), |p| p.parse()).map_err(|_| ())?;
+        Ok(Level::Fixed { major, minor, high_tier: false })
+      }
+    }
+  }
+}
+
 #[derive(Clone, Copy, Debug, PartialOrd, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum PredictionModesSetting {
   Simple,
   ComplexKeyframes,
@@ -336,6 +1159,7 @@ pub enum PredictionModesSetting {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum ChromaSampling {
   Cs420,
@@ -364,6 +1188,7 @@ impl ChromaSampling {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum ChromaSamplePosition {
   Unknown,
@@ -378,6 +1203,7 @@ impl Default for ChromaSamplePosition {
 }
 
 #[derive(ArgEnum, Debug, Clone, Copy, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum PixelRange {
     Unspecified = 0,
@@ -392,6 +1218,7 @@ impl Default for PixelRange {
 }
 
 #[derive(ArgEnum, Debug, Clone, Copy, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum MatrixCoefficients {
     Identity = 0,
@@ -417,6 +1244,7 @@ impl Default for MatrixCoefficients {
 }
 
 #[derive(ArgEnum, Debug, Clone, Copy, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum ColorPrimaries {
     BT709 = 1,
@@ -440,6 +1268,7 @@ impl Default for ColorPrimaries {
 }
 
 #[derive(ArgEnum, Debug, Clone, Copy, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum TransferCharacteristics {
     BT1886 = 1,
@@ -467,66 +1296,121 @@ impl Default for TransferCharacteristics {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Color description signaled in the sequence header, with values as
+/// defined in ITU-T H.273
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct ColorDescription {
+    /// Chromaticity of the primaries and white point
     pub color_primaries: ColorPrimaries,
+    /// Opto-electronic transfer function
     pub transfer_characteristics: TransferCharacteristics,
+    /// Conversion from RGB to the coded luma and chroma
     pub matrix_coefficients: MatrixCoefficients
 }
 
+/// Mastering display color volume, signaled in a metadata OBU with each
+/// sequence header
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct MasteringDisplay {
+    /// CIE 1931 chromaticity of the red, green and blue primaries, in 0.16
+    /// fixed point
     pub primaries: [Point; 3],
+    /// CIE 1931 chromaticity of the white point, in 0.16 fixed point
     pub white_point: Point,
+    /// Maximum luminance in cd/m², in 24.8 fixed point
     pub max_luminance: u32,
+    /// Minimum luminance in cd/m², in 18.14 fixed point
     pub min_luminance: u32,
 }
 
+/// Content light levels, signaled in a metadata OBU with each sequence
+/// header
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct ContentLight {
+    /// Maximum light level of any pixel, in cd/m² (MaxCLL)
     pub max_content_light_level: u16,
+    /// Maximum average light level of any frame, in cd/m² (MaxFALL)
     pub max_frame_average_light_level: u16,
 }
 
+/// The rate control settings of `EncoderConfig` that can be changed during
+/// an encode with `Context::set_parameters()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateParameters {
+  /// Target bitrate in bits per second, 0 for a constant quantizer
+  pub bitrate: i32,
+  /// Quantizer, 0-255, the maximum one with a target bitrate
+  pub quantizer: usize,
+  /// Minimum quantizer with a target bitrate
+  pub min_quantizer: u8,
+}
+
 /// Contains all the encoder configuration
 #[derive(Clone, Debug, Default)]
 pub struct Config {
   pub enc: EncoderConfig,
   /// The number of threads in the threadpool.
+  ///
+  /// Tiles are the unit of parallelism and are coded independently, so the
+  /// bitstream is the same for any number of threads.
   pub threads: usize
 }
 
 impl Config {
-  pub fn new_context<T: Pixel>(&self) -> Context<T> {
-    assert!(8 * std::mem::size_of::<T>() >= self.enc.bit_depth, "The Pixel u{} does not match the Config bit_depth {}",
-            8 * std::mem::size_of::<T>(), self.enc.bit_depth);
-
-    let pool = rayon::ThreadPoolBuilder::new().num_threads(self.threads).build().unwrap();
+  pub fn new_context<T: Pixel>(&self) -> Result<Context<T>, InvalidConfig> {
+    self.enc.validate()?;
+    let pixel_bits = 8 * std::mem::size_of::<T>();
+    if pixel_bits < self.enc.bit_depth {
+      return Err(InvalidConfig::PixelTooNarrow {
+        pixel_bits,
+        bit_depth: self.enc.bit_depth
+      });
+    }
 
-    let mut config = self.enc.clone();
+    let pool = ThreadPoolBuilder::new().num_threads(self.threads).build().unwrap();
 
-    // FIXME: inter unsupported with 4:2:2 and 4:4:4 chroma sampling
-    let chroma_sampling = config.chroma_sampling;
+    let config = sequence_config(&self.enc)?;
+    debug!("Coding at {:?}", config.level);
 
-    // FIXME: tx partition for intra not supported for chroma 422
-    if chroma_sampling == ChromaSampling::Cs422 {
-      config.speed_settings.rdo_tx_decision = false;
+    let mut inner = ContextInner::new(&config);
+    if config.still_picture {
+      inner.limit = 1;
     }
 
-    let inner = ContextInner::new(&config);
-
-    Context {
+    Ok(Context {
       inner,
+      resized: VecDeque::new(),
+      input_frameno_offset: 0,
       pool,
       config
-    }
+    })
   }
 }
 
+/// The settings a sequence is coded with, once the level is selected
+fn sequence_config(enc: &EncoderConfig) -> Result<EncoderConfig, InvalidConfig> {
+  let mut config = enc.clone();
+  config.level = select_level(&config)?;
+
+  // FIXME: inter unsupported with 4:2:2 and 4:4:4 chroma sampling
+  let chroma_sampling = config.chroma_sampling;
+
+  // FIXME: tx partition for intra not supported for chroma 422
+  if chroma_sampling == ChromaSampling::Cs422 {
+    config.speed_settings.rdo_tx_decision = false;
+  }
+
+  Ok(config)
+}
+
 // The set of options that controls frame re-ordering and reference picture
 //  selection.
 // The options stored here are invariant over the whole encode.
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub(crate) struct InterConfig {
   // Whether frame re-ordering is enabled.
   reorder: bool,
@@ -544,7 +1428,8 @@ pub(crate) struct InterConfig {
 
 impl InterConfig {
   fn new(enc_config: &EncoderConfig) -> InterConfig {
-    let reorder = !enc_config.low_latency;
+    let reorder = !(enc_config.low_latency || enc_config.zero_delay
+      || enc_config.all_intra || enc_config.intra_refresh.is_some());
     // A group always starts with (group_output_len - group_input_len) hidden
     //  frames, followed by group_input_len shown frames.
     // The shown frames iterate over the input frames in order, with frames
@@ -643,6 +1528,10 @@ impl InterConfig {
   }
 }
 
+/// Most input frames to keep around for reuse
+const MAX_POOLED_FRAMES: usize = 8;
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub(crate) struct ContextInner<T: Pixel> {
   frame_count: u64,
   limit: u64,
@@ -663,6 +1552,26 @@ pub(crate) struct ContextInner<T: Pixel> {
   segment_output_frameno_start: u64,
   pub(crate) segment_input_frameno_start: u64,
   keyframe_detector: SceneChangeDetector<T>,
+  denoiser: Option<Denoiser<T>>,
+  /// Maps *input_frameno* to the grain removed by the denoiser
+  film_grain_params: BTreeMap<u64, FilmGrainParams>,
+  /// Maps *input_frameno* to the parameters supplied with the frame
+  frame_params: BTreeMap<u64, Arc<FrameParameters>>,
+  /// Input frames the encoder is done with, for `new_frame` to hand out
+  /// again instead of allocating
+  #[cfg_attr(feature = "serialize", serde(skip, default = "Default::default"))]
+  frame_pool: Mutex<Vec<Frame<T>>>,
+  /// Source frames dropped before reaching the encoder, which the default
+  /// time stamps of the following frames skip
+  frames_dropped: u64,
+  /// Last input frame not taken for a repeat, which the following frames
+  /// are compared to when looking for repeated frames, so that a slow drift
+  /// is not frozen
+  last_input_frame: Option<Arc<Frame<T>>>,
+  /// *input_frameno* of the frames repeating the frame before them
+  duplicate_frames: BTreeSet<u64>,
+  #[cfg_attr(feature = "serialize", serde(skip))]
+  observer: Option<Box<dyn EncoderObserver>>,
   pub(crate) config: EncoderConfig,
   rc_state: RCState,
   maybe_prev_log_base_q: Option<i64>,
@@ -671,8 +1580,13 @@ pub(crate) struct ContextInner<T: Pixel> {
 
 pub struct Context<T: Pixel> {
   inner: ContextInner<T>,
+  /// Sequences at the sizes the input was resized to, each with the input
+  /// frame number of its first frame, coded once `inner` is flushed
+  resized: VecDeque<(u64, ContextInner<T>)>,
+  /// Input frame number of the first frame of `inner`
+  input_frameno_offset: u64,
   config: EncoderConfig,
-  pool: rayon::ThreadPool,
+  pool: ThreadPool,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -697,65 +1611,650 @@ pub enum EncoderStatus {
   NotReady
 }
 
+/// A configuration `Config::new_context` can't encode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidConfig {
+  /// The level is not one defined by the AV1 specification
+  UndefinedLevel { major: u8, minor: u8 },
+  /// Levels below 4.0 only have a main tier
+  NoHighTier { major: u8, minor: u8 },
+  /// A parameter is beyond what the requested level allows
+  LevelLimitExceeded {
+    major: u8,
+    minor: u8,
+    limit: LevelLimit,
+    actual: u64,
+    max: u64
+  },
+  /// The width is 0 or takes more than 16 bits
+  InvalidWidth(usize),
+  /// The height is 0 or takes more than 16 bits
+  InvalidHeight(usize),
+  /// Only 8, 10 and 12 bits per sample are supported
+  InvalidBitDepth(usize),
+  /// The pixel type of the context cannot hold samples of this bit depth
+  PixelTooNarrow { pixel_bits: usize, bit_depth: usize },
+  /// Quantizers range from 0 to 255
+  InvalidQuantizer(usize),
+  /// The minimum quantizer is above the maximum
+  InvalidQuantizerRange { min: u8, max: u8 },
+  /// The target bitrate is negative
+  InvalidBitrate(i32),
+  /// The frame rate has a zero numerator or denominator
+  InvalidTimeBase { num: u64, den: u64 },
+  /// The minimum key frame interval is above the maximum
+  InvalidKeyFrameInterval { min: u64, max: u64 },
+  /// The scene change sensitivity is above 100
+  InvalidScenecutSensitivity(u8),
+  /// The intra refresh wave takes no frame to sweep the frame
+  InvalidIntraRefreshPeriod(u64),
+  /// The film grain parameters of the segment of the film grain table
+  /// starting at `start_time` cannot be signaled
+  InvalidFilmGrainTable { start_time: u64 },
+  /// The superres denominator is not in 9..=16
+  InvalidSuperresDenominator(u8),
+  /// The render width or height is not in 1..=65536
+  InvalidRenderSize { width: usize, height: usize },
+  /// Log2 of the tile columns or rows is above 6
+  InvalidTilesLog2 { cols: usize, rows: usize },
+  /// The partition sizes searched are not square, or the minimum is above
+  /// the maximum
+  InvalidPartitionRange { min: BlockSize, max: BlockSize },
+  /// Log2 of the CDEF strengths is above 3
+  InvalidCdefStrengths(u8),
+  /// A quantizer offset is not a number in -8.0..=8.0
+  InvalidQuantizerOffsets(QuantizerOffsets),
+  /// The number of temporal layers is not in 1..=4, or there are several
+  /// without low latency, or along with all-intra coding or intra refresh
+  InvalidTemporalLayers(usize),
+  /// Quantizer matrix levels other than the flat level 15 are not available
+  InvalidQmLevels([u8; 3]),
+  /// A distortion weight is 0, which would leave only the rate to decide
+  InvalidDistortionWeight,
+}
+
+/// The level constraints checked when creating a context
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LevelLimit {
+  /// Luma samples per frame
+  PictureSize,
+  Width,
+  Height,
+  /// Luma samples per second
+  DisplayRate,
+  /// Frames per second
+  FrameRate,
+  /// Bits per second
+  Bitrate,
+  Tiles,
+  TileColumns,
+}
+
+impl fmt::Display for InvalidConfig {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      InvalidConfig::UndefinedLevel { major, minor } => {
+        write!(f, "Level {}.{} is not defined", major, minor)
+      }
+      InvalidConfig::NoHighTier { major, minor } => {
+        write!(f, "Level {}.{} has no high tier", major, minor)
+      }
+      InvalidConfig::LevelLimitExceeded { major, minor, limit, actual, max } => {
+        write!(
+          f,
+          "{:?} {} exceeds the maximum of {} for level {}.{}",
+          limit, actual, max, major, minor
+        )
+      }
+      InvalidConfig::InvalidWidth(width) => {
+        write!(f, "Width {} is not in 1..=65535", width)
+      }
+      InvalidConfig::InvalidHeight(height) => {
+        write!(f, "Height {} is not in 1..=65535", height)
+      }
+      InvalidConfig::InvalidBitDepth(bit_depth) => {
+        write!(f, "Bit depth {} is not 8, 10 or 12", bit_depth)
+      }
+      InvalidConfig::PixelTooNarrow { pixel_bits, bit_depth } => {
+        write!(
+          f,
+          "The Pixel u{} does not match the Config bit_depth {}",
+          pixel_bits, bit_depth
+        )
+      }
+      InvalidConfig::InvalidQuantizer(quantizer) => {
+        write!(f, "Quantizer {} is not in 0..=255", quantizer)
+      }
+      InvalidConfig::InvalidQuantizerRange { min, max } => {
+        write!(f, "Minimum quantizer {} is above the maximum {}", min, max)
+      }
+      InvalidConfig::InvalidBitrate(bitrate) => {
+        write!(f, "Bitrate {} is negative", bitrate)
+      }
+      InvalidConfig::InvalidTimeBase { num, den } => {
+        write!(f, "Time base {}/{} is not a valid frame rate", num, den)
+      }
+      InvalidConfig::InvalidKeyFrameInterval { min, max } => {
+        write!(
+          f,
+          "Minimum key frame interval {} is above the maximum {}",
+          min, max
+        )
+      }
+      InvalidConfig::InvalidScenecutSensitivity(sensitivity) => {
+        write!(f, "Scene change sensitivity {} is greater than 100", sensitivity)
+      }
+      InvalidConfig::InvalidIntraRefreshPeriod(period) => {
+        write!(f, "Intra refresh period {} is not at least 1", period)
+      }
+      InvalidConfig::InvalidFilmGrainTable { start_time } => {
+        write!(
+          f,
+          "The film grain parameters starting at time {} are invalid",
+          start_time
+        )
+      }
+      InvalidConfig::InvalidSuperresDenominator(denom) => {
+        write!(f, "The superres denominator {} is not in 9..=16", denom)
+      }
+      InvalidConfig::InvalidRenderSize { width, height } => {
+        write!(f, "Render size {}x{} is not in 1..=65536", width, height)
+      }
+      InvalidConfig::InvalidTilesLog2 { cols, rows } => {
+        write!(
+          f,
+          "Log2 of tile columns {} and rows {} may not be greater than 6",
+          cols, rows
+        )
+      }
+      InvalidConfig::InvalidPartitionRange { min, max } => {
+        write!(f, "Partition sizes {:?} to {:?} are not a square range", min, max)
+      }
+      InvalidConfig::InvalidCdefStrengths(log2) => {
+        write!(f, "Log2 of the CDEF strengths {} is greater than 3", log2)
+      }
+      InvalidConfig::InvalidQuantizerOffsets(offsets) => {
+        write!(f, "Quantizer offsets {:?} are not within -8.0 to 8.0", offsets)
+      }
+      InvalidConfig::InvalidTemporalLayers(layers) => {
+        write!(
+          f,
+          "{} temporal layers are not in 1..=4, or need low latency without \
+           all-intra coding or intra refresh",
+          layers
+        )
+      }
+      InvalidConfig::InvalidQmLevels(levels) => {
+        write!(
+          f,
+          "Quantizer matrix levels {:?} are not available, only the flat \
+           level 15 is",
+          levels
+        )
+      }
+      InvalidConfig::InvalidDistortionWeight => {
+        write!(f, "Distortion weights have to be at least 1")
+      }
+    }
+  }
+}
+
+impl std::error::Error for InvalidConfig {}
+
 pub struct Packet<T: Pixel> {
   pub data: Vec<u8>,
   pub rec: Option<Frame<T>>,
+  /// Prediction of the shown frame, the reconstruction before the residual
+  /// and the loop filters, if `output_prediction` is set. `None` for frames
+  /// coded in an earlier packet or with super-resolution.
+  pub prediction: Option<Frame<T>>,
   /// The number of the input frame corresponding to the one shown frame in the
   /// TU stored in this packet. Since AV1 does not explicitly reorder frames,
   /// these will increase sequentially.
-  // TODO: When we want to add VFR support, we will need a more explicit time
-  // stamp here.
   pub input_frameno: u64,
+  /// Presentation time stamp of the shown frame in `time_base` units, as
+  /// given in its `FrameParameters`, or else its input frame number
+  pub pts: u64,
   pub frame_type: FrameType,
+  /// Coding role of the shown frame
+  pub frame_subtype: FrameSubtype,
+  /// Base quantizer index of the shown frame, 0 if it was coded in an
+  /// earlier packet
+  pub qp: u8,
+  /// Level of the shown frame in the frame reordering pyramid, 0 for key
+  /// frames and frames coded in display order, or its temporal layer with
+  /// `temporal_layers`
+  pub temporal_layer: u64,
+  /// Input frame numbers of the frames the shown frame predicts from
+  pub references: Vec<u64>,
   /// PSNR for Y, U, and V planes
   pub psnr: Option<(f64, f64, f64)>,
+  /// SSIM for Y, U, and V planes, if `show_ssim` is set
+  pub ssim: Option<(f64, f64, f64)>,
+  /// MS-SSIM for Y, U, and V planes, if `show_ssim` is set
+  pub ms_ssim: Option<(f64, f64, f64)>,
 }
 
-impl<T: Pixel> fmt::Display for Packet<T> {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(
-      f,
-      "Frame {} - {} - {} bytes",
-      self.input_frameno,
-      self.frame_type,
-      self.data.len()
-    )
-  }
+/// Coding role of the frame shown by a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameSubtype {
+  /// Key frame, predicting from nothing
+  Key,
+  /// Intra-only frame, predicting from nothing without resetting the
+  /// references
+  Intra,
+  /// Inter frame
+  Inter,
+  /// Frame coded in an earlier packet, shown with `show_existing_frame`
+  ShowExisting,
 }
 
-impl<T: Pixel> Context<T> {
-  pub fn new_frame(&self) -> Arc<Frame<T>> {
-    Arc::new(Frame::new(
-      self.config.width,
-      self.config.height,
-      self.config.chroma_sampling
-    ))
+impl FrameSubtype {
+  fn of<T: Pixel>(fi: &FrameInvariants<T>) -> Self {
+    if fi.show_existing_frame {
+      FrameSubtype::ShowExisting
+    } else if fi.frame_type == FrameType::KEY {
+      FrameSubtype::Key
+    } else if fi.intra_only {
+      FrameSubtype::Intra
+    } else {
+      FrameSubtype::Inter
+    }
   }
+}
 
-  pub fn send_frame<F>(&mut self, frame: F) -> Result<(), EncoderStatus>
-  where
-    F: Into<Option<Arc<Frame<T>>>>,
-  {
-    let frame = frame.into();
+/// Statistics of a frame the encoder just coded
+#[derive(Clone, Debug)]
+pub struct FrameEncodeStats {
+  pub input_frameno: u64,
+  pub frame_type: FrameType,
+  pub frame_subtype: FrameSubtype,
+  /// Whether the frame is displayed now. Hidden frames are shown by a later
+  /// `FrameSubtype::ShowExisting` frame.
+  pub show_frame: bool,
+  /// Base quantizer index, 0 for `FrameSubtype::ShowExisting` frames
+  pub qp: u8,
+  /// Size of the coded frame
+  pub bits: u64,
+  /// Lowest SAD-based cost of the coarse motion search, summed over the
+  /// superblocks of the frame, 0 for intra frames
+  pub me_cost: u64,
+  /// Coding decisions of every block, empty unless
+  /// `EncoderConfig::log_block_decisions` is set
+  pub blocks: Vec<BlockDecision>,
+  /// Time spent coding the frame, including any trial encodes
+  ///
+  /// Always zero on wasm32, which has no clock to measure it with.
+  pub encode_time: Duration,
+  /// Time spent in each stage, zero unless `EncoderConfig::time_stages` is
+  /// set
+  pub stage_times: StageTimes,
+  /// PSNR for Y, U, and V planes, for shown frames if `show_psnr` is set
+  pub psnr: Option<(f64, f64, f64)>,
+  /// SSIM for Y, U, and V planes, for shown frames if `show_ssim` is set
+  pub ssim: Option<(f64, f64, f64)>,
+  /// MS-SSIM for Y, U, and V planes, for shown frames if `show_ssim` is set
+  pub ms_ssim: Option<(f64, f64, f64)>,
+}
 
-    if frame.is_none() {
-        self.inner.limit = self.inner.frame_count;
+impl FrameEncodeStats {
+  fn new<T: Pixel>(
+    fi: &FrameInvariants<T>, bits: u64, start: Option<Instant>,
+  ) -> Self {
+    FrameEncodeStats {
+      input_frameno: fi.input_frameno,
+      frame_type: fi.frame_type,
+      frame_subtype: FrameSubtype::of(fi),
+      show_frame: fi.show_frame || fi.show_existing_frame,
+      qp: if fi.show_existing_frame { 0 } else { fi.base_q_idx },
+      bits,
+      me_cost: 0,
+      blocks: Vec::new(),
+      encode_time: start.map_or(Duration::default(), |start| start.elapsed()),
+      stage_times: StageTimes::default(),
+      psnr: None,
+      ssim: None,
+      ms_ssim: None,
     }
+  }
 
-    self.inner.send_frame(frame)
+  fn set_metrics<T: Pixel>(&mut self, pkt: &Packet<T>) {
+    self.psnr = pkt.psnr;
+    self.ssim = pkt.ssim;
+    self.ms_ssim = pkt.ms_ssim;
   }
+}
 
-  /// Retrieve the first-pass data of a two-pass encode for the frame that was
-  /// just encoded. This should be called BEFORE every call to receive_packet()
-  /// (including the very first one), even if no packet was produced by the
-  /// last call to receive_packet, if any (i.e., *EncoderStatus::Encoded* was
-  /// returned). It needs to be called once more after
-  /// *EncoderStatus::LimitReached* is returned, to retrieve the header that
-  /// should be written to the front of the stats file (overwriting the
-  /// placeholder header that was emitted at the start of encoding).
-  ///
-  /// It is still safe to call this function when receive_packet() returns any
-  /// other error. It will return *None* instead of returning a duplicate copy
+/// Receiver of encoding progress, e.g. for a frontend to display it without
+/// polling
+pub trait EncoderObserver: Send {
+  /// Called after coding each frame, hidden or shown, from the thread
+  /// calling `Context::receive_packet`
+  fn frame_encoded(&mut self, stats: &FrameEncodeStats);
+}
+
+/// Forwards the statistics to each observer in turn
+impl EncoderObserver for Vec<Box<dyn EncoderObserver>> {
+  fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+    for observer in self.iter_mut() {
+      observer.frame_encoded(stats);
+    }
+  }
+}
+
+/// Layout of the records written by `FrameStatsLog`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsFormat {
+  /// An array of objects, one per frame
+  Json,
+  /// A header line followed by one line per frame
+  Csv,
+}
+
+const FRAME_STATS_FIELDS: &[&str] = &[
+  "input_frameno", "frame_type", "frame_subtype", "show_frame", "qp", "bits",
+  "me_cost", "encode_time_ms", "me_time_ms", "mode_decision_time_ms",
+  "transform_time_ms", "entropy_coding_time_ms", "loop_filters_time_ms",
+  "psnr_y", "psnr_u", "psnr_v", "ssim_y",
+  "ssim_u", "ssim_v", "ms_ssim_y", "ms_ssim_u", "ms_ssim_v",
+];
+
+/// Observer writing a record of every coded frame, for analysis and plotting
+///
+/// Write errors are ignored so that they cannot interrupt the encode.
+pub struct FrameStatsLog<W: io::Write + Send> {
+  writer: W,
+  format: StatsFormat,
+  records: usize,
+}
+
+impl<W: io::Write + Send> FrameStatsLog<W> {
+  pub fn new(writer: W, format: StatsFormat) -> Self {
+    FrameStatsLog { writer, format, records: 0 }
+  }
+
+  fn write_record(&mut self, stats: &FrameEncodeStats) -> io::Result<()> {
+    let metric = |m: Option<(f64, f64, f64)>| -> [Option<f64>; 3] {
+      m.map_or([None; 3], |(y, u, v)| [Some(y), Some(u), Some(v)])
+    };
+    let mut values = vec![
+      stats.input_frameno.to_string(),
+      format!("{:?}", stats.frame_type),
+      format!("{:?}", stats.frame_subtype),
+      stats.show_frame.to_string(),
+      stats.qp.to_string(),
+      stats.bits.to_string(),
+      stats.me_cost.to_string(),
+    ];
+    let times = &stats.stage_times;
+    for &time in &[
+      stats.encode_time,
+      times.motion_estimation,
+      times.mode_decision,
+      times.transform,
+      times.entropy_coding,
+      times.loop_filters,
+    ] {
+      values.push(format!("{:.3}", time.as_micros() as f64 / 1000.0));
+    }
+    let null = if self.format == StatsFormat::Json { "null" } else { "" };
+    for &value in metric(stats.psnr)
+      .iter()
+      .chain(metric(stats.ssim).iter())
+      .chain(metric(stats.ms_ssim).iter())
+    {
+      values.push(match value {
+        Some(v) => format!("{:.4}", v),
+        None => null.to_string(),
+      });
+    }
+
+    match self.format {
+      StatsFormat::Csv => {
+        if self.records == 0 {
+          writeln!(self.writer, "{}", FRAME_STATS_FIELDS.join(","))?;
+        }
+        writeln!(self.writer, "{}", values.join(","))
+      }
+      StatsFormat::Json => {
+        let separator = if self.records == 0 { "[" } else { "," };
+        let fields: Vec<String> = FRAME_STATS_FIELDS
+          .iter()
+          .zip(values.iter())
+          .enumerate()
+          .map(|(i, (name, value))| match i {
+            // The frame types are the only strings
+            1 | 2 => format!("\"{}\":\"{}\"", name, value),
+            _ => format!("\"{}\":{}", name, value),
+          })
+          .collect();
+        writeln!(self.writer, "{}{{{}}}", separator, fields.join(","))
+      }
+    }
+  }
+}
+
+impl<W: io::Write + Send> EncoderObserver for FrameStatsLog<W> {
+  fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+    let _ = self.write_record(stats);
+    self.records += 1;
+  }
+}
+
+impl<W: io::Write + Send> Drop for FrameStatsLog<W> {
+  fn drop(&mut self) {
+    if self.format == StatsFormat::Json {
+      let _ = if self.records == 0 {
+        writeln!(self.writer, "[]")
+      } else {
+        writeln!(self.writer, "]")
+      };
+    }
+    let _ = self.writer.flush();
+  }
+}
+
+impl<T: Pixel> fmt::Display for Packet<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Frame {} - {} - {} bytes",
+      self.input_frameno,
+      self.frame_type,
+      self.data.len()
+    )
+  }
+}
+
+fn read_leb128(data: &[u8]) -> (u64, usize) {
+  let mut value = 0;
+  for (i, &byte) in data.iter().enumerate().take(8) {
+    value |= u64::from(byte & 0x7f) << (i * 7);
+    if byte & 0x80 == 0 {
+      return (value, i + 1);
+    }
+  }
+  panic!("Invalid leb128 value in the encoded OBUs");
+}
+
+/// Drops the OBUs of the temporal layers above `max_temporal_id` from
+/// encoded data in the low overhead format, such as the `data` of a
+/// `Packet` or a stream of them, keeping the operating point of the lower
+/// layers of an encode with `temporal_layers`. The temporal units left
+/// without a frame are dropped whole, temporal delimiter included.
+pub fn filter_temporal_layers(data: &[u8], max_temporal_id: u8) -> Vec<u8> {
+  let mut filtered = Vec::with_capacity(data.len());
+  let mut temporal_unit = Vec::new();
+  let mut has_frame = false;
+  let mut pos = 0;
+  while pos < data.len() {
+    let header = data[pos];
+    let obu_type = (header >> 3) & 0xf;
+    let has_extension = header & 0x04 != 0;
+    let header_len = 1 + has_extension as usize;
+    assert!(header & 0x02 != 0, "OBUs are expected to carry their size");
+    let (size, size_len) = read_leb128(&data[pos + header_len..]);
+    let end = pos + header_len + size_len + size as usize;
+
+    if obu_type == ObuType::OBU_TEMPORAL_DELIMITER as u8 {
+      if has_frame {
+        filtered.extend_from_slice(&temporal_unit);
+      }
+      temporal_unit.clear();
+      has_frame = false;
+    }
+    // OBUs without an extension belong to all the layers
+    let temporal_id = if has_extension { data[pos + 1] >> 5 } else { 0 };
+    if temporal_id <= max_temporal_id {
+      temporal_unit.extend_from_slice(&data[pos..end]);
+      has_frame |= obu_type == ObuType::OBU_FRAME_HEADER as u8
+        || obu_type == ObuType::OBU_FRAME as u8;
+    }
+    pos = end;
+  }
+  if has_frame {
+    filtered.extend_from_slice(&temporal_unit);
+  }
+  filtered
+}
+
+impl<T: Pixel> Context<T> {
+  /// Allocate a frame of the size of the encode.
+  ///
+  /// Frames sent to the encoder and no longer held by the caller are
+  /// recycled once encoded, so feeding every input through `new_frame`
+  /// avoids an allocation per frame. A recycled frame still holds the pixels
+  /// of an earlier input, which are to be overwritten.
+  pub fn new_frame(&self) -> Arc<Frame<T>> {
+    let inner = self.resized.back().map_or(&self.inner, |(_, inner)| inner);
+    let recycled = inner.frame_pool.lock().unwrap().pop();
+    Arc::new(recycled.unwrap_or_else(|| {
+      Frame::new(
+        self.config.width,
+        self.config.height,
+        self.config.chroma_sampling
+      )
+    }))
+  }
+
+  /// Send a frame, optionally along with parameters that only apply to it,
+  /// or `None` to flush the encoder. Fails if the parameters are invalid.
+  ///
+  /// A frame of another size than the previous ones starts a new sequence
+  /// at its size, see [`resize()`](#method.resize). Frames are allocated in
+  /// multiples of 8 pixels, so a size that is not is to be set with
+  /// `resize()` before sending the frame.
+  pub fn send_frame<F>(&mut self, frame: F) -> Result<(), EncoderStatus>
+  where
+    F: IntoFrame<T>,
+  {
+    let (frame, mut params) = frame.into_frame();
+
+    if !params.is_valid() {
+      return Err(EncoderStatus::Failure);
+    }
+
+    if let Some(frame) = frame.as_ref() {
+      let PlaneConfig { width, height, .. } = frame.planes[0].cfg;
+      if width != self.config.width.align_power_of_two(3)
+        || height != self.config.height.align_power_of_two(3)
+      {
+        self.resize(width, height)?;
+      }
+    }
+
+    if frame.is_none() && self.input_inner().frame_count == 0 {
+      // Nothing was sent since the last resize
+      self.resized.pop_back();
+    }
+
+    let offset = self.input_frameno_offset();
+    let inner = self.input_inner();
+    if frame.is_none() {
+        inner.limit = inner.frame_count;
+    } else if offset > 0 && params.pts.is_none() {
+      params.pts = Some(offset + inner.frame_count + inner.frames_dropped);
+    }
+
+    inner.send_frame(frame, params)
+  }
+
+  /// Code the frames sent from now on at another size, e.g. to lower the
+  /// resolution of a real-time stream under bandwidth pressure
+  ///
+  /// The frames already sent are coded at the previous size, then a new
+  /// sequence starts with a key frame at the new size. Its rate control
+  /// starts afresh, from the parameters currently in effect. Resizing is
+  /// not supported by two-pass encodes nor still pictures, and fails if
+  /// the level of the stream does not allow the new size.
+  pub fn resize(
+    &mut self, width: usize, height: usize
+  ) -> Result<(), EncoderStatus> {
+    if width == self.config.width && height == self.config.height {
+      return Ok(());
+    }
+    if self.config.pass.is_some() || self.config.still_picture {
+      return Err(EncoderStatus::Failure);
+    }
+    let mut config = self.config.clone();
+    config.width = width;
+    config.height = height;
+    config.validate().or(Err(EncoderStatus::Failure))?;
+    let config = sequence_config(&config).or(Err(EncoderStatus::Failure))?;
+
+    let offset = self.input_frameno_offset();
+    let prev = self.input_inner();
+    info!(
+      "Resizing to {}x{} from input frame {}",
+      width,
+      height,
+      offset + prev.frame_count
+    );
+    let mut inner = ContextInner::new(&config);
+    inner.frames_dropped = prev.frames_dropped;
+    if prev.frame_count == 0 {
+      // Nothing was sent at the previous size
+      inner.observer = prev.observer.take();
+      *prev = inner;
+    } else {
+      prev.limit = prev.frame_count;
+      let offset = offset + prev.frame_count;
+      self.resized.push_back((offset, inner));
+    }
+    self.config = config;
+    Ok(())
+  }
+
+  /// The sequence being coded, then the ones waiting for it to complete
+  fn inners_mut(&mut self) -> impl Iterator<Item = &mut ContextInner<T>> {
+    std::iter::once(&mut self.inner)
+      .chain(self.resized.iter_mut().map(|(_, inner)| inner))
+  }
+
+  /// The sequence the frames sent are added to
+  fn input_inner(&mut self) -> &mut ContextInner<T> {
+    match self.resized.back_mut() {
+      Some((_, inner)) => inner,
+      None => &mut self.inner
+    }
+  }
+
+  /// Input frame number of the first frame of `input_inner()`
+  fn input_frameno_offset(&self) -> u64 {
+    self.resized.back().map_or(self.input_frameno_offset, |&(offset, _)| offset)
+  }
+
+  /// Retrieve the first-pass data of a two-pass encode for the frame that was
+  /// just encoded. This should be called BEFORE every call to receive_packet()
+  /// (including the very first one), even if no packet was produced by the
+  /// last call to receive_packet, if any (i.e., *EncoderStatus::Encoded* was
+  /// returned). It needs to be called once more after
+  /// *EncoderStatus::LimitReached* is returned, to retrieve the header that
+  /// should be written to the front of the stats file (overwriting the
+  /// placeholder header that was emitted at the start of encoding).
+  ///
+  /// It is still safe to call this function when receive_packet() returns any
+  /// other error. It will return *None* instead of returning a duplicate copy
   /// of the previous frame's data.
   pub fn twopass_out(&mut self) -> Option<&[u8]> {
     let params = self.inner.rc_state.get_twopass_out_params(&self.inner);
@@ -783,17 +2282,143 @@ impl<T: Pixel> Context<T> {
     self.inner.rc_state.twopass_in(Some(buf)).or(Err(EncoderStatus::Failure))
   }
 
-  pub fn receive_packet(&mut self) -> Result<Packet<T>, EncoderStatus> {
-    let inner = &mut self.inner;
-    let pool = &mut self.pool;
+  /// Retrieve the rate control state adapted so far, to prime the encode of
+  /// the next chunk of the same video with rate_summary_in(). This lets
+  /// encoders splitting a video into chunks keep a consistent allocation
+  /// across the chunk boundaries.
+  pub fn rate_summary(&self) -> Vec<u8> {
+    self.inner.rc_state.summary()
+  }
+
+  /// Prime the rate control with the summary of the previous chunk. This
+  /// must be called before the first call to receive_packet(), and is only
+  /// supported by single-pass encodes.
+  pub fn rate_summary_in(&mut self, buf: &[u8]) -> Result<(), EncoderStatus> {
+    self.inner.rc_state.restore_summary(buf).or(Err(EncoderStatus::Failure))
+  }
 
-    pool.install(|| inner.receive_packet())
+  pub fn receive_packet(&mut self) -> Result<Packet<T>, EncoderStatus> {
+    loop {
+      let inner = &mut self.inner;
+      let pool = &mut self.pool;
+
+      match pool.install(|| inner.receive_packet()) {
+        Err(EncoderStatus::LimitReached) if !self.resized.is_empty() => {
+          // The sequence at the previous size is complete
+          let (offset, mut next) = self.resized.pop_front().unwrap();
+          next.observer = self.inner.observer.take();
+          self.inner = next;
+          self.input_frameno_offset = offset;
+        }
+        ret => {
+          let offset = self.input_frameno_offset;
+          return ret.map(|mut pkt| {
+            pkt.input_frameno += offset;
+            for frameno in pkt.references.iter_mut() {
+              *frameno += offset;
+            }
+            pkt
+          });
+        }
+      }
+    }
   }
 
   pub fn flush(&mut self) {
     self.send_frame(None).unwrap();
   }
 
+  /// The rate control parameters currently in effect
+  pub fn rate_parameters(&self) -> RateParameters {
+    RateParameters {
+      bitrate: self.config.bitrate,
+      quantizer: self.config.quantizer,
+      min_quantizer: self.config.min_quantizer,
+    }
+  }
+
+  /// Tell the encoder that a source frame was dropped instead of sent, e.g.
+  /// by a live capture falling behind
+  ///
+  /// Nothing is coded for it: the bits it would have used go to the frames
+  /// that follow, and frames sent without an explicit `pts` are stamped
+  /// past it, so the gap stays in the timing of the stream.
+  pub fn drop_frame(&mut self) {
+    let inner = self.input_inner();
+    inner.frames_dropped += 1;
+    inner.rc_state.skip_tu();
+  }
+
+  /// Change the rate control parameters, from the next frame the encoder
+  /// codes on, without restarting the encode
+  ///
+  /// Frames already coded, including hidden frames shown later, keep their
+  /// quantizers. The change is rejected for two-pass encodes, for bitrates
+  /// beyond the level of the stream and when the decoder model signaled in
+  /// the sequence header depends on the bitrate.
+  pub fn set_parameters(&mut self, params: RateParameters) -> Result<(), EncoderStatus> {
+    if params.quantizer > 255 || params.bitrate < 0 {
+      return Err(EncoderStatus::Failure);
+    }
+    if params.bitrate != self.config.bitrate {
+      if self.config.decoder_model_info && self.config.bitrate > 0 {
+        return Err(EncoderStatus::Failure);
+      }
+      let mut config = self.config.clone();
+      config.bitrate = params.bitrate;
+      select_level(&config).or(Err(EncoderStatus::Failure))?;
+
+      let time_base = self.config.time_base;
+      for inner in self.inners_mut() {
+        inner
+          .rc_state
+          .set_target_bitrate(
+            params.bitrate,
+            time_base.den as i64,
+            time_base.num as i64
+          )
+          .or(Err(EncoderStatus::Failure))?;
+      }
+    }
+    let maybe_ac_qi_max = if params.quantizer < 255 {
+      Some(params.quantizer as u8)
+    } else {
+      None
+    };
+    for inner in self.inners_mut() {
+      inner.rc_state.set_ac_qi_range(params.min_quantizer, maybe_ac_qi_max);
+      inner.config.bitrate = params.bitrate;
+      inner.config.quantizer = params.quantizer;
+      inner.config.min_quantizer = params.min_quantizer;
+    }
+    self.config.bitrate = params.bitrate;
+    self.config.quantizer = params.quantizer;
+    self.config.min_quantizer = params.min_quantizer;
+    Ok(())
+  }
+
+  /// Change the target bitrate, in bits per second, of the frames encoded
+  /// from now on, 0 for a constant quantizer
+  ///
+  /// See [`set_parameters()`](#method.set_parameters).
+  pub fn set_bitrate(&mut self, bitrate: i32) -> Result<(), EncoderStatus> {
+    self.set_parameters(RateParameters { bitrate, ..self.rate_parameters() })
+  }
+
+  /// Change the quantizer of the frames encoded from now on, 0-255
+  ///
+  /// With a target bitrate this is the maximum quantizer instead.
+  pub fn set_quantizer(&mut self, quantizer: usize) -> Result<(), EncoderStatus> {
+    self.set_parameters(RateParameters { quantizer, ..self.rate_parameters() })
+  }
+
+  /// Set a receiver of the statistics of every frame coded from now on
+  pub fn set_observer(&mut self, observer: Box<dyn EncoderObserver>) {
+    self.inner.observer = Some(observer);
+  }
+
+  /// The AV1 codec configuration record (av1C) of the stream, for
+  /// containers, with the sequence header OBU as its configOBUs
   pub fn container_sequence_header(&mut self) -> Vec<u8> {
     fn sequence_header_inner(seq: &Sequence) -> io::Result<Vec<u8>> {
       let mut buf = Vec::new();
@@ -803,14 +2428,14 @@ impl<T: Pixel> Context<T> {
         bw.write_bit(true)?; // marker
         bw.write(7, 1)?; // version
         bw.write(3, seq.profile)?;
-        bw.write(5, 31)?; // level
-        bw.write_bit(false)?; // tier
+        bw.write(5, seq.seq_level_idx[0])?;
+        bw.write_bit(seq.tier[0])?;
         bw.write_bit(seq.bit_depth > 8)?; // high_bitdepth
         bw.write_bit(seq.bit_depth == 12)?; // twelve_bit
-        bw.write_bit(seq.bit_depth == 1)?; // monochrome
-        bw.write_bit(seq.bit_depth == 12)?; // twelve_bit
+        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs400)?; // monochrome
         bw.write_bit(seq.chroma_sampling != ChromaSampling::Cs444)?; // chroma_subsampling_x
-        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs420)?; // chroma_subsampling_y
+        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs420 ||
+          seq.chroma_sampling == ChromaSampling::Cs400)?; // chroma_subsampling_y
         bw.write(2, 0)?; // sample_position
         bw.write(3, 0)?; // reserved
         bw.write_bit(false)?; // initial_presentation_delay_present
@@ -822,15 +2447,205 @@ impl<T: Pixel> Context<T> {
     }
 
     let seq = Sequence::new(&self.config);
+    let fi = FrameInvariants::<T>::new(self.config.clone(), seq);
 
-    sequence_header_inner(&seq).unwrap()
+    let mut config = sequence_header_inner(&seq).unwrap();
+    // configOBUs
+    config.extend(sequence_header_obu(&fi).unwrap());
+    config
   }
 
   pub fn get_first_pass_data(&self) -> &FirstPassData {
     &self.inner.first_pass_data
   }
+
+  /// Number of frames that were sent to the encoder but not yet returned
+  /// in a packet.
+  pub fn frames_queued(&self) -> u64 {
+    let inner = &self.inner;
+    inner.frame_count - inner.frames_processed
+      + self.resized.iter().map(|(_, inner)| inner.frame_count).sum::<u64>()
+  }
+
+  /// Number of frames to send after a frame before its packet can be
+  /// received, without flushing: the lookahead of the scene change
+  /// detection and rate control, and the frames reordered ahead of it. It
+  /// is 0 with `zero_delay` and `all_intra`.
+  pub fn frame_delay(&self) -> u64 {
+    self.inner.frame_delay()
+  }
+
+  /// Most frames held by the encoder at once, from their sending until
+  /// the packet showing them is received, e.g. to size the buffers of the
+  /// input frames and their parameters.
+  pub fn max_latency_frames(&self) -> u64 {
+    self.frame_delay() + 1
+  }
+
+  /// Fraction of the rate control buffer that is currently available, in
+  /// the range 0.0 to 1.0. Returns `None` when not encoding to a bitrate.
+  pub fn buffer_fullness(&self) -> Option<f64> {
+    self.inner.rc_state.buffer_fullness()
+  }
+}
+
+/// Whether no sample of `b` differs from `a` by more than `threshold`
+fn frames_match<T: Pixel>(a: &Frame<T>, b: &Frame<T>, threshold: u16) -> bool {
+  let threshold = i32::from(threshold);
+  a.planes.iter().zip(b.planes.iter()).all(|(pa, pb)| {
+    pa.iter().zip(pb.iter()).all(|(sa, sb)| {
+      (i32::cast_from(sa) - i32::cast_from(sb)).abs() <= threshold
+    })
+  })
+}
+
+/// The frame invariants of every sequence of a `Context`, in a fixed order
+#[cfg(feature = "serialize")]
+fn frame_invariants<'a, T: Pixel>(
+  inner: &'a ContextInner<T>, resized: &'a VecDeque<(u64, ContextInner<T>)>
+) -> impl Iterator<Item = &'a FrameInvariants<T>> {
+  std::iter::once(inner)
+    .chain(resized.iter().map(|(_, inner)| inner))
+    .flat_map(|inner| inner.frame_invariants.values())
+}
+
+/// Mutable counterpart of `frame_invariants`, in the same order
+#[cfg(feature = "serialize")]
+fn frame_invariants_mut<'a, T: Pixel>(
+  inner: &'a mut ContextInner<T>,
+  resized: &'a mut VecDeque<(u64, ContextInner<T>)>
+) -> impl Iterator<Item = &'a mut FrameInvariants<T>> {
+  std::iter::once(inner)
+    .chain(resized.iter_mut().map(|(_, inner)| inner))
+    .flat_map(|inner| inner.frame_invariants.values_mut())
+}
+
+/// The reference frames of every frame invariants, each listed once, and the
+/// index in that list of the frame in every slot of every frame invariants
+#[cfg(feature = "serialize")]
+fn reference_slots<'a, T: Pixel>(
+  inner: &'a ContextInner<T>, resized: &'a VecDeque<(u64, ContextInner<T>)>
+) -> (Vec<&'a Arc<ReferenceFrame<T>>>, Vec<[Option<usize>; REF_FRAMES]>) {
+  let mut ref_frames: Vec<&Arc<ReferenceFrame<T>>> = Vec::new();
+  let ref_slots = frame_invariants(inner, resized)
+    .map(|fi| {
+      let mut slots = [None; REF_FRAMES];
+      for (slot, frame) in slots.iter_mut().zip(fi.rec_buffer.frames.iter()) {
+        *slot = frame.as_ref().map(|frame| {
+          match ref_frames.iter().position(|f| Arc::ptr_eq(f, frame)) {
+            Some(index) => index,
+            None => {
+              ref_frames.push(frame);
+              ref_frames.len() - 1
+            }
+          }
+        });
+      }
+      slots
+    })
+    .collect();
+  (ref_frames, ref_slots)
+}
+
+/// State of a `Context` as it is serialized, borrowed from the context
+#[cfg(feature = "serialize")]
+#[derive(Serialize)]
+struct ContextStateRef<'a, T: Pixel> {
+  inner: &'a ContextInner<T>,
+  resized: &'a VecDeque<(u64, ContextInner<T>)>,
+  ref_frames: Vec<&'a ReferenceFrame<T>>,
+  ref_slots: Vec<[Option<usize>; REF_FRAMES]>,
+  input_frameno_offset: u64,
+  config: &'a EncoderConfig,
+  threads: usize,
+}
+
+/// State of a `Context` as it is deserialized
+#[cfg(feature = "serialize")]
+#[derive(Deserialize)]
+struct ContextState<T: Pixel> {
+  inner: ContextInner<T>,
+  resized: VecDeque<(u64, ContextInner<T>)>,
+  ref_frames: Vec<ReferenceFrame<T>>,
+  ref_slots: Vec<[Option<usize>; REF_FRAMES]>,
+  input_frameno_offset: u64,
+  config: EncoderConfig,
+  threads: usize,
+}
+
+/// The complete state of the encoder, for a long encode to be checkpointed
+/// and resumed after an interruption, continuing the stream bit-exactly.
+///
+/// The observer set with `set_observer` is not part of the state and has to
+/// be set again on the resumed context. The state is only meant to be read
+/// back by the same build of the encoder.
+#[cfg(feature = "serialize")]
+impl<T: Pixel + serde::Serialize> serde::Serialize for Context<T> {
+  fn serialize<S: serde::Serializer>(
+    &self, serializer: S
+  ) -> Result<S::Ok, S::Error> {
+    let (ref_frames, ref_slots) =
+      reference_slots(&self.inner, &self.resized);
+    let state = ContextStateRef {
+      inner: &self.inner,
+      resized: &self.resized,
+      ref_frames: ref_frames.into_iter().map(|frame| &**frame).collect(),
+      ref_slots,
+      input_frameno_offset: self.input_frameno_offset,
+      config: &self.config,
+      threads: self.pool.current_num_threads(),
+    };
+    serde::Serialize::serialize(&state, serializer)
+  }
 }
 
+#[cfg(feature = "serialize")]
+impl<'de, T: Pixel + serde::Deserialize<'de>> serde::Deserialize<'de>
+  for Context<T>
+{
+  fn deserialize<D: serde::Deserializer<'de>>(
+    deserializer: D
+  ) -> Result<Self, D::Error> {
+    use serde::de::Error;
+
+    let mut state =
+      <ContextState<T> as serde::Deserialize>::deserialize(deserializer)?;
+    let ref_frames: Vec<_> =
+      state.ref_frames.into_iter().map(Arc::new).collect();
+    let mut ref_slots = state.ref_slots.iter();
+    for fi in frame_invariants_mut(&mut state.inner, &mut state.resized) {
+      let slots = ref_slots
+        .next()
+        .ok_or_else(|| D::Error::custom("missing reference frame slots"))?;
+      for (frame, slot) in fi.rec_buffer.frames.iter_mut().zip(slots.iter()) {
+        *frame = match *slot {
+          Some(index) => Some(
+            ref_frames
+              .get(index)
+              .ok_or_else(|| D::Error::custom("invalid reference frame"))?
+              .clone()
+          ),
+          None => None
+        };
+      }
+    }
+    if ref_slots.next().is_some() {
+      return Err(D::Error::custom("unused reference frame slots"));
+    }
+    let pool = ThreadPoolBuilder::new()
+      .num_threads(state.threads)
+      .build()
+      .map_err(serde::de::Error::custom)?;
+
+    Ok(Context {
+      inner: state.inner,
+      resized: state.resized,
+      input_frameno_offset: state.input_frameno_offset,
+      config: state.config,
+      pool
+    })
+  }
+}
 
 impl<T: Pixel> ContextInner<T> {
   pub fn new(enc: &EncoderConfig) -> Self {
@@ -855,7 +2670,22 @@ impl<T: Pixel> ContextInner<T> {
         packet_data,
         segment_output_frameno_start: 0,
         segment_input_frameno_start: 0,
-        keyframe_detector: SceneChangeDetector::new(enc.bit_depth),
+        keyframe_detector: SceneChangeDetector::new(
+          enc.bit_depth,
+          enc.scenecut_sensitivity
+        ),
+        denoiser: if enc.denoise_strength > 0 {
+          Some(Denoiser::new(enc.denoise_strength, enc.bit_depth))
+        } else {
+          None
+        },
+        film_grain_params: BTreeMap::new(),
+        frame_params: BTreeMap::new(),
+        frame_pool: Mutex::new(Vec::new()),
+        frames_dropped: 0,
+        last_input_frame: None,
+        duplicate_frames: BTreeSet::new(),
+        observer: None,
         config: enc.clone(),
         rc_state: RCState::new(
           enc.width as i32,
@@ -873,19 +2703,77 @@ impl<T: Pixel> ContextInner<T> {
     }
   }
 
-  pub fn send_frame<F>(&mut self, frame: F) -> Result<(), EncoderStatus>
-  where
-    F: Into<Option<Arc<Frame<T>>>>
-  {
+  pub fn send_frame(
+    &mut self, frame: Option<Arc<Frame<T>>>, params: FrameParameters
+  ) -> Result<(), EncoderStatus> {
     let input_frameno = self.frame_count;
-    let frame = frame.into();
     if frame.is_some() {
+      let mut params = params;
+      if params.pts.is_none() && self.frames_dropped > 0 {
+        params.pts = Some(input_frameno + self.frames_dropped);
+      }
       self.frame_count += 1;
+      self.frame_params.insert(input_frameno, Arc::new(params));
+    }
+    let frame = frame.map(|frame| self.pad_input(frame));
+    if let (Some(threshold), Some(frame)) =
+      (self.config.duplicate_frame_threshold, frame.as_ref())
+    {
+      if let Some(last) = self.last_input_frame.as_ref() {
+        if frames_match(last, frame, threshold) {
+          self.duplicate_frames.insert(input_frameno);
+        }
+      }
+      if !self.duplicate_frames.contains(&input_frameno) {
+        self.last_input_frame = Some(frame.clone());
+      }
+    }
+    let frame = match (frame, self.denoiser.as_mut()) {
+      (Some(frame), Some(denoiser)) => {
+        let (frame, mut params) = denoiser.denoise(&frame);
+        params.random_seed = FilmGrainParams::seed_for_frame(input_frameno);
+        self.film_grain_params.insert(input_frameno, params);
+        Some(frame)
+      }
+      (frame, _) => frame
+    };
+    if let (Some(table), Some(_)) =
+      (self.config.film_grain_table.as_ref(), frame.as_ref())
+    {
+      self.film_grain_params.remove(&input_frameno);
+      let pts = self.frame_params[&input_frameno].pts.unwrap_or(input_frameno);
+      let time_base = self.config.time_base;
+      let time = (pts as u128 * time_base.num as u128
+        * FilmGrainTable::TIME_SCALE as u128
+        / time_base.den as u128) as u64;
+      if let Some(params) = table.params_at(time) {
+        let mut params = params.clone();
+        // Vary the seed of the segment so that its frames do not repeat
+        // the same grain pattern
+        params.random_seed = params
+          .random_seed
+          .wrapping_add(FilmGrainParams::seed_for_frame(input_frameno));
+        self.film_grain_params.insert(input_frameno, params);
+      }
     }
     self.frame_q.insert(input_frameno, frame);
     Ok(())
   }
 
+  /// Frames are coded in units of 8x8 pixels: replicate the right and
+  /// bottom edges of a frame of any other size up to the next multiple of
+  /// 8, where the decoder crops the coded pixels.
+  fn pad_input(&self, frame: Arc<Frame<T>>) -> Arc<Frame<T>> {
+    let (width, height) = (self.config.width, self.config.height);
+    if width & 7 == 0 && height & 7 == 0 {
+      return frame;
+    }
+    let mut frame =
+      Arc::try_unwrap(frame).unwrap_or_else(|frame| (*frame).clone());
+    frame.pad(width, height);
+    Arc::new(frame)
+  }
+
   fn get_frame(&self, input_frameno: u64) -> Arc<Frame<T>> {
     // Clones only the arc, so low cost overhead
     self.frame_q.get(&input_frameno)
@@ -893,7 +2781,20 @@ impl<T: Pixel> ContextInner<T> {
   }
 
   pub(crate) fn needs_more_lookahead(&self) -> bool {
-    self.needs_more_frames(self.frame_count) && self.frames_processed + LOOKAHEAD_FRAMES > self.frame_q.keys().last().cloned().unwrap_or(0)
+    self.needs_more_frames(self.frame_count) && self.frames_processed + self.lookahead_frames() > self.frame_q.keys().last().cloned().unwrap_or(0)
+  }
+
+  fn lookahead_frames(&self) -> u64 {
+    if self.config.zero_delay || self.config.all_intra {
+      0
+    } else {
+      LOOKAHEAD_FRAMES
+    }
+  }
+
+  pub(crate) fn frame_delay(&self) -> u64 {
+    // The last frame of a reordered group is coded before the first one
+    self.lookahead_frames().max(self.inter_cfg.group_input_len - 1)
   }
 
   pub fn needs_more_frames(&self, frame_count: u64) -> bool {
@@ -907,8 +2808,22 @@ impl<T: Pixel> ContextInner<T> {
         fi.frame_type == FrameType::KEY
          && fi.input_frameno > segment_input_frameno_start
       }).map(|fi| fi.input_frameno);
-    let mut next_limit =
-     segment_input_frameno_start + self.config.max_key_frame_interval;
+    // Forced key frames are known ahead, so that the frame groups end there
+    let next_forced = self.frame_params
+      .range(segment_input_frameno_start + 1..)
+      .find(|(_, params)| {
+        params.frame_type_override == Some(FrameTypeOverride::Key)
+      }).map(|(&input_frameno, _)| input_frameno);
+    let next_detected = match (next_detected, next_forced) {
+      (Some(detected), Some(forced)) => Some(detected.min(forced)),
+      (detected, forced) => detected.or(forced),
+    };
+    let max_interval = if self.config.all_intra {
+      1
+    } else {
+      self.config.max_key_frame_interval
+    };
+    let mut next_limit = segment_input_frameno_start + max_interval;
     if !ignore_limit && self.limit != 0 {
       next_limit = next_limit.min(self.limit);
     }
@@ -994,6 +2909,23 @@ impl<T: Pixel> ContextInner<T> {
     if output_frameno_in_segment == 0 {
       fi = FrameInvariants::new_key_frame(&fi,
        self.segment_input_frameno_start);
+      if self.config.detect_screen_content
+        && fi.allow_screen_content_tools == 0
+      {
+        // Vote over the frames of the segment known so far, so that a
+        // single frame does not decide for the whole segment
+        let end = self.next_keyframe_input_frameno(fi.input_frameno, false)
+          .min(fi.input_frameno + self.lookahead_frames() + 1);
+        let (mut votes, mut frames) = (0, 0);
+        for frame in self.frame_q.range(fi.input_frameno..end)
+          .filter_map(|(_, frame)| frame.as_ref())
+        {
+          votes += is_screen_content(&frame.planes[0], self.config.bit_depth)
+            as usize;
+          frames += 1;
+        }
+        fi.set_screen_content(votes * 2 > frames);
+      }
     } else {
       let next_keyframe_input_frameno = self.next_keyframe_input_frameno(
        self.segment_input_frameno_start, false);
@@ -1008,6 +2940,15 @@ impl<T: Pixel> ContextInner<T> {
       if !end_of_subgop {
         return Ok((fi, false));
       }
+      if frame_type == FrameType::INTRA_ONLY && !fi.show_existing_frame {
+        fi.set_intra_only();
+      }
+    }
+    if let Some(params) = self.frame_params.get(&fi.input_frameno) {
+      fi.set_reference_control(params);
+    }
+    if self.duplicate_frames.contains(&fi.input_frameno) {
+      fi.set_duplicate_ref();
     }
     Ok((fi, true))
   }
@@ -1035,6 +2976,7 @@ impl<T: Pixel> ContextInner<T> {
     }
 
     let cur_output_frameno = self.output_frameno;
+    let start = timing::now();
 
     let ret = {
       let fi = self.frame_invariants.get_mut(&cur_output_frameno).unwrap();
@@ -1042,6 +2984,7 @@ impl<T: Pixel> ContextInner<T> {
         if !self.rc_state.ready() {
           return Err(EncoderStatus::NotReady);
         }
+        fi.frame_params = self.frame_params.get(&fi.input_frameno).cloned();
         let mut fs = FrameState::new(fi);
 
         let sef_data = encode_show_existing_frame(fi, &mut fs);
@@ -1058,7 +3001,13 @@ impl<T: Pixel> ContextInner<T> {
         let rec = if fi.show_frame { Some(fs.rec) } else { None };
         let fi = fi.clone();
         self.output_frameno += 1;
-        self.finalize_packet(rec, &fi)
+        let pkt = self.finalize_packet(rec, None, &fi);
+        let mut stats = FrameEncodeStats::new(&fi, bits as u64, start);
+        if let Ok(ref pkt) = pkt {
+          stats.set_metrics(pkt);
+        }
+        self.notify_observer(&stats);
+        pkt
       } else if let Some(f) = self.frame_q.get(&fi.input_frameno) {
         if !self.rc_state.ready() {
           return Err(EncoderStatus::NotReady);
@@ -1069,6 +3018,11 @@ impl<T: Pixel> ContextInner<T> {
             self.rc_state.select_qi(self, fti, self.maybe_prev_log_base_q);
           let fi = self.frame_invariants.get_mut(&cur_output_frameno).unwrap();
           fi.set_quantizers(&qps);
+          fi.set_superres();
+          fi.set_cdf_inheritance();
+          fi.film_grain_params =
+            self.film_grain_params.get(&fi.input_frameno).cloned();
+          fi.frame_params = self.frame_params.get(&fi.input_frameno).cloned();
 
           if self.rc_state.needs_trial_encode(fti) {
             let mut fs = FrameState::new_with_frame(fi, frame.clone());
@@ -1086,12 +3040,37 @@ impl<T: Pixel> ContextInner<T> {
             let fi =
              self.frame_invariants.get_mut(&cur_output_frameno).unwrap();
             fi.set_quantizers(&qps);
+            fi.set_superres();
+            fi.set_cdf_inheritance();
           }
 
           let fi = self.frame_invariants.get_mut(&cur_output_frameno).unwrap();
           let mut fs = FrameState::new_with_frame(fi, frame.clone());
-          let data = encode_frame(fi, &mut fs);
+          let mut data = encode_frame(fi, &mut fs);
+          // Scene cuts can make key frames overshoot their target by far
+          let mut qps = qps;
+          if fi.frame_type == FrameType::KEY {
+            for _ in 0..self.config.keyframe_max_reencodes {
+              match self.rc_state.reencode_qi(
+                &qps,
+                fti,
+                (data.len() * 8) as i64,
+                self.config.keyframe_overshoot_pct,
+                self.config.max_quantizer,
+                self.config.bit_depth
+              ) {
+                Some(reencode_qps) => qps = reencode_qps,
+                None => break
+              }
+              fi.set_quantizers(&qps);
+              fi.set_superres();
+              fi.set_cdf_inheritance();
+              fs = FrameState::new_with_frame(fi, frame.clone());
+              data = encode_frame(fi, &mut fs);
+            }
+          }
           self.maybe_prev_log_base_q = Some(qps.log_base_q);
+          let bits = data.len() as u64 * 8;
           // TODO: Add support for dropping frames.
           self.rc_state.update_state(
             (data.len() * 8) as i64,
@@ -1103,19 +3082,44 @@ impl<T: Pixel> ContextInner<T> {
           );
           self.packet_data.extend(data);
 
-          fs.rec.pad(fi.width, fi.height);
+          if fi.superres_denom != SUPERRES_NUM {
+            fs.superres_upscale(fi, frame.clone());
+          }
+          fs.rec.pad(fi.upscaled_width, fi.height);
 
           // TODO avoid the clone by having rec Arc.
           let rec = if fi.show_frame { Some(fs.rec.clone()) } else { None };
+          let prediction = if fi.superres_denom == SUPERRES_NUM {
+            fs.pred.take()
+          } else {
+            None
+          };
 
+          let me_cost = fs.me_cost;
+          let stage_times = fs.stage_times;
+          let blocks = std::mem::replace(&mut fs.block_decisions, Vec::new());
           update_rec_buffer(fi, fs);
 
           self.output_frameno += 1;
 
           if fi.show_frame {
             let fi = fi.clone();
-            self.finalize_packet(rec, &fi)
+            let pkt = self.finalize_packet(rec, prediction, &fi);
+            let mut stats = FrameEncodeStats::new(&fi, bits, start);
+            stats.me_cost = me_cost;
+            stats.stage_times = stage_times;
+            stats.blocks = blocks;
+            if let Ok(ref pkt) = pkt {
+              stats.set_metrics(pkt);
+            }
+            self.notify_observer(&stats);
+            pkt
           } else {
+            let mut stats = FrameEncodeStats::new(fi, bits, start);
+            stats.me_cost = me_cost;
+            stats.stage_times = stage_times;
+            stats.blocks = blocks;
+            self.notify_observer(&stats);
             Err(EncoderStatus::Encoded)
           }
         } else {
@@ -1133,7 +3137,10 @@ impl<T: Pixel> ContextInner<T> {
     ret
   }
 
-  fn finalize_packet(&mut self, rec: Option<Frame<T>>, fi: &FrameInvariants<T>) -> Result<Packet<T>, EncoderStatus> {
+  fn finalize_packet(
+    &mut self, rec: Option<Frame<T>>, prediction: Option<Frame<T>>,
+    fi: &FrameInvariants<T>
+  ) -> Result<Packet<T>, EncoderStatus> {
     let data = self.packet_data.clone();
     self.packet_data.clear();
     if write_temporal_delimiter(&mut self.packet_data).is_err() {
@@ -1141,14 +3148,20 @@ impl<T: Pixel> ContextInner<T> {
     }
 
     let mut psnr = None;
-    if self.config.show_psnr {
-      if let Some(ref rec) = rec {
+    let mut ssim = None;
+    let mut ms_ssim = None;
+    if let Some(ref rec) = rec {
+      let bit_depth = fi.sequence.bit_depth;
+      if self.config.show_psnr || self.config.show_ssim {
         let original_frame = self.get_frame(fi.input_frameno);
-        psnr = Some(calculate_frame_psnr(
-          &*original_frame,
-          rec,
-          fi.sequence.bit_depth
-        ));
+        if self.config.show_psnr {
+          psnr = Some(calculate_frame_psnr(&*original_frame, rec, bit_depth));
+        }
+        if self.config.show_ssim {
+          ssim = Some(calculate_frame_ssim(&*original_frame, rec, bit_depth));
+          ms_ssim =
+            Some(calculate_frame_ms_ssim(&*original_frame, rec, bit_depth));
+        }
       }
     }
 
@@ -1156,22 +3169,51 @@ impl<T: Pixel> ContextInner<T> {
       self.first_pass_data.frames.push(FirstPassFrame::from(fi));
     }
 
+    let pts = self
+      .frame_params
+      .get(&fi.input_frameno)
+      .and_then(|params| params.pts)
+      .unwrap_or(fi.input_frameno);
+
     self.frames_processed += 1;
     Ok(Packet {
       data,
       rec,
+      prediction,
       input_frameno: fi.input_frameno,
+      pts,
       frame_type: fi.frame_type,
-      psnr
+      frame_subtype: FrameSubtype::of(fi),
+      qp: if fi.show_existing_frame { 0 } else { fi.base_q_idx },
+      temporal_layer: if fi.config.temporal_layers > 1 {
+        fi.temporal_id as u64
+      } else {
+        fi.pyramid_level
+      },
+      references: fi.reference_framenos(),
+      psnr,
+      ssim,
+      ms_ssim
     })
   }
 
+  fn notify_observer(&mut self, stats: &FrameEncodeStats) {
+    if let Some(observer) = self.observer.as_mut() {
+      observer.frame_encoded(stats);
+    }
+  }
+
   fn garbage_collect(&mut self, cur_input_frameno: u64) {
     if cur_input_frameno == 0 {
       return;
     }
     for i in 0..cur_input_frameno {
-      self.frame_q.remove(&i);
+      if let Some(Some(frame)) = self.frame_q.remove(&i) {
+        self.recycle_frame(frame);
+      }
+      self.film_grain_params.remove(&i);
+      self.frame_params.remove(&i);
+      self.duplicate_frames.remove(&i);
     }
     if self.output_frameno < 2 {
       return;
@@ -1181,39 +3223,86 @@ impl<T: Pixel> ContextInner<T> {
     }
   }
 
+  /// Returns a frame to the pool, unless it is still referenced elsewhere or
+  /// the pool is full
+  fn recycle_frame(&mut self, frame: Arc<Frame<T>>) {
+    let pool = self.frame_pool.get_mut().unwrap();
+    if pool.len() >= MAX_POOLED_FRAMES {
+      return;
+    }
+    if let Ok(frame) = Arc::try_unwrap(frame) {
+      let luma = &frame.planes[0].cfg;
+      if luma.width == self.config.width && luma.height == self.config.height
+      {
+        pool.push(frame);
+      }
+    }
+  }
+
   fn determine_frame_type(&mut self, input_frameno: u64) -> FrameType {
-    if input_frameno == 0 {
+    if input_frameno == 0 || self.config.all_intra {
       return FrameType::KEY;
     }
-    if self.config.speed_settings.no_scene_detection {
-      if input_frameno % self.config.max_key_frame_interval == 0 {
-        return FrameType::KEY;
-      } else {
-        return FrameType::INTER;
+    let frame_type_override = self
+      .frame_params
+      .get(&input_frameno)
+      .and_then(|params| params.frame_type_override);
+    if frame_type_override == Some(FrameTypeOverride::Key) {
+      // Keep the detector comparing consecutive frames
+      if let Some(Some(frame)) = self.frame_q.get(&input_frameno) {
+        self.keyframe_detector.set_last_frame(frame.clone(), input_frameno as usize);
       }
+      return FrameType::KEY;
     }
-
+    // The intra refresh wave replaces the key frames
+    if self.config.intra_refresh.is_some() {
+      return FrameType::INTER;
+    }
+    // The intervals count from the last key frame, whether it was placed at
+    // a scene change, at the maximum interval or forced
     let prev_keyframe_input_frameno = self.keyframes.iter()
       .rfind(|&&keyframe_input_frameno| keyframe_input_frameno < input_frameno)
       .cloned()
       .unwrap_or(0);
+    let distance = input_frameno - prev_keyframe_input_frameno;
+    if self.config.speed_settings.no_scene_detection
+      || self.config.scenecut_sensitivity == 0
+    {
+      if distance >= self.config.max_key_frame_interval {
+        return FrameType::KEY;
+      } else {
+        return FrameType::INTER;
+      }
+    }
+
     let frame = match self.frame_q.get(&input_frameno).cloned() {
       Some(frame) => frame,
       None => { return FrameType::KEY; }
     };
     if let Some(frame) = frame {
-      let distance = input_frameno - prev_keyframe_input_frameno;
-      if distance < self.config.min_key_frame_interval {
+      if distance < self.config.min_key_frame_interval
+        && !self.config.intra_only_scene_changes
+      {
         if distance + 1 == self.config.min_key_frame_interval {
           self.keyframe_detector.set_last_frame(frame, input_frameno as usize);
         }
         return FrameType::INTER;
       }
       if distance >= self.config.max_key_frame_interval {
+        // A cut right after it is still detected
+        self.keyframe_detector.set_last_frame(frame, input_frameno as usize);
         return FrameType::KEY;
       }
+      if frame_type_override == Some(FrameTypeOverride::NoKey) {
+        self.keyframe_detector.set_last_frame(frame, input_frameno as usize);
+        return FrameType::INTER;
+      }
       if self.keyframe_detector.detect_scene_change(frame,
        input_frameno as usize) {
+        debug!("Scene change at input frame {}", input_frameno);
+        if self.config.intra_only_scene_changes {
+          return FrameType::INTRA_ONLY;
+        }
         return FrameType::KEY;
       }
     }
@@ -1363,6 +3452,9 @@ impl<T: Pixel> From<&FrameInvariants<T>> for FirstPassFrame {
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::film_grain::FilmGrainSegment;
+  use crate::header::PRIMARY_REF_NONE;
+  use crate::partition::RefType::*;
 
   use interpolate_name::interpolate_test;
 
@@ -1388,7 +3480,7 @@ mod test {
 
     let cfg = Config { enc, threads: 0 };
 
-    cfg.new_context()
+    cfg.new_context().unwrap()
   }
 
   /*
@@ -1486,4 +3578,1706 @@ mod test {
 
     assert_eq!(limit, count);
   }
+
+  #[test]
+  fn send_frame_with_roi_map() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, true, true);
+    let limit = 3;
+
+    let invalid = FrameParameters {
+      roi_map: Some(RoiMap {
+        cols: 2,
+        rows: 2,
+        values: RoiValues::Importance(vec![255; 3])
+      }),
+      ..Default::default()
+    };
+    assert!(ctx.send_frame((ctx.new_frame(), invalid)).is_err());
+
+    for i in 0..limit {
+      let params = FrameParameters {
+        roi_map: Some(RoiMap {
+          cols: 2,
+          rows: 1,
+          values: RoiValues::QindexOffsets(vec![-40, 20 * i as i16])
+        }),
+        ..Default::default()
+      };
+      ctx.send_frame((ctx.new_frame(), params)).unwrap();
+    }
+
+    ctx.flush();
+
+    let mut count = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(_) => count += 1,
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(limit, count);
+  }
+
+  #[test]
+  fn golden_reference_pattern() {
+    let mut enc = EncoderConfig::default();
+    enc.low_latency = true;
+    enc.error_resilient = true;
+    enc.reference_pattern =
+      ReferencePattern { golden_interval: 3, last_and_golden_only: true };
+    let inter_cfg = InterConfig::new(&enc);
+    let seq = Sequence::new(&enc);
+    let mut fi =
+      FrameInvariants::<u8>::new_key_frame(&FrameInvariants::new(enc, seq), 0);
+
+    for output_frameno in 1..8 {
+      let (next, ok) = FrameInvariants::new_inter_frame(
+        &fi, &inter_cfg, 0, output_frameno, 100
+      );
+      assert!(ok);
+      assert!(next.error_resilient);
+      assert_eq!(next.primary_ref_frame, PRIMARY_REF_NONE);
+
+      let last = next.ref_frames[LAST_FRAME.to_index()];
+      for (i, &slot) in next.ref_frames.iter().enumerate() {
+        if i == GOLDEN_FRAME.to_index() {
+          assert_eq!(slot, 7);
+        } else {
+          assert_eq!(slot, last);
+        }
+      }
+      let refreshes_golden = next.refresh_frame_flags & (1 << 7) != 0;
+      assert_eq!(refreshes_golden, output_frameno % 3 == 0);
+      fi = next;
+    }
+  }
+
+  #[test]
+  fn temporal_layers() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.low_latency = true;
+    enc.temporal_layers = 3;
+    let cfg = Config { enc, threads: 0 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    for _ in 0..9 {
+      let _ = ctx.send_frame(ctx.new_frame());
+    }
+    ctx.flush();
+
+    let mut layers = Vec::new();
+    let mut base_layer = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          // Frames only predict from their own and lower layers
+          for &frameno in &pkt.references {
+            assert!(layers[frameno as usize] <= pkt.temporal_layer);
+          }
+          layers.push(pkt.temporal_layer);
+          let filtered = filter_temporal_layers(&pkt.data, 0);
+          assert_eq!(filtered.is_empty(), pkt.temporal_layer > 0);
+          base_layer.extend_from_slice(&filtered);
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+    assert_eq!(layers, [0, 2, 1, 2, 0, 2, 1, 2, 0]);
+    assert_eq!(filter_temporal_layers(&base_layer, 0), base_layer);
+  }
+
+  #[test]
+  fn recover_from_long_term_reference() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, true, true);
+    let limit = 8;
+
+    for i in 0..limit {
+      let params = FrameParameters {
+        long_term_reference: i == 1,
+        reference_frameno: if i == 6 { Some(1) } else { None },
+        ..Default::default()
+      };
+      let _ = ctx.send_frame((ctx.new_frame(), params));
+    }
+
+    ctx.flush();
+
+    let mut count = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          count += 1;
+          let fi = &ctx.inner.frame_invariants[&(ctx.inner.output_frameno - 1)];
+          assert_eq!(fi.input_frameno, pkt.input_frameno);
+          if pkt.frame_type != FrameType::KEY {
+            assert_eq!(pkt.input_frameno == 1, fi.refresh_frame_flags & (1 << 6) != 0);
+          }
+          if pkt.input_frameno == 6 {
+            // The short-term slots have all been refreshed since frame 1
+            assert!(fi.ref_frames.iter().all(|&slot| slot == 6));
+          }
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(limit, count);
+  }
+
+  #[test]
+  fn t35_metadata_in_shown_temporal_unit() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, false, true);
+    let limit = 5;
+    let payload: &[u8] = b"\x00\x3c\x00\x01rav1e-t35";
+
+    for i in 0..limit {
+      let params = FrameParameters {
+        t35_metadata: if i == 2 {
+          vec![T35 {
+            country_code: 0xB5,
+            country_code_extension_byte: 0,
+            data: payload.into()
+          }]
+        } else {
+          Vec::new()
+        },
+        ..Default::default()
+      };
+      let _ = ctx.send_frame((ctx.new_frame(), params));
+    }
+
+    ctx.flush();
+
+    let mut expected = vec![0xB5];
+    expected.extend_from_slice(payload);
+    let mut count = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          count += 1;
+          let found = pkt.data.windows(expected.len()).any(|w| w == &expected[..]);
+          assert_eq!(found, pkt.input_frameno == 2);
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(limit, count);
+  }
+
+  #[test]
+  fn packet_metadata() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, false, true);
+    let limit = 10;
+
+    for _ in 0..limit {
+      let _ = ctx.send_frame(ctx.new_frame());
+    }
+    ctx.flush();
+
+    let mut subtypes = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          match pkt.frame_subtype {
+            FrameSubtype::Key => {
+              assert!(pkt.references.is_empty());
+              assert_eq!(pkt.temporal_layer, 0);
+            }
+            FrameSubtype::Inter => assert!(!pkt.references.is_empty()),
+            FrameSubtype::ShowExisting => {
+              assert!(pkt.references.is_empty());
+              assert_eq!(pkt.qp, 0);
+            }
+            FrameSubtype::Intra => {}
+          }
+          if pkt.frame_subtype != FrameSubtype::ShowExisting {
+            assert!(pkt.qp > 0);
+          }
+          subtypes.push(pkt.frame_subtype);
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(subtypes.len(), limit);
+    assert_eq!(subtypes[0], FrameSubtype::Key);
+    // Frames shown out of coding order with the default reordering
+    assert!(subtypes.contains(&FrameSubtype::ShowExisting));
+  }
+
+  #[test]
+  fn frame_type_override() {
+    // Frames from 5 on are a new scene
+    fn key_framenos(
+      ctx: &mut Context<u8>, overrides: &[(u64, FrameTypeOverride)]
+    ) -> Vec<u64> {
+      for input_frameno in 0..10 {
+        let mut frame = ctx.new_frame();
+        let value = if input_frameno < 5 { 0 } else { 255 };
+        for plane in Arc::get_mut(&mut frame).unwrap().planes.iter_mut() {
+          plane.data.iter_mut().for_each(|v| *v = value);
+        }
+        let frame_type_override = overrides.iter()
+          .find(|&&(frameno, _)| frameno == input_frameno)
+          .map(|&(_, frame_type_override)| frame_type_override);
+        let params = FrameParameters { frame_type_override, ..Default::default() };
+        let _ = ctx.send_frame((frame, params));
+      }
+      ctx.flush();
+
+      let mut keys = Vec::new();
+      loop {
+        match ctx.receive_packet() {
+          Ok(pkt) => if pkt.frame_type == FrameType::KEY {
+            keys.push(pkt.input_frameno);
+          },
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      keys
+    }
+
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 0, 200, 0, true, false);
+    assert_eq!(key_framenos(&mut ctx, &[]), vec![0, 5]);
+
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 0, 200, 0, true, false);
+    assert_eq!(key_framenos(&mut ctx, &[(5, FrameTypeOverride::NoKey)]), vec![0]);
+
+    // Forced within the minimum key frame interval, with frame reordering
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, false, true);
+    assert_eq!(
+      key_framenos(&mut ctx, &[(3, FrameTypeOverride::Key)]),
+      vec![0, 3]
+    );
+  }
+
+  #[test]
+  fn scenecut_sensitivity() {
+    // Frames from 5 on are a new scene
+    let key_framenos = |sensitivity, max_key_frame_interval| {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      enc.width = 64;
+      enc.height = 80;
+      enc.low_latency = true;
+      enc.min_key_frame_interval = 0;
+      enc.max_key_frame_interval = max_key_frame_interval;
+      enc.scenecut_sensitivity = sensitivity;
+      let cfg = Config { enc, threads: 1 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+      for input_frameno in 0..10 {
+        let mut frame = ctx.new_frame();
+        let value = if input_frameno < 5 { 0 } else { 255 };
+        for plane in Arc::get_mut(&mut frame).unwrap().planes.iter_mut() {
+          plane.data.iter_mut().for_each(|v| *v = value);
+        }
+        let _ = ctx.send_frame(frame);
+      }
+      ctx.flush();
+
+      let mut keys = Vec::new();
+      loop {
+        match ctx.receive_packet() {
+          Ok(pkt) => if pkt.frame_type == FrameType::KEY {
+            keys.push(pkt.input_frameno);
+          },
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      keys
+    };
+
+    assert_eq!(key_framenos(DEFAULT_SENSITIVITY, 200), vec![0, 5]);
+    assert_eq!(key_framenos(0, 200), vec![0]);
+    // The maximum interval counts from the last key frame, and a cut right
+    // after a key frame at the maximum interval is still detected
+    assert_eq!(key_framenos(DEFAULT_SENSITIVITY, 4), vec![0, 4, 5, 9]);
+    assert_eq!(key_framenos(0, 4), vec![0, 4, 8]);
+  }
+
+  #[test]
+  fn intra_only_scene_changes() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.low_latency = true;
+    enc.min_key_frame_interval = 8;
+    enc.intra_only_scene_changes = true;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    // Frames from 5 on are a new scene, within the minimum interval
+    for input_frameno in 0..10 {
+      let mut frame = ctx.new_frame();
+      let value = if input_frameno < 5 { 0 } else { 255 };
+      for plane in Arc::get_mut(&mut frame).unwrap().planes.iter_mut() {
+        plane.data.iter_mut().for_each(|v| *v = value);
+      }
+      let _ = ctx.send_frame(frame);
+    }
+    ctx.flush();
+
+    let mut frame_types = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => frame_types.push((pkt.input_frameno, pkt.frame_type)),
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+    assert_eq!(frame_types.len(), 10);
+    for &(input_frameno, frame_type) in frame_types.iter() {
+      let expected = match input_frameno {
+        0 => FrameType::KEY,
+        5 => FrameType::INTRA_ONLY,
+        _ => FrameType::INTER
+      };
+      assert_eq!(frame_type, expected);
+    }
+  }
+
+  #[test]
+  fn intra_refresh() {
+    struct Collect(Arc<Mutex<Vec<FrameEncodeStats>>>);
+    impl EncoderObserver for Collect {
+      fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+        self.0.lock().unwrap().push(stats.clone());
+      }
+    }
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 192;
+    enc.height = 64;
+    enc.max_key_frame_interval = 4;
+    enc.log_block_decisions = true;
+    enc.intra_refresh =
+      Some(IntraRefresh { direction: IntraRefreshDirection::Columns, period: 3 });
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    let collected = Arc::new(Mutex::new(Vec::new()));
+    ctx.set_observer(Box::new(Collect(collected.clone())));
+    // A pattern moving to the left
+    for input_frameno in 0..8 {
+      let mut frame = ctx.new_frame();
+      for plane in Arc::get_mut(&mut frame).unwrap().planes.iter_mut() {
+        let stride = plane.cfg.stride;
+        for (i, v) in plane.data.iter_mut().enumerate() {
+          *v = (((i % stride + 3 * input_frameno) * 7) % 256) as u8;
+        }
+      }
+      let _ = ctx.send_frame(frame);
+    }
+    ctx.flush();
+    loop {
+      match ctx.receive_packet() {
+        Ok(_) | Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    let collected = collected.lock().unwrap();
+    assert_eq!(collected.len(), 8);
+    for stats in collected.iter() {
+      if stats.input_frameno == 0 {
+        assert_eq!(stats.frame_type, FrameType::KEY);
+        continue;
+      }
+      assert_eq!(stats.frame_type, FrameType::INTER);
+      // One superblock column per frame
+      let start = ((stats.input_frameno as usize - 1) % 3) * 64;
+      for block in stats.blocks.iter() {
+        let intra = block.ref_frames[0] == 0;
+        if block.x >= start && block.x < start + 64 {
+          assert!(intra);
+        } else if block.x < start && !intra {
+          let end = (block.x + block.size.width()) as isize
+            + (block.mvs[0].1 as isize >> 3);
+          assert!(end <= start as isize - MV_FILTER_MARGIN as isize);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn tile_constrained_mvs() {
+    struct Collect(Arc<Mutex<Vec<FrameEncodeStats>>>);
+    impl EncoderObserver for Collect {
+      fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+        self.0.lock().unwrap().push(stats.clone());
+      }
+    }
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 256;
+    enc.height = 64;
+    enc.low_latency = true;
+    enc.tile_cols_log2 = 1;
+    enc.log_block_decisions = true;
+    enc.tile_constrained_mvs = true;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    let collected = Arc::new(Mutex::new(Vec::new()));
+    ctx.set_observer(Box::new(Collect(collected.clone())));
+    // A pattern moving to the left, across the edge of the tiles
+    for input_frameno in 0..4 {
+      let mut frame = ctx.new_frame();
+      for plane in Arc::get_mut(&mut frame).unwrap().planes.iter_mut() {
+        let stride = plane.cfg.stride;
+        for (i, v) in plane.data.iter_mut().enumerate() {
+          *v = (((i % stride + 3 * input_frameno) * 7) % 256) as u8;
+        }
+      }
+      let _ = ctx.send_frame(frame);
+    }
+    ctx.flush();
+    loop {
+      match ctx.receive_packet() {
+        Ok(_) | Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    let collected = collected.lock().unwrap();
+    assert_eq!(collected.len(), 4);
+    let edge = 128;
+    let margin = MV_FILTER_MARGIN as isize;
+    for stats in collected.iter().filter(|s| s.frame_type == FrameType::INTER) {
+      for block in stats.blocks.iter().filter(|b| b.ref_frames[0] != 0) {
+        let left = block.x as isize + (block.mvs[0].1 as isize >> 3);
+        let right = left + block.size.width() as isize;
+        if block.x < edge {
+          assert!(right <= edge as isize - margin);
+        } else {
+          assert!(left >= edge as isize + margin);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn quantizer_bounds() {
+    struct Collect(Arc<Mutex<Vec<FrameEncodeStats>>>);
+    impl EncoderObserver for Collect {
+      fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+        self.0.lock().unwrap().push(stats.clone());
+      }
+    }
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.quantizer = 100;
+    enc.min_quantizer = 80;
+    enc.max_quantizer = 90;
+    enc.variance_aq = true;
+    enc.log_block_decisions = true;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    let collected = Arc::new(Mutex::new(Vec::new()));
+    ctx.set_observer(Box::new(Collect(collected.clone())));
+    // Flat and textured areas, for the adaptive quantization to tell apart
+    for _ in 0..6 {
+      let mut frame = ctx.new_frame();
+      for plane in Arc::get_mut(&mut frame).unwrap().planes.iter_mut() {
+        let stride = plane.cfg.stride;
+        for (i, v) in plane.data.iter_mut().enumerate() {
+          *v = if i % stride < 32 { 128 } else { ((i * 37) % 256) as u8 };
+        }
+      }
+      let _ = ctx.send_frame(frame);
+    }
+    ctx.flush();
+    loop {
+      match ctx.receive_packet() {
+        Ok(_) | Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    let collected = collected.lock().unwrap();
+    let coded = collected.iter().filter(|s| !s.blocks.is_empty());
+    for stats in coded {
+      assert!(stats.qp >= 80 && stats.qp <= 90);
+      for block in stats.blocks.iter() {
+        assert!(block.qidx >= 80 && block.qidx <= 90);
+      }
+    }
+  }
+
+  #[test]
+  fn all_intra() {
+    let mut enc = EncoderConfig::all_intra_with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    // Without lookahead, each frame is coded as soon as it is sent
+    for i in 0..5 {
+      let _ = ctx.send_frame(ctx.new_frame());
+      let pkt = ctx.receive_packet().unwrap();
+      assert_eq!(pkt.input_frameno, i);
+      assert_eq!(pkt.frame_type, FrameType::KEY);
+      assert!(ctx.receive_packet().is_err());
+    }
+  }
+
+  #[test]
+  fn all_intra_rate_control() {
+    let mut enc = EncoderConfig::all_intra_with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.bitrate = 100_000;
+    let cfg = Config { enc, threads: 1 };
+    let ctx: Context<u8> = cfg.new_context().unwrap();
+    let inner = &ctx.inner;
+
+    // Wherever the previous frame was, the base quantizer moves towards the
+    //  target of the rate model by at most 1/16th
+    let qps = inner.rc_state.select_qi(inner, FRAME_SUBTYPE_I, None);
+    for &prev_log_base_q in
+      &[qps.log_base_q + (1 << 58), qps.log_base_q - (1 << 58)]
+    {
+      let qps =
+        inner.rc_state.select_qi(inner, FRAME_SUBTYPE_I, Some(prev_log_base_q));
+      assert_eq!(
+        (qps.log_base_q - prev_log_base_q).abs(),
+        crate::rate::ALL_INTRA_MAX_LOG_BASE_Q_CHANGE
+      );
+    }
+  }
+
+  #[test]
+  fn frame_delay() {
+    for &(low_latency, zero_delay) in
+      &[(false, false), (true, false), (true, true)]
+    {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      enc.width = 64;
+      enc.height = 80;
+      enc.low_latency = low_latency;
+      enc.zero_delay = zero_delay;
+      let cfg = Config { enc, threads: 1 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+      let delay = ctx.frame_delay();
+      assert_eq!(delay == 0, zero_delay);
+      assert_eq!(ctx.max_latency_frames(), delay + 1);
+
+      // The packet of each frame comes once `delay` more frames are sent
+      let mut received = 0;
+      for sent in 0..20 {
+        let _ = ctx.send_frame(ctx.new_frame());
+        assert!(ctx.frames_queued() <= ctx.max_latency_frames());
+        loop {
+          match ctx.receive_packet() {
+            Ok(pkt) => {
+              assert_eq!(pkt.input_frameno, received);
+              assert_eq!(sent, received + delay);
+              received += 1;
+            }
+            Err(EncoderStatus::Encoded) => {}
+            Err(_) => break
+          }
+        }
+      }
+      assert_eq!(received, 20 - delay);
+    }
+  }
+
+  #[test]
+  fn rate_parameters_change() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, true, true);
+    let limit = 10;
+
+    for _ in 0..limit {
+      let _ = ctx.send_frame(ctx.new_frame());
+    }
+    ctx.flush();
+
+    let mut qps = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          qps.push(pkt.qp);
+          if qps.len() == 5 {
+            ctx.set_quantizer(200).unwrap();
+          }
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(qps.len(), limit);
+    // The frames coded after the change use the new quantizer
+    assert!(qps[5..].iter().all(|&qp| qp > qps[4]));
+    assert_eq!(ctx.rate_parameters().quantizer, 200);
+
+    assert!(ctx.set_bitrate(-1).is_err());
+    ctx.set_bitrate(100_000).unwrap();
+    assert_eq!(ctx.rate_parameters().bitrate, 100_000);
+  }
+
+  #[test]
+  fn observer() {
+    struct Collect(Arc<Mutex<Vec<FrameEncodeStats>>>);
+    impl EncoderObserver for Collect {
+      fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+        self.0.lock().unwrap().push(stats.clone());
+      }
+    }
+
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, false, true);
+    let collected = Arc::new(Mutex::new(Vec::new()));
+    ctx.set_observer(Box::new(Collect(collected.clone())));
+    let limit = 10;
+
+    for _ in 0..limit {
+      let _ = ctx.send_frame(ctx.new_frame());
+    }
+    ctx.flush();
+
+    let mut bits = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => bits += pkt.data.len() as u64 * 8,
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    let collected = collected.lock().unwrap();
+    assert_eq!(collected.iter().filter(|stats| stats.show_frame).count(), limit);
+    // Packets also hold temporal delimiters
+    assert!(collected.iter().map(|stats| stats.bits).sum::<u64>() < bits);
+  }
+
+  #[test]
+  fn frame_stats_log() {
+    let stats = FrameEncodeStats {
+      input_frameno: 3,
+      frame_type: FrameType::INTER,
+      frame_subtype: FrameSubtype::Inter,
+      show_frame: true,
+      qp: 120,
+      bits: 800,
+      me_cost: 42,
+      blocks: Vec::new(),
+      encode_time: Duration::from_millis(5),
+      stage_times: StageTimes {
+        mode_decision: Duration::from_millis(3),
+        ..Default::default()
+      },
+      psnr: Some((40.0, 100.0, 42.5)),
+      ssim: None,
+      ms_ssim: None,
+    };
+
+    let mut csv = Vec::new();
+    {
+      let mut log = FrameStatsLog::new(&mut csv, StatsFormat::Csv);
+      log.frame_encoded(&stats);
+      log.frame_encoded(&stats);
+    }
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0].split(',').count(), FRAME_STATS_FIELDS.len());
+    assert_eq!(
+      lines[1],
+      "3,INTER,Inter,true,120,800,42,5.000,0.000,3.000,0.000,0.000,0.000,\
+       40.0000,100.0000,42.5000,,,,,,"
+    );
+
+    let mut json = Vec::new();
+    {
+      let mut log = FrameStatsLog::new(&mut json, StatsFormat::Json);
+      log.frame_encoded(&stats);
+    }
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with("[{\"input_frameno\":3,\"frame_type\":\"INTER\","));
+    assert!(json.contains("\"psnr_u\":100.0000,"));
+    assert!(json.contains("\"ssim_y\":null,"));
+    assert!(json.trim_end().ends_with("\"ms_ssim_v\":null}\n]"));
+  }
+
+  #[test]
+  fn frame_reuse() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, false, true);
+    let limit = 10;
+
+    let mut sent = Vec::new();
+    for _ in 0..limit {
+      let frame = ctx.new_frame();
+      sent.push(frame.planes[0].data.as_ptr());
+      let _ = ctx.send_frame(frame);
+    }
+    ctx.flush();
+
+    loop {
+      match ctx.receive_packet() {
+        Ok(_) | Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert!(!ctx.inner.frame_pool.lock().unwrap().is_empty());
+    let frame = ctx.new_frame();
+    // The allocation of an earlier input is handed out again
+    assert!(sent.contains(&frame.planes[0].data.as_ptr()));
+  }
+
+  #[test]
+  fn pts_passthrough() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, false, true);
+    let limit = 10;
+    // Variable frame durations
+    let pts = |i: u64| i * 3 + i % 2;
+
+    for i in 0..limit {
+      let params = FrameParameters { pts: Some(pts(i)), ..Default::default() };
+      let _ = ctx.send_frame((ctx.new_frame(), params));
+    }
+
+    ctx.flush();
+
+    let mut count = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          assert_eq!(pkt.pts, pts(pkt.input_frameno));
+          count += 1;
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(limit, count);
+  }
+
+  #[test]
+  fn dropped_frames() {
+    let mut ctx = setup_encoder::<u8>(64, 80, 10, 100, 8, ChromaSampling::Cs420, 150, 200, 0, false, true);
+
+    for i in 0..6 {
+      if i == 3 {
+        ctx.drop_frame();
+      } else {
+        let _ = ctx.send_frame(ctx.new_frame());
+      }
+    }
+
+    ctx.flush();
+
+    let mut pts = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => pts.push(pkt.pts),
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(pts, vec![0, 1, 2, 4, 5]);
+  }
+
+  #[test]
+  fn duplicate_frames() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.low_latency = true;
+    enc.duplicate_frame_threshold = Some(1);
+    let cfg = Config { enc, threads: 0 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    let mut frame = ctx.new_frame();
+    for (i, p) in Arc::make_mut(&mut frame).planes[0].data_origin_mut()
+      .iter_mut().enumerate()
+    {
+      *p = (i % 200) as u8;
+    }
+    for _ in 0..4 {
+      ctx.send_frame(frame.clone()).unwrap();
+    }
+    // Within the threshold
+    let mut near = (*frame).clone();
+    near.planes[0].data_origin_mut()[0] += 1;
+    ctx.send_frame(Arc::new(near.clone())).unwrap();
+    // Beyond it
+    near.planes[0].data_origin_mut()[0] += 10;
+    ctx.send_frame(Arc::new(near)).unwrap();
+
+    assert_eq!(
+      ctx.inner.duplicate_frames.iter().cloned().collect::<Vec<_>>(),
+      vec![1, 2, 3, 4]
+    );
+
+    ctx.flush();
+
+    let mut packets = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(_) => packets += 1,
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    assert_eq!(packets, 6);
+  }
+
+  #[test]
+  fn duplicate_frames_follow_a_slow_ramp() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.low_latency = true;
+    enc.duplicate_frame_threshold = Some(1);
+    let cfg = Config { enc, threads: 0 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    // Every frame is brighter than the previous one by the threshold, so
+    // only every other frame is close enough to the last one kept
+    let base = ctx.new_frame();
+    for i in 0..8 {
+      let mut frame = (*base).clone();
+      for plane in frame.planes.iter_mut() {
+        for p in plane.data.iter_mut() {
+          *p = 100 + i;
+        }
+      }
+      ctx.send_frame(Arc::new(frame)).unwrap();
+    }
+
+    assert_eq!(
+      ctx.inner.duplicate_frames.iter().cloned().collect::<Vec<_>>(),
+      vec![1, 3, 5, 7]
+    );
+  }
+
+  #[test]
+  fn speed_settings_override() {
+    struct Collect(Arc<Mutex<Vec<FrameEncodeStats>>>);
+    impl EncoderObserver for Collect {
+      fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+        self.0.lock().unwrap().push(stats.clone());
+      }
+    }
+
+    // Largest block coded, in pixels
+    fn max_block_size(speed_settings: SpeedSettings) -> usize {
+      let mut enc = EncoderConfig::with_speed_preset(3);
+      enc.width = 64;
+      enc.height = 80;
+      enc.low_latency = true;
+      enc.log_block_decisions = true;
+      enc.speed_settings = speed_settings;
+      let cfg = Config { enc, threads: 1 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+      let collected = Arc::new(Mutex::new(Vec::new()));
+      ctx.set_observer(Box::new(Collect(collected.clone())));
+
+      for _ in 0..3 {
+        let _ = ctx.send_frame(ctx.new_frame());
+      }
+      ctx.flush();
+
+      let mut packets = 0;
+      loop {
+        match ctx.receive_packet() {
+          Ok(_) => packets += 1,
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      assert_eq!(packets, 3);
+
+      let collected = collected.lock().unwrap();
+      collected
+        .iter()
+        .flat_map(|stats| stats.blocks.iter())
+        .map(|block| block.size.width().max(block.size.height()))
+        .max()
+        .unwrap()
+    }
+
+    // Flat frames are coded with the largest blocks allowed
+    let mut speed_settings = SpeedSettings::from_preset(3);
+    assert!(max_block_size(speed_settings) > 16);
+    speed_settings.max_block_size = BlockSize::BLOCK_16X16;
+    speed_settings.tx_search_depth = 1;
+    speed_settings.cdef_strengths_log2 = 1;
+    speed_settings.ext_partition_types = true;
+    speed_settings.superblock_second_pass = true;
+    assert_eq!(max_block_size(speed_settings), 16);
+  }
+
+  #[test]
+  fn zero_delay_output() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.zero_delay = true;
+    let cfg = Config { enc, threads: 0 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    for i in 0..5 {
+      let _ = ctx.send_frame(ctx.new_frame());
+      let pkt = ctx.receive_packet().unwrap();
+      assert_eq!(pkt.input_frameno, i);
+      assert!(ctx.receive_packet().is_err());
+    }
+  }
+
+  #[test]
+  fn chroma_weighted_encode() {
+    // The packets and the squared error of the chroma reconstruction
+    fn encode(weight: u8, chroma_me: bool) -> (Vec<Vec<u8>>, u64) {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      enc.width = 64;
+      enc.height = 80;
+      enc.low_latency = true;
+      enc.chroma_distortion_weight = weight;
+      enc.chroma_me = chroma_me;
+      let cfg = Config { enc, threads: 1 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+      let mut inputs = Vec::new();
+      for i in 0..3 {
+        let mut input = ctx.new_frame();
+        for (p, plane) in
+          Arc::get_mut(&mut input).unwrap().planes.iter_mut().enumerate()
+        {
+          let stride = plane.cfg.stride;
+          for (j, v) in plane.data.iter_mut().enumerate() {
+            let (x, y) = (j % stride + i * 2, j / stride + i);
+            *v = ((x * x * (p + 1) + 5 * y * y + 3 * x * y) % 241) as u8;
+          }
+        }
+        inputs.push(input.clone());
+        let _ = ctx.send_frame(input);
+      }
+      ctx.flush();
+
+      let mut packets = Vec::new();
+      let mut chroma_sse = 0;
+      loop {
+        match ctx.receive_packet() {
+          Ok(pkt) => {
+            let rec = pkt.rec.as_ref().unwrap();
+            let input = &inputs[pkt.input_frameno as usize];
+            for p in 1..3 {
+              for y in 0..40 {
+                for x in 0..32 {
+                  let d = i64::from(input.planes[p].p(x, y))
+                    - i64::from(rec.planes[p].p(x, y));
+                  chroma_sse += (d * d) as u64;
+                }
+              }
+            }
+            packets.push(pkt.data);
+          }
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      assert_eq!(packets.len(), 3);
+      (packets, chroma_sse)
+    }
+
+    let (default, _) = encode(8, false);
+    let (light, light_sse) = encode(1, false);
+    let (heavy, heavy_sse) = encode(64, false);
+    assert!(light != default && heavy != default);
+    // Weighing chroma up trades luma quality for a closer chroma
+    assert!(heavy_sse < light_sse);
+    // The chroma of the moving content changes the motion vectors
+    assert!(encode(8, true).0 != default);
+  }
+
+  #[test]
+  fn alternative_distortion_metrics() {
+    fn encode(me: Distortion, rdo: Distortion, weight: u8) -> Vec<Vec<u8>> {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      enc.width = 64;
+      enc.height = 80;
+      enc.low_latency = true;
+      enc.tune = Tune::Psnr;
+      enc.me_distortion = me;
+      enc.rdo_distortion = rdo;
+      enc.rdo_distortion_weight = weight;
+      let cfg = Config { enc, threads: 1 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+      for i in 0..3 {
+        let mut input = ctx.new_frame();
+        for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+          let stride = plane.cfg.stride;
+          for (j, v) in plane.data.iter_mut().enumerate() {
+            let (x, y) = (j % stride + i * 3, j / stride + i);
+            *v = ((x * x + 7 * y * y + x * y) % 251) as u8;
+          }
+        }
+        let _ = ctx.send_frame(input);
+      }
+      ctx.flush();
+
+      let mut packets = Vec::new();
+      loop {
+        match ctx.receive_packet() {
+          Ok(pkt) => packets.push(pkt.data),
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      assert_eq!(packets.len(), 3);
+      packets
+    }
+
+    // The textured, moving content makes every metric pick its own modes
+    // and motion vectors
+    let default = encode(Distortion::Sad, Distortion::Sse, 8);
+    assert!(encode(Distortion::Satd, Distortion::Sad, 8) != default);
+    assert!(encode(Distortion::Sse, Distortion::Satd, 8) != default);
+    // As does weighing the distortion up against the rate
+    assert!(encode(Distortion::Sad, Distortion::Sse, 32) != default);
+  }
+
+  #[test]
+  fn output_prediction() {
+    struct Collect(Arc<Mutex<Vec<FrameEncodeStats>>>);
+    impl EncoderObserver for Collect {
+      fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+        self.0.lock().unwrap().push(stats.clone());
+      }
+    }
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.low_latency = true;
+    enc.output_prediction = true;
+    enc.log_block_decisions = true;
+    // Key frames using IntraBC are not loop filtered, so the reconstruction
+    // is the prediction plus the coded residual
+    enc.enable_intrabc = true;
+    enc.min_key_frame_interval = 1;
+    enc.max_key_frame_interval = 1;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    let collected = Arc::new(Mutex::new(Vec::new()));
+    ctx.set_observer(Box::new(Collect(collected.clone())));
+
+    let mut inputs = Vec::new();
+    for i in 0..3 {
+      let mut input = ctx.new_frame();
+      for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+        let stride = plane.cfg.stride;
+        for (j, v) in plane.data.iter_mut().enumerate() {
+          let (x, y) = (j % stride + i * 3, j / stride + i);
+          *v = ((x * x + 7 * y * y + x * y) % 251) as u8;
+        }
+      }
+      inputs.push(input.clone());
+      let _ = ctx.send_frame(input);
+    }
+    ctx.flush();
+
+    let mut count = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          assert_eq!(pkt.frame_type, FrameType::KEY);
+          let rec = pkt.rec.unwrap();
+          let prediction = pkt.prediction.unwrap();
+          let input = &inputs[pkt.input_frameno as usize];
+          for (pred, rec) in prediction.planes.iter().zip(rec.planes.iter()) {
+            assert_eq!(pred.cfg, rec.cfg);
+          }
+
+          let (pred, rec) = (&prediction.planes[0], &rec.planes[0]);
+          let (width, height) = (64, 80);
+          let sse = |plane: &Plane<u8>| -> u64 {
+            let mut sse = 0;
+            for y in 0..height {
+              for x in 0..width {
+                let d = i64::from(plane.p(x, y))
+                  - i64::from(input.planes[0].p(x, y));
+                sse += (d * d) as u64;
+              }
+            }
+            sse
+          };
+          // The residual brings the prediction closer to the source
+          assert!(sse(rec) < sse(pred));
+
+          // Blocks coded without a residual are reconstructed as predicted
+          let collected = collected.lock().unwrap();
+          let stats = collected
+            .iter()
+            .find(|stats| stats.input_frameno == pkt.input_frameno)
+            .unwrap();
+          let mut skipped = 0;
+          for block in stats.blocks.iter().filter(|block| block.skip) {
+            for y in block.y..(block.y + block.size.height()).min(height) {
+              for x in block.x..(block.x + block.size.width()).min(width) {
+                assert_eq!(rec.p(x, y), pred.p(x, y));
+              }
+            }
+            skipped += 1;
+          }
+          assert!(skipped < stats.blocks.len());
+          count += 1;
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+    assert_eq!(count, 3);
+  }
+
+  #[test]
+  fn quantizer_offsets() {
+    let qps = |offsets: QuantizerOffsets| {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      enc.width = 64;
+      enc.height = 80;
+      enc.low_latency = true;
+      enc.quantizer_offsets = offsets;
+      let cfg = Config { enc, threads: 1 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+      for _ in 0..3 {
+        let _ = ctx.send_frame(ctx.new_frame());
+      }
+      ctx.flush();
+
+      let mut qps = Vec::new();
+      loop {
+        match ctx.receive_packet() {
+          Ok(pkt) => qps.push(pkt.qp),
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      qps
+    };
+
+    let flat = qps(QuantizerOffsets {
+      key_frame: 0.0,
+      alt_ref: 0.0,
+      pyramid_levels: [0.0; 2]
+    });
+    assert_eq!(flat.len(), 3);
+    assert!(flat.iter().all(|&qp| qp == flat[0]));
+
+    let coarse_inter = qps(QuantizerOffsets {
+      key_frame: 0.0,
+      alt_ref: 1.0,
+      pyramid_levels: [0.0; 2]
+    });
+    assert_eq!(coarse_inter[0], flat[0]);
+    assert!(coarse_inter[1..].iter().all(|&qp| qp > flat[0]));
+  }
+
+  #[test]
+  fn decoder_model_from_reservoir() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.time_base = Rational::new(1, 30);
+    enc.max_key_frame_interval = 240;
+    enc.bitrate = 500_000;
+    enc.decoder_model_info = true;
+
+    // 360 frames of reservoir, half full at the start
+    let timing_info = Sequence::new(&enc).timing_info.unwrap();
+    assert_eq!(timing_info.num_units_in_display_tick, 1);
+    assert_eq!(timing_info.time_scale, 30);
+    assert_eq!(timing_info.decoder_buffer_delay, 540_000);
+    assert_eq!(timing_info.encoder_buffer_delay, 540_000);
+
+    // The sequence header OBU follows the 4 bytes of the av1C header, and
+    // starts with profile, still_picture, reduced_still_picture_header and
+    // timing_info_present_flag
+    let timing_info_present = |enc: &EncoderConfig| {
+      let cfg = Config { enc: enc.clone(), threads: 1 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+      let av1c = ctx.container_sequence_header();
+      assert_eq!(av1c[4] >> 3 & 0xf, ObuType::OBU_SEQUENCE_HEADER as u8);
+      av1c[6] & 0x04 != 0
+    };
+    assert!(timing_info_present(&enc));
+
+    // Only in bitrate mode
+    enc.bitrate = 0;
+    assert!(Sequence::new(&enc).timing_info.is_none());
+    assert!(!timing_info_present(&enc));
+  }
+
+  #[test]
+  fn still_picture() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.still_picture = true;
+    let cfg = Config { enc, threads: 0 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    for _ in 0..3 {
+      let _ = ctx.send_frame(ctx.new_frame());
+    }
+    ctx.flush();
+
+    let mut packets = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => packets.push(pkt),
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].frame_type, FrameType::KEY);
+
+    // Temporal delimiter, then the sequence header OBU with the
+    // still_picture and reduced_still_picture_header bits set
+    let data = &packets[0].data;
+    assert_eq!(&data[..3], &[0x12, 0x00, 0x0A]);
+    assert_eq!(data[4] & 0x18, 0x18);
+  }
+
+  fn encode_still_picture(render_width: Option<usize>) -> Vec<u8> {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 64;
+    enc.still_picture = true;
+    enc.render_width = render_width;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    let _ = ctx.send_frame(ctx.new_frame());
+    ctx.flush();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => return pkt.data,
+        Err(EncoderStatus::Encoded) => {}
+        Err(e) => panic!("{:?}", e)
+      }
+    }
+  }
+
+  #[test]
+  fn render_size() {
+    let plain = encode_still_picture(None);
+    assert_eq!(encode_still_picture(Some(64)), plain);
+
+    // The render width and height take 32 more bits of frame header, the
+    // tile data being unchanged
+    let anamorphic = encode_still_picture(Some(128));
+    assert_eq!(anamorphic.len(), plain.len() + 4);
+  }
+
+  #[test]
+  fn resize_mid_stream() {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 64;
+    enc.low_latency = true;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    for _ in 0..4 {
+      let _ = ctx.send_frame(ctx.new_frame());
+    }
+    ctx.resize(44, 30).unwrap();
+    for _ in 0..3 {
+      let frame = ctx.new_frame();
+      assert_eq!(frame.planes[0].cfg.width, 48);
+      let _ = ctx.send_frame(frame);
+    }
+    // A frame of another size resizes on its own
+    let _ = ctx.send_frame(Arc::new(Frame::new(32, 32, ChromaSampling::Cs420)));
+    ctx.flush();
+
+    let mut packets = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => packets.push(pkt),
+        Err(EncoderStatus::Encoded) => {}
+        Err(EncoderStatus::LimitReached) => break,
+        Err(e) => panic!("{:?}", e)
+      }
+    }
+
+    let framenos: Vec<_> = packets.iter().map(|pkt| pkt.input_frameno).collect();
+    assert_eq!(framenos, (0..8).collect::<Vec<_>>());
+    assert_eq!(ctx.frames_queued(), 0);
+    for pkt in packets.iter() {
+      let new_sequence = [0, 4, 7].contains(&pkt.input_frameno);
+      assert_eq!(pkt.frame_type == FrameType::KEY, new_sequence);
+      assert_eq!(pkt.pts, pkt.input_frameno);
+      assert!(pkt.references.iter().all(|&r| r < pkt.input_frameno));
+      // Temporal delimiter, then the sequence header OBU on key frames
+      assert_eq!(pkt.data[2] == 0x0A, new_sequence);
+      let rec = pkt.rec.as_ref().unwrap();
+      let width = match pkt.input_frameno { 0..=3 => 64, 4..=6 => 48, _ => 32 };
+      assert_eq!(rec.planes[0].cfg.width, width);
+    }
+  }
+
+  #[cfg(feature = "serialize")]
+  #[test]
+  fn resume_from_serialized_state() {
+    fn encode(
+      ctx: &mut Context<u8>, frames: std::ops::Range<usize>, flush: bool,
+      packets: &mut Vec<Vec<u8>>
+    ) {
+      let mut receive = |ctx: &mut Context<u8>| loop {
+        match ctx.receive_packet() {
+          Ok(pkt) => packets.push(pkt.data),
+          Err(EncoderStatus::Encoded) => {}
+          Err(EncoderStatus::NeedMoreData) | Err(EncoderStatus::LimitReached) => {
+            break
+          }
+          Err(e) => panic!("{:?}", e)
+        }
+      };
+      for i in frames {
+        let mut input = ctx.new_frame();
+        for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+          for (j, v) in plane.data.iter_mut().enumerate() {
+            *v = ((j * 7 + i * 3) % 251) as u8;
+          }
+        }
+        let _ = ctx.send_frame(input);
+        receive(&mut *ctx);
+      }
+      if flush {
+        ctx.flush();
+        receive(&mut *ctx);
+      }
+    }
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 64;
+    enc.bitrate = 100_000;
+    let cfg = Config { enc, threads: 1 };
+
+    let mut expected = Vec::new();
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    encode(&mut ctx, 0..20, true, &mut expected);
+
+    let mut packets = Vec::new();
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    encode(&mut ctx, 0..12, false, &mut packets);
+    let state = bincode::serialize(&ctx).unwrap();
+    let (ref_frame_count, ref_slots) = {
+      let (ref_frames, ref_slots) = reference_slots(&ctx.inner, &ctx.resized);
+      (ref_frames.len(), ref_slots)
+    };
+    let used_slots = ref_slots.iter().flatten().flatten().count();
+    assert!(ref_frame_count < used_slots);
+    drop(ctx);
+    let mut ctx: Context<u8> = bincode::deserialize(&state).unwrap();
+    // The reference frames are shared between the same slots as before
+    let (ref_frames, resumed_slots) =
+      reference_slots(&ctx.inner, &ctx.resized);
+    assert_eq!(ref_frames.len(), ref_frame_count);
+    assert_eq!(resumed_slots, ref_slots);
+    encode(&mut ctx, 12..20, true, &mut packets);
+
+    assert_eq!(packets.len(), expected.len());
+    assert!(packets == expected);
+  }
+
+  #[test]
+  fn odd_dimensions() {
+    for &cs in &[
+      ChromaSampling::Cs420,
+      ChromaSampling::Cs422,
+      ChromaSampling::Cs444,
+      ChromaSampling::Cs400
+    ] {
+      let planes = if cs == ChromaSampling::Cs400 { 1 } else { 3 };
+      let mut ctx = setup_encoder::<u8>(67, 33, 10, 100, 8, cs, 0, 15, 0, true, true);
+      for input_frameno in 0..3 {
+        // A pattern within the frame and garbage past its edges
+        let mut input = ctx.new_frame();
+        let frame = Arc::get_mut(&mut input).unwrap();
+        for plane in frame.planes.iter_mut() {
+          let PlaneConfig { stride, xdec, ydec, .. } = plane.cfg;
+          let (w, h) = ((67 + xdec) >> xdec, (33 + ydec) >> ydec);
+          let data = plane.data_origin_mut();
+          for (y, row) in data.chunks_mut(stride).enumerate() {
+            for (x, pixel) in row.iter_mut().enumerate() {
+              *pixel =
+                if x < w && y < h { (x * 7 + y * 3) as u8 } else { 255 };
+            }
+          }
+        }
+        // The frame is copied before padding while the caller holds it
+        let held = if input_frameno == 1 { Some(input.clone()) } else { None };
+        let _ = ctx.send_frame(input);
+
+        let padded = ctx.inner.get_frame(input_frameno);
+        for plane in padded.planes.iter().take(planes) {
+          let PlaneConfig { xdec, ydec, .. } = plane.cfg;
+          let (w, h) = ((67 + xdec) >> xdec, (33 + ydec) >> ydec);
+          let (w8, h8) = (72 >> xdec, 40 >> ydec);
+          for y in 0..h8 {
+            for x in 0..w8 {
+              assert_eq!(plane.p(x, y), plane.p(x.min(w - 1), y.min(h - 1)));
+            }
+          }
+        }
+        if let Some(held) = held {
+          assert_eq!(held.planes[0].p(67, 0), 255);
+        }
+      }
+      ctx.flush();
+
+      let mut count = 0;
+      loop {
+        match ctx.receive_packet() {
+          Ok(_) => count += 1,
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      assert_eq!(count, 3);
+    }
+  }
+
+  #[test]
+  fn screen_content_tools() {
+    // (tune, detection, expected screen content tools, expected IntraBC)
+    let cases = [
+      (Tune::Psychovisual, false, false, false),
+      (Tune::Psychovisual, true, true, false),
+      (Tune::Screen, false, true, true),
+    ];
+    for &(tune, detect, tools, intrabc) in &cases {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      // Not a multiple of 8, so that the color maps of the palettes go past
+      // the mode info grid
+      enc.width = 67;
+      enc.height = 33;
+      enc.low_latency = true;
+      enc.tune = tune;
+      enc.detect_screen_content = detect;
+      let cfg = Config { enc, threads: 0 };
+      let mut ctx: Context<u8> = cfg.new_context().unwrap();
+      for i in 0..3 {
+        let mut input = ctx.new_frame();
+        let frame = Arc::get_mut(&mut input).unwrap();
+        // Black glyph-like strokes on a white background, scrolling
+        let plane = &mut frame.planes[0];
+        let stride = plane.cfg.stride;
+        for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+          for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = if x % 7 == 0 || (y + i) % 11 == 0 { 0 } else { 255 };
+          }
+        }
+        let _ = ctx.send_frame(input);
+      }
+      ctx.flush();
+
+      let mut count = 0;
+      loop {
+        match ctx.receive_packet() {
+          Ok(pkt) => {
+            count += 1;
+            let fi =
+              &ctx.inner.frame_invariants[&(ctx.inner.output_frameno - 1)];
+            assert_eq!(fi.allow_screen_content_tools != 0, tools);
+            if pkt.frame_type == FrameType::KEY {
+              assert_eq!(fi.allow_intrabc, intrabc);
+            }
+          }
+          Err(EncoderStatus::Encoded) => {}
+          Err(_) => break
+        }
+      }
+      assert_eq!(count, 3);
+    }
+  }
+
+  #[cfg(all(feature = "serialize", feature = "serde_json"))]
+  #[test]
+  fn partial_config_takes_defaults() {
+    let enc: EncoderConfig = serde_json::from_str(
+      r#"{ "quantizer": 80, "speed_settings": { "diamond_me": false } }"#
+    ).unwrap();
+    let default = EncoderConfig::default();
+    assert_eq!(enc.quantizer, 80);
+    assert_eq!(enc.max_key_frame_interval, default.max_key_frame_interval);
+    assert_eq!(enc.tune, default.tune);
+    assert!(!enc.speed_settings.diamond_me);
+    assert_eq!(
+      enc.speed_settings.min_block_size,
+      SpeedSettings::default().min_block_size
+    );
+  }
+
+  #[test]
+  fn invalid_configs_are_rejected() {
+    fn check(
+      f: impl Fn(&mut EncoderConfig), expected: InvalidConfig
+    ) {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      f(&mut enc);
+      let cfg = Config { enc, threads: 1 };
+      assert_eq!(cfg.new_context::<u16>().err(), Some(expected));
+    }
+
+    check(|enc| enc.width = 0, InvalidConfig::InvalidWidth(0));
+    check(|enc| enc.height = 1 << 16, InvalidConfig::InvalidHeight(1 << 16));
+    check(|enc| enc.bit_depth = 9, InvalidConfig::InvalidBitDepth(9));
+    check(|enc| enc.quantizer = 256, InvalidConfig::InvalidQuantizer(256));
+    check(|enc| enc.bitrate = -1, InvalidConfig::InvalidBitrate(-1));
+    check(
+      |enc| enc.min_quantizer = 200,
+      InvalidConfig::InvalidQuantizerRange { min: 200, max: 100 }
+    );
+    check(
+      |enc| enc.time_base = Rational { num: 1, den: 0 },
+      InvalidConfig::InvalidTimeBase { num: 1, den: 0 }
+    );
+    check(
+      |enc| enc.min_key_frame_interval = 300,
+      InvalidConfig::InvalidKeyFrameInterval { min: 300, max: 240 }
+    );
+    check(
+      |enc| enc.scenecut_sensitivity = 101,
+      InvalidConfig::InvalidScenecutSensitivity(101)
+    );
+    check(
+      |enc| {
+        enc.intra_refresh = Some(IntraRefresh {
+          direction: IntraRefreshDirection::Columns,
+          period: 0
+        })
+      },
+      InvalidConfig::InvalidIntraRefreshPeriod(0)
+    );
+    check(
+      |enc| {
+        let mut params = FilmGrainParams::default();
+        params.scaling_shift = 12;
+        enc.film_grain_table = Some(FilmGrainTable {
+          segments: vec![FilmGrainSegment {
+            start_time: 0,
+            end_time: 10_000_000,
+            params: Some(params)
+          }]
+        })
+      },
+      InvalidConfig::InvalidFilmGrainTable { start_time: 0 }
+    );
+    check(
+      |enc| enc.superres = SuperResMode::Fixed(8),
+      InvalidConfig::InvalidSuperresDenominator(8)
+    );
+    check(
+      |enc| enc.render_width = Some(0),
+      InvalidConfig::InvalidRenderSize { width: 0, height: 480 }
+    );
+    check(
+      |enc| enc.tile_cols_log2 = 7,
+      InvalidConfig::InvalidTilesLog2 { cols: 7, rows: 0 }
+    );
+    check(
+      |enc| enc.speed_settings.min_block_size = BlockSize::BLOCK_8X16,
+      InvalidConfig::InvalidPartitionRange {
+        min: BlockSize::BLOCK_8X16,
+        max: BlockSize::BLOCK_64X64
+      }
+    );
+    check(
+      |enc| enc.speed_settings.cdef_strengths_log2 = 4,
+      InvalidConfig::InvalidCdefStrengths(4)
+    );
+    let offsets = QuantizerOffsets { key_frame: -9.0, ..Default::default() };
+    check(
+      |enc| enc.quantizer_offsets = offsets,
+      InvalidConfig::InvalidQuantizerOffsets(offsets)
+    );
+    // Layers need the low latency structure
+    check(
+      |enc| enc.temporal_layers = 2,
+      InvalidConfig::InvalidTemporalLayers(2)
+    );
+    check(
+      |enc| enc.qm_levels = Some([8, 15, 15]),
+      InvalidConfig::InvalidQmLevels([8, 15, 15])
+    );
+    check(
+      |enc| enc.rdo_distortion_weight = 0,
+      InvalidConfig::InvalidDistortionWeight
+    );
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.bit_depth = 10;
+    let cfg = Config { enc, threads: 1 };
+    assert_eq!(
+      cfg.new_context::<u8>().err(),
+      Some(InvalidConfig::PixelTooNarrow { pixel_bits: 8, bit_depth: 10 })
+    );
+  }
+
+  fn encode_with_threads(threads: usize) -> Vec<Vec<u8>> {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 160;
+    enc.height = 128;
+    enc.quantizer = 100;
+    enc.tiles = 4;
+    enc.low_latency = true;
+    let cfg = Config { enc, threads };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    for i in 0..6 {
+      let mut input = ctx.new_frame();
+      let frame = Arc::get_mut(&mut input).unwrap();
+      for plane in frame.planes.iter_mut() {
+        let stride = plane.cfg.stride;
+        for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+          for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = ((x + 3 * i) ^ (y * 5)) as u8;
+          }
+        }
+      }
+      let _ = ctx.send_frame(input);
+    }
+    ctx.flush();
+
+    let mut packets = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => packets.push(pkt.data),
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+    packets
+  }
+
+  #[test]
+  fn output_does_not_depend_on_threads() {
+    let reference = encode_with_threads(1);
+    assert_eq!(reference.len(), 6);
+    for &threads in &[2, 4, 0] {
+      assert_eq!(reference, encode_with_threads(threads));
+    }
+  }
 }
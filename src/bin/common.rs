@@ -7,6 +7,8 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use crate::decoder::VideoDetails;
+use crate::decoder::yuv::{parse_pixel_format, PIXEL_FORMATS};
 use crate::muxer::{create_muxer, Muxer};
 use crate::{ColorPrimaries, MatrixCoefficients, TransferCharacteristics};
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand, Shell};
@@ -14,6 +16,7 @@ use rav1e::prelude::*;
 use rav1e::version;
 use scan_fmt::scan_fmt;
 
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::PathBuf;
@@ -21,21 +24,95 @@ use std::time::Instant;
 use std::{fmt, io};
 
 pub struct EncoderIO {
-  pub input: Box<dyn Read>,
+  pub input: Box<dyn Read + Send>,
   pub output: Box<dyn Muxer>,
-  pub rec: Option<Box<dyn Write>>
+  pub rec: Option<Box<dyn Write>>,
+  pub prediction: Option<Box<dyn Write>>,
+  pub residual: Option<Box<dyn Write>>,
+  pub alpha: Option<Box<dyn Read + Send>>
+}
+
+/// How much the CLI reports while encoding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verbosity {
+  /// Errors only
+  Quiet,
+  /// A progress line, warnings and the summary of the encode
+  Normal,
+  /// A line per frame, and what the library logs on the way
+  Verbose
+}
+
+/// What to do with interlaced Y4M input, which AV1 cannot code as fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterlacedInput {
+  /// Exit with an error
+  Reject,
+  /// Code the frames as they are, combing included
+  Progressive,
+  /// Deinterlace the frames on ingest
+  Deinterlace
+}
+
+/// Writes the records logged by the library to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+  fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+    metadata.level() <= log::max_level()
+  }
+
+  fn log(&self, record: &log::Record<'_>) {
+    if self.enabled(record.metadata()) {
+      // Start over the progress line
+      eprintln!("\r{}: {}", record.level(), record.args());
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Route the log records of the library to stderr, filtered by verbosity
+pub fn init_logger(verbosity: Verbosity) {
+  log::set_logger(&LOGGER).expect("Cannot set the logger");
+  log::set_max_level(match verbosity {
+    Verbosity::Quiet => log::LevelFilter::Error,
+    Verbosity::Normal => log::LevelFilter::Warn,
+    Verbosity::Verbose => log::LevelFilter::Debug
+  });
 }
 
 pub struct CliOptions {
   pub io: EncoderIO,
   pub enc: EncoderConfig,
+  /// Geometry of raw YUV input, None for Y4M input
+  pub raw_input: Option<VideoDetails>,
+  /// Size of the input file in bytes, unknown when reading from stdin
+  pub input_size: Option<u64>,
   pub limit: usize,
   pub color_range_specified: bool,
   pub skip: usize,
-  pub verbose: bool,
+  pub verbosity: Verbosity,
+  /// Decode every packet and compare it with the reconstruction
+  pub check_decode: bool,
+  pub interlaced: InterlacedInput,
+  /// Sample aspect ratio to derive the render width from
+  pub sar: Option<Rational>,
+  /// ISO sensitivity of the photon noise to signal as film grain, which
+  /// depends on the size of the input
+  pub photon_noise: Option<u32>,
   pub threads: usize,
+  /// Depth of the input and output queues, 0 to read and write in line
+  pub io_buffer_frames: usize,
   pub pass1file_name: Option<String>,
-  pub pass2file_name: Option<String>
+  pub pass2file_name: Option<String>,
+  pub stats_server: Option<String>,
+  /// Where to log the statistics of every frame, as JSON or CSV
+  pub frame_stats: Option<String>,
+  /// Where to log the coding decisions of every block, as JSON lines
+  pub block_log: Option<String>
 }
 
 pub fn parse_cli() -> CliOptions {
@@ -60,21 +137,85 @@ pub fn parse_cli() -> CliOptions {
         .takes_value(true)
         .default_value("0")
     )
+    .arg(
+      Arg::with_name("IO_BUFFER_FRAMES")
+        .help("Number of frames read ahead and packets written behind the\n\
+               encoder on their own threads, 0 to do it in the encoding loop")
+        .long("io-buffer-frames")
+        .takes_value(true)
+        .default_value("8")
+    )
     // INPUT/OUTPUT
     .arg(
       Arg::with_name("INPUT")
-        .help("Uncompressed YUV4MPEG2 video input")
+        .help("Uncompressed YUV4MPEG2 video input, or raw planar frames\n\
+               with the .yuv extension or --width and --height")
         .required_unless("FULLHELP")
         .index(1)
     )
+    .arg(
+      Arg::with_name("WIDTH")
+        .help("Width of raw YUV input")
+        .long("width")
+        .takes_value(true)
+        .requires("HEIGHT")
+    )
+    .arg(
+      Arg::with_name("HEIGHT")
+        .help("Height of raw YUV input")
+        .long("height")
+        .takes_value(true)
+        .requires("WIDTH")
+    )
+    .arg(
+      Arg::with_name("FPS")
+        .help("Frame rate of raw YUV input, as a number such as 29.97 or a\n\
+               fraction such as 30000/1001")
+        .long("fps")
+        .takes_value(true)
+        .default_value("30")
+    )
+    .arg(
+      Arg::with_name("PIXEL_FORMAT")
+        .help("Pixel format of raw YUV input")
+        .long("pixel-format")
+        .takes_value(true)
+        .possible_values(PIXEL_FORMATS)
+        .default_value("yuv420p")
+    )
     .arg(
       Arg::with_name("OUTPUT")
-        .help("Compressed AV1 in IVF video output")
+        .help("Compressed AV1 in IVF video output, a raw OBU stream, MP4,\n\
+               Matroska or WebM with the .obu, .mp4, .mkv or .webm\n\
+               extensions, or an AVIF still image of the first frame with\n\
+               the .avif extension")
         .short("o")
         .long("output")
         .required_unless("FULLHELP")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("OBU")
+        .help("Write a raw OBU stream in the low overhead format of\n\
+               section 5 or the length delimited format of Annex B,\n\
+               whatever the output extension")
+        .long("obu")
+        .takes_value(true)
+        .possible_values(&["section5", "annexb"])
+    )
+    .arg(
+      Arg::with_name("ALPHA")
+        .help("Y4M file whose first frame luma is the alpha plane of the\n\
+               AVIF output")
+        .long("alpha")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("STILL_PICTURE")
+        .help("Encode only the first frame as a still picture, implied by\n\
+               AVIF output")
+        .long("still-picture")
+    )
     .arg(
       Arg::with_name("STATS_FILE")
         .help("Custom location for first-pass stats file")
@@ -83,6 +224,18 @@ pub fn parse_cli() -> CliOptions {
         .default_value("rav1e_stats.json")
     )
     // ENCODING SETTINGS
+    .arg(
+      Arg::with_name("CONFIG")
+        .help("Read the encoder settings from a TOML file, or a JSON file with\n\
+               the .json extension, instead of the encoding options, which\n\
+               cannot be combined with it. Missing settings take their\n\
+               default value, the input still sets the frame size and pixel\n\
+               format, and the metrics, logs and debug outputs requested are\n\
+               still enabled.")
+        .long("config")
+        .takes_value(true)
+        .conflicts_with("SPEED_TEST")
+    )
     .arg(
       Arg::with_name("FIRST_PASS")
         .help("Perform the first pass of a two-pass encode, saving the pass data to the specified file for future passes")
@@ -110,6 +263,13 @@ pub fn parse_cli() -> CliOptions {
         .takes_value(true)
         .default_value("0")
     )
+    .arg(
+      Arg::with_name("FRAMES")
+        .help("Encode only the input frames START..END, numbered from 0\n\
+               and END excluded, either bound being optional")
+        .long("frames")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("QP")
         .help("Quantizer (0-255), smaller values are higher quality [default: 100]")
@@ -118,11 +278,19 @@ pub fn parse_cli() -> CliOptions {
     )
     .arg(
       Arg::with_name("MINQP")
-        .help("Minimum quantizer (0-255) to use in bitrate mode [default: 0]")
+        .help("Minimum quantizer (0-255) of any block, and base quantizer\n\
+               in bitrate mode [default: 0]")
         .long("min-quantizer")
         .alias("min_quantizer")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("MAXQP")
+        .help("Maximum quantizer (0-255) of any block, whatever the rate\n\
+               control and the adaptive quantization pick [default: 255]")
+        .long("max-quantizer")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("BITRATE")
         .help("Bitrate (kbps)")
@@ -130,6 +298,45 @@ pub fn parse_cli() -> CliOptions {
         .long("bitrate")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("KEYFRAME_REENCODES")
+        .help("Maximum number of times a key frame overshooting its target\n\
+               size is re-encoded at a higher quantizer in bitrate mode")
+        .long("keyframe-reencodes")
+        .takes_value(true)
+        .default_value("0")
+    )
+    .arg(
+      Arg::with_name("KEYFRAME_OVERSHOOT")
+        .help("Overshoot of the target size, in percent, above which a\n\
+               key frame is re-encoded")
+        .long("keyframe-overshoot")
+        .takes_value(true)
+        .default_value("100")
+    )
+    .arg(
+      Arg::with_name("QUANTIZER_OFFSETS")
+        .help("Quantizers of key frames, alt-ref frames and the first\n\
+               and second pyramid levels, as KEY,ARF,L1,L2 in log2 of\n\
+               their ratio to the base quantizer\n\
+               [default: -0.39,0,0.39,0.79]")
+        .long("quantizer-offsets")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("QM_LEVELS")
+        .help("Signal quantizer matrices with these levels of the Y, U\n\
+               and V planes, as Y,U,V. Only the flat level 15 is\n\
+               available.")
+        .long("qm-levels")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("DECODER_MODEL")
+        .help("Signal timing info and a decoder model matching the rate\n\
+               control buffer, in bitrate mode")
+        .long("decoder-model")
+    )
     .arg(
       Arg::with_name("SPEED")
         .help("Speed level (0 is best quality, 10 is fastest)\n\
@@ -147,17 +354,17 @@ pub fn parse_cli() -> CliOptions {
         - 6:\n\
         Min block size 8x8, reduced TX set, TX domain distortion\n\
         - 5 (default):\n\
-        Min block size 8x8, reduced TX set, TX domain distortion, complex pred modes for keyframes\n\
+        Min block size 8x8, reduced TX set, TX domain distortion, complex pred modes for keyframes, RDOQ\n\
         - 4:\n\
-        Min block size 8x8, TX domain distortion, complex pred modes for keyframes\n\
+        Min block size 8x8, TX domain distortion, complex pred modes for keyframes, filter intra, RDOQ\n\
         - 3:\n\
-        Min block size 8x8, TX domain distortion, complex pred modes for keyframes, RDO TX decision\n\
+        Min block size 8x8, TX domain distortion, complex pred modes for keyframes, RDO TX decision, distance-weighted compound, filter intra, RDOQ\n\
         - 2:\n\
-        Min block size 8x8, TX domain distortion, complex pred modes for keyframes, RDO TX decision, include near MVs\n\
+        Min block size 8x8, TX domain distortion, complex pred modes for keyframes, RDO TX decision, include near MVs, masked compound, distance-weighted compound, filter intra, RDOQ\n\
         - 1:\n\
-        Min block size 8x8, TX domain distortion, complex pred modes, RDO TX decision, include near MVs\n\
+        Min block size 8x8, TX domain distortion, complex pred modes, RDO TX decision, include near MVs, masked compound, distance-weighted compound, filter intra, RDOQ, superblock deblock deltas\n\
         - 0 (slowest):\n\
-        Min block size 4x4, TX domain distortion, complex pred modes, RDO TX decision, include near MVs, bottom-up encoding\n")
+        Min block size 4x4, TX domain distortion, complex pred modes, RDO TX decision, include near MVs, masked compound, distance-weighted compound, filter intra, RDOQ, superblock deblock deltas, bottom-up encoding\n")
         .short("s")
         .long("speed")
         .takes_value(true)
@@ -179,6 +386,30 @@ pub fn parse_cli() -> CliOptions {
         .takes_value(true)
         .default_value("240")
     )
+    .arg(
+      Arg::with_name("SCENECUT_SENSITIVITY")
+        .help("Sensitivity of the scene change detection placing\n\
+               keyframes between the minimum and maximum intervals,\n\
+               from 1 to 100. 0 disables it.")
+        .long("scenecut-sensitivity")
+        .takes_value(true)
+        .default_value("50")
+    )
+    .arg(
+      Arg::with_name("INTRA_ONLY_SCENECUTS")
+        .help("Code scene changes as intra-only frames, keeping the\n\
+               references, and place keyframes at the maximum interval\n\
+               only")
+        .long("intra-only-scenecuts")
+    )
+    .arg(
+      Arg::with_name("INTRA_REFRESH")
+        .help("Refresh the frame with a wave of intra superblocks sweeping\n\
+               it in PERIOD frames instead of keyframes, as columns:PERIOD\n\
+               or rows:PERIOD. Implies --low-latency.")
+        .long("intra-refresh")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("RESERVOIR_FRAME_DELAY")
         .help("Number of frames over which rate control should distribute the reservoir [default: max(240, 1.5x keyint)]\n\
@@ -194,6 +425,52 @@ pub fn parse_cli() -> CliOptions {
         .long("low-latency")
         .alias("low_latency")
     )
+    .arg(
+      Arg::with_name("ZERO_DELAY")
+        .help("Output each frame as soon as it is read, without lookahead;\n\
+               implies --low-latency")
+        .long("zero-delay")
+    )
+    .arg(
+      Arg::with_name("ALL_INTRA")
+        .help("Code every frame as a keyframe, e.g. for editing\n\
+               intermediates, with speed settings for intra coding;\n\
+               implies --zero-delay")
+        .long("all-intra")
+    )
+    .arg(
+      Arg::with_name("ERROR_RESILIENT")
+        .help("Code inter frames in error resilient mode")
+        .long("error-resilient")
+    )
+    .arg(
+      Arg::with_name("TILE_CONSTRAINED_MVS")
+        .help("Keep motion vectors inside their tile, so that tiles decode\n\
+               independently")
+        .long("tile-constrained-mvs")
+    )
+    .arg(
+      Arg::with_name("GOLDEN_INTERVAL")
+        .help("Refresh a long-term golden reference every N frames\n\
+               in low latency mode. 0 disables it.")
+        .long("golden-interval")
+        .takes_value(true)
+        .default_value("0")
+    )
+    .arg(
+      Arg::with_name("LAST_GOLDEN_ONLY")
+        .help("Only reference the previous and the golden frame\n\
+               in low latency mode")
+        .long("last-golden-only")
+    )
+    .arg(
+      Arg::with_name("TEMPORAL_LAYERS")
+        .help("Number of temporal layers in low latency mode, from 1 to 4.\n\
+               Frames only predict from their own and lower layers.")
+        .long("temporal-layers")
+        .takes_value(true)
+        .default_value("1")
+    )
     .arg(
       Arg::with_name("TUNE")
         .help("Quality tuning")
@@ -202,6 +479,161 @@ pub fn parse_cli() -> CliOptions {
         .default_value("Psychovisual")
         .case_insensitive(true)
     )
+    .arg(
+      Arg::with_name("PSY_RD")
+        .help("Strength of the psychovisual rate-distortion term of the\n\
+               Psychovisual tune, in 1/8 units. 0 disables it.")
+        .long("psy-rd")
+        .takes_value(true)
+        .default_value("0")
+    )
+    .arg(
+      Arg::with_name("CHROMA_WEIGHT")
+        .help("Weight of the chroma distortion relative to the luma one in\n\
+               the rate-distortion decisions, in 1/8 units")
+        .long("chroma-weight")
+        .takes_value(true)
+        .default_value("8")
+    )
+    .arg(
+      Arg::with_name("CHROMA_ME")
+        .help("Include chroma in the sub-pixel motion vector search")
+        .long("chroma-me")
+    )
+    .arg(
+      Arg::with_name("ME_DISTORTION")
+        .help("Distortion metric of the motion vector search")
+        .long("me-distortion")
+        .possible_values(&Distortion::variants())
+        .default_value("Sad")
+        .case_insensitive(true)
+    )
+    .arg(
+      Arg::with_name("RDO_DISTORTION")
+        .help("Distortion metric of the rate-distortion decisions")
+        .long("rdo-distortion")
+        .possible_values(&Distortion::variants())
+        .default_value("Sse")
+        .case_insensitive(true)
+    )
+    .arg(
+      Arg::with_name("ME_DISTORTION_WEIGHT")
+        .help("Weight of the motion vector search distortion against the\n\
+               rate, in 1/8 units")
+        .long("me-distortion-weight")
+        .takes_value(true)
+        .default_value("8")
+    )
+    .arg(
+      Arg::with_name("RDO_DISTORTION_WEIGHT")
+        .help("Weight of the rate-distortion decisions distortion against\n\
+               the rate, in 1/8 units")
+        .long("rdo-distortion-weight")
+        .takes_value(true)
+        .default_value("8")
+    )
+    .arg(
+      Arg::with_name("DENOISE")
+        .help("Strength of the pre-encode denoiser; the removed noise is\n\
+               re-synthesized by the decoder as film grain. 0 disables it.")
+        .long("denoise")
+        .takes_value(true)
+        .default_value("0")
+    )
+    .arg(
+      Arg::with_name("FILM_GRAIN_TABLE")
+        .help("Signal the film grain of an aomenc film grain table file\n\
+               instead of the one removed by --denoise")
+        .long("film-grain-table")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("PHOTON_NOISE")
+        .help("Signal the film grain of a camera sensor at this ISO\n\
+               sensitivity, e.g. 800, for the transfer function of the input")
+        .long("photon-noise")
+        .takes_value(true)
+        .conflicts_with("FILM_GRAIN_TABLE")
+    )
+    .arg(
+      Arg::with_name("DUPLICATE_THRESHOLD")
+        .help("Code input frames repeating the previous one as skip-only\n\
+               frames. The value is the largest sample difference tolerated.")
+        .long("duplicate-threshold")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("INTRABC")
+        .help("Allow key frames to copy blocks from their own coded area")
+        .long("intrabc")
+    )
+    .arg(
+      Arg::with_name("DETECT_SCREEN_CONTENT")
+        .help("Enable the screen content tools on the key frames of segments\n\
+               detected as screen content")
+        .long("detect-screen-content")
+    )
+    .arg(
+      Arg::with_name("VARIANCE_AQ")
+        .help("Lower the quantizer of flat areas, segmented by variance")
+        .long("variance-aq")
+    )
+    .arg(
+      Arg::with_name("ACTIVITY_MASKING")
+        .help("Scale lambda per block by the variance of the source")
+        .long("activity-masking")
+    )
+    .arg(
+      Arg::with_name("AQ_DELTA_Q")
+        .help("Vary the quantizer of variance AQ and region of interest\n\
+               maps per superblock instead of with segmentation")
+        .long("aq-delta-q")
+    )
+    .arg(
+      Arg::with_name("SUPERRES")
+        .help("Code key frames at a reduced width upscaled by the decoder:\n\
+               a denominator from 9 to 16 for a width of 8/denominator,\n\
+               or \"auto\" to only do it at high quantizers")
+        .long("superres")
+        .takes_value(true)
+        .default_value("none")
+    )
+    .arg(
+      Arg::with_name("RENDER_SIZE")
+        .help("Size to display the decoded frames at, as WIDTHxHEIGHT")
+        .long("render-size")
+        .takes_value(true)
+        .conflicts_with("SAR")
+    )
+    .arg(
+      Arg::with_name("INTERLACED")
+        .help("What to do with interlaced input, which cannot be coded as\n\
+               fields: reject it, code its frames as they are or\n\
+               deinterlace them")
+        .long("interlaced")
+        .possible_values(&["reject", "progressive", "deinterlace"])
+        .default_value("reject")
+    )
+    .arg(
+      Arg::with_name("SAR")
+        .help("Sample aspect ratio of the input, as NUM:DEN, signaled as\n\
+               a render width of the frame width scaled by it")
+        .long("sar")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("LEVEL")
+        .help("AV1 level to conform to, such as \"5.1\", \"auto\" for the\n\
+               lowest one fitting the settings or \"none\"")
+        .long("level")
+        .takes_value(true)
+        .default_value("none")
+    )
+    .arg(
+      Arg::with_name("HIGH_TIER")
+        .help("Use the high tier of the level")
+        .long("high-tier")
+    )
     .arg(
       Arg::with_name("TILE_ROWS_LOG2")
         .help("Log2 of number of tile rows")
@@ -265,11 +697,21 @@ pub fn parse_cli() -> CliOptions {
         .default_value("unspecified")
         .case_insensitive(true)
     )
+    .arg(
+      Arg::with_name("MASTER_DISPLAY")
+        .help("Mastering display in the x265 form of\n\
+               G(x,y)B(x,y)R(x,y)WP(x,y)L(max,min), with the chromaticities\n\
+               in units of 0.00002 and the luminances in units of\n\
+               0.0001 cd/m². Overrides --mastering-display.")
+        .long("master-display")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("CONTENT_LIGHT")
         .help("Content light level used to describe content luminosity (cll,fall)")
         .long("content-light")
         .alias("content_light")
+        .alias("max-cll")
         .default_value("0,0")
         .case_insensitive(true)
     )
@@ -280,18 +722,72 @@ pub fn parse_cli() -> CliOptions {
         .long("verbose")
         .short("v")
     )
+    .arg(
+      Arg::with_name("QUIET")
+        .help("Only output errors")
+        .long("quiet")
+        .short("q")
+        .conflicts_with("VERBOSE")
+    )
+    .arg(
+      Arg::with_name("CHECK_DECODE")
+        .help("Decode every packet with dav1d and stop at the first frame\n\
+               which does not match the reconstruction of the encoder")
+        .long("check-decode")
+    )
     .arg(
       Arg::with_name("PSNR")
         .help("Calculate and display PSNR metrics")
         .long("psnr")
     )
+    .arg(
+      Arg::with_name("SSIM")
+        .help("Calculate and display SSIM and MS-SSIM metrics")
+        .long("ssim")
+    )
+    .arg(
+      Arg::with_name("STATS_SERVER")
+        .help("Serve live encoding statistics as JSON on the given address,\n\
+               e.g. 127.0.0.1:8080")
+        .long("stats-server")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("FRAME_STATS")
+        .help("Write the statistics of every frame, including the time spent\n\
+               in each stage of the encoder, to a file, as CSV if its\n\
+               extension is .csv and as JSON otherwise")
+        .long("stats-file")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("BLOCK_LOG")
+        .help("Write the partition, modes, motion vectors, transforms and\n\
+               quantizer of every block to a file, as JSON lines")
+        .long("block-log")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("RECONSTRUCTION")
         .help("Outputs a Y4M file containing the output from the decoder")
         .long("reconstruction")
+        .visible_alias("output-recon")
         .short("r")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("PREDICTION")
+        .help("Outputs a Y4M file containing the prediction of every frame")
+        .long("output-pred")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("RESIDUAL")
+        .help("Outputs a Y4M file containing the reconstruction minus the\n\
+               prediction of every frame, centered on mid-gray")
+        .long("output-residual")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("SPEED_TEST")
         .help("Run an encode using default encoding settings, manually adjusting only the settings specified; allows benchmarking settings in isolation")
@@ -322,6 +818,11 @@ pub fn parse_cli() -> CliOptions {
     std::process::exit(0);
   }
 
+  let check_decode = matches.is_present("CHECK_DECODE");
+  if check_decode && !cfg!(feature = "check_decode") {
+    panic!("--check-decode requires rav1e to be built with the check_decode feature");
+  }
+
   let threads = matches.value_of("THREADS").map(|v| v.parse().expect("Threads must be an integer")).unwrap();
 
   if let Some(matches) = matches.subcommand_matches("advanced") {
@@ -331,29 +832,218 @@ pub fn parse_cli() -> CliOptions {
     }
   }
 
+  let input = matches.value_of("INPUT").unwrap();
+  let raw_input = if matches.is_present("WIDTH")
+    || input.to_lowercase().ends_with(".yuv")
+  {
+    Some(parse_raw_input(&matches))
+  } else {
+    None
+  };
+
+  let input_size = match input {
+    "-" => None,
+    f => std::fs::metadata(f).ok().map(|metadata| metadata.len())
+  };
+
   let io = EncoderIO {
-    input: match matches.value_of("INPUT").unwrap() {
-      "-" => Box::new(io::stdin()) as Box<dyn Read>,
-      f => Box::new(File::open(&f).unwrap()) as Box<dyn Read>
+    input: match input {
+      "-" => Box::new(io::stdin()) as Box<dyn Read + Send>,
+      f => Box::new(File::open(&f).unwrap()) as Box<dyn Read + Send>
     },
-    output: create_muxer(matches.value_of("OUTPUT").unwrap()),
+    output: create_muxer(
+      matches.value_of("OUTPUT").unwrap(),
+      matches.value_of("OBU").map(|f| f.parse().unwrap())
+    ),
     rec: matches
       .value_of("RECONSTRUCTION")
-      .map(|f| Box::new(File::create(&f).unwrap()) as Box<dyn Write>)
+      .map(|f| Box::new(File::create(&f).unwrap()) as Box<dyn Write>),
+    prediction: matches
+      .value_of("PREDICTION")
+      .map(|f| Box::new(File::create(&f).unwrap()) as Box<dyn Write>),
+    residual: matches
+      .value_of("RESIDUAL")
+      .map(|f| Box::new(File::create(&f).unwrap()) as Box<dyn Write>),
+    alpha: matches
+      .value_of("ALPHA")
+      .map(|f| Box::new(File::open(&f).unwrap()) as Box<dyn Read + Send>)
+  };
+
+  let (skip, limit) = match matches.value_of("FRAMES") {
+    Some(range) => {
+      if matches.occurrences_of("SKIP") > 0 || matches.occurrences_of("LIMIT") > 0 {
+        panic!("--frames cannot be combined with --skip or --limit");
+      }
+      parse_frame_range(range)
+    }
+    None => (
+      matches.value_of("SKIP").unwrap().parse().unwrap(),
+      matches.value_of("LIMIT").unwrap().parse().unwrap()
+    )
   };
 
   CliOptions {
     io,
-    enc: parse_config(&matches),
-    limit: matches.value_of("LIMIT").unwrap().parse().unwrap(),
+    enc: match matches.value_of("CONFIG") {
+      Some(path) => {
+        if ENCODER_SETTINGS.iter().any(|&arg| matches.occurrences_of(arg) > 0) {
+          panic!("--config cannot be combined with the encoding options");
+        }
+        let mut cfg = load_config(path);
+        apply_output_config(&mut cfg, &matches);
+        cfg
+      }
+      None => parse_config(&matches)
+    },
+    raw_input,
+    input_size,
+    limit,
     // Use `occurrences_of()` because `is_present()` is always true
     // if a parameter has a default value.
     color_range_specified: matches.occurrences_of("PIXEL_RANGE") > 0,
-    skip: matches.value_of("SKIP").unwrap().parse().unwrap(),
-    verbose: matches.is_present("VERBOSE"),
+    skip,
+    verbosity: if matches.is_present("QUIET") {
+      Verbosity::Quiet
+    } else if matches.is_present("VERBOSE") {
+      Verbosity::Verbose
+    } else {
+      Verbosity::Normal
+    },
+    check_decode,
+    interlaced: match matches.value_of("INTERLACED").unwrap() {
+      "progressive" => InterlacedInput::Progressive,
+      "deinterlace" => InterlacedInput::Deinterlace,
+      _ => InterlacedInput::Reject
+    },
+    sar: matches.value_of("SAR").map(|sar| {
+      let (num, den) = scan_fmt!(sar, "{}:{}", u64, u64)
+        .expect("Cannot parse the sample aspect ratio");
+      if num == 0 || den == 0 {
+        panic!("The sample aspect ratio must be positive");
+      }
+      Rational::new(num, den)
+    }),
+    photon_noise: matches.value_of("PHOTON_NOISE").map(|iso| {
+      match iso.parse() {
+        Ok(0) | Err(_) => panic!("The ISO sensitivity must be a positive integer"),
+        Ok(iso) => iso
+      }
+    }),
     threads,
+    io_buffer_frames: matches.value_of("IO_BUFFER_FRAMES").unwrap().parse()
+      .expect("The I/O buffer must be a number of frames"),
     pass1file_name: matches.value_of("FIRST_PASS").map(|s| s.to_owned()),
-    pass2file_name: matches.value_of("SECOND_PASS").map(|s| s.to_owned())
+    pass2file_name: matches.value_of("SECOND_PASS").map(|s| s.to_owned()),
+    stats_server: matches.value_of("STATS_SERVER").map(|s| s.to_owned()),
+    frame_stats: matches.value_of("FRAME_STATS").map(|s| s.to_owned()),
+    block_log: matches.value_of("BLOCK_LOG").map(|s| s.to_owned())
+  }
+}
+
+/// The frames to skip and the limit selecting the input frames START..END
+fn parse_frame_range(range: &str) -> (usize, usize) {
+  let mut bounds = range.splitn(2, "..");
+  let (start, end) = match (bounds.next(), bounds.next()) {
+    (Some(start), Some(end)) => (start, end),
+    _ => panic!("The frame range must be START..END")
+  };
+  let parse = |bound: &str| -> usize {
+    bound.parse().expect("Cannot parse the frame range")
+  };
+  let start = if start.is_empty() { 0 } else { parse(start) };
+  let limit = if end.is_empty() {
+    0
+  } else {
+    let end = parse(end);
+    if end <= start {
+      panic!("The frame range {} is empty", range);
+    }
+    end - start
+  };
+  (start, limit)
+}
+
+fn parse_raw_input(matches: &ArgMatches<'_>) -> VideoDetails {
+  let (width, height) =
+    match (matches.value_of("WIDTH"), matches.value_of("HEIGHT")) {
+      (Some(w), Some(h)) => (w.parse().unwrap(), h.parse().unwrap()),
+      _ => panic!("Raw YUV input needs --width and --height")
+    };
+  let (chroma_sampling, bit_depth) =
+    parse_pixel_format(matches.value_of("PIXEL_FORMAT").unwrap()).unwrap();
+  let (fps_num, fps_den) =
+    parse_frame_rate(matches.value_of("FPS").unwrap());
+
+  VideoDetails {
+    width,
+    height,
+    bit_depth,
+    chroma_sampling,
+    chroma_sample_position: ChromaSamplePosition::Unknown,
+    pixel_range: PixelRange::Limited,
+    time_base: Rational::new(fps_den, fps_num)
+  }
+}
+
+/// Parses a frame rate given as a number, such as 25 or 29.97, or as a
+/// fraction, such as 30000/1001, into a reduced fraction
+fn parse_frame_rate(fps: &str) -> (u64, u64) {
+  let (num, den) = if fps.contains('/') {
+    scan_fmt!(fps, "{}/{}", u64, u64).expect("Cannot parse the frame rate")
+  } else {
+    let mut parts = fps.splitn(2, '.');
+    let int = parts.next().unwrap();
+    let frac = parts.next().unwrap_or("");
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if (int.is_empty() && frac.is_empty()) || !digits(int) || !digits(frac) {
+      panic!("Cannot parse the frame rate");
+    }
+    let den = 10u64.checked_pow(frac.len() as u32)
+      .expect("The frame rate has too many decimals");
+    let num = format!("{}{}", int, frac).parse::<u64>()
+      .expect("Cannot parse the frame rate");
+    (num, den)
+  };
+  if num == 0 || den == 0 {
+    panic!("The frame rate must be positive");
+  }
+  let (mut a, mut b) = (num, den);
+  while b != 0 {
+    let r = a % b;
+    a = b;
+    b = r;
+  }
+  (num / a, den / a)
+}
+
+/// The options of `parse_config` replaced by the settings of `--config`
+const ENCODER_SETTINGS: &[&str] = &[
+  "ACTIVITY_MASKING", "ALL_INTRA", "AQ_DELTA_Q", "BITRATE", "CHROMA_ME",
+  "CHROMA_WEIGHT", "COLOR_PRIMARIES", "CONTENT_LIGHT", "DECODER_MODEL",
+  "DENOISE", "DETECT_SCREEN_CONTENT", "DUPLICATE_THRESHOLD", "ERROR_RESILIENT",
+  "FILM_GRAIN_TABLE", "GOLDEN_INTERVAL", "HIGH_TIER", "INTRABC",
+  "INTRA_ONLY_SCENECUTS", "INTRA_REFRESH", "KEYFRAME_INTERVAL",
+  "KEYFRAME_OVERSHOOT", "KEYFRAME_REENCODES", "LAST_GOLDEN_ONLY", "LEVEL",
+  "LOW_LATENCY", "MASTERING_DISPLAY", "MASTER_DISPLAY", "MATRIX_COEFFICIENTS",
+  "MAXQP", "ME_DISTORTION", "ME_DISTORTION_WEIGHT", "MINQP",
+  "MIN_KEYFRAME_INTERVAL", "PIXEL_RANGE", "PSY_RD", "QM_LEVELS", "QP",
+  "QUANTIZER_OFFSETS", "RDO_DISTORTION", "RDO_DISTORTION_WEIGHT",
+  "RENDER_SIZE", "RESERVOIR_FRAME_DELAY", "SCENECUT_SENSITIVITY", "SPEED",
+  "STILL_PICTURE", "SUPERRES", "TEMPORAL_LAYERS", "TILES", "TILE_COLS_LOG2",
+  "TILE_CONSTRAINED_MVS", "TILE_ROWS_LOG2", "TRANSFER_CHARACTERISTICS",
+  "TUNE", "VARIANCE_AQ", "ZERO_DELAY", "train-rdo"
+];
+
+/// Encoder settings read from a TOML or JSON file
+fn load_config(path: &str) -> EncoderConfig {
+  let text = std::fs::read_to_string(path)
+    .unwrap_or_else(|e| panic!("Cannot read \"{}\": {}", path, e));
+  if path.to_lowercase().ends_with(".json") {
+    serde_json::from_str(&text)
+      .unwrap_or_else(|e| panic!("Cannot parse \"{}\": {}", path, e))
+  } else {
+    toml::from_str(&text)
+      .unwrap_or_else(|e| panic!("Cannot parse \"{}\": {}", path, e))
   }
 }
 
@@ -419,9 +1109,21 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
       .parse()
       .unwrap_or_default();
 
-    let mut cfg = EncoderConfig::with_speed_preset(speed);
-    cfg.max_key_frame_interval = min_interval;
+    let mut cfg = if matches.is_present("ALL_INTRA") {
+      EncoderConfig::all_intra_with_speed_preset(speed)
+    } else {
+      EncoderConfig::with_speed_preset(speed)
+    };
+    cfg.min_key_frame_interval = min_interval;
     cfg.max_key_frame_interval = max_interval;
+    cfg.scenecut_sensitivity =
+      matches.value_of("SCENECUT_SENSITIVITY").unwrap().parse().unwrap();
+    cfg.intra_only_scene_changes = matches.is_present("INTRA_ONLY_SCENECUTS");
+    cfg.intra_refresh = matches.value_of("INTRA_REFRESH").map(|refresh| {
+      refresh.parse().expect(
+        "Intra refresh must be columns:PERIOD or rows:PERIOD with a positive period"
+      )
+    });
 
     cfg.pixel_range = matches.value_of("PIXEL_RANGE").unwrap().parse().unwrap_or_default();
     cfg.color_description = if color_primaries == ColorPrimaries::Unspecified &&
@@ -438,7 +1140,21 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
     };
 
     let mastering_display_opt = matches.value_of("MASTERING_DISPLAY").unwrap();
-    cfg.mastering_display = if mastering_display_opt == "unspecified" { None } else {
+    cfg.mastering_display = if let Some(master_display_opt) = matches.value_of("MASTER_DISPLAY") {
+      let (g_x, g_y, b_x, b_y, r_x, r_y, wp_x, wp_y, max_lum, min_lum) = scan_fmt!(master_display_opt, "G({},{})B({},{})R({},{})WP({},{})L({},{})", u32, u32, u32, u32, u32, u32, u32, u32, u64, u64).expect("Cannot parse the master display option");
+      // x265 chromaticities are in units of 1/50000, luminances of 1/10000
+      let chromaticity = |v: u32| (((v as u64) << 16) + 25000) / 50000;
+      let point = |x: u32, y: u32| Point {
+        x: chromaticity(x).min(std::u16::MAX as u64) as u16,
+        y: chromaticity(y).min(std::u16::MAX as u64) as u16,
+      };
+      Some(MasteringDisplay {
+        primaries: [point(r_x, r_y), point(g_x, g_y), point(b_x, b_y)],
+        white_point: point(wp_x, wp_y),
+        max_luminance: (((max_lum << 8) + 5000) / 10000) as u32,
+        min_luminance: (((min_lum << 14) + 5000) / 10000) as u32,
+      })
+    } else if mastering_display_opt == "unspecified" { None } else {
       let (g_x, g_y, b_x, b_y, r_x, r_y, wp_x, wp_y, max_lum, min_lum) = scan_fmt!(mastering_display_opt, "G({},{})B({},{})R({},{})WP({},{})L({},{})", f64, f64, f64, f64, f64, f64, f64, f64, f64, f64).expect("Cannot parse the mastering display option");
       Some(MasteringDisplay {
         primaries: [
@@ -477,9 +1193,26 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
 
   cfg.quantizer = quantizer;
   cfg.min_quantizer = matches.value_of("MINQP").unwrap_or("0").parse().unwrap();
+  cfg.max_quantizer = matches.value_of("MAXQP").unwrap_or("255").parse().unwrap();
   cfg.bitrate = bitrate.checked_mul(1000).expect("Bitrate too high");
+  cfg.keyframe_max_reencodes =
+    matches.value_of("KEYFRAME_REENCODES").unwrap().parse().unwrap();
+  cfg.keyframe_overshoot_pct =
+    matches.value_of("KEYFRAME_OVERSHOOT").unwrap().parse().unwrap();
+  if let Some(offsets) = matches.value_of("QUANTIZER_OFFSETS") {
+    let (key_frame, alt_ref, level1, level2) =
+      scan_fmt!(offsets, "{},{},{},{}", f64, f64, f64, f64)
+        .expect("Cannot parse the quantizer offsets");
+    cfg.quantizer_offsets =
+      QuantizerOffsets { key_frame, alt_ref, pyramid_levels: [level1, level2] };
+  }
+  cfg.qm_levels = matches.value_of("QM_LEVELS").map(|levels| {
+    let (y, u, v) = scan_fmt!(levels, "{},{},{}", u8, u8, u8)
+      .expect("Cannot parse the quantizer matrix levels");
+    [y, u, v]
+  });
+  cfg.decoder_model_info = matches.is_present("DECODER_MODEL");
   cfg.reservoir_frame_delay = matches.value_of("RESERVOIR_FRAME_DELAY").map(|reservior_frame_delay| reservior_frame_delay.parse().unwrap());
-  cfg.show_psnr = matches.is_present("PSNR");
   cfg.pass = None;
   cfg.stats_file = if cfg.pass.is_some() {
     Some(PathBuf::from(matches.value_of("STATS_FILE").unwrap()))
@@ -487,6 +1220,47 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
     None
   };
   cfg.tune = matches.value_of("TUNE").unwrap().parse().unwrap();
+  cfg.psy_rd_strength = matches.value_of("PSY_RD").unwrap().parse().unwrap();
+  cfg.chroma_distortion_weight =
+    matches.value_of("CHROMA_WEIGHT").unwrap().parse().unwrap();
+  cfg.chroma_me = matches.is_present("CHROMA_ME");
+  cfg.me_distortion =
+    matches.value_of("ME_DISTORTION").unwrap().parse().unwrap();
+  cfg.rdo_distortion =
+    matches.value_of("RDO_DISTORTION").unwrap().parse().unwrap();
+  cfg.me_distortion_weight =
+    matches.value_of("ME_DISTORTION_WEIGHT").unwrap().parse().unwrap();
+  cfg.rdo_distortion_weight =
+    matches.value_of("RDO_DISTORTION_WEIGHT").unwrap().parse().unwrap();
+  cfg.denoise_strength = matches.value_of("DENOISE").unwrap().parse().unwrap();
+  cfg.film_grain_table = matches.value_of("FILM_GRAIN_TABLE").map(|path| {
+    std::fs::read_to_string(path)
+      .unwrap_or_else(|e| panic!("Cannot read \"{}\": {}", path, e))
+      .parse()
+      .unwrap_or_else(|_| panic!("\"{}\" is not a film grain table", path))
+  });
+  cfg.duplicate_frame_threshold = matches.value_of("DUPLICATE_THRESHOLD")
+    .map(|t| t.parse().expect("Duplicate threshold must be 0-65535"));
+  cfg.enable_intrabc = matches.is_present("INTRABC");
+  cfg.detect_screen_content = matches.is_present("DETECT_SCREEN_CONTENT");
+  cfg.variance_aq = matches.is_present("VARIANCE_AQ");
+  cfg.aq_delta_q = matches.is_present("AQ_DELTA_Q");
+  cfg.activity_masking = matches.is_present("ACTIVITY_MASKING");
+  cfg.superres = matches.value_of("SUPERRES").unwrap().parse()
+    .expect("Superres must be \"none\", \"auto\" or a denominator from 9 to 16");
+  if let Some(render_size) = matches.value_of("RENDER_SIZE") {
+    let (width, height) = scan_fmt!(render_size, "{}x{}", usize, usize)
+      .expect("Cannot parse the render size");
+    cfg.render_width = Some(width);
+    cfg.render_height = Some(height);
+  }
+  cfg.still_picture = matches.is_present("STILL_PICTURE");
+
+  cfg.level = matches.value_of("LEVEL").unwrap().parse()
+    .expect("Level must be \"none\", \"auto\" or a level such as \"5.1\"");
+  if let Level::Fixed { ref mut high_tier, .. } = cfg.level {
+    *high_tier = matches.is_present("HIGH_TIER");
+  }
 
   cfg.tile_cols_log2 = matches.value_of("TILE_COLS_LOG2").unwrap().parse().unwrap();
   cfg.tile_rows_log2 = matches.value_of("TILE_ROWS_LOG2").unwrap().parse().unwrap();
@@ -498,10 +1272,39 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
   }
 
   cfg.low_latency = matches.is_present("LOW_LATENCY");
+  cfg.zero_delay = matches.is_present("ZERO_DELAY");
+  cfg.error_resilient = matches.is_present("ERROR_RESILIENT");
+  cfg.tile_constrained_mvs = matches.is_present("TILE_CONSTRAINED_MVS");
+  cfg.reference_pattern.golden_interval =
+    matches.value_of("GOLDEN_INTERVAL").unwrap().parse().unwrap();
+  cfg.reference_pattern.last_and_golden_only =
+    matches.is_present("LAST_GOLDEN_ONLY");
+  cfg.temporal_layers =
+    matches.value_of("TEMPORAL_LAYERS").unwrap().parse().unwrap();
   cfg.train_rdo = train_rdo;
+  apply_output_config(&mut cfg, matches);
   cfg
 }
 
+/// Enables the metrics, logs and debug outputs requested, and the still
+/// picture coding of AVIF output, whether the settings come from the
+/// options or from `--config`
+fn apply_output_config(cfg: &mut EncoderConfig, matches: &ArgMatches<'_>) {
+  cfg.show_psnr |= matches.is_present("PSNR");
+  cfg.show_ssim |= matches.is_present("SSIM");
+  cfg.log_block_decisions |= matches.is_present("BLOCK_LOG");
+  cfg.time_stages |= matches.is_present("FRAME_STATS");
+  cfg.output_prediction |=
+    matches.is_present("PREDICTION") || matches.is_present("RESIDUAL");
+
+  let avif_output = matches.value_of("OUTPUT")
+    .map_or(false, |f| f.to_lowercase().ends_with(".avif"));
+  if matches.is_present("ALPHA") && !avif_output {
+    panic!("An alpha plane can only be written to .avif output");
+  }
+  cfg.still_picture |= avif_output;
+}
+
 fn apply_speed_test_cfg(cfg: &mut EncoderConfig, setting: &str) {
   match setting {
     "baseline" => {
@@ -519,6 +1322,9 @@ fn apply_speed_test_cfg(cfg: &mut EncoderConfig, setting: &str) {
     "min_block_size_64x64" => {
       cfg.speed_settings.min_block_size = BlockSize::BLOCK_64X64;
     },
+    "max_block_size_32x32" => {
+      cfg.speed_settings.max_block_size = BlockSize::BLOCK_32X32;
+    },
     "multiref" => {
       cfg.speed_settings.multiref = true;
     },
@@ -537,8 +1343,19 @@ fn apply_speed_test_cfg(cfg: &mut EncoderConfig, setting: &str) {
     "encode_bottomup" => {
       cfg.speed_settings.encode_bottomup = true;
     },
+    "ext_partition_types" => {
+      cfg.speed_settings.ext_partition_types = true;
+    },
+    "superblock_second_pass" => {
+      cfg.speed_settings.superblock_second_pass = true;
+    },
     "rdo_tx_decision" => {
       cfg.speed_settings.rdo_tx_decision = true;
+      cfg.speed_settings.tx_search_depth = 2;
+    },
+    "rdo_tx_decision_depth_1" => {
+      cfg.speed_settings.rdo_tx_decision = true;
+      cfg.speed_settings.tx_search_depth = 1;
     },
     "prediction_modes_keyframes" => {
       cfg.speed_settings.prediction_modes = PredictionModesSetting::ComplexKeyframes;
@@ -558,6 +1375,25 @@ fn apply_speed_test_cfg(cfg: &mut EncoderConfig, setting: &str) {
     "cdef" => {
       cfg.speed_settings.cdef = true;
     }
+    "cdef_4_strengths" => {
+      cfg.speed_settings.cdef = true;
+      cfg.speed_settings.cdef_strengths_log2 = 2;
+    }
+    "masked_compound" => {
+      cfg.speed_settings.masked_compound = true;
+    }
+    "dist_wtd_compound" => {
+      cfg.speed_settings.dist_wtd_compound = true;
+    }
+    "filter_intra" => {
+      cfg.speed_settings.filter_intra = true;
+    }
+    "rdoq" => {
+      cfg.speed_settings.rdoq = true;
+    }
+    "deblock_deltas" => {
+      cfg.speed_settings.deblock_deltas = true;
+    }
     setting => {
       panic!("Unrecognized speed test setting {}", setting);
     }
@@ -572,6 +1408,9 @@ pub struct FrameSummary {
   pub frame_type: FrameType,
   // PSNR for Y, U, and V planes
   pub psnr: Option<(f64, f64, f64)>,
+  // SSIM and MS-SSIM for Y, U, and V planes
+  pub ssim: Option<(f64, f64, f64)>,
+  pub ms_ssim: Option<(f64, f64, f64)>,
 }
 
 impl<T: Pixel> From<Packet<T>> for FrameSummary {
@@ -581,6 +1420,8 @@ impl<T: Pixel> From<Packet<T>> for FrameSummary {
       input_frameno: packet.input_frameno,
       frame_type: packet.frame_type,
       psnr: packet.psnr,
+      ssim: packet.ssim,
+      ms_ssim: packet.ms_ssim,
     }
   }
 }
@@ -589,17 +1430,40 @@ impl fmt::Display for FrameSummary {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
       f,
-      "Input Frame {} - {} - {} bytes{}",
+      "Input Frame {} - {} - {} bytes{}{}{}",
       self.input_frameno,
       self.frame_type,
       self.size,
       if let Some(psnr) = self.psnr {
         format!(" - PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}", psnr.0, psnr.1, psnr.2)
+      } else { String::new() },
+      if let Some(ssim) = self.ssim {
+        format!(" - SSIM: Y: {:.4}  Cb: {:.4}  Cr: {:.4}", ssim.0, ssim.1, ssim.2)
+      } else { String::new() },
+      if let Some(ms_ssim) = self.ms_ssim {
+        format!(" - MS-SSIM: Y: {:.4}  Cb: {:.4}  Cr: {:.4}", ms_ssim.0, ms_ssim.1, ms_ssim.2)
       } else { String::new() }
     )
   }
 }
 
+/// Number of frames the current encoding speed is measured over
+const RECENT_FRAMES: usize = 30;
+
+/// `seconds` as h:mm:ss, or m:ss under an hour
+fn format_duration(seconds: f64) -> String {
+  if !seconds.is_finite() {
+    return "-".to_owned();
+  }
+  let seconds = seconds.round() as u64;
+  let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+  if h > 0 {
+    format!("{}:{:02}:{:02}", h, m, s)
+  } else {
+    format!("{}:{:02}", m, s)
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct ProgressInfo {
   // Frame rate of the video
@@ -610,6 +1474,8 @@ pub struct ProgressInfo {
   time_started: Instant,
   // List of frames encoded so far
   frame_info: Vec<FrameSummary>,
+  // When the last frames were encoded, for the current encoding speed
+  recent_frames: VecDeque<Instant>,
   // Video size so far in bytes.
   //
   // This value will be updated in the CLI very frequently, so we cache the previous value
@@ -617,23 +1483,31 @@ pub struct ProgressInfo {
   encoded_size: usize,
   // Whether to display PSNR statistics during and at end of encode
   show_psnr: bool,
+  // Whether to display SSIM and MS-SSIM statistics at the end of encode
+  show_ssim: bool,
 }
 
 impl ProgressInfo {
-  pub fn new(frame_rate: Rational, total_frames: Option<usize>, show_psnr: bool) -> Self {
+  pub fn new(frame_rate: Rational, total_frames: Option<usize>, show_psnr: bool, show_ssim: bool) -> Self {
     Self {
       frame_rate,
       total_frames,
       time_started: Instant::now(),
       frame_info: Vec::with_capacity(total_frames.unwrap_or_default()),
+      recent_frames: VecDeque::with_capacity(RECENT_FRAMES + 1),
       encoded_size: 0,
       show_psnr,
+      show_ssim,
     }
   }
 
   pub fn add_frame(&mut self, frame: FrameSummary) {
     self.encoded_size += frame.size;
     self.frame_info.push(frame);
+    self.recent_frames.push_back(Instant::now());
+    if self.recent_frames.len() > RECENT_FRAMES {
+      self.recent_frames.pop_front();
+    }
   }
 
   pub fn frames_encoded(&self) -> usize {
@@ -645,6 +1519,18 @@ impl ProgressInfo {
     self.frame_info.len() as f64 / (duration.as_secs() as f64 + duration.subsec_millis() as f64 / 1000f64)
   }
 
+  // Encoding speed over the last frames
+  pub fn current_fps(&self) -> f64 {
+    match (self.recent_frames.front(), self.recent_frames.back()) {
+      (Some(first), Some(last)) if self.recent_frames.len() > 1 => {
+        let duration = last.duration_since(*first);
+        (self.recent_frames.len() - 1) as f64
+          / (duration.as_secs() as f64 + duration.subsec_millis() as f64 / 1000f64)
+      }
+      _ => self.encoding_fps()
+    }
+  }
+
   pub fn video_fps(&self) -> f64 {
     self.frame_rate.num as f64 / self.frame_rate.den as f64
   }
@@ -666,7 +1552,9 @@ impl ProgressInfo {
   // Estimates the remaining encoding time in seconds, if the number of frames is known
   pub fn estimated_time(&self) -> f64 {
     self.total_frames
-      .map(|frames| (frames - self.frames_encoded()) as f64 / self.encoding_fps())
+      .map(|frames| {
+        frames.saturating_sub(self.frames_encoded()) as f64 / self.encoding_fps()
+      })
       .unwrap_or_default()
   }
 
@@ -707,7 +1595,7 @@ impl ProgressInfo {
     Inter:      {:>6}    avg size: {:>7} B\n\
     Intra Only: {:>6}    avg size: {:>7} B\n\
     Switch:     {:>6}    avg size: {:>7} B\
-    {}",
+    {}{}",
       key, key_size / key,
       inter, inter_size.checked_div(inter).unwrap_or(0),
       ionly, ionly_size / key,
@@ -722,9 +1610,34 @@ impl ProgressInfo {
         let psnr_v =
           self.frame_info.iter().map(|fi| fi.psnr.unwrap().2).sum::<f64>()
             / self.frame_info.len() as f64;
-        format!("\nMean PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}  Avg: {:.4}",
+        // The global PSNR is that of the mean squared error of all frames
+        let global_psnr = |psnr: &dyn Fn(&FrameSummary) -> f64| {
+          let mean_error = self.frame_info.iter()
+            .map(|fi| 10f64.powf(-psnr(fi) / 10.0))
+            .sum::<f64>() / self.frame_info.len() as f64;
+          -10.0 * mean_error.log10()
+        };
+        format!("\nMean PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}  Avg: {:.4}\n\
+                 Global PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}",
                 psnr_y, psnr_u, psnr_v,
-                (psnr_y + psnr_u + psnr_v) / 3.0)
+                (psnr_y + psnr_u + psnr_v) / 3.0,
+                global_psnr(&|fi| fi.psnr.unwrap().0),
+                global_psnr(&|fi| fi.psnr.unwrap().1),
+                global_psnr(&|fi| fi.psnr.unwrap().2))
+      } else { String::new() },
+      if self.show_ssim {
+        let mean = |metric: &dyn Fn(&FrameSummary) -> f64| {
+          self.frame_info.iter().map(metric).sum::<f64>()
+            / self.frame_info.len() as f64
+        };
+        format!("\nMean SSIM: Y: {:.4}  Cb: {:.4}  Cr: {:.4}\n\
+                 Mean MS-SSIM: Y: {:.4}  Cb: {:.4}  Cr: {:.4}",
+                mean(&|fi| fi.ssim.unwrap().0),
+                mean(&|fi| fi.ssim.unwrap().1),
+                mean(&|fi| fi.ssim.unwrap().2),
+                mean(&|fi| fi.ms_ssim.unwrap().0),
+                mean(&|fi| fi.ms_ssim.unwrap().1),
+                mean(&|fi| fi.ms_ssim.unwrap().2))
       } else { String::new() }
     )
   }
@@ -735,22 +1648,53 @@ impl fmt::Display for ProgressInfo {
     if let Some(total_frames) = self.total_frames {
       write!(
         f,
-        "encoded {}/{} frames, {:.3} fps, {:.2} Kb/s, est. size: {:.2} MB, est. time: {:.0} s",
+        "encoded {}/{} frames, {:.3} fps (avg {:.3}), {:.2} Kb/s, est. size: {:.2} MB, ETA: {}",
         self.frames_encoded(),
         total_frames,
+        self.current_fps(),
         self.encoding_fps(),
         self.bitrate() as f64 / 1000f64,
         self.estimated_size() as f64 / (1024 * 1024) as f64,
-        self.estimated_time()
+        format_duration(self.estimated_time())
       )
     } else {
       write!(
         f,
-        "encoded {} frames, {:.3} fps, {:.2} Kb/s",
+        "encoded {} frames, {:.3} fps (avg {:.3}), {:.2} Kb/s",
         self.frames_encoded(),
+        self.current_fps(),
         self.encoding_fps(),
         self.bitrate() as f64 / 1000f64
       )
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn frame_rates() {
+    assert_eq!(parse_frame_rate("30"), (30, 1));
+    assert_eq!(parse_frame_rate("29.97"), (2997, 100));
+    assert_eq!(parse_frame_rate("23.976"), (2997, 125));
+    assert_eq!(parse_frame_rate("12.5"), (25, 2));
+    assert_eq!(parse_frame_rate("25.0"), (25, 1));
+    assert_eq!(parse_frame_rate(".5"), (1, 2));
+    assert_eq!(parse_frame_rate("30000/1001"), (30000, 1001));
+    assert_eq!(parse_frame_rate("60/2"), (30, 1));
+  }
+
+  #[test]
+  #[should_panic(expected = "Cannot parse the frame rate")]
+  fn invalid_frame_rate() {
+    parse_frame_rate("29,97");
+  }
+
+  #[test]
+  #[should_panic(expected = "The frame rate must be positive")]
+  fn null_frame_rate() {
+    parse_frame_rate("0.0");
+  }
+}
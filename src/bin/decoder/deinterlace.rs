@@ -0,0 +1,58 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+/// Order of the fields of the input frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interlacing {
+  Progressive,
+  TopFieldFirst,
+  BottomFieldFirst,
+  /// Given for each frame, which is not read
+  Mixed,
+}
+
+/// Filter turning the interlaced frames of the input into progressive ones
+/// before they are encoded, as AV1 has no field coding
+pub trait Deinterlacer: Send {
+  /// Deinterlaces a plane of `width` by `height` samples in place
+  fn deinterlace(
+    &mut self, plane: &mut [u16], width: usize, height: usize,
+    interlacing: Interlacing
+  );
+}
+
+/// Linear blend: a vertical [1 2 1] filter over each row and its neighbors,
+/// which belong to the other field
+///
+/// It leaves no combing, at the cost of some vertical detail, and does not
+/// depend on the field order.
+pub struct BlendDeinterlacer;
+
+impl Deinterlacer for BlendDeinterlacer {
+  fn deinterlace(
+    &mut self, plane: &mut [u16], width: usize, height: usize,
+    _interlacing: Interlacing
+  ) {
+    if height < 3 {
+      return;
+    }
+    // The unfiltered previous row, mirrored at the edges
+    let mut above = plane[width..2 * width].to_vec();
+    for y in 0..height {
+      for x in 0..width {
+        let current = plane[y * width + x];
+        let below =
+          if y + 1 < height { plane[(y + 1) * width + x] } else { above[x] };
+        let sum = above[x] as u32 + 2 * current as u32 + below as u32;
+        above[x] = current;
+        plane[y * width + x] = ((sum + 2) >> 2) as u16;
+      }
+    }
+  }
+}
@@ -1,7 +1,10 @@
 use std::io;
+use std::io::Read;
 use rav1e::prelude::*;
 
+pub mod deinterlace;
 pub mod y4m;
+pub mod yuv;
 
 
 pub trait Decoder {
@@ -9,6 +12,38 @@ pub trait Decoder {
   fn read_frame<T: Pixel>(&mut self, cfg: &VideoDetails) -> Result<Frame<T>, DecodeError>;
 }
 
+/// Decoder of either supported input format
+pub enum InputDecoder<'d> {
+  Y4m(y4m::Y4mDecoder<'d, Box<dyn Read + Send>>),
+  Yuv(yuv::YuvDecoder<'d, Box<dyn Read + Send>>),
+}
+
+impl InputDecoder<'_> {
+  /// Number of frames of the input, if its header gives it
+  pub fn length(&self) -> Option<usize> {
+    match self {
+      InputDecoder::Y4m(dec) => dec.length(),
+      InputDecoder::Yuv(_) => None,
+    }
+  }
+}
+
+impl Decoder for InputDecoder<'_> {
+  fn get_video_details(&self) -> VideoDetails {
+    match self {
+      InputDecoder::Y4m(dec) => dec.get_video_details(),
+      InputDecoder::Yuv(dec) => dec.get_video_details(),
+    }
+  }
+
+  fn read_frame<T: Pixel>(&mut self, cfg: &VideoDetails) -> Result<Frame<T>, DecodeError> {
+    match self {
+      InputDecoder::Y4m(dec) => dec.read_frame(cfg),
+      InputDecoder::Yuv(dec) => dec.read_frame(cfg),
+    }
+  }
+}
+
 #[derive(Debug)]
 pub enum DecodeError {
   EOF,
@@ -25,6 +60,7 @@ pub struct VideoDetails {
   pub bit_depth: usize,
   pub chroma_sampling: ChromaSampling,
   pub chroma_sample_position: ChromaSamplePosition,
+  pub pixel_range: PixelRange,
   pub time_base: Rational,
 }
 
@@ -36,7 +72,50 @@ impl Default for VideoDetails {
       bit_depth: 8,
       chroma_sampling: ChromaSampling::Cs420,
       chroma_sample_position: ChromaSamplePosition::Unknown,
+      pixel_range: PixelRange::Limited,
       time_base: Rational { num: 30, den: 1 }
     }
   }
 }
+
+impl VideoDetails {
+  /// Bytes taken by the luma plane and by each chroma plane of a raw frame
+  pub fn plane_sizes(&self) -> (usize, usize) {
+    let bytes = if self.bit_depth > 8 { 2 } else { 1 };
+    let luma = self.width * self.height * bytes;
+    if self.chroma_sampling == ChromaSampling::Cs400 {
+      return (luma, 0);
+    }
+    let (xdec, ydec) = self.chroma_sampling.sampling_period();
+    let chroma_width = (self.width + xdec - 1) / xdec;
+    let chroma_height = (self.height + ydec - 1) / ydec;
+    (luma, chroma_width * chroma_height * bytes)
+  }
+}
+
+/// Builds a frame from the raw planes of an input, with samples of one byte,
+/// or two little endian bytes for high bit depths
+pub fn frame_from_planes<T: Pixel>(
+  cfg: &VideoDetails, y: &[u8], u: &[u8], v: &[u8]
+) -> Frame<T> {
+  let bytes = if cfg.bit_depth > 8 { 2 } else { 1 };
+  let mut f: Frame<T> = Frame::new(cfg.width, cfg.height, cfg.chroma_sampling);
+
+  let (chroma_period, _) = cfg.chroma_sampling.sampling_period();
+  let chroma_width = (cfg.width + chroma_period - 1) / chroma_period;
+
+  f.planes[0].copy_from_raw_u8(y, cfg.width * bytes, bytes);
+  if cfg.chroma_sampling == ChromaSampling::Cs400 {
+    // Monochrome input has no chroma, which is left neutral
+    let neutral = T::cast_from(1u16 << (cfg.bit_depth - 1));
+    for p in 1..3 {
+      for v in f.planes[p].data.iter_mut() {
+        *v = neutral;
+      }
+    }
+  } else {
+    f.planes[1].copy_from_raw_u8(u, chroma_width * bytes, bytes);
+    f.planes[2].copy_from_raw_u8(v, chroma_width * bytes, bytes);
+  }
+  f
+}
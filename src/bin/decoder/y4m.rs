@@ -1,80 +1,394 @@
+use std::io;
 use std::io::Read;
+use std::str;
 
+use crate::decoder::deinterlace::{Deinterlacer, Interlacing};
+use crate::decoder::frame_from_planes;
 use crate::decoder::DecodeError;
 use crate::decoder::Decoder;
 use crate::decoder::VideoDetails;
 use crate::Frame;
 use rav1e::prelude::*;
 
-impl Decoder for y4m::Decoder<'_, Box<dyn Read>> {
-  fn get_video_details(&self) -> VideoDetails {
-    let width = self.get_width();
-    let height = self.get_height();
-    let color_space = self.get_colorspace();
-    let bit_depth = color_space.get_bit_depth();
-    let (chroma_sampling, chroma_sample_position) = map_y4m_color_space(color_space);
-    let framerate = self.get_framerate();
-    let time_base =  Rational::new(framerate.den as u64, framerate.num as u64);
-
-    VideoDetails {
-      width,
-      height,
-      bit_depth,
-      chroma_sampling,
-      chroma_sample_position,
-      time_base,
+/// Longest header line accepted, past which the input is taken for garbage
+const MAX_HEADER_LEN: usize = 4096;
+
+/// Reader of YUV4MPEG2 streams
+///
+/// Besides the tags of the format, it reads the `XCOLORRANGE`, `XYSCSS` and
+/// `XLENGTH` extensions, the high bit depth and monochrome colorspaces
+/// written by FFmpeg, and tolerates CRLF line endings, repeated spaces,
+/// unknown tags and a missing frame rate or colorspace. Interlaced frames
+/// are read as they are, unless a deinterlacer is set.
+pub struct Y4mDecoder<'d, R: Read> {
+  reader: &'d mut R,
+  details: VideoDetails,
+  interlacing: Interlacing,
+  length: Option<usize>,
+  deinterlacer: Option<Box<dyn Deinterlacer>>,
+  buffer: Vec<u8>,
+}
+
+impl<'d, R: Read> Y4mDecoder<'d, R> {
+  pub fn new(reader: &'d mut R) -> Result<Self, DecodeError> {
+    let line = read_line(reader)?;
+    let mut tokens = line.split(|&b| b == b' ').filter(|t| !t.is_empty());
+    if tokens.next() != Some(&b"YUV4MPEG2"[..]) {
+      return Err(DecodeError::BadInput);
+    }
+
+    let mut width = None;
+    let mut height = None;
+    let mut time_base = None;
+    let mut colorspace = None;
+    let mut legacy_colorspace = None;
+    let mut pixel_range = PixelRange::Limited;
+    let mut interlacing = Interlacing::Progressive;
+    let mut length = None;
+    for token in tokens {
+      let value = match str::from_utf8(&token[1..]) {
+        Ok(value) => value,
+        Err(_) => continue,
+      };
+      match token[0] {
+        b'W' => width = Some(parse_dimension(value)?),
+        b'H' => height = Some(parse_dimension(value)?),
+        b'F' => time_base = parse_ratio(value)
+          .filter(|&(num, den)| num != 0 && den != 0)
+          .map(|(num, den)| Rational::new(den, num)),
+        b'C' => colorspace = Some(
+          parse_colorspace(value).ok_or(DecodeError::UnknownColorspace)?
+        ),
+        b'I' => interlacing = match value {
+          "t" => Interlacing::TopFieldFirst,
+          "b" => Interlacing::BottomFieldFirst,
+          "m" => Interlacing::Mixed,
+          _ => Interlacing::Progressive,
+        },
+        b'X' => {
+          let mut param = value.splitn(2, '=');
+          match (param.next(), param.next()) {
+            (Some("COLORRANGE"), Some("FULL")) => pixel_range = PixelRange::Full,
+            (Some("COLORRANGE"), Some("LIMITED")) => {
+              pixel_range = PixelRange::Limited
+            }
+            // The colorspace of mjpegtools before the C420p10 tags
+            (Some("YSCSS"), Some(tag)) => legacy_colorspace = parse_colorspace(tag),
+            (Some("LENGTH"), Some(frames)) => length = frames.parse().ok(),
+            _ => {}
+          }
+        }
+        // Aspect ratio, and tags of later versions of the format
+        _ => {}
+      }
+    }
+
+    let (width, height) = match (width, height) {
+      (Some(width), Some(height)) => (width, height),
+      _ => return Err(DecodeError::ParseError),
+    };
+    let time_base = time_base.unwrap_or_else(|| {
+      log::warn!("The input has no frame rate, assuming 30 fps");
+      Rational::new(1, 30)
+    });
+    let (chroma_sampling, chroma_sample_position, bit_depth) = colorspace
+      .or(legacy_colorspace)
+      .unwrap_or((ChromaSampling::Cs420, ChromaSamplePosition::Unknown, 8));
+
+    Ok(Y4mDecoder {
+      reader,
+      details: VideoDetails {
+        width,
+        height,
+        bit_depth,
+        chroma_sampling,
+        chroma_sample_position,
+        pixel_range,
+        time_base,
+      },
+      interlacing,
+      length,
+      deinterlacer: None,
+      buffer: Vec::new(),
+    })
+  }
+
+  pub fn interlacing(&self) -> Interlacing {
+    self.interlacing
+  }
+
+  /// Number of frames given by the `XLENGTH` extension
+  pub fn length(&self) -> Option<usize> {
+    self.length
+  }
+
+  /// Filters every frame read with `deinterlacer`
+  pub fn set_deinterlacer(&mut self, deinterlacer: Box<dyn Deinterlacer>) {
+    self.deinterlacer = Some(deinterlacer);
+  }
+
+  /// Runs the deinterlacer over the planes of the frame in `buffer`
+  fn deinterlace(&mut self) {
+    let deinterlacer = match self.deinterlacer.as_mut() {
+      Some(deinterlacer) => deinterlacer,
+      None => return,
+    };
+    let details = &self.details;
+    let bytes = if details.bit_depth > 8 { 2 } else { 1 };
+    let mut planes = vec![(details.width, details.height)];
+    if details.chroma_sampling != ChromaSampling::Cs400 {
+      let (xdec, ydec) = details.chroma_sampling.sampling_period();
+      let chroma = (
+        (details.width + xdec - 1) / xdec,
+        (details.height + ydec - 1) / ydec,
+      );
+      planes.push(chroma);
+      planes.push(chroma);
+    }
+
+    let mut offset = 0;
+    let mut samples = Vec::new();
+    for (width, height) in planes {
+      let raw = &mut self.buffer[offset..offset + width * height * bytes];
+      samples.clear();
+      samples.extend(raw.chunks(bytes).map(|s| {
+        if bytes == 2 { u16::from_le_bytes([s[0], s[1]]) } else { s[0] as u16 }
+      }));
+      deinterlacer.deinterlace(&mut samples, width, height, self.interlacing);
+      for (s, &v) in raw.chunks_mut(bytes).zip(samples.iter()) {
+        if bytes == 2 {
+          s.copy_from_slice(&v.to_le_bytes());
+        } else {
+          s[0] = v as u8;
+        }
+      }
+      offset += width * height * bytes;
     }
   }
+}
+
+impl<'d, R: Read> Decoder for Y4mDecoder<'d, R> {
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
 
   fn read_frame<T: Pixel>(&mut self, cfg: &VideoDetails) -> Result<Frame<T>, DecodeError> {
-    let bytes = self.get_bytes_per_sample();
-    self.read_frame()
-      .map(|frame| {
-        let mut f: Frame<T> = Frame::new(cfg.width, cfg.height, cfg.chroma_sampling);
-
-        let (chroma_period, _) = cfg.chroma_sampling.sampling_period();
-
-        f.planes[0].copy_from_raw_u8(frame.get_y_plane(), cfg.width * bytes, bytes);
-        f.planes[1].copy_from_raw_u8(
-          frame.get_u_plane(),
-          cfg.width * bytes / chroma_period,
-          bytes
-        );
-        f.planes[2].copy_from_raw_u8(
-          frame.get_v_plane(),
-          cfg.width * bytes / chroma_period,
-          bytes
-        );
-        f
-      })
-      .map_err(Into::into)
+    // Frame parameters are ignored
+    let line = read_line(self.reader)?;
+    if !line.starts_with(b"FRAME") {
+      return Err(DecodeError::ParseError);
+    }
+
+    let (luma, chroma) = self.details.plane_sizes();
+    self.buffer.resize(luma + 2 * chroma, 0);
+    self.reader.read_exact(&mut self.buffer).map_err(|e| {
+      match e.kind() {
+        // A truncated last frame is dropped
+        io::ErrorKind::UnexpectedEof => DecodeError::EOF,
+        _ => DecodeError::IoError(e),
+      }
+    })?;
+    self.deinterlace();
+
+    let (y, uv) = self.buffer.split_at(luma);
+    let (u, v) = uv.split_at(chroma);
+    if cfg.chroma_sampling == ChromaSampling::Cs400 {
+      Ok(frame_from_planes(cfg, y, &[], &[]))
+    } else {
+      Ok(frame_from_planes(cfg, y, u, v))
+    }
   }
 }
 
-impl From<y4m::Error> for DecodeError {
-  fn from(e: y4m::Error) -> DecodeError {
-    match e {
-      y4m::Error::EOF => DecodeError::EOF,
-      y4m::Error::BadInput => DecodeError::BadInput,
-      y4m::Error::UnknownColorspace => DecodeError::UnknownColorspace,
-      y4m::Error::ParseError => DecodeError::ParseError,
-      y4m::Error::IoError(e) => DecodeError::IoError(e),
+/// Reads a header line without its line ending, `DecodeError::EOF` if the
+/// input ends before it
+fn read_line<R: Read>(reader: &mut R) -> Result<Vec<u8>, DecodeError> {
+  let mut line = Vec::new();
+  let mut byte = [0u8];
+  loop {
+    match reader.read(&mut byte) {
+      Ok(0) => return Err(DecodeError::EOF),
+      Ok(_) if byte[0] == b'\n' => break,
+      Ok(_) => line.push(byte[0]),
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(DecodeError::IoError(e)),
     }
+    if line.len() > MAX_HEADER_LEN {
+      return Err(DecodeError::ParseError);
+    }
+  }
+  if line.last() == Some(&b'\r') {
+    line.pop();
   }
+  Ok(line)
 }
 
-pub fn map_y4m_color_space(
-  color_space: y4m::Colorspace
-) -> (ChromaSampling, ChromaSamplePosition) {
+fn parse_dimension(value: &str) -> Result<usize, DecodeError> {
+  match value.parse() {
+    Ok(0) | Err(_) => Err(DecodeError::ParseError),
+    Ok(v) => Ok(v),
+  }
+}
+
+fn parse_ratio(value: &str) -> Option<(u64, u64)> {
+  let mut parts = value.splitn(2, ':');
+  match (parts.next(), parts.next()) {
+    (Some(num), Some(den)) => Some((num.parse().ok()?, den.parse().ok()?)),
+    _ => None,
+  }
+}
+
+/// The chroma sampling, chroma sample position and bit depth of a `C` tag,
+/// or of an `XYSCSS` one, whatever the case
+fn parse_colorspace(
+  tag: &str
+) -> Option<(ChromaSampling, ChromaSamplePosition, usize)> {
+  use crate::ChromaSampling::*;
+  use crate::ChromaSamplePosition::*;
+  let tag = tag.to_ascii_lowercase();
+  let (layout, bit_depth) = if tag.starts_with("mono") && tag.len() > 4 {
+    ("mono", tag[4..].parse().ok()?)
+  } else {
+    match tag.find('p') {
+      // Not the p of "420jpeg" or "420paldv", 8-bit layouts
+      Some(i) if tag[i + 1..].starts_with(|c: char| c.is_ascii_digit()) => {
+        (&tag[..i], tag[i + 1..].parse().ok()?)
+      }
+      _ => (&tag[..], 8),
+    }
+  };
+  if bit_depth != 8 && bit_depth != 10 && bit_depth != 12 {
+    return None;
+  }
+  let (chroma_sampling, chroma_sample_position) = match layout {
+    "mono" => (Cs400, Unknown),
+    "420jpeg" | "420paldv" => (Cs420, Unknown),
+    "420mpeg2" => (Cs420, Vertical),
+    "420" => (Cs420, Colocated),
+    "422" => (Cs422, Colocated),
+    "444" => (Cs444, Colocated),
+    _ => return None,
+  };
+  Some((chroma_sampling, chroma_sample_position, bit_depth))
+}
+
+/// The Y4M color space of input frames, for e.g. writing the reconstruction
+/// of a raw input
+pub fn y4m_color_space(details: &VideoDetails) -> y4m::Colorspace {
   use y4m::Colorspace::*;
   use crate::ChromaSampling::*;
+  match (details.chroma_sampling, details.bit_depth) {
+    (Cs400, _) => Cmono,
+    (Cs420, 8) => match details.chroma_sample_position {
+      ChromaSamplePosition::Vertical => C420mpeg2,
+      ChromaSamplePosition::Unknown => C420jpeg,
+      _ => C420,
+    },
+    (Cs420, 10) => C420p10,
+    (Cs420, _) => C420p12,
+    (Cs422, 8) => C422,
+    (Cs422, 10) => C422p10,
+    (Cs422, _) => C422p12,
+    (Cs444, 8) => C444,
+    (Cs444, 10) => C444p10,
+    (Cs444, _) => C444p12,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
   use crate::ChromaSamplePosition::*;
-  match color_space {
-    Cmono => (Cs400, Unknown),
-    C420jpeg | C420paldv => (Cs420, Unknown),
-    C420mpeg2 => (Cs420, Vertical),
-    C420 | C420p10 | C420p12 => (Cs420, Colocated),
-    C422 | C422p10 | C422p12 => (Cs422, Colocated),
-    C444 | C444p10 | C444p12 => (Cs444, Colocated),
+  use crate::ChromaSampling::*;
+
+  #[test]
+  fn colorspace_tags() {
+    assert_eq!(parse_colorspace("mono"), Some((Cs400, Unknown, 8)));
+    assert_eq!(parse_colorspace("mono10"), Some((Cs400, Unknown, 10)));
+    assert_eq!(parse_colorspace("mono12"), Some((Cs400, Unknown, 12)));
+    assert_eq!(parse_colorspace("mono16"), None);
+    assert_eq!(parse_colorspace("420p10"), Some((Cs420, Colocated, 10)));
+    assert_eq!(parse_colorspace("422p12"), Some((Cs422, Colocated, 12)));
+    assert_eq!(parse_colorspace("444p10"), Some((Cs444, Colocated, 10)));
+    assert_eq!(parse_colorspace("420p9"), None);
+    assert_eq!(parse_colorspace("420jpeg"), Some((Cs420, Unknown, 8)));
+    assert_eq!(parse_colorspace("420paldv"), Some((Cs420, Unknown, 8)));
+    assert_eq!(parse_colorspace("420mpeg2"), Some((Cs420, Vertical, 8)));
+    assert_eq!(parse_colorspace("411"), None);
+    // The XYSCSS tags of mjpegtools are upper case
+    assert_eq!(parse_colorspace("420P10"), Some((Cs420, Colocated, 10)));
+    assert_eq!(parse_colorspace("444"), Some((Cs444, Colocated, 8)));
+  }
+
+  #[test]
+  fn extended_header() {
+    let mut input: &[u8] = b"YUV4MPEG2  W4 H2 F25:1 Ip A1:1 C420p10 \
+      XCOLORRANGE=FULL XLENGTH=3 XFOO=bar Zunknown\r\n";
+    let dec = Y4mDecoder::new(&mut input).unwrap();
+    let details = dec.get_video_details();
+    assert_eq!((details.width, details.height), (4, 2));
+    assert_eq!((details.time_base.num, details.time_base.den), (1, 25));
+    assert_eq!(details.chroma_sampling, Cs420);
+    assert_eq!(details.chroma_sample_position, Colocated);
+    assert_eq!(details.bit_depth, 10);
+    assert_eq!(details.pixel_range, PixelRange::Full);
+    assert_eq!(dec.length(), Some(3));
+    assert_eq!(dec.interlacing(), Interlacing::Progressive);
+  }
+
+  #[test]
+  fn legacy_colorspace_and_defaults() {
+    let mut input: &[u8] = b"YUV4MPEG2 W16 H16 XYSCSS=422P10\n";
+    let dec = Y4mDecoder::new(&mut input).unwrap();
+    let details = dec.get_video_details();
+    assert_eq!((details.chroma_sampling, details.bit_depth), (Cs422, 10));
+    // A missing frame rate is taken for 30 fps
+    assert_eq!((details.time_base.num, details.time_base.den), (1, 30));
+    assert_eq!(details.pixel_range, PixelRange::Limited);
+    assert_eq!(dec.length(), None);
+
+    // The C tag wins over XYSCSS
+    let mut input: &[u8] = b"YUV4MPEG2 W16 H16 XYSCSS=422P10 C444\n";
+    let dec = Y4mDecoder::new(&mut input).unwrap();
+    let details = dec.get_video_details();
+    assert_eq!((details.chroma_sampling, details.bit_depth), (Cs444, 8));
+  }
+
+  fn header_error(mut input: &[u8]) -> String {
+    match Y4mDecoder::new(&mut input) {
+      Ok(_) => panic!("the header was accepted"),
+      Err(e) => format!("{:?}", e),
+    }
+  }
+
+  #[test]
+  fn invalid_headers() {
+    assert_eq!(header_error(b"YUV4MPEG W16 H16\n"), "BadInput");
+    assert_eq!(header_error(b"YUV4MPEG2 W16\n"), "ParseError");
+    assert_eq!(header_error(b"YUV4MPEG2 W0 H16\n"), "ParseError");
+    assert_eq!(header_error(b"YUV4MPEG2 W16 H16 C411\n"), "UnknownColorspace");
+    assert_eq!(header_error(b"YUV4MPEG2 W16 H16"), "EOF");
+  }
+
+  #[test]
+  fn crlf_frames() {
+    let mut input: &[u8] = b"YUV4MPEG2 W2 H2 Cmono\r\n\
+      FRAME\r\n\x01\x02\x03\x04\
+      FRAME\r\n\x05";
+    let mut dec = Y4mDecoder::new(&mut input).unwrap();
+    let details = dec.get_video_details();
+    assert_eq!(details.chroma_sampling, Cs400);
+    let frame = dec.read_frame::<u8>(&details).unwrap();
+    let luma = &frame.planes[0];
+    assert_eq!(
+      [luma.p(0, 0), luma.p(1, 0), luma.p(0, 1), luma.p(1, 1)],
+      [1, 2, 3, 4]
+    );
+    // The truncated last frame is dropped
+    match dec.read_frame::<u8>(&details) {
+      Err(DecodeError::EOF) => {}
+      Err(e) => panic!("{:?}", e),
+      Ok(_) => panic!("the truncated frame was read"),
+    }
   }
 }
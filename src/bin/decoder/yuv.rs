@@ -0,0 +1,75 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use std::io;
+use std::io::Read;
+
+use crate::decoder::{frame_from_planes, DecodeError, Decoder, VideoDetails};
+use rav1e::prelude::*;
+
+/// Pixel formats of raw input, named as in FFmpeg. Samples wider than 8 bits
+/// take two little endian bytes.
+pub const PIXEL_FORMATS: &[&str] = &[
+  "yuv420p", "yuv422p", "yuv444p", "gray",
+  "yuv420p10le", "yuv422p10le", "yuv444p10le", "gray10le",
+  "yuv420p12le", "yuv422p12le", "yuv444p12le", "gray12le",
+];
+
+/// The chroma sampling and bit depth of a pixel format from `PIXEL_FORMATS`
+pub fn parse_pixel_format(format: &str) -> Option<(ChromaSampling, usize)> {
+  let (layout, bit_depth) = match format {
+    f if f.ends_with("10le") => (&f[..f.len() - 4], 10),
+    f if f.ends_with("12le") => (&f[..f.len() - 4], 12),
+    f => (f, 8),
+  };
+  let chroma_sampling = match layout {
+    "yuv420p" => ChromaSampling::Cs420,
+    "yuv422p" => ChromaSampling::Cs422,
+    "yuv444p" => ChromaSampling::Cs444,
+    "gray" => ChromaSampling::Cs400,
+    _ => return None,
+  };
+  Some((chroma_sampling, bit_depth))
+}
+
+/// Reader of bare planar frames, whose geometry is given rather than read
+/// from a header
+pub struct YuvDecoder<'d, R: Read> {
+  reader: &'d mut R,
+  details: VideoDetails,
+  buffer: Vec<u8>,
+}
+
+impl<'d, R: Read> YuvDecoder<'d, R> {
+  pub fn new(reader: &'d mut R, details: VideoDetails) -> Self {
+    YuvDecoder { reader, details, buffer: Vec::new() }
+  }
+}
+
+impl<'d, R: Read> Decoder for YuvDecoder<'d, R> {
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
+
+  fn read_frame<T: Pixel>(&mut self, cfg: &VideoDetails) -> Result<Frame<T>, DecodeError> {
+    let (luma, chroma) = self.details.plane_sizes();
+    self.buffer.resize(luma + 2 * chroma, 0);
+    self.reader.read_exact(&mut self.buffer).map_err(|e| {
+      match e.kind() {
+        // A truncated last frame is dropped
+        io::ErrorKind::UnexpectedEof => DecodeError::EOF,
+        _ => DecodeError::IoError(e),
+      }
+    })?;
+
+    let (y, uv) = self.buffer.split_at(luma);
+    let (u, v) = uv.split_at(chroma);
+    Ok(frame_from_planes(cfg, y, u, v))
+  }
+}
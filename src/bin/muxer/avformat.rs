@@ -31,6 +31,10 @@ pub struct AvformatMuxer {
   duration: i64,
 }
 
+// The context is only ever used by the thread owning the muxer
+#[cfg(feature = "avformat-sys")]
+unsafe impl Send for AvformatMuxer {}
+
 #[cfg(feature = "avformat-sys")]
 impl AvformatMuxer {
   pub fn open(path: &str) -> Box<dyn Muxer> {
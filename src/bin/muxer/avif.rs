@@ -0,0 +1,204 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::Muxer;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+const COLOR_ITEM_ID: u16 = 1;
+const ALPHA_ITEM_ID: u16 = 2;
+const ALPHA_URN: &[u8] = b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0";
+
+/// AV1 Image File Format muxer, storing the frame of a still picture encode
+/// as the primary item, along with an optional alpha plane item
+pub struct AvifMuxer {
+  output: Box<dyn Write + Send>,
+  width: u32,
+  height: u32,
+  config: Vec<u8>,
+  alpha: Option<(Vec<u8>, Vec<u8>)>,
+  written: bool,
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], payload: &[u8]) {
+  out.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+  out.extend_from_slice(kind);
+  out.extend_from_slice(payload);
+}
+
+fn write_full_box(
+  out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]
+) {
+  let mut full = Vec::with_capacity(4 + payload.len());
+  full.push(version);
+  full.extend_from_slice(&flags.to_be_bytes()[1..]);
+  full.extend_from_slice(payload);
+  write_box(out, kind, &full);
+}
+
+/// Items hold a single temporal unit without its temporal delimiter
+fn item_data(data: &[u8]) -> &[u8] {
+  if data.starts_with(&[0x12, 0x00]) { &data[2..] } else { data }
+}
+
+/// Pixel information property matching an av1C record
+fn pixi(config: &[u8]) -> Vec<u8> {
+  let high_bitdepth = config[2] & 0x40 != 0;
+  let twelve_bit = config[2] & 0x20 != 0;
+  let monochrome = config[2] & 0x10 != 0;
+  let bit_depth = if twelve_bit { 12 } else if high_bitdepth { 10 } else { 8 };
+  let channels = if monochrome { 1 } else { 3 };
+  let mut pixi = vec![channels];
+  pixi.extend((0..channels).map(|_| bit_depth));
+  pixi
+}
+
+impl AvifMuxer {
+  pub fn open(path: &str) -> Box<dyn Muxer> {
+    let avif = AvifMuxer {
+      output: Box::new(File::create(&path).unwrap()),
+      width: 0,
+      height: 0,
+      config: Vec::new(),
+      alpha: None,
+      written: false,
+    };
+    Box::new(avif)
+  }
+
+  fn meta(&self, data_offset: u32, color_len: u32) -> Vec<u8> {
+    let mut items = vec![(COLOR_ITEM_ID, data_offset, color_len)];
+    if let Some((ref data, _)) = self.alpha {
+      let alpha_len = item_data(data).len() as u32;
+      items.push((ALPHA_ITEM_ID, data_offset + color_len, alpha_len));
+    }
+
+    let mut meta = Vec::new();
+
+    let mut hdlr = vec![0; 4]; // pre_defined
+    hdlr.extend_from_slice(b"pict");
+    hdlr.extend_from_slice(&[0; 12]); // reserved
+    hdlr.push(0); // empty name
+    write_full_box(&mut meta, b"hdlr", 0, 0, &hdlr);
+
+    write_full_box(&mut meta, b"pitm", 0, 0, &COLOR_ITEM_ID.to_be_bytes());
+
+    // 4-byte offsets and lengths, no base offset
+    let mut iloc = vec![0x44, 0x00];
+    iloc.extend_from_slice(&(items.len() as u16).to_be_bytes());
+    for &(id, offset, len) in items.iter() {
+      iloc.extend_from_slice(&id.to_be_bytes());
+      iloc.extend_from_slice(&0u16.to_be_bytes()); // data_reference_index
+      iloc.extend_from_slice(&1u16.to_be_bytes()); // extent_count
+      iloc.extend_from_slice(&offset.to_be_bytes());
+      iloc.extend_from_slice(&len.to_be_bytes());
+    }
+    write_full_box(&mut meta, b"iloc", 0, 0, &iloc);
+
+    let mut iinf = (items.len() as u16).to_be_bytes().to_vec();
+    for &(id, _, _) in items.iter() {
+      let mut infe = id.to_be_bytes().to_vec();
+      infe.extend_from_slice(&0u16.to_be_bytes()); // item_protection_index
+      infe.extend_from_slice(b"av01");
+      infe.push(0); // empty name
+      write_full_box(&mut iinf, b"infe", 2, 0, &infe);
+    }
+    write_full_box(&mut meta, b"iinf", 0, 0, &iinf);
+
+    // Property indices are 1-based, the high bit marking essential ones
+    let mut ipco = Vec::new();
+    let mut ispe = self.width.to_be_bytes().to_vec();
+    ispe.extend_from_slice(&self.height.to_be_bytes());
+    write_full_box(&mut ipco, b"ispe", 0, 0, &ispe);
+    write_box(&mut ipco, b"av1C", &self.config);
+    write_full_box(&mut ipco, b"pixi", 0, 0, &pixi(&self.config));
+    let mut ipma = Vec::new();
+    ipma.extend_from_slice(&COLOR_ITEM_ID.to_be_bytes());
+    ipma.extend_from_slice(&[3, 0x01, 0x82, 0x03]);
+
+    if let Some((_, ref config)) = self.alpha {
+      write_box(&mut ipco, b"av1C", config);
+      write_full_box(&mut ipco, b"pixi", 0, 0, &pixi(config));
+      write_full_box(&mut ipco, b"auxC", 0, 0, ALPHA_URN);
+      ipma.extend_from_slice(&ALPHA_ITEM_ID.to_be_bytes());
+      ipma.extend_from_slice(&[4, 0x01, 0x84, 0x05, 0x86]);
+
+      let mut auxl = ALPHA_ITEM_ID.to_be_bytes().to_vec();
+      auxl.extend_from_slice(&1u16.to_be_bytes()); // reference_count
+      auxl.extend_from_slice(&COLOR_ITEM_ID.to_be_bytes());
+      let mut iref = Vec::new();
+      write_box(&mut iref, b"auxl", &auxl);
+      write_full_box(&mut meta, b"iref", 0, 0, &iref);
+    }
+
+    let mut iprp = Vec::new();
+    write_box(&mut iprp, b"ipco", &ipco);
+    let mut ipma_full = (items.len() as u32).to_be_bytes().to_vec();
+    ipma_full.extend_from_slice(&ipma);
+    write_full_box(&mut iprp, b"ipma", 0, 0, &ipma_full);
+    write_box(&mut meta, b"iprp", &iprp);
+
+    let mut meta_box = Vec::new();
+    write_full_box(&mut meta_box, b"meta", 0, 0, &meta);
+    meta_box
+  }
+}
+
+impl Muxer for AvifMuxer {
+  fn write_header(
+    &mut self, width: usize, height: usize, _framerate_num: usize,
+    _framerate_den: usize
+  ) {
+    self.width = width as u32;
+    self.height = height as u32;
+  }
+
+  fn write_codec_config(&mut self, config: &[u8]) {
+    self.config = config.to_vec();
+  }
+
+  fn write_alpha(&mut self, data: &[u8], config: &[u8]) {
+    self.alpha = Some((data.to_vec(), config.to_vec()));
+  }
+
+  fn write_frame(&mut self, _pts: u64, data: &[u8], _frame_type: FrameType) {
+    assert!(!self.written, "AVIF output only holds a single frame");
+    self.written = true;
+
+    let color = item_data(data);
+
+    let mut ftyp = Vec::new();
+    let mut brands = b"avif".to_vec();
+    brands.extend_from_slice(&0u32.to_be_bytes()); // minor_version
+    brands.extend_from_slice(b"avifmif1miaf");
+    write_box(&mut ftyp, b"ftyp", &brands);
+
+    // The meta box size does not depend on the offsets it holds
+    let meta_len = self.meta(0, color.len() as u32).len();
+    let data_offset = (ftyp.len() + meta_len + 8) as u32;
+    let meta = self.meta(data_offset, color.len() as u32);
+
+    let mut mdat = color.to_vec();
+    if let Some((ref alpha, _)) = self.alpha {
+      mdat.extend_from_slice(item_data(alpha));
+    }
+
+    self.output.write_all(&ftyp).unwrap();
+    self.output.write_all(&meta).unwrap();
+    self.output.write_all(&(8 + mdat.len() as u32).to_be_bytes()).unwrap();
+    self.output.write_all(b"mdat").unwrap();
+    self.output.write_all(&mdat).unwrap();
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
@@ -6,7 +6,7 @@ use std::io;
 use std::io::Write;
 
 pub struct IvfMuxer {
-  output: Box<dyn Write>,
+  output: Box<dyn Write + Send>,
 }
 
 impl Muxer for IvfMuxer {
@@ -0,0 +1,375 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::Muxer;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549_A966;
+const TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMECODE: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const CUES: u32 = 0x1C53_BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+/// Timestamps are in milliseconds
+const TIMECODE_SCALE_NS: u64 = 1_000_000;
+/// Size field of elements whose size is patched in once known
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+fn write_id(out: &mut Vec<u8>, id: u32) {
+  let bytes = id.to_be_bytes();
+  let skip = bytes.iter().take_while(|&&b| b == 0).count();
+  out.extend_from_slice(&bytes[skip..]);
+}
+
+fn write_size(out: &mut Vec<u8>, size: u64) {
+  let len = (1..8).find(|&n| size < (1 << (7 * n)) - 1).unwrap_or(8);
+  let vint = size | (1 << (7 * len));
+  out.extend_from_slice(&vint.to_be_bytes()[8 - len as usize..]);
+}
+
+/// Size field of 8 bytes, to be overwritten in place
+fn fixed_size(size: u64) -> [u8; 8] {
+  let mut vint = size.to_be_bytes();
+  vint[0] = 0x01;
+  vint
+}
+
+fn element(out: &mut Vec<u8>, id: u32, payload: &[u8]) {
+  write_id(out, id);
+  write_size(out, payload.len() as u64);
+  out.extend_from_slice(payload);
+}
+
+fn uint_element(out: &mut Vec<u8>, id: u32, value: u64) {
+  let bytes = value.to_be_bytes();
+  let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+  element(out, id, &bytes[skip..]);
+}
+
+fn string_element(out: &mut Vec<u8>, id: u32, value: &str) {
+  element(out, id, value.as_bytes());
+}
+
+/// Blocks hold a temporal unit without its temporal delimiter
+fn block_data(data: &[u8]) -> &[u8] {
+  if data.starts_with(&[0x12, 0x00]) { &data[2..] } else { data }
+}
+
+struct Cluster {
+  /// Offset of its size field in the file
+  size_offset: u64,
+  timecode: u64,
+}
+
+/// Matroska and WebM muxer. The headers are written with the first frame,
+/// once the codec configuration is known, and the cues and element sizes
+/// when the muxer is dropped.
+pub struct MatroskaMuxer {
+  output: BufWriter<File>,
+  doc_type: &'static str,
+  width: usize,
+  height: usize,
+  framerate_num: u64,
+  framerate_den: u64,
+  codec_private: Vec<u8>,
+  /// Current offset in the file
+  pos: u64,
+  /// Offset of the segment data, which seek positions are relative to
+  segment_offset: u64,
+  cues_seek_offset: u64,
+  duration_offset: u64,
+  cluster: Option<Cluster>,
+  /// Timecode and segment position of the clusters starting with a key
+  /// frame
+  cues: Vec<(u64, u64)>,
+  end_timecode: u64,
+}
+
+impl MatroskaMuxer {
+  pub fn open(path: &str, webm: bool) -> Box<dyn Muxer> {
+    let mkv = MatroskaMuxer {
+      output: BufWriter::new(File::create(&path).unwrap()),
+      doc_type: if webm { "webm" } else { "matroska" },
+      width: 0,
+      height: 0,
+      framerate_num: 30,
+      framerate_den: 1,
+      codec_private: Vec::new(),
+      pos: 0,
+      segment_offset: 0,
+      cues_seek_offset: 0,
+      duration_offset: 0,
+      cluster: None,
+      cues: Vec::new(),
+      end_timecode: 0,
+    };
+    Box::new(mkv)
+  }
+
+  fn write(&mut self, data: &[u8]) -> io::Result<()> {
+    self.output.write_all(data)?;
+    self.pos += data.len() as u64;
+    Ok(())
+  }
+
+  fn patch(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+    self.output.seek(SeekFrom::Start(offset))?;
+    self.output.write_all(data)?;
+    self.output.seek(SeekFrom::Start(self.pos))?;
+    Ok(())
+  }
+
+  fn timecode(&self, pts: u64) -> u64 {
+    pts * 1000 * self.framerate_den / self.framerate_num
+  }
+
+  /// Timecode of `pts` relative to the current cluster, or an error if it
+  /// precedes the cluster
+  fn relative_timecode(&self, cluster: &Cluster, pts: u64) -> io::Result<u64> {
+    self.timecode(pts).checked_sub(cluster.timecode).ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Frame {} precedes the start of its cluster", pts)
+      )
+    })
+  }
+
+  fn write_block(
+    &mut self, pts: u64, data: &[u8], keyframe: bool
+  ) -> io::Result<()> {
+    let timecode = self.timecode(pts);
+    // Block timecodes are signed 16-bit offsets from the cluster timecode
+    let new_cluster = match self.cluster {
+      Some(ref cluster) =>
+        keyframe || self.relative_timecode(cluster, pts)? > 32767,
+      None => true
+    };
+    if new_cluster {
+      self.open_cluster(timecode, keyframe)?;
+    }
+    let relative_timecode =
+      self.relative_timecode(self.cluster.as_ref().unwrap(), pts)? as i16;
+
+    let data = block_data(data);
+    let mut block = Vec::new();
+    write_id(&mut block, SIMPLE_BLOCK);
+    write_size(&mut block, 4 + data.len() as u64);
+    block.push(0x81); // track number
+    block.extend_from_slice(&relative_timecode.to_be_bytes());
+    block.push(if keyframe { 0x80 } else { 0 });
+    self.write(&block)?;
+    self.write(data)?;
+
+    self.end_timecode = self.timecode(pts + 1);
+    Ok(())
+  }
+
+  fn write_headers(&mut self) -> io::Result<()> {
+    let mut header = Vec::new();
+    let mut ebml = Vec::new();
+    uint_element(&mut ebml, EBML_VERSION, 1);
+    uint_element(&mut ebml, EBML_READ_VERSION, 1);
+    uint_element(&mut ebml, EBML_MAX_ID_LENGTH, 4);
+    uint_element(&mut ebml, EBML_MAX_SIZE_LENGTH, 8);
+    string_element(&mut ebml, DOC_TYPE, self.doc_type);
+    uint_element(&mut ebml, DOC_TYPE_VERSION, 4);
+    uint_element(&mut ebml, DOC_TYPE_READ_VERSION, 2);
+    element(&mut header, EBML, &ebml);
+
+    write_id(&mut header, SEGMENT);
+    header.extend_from_slice(&UNKNOWN_SIZE);
+    self.write(&header)?;
+    self.segment_offset = self.pos;
+
+    let mut info = Vec::new();
+    uint_element(&mut info, TIMECODE_SCALE, TIMECODE_SCALE_NS);
+    string_element(&mut info, MUXING_APP, "rav1e");
+    string_element(&mut info, WRITING_APP, "rav1e");
+    // Patched in at the end
+    write_id(&mut info, DURATION);
+    write_size(&mut info, 8);
+    let duration_pos = info.len();
+    info.extend_from_slice(&0f64.to_bits().to_be_bytes());
+    let mut info_element = Vec::new();
+    element(&mut info_element, INFO, &info);
+
+    let mut video = Vec::new();
+    uint_element(&mut video, PIXEL_WIDTH, self.width as u64);
+    uint_element(&mut video, PIXEL_HEIGHT, self.height as u64);
+    let mut track = Vec::new();
+    uint_element(&mut track, TRACK_NUMBER, 1);
+    uint_element(&mut track, TRACK_UID, 1);
+    uint_element(&mut track, TRACK_TYPE, 1); // video
+    uint_element(&mut track, FLAG_LACING, 0);
+    string_element(&mut track, CODEC_ID, "V_AV1");
+    element(&mut track, CODEC_PRIVATE, &self.codec_private);
+    uint_element(
+      &mut track,
+      DEFAULT_DURATION,
+      1_000_000_000 * self.framerate_den / self.framerate_num
+    );
+    element(&mut track, VIDEO, &video);
+    let mut tracks = Vec::new();
+    element(&mut tracks, TRACK_ENTRY, &track);
+    let mut tracks_element = Vec::new();
+    element(&mut tracks_element, TRACKS, &tracks);
+
+    // Seek positions of 8 bytes each, so that the one of the cues can be
+    // patched in at the end
+    let seek_head_size = 3 * (2 + 1 + (2 + 1 + 4) + (2 + 1 + 8)) + 4 + 1;
+    let info_position = seek_head_size as u64;
+    let tracks_position = info_position + info_element.len() as u64;
+    let mut seek_head = Vec::new();
+    for &(id, position) in
+      [(INFO, info_position), (TRACKS, tracks_position), (CUES, 0)].iter()
+    {
+      let mut seek = Vec::new();
+      element(&mut seek, SEEK_ID, &id.to_be_bytes());
+      write_id(&mut seek, SEEK_POSITION);
+      write_size(&mut seek, 8);
+      seek.extend_from_slice(&position.to_be_bytes());
+      element(&mut seek_head, SEEK, &seek);
+    }
+    let mut seek_head_element = Vec::new();
+    element(&mut seek_head_element, SEEK_HEAD, &seek_head);
+    assert_eq!(seek_head_element.len(), seek_head_size);
+
+    self.cues_seek_offset = self.pos + seek_head_size as u64 - 8;
+    self.write(&seek_head_element)?;
+    self.duration_offset = self.pos + (info_element.len() - info.len()
+      + duration_pos) as u64;
+    self.write(&info_element)?;
+    self.write(&tracks_element)?;
+    Ok(())
+  }
+
+  fn close_cluster(&mut self) -> io::Result<()> {
+    if let Some(cluster) = self.cluster.take() {
+      let size = self.pos - cluster.size_offset - 8;
+      self.patch(cluster.size_offset, &fixed_size(size))?;
+    }
+    Ok(())
+  }
+
+  fn open_cluster(&mut self, timecode: u64, keyframe: bool) -> io::Result<()> {
+    self.close_cluster()?;
+    if keyframe {
+      self.cues.push((timecode, self.pos - self.segment_offset));
+    }
+    let mut header = Vec::new();
+    write_id(&mut header, CLUSTER);
+    let size_offset = self.pos + header.len() as u64;
+    header.extend_from_slice(&UNKNOWN_SIZE);
+    uint_element(&mut header, TIMECODE, timecode);
+    self.write(&header)?;
+    self.cluster = Some(Cluster { size_offset, timecode });
+    Ok(())
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    if self.pos == 0 {
+      return Ok(());
+    }
+    self.close_cluster()?;
+
+    let cues_position = self.pos - self.segment_offset;
+    let mut cues = Vec::new();
+    for &(timecode, position) in self.cues.iter() {
+      let mut positions = Vec::new();
+      uint_element(&mut positions, CUE_TRACK, 1);
+      uint_element(&mut positions, CUE_CLUSTER_POSITION, position);
+      let mut cue_point = Vec::new();
+      uint_element(&mut cue_point, CUE_TIME, timecode);
+      element(&mut cue_point, CUE_TRACK_POSITIONS, &positions);
+      element(&mut cues, CUE_POINT, &cue_point);
+    }
+    let mut cues_element = Vec::new();
+    element(&mut cues_element, CUES, &cues);
+    self.write(&cues_element)?;
+
+    let segment_size = self.pos - self.segment_offset;
+    let duration = self.end_timecode as f64;
+    self.patch(self.segment_offset - 8, &fixed_size(segment_size))?;
+    self.patch(self.cues_seek_offset, &cues_position.to_be_bytes())?;
+    self.patch(self.duration_offset, &duration.to_bits().to_be_bytes())?;
+    self.output.flush()
+  }
+}
+
+impl Muxer for MatroskaMuxer {
+  fn write_header(
+    &mut self, width: usize, height: usize, framerate_num: usize,
+    framerate_den: usize
+  ) {
+    self.width = width;
+    self.height = height;
+    self.framerate_num = framerate_num as u64;
+    self.framerate_den = framerate_den as u64;
+  }
+
+  fn write_codec_config(&mut self, config: &[u8]) {
+    self.codec_private = config.to_vec();
+  }
+
+  fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType) {
+    if self.pos == 0 {
+      self.write_headers().unwrap();
+    }
+    self.write_block(pts, data, frame_type == FrameType::KEY).unwrap();
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
+
+impl Drop for MatroskaMuxer {
+  fn drop(&mut self) {
+    if let Err(e) = self.finish() {
+      eprintln!("Failed to finish the Matroska file: {}", e);
+    }
+  }
+}
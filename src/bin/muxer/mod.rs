@@ -7,37 +7,65 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+mod avif;
+use self::avif::AvifMuxer;
+
 mod ivf;
 use self::ivf::IvfMuxer;
 
+mod matroska;
+use self::matroska::MatroskaMuxer;
+
 mod mp4;
 use mp4::Mp4Muxer;
 
+mod obu;
+use self::obu::ObuMuxer;
+pub use self::obu::ObuFormat;
+
 mod y4m;
-pub use self::y4m::write_y4m_frame;
+pub use self::y4m::DebugOutputs;
 
 use rav1e::prelude::*;
 
 #[cfg(feature = "avformat-sys")]
 mod avformat;
+#[cfg(feature = "avformat-sys")]
+use self::avformat::AvformatMuxer;
 
 use std::io;
 use std::ffi::OsStr;
 use std::path::Path;
 
 
-pub trait Muxer {
+/// Muxers are `Send` so that packets can be written on their own thread
+pub trait Muxer: Send {
   fn write_header(
     &mut self, width: usize, height: usize, framerate_num: usize,
     framerate_den: usize
   );
 
+  /// Codec configuration record from `Context::container_sequence_header`,
+  /// for containers storing it out of band
+  fn write_codec_config(&mut self, _config: &[u8]) {}
+
+  /// Coded alpha plane of a still picture and its codec configuration
+  fn write_alpha(&mut self, _data: &[u8], _config: &[u8]) {
+    panic!("Only AVIF output supports an alpha plane");
+  }
+
   fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType);
 
   fn flush(&mut self) -> io::Result<()>;
 }
 
-pub fn create_muxer(path: &str) -> Box<dyn Muxer> {
+/// Picks the muxer from the extension of `path`, unless a raw OBU stream
+/// format is given
+pub fn create_muxer(path: &str, obu: Option<ObuFormat>) -> Box<dyn Muxer> {
+  if let Some(format) = obu {
+    return ObuMuxer::open(path, format);
+  }
+
   if path == "-" {
     return IvfMuxer::open(path);
   }
@@ -49,14 +77,31 @@ pub fn create_muxer(path: &str) -> Box<dyn Muxer> {
     .unwrap_or_else(|| "ivf".into());
 
   match &ext[..] {
+    "avif" => {
+      AvifMuxer::open(path)
+    }
     "mp4" => {
       Mp4Muxer::open(path)
     }
     "ivf" => {
       IvfMuxer::open(path)
     }
+    "obu" => {
+      ObuMuxer::open(path, ObuFormat::Section5)
+    }
+    "mkv" => {
+      MatroskaMuxer::open(path, false)
+    }
+    "webm" => {
+      MatroskaMuxer::open(path, true)
+    }
+    #[cfg(feature = "avformat-sys")]
+    _e => {
+      AvformatMuxer::open(path)
+    }
+    #[cfg(not(feature = "avformat-sys"))]
     _e => {
-      panic!("{} is not a supported extension, please change to .ivf", ext);
+      panic!("{} is not a supported extension, please change to .ivf, .obu, .mp4, .mkv, .webm or .avif, or build with --features=\"avformat-sys\"", ext);
     }
   }
 }
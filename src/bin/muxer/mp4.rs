@@ -1,4 +1,4 @@
-// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+// Copyright (c) 2017-2019, The rav1e contributors. All rights reserved
 //
 // This source code is subject to the terms of the BSD 2 Clause License and
 // the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
@@ -8,22 +8,335 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use super::Muxer;
+use rav1e::prelude::*;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 
-#[cfg(feature = "avformat-sys")]
-use super::avformat::AvformatMuxer;
+const MATRIX: [u32; 9] =
+  [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
 
-pub struct Mp4Muxer {
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], payload: &[u8]) {
+  out.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+  out.extend_from_slice(kind);
+  out.extend_from_slice(payload);
+}
+
+fn write_full_box(
+  out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]
+) {
+  let mut full = Vec::with_capacity(4 + payload.len());
+  full.push(version);
+  full.extend_from_slice(&flags.to_be_bytes()[1..]);
+  full.extend_from_slice(payload);
+  write_box(out, kind, &full);
+}
 
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+  out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+  out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_matrix(out: &mut Vec<u8>) {
+  for &v in MATRIX.iter() {
+    put_u32(out, v);
+  }
+}
+
+/// Samples hold a temporal unit without its temporal delimiter
+fn sample_data(data: &[u8]) -> &[u8] {
+  if data.starts_with(&[0x12, 0x00]) { &data[2..] } else { data }
+}
+
+/// ISOBMFF muxer with an `av01` sample entry. The samples are written to
+/// the mdat box as they come, all in one chunk, and the moov box when the
+/// muxer is dropped.
+pub struct Mp4Muxer {
+  output: BufWriter<File>,
+  width: u16,
+  height: u16,
+  /// Media timescale, the frame rate numerator
+  timescale: u32,
+  /// Nominal duration of a frame, the frame rate denominator
+  sample_delta: u32,
+  av1c: Vec<u8>,
+  /// Current offset in the file
+  pos: u64,
+  /// Offset of the mdat box, whose 64-bit size is patched in at the end
+  mdat_offset: u64,
+  sample_sizes: Vec<u32>,
+  /// Time stamps of the samples, in frame rate units
+  sample_pts: Vec<u64>,
+  /// 1-based numbers of the key frame samples
+  sync_samples: Vec<u32>,
 }
 
-#[allow(unused_variables)]
 impl Mp4Muxer {
   pub fn open(path: &str) -> Box<dyn Muxer> {
-    #[cfg(feature = "avformat-sys")] {
-      AvformatMuxer::open(path)
+    let mp4 = Mp4Muxer {
+      output: BufWriter::new(File::create(&path).unwrap()),
+      width: 0,
+      height: 0,
+      timescale: 30,
+      sample_delta: 1,
+      av1c: Vec::new(),
+      pos: 0,
+      mdat_offset: 0,
+      sample_sizes: Vec::new(),
+      sample_pts: Vec::new(),
+      sync_samples: Vec::new(),
+    };
+    Box::new(mp4)
+  }
+
+  fn write(&mut self, data: &[u8]) -> io::Result<()> {
+    self.output.write_all(data)?;
+    self.pos += data.len() as u64;
+    Ok(())
+  }
+
+  fn write_headers(&mut self) -> io::Result<()> {
+    let mut ftyp_payload = b"isom".to_vec();
+    put_u32(&mut ftyp_payload, 0x200); // minor_version
+    ftyp_payload.extend_from_slice(b"isomiso6av01mp41");
+    let mut header = Vec::new();
+    write_box(&mut header, b"ftyp", &ftyp_payload);
+
+    self.mdat_offset = header.len() as u64;
+    put_u32(&mut header, 1); // 64-bit largesize follows
+    header.extend_from_slice(b"mdat");
+    header.extend_from_slice(&0u64.to_be_bytes());
+    self.write(&header)
+  }
+
+  fn sample_entry(&self) -> Vec<u8> {
+    let mut av01 = vec![0; 6]; // reserved
+    put_u16(&mut av01, 1); // data_reference_index
+    av01.extend_from_slice(&[0; 16]); // pre_defined and reserved
+    put_u16(&mut av01, self.width);
+    put_u16(&mut av01, self.height);
+    put_u32(&mut av01, 0x0048_0000); // 72 dpi
+    put_u32(&mut av01, 0x0048_0000);
+    put_u32(&mut av01, 0); // reserved
+    put_u16(&mut av01, 1); // frame_count
+    let mut compressorname = [0u8; 32];
+    let name = b"AOM Coding";
+    compressorname[0] = name.len() as u8;
+    compressorname[1..=name.len()].copy_from_slice(name);
+    av01.extend_from_slice(&compressorname);
+    put_u16(&mut av01, 0x0018); // depth
+    put_u16(&mut av01, 0xFFFF); // pre_defined
+    write_box(&mut av01, b"av1C", &self.av1c);
+
+    let mut stsd = Vec::new();
+    put_u32(&mut stsd, 1);
+    write_box(&mut stsd, b"av01", &av01);
+    stsd
+  }
+
+  /// Sample durations in the media timescale, run-length coded as in the
+  /// stts box. The last sample gets the nominal frame duration. Fails if
+  /// the time stamps decrease or a duration overflows.
+  fn sample_durations(&self) -> io::Result<Vec<(u32, u32)>> {
+    let mut durations: Vec<(u32, u32)> = Vec::new();
+    for (i, &pts) in self.sample_pts.iter().enumerate() {
+      let delta = match self.sample_pts.get(i + 1) {
+        Some(&next) => next
+          .checked_sub(pts)
+          .and_then(|frames| u32::try_from(frames).ok())
+          .and_then(|frames| frames.checked_mul(self.sample_delta))
+          .ok_or_else(|| {
+            io::Error::new(
+              io::ErrorKind::InvalidInput,
+              format!("Invalid duration of the frame at time stamp {}", pts)
+            )
+          })?,
+        None => self.sample_delta,
+      };
+      match durations.last_mut() {
+        Some((count, last)) if *last == delta => *count += 1,
+        _ => durations.push((1, delta)),
+      }
+    }
+    Ok(durations)
+  }
+
+  fn moov(&self, data_offset: u64) -> io::Result<Vec<u8>> {
+    let sample_count = self.sample_sizes.len() as u32;
+    let durations = self.sample_durations()?;
+    let duration: u32 =
+      durations.iter().map(|&(count, delta)| count * delta).sum();
+
+    let mut stbl = Vec::new();
+    write_full_box(&mut stbl, b"stsd", 0, 0, &self.sample_entry());
+    let mut stts = Vec::new();
+    put_u32(&mut stts, durations.len() as u32);
+    for &(count, delta) in durations.iter() {
+      put_u32(&mut stts, count);
+      put_u32(&mut stts, delta);
+    }
+    write_full_box(&mut stbl, b"stts", 0, 0, &stts);
+    let mut stss = Vec::new();
+    put_u32(&mut stss, self.sync_samples.len() as u32);
+    for &sample in self.sync_samples.iter() {
+      put_u32(&mut stss, sample);
     }
-    #[cfg(not(feature = "avformat-sys"))] {
-      panic!("need avformat-sys for .mp4, please build with --features=\"avformat-sys\", or you can use .ivf extension");
+    write_full_box(&mut stbl, b"stss", 0, 0, &stss);
+    // A single chunk holding every sample
+    let mut stsc = Vec::new();
+    put_u32(&mut stsc, 1);
+    put_u32(&mut stsc, 1); // first_chunk
+    put_u32(&mut stsc, sample_count);
+    put_u32(&mut stsc, 1); // sample_description_index
+    write_full_box(&mut stbl, b"stsc", 0, 0, &stsc);
+    let mut stsz = Vec::new();
+    put_u32(&mut stsz, 0); // sample_size, 0 for a table
+    put_u32(&mut stsz, sample_count);
+    for &size in self.sample_sizes.iter() {
+      put_u32(&mut stsz, size);
+    }
+    write_full_box(&mut stbl, b"stsz", 0, 0, &stsz);
+    let mut co64 = Vec::new();
+    put_u32(&mut co64, 1);
+    co64.extend_from_slice(&data_offset.to_be_bytes());
+    write_full_box(&mut stbl, b"co64", 0, 0, &co64);
+
+    let mut dref = Vec::new();
+    put_u32(&mut dref, 1);
+    write_full_box(&mut dref, b"url ", 0, 1, &[]); // media in this file
+    let mut dinf = Vec::new();
+    write_full_box(&mut dinf, b"dref", 0, 0, &dref);
+
+    let mut minf = Vec::new();
+    write_full_box(&mut minf, b"vmhd", 0, 1, &[0; 8]);
+    write_box(&mut minf, b"dinf", &dinf);
+    write_box(&mut minf, b"stbl", &stbl);
+
+    let mut mdhd = Vec::new();
+    put_u32(&mut mdhd, 0); // creation_time
+    put_u32(&mut mdhd, 0); // modification_time
+    put_u32(&mut mdhd, self.timescale);
+    put_u32(&mut mdhd, duration);
+    put_u16(&mut mdhd, 0x55C4); // "und"
+    put_u16(&mut mdhd, 0);
+    let mut hdlr = vec![0; 4]; // pre_defined
+    hdlr.extend_from_slice(b"vide");
+    hdlr.extend_from_slice(&[0; 12]); // reserved
+    hdlr.extend_from_slice(b"VideoHandler\0");
+    let mut mdia = Vec::new();
+    write_full_box(&mut mdia, b"mdhd", 0, 0, &mdhd);
+    write_full_box(&mut mdia, b"hdlr", 0, 0, &hdlr);
+    write_box(&mut mdia, b"minf", &minf);
+
+    // Frames come out in display order, so the edit list maps the whole
+    // media from its start, without any reordering delay to skip
+    let mut elst = Vec::new();
+    put_u32(&mut elst, 1);
+    put_u32(&mut elst, duration); // segment_duration
+    put_u32(&mut elst, 0); // media_time
+    put_u16(&mut elst, 1); // media_rate_integer
+    put_u16(&mut elst, 0); // media_rate_fraction
+    let mut edts = Vec::new();
+    write_full_box(&mut edts, b"elst", 0, 0, &elst);
+
+    let mut tkhd = Vec::new();
+    put_u32(&mut tkhd, 0); // creation_time
+    put_u32(&mut tkhd, 0); // modification_time
+    put_u32(&mut tkhd, 1); // track_ID
+    put_u32(&mut tkhd, 0); // reserved
+    put_u32(&mut tkhd, duration);
+    tkhd.extend_from_slice(&[0; 8]); // reserved
+    put_u16(&mut tkhd, 0); // layer
+    put_u16(&mut tkhd, 0); // alternate_group
+    put_u16(&mut tkhd, 0); // volume
+    put_u16(&mut tkhd, 0); // reserved
+    put_matrix(&mut tkhd);
+    put_u32(&mut tkhd, u32::from(self.width) << 16);
+    put_u32(&mut tkhd, u32::from(self.height) << 16);
+    let mut trak = Vec::new();
+    // track_enabled | track_in_movie
+    write_full_box(&mut trak, b"tkhd", 0, 3, &tkhd);
+    write_box(&mut trak, b"edts", &edts);
+    write_box(&mut trak, b"mdia", &mdia);
+
+    // The movie has the timescale of the media
+    let mut mvhd = Vec::new();
+    put_u32(&mut mvhd, 0); // creation_time
+    put_u32(&mut mvhd, 0); // modification_time
+    put_u32(&mut mvhd, self.timescale);
+    put_u32(&mut mvhd, duration);
+    put_u32(&mut mvhd, 0x0001_0000); // rate
+    put_u16(&mut mvhd, 0x0100); // volume
+    mvhd.extend_from_slice(&[0; 10]); // reserved
+    put_matrix(&mut mvhd);
+    mvhd.extend_from_slice(&[0; 24]); // pre_defined
+    put_u32(&mut mvhd, 2); // next_track_ID
+
+    let mut moov = Vec::new();
+    write_full_box(&mut moov, b"mvhd", 0, 0, &mvhd);
+    write_box(&mut moov, b"trak", &trak);
+    let mut moov_box = Vec::new();
+    write_box(&mut moov_box, b"moov", &moov);
+    Ok(moov_box)
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    if self.pos == 0 {
+      return Ok(());
+    }
+
+    let mdat_size = self.pos - self.mdat_offset;
+    let moov = self.moov(self.mdat_offset + 16)?;
+    self.write(&moov)?;
+
+    self.output.seek(SeekFrom::Start(self.mdat_offset + 8))?;
+    self.output.write_all(&mdat_size.to_be_bytes())?;
+    self.output.flush()
+  }
+}
+
+impl Muxer for Mp4Muxer {
+  fn write_header(
+    &mut self, width: usize, height: usize, framerate_num: usize,
+    framerate_den: usize
+  ) {
+    self.width = width as u16;
+    self.height = height as u16;
+    self.timescale = framerate_num as u32;
+    self.sample_delta = framerate_den as u32;
+  }
+
+  fn write_codec_config(&mut self, config: &[u8]) {
+    self.av1c = config.to_vec();
+  }
+
+  fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType) {
+    if self.pos == 0 {
+      self.write_headers().unwrap();
+    }
+
+    let data = sample_data(data);
+    self.write(data).unwrap();
+    self.sample_sizes.push(data.len() as u32);
+    self.sample_pts.push(pts);
+    if frame_type == FrameType::KEY {
+      self.sync_samples.push(self.sample_sizes.len() as u32);
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
+
+impl Drop for Mp4Muxer {
+  fn drop(&mut self) {
+    if let Err(e) = self.finish() {
+      eprintln!("Failed to finish the MP4 file: {}", e);
     }
   }
 }
@@ -0,0 +1,146 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::Muxer;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+const OBU_FRAME_HEADER: u8 = 3;
+const OBU_FRAME: u8 = 6;
+
+/// Layout of a raw OBU stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObuFormat {
+  /// Low overhead bitstream format, OBUs with their size fields back to back
+  Section5,
+  /// Length delimited bitstream format of Annex B
+  AnnexB,
+}
+
+impl std::str::FromStr for ObuFormat {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "section5" => Ok(ObuFormat::Section5),
+      "annexb" => Ok(ObuFormat::AnnexB),
+      _ => Err(()),
+    }
+  }
+}
+
+/// Muxer writing the temporal units as a raw OBU stream, without any
+/// container
+pub struct ObuMuxer {
+  output: Box<dyn Write + Send>,
+  format: ObuFormat,
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: u64) {
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if value == 0 {
+      out.push(byte);
+      break;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn read_leb128(data: &[u8]) -> (u64, usize) {
+  let mut value = 0;
+  for (i, &byte) in data.iter().enumerate().take(8) {
+    value |= u64::from(byte & 0x7f) << (i * 7);
+    if byte & 0x80 == 0 {
+      return (value, i + 1);
+    }
+  }
+  panic!("Invalid leb128 value in the encoded OBUs");
+}
+
+/// Rewrites a low overhead temporal unit as an Annex B temporal_unit(),
+/// dropping the OBU size fields in favor of obu_length and grouping the
+/// OBUs into frame units
+fn annexb_temporal_unit(data: &[u8]) -> Vec<u8> {
+  let mut frame_units: Vec<Vec<u8>> = vec![Vec::new()];
+  let mut has_frame_header = false;
+  let mut pos = 0;
+  while pos < data.len() {
+    let obu_start = pos;
+    let header = data[pos];
+    let obu_type = (header >> 3) & 0xf;
+    let header_len = if header & 0x04 != 0 { 2 } else { 1 };
+    assert!(header & 0x02 != 0, "OBUs are expected to carry their size");
+    let (size, size_len) = read_leb128(&data[pos + header_len..]);
+    let payload_start = pos + header_len + size_len;
+    let payload = &data[payload_start..payload_start + size as usize];
+    pos = payload_start + size as usize;
+
+    // A frame unit holds a single frame, with its headers and tile groups
+    if obu_type == OBU_FRAME_HEADER || obu_type == OBU_FRAME {
+      if has_frame_header {
+        frame_units.push(Vec::new());
+      }
+      has_frame_header = true;
+    }
+
+    let frame_unit = frame_units.last_mut().unwrap();
+    write_leb128(frame_unit, (header_len + payload.len()) as u64);
+    frame_unit.push(header & !0x02); // obu_has_size_field = 0
+    frame_unit.extend_from_slice(&data[obu_start + 1..obu_start + header_len]);
+    frame_unit.extend_from_slice(payload);
+  }
+
+  let mut temporal_unit = Vec::new();
+  for frame_unit in frame_units.iter() {
+    write_leb128(&mut temporal_unit, frame_unit.len() as u64);
+    temporal_unit.extend_from_slice(frame_unit);
+  }
+  let mut out = Vec::with_capacity(temporal_unit.len() + 8);
+  write_leb128(&mut out, temporal_unit.len() as u64);
+  out.extend_from_slice(&temporal_unit);
+  out
+}
+
+impl ObuMuxer {
+  pub fn open(path: &str, format: ObuFormat) -> Box<dyn Muxer> {
+    let obu = ObuMuxer {
+      output: match path {
+        "-" => Box::new(std::io::stdout()),
+        f => Box::new(File::create(&f).unwrap())
+      },
+      format,
+    };
+    Box::new(obu)
+  }
+}
+
+impl Muxer for ObuMuxer {
+  fn write_header(
+    &mut self, _width: usize, _height: usize, _framerate_num: usize,
+    _framerate_den: usize
+  ) {
+  }
+
+  fn write_frame(&mut self, _pts: u64, data: &[u8], _frame_type: FrameType) {
+    match self.format {
+      ObuFormat::Section5 => self.output.write_all(data).unwrap(),
+      ObuFormat::AnnexB => {
+        self.output.write_all(&annexb_temporal_unit(data)).unwrap()
+      }
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
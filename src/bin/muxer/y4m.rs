@@ -15,10 +15,15 @@ use rav1e::prelude::*;
 pub fn write_y4m_frame<T: Pixel>(y4m_enc: &mut y4m::Encoder<'_, Box<dyn Write>>, rec: &Frame<T>, y4m_details: VideoDetails) {
   let pitch_y = if y4m_details.bit_depth > 8 { y4m_details.width * 2 } else { y4m_details.width };
   let chroma_sampling_period = y4m_details.chroma_sampling.sampling_period();
-  let (pitch_uv, height_uv) = (
-    pitch_y / chroma_sampling_period.0,
-    y4m_details.height / chroma_sampling_period.1
-  );
+  // Monochrome y4m frames have no chroma planes
+  let (pitch_uv, height_uv) = if y4m_details.chroma_sampling == ChromaSampling::Cs400 {
+    (0, 0)
+  } else {
+    (
+      pitch_y / chroma_sampling_period.0,
+      y4m_details.height / chroma_sampling_period.1
+    )
+  };
 
   let (mut rec_y, mut rec_u, mut rec_v) = (
     vec![128u8; pitch_y * y4m_details.height],
@@ -50,43 +55,90 @@ pub fn write_y4m_frame<T: Pixel>(y4m_enc: &mut y4m::Encoder<'_, Box<dyn Write>>,
       );
     }
   }
-  for (line, line_out) in rec.planes[1]
-    .data_origin()
-    .chunks(stride_u)
-    .zip(rec_u.chunks_mut(pitch_uv))
-  {
-    if y4m_details.bit_depth > 8 {
-      unsafe {
-        line_out.copy_from_slice(slice::from_raw_parts::<u8>(
-          line.as_ptr() as (*const u8),
-          pitch_uv
-        ));
+  if pitch_uv > 0 {
+    for (line, line_out) in rec.planes[1]
+      .data_origin()
+      .chunks(stride_u)
+      .zip(rec_u.chunks_mut(pitch_uv))
+    {
+      if y4m_details.bit_depth > 8 {
+        unsafe {
+          line_out.copy_from_slice(slice::from_raw_parts::<u8>(
+            line.as_ptr() as (*const u8),
+            pitch_uv
+          ));
+        }
+      } else {
+        line_out.copy_from_slice(
+          &line.iter().map(|&v| u8::cast_from(v)).collect::<Vec<u8>>()[..pitch_uv]
+        );
       }
-    } else {
-      line_out.copy_from_slice(
-        &line.iter().map(|&v| u8::cast_from(v)).collect::<Vec<u8>>()[..pitch_uv]
-      );
     }
-  }
-  for (line, line_out) in rec.planes[2]
-    .data_origin()
-    .chunks(stride_v)
-    .zip(rec_v.chunks_mut(pitch_uv))
-  {
-    if y4m_details.bit_depth > 8 {
-      unsafe {
-        line_out.copy_from_slice(slice::from_raw_parts::<u8>(
-          line.as_ptr() as (*const u8),
-          pitch_uv
-        ));
+    for (line, line_out) in rec.planes[2]
+      .data_origin()
+      .chunks(stride_v)
+      .zip(rec_v.chunks_mut(pitch_uv))
+    {
+      if y4m_details.bit_depth > 8 {
+        unsafe {
+          line_out.copy_from_slice(slice::from_raw_parts::<u8>(
+            line.as_ptr() as (*const u8),
+            pitch_uv
+          ));
+        }
+      } else {
+        line_out.copy_from_slice(
+          &line.iter().map(|&v| u8::cast_from(v)).collect::<Vec<u8>>()[..pitch_uv]
+        );
       }
-    } else {
-      line_out.copy_from_slice(
-        &line.iter().map(|&v| u8::cast_from(v)).collect::<Vec<u8>>()[..pitch_uv]
-      );
     }
   }
 
   let rec_frame = y4m::Frame::new([&rec_y, &rec_u, &rec_v], None);
   y4m_enc.write_frame(&rec_frame).unwrap();
 }
+
+/// Y4M outputs of the reconstruction, the prediction and the residual of
+/// the shown frames
+pub struct DebugOutputs<'a> {
+  pub rec: Option<y4m::Encoder<'a, Box<dyn Write>>>,
+  pub prediction: Option<y4m::Encoder<'a, Box<dyn Write>>>,
+  pub residual: Option<y4m::Encoder<'a, Box<dyn Write>>>
+}
+
+impl DebugOutputs<'_> {
+  pub fn write_packet<T: Pixel>(&mut self, pkt: &Packet<T>, y4m_details: VideoDetails) {
+    let rec = match pkt.rec {
+      Some(ref rec) => rec,
+      None => return
+    };
+    if let Some(ref mut y4m_enc) = self.rec {
+      write_y4m_frame(y4m_enc, rec, y4m_details);
+    }
+    // Frames without a prediction, e.g. those coded in an earlier packet,
+    // are copies of their reference with no residual
+    let prediction = pkt.prediction.as_ref().unwrap_or(rec);
+    if let Some(ref mut y4m_enc) = self.prediction {
+      write_y4m_frame(y4m_enc, prediction, y4m_details);
+    }
+    if let Some(ref mut y4m_enc) = self.residual {
+      let residual = residual_frame(rec, prediction, y4m_details.bit_depth);
+      write_y4m_frame(y4m_enc, &residual, y4m_details);
+    }
+  }
+}
+
+/// `rec` minus `prediction`, offset by half the pixel range so that a null
+/// residual shows as mid-gray. It also holds what the loop filters changed.
+fn residual_frame<T: Pixel>(rec: &Frame<T>, prediction: &Frame<T>, bit_depth: usize) -> Frame<T> {
+  let mid = 1i32 << (bit_depth - 1);
+  let max = (1i32 << bit_depth) - 1;
+  let mut residual = rec.clone();
+  for (res, pred) in residual.planes.iter_mut().zip(prediction.planes.iter()) {
+    for (r, &p) in res.data.iter_mut().zip(pred.data.iter()) {
+      let diff = i32::cast_from(*r) - i32::cast_from(p) + mid;
+      *r = T::cast_from(diff.max(0).min(max));
+    }
+  }
+  residual
+}
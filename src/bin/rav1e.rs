@@ -12,7 +12,11 @@
 mod common;
 mod decoder;
 mod muxer;
+mod stats_server;
+mod threaded_io;
 use crate::common::*;
+use crate::stats_server::*;
+use crate::threaded_io::*;
 use rav1e::prelude::*;
 
 use std::io;
@@ -22,7 +26,11 @@ use std::io::Seek;
 use std::path::Path;
 use std::sync::Arc;
 use crate::decoder::Decoder;
+use crate::decoder::deinterlace::{BlendDeinterlacer, Interlacing};
+use crate::decoder::InputDecoder;
 use crate::decoder::VideoDetails;
+use crate::decoder::y4m::{y4m_color_space, Y4mDecoder};
+use crate::decoder::yuv::YuvDecoder;
 use crate::muxer::*;
 use std::fs::File;
 use std::io::BufWriter;
@@ -36,47 +44,46 @@ struct Source<D: Decoder> {
 }
 
 impl<D: Decoder> Source<D> {
-  fn read_frame<T: Pixel>(&mut self, ctx: &mut Context<T>, video_info: VideoDetails) {
+  /// The next frame to encode, `None` once the input is exhausted, the
+  /// limit is reached or the exit is requested.
+  fn next_frame<T: Pixel>(&mut self, video_info: &VideoDetails) -> Option<Frame<T>> {
     if self.limit != 0 && self.count == self.limit {
-      ctx.flush();
-      return;
+      return None;
     }
 
     #[cfg(all(unix, feature = "signal-hook"))] {
       if self.exit_requested.load(std::sync::atomic::Ordering::SeqCst) {
-        ctx.flush();
-        return;
+        return None;
       }
     }
 
-    match self.input.read_frame(&video_info) {
+    match self.input.read_frame(video_info) {
       Ok(frame) => {
         match video_info.bit_depth {
           8 | 10 | 12 => {}
           _ => panic!("unknown input bit depth!")
         }
         self.count += 1;
-        let _ = ctx.send_frame(Some(Arc::new(frame)));
-      }
-      _ => {
-        ctx.flush();
+        Some(frame)
       }
-    };
+      _ => None
+    }
   }
 }
 
 // Encode and write a frame.
 // Returns frame information in a `Result`.
-fn process_frame<T: Pixel, D: Decoder>(
+fn process_frame<T: Pixel>(
   ctx: &mut Context<T>, output_file: &mut dyn Muxer,
-  source: &mut Source<D>,
+  next_frame: &mut dyn FnMut() -> Option<Frame<T>>,
+  y4m_details: VideoDetails,
   pass1file: Option<&mut File>,
   pass2file: Option<&mut File>,
   buffer: &mut [u8],
   buf_pos: &mut usize,
-  mut y4m_enc: Option<&mut y4m::Encoder<'_, Box<dyn Write>>>
+  debug_outputs: &mut DebugOutputs<'_>,
+  check_packet: &mut dyn FnMut(&Packet<T>)
 ) -> Option<Vec<FrameSummary>> {
-  let y4m_details = source.input.get_video_details();
   let mut frame_summaries = Vec::new();
   let mut pass1file = pass1file;
   let mut pass2file = pass2file;
@@ -118,14 +125,18 @@ fn process_frame<T: Pixel, D: Decoder>(
   let pkt_wrapped = ctx.receive_packet();
   match pkt_wrapped {
     Ok(pkt) => {
-      output_file.write_frame(pkt.input_frameno as u64, pkt.data.as_ref(), pkt.frame_type);
-      if let (Some(ref mut y4m_enc_uw), Some(ref rec)) = (y4m_enc.as_mut(), &pkt.rec) {
-        write_y4m_frame(y4m_enc_uw, rec, y4m_details);
-      }
+      check_packet(&pkt);
+      output_file.write_frame(pkt.pts, pkt.data.as_ref(), pkt.frame_type);
+      debug_outputs.write_packet(&pkt, y4m_details);
       frame_summaries.push(pkt.into());
     }
     Err(EncoderStatus::NeedMoreData) => {
-      source.read_frame(ctx, y4m_details);
+      match next_frame() {
+        Some(frame) => {
+          let _ = ctx.send_frame(Some(Arc::new(frame)));
+        }
+        None => ctx.flush()
+      }
     }
     Err(EncoderStatus::EnoughData) => {
       unreachable!();
@@ -155,6 +166,18 @@ fn process_frame<T: Pixel, D: Decoder>(
   Some(frame_summaries)
 }
 
+fn y4m_encoder<'a>(
+  output: &'a mut Box<dyn Write>, video_info: &VideoDetails
+) -> y4m::Encoder<'a, Box<dyn Write>> {
+  y4m::encode(
+    video_info.width,
+    video_info.height,
+    y4m::Ratio::new(video_info.time_base.den as usize, video_info.time_base.num as usize)
+  ).with_colorspace(y4m_color_space(video_info))
+    .write_header(output)
+    .unwrap()
+}
+
 fn write_stats_file<T: Pixel>(ctx: &Context<T>, filename: &Path) -> Result<(), io::Error> {
   let file = File::create(filename)?;
   let writer = BufWriter::new(file);
@@ -162,15 +185,74 @@ fn write_stats_file<T: Pixel>(ctx: &Context<T>, filename: &Path) -> Result<(), i
   Ok(())
 }
 
-fn do_encode<T: Pixel, D: Decoder>(
-  cfg: Config, verbose: bool, mut progress: ProgressInfo,
+fn new_context<T: Pixel>(cfg: &Config) -> Context<T> {
+  cfg.new_context().unwrap_or_else(|e| {
+    eprintln!("Invalid configuration: {}", e);
+    std::process::exit(1);
+  })
+}
+
+fn encode_still_picture<T: Pixel, D: Decoder>(
+  cfg: &Config, input: &mut D, video_info: &VideoDetails
+) -> (Vec<u8>, Vec<u8>) {
+  let mut ctx: Context<T> = new_context(cfg);
+  let frame = input.read_frame(video_info).expect("Cannot read the frame");
+  let _ = ctx.send_frame(Some(Arc::new(frame)));
+  ctx.flush();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => return (pkt.data, ctx.container_sequence_header()),
+      Err(EncoderStatus::Encoded) => {}
+      Err(e) => panic!("Failed to encode the still picture: {:?}", e)
+    }
+  }
+}
+
+// Encodes the luma of the first frame of a Y4M file as a full range
+// monochrome still picture, returning its data and codec configuration.
+fn encode_alpha(cfg: &Config, input: &mut Box<dyn Read + Send>) -> (Vec<u8>, Vec<u8>) {
+  let mut dec = Y4mDecoder::new(input).expect("alpha is not a y4m file");
+  let mut video_info = dec.get_video_details();
+  assert!(
+    video_info.width == cfg.enc.width && video_info.height == cfg.enc.height,
+    "The alpha plane must have the size of the image"
+  );
+  video_info.chroma_sampling = ChromaSampling::Cs400;
+
+  let mut enc = cfg.enc.clone();
+  enc.bit_depth = video_info.bit_depth;
+  enc.chroma_sampling = ChromaSampling::Cs400;
+  enc.chroma_sample_position = ChromaSamplePosition::Unknown;
+  enc.pixel_range = PixelRange::Full;
+  enc.color_description = None;
+  enc.mastering_display = None;
+  enc.content_light = None;
+  let cfg = Config { enc, threads: cfg.threads };
+
+  if video_info.bit_depth == 8 {
+    encode_still_picture::<u8, _>(&cfg, &mut dec, &video_info)
+  } else {
+    encode_still_picture::<u16, _>(&cfg, &mut dec, &video_info)
+  }
+}
+
+fn do_encode<T: Pixel, D: Decoder + Send + 'static>(
+  cfg: Config, verbosity: Verbosity, check_decode: bool,
+  mut progress: ProgressInfo,
   output: &mut dyn Muxer,
-  source: &mut Source<D>,
+  mut source: Source<D>,
+  io_buffer_frames: usize,
   pass1file_name: Option<&String>,
   pass2file_name: Option<&String>,
-  mut y4m_enc: Option<y4m::Encoder<'_, Box<dyn Write>>>
+  mut debug_outputs: DebugOutputs<'_>,
+  stats_server: Option<&StatsServer>,
+  observer: Option<Box<dyn EncoderObserver>>
 ) {
-  let mut ctx: Context<T> = cfg.new_context();
+  let mut ctx: Context<T> = new_context(&cfg);
+  if let Some(observer) = observer {
+    ctx.set_observer(observer);
+  }
+  output.write_codec_config(&ctx.container_sequence_header());
 
   let mut pass2file = pass2file_name.map(|f| {
     File::open(f)
@@ -181,20 +263,59 @@ fn do_encode<T: Pixel, D: Decoder>(
      .unwrap_or_else(|_| panic!("Unable to open \"{}\" for writing two-pass data.", f))
   });
 
+  let video_info = source.input.get_video_details();
+  let mut next_frame: Box<dyn FnMut() -> Option<Frame<T>>> =
+    if io_buffer_frames > 0 {
+      let mut reader = FrameReader::spawn(
+        move || source.next_frame(&video_info),
+        io_buffer_frames
+      );
+      Box::new(move || reader.recv())
+    } else {
+      Box::new(move || source.next_frame(&video_info))
+    };
+
   let mut buffer: [u8; 80] = [0; 80];
   let mut buf_pos = 0;
 
+  #[cfg(feature = "check_decode")]
+  let mut checker = if check_decode {
+    Some(rav1e::check_decode::DecodeChecker::<T>::new(cfg.enc.width, cfg.enc.height))
+  } else {
+    None
+  };
+  #[cfg(not(feature = "check_decode"))]
+  let _ = check_decode;
+  let mut check_packet = |_pkt: &Packet<T>| {
+    #[cfg(feature = "check_decode")]
+    {
+      if let Some(checker) = checker.as_mut() {
+        if let Err(e) = checker.check(_pkt) {
+          eprintln!("\nError: {}", e);
+          std::process::exit(1);
+        }
+      }
+    }
+  };
+
   while let Some(frame_info) =
-    process_frame(&mut ctx, &mut *output, source, pass1file.as_mut(),
-     pass2file.as_mut(), &mut buffer, &mut buf_pos, y4m_enc.as_mut())
+    process_frame(&mut ctx, &mut *output, &mut *next_frame, video_info,
+     pass1file.as_mut(), pass2file.as_mut(), &mut buffer, &mut buf_pos, &mut debug_outputs,
+     &mut check_packet)
   {
     for frame in frame_info {
       progress.add_frame(frame);
-      if verbose {
-        eprintln!("{} - {}", frame, progress);
-      } else {
-        eprint!("\r{}                    ", progress);
-      };
+      match verbosity {
+        Verbosity::Quiet => {}
+        Verbosity::Normal => eprint!("\r{}                    ", progress),
+        Verbosity::Verbose => eprintln!("{} - {}", frame, progress)
+      }
+    }
+
+    if let Some(server) = stats_server {
+      if progress.frames_encoded() > 0 {
+        server.update(LiveStats::new(&progress, &ctx));
+      }
     }
 
     output.flush().unwrap();
@@ -207,24 +328,51 @@ fn do_encode<T: Pixel, D: Decoder>(
       eprintln!("\nError: Failed to write stats file! {}\n", e);
     }
   }
-  eprint!("\n{}\n", progress.print_summary());
+  if let Some(server) = stats_server {
+    server.finish();
+  }
+  if verbosity != Verbosity::Quiet {
+    eprint!("\n{}\n", progress.print_summary());
+  }
 }
 
 fn main() {
   let mut cli = parse_cli();
-  let mut y4m_dec = y4m::decode(&mut cli.io.input).expect("input is not a y4m file");
-  let video_info = y4m_dec.get_video_details();
-  let y4m_enc = match cli.io.rec.as_mut() {
-    Some(rec) => Some(
-      y4m::encode(
-        video_info.width,
-        video_info.height,
-        y4m::Ratio::new(video_info.time_base.den as usize, video_info.time_base.num as usize)
-      ).with_colorspace(y4m_dec.get_colorspace())
-        .write_header(rec)
-        .unwrap()
-    ),
-    None => None
+  init_logger(cli.verbosity);
+  let raw_input = cli.raw_input.is_some();
+  // The input is read until the process exits, possibly on its own thread
+  let input: &'static mut Box<dyn Read + Send> = Box::leak(Box::new(cli.io.input));
+  let mut dec = match cli.raw_input {
+    Some(details) => InputDecoder::Yuv(YuvDecoder::new(input, details)),
+    None => {
+      let mut dec = Y4mDecoder::new(input).expect("input is not a y4m file");
+      if dec.interlacing() != Interlacing::Progressive {
+        match cli.interlaced {
+          InterlacedInput::Reject => {
+            eprintln!(
+              "Error: the input is interlaced ({:?}), which AV1 cannot code \
+               as fields.\nPass --interlaced deinterlace to deinterlace it, \
+               or --interlaced progressive to code its frames as they are.",
+              dec.interlacing()
+            );
+            std::process::exit(1);
+          }
+          InterlacedInput::Progressive => {}
+          InterlacedInput::Deinterlace => {
+            dec.set_deinterlacer(Box::new(BlendDeinterlacer))
+          }
+        }
+      }
+      InputDecoder::Y4m(dec)
+    }
+  };
+  let video_info = dec.get_video_details();
+  let debug_outputs = DebugOutputs {
+    rec: cli.io.rec.as_mut().map(|rec| y4m_encoder(rec, &video_info)),
+    prediction: cli.io.prediction.as_mut()
+      .map(|prediction| y4m_encoder(prediction, &video_info)),
+    residual: cli.io.residual.as_mut()
+      .map(|residual| y4m_encoder(residual, &video_info))
   };
 
   cli.enc.width = video_info.width;
@@ -233,40 +381,93 @@ fn main() {
   cli.enc.chroma_sampling = video_info.chroma_sampling;
   cli.enc.chroma_sample_position = video_info.chroma_sample_position;
 
-  // If no pixel range is specified via CLI, assume limited,
-  // as it is the default for the Y4M format.
+  // If no pixel range is specified via CLI, use the one from the Y4M header,
+  // which is limited unless stated otherwise, as it is for raw input.
   if !cli.color_range_specified {
-    cli.enc.pixel_range = PixelRange::Limited;
+    cli.enc.pixel_range = video_info.pixel_range;
+  }
+
+  if let Some(sar) = cli.sar {
+    let width = video_info.width as u64;
+    let render_width = (width * sar.num + sar.den / 2) / sar.den;
+    cli.enc.render_width = Some(render_width.max(1) as usize);
+  }
+
+  if let Some(iso) = cli.photon_noise {
+    let transfer = cli.enc.color_description.map_or(
+      TransferCharacteristics::Unspecified,
+      |desc| desc.transfer_characteristics
+    );
+    let params = rav1e::config::FilmGrainParams::photon_noise(
+      iso,
+      video_info.width,
+      video_info.height,
+      transfer
+    );
+    cli.enc.film_grain_table = Some(params.into());
   }
 
   cli.enc.time_base = video_info.time_base;
+  if cli.enc.still_picture {
+    cli.limit = 1;
+  }
   let cfg = Config {
     enc: cli.enc,
     threads: cli.threads,
   };
 
-  eprintln!("{}x{} @ {}/{} fps",
-    video_info.width,
-    video_info.height,
-    video_info.time_base.den,
-    video_info.time_base.num
-  );
+  if cli.verbosity != Verbosity::Quiet {
+    eprintln!("{}x{} @ {}/{} fps",
+      video_info.width,
+      video_info.height,
+      video_info.time_base.den,
+      video_info.time_base.num
+    );
+  }
 
-  cli.io.output.write_header(
+  let mut output = if cli.io_buffer_frames > 0 {
+    Box::new(ThreadedMuxer::spawn(cli.io.output, cli.io_buffer_frames))
+      as Box<dyn Muxer>
+  } else {
+    cli.io.output
+  };
+
+  output.write_header(
     video_info.width,
     video_info.height,
     video_info.time_base.den as usize,
     video_info.time_base.num as usize
   );
 
+  if let Some(alpha) = cli.io.alpha.as_mut() {
+    let (data, config) = encode_alpha(&cfg, alpha);
+    output.write_alpha(&data, &config);
+  }
+
+  // Unless the header gives it, the frames of a file are counted from its
+  // size, each Y4M frame having a 6 byte header, which also covers the
+  // stream header
+  let input_frames = dec.length().or_else(|| cli.input_size.map(|size| {
+    let (luma, chroma) = video_info.plane_sizes();
+    let frame_size = luma + 2 * chroma + if raw_input { 0 } else { 6 };
+    (size / frame_size as u64) as usize
+  })).map(|frames| frames.saturating_sub(cli.skip));
+  let total_frames = match (input_frames, cli.limit) {
+    (Some(frames), 0) => Some(frames),
+    (Some(frames), limit) => Some(frames.min(limit)),
+    (None, 0) => None,
+    (None, limit) => Some(limit)
+  };
+
   let progress = ProgressInfo::new(
     Rational { num: video_info.time_base.den, den: video_info.time_base.num },
-    if cli.limit == 0 { None } else { Some(cli.limit) },
-      cfg.enc.show_psnr
+    total_frames,
+      cfg.enc.show_psnr,
+      cfg.enc.show_ssim
   );
 
   for _ in 0..cli.skip {
-    y4m_dec.read_frame().expect("Skipped more frames than in the input");
+    dec.read_frame::<u16>(&video_info).expect("Skipped more frames than in the input");
   }
 
   #[cfg(all(unix, feature = "signal-hook"))]
@@ -294,24 +495,59 @@ fn main() {
   };
 
   #[cfg(all(unix, feature = "signal-hook"))]
-  let mut source = Source {
+  let source = Source {
     limit: cli.limit,
-    input: y4m_dec,
+    input: dec,
     count: 0,
     exit_requested
   };
   #[cfg(not(all(unix, feature = "signal-hook")))]
-  let mut source = Source { limit: cli.limit, input: y4m_dec, count: 0 };
+  let source = Source { limit: cli.limit, input: dec, count: 0 };
+
+  let stats_server = cli.stats_server.as_ref().map(|addr| {
+    StatsServer::start(addr)
+      .unwrap_or_else(|e| panic!("Cannot serve statistics on {}: {}", addr, e))
+  });
+
+  let frame_stats = cli.frame_stats.as_ref().map(|path| {
+    let file = File::create(path)
+      .unwrap_or_else(|e| panic!("Cannot create \"{}\": {}", path, e));
+    let format = if path.ends_with(".csv") {
+      StatsFormat::Csv
+    } else {
+      StatsFormat::Json
+    };
+    Box::new(FrameStatsLog::new(BufWriter::new(file), format))
+      as Box<dyn EncoderObserver>
+  });
+
+  let block_log = cli.block_log.as_ref().map(|path| {
+    let file = File::create(path)
+      .unwrap_or_else(|e| panic!("Cannot create \"{}\": {}", path, e));
+    Box::new(BlockDecisionLog::new(BufWriter::new(file)))
+      as Box<dyn EncoderObserver>
+  });
+
+  let observers: Vec<_> = frame_stats.into_iter().chain(block_log).collect();
+  let observer = if observers.is_empty() {
+    None
+  } else {
+    Some(Box::new(observers) as Box<dyn EncoderObserver>)
+  };
 
   if video_info.bit_depth == 8 {
-    do_encode::<u8, y4m::Decoder<'_, Box<dyn Read>>>(
-      cfg, cli.verbose, progress, &mut *cli.io.output, &mut source,
-      cli.pass1file_name.as_ref(), cli.pass2file_name.as_ref(), y4m_enc
+    do_encode::<u8, InputDecoder<'_>>(
+      cfg, cli.verbosity, cli.check_decode, progress, &mut *output,
+      source, cli.io_buffer_frames,
+      cli.pass1file_name.as_ref(), cli.pass2file_name.as_ref(), debug_outputs,
+      stats_server.as_ref(), observer
     )
   } else {
-    do_encode::<u16, y4m::Decoder<'_, Box<dyn Read>>>(
-      cfg, cli.verbose, progress, &mut *cli.io.output, &mut source,
-      cli.pass1file_name.as_ref(), cli.pass2file_name.as_ref(), y4m_enc
+    do_encode::<u16, InputDecoder<'_>>(
+      cfg, cli.verbosity, cli.check_decode, progress, &mut *output,
+      source, cli.io_buffer_frames,
+      cli.pass1file_name.as_ref(), cli.pass2file_name.as_ref(), debug_outputs,
+      stats_server.as_ref(), observer
     )
   }
 }
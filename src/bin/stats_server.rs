@@ -0,0 +1,101 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::common::ProgressInfo;
+use rav1e::prelude::*;
+use serde_derive::Serialize;
+
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Snapshot of the encoder state, as served to monitoring clients.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LiveStats {
+  pub frames_encoded: usize,
+  pub encoding_fps: f64,
+  /// Bitrate of the frames encoded so far, in bits/second
+  pub bitrate: usize,
+  /// Fraction of the rate control buffer available, in bitrate mode only
+  pub buffer_fullness: Option<f64>,
+  /// Frames sent to the encoder but not yet output
+  pub queue_depth: u64,
+  pub finished: bool,
+}
+
+impl LiveStats {
+  pub fn new<T: Pixel>(progress: &ProgressInfo, ctx: &Context<T>) -> Self {
+    LiveStats {
+      frames_encoded: progress.frames_encoded(),
+      encoding_fps: progress.encoding_fps(),
+      bitrate: progress.bitrate(),
+      buffer_fullness: ctx.buffer_fullness(),
+      queue_depth: ctx.frames_queued(),
+      finished: false,
+    }
+  }
+}
+
+/// Serves the latest `LiveStats` as JSON to anything connecting to the
+/// listening address, so that plain HTTP clients and raw sockets both work.
+pub struct StatsServer {
+  stats: Arc<Mutex<LiveStats>>,
+}
+
+impl StatsServer {
+  pub fn start(addr: &str) -> io::Result<Self> {
+    let listener = TcpListener::bind(addr)?;
+    let stats = Arc::new(Mutex::new(LiveStats::default()));
+    let shared = stats.clone();
+
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        if let Ok(stream) = stream {
+          let body = serde_json::to_string(&*shared.lock().unwrap())
+            .expect("Serialization should not fail");
+          // A client that hangs up early must not bring the encode down.
+          let _ = respond(stream, &body);
+        }
+      }
+    });
+
+    Ok(StatsServer { stats })
+  }
+
+  pub fn update(&self, stats: LiveStats) {
+    *self.stats.lock().unwrap() = stats;
+  }
+
+  pub fn finish(&self) {
+    self.stats.lock().unwrap().finished = true;
+  }
+}
+
+fn respond(mut stream: TcpStream, body: &str) -> io::Result<()> {
+  // Every request gets the same answer, so only drain what the client sent.
+  stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+  let mut request = [0u8; 1024];
+  let _ = stream.read(&mut request);
+
+  write!(
+    stream,
+    "HTTP/1.1 200 OK\r\n\
+     Content-Type: application/json\r\n\
+     Content-Length: {}\r\n\
+     Connection: close\r\n\
+     \r\n\
+     {}",
+    body.len(),
+    body
+  )?;
+  stream.flush()
+}
@@ -0,0 +1,301 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Input reading and output writing off the encoding thread
+//!
+//! Frames are read ahead and packets written behind the encoder through
+//! bounded channels, so that a slow disk or pipe only stalls the encoder
+//! once the buffer of the channel is full.
+
+use crate::muxer::Muxer;
+use rav1e::prelude::*;
+
+use std::io;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Reads the input frames on its own thread, up to `capacity` frames ahead
+pub struct FrameReader<T: Pixel> {
+  frames: Receiver<Frame<T>>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl<T: Pixel> FrameReader<T> {
+  /// Read frames with `next_frame` until it returns `None`
+  pub fn spawn<F>(mut next_frame: F, capacity: usize) -> Self
+  where
+    F: FnMut() -> Option<Frame<T>> + Send + 'static,
+  {
+    let (sender, frames) = sync_channel(capacity);
+    let thread = thread::Builder::new()
+      .name("input".into())
+      .spawn(move || {
+        while let Some(frame) = next_frame() {
+          // The encoder hung up, there is no one left to read for
+          if sender.send(frame).is_err() {
+            break;
+          }
+        }
+      })
+      .expect("Cannot spawn the input thread");
+
+    FrameReader { frames, thread: Some(thread) }
+  }
+
+  /// The next input frame, `None` once the input is exhausted
+  pub fn recv(&mut self) -> Option<Frame<T>> {
+    match self.frames.recv() {
+      Ok(frame) => Some(frame),
+      Err(_) => {
+        // Tell a failure to read apart from the end of the input
+        if let Some(thread) = self.thread.take() {
+          if thread.join().is_err() {
+            panic!("The input thread panicked");
+          }
+        }
+        None
+      }
+    }
+  }
+}
+
+enum MuxerMessage {
+  Header { width: usize, height: usize, num: usize, den: usize },
+  CodecConfig(Vec<u8>),
+  Alpha { data: Vec<u8>, config: Vec<u8> },
+  Frame { pts: u64, data: Vec<u8>, frame_type: FrameType },
+  Flush,
+}
+
+/// Forwards everything written to it to a muxer owned by another thread,
+/// buffering up to `capacity` messages.
+///
+/// The muxer is flushed when the output thread reaches a `flush`, and
+/// finalized when this is dropped.
+pub struct ThreadedMuxer {
+  sender: Option<SyncSender<MuxerMessage>>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedMuxer {
+  pub fn spawn(mut muxer: Box<dyn Muxer>, capacity: usize) -> Self {
+    let (sender, messages) = sync_channel(capacity);
+    let thread = thread::Builder::new()
+      .name("output".into())
+      .spawn(move || {
+        for message in messages {
+          match message {
+            MuxerMessage::Header { width, height, num, den } => {
+              muxer.write_header(width, height, num, den)
+            }
+            MuxerMessage::CodecConfig(config) => {
+              muxer.write_codec_config(&config)
+            }
+            MuxerMessage::Alpha { data, config } => {
+              muxer.write_alpha(&data, &config)
+            }
+            MuxerMessage::Frame { pts, data, frame_type } => {
+              muxer.write_frame(pts, &data, frame_type)
+            }
+            MuxerMessage::Flush => {
+              muxer.flush().expect("Cannot flush the output")
+            }
+          }
+        }
+      })
+      .expect("Cannot spawn the output thread");
+
+    ThreadedMuxer { sender: Some(sender), thread: Some(thread) }
+  }
+
+  fn send(&mut self, message: MuxerMessage) {
+    let sent = self.sender.as_ref().map(|sender| sender.send(message));
+    if let Some(Err(_)) = sent {
+      // The output thread is gone, report why
+      self.join();
+    }
+  }
+
+  fn join(&mut self) {
+    self.sender = None;
+    if let Some(thread) = self.thread.take() {
+      if thread.join().is_err() && !thread::panicking() {
+        panic!("The output thread panicked");
+      }
+    }
+  }
+}
+
+impl Muxer for ThreadedMuxer {
+  fn write_header(
+    &mut self, width: usize, height: usize, framerate_num: usize,
+    framerate_den: usize,
+  ) {
+    self.send(MuxerMessage::Header {
+      width,
+      height,
+      num: framerate_num,
+      den: framerate_den,
+    });
+  }
+
+  fn write_codec_config(&mut self, config: &[u8]) {
+    self.send(MuxerMessage::CodecConfig(config.to_vec()));
+  }
+
+  fn write_alpha(&mut self, data: &[u8], config: &[u8]) {
+    self.send(MuxerMessage::Alpha {
+      data: data.to_vec(),
+      config: config.to_vec(),
+    });
+  }
+
+  fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType) {
+    self.send(MuxerMessage::Frame { pts, data: data.to_vec(), frame_type });
+  }
+
+  /// Flush the muxer once the frames written so far are, without waiting
+  /// for it. A failure stops the output thread and is reported by the next
+  /// call.
+  fn flush(&mut self) -> io::Result<()> {
+    self.send(MuxerMessage::Flush);
+    Ok(())
+  }
+}
+
+impl Drop for ThreadedMuxer {
+  /// Wait for the pending packets to be written and the muxer finalized
+  fn drop(&mut self) {
+    self.join();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::mpsc::{channel, RecvTimeoutError};
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  fn numbered_frame(n: u8) -> Frame<u8> {
+    let mut frame = Frame::new(16, 16, ChromaSampling::Cs420);
+    frame.planes[0].data_origin_mut()[0] = n;
+    frame
+  }
+
+  #[test]
+  fn frame_reader_order() {
+    let mut n = 0;
+    let mut reader = FrameReader::spawn(
+      move || {
+        n += 1;
+        if n <= 10 { Some(numbered_frame(n)) } else { None }
+      },
+      2,
+    );
+    for n in 1..=10 {
+      let frame = reader.recv().unwrap();
+      assert_eq!(frame.planes[0].data_origin()[0], n);
+    }
+    assert!(reader.recv().is_none());
+    assert!(reader.recv().is_none());
+  }
+
+  #[test]
+  fn frame_reader_stops_when_dropped() {
+    let (done, stopped) = channel::<()>();
+    let mut reader = FrameReader::spawn(
+      move || {
+        let _done = &done;
+        Some(numbered_frame(0))
+      },
+      2,
+    );
+    assert!(reader.recv().is_some());
+    drop(reader);
+    // The closure, and the sender it holds, are dropped with the thread
+    assert_eq!(
+      stopped.recv_timeout(Duration::from_secs(10)),
+      Err(RecvTimeoutError::Disconnected)
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "The input thread panicked")]
+  fn frame_reader_panic() {
+    let mut reader: FrameReader<u8> =
+      FrameReader::spawn(|| panic!("Cannot read the input"), 2);
+    reader.recv();
+  }
+
+  /// Logs the calls made to it, and its drop
+  struct LogMuxer(Arc<Mutex<Vec<String>>>);
+
+  impl Muxer for LogMuxer {
+    fn write_header(
+      &mut self, width: usize, height: usize, framerate_num: usize,
+      framerate_den: usize,
+    ) {
+      self.0.lock().unwrap().push(format!(
+        "header {}x{} {}/{}",
+        width, height, framerate_num, framerate_den
+      ));
+    }
+
+    fn write_frame(&mut self, pts: u64, _data: &[u8], _frame_type: FrameType) {
+      if pts == 1000 {
+        panic!("Cannot write the frame");
+      }
+      self.0.lock().unwrap().push(format!("frame {}", pts));
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      self.0.lock().unwrap().push("flush".into());
+      Ok(())
+    }
+  }
+
+  impl Drop for LogMuxer {
+    fn drop(&mut self) {
+      self.0.lock().unwrap().push("finish".into());
+    }
+  }
+
+  #[test]
+  fn threaded_muxer_order() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut muxer = ThreadedMuxer::spawn(Box::new(LogMuxer(log.clone())), 1);
+    muxer.write_header(64, 48, 30, 1);
+    for pts in 0..3 {
+      muxer.write_frame(pts, &[], FrameType::INTER);
+    }
+    muxer.flush().unwrap();
+    muxer.write_frame(3, &[], FrameType::INTER);
+    // Dropping waits for the pending frames and finalizes the muxer
+    drop(muxer);
+
+    assert_eq!(
+      *log.lock().unwrap(),
+      vec![
+        "header 64x48 30/1", "frame 0", "frame 1", "frame 2", "flush",
+        "frame 3", "finish",
+      ]
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "The output thread panicked")]
+  fn threaded_muxer_panic() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut muxer = ThreadedMuxer::spawn(Box::new(LogMuxer(log)), 1);
+    for pts in 1000..1010 {
+      muxer.write_frame(pts, &[], FrameType::INTER);
+    }
+  }
+}
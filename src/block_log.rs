@@ -0,0 +1,293 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Per-block coding decisions, for debugging and analyzer tools
+//!
+//! `BlockDecisionLog` writes them as JSON lines. The first line is the
+//! schema:
+//!
+//! ```json
+//! {"columns":["x","y",...],"partitions":["PARTITION_NONE",...],
+//!  "modes":["DC_PRED",...],"refs":["INTRA_FRAME",...],
+//!  "tx_types":["DCT_DCT",...]}
+//! ```
+//!
+//! and every following line is a coded frame:
+//!
+//! ```json
+//! {"input_frameno":0,"frame_type":"KEY","blocks":[[0,0,16,16,...],...]}
+//! ```
+//!
+//! where each block is an array of the values named by `columns`. The
+//! partition, mode, reference and transform type columns are indices in the
+//! matching name lists of the schema.
+
+use crate::api::{EncoderObserver, FrameEncodeStats};
+use crate::context::{BlockOffset, FrameBlocks, MI_SIZE_LOG2};
+use crate::encoder::{sb_qidx, segment_qidx, FrameInvariants, FrameState};
+use crate::partition::BlockSize;
+use crate::util::Pixel;
+
+use std::io;
+
+/// How a block was coded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockDecision {
+  /// Position of the top-left corner, in luma pixels
+  pub x: usize,
+  pub y: usize,
+  pub size: BlockSize,
+  /// Index in `PARTITIONS` of the partition the block comes from
+  pub partition: u8,
+  /// Index in `MODES` of the luma prediction mode
+  pub mode: u8,
+  /// Indices in `REFS` of the references, the second one being
+  /// `NONE_FRAME` for single reference blocks
+  pub ref_frames: [u8; 2],
+  /// Motion vectors as (row, column), in 1/8 pixel units
+  pub mvs: [(i16, i16); 2],
+  /// Size of the luma transform blocks, in pixels
+  pub tx_width: usize,
+  pub tx_height: usize,
+  /// Index in `TX_TYPES` of the luma transform type
+  pub tx_type: u8,
+  pub skip: bool,
+  /// Quantizer index, with the superblock and segment offsets
+  pub qidx: u8,
+  pub segment: u8,
+}
+
+impl BlockDecision {
+  pub const COLUMNS: &'static [&'static str] = &[
+    "x", "y", "width", "height", "partition", "mode", "ref0", "ref1",
+    "mv0_row", "mv0_col", "mv1_row", "mv1_col", "tx_width", "tx_height",
+    "tx_type", "skip", "qidx", "segment",
+  ];
+
+  pub const PARTITIONS: &'static [&'static str] = &[
+    "PARTITION_NONE", "PARTITION_HORZ", "PARTITION_VERT", "PARTITION_SPLIT",
+    "PARTITION_HORZ_A", "PARTITION_HORZ_B", "PARTITION_VERT_A",
+    "PARTITION_VERT_B", "PARTITION_HORZ_4", "PARTITION_VERT_4",
+    "PARTITION_INVALID",
+  ];
+
+  pub const MODES: &'static [&'static str] = &[
+    "DC_PRED", "V_PRED", "H_PRED", "D45_PRED", "D135_PRED", "D117_PRED",
+    "D153_PRED", "D207_PRED", "D63_PRED", "SMOOTH_PRED", "SMOOTH_V_PRED",
+    "SMOOTH_H_PRED", "PAETH_PRED", "UV_CFL_PRED", "NEARESTMV", "NEAR0MV",
+    "NEAR1MV", "NEAR2MV", "GLOBALMV", "NEWMV", "NEAREST_NEARESTMV",
+    "NEAR_NEARMV", "NEAREST_NEWMV", "NEW_NEARESTMV", "NEAR_NEWMV",
+    "NEW_NEARMV", "GLOBAL_GLOBALMV", "NEW_NEWMV", "INTRABC",
+  ];
+
+  pub const REFS: &'static [&'static str] = &[
+    "INTRA_FRAME", "LAST_FRAME", "LAST2_FRAME", "LAST3_FRAME",
+    "GOLDEN_FRAME", "BWDREF_FRAME", "ALTREF2_FRAME", "ALTREF_FRAME",
+    "NONE_FRAME",
+  ];
+
+  pub const TX_TYPES: &'static [&'static str] = &[
+    "DCT_DCT", "ADST_DCT", "DCT_ADST", "ADST_ADST", "FLIPADST_DCT",
+    "DCT_FLIPADST", "FLIPADST_FLIPADST", "ADST_FLIPADST", "FLIPADST_ADST",
+    "IDTX", "V_DCT", "H_DCT", "V_ADST", "H_ADST", "V_FLIPADST",
+    "H_FLIPADST",
+  ];
+
+  fn values(&self) -> [i32; 18] {
+    [
+      self.x as i32,
+      self.y as i32,
+      self.size.width() as i32,
+      self.size.height() as i32,
+      self.partition as i32,
+      self.mode as i32,
+      self.ref_frames[0] as i32,
+      self.ref_frames[1] as i32,
+      self.mvs[0].0 as i32,
+      self.mvs[0].1 as i32,
+      self.mvs[1].0 as i32,
+      self.mvs[1].1 as i32,
+      self.tx_width as i32,
+      self.tx_height as i32,
+      self.tx_type as i32,
+      self.skip as i32,
+      self.qidx as i32,
+      self.segment as i32,
+    ]
+  }
+}
+
+/// The decisions of every block of a coded frame, in raster order of their
+/// top-left corners
+pub(crate) fn block_decisions<T: Pixel>(
+  fi: &FrameInvariants<T>, fs: &FrameState<T>, blocks: &FrameBlocks
+) -> Vec<BlockDecision> {
+  let mut decisions = Vec::new();
+  for y in 0..blocks.rows {
+    for x in 0..blocks.cols {
+      let bo = BlockOffset { x, y };
+      let block = &blocks[bo];
+      // Blocks are aligned on their size
+      if x % block.n4_w != 0 || y % block.n4_h != 0 {
+        continue;
+      }
+      let qidx = sb_qidx(fi, &fs.sb_qindex_offsets, bo.sb_offset());
+      decisions.push(BlockDecision {
+        x: x << MI_SIZE_LOG2,
+        y: y << MI_SIZE_LOG2,
+        size: block.bsize,
+        partition: block.partition as u8,
+        mode: block.mode as u8,
+        ref_frames: [block.ref_frames[0] as u8, block.ref_frames[1] as u8],
+        mvs: [
+          (block.mv[0].row, block.mv[0].col),
+          (block.mv[1].row, block.mv[1].col),
+        ],
+        tx_width: block.txsize.width(),
+        tx_height: block.txsize.height(),
+        tx_type: block.txtype as u8,
+        skip: block.skip,
+        qidx: segment_qidx(&fs.segmentation, &fs.segment_ids, bo, qidx),
+        segment: fs.segment_ids.get(bo),
+      });
+    }
+  }
+  decisions
+}
+
+/// Observer writing the block decisions of every coded frame, which the
+/// encoder only records with `EncoderConfig::log_block_decisions`
+///
+/// Write errors are ignored so that they cannot interrupt the encode.
+pub struct BlockDecisionLog<W: io::Write + Send> {
+  writer: W,
+  records: usize,
+}
+
+impl<W: io::Write + Send> BlockDecisionLog<W> {
+  pub fn new(writer: W) -> Self {
+    BlockDecisionLog { writer, records: 0 }
+  }
+
+  fn write_schema(&mut self) -> io::Result<()> {
+    let list = |names: &[&str]| {
+      let quoted: Vec<String> =
+        names.iter().map(|name| format!("\"{}\"", name)).collect();
+      format!("[{}]", quoted.join(","))
+    };
+    writeln!(
+      self.writer,
+      "{{\"columns\":{},\"partitions\":{},\"modes\":{},\"refs\":{},\"tx_types\":{}}}",
+      list(BlockDecision::COLUMNS),
+      list(BlockDecision::PARTITIONS),
+      list(BlockDecision::MODES),
+      list(BlockDecision::REFS),
+      list(BlockDecision::TX_TYPES)
+    )
+  }
+
+  fn write_record(&mut self, stats: &FrameEncodeStats) -> io::Result<()> {
+    if self.records == 0 {
+      self.write_schema()?;
+    }
+    let blocks: Vec<String> = stats
+      .blocks
+      .iter()
+      .map(|block| {
+        let values: Vec<String> =
+          block.values().iter().map(|v| v.to_string()).collect();
+        format!("[{}]", values.join(","))
+      })
+      .collect();
+    writeln!(
+      self.writer,
+      "{{\"input_frameno\":{},\"frame_type\":\"{:?}\",\"blocks\":[{}]}}",
+      stats.input_frameno,
+      stats.frame_type,
+      blocks.join(",")
+    )
+  }
+}
+
+impl<W: io::Write + Send> EncoderObserver for BlockDecisionLog<W> {
+  fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+    let _ = self.write_record(stats);
+    self.records += 1;
+  }
+}
+
+impl<W: io::Write + Send> Drop for BlockDecisionLog<W> {
+  fn drop(&mut self) {
+    let _ = self.writer.flush();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::api::*;
+  use std::sync::{Arc, Mutex};
+
+  #[test]
+  fn blocks_cover_the_frame() {
+    struct Collect(Arc<Mutex<Vec<FrameEncodeStats>>>);
+    impl EncoderObserver for Collect {
+      fn frame_encoded(&mut self, stats: &FrameEncodeStats) {
+        self.0.lock().unwrap().push(stats.clone());
+      }
+    }
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 80;
+    enc.log_block_decisions = true;
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    let collected = Arc::new(Mutex::new(Vec::new()));
+    ctx.set_observer(Box::new(Collect(collected.clone())));
+
+    for _ in 0..2 {
+      let _ = ctx.send_frame(ctx.new_frame());
+    }
+    ctx.flush();
+    loop {
+      match ctx.receive_packet() {
+        Ok(_) | Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+
+    let collected = collected.lock().unwrap();
+    let mut log = Vec::new();
+    {
+      let mut observer = BlockDecisionLog::new(&mut log);
+      for stats in collected.iter() {
+        observer.frame_encoded(stats);
+        if stats.frame_subtype == FrameSubtype::ShowExisting {
+          assert!(stats.blocks.is_empty());
+          continue;
+        }
+        let area: usize = stats
+          .blocks
+          .iter()
+          .map(|b| {
+            (64 - b.x).min(b.size.width()) * (80 - b.y).min(b.size.height())
+          })
+          .sum();
+        assert_eq!(area, 64 * 80);
+      }
+    }
+
+    let log = String::from_utf8(log).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), collected.len() + 1);
+    assert!(lines[0].starts_with("{\"columns\":[\"x\",\"y\","));
+    assert!(lines[1].starts_with("{\"input_frameno\":0,\"frame_type\":\"KEY\""));
+  }
+}
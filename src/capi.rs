@@ -25,7 +25,7 @@ use crate::prelude as rav1e;
 ///
 /// It can be allocated throught rav1e_frame_new(), populated using rav1e_frame_fill_plane()
 /// and freed using rav1e_frame_unref().
-pub struct Frame(Arc<rav1e::Frame<u16>>);
+pub struct Frame(Arc<rav1e::Frame<u16>>, rav1e::FrameParameters);
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, FromPrimitive)]
@@ -104,6 +104,8 @@ pub struct Packet {
     pub len: size_t,
     /// Frame sequence number
     pub input_frameno: u64,
+    /// Presentation time stamp, in time base units
+    pub pts: u64,
     /// Frame type
     pub frame_type: FrameType,
 }
@@ -270,13 +272,73 @@ unsafe fn option_match(
         "tile_cols_log2" => enc.tile_cols_log2 = value.parse().map_err(|_| ())?,
 
         "tune" => enc.tune = value.parse().map_err(|_| ())?,
+        "psy_rd_strength" => enc.psy_rd_strength = value.parse().map_err(|_| ())?,
+        "chroma_distortion_weight" => enc.chroma_distortion_weight = value.parse().map_err(|_| ())?,
+        "chroma_me" => enc.chroma_me = value.parse().map_err(|_| ())?,
+        "me_distortion" => enc.me_distortion = value.parse().map_err(|_| ())?,
+        "rdo_distortion" => enc.rdo_distortion = value.parse().map_err(|_| ())?,
+        "me_distortion_weight" => enc.me_distortion_weight = value.parse().map_err(|_| ())?,
+        "rdo_distortion_weight" => enc.rdo_distortion_weight = value.parse().map_err(|_| ())?,
+        "denoise_strength" => enc.denoise_strength = value.parse().map_err(|_| ())?,
+        "film_grain_table" => enc.film_grain_table = match value {
+            "" => None,
+            _ => Some(std::fs::read_to_string(value).map_err(|_| ())?.parse()?),
+        },
+        "duplicate_frame_threshold" => enc.duplicate_frame_threshold = Some(value.parse().map_err(|_| ())?),
+        "enable_intrabc" => enc.enable_intrabc = value.parse().map_err(|_| ())?,
+        "detect_screen_content" => enc.detect_screen_content = value.parse().map_err(|_| ())?,
+        "variance_aq" => enc.variance_aq = value.parse().map_err(|_| ())?,
+        "aq_delta_q" => enc.aq_delta_q = value.parse().map_err(|_| ())?,
+        "activity_masking" => enc.activity_masking = value.parse().map_err(|_| ())?,
+        "error_resilient" => enc.error_resilient = value.parse().map_err(|_| ())?,
+        "tile_constrained_mvs" => enc.tile_constrained_mvs = value.parse().map_err(|_| ())?,
+        "golden_interval" => enc.reference_pattern.golden_interval = value.parse().map_err(|_| ())?,
+        "last_and_golden_only" => enc.reference_pattern.last_and_golden_only = value.parse().map_err(|_| ())?,
+        "temporal_layers" => enc.temporal_layers = value.parse().map_err(|_| ())?,
+        "superres" => enc.superres = value.parse()?,
+        "render_width" => enc.render_width = Some(value.parse().map_err(|_| ())?),
+        "render_height" => enc.render_height = Some(value.parse().map_err(|_| ())?),
+        "level" => enc.level = value.parse()?,
+        "high_tier" => if let rav1e::Level::Fixed { ref mut high_tier, .. } = enc.level {
+            *high_tier = value.parse().map_err(|_| ())?;
+        } else {
+            return Err(());
+        },
         "quantizer" => enc.quantizer = value.parse().map_err(|_| ())?,
+        "min_quantizer" => enc.min_quantizer = value.parse().map_err(|_| ())?,
+        "max_quantizer" => enc.max_quantizer = value.parse().map_err(|_| ())?,
         "bitrate" => enc.bitrate = value.parse().map_err(|_| ())?,
+        "keyframe_max_reencodes" => enc.keyframe_max_reencodes = value.parse().map_err(|_| ())?,
+        "keyframe_overshoot_pct" => enc.keyframe_overshoot_pct = value.parse().map_err(|_| ())?,
+        "decoder_model_info" => enc.decoder_model_info = value.parse().map_err(|_| ())?,
+        "key_frame_quantizer_offset" => enc.quantizer_offsets.key_frame = value.parse().map_err(|_| ())?,
+        "alt_ref_quantizer_offset" => enc.quantizer_offsets.alt_ref = value.parse().map_err(|_| ())?,
+        "pyramid_level1_quantizer_offset" => enc.quantizer_offsets.pyramid_levels[0] = value.parse().map_err(|_| ())?,
+        "pyramid_level2_quantizer_offset" => enc.quantizer_offsets.pyramid_levels[1] = value.parse().map_err(|_| ())?,
+        "qm_levels" => enc.qm_levels = if value.is_empty() {
+            None
+        } else {
+            let levels = value.split(',')
+                .map(|level| level.parse().map_err(|_| ()))
+                .collect::<Result<Vec<u8>, ()>>()?;
+            if levels.len() != 3 {
+                return Err(());
+            }
+            Some([levels[0], levels[1], levels[2]])
+        },
 
         "key_frame_interval" => enc.max_key_frame_interval = value.parse().map_err(|_| ())?,
         "min_key_frame_interval" => enc.min_key_frame_interval = value.parse().map_err(|_| ())?,
+        "scenecut_sensitivity" => enc.scenecut_sensitivity = value.parse().map_err(|_| ())?,
+        "intra_only_scene_changes" => enc.intra_only_scene_changes = value.parse().map_err(|_| ())?,
+        "intra_refresh" => enc.intra_refresh = match value {
+            "none" => None,
+            _ => Some(value.parse()?),
+        },
         "reservoir_frame_delay" => enc.reservoir_frame_delay = Some(value.parse().map_err(|_| ())?),
         "low_latency" => enc.low_latency = value.parse().map_err(|_| ())?,
+        "zero_delay" => enc.zero_delay = value.parse().map_err(|_| ())?,
+        "all_intra" => enc.all_intra = value.parse().map_err(|_| ())?,
 
         _ => return Err(())
     }
@@ -284,12 +346,132 @@ unsafe fn option_match(
     Ok(())
 }
 
+/// Keys accepted by rav1e_config_parse(), with their default value and, for
+/// the numeric ones, their valid range
+struct ConfigKey {
+    name: &'static str,
+    default: &'static str,
+    range: Option<(i64, i64)>,
+}
+
+const UNBOUNDED: i64 = std::i64::MAX;
+
+const CONFIG_KEYS: &[ConfigKey] = &[
+    ConfigKey { name: "width", default: "640", range: Some((1, 65536)) },
+    ConfigKey { name: "height", default: "480", range: Some((1, 65536)) },
+    ConfigKey { name: "speed", default: "5", range: Some((0, 10)) },
+    ConfigKey { name: "threads", default: "0", range: Some((0, UNBOUNDED)) },
+    ConfigKey { name: "tile_rows_log2", default: "0", range: Some((0, 6)) },
+    ConfigKey { name: "tile_cols_log2", default: "0", range: Some((0, 6)) },
+    ConfigKey { name: "tune", default: "psychovisual", range: None },
+    ConfigKey { name: "psy_rd_strength", default: "0", range: Some((0, 255)) },
+    ConfigKey { name: "chroma_distortion_weight", default: "8", range: Some((0, 255)) },
+    ConfigKey { name: "chroma_me", default: "false", range: None },
+    ConfigKey { name: "me_distortion", default: "sad", range: None },
+    ConfigKey { name: "rdo_distortion", default: "sse", range: None },
+    ConfigKey { name: "me_distortion_weight", default: "8", range: Some((1, 255)) },
+    ConfigKey { name: "rdo_distortion_weight", default: "8", range: Some((1, 255)) },
+    ConfigKey { name: "denoise_strength", default: "0", range: Some((0, 255)) },
+    ConfigKey { name: "film_grain_table", default: "", range: None },
+    ConfigKey { name: "duplicate_frame_threshold", default: "", range: Some((0, 65535)) },
+    ConfigKey { name: "enable_intrabc", default: "false", range: None },
+    ConfigKey { name: "detect_screen_content", default: "false", range: None },
+    ConfigKey { name: "variance_aq", default: "false", range: None },
+    ConfigKey { name: "aq_delta_q", default: "false", range: None },
+    ConfigKey { name: "activity_masking", default: "false", range: None },
+    ConfigKey { name: "error_resilient", default: "false", range: None },
+    ConfigKey { name: "tile_constrained_mvs", default: "false", range: None },
+    ConfigKey { name: "golden_interval", default: "0", range: Some((0, UNBOUNDED)) },
+    ConfigKey { name: "last_and_golden_only", default: "false", range: None },
+    ConfigKey { name: "temporal_layers", default: "1", range: Some((1, 4)) },
+    ConfigKey { name: "superres", default: "none", range: None },
+    ConfigKey { name: "render_width", default: "", range: Some((1, 65536)) },
+    ConfigKey { name: "render_height", default: "", range: Some((1, 65536)) },
+    ConfigKey { name: "level", default: "none", range: None },
+    ConfigKey { name: "high_tier", default: "false", range: None },
+    ConfigKey { name: "quantizer", default: "100", range: Some((0, 255)) },
+    ConfigKey { name: "min_quantizer", default: "0", range: Some((0, 255)) },
+    ConfigKey { name: "max_quantizer", default: "255", range: Some((0, 255)) },
+    ConfigKey { name: "bitrate", default: "0", range: Some((0, std::i32::MAX as i64)) },
+    ConfigKey { name: "keyframe_max_reencodes", default: "0", range: Some((0, 255)) },
+    ConfigKey { name: "keyframe_overshoot_pct", default: "100", range: Some((0, std::u32::MAX as i64)) },
+    ConfigKey { name: "decoder_model_info", default: "false", range: None },
+    ConfigKey { name: "key_frame_quantizer_offset", default: "-0.39294372842822706", range: None },
+    ConfigKey { name: "alt_ref_quantizer_offset", default: "0", range: None },
+    ConfigKey { name: "pyramid_level1_quantizer_offset", default: "0.39294372842822706", range: None },
+    ConfigKey { name: "pyramid_level2_quantizer_offset", default: "0.7858874568564541", range: None },
+    ConfigKey { name: "qm_levels", default: "", range: None },
+    ConfigKey { name: "key_frame_interval", default: "240", range: Some((0, UNBOUNDED)) },
+    ConfigKey { name: "min_key_frame_interval", default: "12", range: Some((0, UNBOUNDED)) },
+    ConfigKey { name: "scenecut_sensitivity", default: "50", range: Some((0, 100)) },
+    ConfigKey { name: "intra_only_scene_changes", default: "false", range: None },
+    ConfigKey { name: "intra_refresh", default: "none", range: None },
+    ConfigKey { name: "reservoir_frame_delay", default: "", range: Some((12, std::i32::MAX as i64)) },
+    ConfigKey { name: "low_latency", default: "false", range: None },
+    ConfigKey { name: "zero_delay", default: "false", range: None },
+    ConfigKey { name: "all_intra", default: "false", range: None },
+];
+
+unsafe fn config_key(key: *const c_char) -> Option<&'static ConfigKey> {
+    let key = CStr::from_ptr(key).to_str().ok()?;
+    CONFIG_KEYS.iter().find(|k| k.name == key)
+}
+
 /// Set a configuration parameter using its key and value as string.
 ///
-/// Available keys and values
+/// Available keys and values, see also rav1e_config_key_default() and
+/// rav1e_config_key_range()
 /// - "quantizer": 0-255, default 100
-/// - "speed": 0-10, default 3
-/// - "tune": "psnr"-"psychovisual", default "psnr"
+/// - "min_quantizer": 0-255, default 0
+/// - "max_quantizer": 0-255, default 255
+/// - "bitrate": 0-, default 0 (constant quantizer)
+/// - "speed": 0-10, default 5
+/// - "tune": "psnr", "psychovisual", "screen" or "ssim", default "psychovisual"
+/// - "psy_rd_strength": 0-255 in 1/8 units, default 0 (disabled)
+/// - "chroma_distortion_weight": 0-255 in 1/8 units, default 8
+/// - "chroma_me": "true"-"false", default "false"
+/// - "me_distortion": "sad", "satd" or "sse", default "sad"
+/// - "rdo_distortion": "sad", "satd" or "sse", default "sse"
+/// - "me_distortion_weight": 1-255 in 1/8 units, default 8
+/// - "rdo_distortion_weight": 1-255 in 1/8 units, default 8
+/// - "denoise_strength": 0-255, default 0 (no denoising nor film grain)
+/// - "film_grain_table": path of an aomenc film grain table file, default ""
+///   (the film grain removed by the denoiser)
+/// - "duplicate_frame_threshold": 0-65535, default unset (no detection)
+/// - "enable_intrabc": "true"-"false", default "false"
+/// - "detect_screen_content": "true"-"false", default "false"
+/// - "variance_aq": "true"-"false", default "false"
+/// - "aq_delta_q": "true"-"false", default "false"
+/// - "activity_masking": "true"-"false", default "false"
+/// - "zero_delay": "true"-"false", default "false"
+/// - "all_intra": "true"-"false", default "false"
+/// - "keyframe_max_reencodes": 0-255, default 0 (no re-encoding)
+/// - "keyframe_overshoot_pct": 0-, default 100
+/// - "decoder_model_info": "true"-"false", default "false", bitrate mode only
+/// - "key_frame_quantizer_offset", "alt_ref_quantizer_offset",
+///   "pyramid_level1_quantizer_offset", "pyramid_level2_quantizer_offset":
+///   -8.0-8.0, log2 of the quantizer of the frame type relative to the base
+///   quantizer, default about -0.39, 0, 0.39 and 0.79
+/// - "qm_levels": quantizer matrix levels as "Y,U,V", only "15,15,15" for
+///   now, default "" (no quantizer matrices)
+/// - "error_resilient": "true"-"false", default "false"
+/// - "tile_constrained_mvs": "true"-"false", default "false"
+/// - "golden_interval": 0-, default 0 (no golden frame), low latency only
+/// - "last_and_golden_only": "true"-"false", default "false", low latency only
+/// - "temporal_layers": 1-4, default 1, low latency only
+/// - "superres": "none", "auto" or 9-16, default "none"
+/// - "render_width": 1-65536, default unset (the frame width)
+/// - "render_height": 1-65536, default unset (the frame height)
+/// - "level": "auto", "none" or a level such as "5.1", default "none"
+/// - "high_tier": "true"-"false", default "false", set after an explicit level
+/// - "key_frame_interval": 0-, default 240
+/// - "min_key_frame_interval": 0-, default 12
+/// - "scenecut_sensitivity": 0-100, default 50, 0 placing key frames at
+///   key_frame_interval only
+/// - "intra_only_scene_changes": "true"-"false", default "false"
+/// - "intra_refresh": "none", "columns:PERIOD" or "rows:PERIOD", default "none"
+/// - "reservoir_frame_delay": 12-, default derived from key_frame_interval
+/// - "low_latency": "true"-"false", default "false"
 ///
 /// Return a negative value on error or 0.
 #[no_mangle]
@@ -316,14 +498,63 @@ pub unsafe extern "C" fn rav1e_config_parse_int(
     if option_match(cfg, key, val.as_ptr()) == Ok(()) { 0 } else { -1 }
 }
 
+/// Return the default value of a configuration key, as accepted by
+/// rav1e_config_parse(), or NULL for an unknown key.
+///
+/// The empty string stands for a value derived from the other keys.
+///
+/// Must be freed with free().
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_config_key_default(key: *const c_char) -> *mut c_char {
+    let default = match config_key(key) {
+        Some(k) => k.default,
+        None => return std::ptr::null_mut(),
+    };
+
+    let cbuf = CString::new(default).unwrap();
+    let len = cbuf.as_bytes_with_nul().len();
+    let ret = libc::malloc(len);
+
+    if !ret.is_null() {
+        let cptr = cbuf.as_ptr() as *const libc::c_void;
+        libc::memcpy(ret, cptr, len);
+    }
+
+    ret as *mut c_char
+}
+
+/// Retrieve the valid range of a numeric configuration key, both bounds
+/// included.
+///
+/// Return a negative value for an unknown or non-numeric key, or 0.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_config_key_range(
+    key: *const c_char,
+    min: *mut i64,
+    max: *mut i64,
+) -> c_int {
+    match config_key(key).and_then(|k| k.range) {
+        Some((lo, hi)) => {
+            *min = lo;
+            *max = hi;
+            0
+        }
+        None => -1,
+    }
+}
+
 /// Generate a new encoding context from a populated encoder configuration
 ///
 /// Multiple contexts can be generated through it.
+/// Returns NULL if the configuration is invalid, e.g. beyond its level.
 #[no_mangle]
 pub unsafe extern "C" fn rav1e_context_new(cfg: *const Config) -> *mut Context {
-    let ctx = Context {
-        ctx: (*cfg).cfg.new_context(),
-        last_err: None,
+    let ctx = match (*cfg).cfg.new_context() {
+        Ok(ctx) => Context {
+            ctx,
+            last_err: None,
+        },
+        Err(_) => return std::ptr::null_mut(),
     };
 
     Box::into_raw(Box::new(ctx))
@@ -345,7 +576,7 @@ pub unsafe extern "C" fn rav1e_context_unref(ctx: *mut Context) {
 #[no_mangle]
 pub unsafe extern "C" fn rav1e_frame_new(ctx: *const Context) -> *mut Frame {
     let f = (*ctx).ctx.new_frame();
-    let frame = Box::new(Frame(f));
+    let frame = Box::new(Frame(f, rav1e::FrameParameters::default()));
 
     Box::into_raw(frame)
 }
@@ -357,6 +588,15 @@ pub unsafe extern "C" fn rav1e_frame_unref(frame: *mut Frame) {
     }
 }
 
+/// Set the presentation time stamp of a frame, in time base units
+///
+/// It is passed through to the packet displaying the frame. Frames without
+/// one are stamped with their input frame number.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_frame_set_pts(frame: *mut Frame, pts: u64) {
+    (*frame).1.pts = Some(pts);
+}
+
 /// Retrieve the first-pass data of a two-pass encode for the frame that was
 /// just encoded. This should be called BEFORE every call to rav1e_receive_packet()
 /// (including the very first one), even if no packet was produced by the
@@ -422,6 +662,32 @@ pub unsafe extern "C" fn rav1e_twopass_in(ctx: *mut Context, buf: *mut u8, buf_s
     }
 }
 
+/// Retrieve the rate control state adapted so far, to prime the encode of
+/// the next chunk of the same video with rav1e_rate_summary_in().
+///
+/// Must be freed with rav1e_twopass_unref().
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_rate_summary(ctx: *mut Context, buf_size: *mut size_t) -> *mut u8 {
+    let v = (*ctx).ctx.rate_summary();
+    *buf_size = v.len();
+    Box::into_raw(v.into_boxed_slice()) as *mut u8
+}
+
+/// Prime the rate control of a single-pass encode with the summary of the
+/// previous chunk, before the first call to rav1e_receive_packet().
+/// Returns -1 on failure or 0.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_rate_summary_in(ctx: *mut Context, buf: *mut u8, buf_size: size_t) -> c_int {
+    let buf_slice = slice::from_raw_parts(buf, buf_size as usize);
+    match (*ctx).ctx.rate_summary_in(buf_slice) {
+        Ok(()) => 0,
+        Err(v) => {
+            (*ctx).last_err = Some(v);
+            -1
+        },
+    }
+}
+
 /// Send the frame for encoding
 ///
 /// The function increases the frame internal reference count and it can be passed multiple
@@ -433,15 +699,13 @@ pub unsafe extern "C" fn rav1e_twopass_in(ctx: *mut Context, buf: *mut u8, buf_s
 /// - `< 0` on unrecoverable failure
 #[no_mangle]
 pub unsafe extern "C" fn rav1e_send_frame(ctx: *mut Context, frame: *const Frame) -> EncoderStatus {
-    let frame = if frame.is_null() {
-        None
+    let ret = if frame.is_null() {
+        (*ctx).ctx.send_frame(None)
     } else {
-        Some((*frame).0.clone())
+        (*ctx).ctx.send_frame(((*frame).0.clone(), (*frame).1.clone()))
     };
 
-    let ret = (*ctx)
-        .ctx
-        .send_frame(frame)
+    let ret = ret
         .map(|_v| {
             None
         }).unwrap_or_else(|e| {
@@ -453,6 +717,92 @@ pub unsafe extern "C" fn rav1e_send_frame(ctx: *mut Context, frame: *const Frame
     ret.into()
 }
 
+/// Signal the end of the input, for the encoder to emit all the frames it
+/// still holds
+///
+/// It is equivalent to passing NULL to rav1e_send_frame().
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_flush(ctx: *mut Context) -> EncoderStatus {
+    rav1e_send_frame(ctx, std::ptr::null())
+}
+
+/// Signal that a source frame was dropped instead of sent
+///
+/// Nothing is coded for it, its share of the bitrate goes to the following
+/// frames and the default time stamps of the following frames skip it.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_drop_frame(ctx: *mut Context) {
+    (*ctx).ctx.drop_frame();
+}
+
+/// Change the target bitrate, in bits per second, of the frames encoded from
+/// now on, 0 for a constant quantizer
+///
+/// Two-pass encodes cannot be retargeted.
+///
+/// Return a negative value on error or 0.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_set_bitrate(ctx: *mut Context, bitrate: i32) -> c_int {
+    match (*ctx).ctx.set_bitrate(bitrate) {
+        Ok(()) => 0,
+        Err(v) => {
+            (*ctx).last_err = Some(v);
+            -1
+        },
+    }
+}
+
+/// Change the quantizer, 0-255, of the frames encoded from now on
+///
+/// In bitrate mode it bounds the quantizer picked by the rate control.
+///
+/// Return a negative value on error or 0.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_set_quantizer(ctx: *mut Context, quantizer: c_int) -> c_int {
+    if quantizer < 0 {
+        return -1;
+    }
+    match (*ctx).ctx.set_quantizer(quantizer as usize) {
+        Ok(()) => 0,
+        Err(v) => {
+            (*ctx).last_err = Some(v);
+            -1
+        },
+    }
+}
+
+/// Code the frames sent from now on at width x height, starting a new
+/// sequence with a key frame
+///
+/// Frames allocated by rav1e_frame_new() after the call have the new size.
+/// Two-pass encodes and still pictures cannot be resized.
+///
+/// Return a negative value on error or 0.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_resize(ctx: *mut Context, width: size_t, height: size_t) -> c_int {
+    match (*ctx).ctx.resize(width, height) {
+        Ok(()) => 0,
+        Err(v) => {
+            (*ctx).last_err = Some(v);
+            -1
+        },
+    }
+}
+
+/// Return the number of frames to send after a frame before its packet can
+/// be received, without flushing.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_frame_delay(ctx: *const Context) -> u64 {
+    (*ctx).ctx.frame_delay()
+}
+
+/// Return the most frames the encoder holds at once, from their sending
+/// until the packet showing them is received.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_max_latency_frames(ctx: *const Context) -> u64 {
+    (*ctx).ctx.max_latency_frames()
+}
+
 /// Return the last encoder status
 #[no_mangle]
 pub unsafe extern "C" fn rav1e_last_status(ctx: *const Context) -> EncoderStatus {
@@ -494,13 +844,14 @@ pub unsafe extern "C" fn rav1e_receive_packet(
         .ctx
         .receive_packet()
         .map(|p| {
-            let rav1e::Packet { data, input_frameno, frame_type, .. } = p;
+            let rav1e::Packet { data, input_frameno, pts, frame_type, .. } = p;
             let len  = data.len();
             let data = Box::into_raw(data.into_boxed_slice()) as *const u8;
             let packet = Packet {
                 data,
                 len,
                 input_frameno,
+                pts,
                 frame_type,
             };
             *pkt = Box::into_raw(Box::new(packet));
@@ -17,6 +17,11 @@ use crate::tiling::*;
 use crate::util::{clamp, msb, Pixel, CastFromPrimitive};
 
 use std::cmp;
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::mem::size_of;
 
 pub struct CdefDirections {
   dir: [[u8; 8]; 8],
@@ -31,6 +36,10 @@ const CDEF_SEC_STRENGTHS: u8 = 4;
 // the max. */
 const CDEF_DIV_TABLE: [i32; 9] = [ 0, 840, 420, 280, 210, 168, 140, 120, 105 ];
 
+// Direction of the chroma blocks of 4:2:2 frames, indexed by the luma one,
+// whose angles are distorted by the horizontal-only subsampling
+const CDEF_UV_DIR_422: [usize; 8] = [ 7, 0, 2, 4, 5, 6, 6, 6 ];
+
 #[inline]
 /// Returns the position and value of the first instance of the max element in
 /// a slice as a tuple.
@@ -56,6 +65,16 @@ fn first_max_element(elems: &[i32]) -> (usize, i32) {
 // that term is never computed. See Section 2, step 2, of:
 // http://jmvalin.ca/notes/intra_paint.pdf
 fn cdef_find_dir<T: Pixel>(img: &PlaneSlice<'_, T>, var: &mut i32, coeff_shift: usize) -> i32 {
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("sse4.1") {
+      return unsafe { cdef_find_dir_sse4_1(img, var, coeff_shift) };
+    }
+  }
+  cdef_find_dir_rust(img, var, coeff_shift)
+}
+
+fn cdef_find_dir_rust<T: Pixel>(img: &PlaneSlice<'_, T>, var: &mut i32, coeff_shift: usize) -> i32 {
   let mut cost: [i32; 8] = [0; 8];
   let mut partial: [[i32; 15]; 8] = [[0; 15]; 8];
   for i in 0..8 {
@@ -110,6 +129,113 @@ fn cdef_find_dir<T: Pixel>(img: &PlaneSlice<'_, T>, var: &mut i32, coeff_shift:
   best_dir as i32
 }
 
+// Weights of the squared partial sums in the costs of cdef_find_dir(), for
+// the diagonal directions (0 and 4) and the others with lines of several
+// lengths (1, 3, 5 and 7)
+const CDEF_DIAG_WEIGHTS: [i32; 16] =
+  [840, 420, 280, 210, 168, 140, 120, 105, 120, 140, 168, 210, 280, 420, 840, 0];
+const CDEF_ALT_WEIGHTS: [i32; 16] =
+  [420, 210, 140, 105, 105, 105, 105, 105, 140, 210, 420, 0, 0, 0, 0, 0];
+
+// Moves the 8 lanes of v up by n lanes into a 16-lane (low, high) pair
+#[inline(always)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn shift_lanes(v: __m128i, n: usize) -> (__m128i, __m128i) {
+  match n {
+    0 => (v, _mm_setzero_si128()),
+    1 => (_mm_slli_si128(v, 2), _mm_srli_si128(v, 14)),
+    2 => (_mm_slli_si128(v, 4), _mm_srli_si128(v, 12)),
+    3 => (_mm_slli_si128(v, 6), _mm_srli_si128(v, 10)),
+    4 => (_mm_slli_si128(v, 8), _mm_srli_si128(v, 8)),
+    5 => (_mm_slli_si128(v, 10), _mm_srli_si128(v, 6)),
+    6 => (_mm_slli_si128(v, 12), _mm_srli_si128(v, 4)),
+    7 => (_mm_slli_si128(v, 14), _mm_srli_si128(v, 2)),
+    _ => unreachable!()
+  }
+}
+
+// Sum of the squares of 16 partial sums, each multiplied by its weight
+#[target_feature(enable = "sse4.1")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn cdef_dir_cost_sse4_1(partial: (__m128i, __m128i), weights: &[i32; 16]) -> i32 {
+  let mut sum = _mm_setzero_si128();
+  for (k, &v) in [partial.0, partial.1].iter().enumerate() {
+    let halves = [_mm_cvtepi16_epi32(v), _mm_cvtepi16_epi32(_mm_srli_si128(v, 8))];
+    for (h, &x) in halves.iter().enumerate() {
+      let w = _mm_loadu_si128(weights[8 * k + 4 * h..].as_ptr() as *const _);
+      sum = _mm_add_epi32(sum, _mm_mullo_epi32(_mm_mullo_epi32(x, x), w));
+    }
+  }
+  sum = _mm_add_epi32(sum, _mm_shuffle_epi32(sum, 0b01_00_11_10));
+  sum = _mm_add_epi32(sum, _mm_shuffle_epi32(sum, 0b10_11_00_01));
+  _mm_cvtsi128_si32(sum)
+}
+
+// Same as cdef_find_dir_rust(), accumulating the partial sums of a row of 8
+// pixels at a time. Rows are shifted into place for the directions whose
+// lines move across columns, and their pixel pairs are added first for
+// those that move every other column.
+#[target_feature(enable = "sse4.1")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn cdef_find_dir_sse4_1<T: Pixel>(
+  img: &PlaneSlice<'_, T>, var: &mut i32, coeff_shift: usize
+) -> i32 {
+  let reverse = _mm_setr_epi8(14, 15, 12, 13, 10, 11, 8, 9, 6, 7, 4, 5, 2, 3, 0, 1);
+  let shift = _mm_cvtsi32_si128(coeff_shift as i32);
+  let zero = _mm_setzero_si128();
+  let mut rows = [zero; 8];
+  for (i, row) in rows.iter_mut().enumerate() {
+    let p = if size_of::<T>() == 1 {
+      _mm_cvtepu8_epi16(_mm_loadl_epi64(img[i].as_ptr() as *const _))
+    } else {
+      _mm_loadu_si128(img[i].as_ptr() as *const _)
+    };
+    // We subtract 128 here to reduce the maximum range of the squared
+    // partial sums.
+    *row = _mm_sub_epi16(_mm_srl_epi16(p, shift), _mm_set1_epi16(128));
+  }
+
+  let add = |a: (__m128i, __m128i), b: (__m128i, __m128i)| {
+    (_mm_add_epi16(a.0, b.0), _mm_add_epi16(a.1, b.1))
+  };
+  let mut partial = [(zero, zero); 8];
+  for (i, &x) in rows.iter().enumerate() {
+    let reversed = _mm_shuffle_epi8(x, reverse);
+    partial[0] = add(partial[0], shift_lanes(x, i));
+    partial[1] = add(partial[1], shift_lanes(_mm_hadd_epi16(x, zero), i));
+    partial[3] = add(partial[3], shift_lanes(_mm_hadd_epi16(reversed, zero), i));
+    partial[4] = add(partial[4], shift_lanes(reversed, i));
+    partial[5] = add(partial[5], shift_lanes(x, 3 - i / 2));
+    partial[6].0 = _mm_add_epi16(partial[6].0, x);
+    partial[7] = add(partial[7], shift_lanes(x, i / 2));
+  }
+  // Sums of each row, in lane order
+  let pairs = [
+    _mm_hadd_epi16(rows[0], rows[1]),
+    _mm_hadd_epi16(rows[2], rows[3]),
+    _mm_hadd_epi16(rows[4], rows[5]),
+    _mm_hadd_epi16(rows[6], rows[7])
+  ];
+  partial[2].0 = _mm_hadd_epi16(_mm_hadd_epi16(pairs[0], pairs[1]),
+                                _mm_hadd_epi16(pairs[2], pairs[3]));
+
+  let mut cost = [0i32; 8];
+  let flat_weights = [CDEF_DIV_TABLE[8]; 16];
+  for (d, c) in cost.iter_mut().enumerate() {
+    let weights = match d {
+      0 | 4 => &CDEF_DIAG_WEIGHTS,
+      2 | 6 => &flat_weights,
+      _ => &CDEF_ALT_WEIGHTS
+    };
+    *c = cdef_dir_cost_sse4_1(partial[d], weights);
+  }
+
+  let (best_dir, best_cost) = first_max_element(&cost);
+  *var = (best_cost - cost[(best_dir + 4) & 7]) >> 10;
+
+  best_dir as i32
+}
+
 #[inline(always)]
 fn constrain(diff: i32, threshold: i32, damping: i32) -> i32 {
   if threshold != 0 {
@@ -126,7 +252,22 @@ fn constrain(diff: i32, threshold: i32, damping: i32) -> i32 {
 // of the 2-pixel padding around the block, not the block itself.
 // The destination is unpadded.
 #[allow(clippy::erasing_op, clippy::identity_op, clippy::neg_multiply)]
-unsafe fn cdef_filter_block<T: Pixel>(
+pub unsafe fn cdef_filter_block<T: Pixel>(
+  dst: *mut T, dstride: isize, input: *const u16, istride: isize, pri_strength: i32,
+  sec_strength: i32, dir: usize, damping: i32, xsize: isize, ysize: isize, coeff_shift: i32
+) {
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if xsize == 8 && is_x86_feature_detected!("ssse3") {
+      return cdef_filter_block_ssse3(dst, dstride, input, istride, pri_strength,
+                                     sec_strength, dir, damping, ysize, coeff_shift);
+    }
+  }
+  cdef_filter_block_rust(dst, dstride, input, istride, pri_strength, sec_strength,
+                         dir, damping, xsize, ysize, coeff_shift)
+}
+
+unsafe fn cdef_filter_block_rust<T: Pixel>(
   dst: *mut T, dstride: isize, input: *const u16, istride: isize, pri_strength: i32,
   sec_strength: i32, dir: usize, damping: i32, xsize: isize, ysize: isize, coeff_shift: i32
 ) {
@@ -176,8 +317,83 @@ unsafe fn cdef_filter_block<T: Pixel>(
           sum += sec_tap * constrain(i32::cast_from(*s_elem) - i32::cast_from(x), sec_strength, damping);
         }
       }
-      let v = T::cast_from(i32::cast_from(x) + ((8 + sum - (sum < 0) as i32) >> 4));
-      *ptr_out = clamp(v, T::cast_from(min), T::cast_from(max));
+      let v = i32::cast_from(x) + ((8 + sum - (sum < 0) as i32) >> 4);
+      *ptr_out = T::cast_from(clamp(v, i32::cast_from(min), i32::cast_from(max)));
+    }
+  }
+}
+
+// Same as constrain(), on 8 differences at once
+#[target_feature(enable = "ssse3")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn constrain_ssse3(diff: __m128i, threshold: i32, damping: i32) -> __m128i {
+  let shift = if threshold != 0 { cmp::max(0, damping - msb(threshold)) } else { 0 };
+  let magnitude = _mm_abs_epi16(diff);
+  let limit = _mm_subs_epu16(_mm_set1_epi16(threshold as i16),
+                             _mm_srl_epi16(magnitude, _mm_cvtsi32_si128(shift)));
+  _mm_sign_epi16(_mm_min_epi16(magnitude, limit), diff)
+}
+
+// Filters 8 pixels of a row at a time, so only blocks 8 pixels wide are handled.
+#[target_feature(enable = "ssse3")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn cdef_filter_block_ssse3<T: Pixel>(
+  dst: *mut T, dstride: isize, input: *const u16, istride: isize, pri_strength: i32,
+  sec_strength: i32, dir: usize, damping: i32, ysize: isize, coeff_shift: i32
+) {
+  let cdef_pri_taps = [[4, 2], [3, 3]];
+  let cdef_sec_taps = [[2, 1], [2, 1]];
+  let pri_taps = cdef_pri_taps[((pri_strength >> coeff_shift) & 1) as usize];
+  let sec_taps = cdef_sec_taps[((pri_strength >> coeff_shift) & 1) as usize];
+  let cdef_directions = [[-1 * istride + 1, -2 * istride + 2 ],
+                         [ 0 * istride + 1, -1 * istride + 2 ],
+                         [ 0 * istride + 1,  0 * istride + 2 ],
+                         [ 0 * istride + 1,  1 * istride + 2 ],
+                         [ 1 * istride + 1,  2 * istride + 2 ],
+                         [ 1 * istride + 0,  2 * istride + 1 ],
+                         [ 1 * istride + 0,  2 * istride + 0 ],
+                         [ 1 * istride + 0,  2 * istride - 1 ]];
+  let large = _mm_set1_epi16(CDEF_VERY_LARGE as i16);
+  for i in 0..ysize {
+    let ptr_in = input.offset((i + 2) * istride + 2);
+    let ptr_out = dst.offset(i * dstride);
+    let x = _mm_loadu_si128(ptr_in as *const _);
+    let mut sum = _mm_setzero_si128();
+    let mut max = x;
+    let mut min = x;
+    for k in 0..2usize {
+      let cdef_dirs = [cdef_directions[dir][k], cdef_directions[(dir + 2) & 7][k], cdef_directions[(dir + 6) & 7][k]];
+      let p = [_mm_loadu_si128(ptr_in.offset(cdef_dirs[0]) as *const _),
+               _mm_loadu_si128(ptr_in.offset(-cdef_dirs[0]) as *const _)];
+      let pri_tap = _mm_set1_epi16(pri_taps[k]);
+      for &p_elem in p.iter() {
+        let c = constrain_ssse3(_mm_sub_epi16(p_elem, x), pri_strength, damping);
+        sum = _mm_add_epi16(sum, _mm_mullo_epi16(pri_tap, c));
+        max = _mm_max_epi16(max, _mm_andnot_si128(_mm_cmpeq_epi16(p_elem, large), p_elem));
+        min = _mm_min_epi16(min, p_elem);
+      }
+
+      let s = [_mm_loadu_si128(ptr_in.offset(cdef_dirs[1]) as *const _),
+               _mm_loadu_si128(ptr_in.offset(-cdef_dirs[1]) as *const _),
+               _mm_loadu_si128(ptr_in.offset(cdef_dirs[2]) as *const _),
+               _mm_loadu_si128(ptr_in.offset(-cdef_dirs[2]) as *const _)];
+      let sec_tap = _mm_set1_epi16(sec_taps[k]);
+      for &s_elem in s.iter() {
+        let c = constrain_ssse3(_mm_sub_epi16(s_elem, x), sec_strength, damping);
+        sum = _mm_add_epi16(sum, _mm_mullo_epi16(sec_tap, c));
+        max = _mm_max_epi16(max, _mm_andnot_si128(_mm_cmpeq_epi16(s_elem, large), s_elem));
+        min = _mm_min_epi16(min, s_elem);
+      }
+    }
+    // (8 + sum - (sum < 0)) >> 4
+    let rounded = _mm_add_epi16(_mm_add_epi16(sum, _mm_set1_epi16(8)),
+                                _mm_cmplt_epi16(sum, _mm_setzero_si128()));
+    let v = _mm_add_epi16(x, _mm_srai_epi16(rounded, 4));
+    let res = _mm_min_epi16(_mm_max_epi16(v, min), max);
+    if size_of::<T>() == 1 {
+      _mm_storel_epi64(ptr_out as *mut _, _mm_packus_epi16(res, res));
+    } else {
+      _mm_storeu_si128(ptr_out as *mut _, res);
     }
   }
 }
@@ -342,8 +558,10 @@ pub fn cdef_filter_superblock<T: Pixel>(
 ) {
   let coeff_shift = fi.sequence.bit_depth as i32 - 8;
   let cdef_damping = fi.cdef_damping as i32;
-  let cdef_y_strength = fi.cdef_y_strengths[cdef_index as usize];
-  let cdef_uv_strength = fi.cdef_uv_strengths[cdef_index as usize];
+  // With fewer than 8 strengths, the header codes every other one
+  let j = (cdef_index as usize) << (3 - fi.cdef_bits);
+  let cdef_y_strength = fi.cdef_y_strengths[j];
+  let cdef_uv_strength = fi.cdef_uv_strengths[j];
   let cdef_pri_y_strength = (cdef_y_strength / CDEF_SEC_STRENGTHS) as i32;
   let mut cdef_sec_y_strength = (cdef_y_strength % CDEF_SEC_STRENGTHS) as i32;
   let cdef_pri_uv_strength = (cdef_uv_strength / CDEF_SEC_STRENGTHS) as i32;
@@ -367,7 +585,7 @@ pub fn cdef_filter_superblock<T: Pixel>(
         if !skip {
           let dir = cdef_dirs.dir[bx][by];
           let var = cdef_dirs.var[bx][by];
-          for p in 0..3 {
+          for p in 0..fi.sequence.num_planes() {
             let out_plane = &mut out_frame.planes[p];
             let out_po = sbo.plane_offset(&out_plane.cfg);
             let in_plane = &in_frame.planes[p];
@@ -391,7 +609,13 @@ pub fn cdef_filter_superblock<T: Pixel>(
               local_pri_strength = cdef_pri_uv_strength << coeff_shift;
               local_sec_strength = cdef_sec_uv_strength << coeff_shift;
               local_damping -= 1;
-              if cdef_pri_uv_strength != 0 { dir as usize } else { 0 }
+              if cdef_pri_uv_strength == 0 {
+                0
+              } else if xdec != ydec {
+                CDEF_UV_DIR_422[dir as usize]
+              } else {
+                dir as usize
+              }
             };
 
             unsafe {
@@ -497,6 +721,90 @@ mod test {
   use crate::api::*;
   use crate::encoder::*;
 
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  fn check_filter_block_ssse3<T: Pixel>(coeff_shift: i32) {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaChaRng;
+
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let istride = 16;
+    let mut input = [0u16; 16 * 12];
+    let mut expected = [T::cast_from(0u8); 8 * 8];
+    let mut actual = [T::cast_from(0u8); 8 * 8];
+    for _ in 0..256 {
+      for v in input.iter_mut() {
+        *v = if ra.gen_range(0, 16) == 0 {
+          CDEF_VERY_LARGE
+        } else {
+          ra.gen_range(0, 256 << coeff_shift)
+        };
+      }
+      let pri_strength = ra.gen_range(0, 16) << coeff_shift;
+      let sec_strength = [0, 1, 2, 4][ra.gen_range(0, 4)] << coeff_shift;
+      let damping = ra.gen_range(3, 7) + coeff_shift;
+      let dir = ra.gen_range(0, 8);
+      let ysize = [4, 8][ra.gen_range(0, 2)];
+      unsafe {
+        cdef_filter_block_rust(expected.as_mut_ptr(), 8, input.as_ptr(), istride,
+                               pri_strength, sec_strength, dir, damping, 8,
+                               ysize, coeff_shift);
+        cdef_filter_block_ssse3(actual.as_mut_ptr(), 8, input.as_ptr(), istride,
+                                pri_strength, sec_strength, dir, damping, ysize,
+                                coeff_shift);
+      }
+      assert_eq!(&expected[..], &actual[..]);
+    }
+  }
+
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  #[test]
+  fn filter_block_ssse3_matches_rust() {
+    if !is_x86_feature_detected!("ssse3") {
+      return;
+    }
+    check_filter_block_ssse3::<u8>(0);
+    check_filter_block_ssse3::<u16>(0);
+    check_filter_block_ssse3::<u16>(2);
+  }
+
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  fn check_find_dir_sse4_1<T: Pixel>(coeff_shift: usize) {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaChaRng;
+
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let mut plane = Plane::<T>::wrap(vec![T::cast_from(0u8); 8 * 8], 8);
+    for _ in 0..256 {
+      // Lines in a random direction over noise, and pure noise
+      let (dx, dy) = (ra.gen_range(-2, 3), ra.gen_range(-2, 3));
+      let noise = ra.gen_range(1, 128);
+      for (y, row) in plane.as_region_mut().rows_iter_mut().enumerate() {
+        for (x, v) in row.iter_mut().enumerate() {
+          let line: i32 = if (x as i32 * dy - y as i32 * dx) % 3 == 0 { 96 } else { 0 };
+          *v = T::cast_from((line + ra.gen_range(0, noise)) << coeff_shift);
+        }
+      }
+      let (mut expected_var, mut actual_var) = (0, 0);
+      let expected = cdef_find_dir_rust(&plane.as_slice(), &mut expected_var, coeff_shift);
+      let actual = unsafe {
+        cdef_find_dir_sse4_1(&plane.as_slice(), &mut actual_var, coeff_shift)
+      };
+      assert_eq!(expected, actual);
+      assert_eq!(expected_var, actual_var);
+    }
+  }
+
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  #[test]
+  fn find_dir_sse4_1_matches_rust() {
+    if !is_x86_feature_detected!("sse4.1") {
+      return;
+    }
+    check_find_dir_sse4_1::<u8>(0);
+    check_find_dir_sse4_1::<u16>(0);
+    check_find_dir_sse4_1::<u16>(2);
+  }
+
   #[test]
   fn check_max_element() {
     assert_eq!(first_max_element(&[-1, -1, 1, 2, 3, 4, 6, 6]), (6, 6));
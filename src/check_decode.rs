@@ -0,0 +1,228 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Round-trip verification of the encoded packets
+//!
+//! Every packet is decoded with dav1d as soon as it is emitted and the
+//! decoded picture is compared with the reconstruction of the encoder, so
+//! that a bitstream desynchronization is reported on the frame causing it.
+
+use crate::api::Packet;
+use crate::frame::Plane;
+use crate::util::{CastFromPrimitive, Pixel};
+
+use dav1d_sys::*;
+
+use std::marker::PhantomData;
+use std::{error, fmt, mem, ptr, slice};
+
+/// Reason a packet failed the round-trip check
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckDecodeError {
+  /// dav1d rejected the packet or failed to decode it, with this error code
+  Decode(i32),
+  /// The packet holds a shown frame, but dav1d did not output a picture
+  MissingPicture { input_frameno: u64 },
+  /// The decoded picture differs from the reconstruction of the encoder
+  Mismatch { input_frameno: u64, plane: usize, x: usize, y: usize },
+}
+
+impl fmt::Display for CheckDecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      CheckDecodeError::Decode(code) => {
+        write!(f, "dav1d failed to decode the packet ({})", code)
+      }
+      CheckDecodeError::MissingPicture { input_frameno } => write!(
+        f,
+        "dav1d output no picture for frame {}",
+        input_frameno
+      ),
+      CheckDecodeError::Mismatch { input_frameno, plane, x, y } => write!(
+        f,
+        "frame {} does not decode to its reconstruction: plane {} differs \
+         at ({}, {})",
+        input_frameno, plane, x, y
+      ),
+    }
+  }
+}
+
+impl error::Error for CheckDecodeError {}
+
+/// Decodes packets with dav1d and compares them with their reconstruction
+pub struct DecodeChecker<T: Pixel> {
+  dec: *mut Dav1dContext,
+  width: usize,
+  height: usize,
+  pixel: PhantomData<T>,
+}
+
+impl<T: Pixel> DecodeChecker<T> {
+  /// Checker for a stream of `width`x`height` frames.
+  ///
+  /// Panics if dav1d cannot be instantiated.
+  pub fn new(width: usize, height: usize) -> Self {
+    unsafe {
+      let mut settings: Dav1dSettings = mem::zeroed();
+      dav1d_default_settings(&mut settings);
+      // Pictures must come out of the packet that carried them
+      settings.n_frame_threads = 1;
+      // The reconstruction of the encoder has no film grain
+      settings.apply_grain = 0;
+
+      let mut dec = ptr::null_mut();
+      let ret = dav1d_open(&mut dec, &settings);
+      if ret != 0 {
+        panic!("Cannot instantiate the decoder {}", ret);
+      }
+
+      DecodeChecker { dec, width, height, pixel: PhantomData }
+    }
+  }
+
+  /// Decode `packet` and compare the picture it shows with `packet.rec`.
+  pub fn check(&mut self, packet: &Packet<T>) -> Result<(), CheckDecodeError> {
+    unsafe {
+      let mut data: Dav1dData = mem::zeroed();
+      let buf = dav1d_data_create(&mut data, packet.data.len());
+      ptr::copy_nonoverlapping(packet.data.as_ptr(), buf, packet.data.len());
+      let ret = dav1d_send_data(self.dec, &mut data);
+      if ret != 0 {
+        dav1d_data_unref(&mut data);
+        return Err(CheckDecodeError::Decode(ret));
+      }
+
+      let mut pic: Dav1dPicture = mem::zeroed();
+      let ret = dav1d_get_picture(self.dec, &mut pic);
+      let rec = match &packet.rec {
+        Some(rec) => rec,
+        None => {
+          if ret == 0 {
+            dav1d_picture_unref(&mut pic);
+          }
+          return Ok(());
+        }
+      };
+      if ret == -(EAGAIN as i32) {
+        return Err(CheckDecodeError::MissingPicture {
+          input_frameno: packet.input_frameno,
+        });
+      }
+      if ret != 0 {
+        return Err(CheckDecodeError::Decode(ret));
+      }
+
+      let mut result = Ok(());
+      let planes = if pic.data[1].is_null() { 1 } else { 3 };
+      for p in 0..planes {
+        let stride = pic.stride[p.min(1)] as usize;
+        if let Some((x, y)) =
+          self.compare_plane(pic.data[p], stride, &rec.planes[p])
+        {
+          result = Err(CheckDecodeError::Mismatch {
+            input_frameno: packet.input_frameno,
+            plane: p,
+            x,
+            y,
+          });
+          break;
+        }
+      }
+      dav1d_picture_unref(&mut pic);
+      result
+    }
+  }
+
+  /// Position of the first pixel of the decoded plane at `data` which
+  /// differs from `rec`.
+  unsafe fn compare_plane(
+    &self, data: *mut std::os::raw::c_void, stride: usize, rec: &Plane<T>,
+  ) -> Option<(usize, usize)> {
+    let w = (self.width + rec.cfg.xdec) >> rec.cfg.xdec;
+    let h = (self.height + rec.cfg.ydec) >> rec.cfg.ydec;
+    let rec_stride = rec.cfg.stride;
+    let rec_data = rec.data_origin();
+
+    for y in 0..h {
+      let rec_row = &rec_data[y * rec_stride..y * rec_stride + w];
+      let mismatch = if mem::size_of::<T>() == 1 {
+        let row = slice::from_raw_parts((data as *const u8).add(y * stride), w);
+        row.iter().zip(rec_row).position(|(&d, &r)| u16::from(d) != u16::cast_from(r))
+      } else {
+        let row =
+          slice::from_raw_parts((data as *const u8).add(y * stride) as *const u16, w);
+        row.iter().zip(rec_row).position(|(&d, &r)| d != u16::cast_from(r))
+      };
+      if let Some(x) = mismatch {
+        return Some((x, y));
+      }
+    }
+    None
+  }
+}
+
+impl<T: Pixel> Drop for DecodeChecker<T> {
+  fn drop(&mut self) {
+    unsafe { dav1d_close(&mut self.dec) };
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::api::*;
+  use crate::film_grain::{FilmGrainParams, FilmGrainSegment, FilmGrainTable};
+  use std::sync::Arc;
+
+  #[test]
+  fn film_grain_stream_matches_reconstruction() {
+    let mut params = FilmGrainParams::default();
+    params.scaling_points_y = [[0, 64], [255, 64]].iter().cloned().collect();
+    params.chroma_scaling_from_luma = true;
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = 64;
+    enc.height = 64;
+    enc.low_latency = true;
+    enc.film_grain_table = Some(FilmGrainTable {
+      segments: vec![FilmGrainSegment {
+        start_time: 0,
+        end_time: std::u64::MAX,
+        params: Some(params)
+      }]
+    });
+    let cfg = Config { enc, threads: 1 };
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    let mut checker = DecodeChecker::<u8>::new(64, 64);
+
+    for i in 0..3 {
+      let mut input = ctx.new_frame();
+      for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+        for (j, v) in plane.data.iter_mut().enumerate() {
+          *v = ((j * 5 + i * 11) % 233) as u8;
+        }
+      }
+      let _ = ctx.send_frame(input);
+    }
+    ctx.flush();
+
+    let mut count = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          assert_eq!(checker.check(&pkt), Ok(()));
+          count += 1;
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(_) => break
+      }
+    }
+    assert_eq!(count, 3);
+  }
+}
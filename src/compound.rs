@@ -0,0 +1,251 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::frame::*;
+use crate::partition::BlockSize;
+use crate::partition::BlockSize::*;
+use crate::tiling::*;
+use crate::util::{CastFromPrimitive, Pixel};
+
+/// Masks weight the first prediction in units of 1/64
+pub const MASK_WEIGHT_BITS: usize = 6;
+const MASK_MAX: i32 = 1 << MASK_WEIGHT_BITS;
+pub const WEDGE_TYPES: usize = 16;
+
+const MASK_MASTER_SIZE: usize = 64;
+/// Base weight and slope of the difference-weighted mask
+const DIFFWTD_BASE: i32 = 38;
+const DIFFWTD_FACTOR: i32 = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum WedgeDirection {
+  Horizontal,
+  Vertical,
+  Oblique27,
+  Oblique63,
+  Oblique117,
+  Oblique153
+}
+
+use self::WedgeDirection::*;
+
+static WEDGE_MASTER_OBLIQUE_ODD: [u8; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 6, 18, 37, 53,
+  60, 63, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+
+static WEDGE_MASTER_OBLIQUE_EVEN: [u8; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4, 11, 27, 46, 58,
+  62, 63, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+
+static WEDGE_MASTER_VERTICAL: [u8; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 7, 21, 43, 57,
+  62, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+
+/// Wedge shapes as direction and offsets of the edge from the block center,
+/// in 1/8 of the block size, for blocks taller than wide
+static WEDGE_CODEBOOK_HGTW: [(WedgeDirection, usize, usize); WEDGE_TYPES] = [
+  (Oblique27, 4, 4), (Oblique63, 4, 4), (Oblique117, 4, 4),
+  (Oblique153, 4, 4), (Horizontal, 4, 2), (Horizontal, 4, 4),
+  (Horizontal, 4, 6), (Vertical, 4, 4), (Oblique27, 4, 2),
+  (Oblique27, 4, 6), (Oblique153, 4, 2), (Oblique153, 4, 6),
+  (Oblique63, 2, 4), (Oblique63, 6, 4), (Oblique117, 2, 4),
+  (Oblique117, 6, 4)
+];
+
+/// Wedge shapes for square blocks
+static WEDGE_CODEBOOK_HEQW: [(WedgeDirection, usize, usize); WEDGE_TYPES] = [
+  (Oblique27, 4, 4), (Oblique63, 4, 4), (Oblique117, 4, 4),
+  (Oblique153, 4, 4), (Horizontal, 4, 2), (Horizontal, 4, 6),
+  (Vertical, 2, 4), (Vertical, 6, 4), (Oblique27, 4, 2),
+  (Oblique27, 4, 6), (Oblique153, 4, 2), (Oblique153, 4, 6),
+  (Oblique63, 2, 4), (Oblique63, 6, 4), (Oblique117, 2, 4),
+  (Oblique117, 6, 4)
+];
+
+/// Wedge shapes for blocks wider than tall
+static WEDGE_CODEBOOK_HLTW: [(WedgeDirection, usize, usize); WEDGE_TYPES] = [
+  (Oblique27, 4, 4), (Oblique63, 4, 4), (Oblique117, 4, 4),
+  (Oblique153, 4, 4), (Vertical, 2, 4), (Vertical, 4, 4),
+  (Vertical, 6, 4), (Horizontal, 4, 4), (Oblique27, 4, 2),
+  (Oblique27, 4, 6), (Oblique153, 4, 2), (Oblique153, 4, 6),
+  (Oblique63, 2, 4), (Oblique63, 6, 4), (Oblique117, 2, 4),
+  (Oblique117, 6, 4)
+];
+
+/// Whether wedge compound can be used on a block, which is also when the
+/// type of masked compound has to be signaled
+pub fn is_wedge_allowed(bsize: BlockSize) -> bool {
+  match bsize {
+    BLOCK_8X8 | BLOCK_8X16 | BLOCK_16X8 | BLOCK_16X16 | BLOCK_16X32
+    | BLOCK_32X16 | BLOCK_32X32 | BLOCK_8X32 | BLOCK_32X8 => true,
+    _ => false
+  }
+}
+
+/// Value of the 64x64 master mask of a wedge direction. The oblique and
+/// horizontal masters are transposed or mirrored versions of the 63 degree
+/// and vertical ones.
+fn master_mask(dir: WedgeDirection, y: usize, x: usize) -> i32 {
+  fn oblique63(y: usize, x: usize) -> i32 {
+    // Every other row uses the odd master, shifted by a further pixel
+    let (master, shift) = if y & 1 == 0 {
+      (&WEDGE_MASTER_OBLIQUE_EVEN, (MASK_MASTER_SIZE / 4 - y / 2) as isize)
+    } else {
+      (&WEDGE_MASTER_OBLIQUE_ODD, (MASK_MASTER_SIZE / 4 - y / 2) as isize - 1)
+    };
+    let i = (x as isize - shift).max(0).min(MASK_MASTER_SIZE as isize - 1);
+    master[i as usize] as i32
+  }
+
+  let last = MASK_MASTER_SIZE - 1;
+  match dir {
+    Vertical => WEDGE_MASTER_VERTICAL[x] as i32,
+    Horizontal => WEDGE_MASTER_VERTICAL[y] as i32,
+    Oblique63 => oblique63(y, x),
+    Oblique27 => oblique63(x, y),
+    Oblique117 => MASK_MAX - oblique63(y, last - x),
+    Oblique153 => MASK_MAX - oblique63(x, last - y)
+  }
+}
+
+/// Fills `mask` with the wedge of a block, with a stride of the block width.
+/// `sign` selects which of the two predictions gets the larger area.
+pub fn wedge_mask(mask: &mut [u8], bsize: BlockSize, index: usize, sign: bool) {
+  debug_assert!(is_wedge_allowed(bsize));
+  let (w, h) = (bsize.width(), bsize.height());
+  let codebook = if h > w {
+    &WEDGE_CODEBOOK_HGTW
+  } else if h < w {
+    &WEDGE_CODEBOOK_HLTW
+  } else {
+    &WEDGE_CODEBOOK_HEQW
+  };
+  let (dir, x_offset, y_offset) = codebook[index];
+  let xoff = MASK_MASTER_SIZE / 2 - ((x_offset * w) >> 3);
+  let yoff = MASK_MASTER_SIZE / 2 - ((y_offset * h) >> 3);
+
+  // The sign is relative to the side the top and left edges are on
+  let sum = (0..w).map(|x| master_mask(dir, yoff, xoff + x)).sum::<i32>()
+    + (1..h).map(|y| master_mask(dir, yoff + y, xoff)).sum::<i32>();
+  let n = (w + h - 1) as i32;
+  let flip_sign = (sum + (n - 1) / 2) / n < MASK_MAX / 2;
+
+  for y in 0..h {
+    for x in 0..w {
+      let m = master_mask(dir, yoff + y, xoff + x);
+      mask[y * w + x] = if sign == flip_sign { m } else { MASK_MAX - m } as u8;
+    }
+  }
+}
+
+/// Fills `mask` with weights that favor the first prediction where the two
+/// predictions differ, or the second one with `inverse`. The predictions are
+/// the intermediate output of `prep_8tap`.
+pub fn diffwtd_mask(
+  mask: &mut [u8], tmp0: &[i16], tmp1: &[i16], width: usize, height: usize,
+  inverse: bool, bit_depth: usize
+) {
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let shift = bit_depth - 8 + intermediate_bits;
+  for i in 0..width * height {
+    let diff = (tmp0[i] as i32 - tmp1[i] as i32).abs();
+    let diff = (diff + (1 << shift >> 1)) >> shift;
+    let m = (DIFFWTD_BASE + diff / DIFFWTD_FACTOR).min(MASK_MAX);
+    mask[i] = if inverse { MASK_MAX - m } else { m } as u8;
+  }
+}
+
+/// Blends two intermediate predictions with a luma mask of stride
+/// `mask_stride`, which is averaged down for subsampled planes.
+pub fn mask_blend<T: Pixel>(
+  dst: &mut PlaneRegionMut<'_, T>, tmp0: &[i16], tmp1: &[i16], width: usize,
+  height: usize, mask: &[u8], mask_stride: usize, bit_depth: usize
+) {
+  let &PlaneConfig { xdec, ydec, .. } = dst.plane_cfg;
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let shift = MASK_WEIGHT_BITS + intermediate_bits;
+
+  for r in 0..height {
+    let dst_slice = &mut dst[r];
+    for c in 0..width {
+      let mut m = 0;
+      for y in 0..=ydec {
+        for x in 0..=xdec {
+          m += mask[((r << ydec) + y) * mask_stride + (c << xdec) + x] as i32;
+        }
+      }
+      let dec = xdec + ydec;
+      let m = (m + (1 << dec >> 1)) >> dec;
+      let i = r * width + c;
+      let sum = m * tmp0[i] as i32 + (MASK_MAX - m) * tmp1[i] as i32;
+      dst_slice[c] = T::cast_from(
+        ((sum + (1 << shift >> 1)) >> shift).max(0).min(max_sample_val)
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn wedge_signs_are_complementary() {
+    let mut mask0 = [0u8; 32 * 32];
+    let mut mask1 = [0u8; 32 * 32];
+    for &bsize in &[BLOCK_16X16, BLOCK_16X32, BLOCK_32X8] {
+      for index in 0..WEDGE_TYPES {
+        wedge_mask(&mut mask0, bsize, index, false);
+        wedge_mask(&mut mask1, bsize, index, true);
+        let n = bsize.width() * bsize.height();
+        assert!(mask0[..n].iter().zip(mask1[..n].iter())
+          .all(|(&a, &b)| a as i32 + b as i32 == MASK_MAX));
+        // Both sides of the edge are inside every block
+        assert!(mask0[..n].iter().any(|&m| (m as i32) < MASK_MAX / 2));
+        assert!(mask0[..n].iter().any(|&m| (m as i32) > MASK_MAX / 2));
+      }
+    }
+  }
+
+  #[test]
+  fn vertical_wedge_splits_halves() {
+    // Index 6 of the square codebook is a vertical edge at 1/4 of the width
+    let mut mask = [0u8; 16 * 16];
+    wedge_mask(&mut mask, BLOCK_16X16, 6, false);
+    for row in mask.chunks(16) {
+      assert_eq!(row, &mask[..16]);
+    }
+    assert!(mask[..16].windows(2).all(|p| p[0] >= p[1]) ||
+      mask[..16].windows(2).all(|p| p[0] <= p[1]));
+    for row in mask.chunks(16) {
+      assert!(row.iter().any(|&m| (m as i32) < MASK_MAX / 2));
+    }
+  }
+
+  #[test]
+  fn diffwtd_follows_differences() {
+    let tmp0 = [100 << 4, 100 << 4];
+    let tmp1 = [100 << 4, 200 << 4];
+    let mut mask = [0u8; 2];
+    diffwtd_mask(&mut mask, &tmp0, &tmp1, 2, 1, false, 8);
+    assert_eq!(mask, [38, 44]);
+    diffwtd_mask(&mut mask, &tmp0, &tmp1, 2, 1, true, 8);
+    assert_eq!(mask, [26, 20]);
+  }
+}
@@ -12,6 +12,7 @@
 #![allow(dead_code)]
 #![allow(non_camel_case_types)]
 
+use crate::compound::is_wedge_allowed;
 use crate::ec::Writer;
 use crate::ec::OD_BITRES;
 use crate::encoder::FrameInvariants;
@@ -21,8 +22,10 @@ use crate::mc::MotionVector;
 use crate::partition::BlockSize::*;
 use crate::partition::RefType::*;
 use crate::partition::*;
-use crate::predict::PredictionMode;
+use crate::palette::*;
+use crate::predict::{CompoundParams, CompoundType, FilterIntraMode, PaletteColor, PaletteSize, PredictionMode};
 use crate::predict::PredictionMode::*;
+use crate::quantize::{find_eob, QuantizationContext};
 use crate::transform::TxSize::*;
 use crate::transform::TxType::*;
 use crate::transform::*;
@@ -81,6 +84,7 @@ pub const TX_SETS: usize = 9;
 pub const TX_SETS_INTRA: usize = 3;
 pub const TX_SETS_INTER: usize = 4;
 pub const TXFM_PARTITION_CONTEXTS: usize = ((TxSize::TX_SIZES - TxSize::TX_8X8 as usize) * 6 - 3);
+pub const MAX_VARTX_DEPTH: usize = 2;
 const MAX_REF_MV_STACK_SIZE: usize = 8;
 pub const REF_CAT_LEVEL: u32 = 640;
 
@@ -727,10 +731,19 @@ pub struct CDFContext {
     [[[[u16; TX_TYPES + 1]; INTRA_MODES]; TX_SIZE_SQR_CONTEXTS]; TX_SETS_INTRA],
   inter_tx_cdf: [[[u16; TX_TYPES + 1]; TX_SIZE_SQR_CONTEXTS]; TX_SETS_INTER],
   tx_size_cdf: [[[u16; MAX_TX_DEPTH + 1 + 1]; TX_SIZE_CONTEXTS]; MAX_TX_CATS],
+  txfm_partition_cdf: [[u16; 3]; TXFM_PARTITION_CONTEXTS],
   skip_cdfs: [[u16; 3]; SKIP_CONTEXTS],
   intra_inter_cdfs: [[u16; 3]; INTRA_INTER_CONTEXTS],
   angle_delta_cdf: [[u16; 2 * MAX_ANGLE_DELTA + 1 + 1]; DIRECTIONAL_MODES],
   filter_intra_cdfs: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  filter_intra_mode_cdf: [u16; FilterIntraMode::FILTER_INTRA_MODES as usize + 1],
+  palette_y_mode_cdf: [[[u16; 3]; PALETTE_Y_MODE_CONTEXTS]; PALATTE_BSIZE_CTXS],
+  palette_uv_mode_cdf: [[u16; 3]; PALETTE_UV_MODE_CONTEXTS],
+  palette_y_size_cdf: [[u16; PaletteSize::PALETTE_SIZES as usize + 1]; PALATTE_BSIZE_CTXS],
+  palette_y_color_index_cdf:
+    [[[u16; PaletteColor::PALETTE_COLORS as usize + 1]; PALETTE_COLOR_INDEX_CONTEXTS];
+      PaletteSize::PALETTE_SIZES as usize],
+  intrabc_cdf: [u16; 3],
   comp_mode_cdf: [[u16; 3]; COMP_INTER_CONTEXTS],
   comp_ref_type_cdf: [[u16; 3]; COMP_REF_TYPE_CONTEXTS],
   comp_ref_cdf: [[[u16; 3]; FWD_REFS - 1]; REF_CONTEXTS],
@@ -738,7 +751,13 @@ pub struct CDFContext {
   single_ref_cdfs: [[[u16; 2 + 1]; SINGLE_REFS - 1]; REF_CONTEXTS],
   drl_cdfs: [[u16; 2 + 1]; DRL_MODE_CONTEXTS],
   compound_mode_cdf: [[u16; INTER_COMPOUND_MODES + 1]; INTER_MODE_CONTEXTS],
+  compound_idx_cdf: [[u16; 3]; COMP_INDEX_CONTEXTS],
+  comp_group_idx_cdf: [[u16; 3]; COMP_GROUP_IDX_CONTEXTS],
+  compound_type_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  wedge_idx_cdf: [[u16; 16 + 1]; BlockSize::BLOCK_SIZES_ALL],
   nmv_context: NMVContext,
+  dv_context: NMVContext,
+  delta_q_cdf: [u16; DELTA_Q_PROBS + 1 + 1],
   deblock_delta_multi_cdf: [[u16; DELTA_LF_PROBS + 1 + 1]; FRAME_LF_COUNT],
   deblock_delta_cdf: [u16; DELTA_LF_PROBS + 1 + 1],
   spatial_segmentation_cdfs: [[u16; 8 + 1]; 3],
@@ -770,12 +789,7 @@ pub struct CDFContext {
 
 impl CDFContext {
     pub fn new(quantizer: u8) -> CDFContext {
-    let qctx = match quantizer {
-      0..=20 => 0,
-      21..=60 => 1,
-      61..=120 => 2,
-      _ => 3
-    };
+    let qctx = CDFContext::q_ctx(quantizer);
     CDFContext {
       partition_cdf: default_partition_cdf,
       kf_y_cdf: default_kf_y_mode_cdf,
@@ -789,10 +803,17 @@ impl CDFContext {
       intra_tx_cdf: default_intra_ext_tx_cdf,
       inter_tx_cdf: default_inter_ext_tx_cdf,
       tx_size_cdf: default_tx_size_cdf,
+      txfm_partition_cdf: default_txfm_partition_cdf,
       skip_cdfs: default_skip_cdfs,
       intra_inter_cdfs: default_intra_inter_cdf,
       angle_delta_cdf: default_angle_delta_cdf,
       filter_intra_cdfs: default_filter_intra_cdfs,
+      filter_intra_mode_cdf: default_filter_intra_mode_cdf,
+      palette_y_mode_cdf: default_palette_y_mode_cdf,
+      palette_uv_mode_cdf: default_palette_uv_mode_cdf,
+      palette_y_size_cdf: default_palette_y_size_cdf,
+      palette_y_color_index_cdf: default_palette_y_color_index_cdf,
+      intrabc_cdf: default_intrabc_cdf,
       comp_mode_cdf: default_comp_mode_cdf,
       comp_ref_type_cdf: default_comp_ref_type_cdf,
       comp_ref_cdf: default_comp_ref_cdf,
@@ -800,7 +821,13 @@ impl CDFContext {
       single_ref_cdfs: default_single_ref_cdf,
       drl_cdfs: default_drl_cdf,
       compound_mode_cdf: default_compound_mode_cdf,
+      compound_idx_cdf: default_compound_idx_cdfs,
+      comp_group_idx_cdf: default_comp_group_idx_cdfs,
+      compound_type_cdf: default_compound_type_cdf,
+      wedge_idx_cdf: default_wedge_idx_cdf,
       nmv_context: default_nmv_context,
+      dv_context: default_nmv_context,
+      delta_q_cdf: default_delta_q_cdf,
       deblock_delta_multi_cdf: default_delta_lf_multi_cdf,
       deblock_delta_cdf: default_delta_lf_cdf,
       spatial_segmentation_cdfs: default_spatial_pred_seg_tree_cdf,
@@ -827,6 +854,40 @@ impl CDFContext {
     }
   }
 
+  /// Index of the set of default coefficient CDFs used at `quantizer`
+  pub fn q_ctx(quantizer: u8) -> usize {
+    match quantizer {
+      0..=20 => 0,
+      21..=60 => 1,
+      61..=120 => 2,
+      _ => 3
+    }
+  }
+
+  /// Codes the loop filter level deltas of a superblock, relative to those
+  /// of the previous superblock in the tile
+  pub fn write_deblock_deltas(&mut self, w: &mut dyn Writer, deltas: &[i8], multi: bool) {
+    for (i, &delta) in deltas.iter().enumerate() {
+      let abs:u32 = delta.abs() as u32;
+
+      if multi {
+        w.symbol_with_update(cmp::min(abs, DELTA_LF_SMALL),
+                   &mut self.deblock_delta_multi_cdf[i]);
+      } else {
+        w.symbol_with_update(cmp::min(abs, DELTA_LF_SMALL),
+                   &mut self.deblock_delta_cdf);
+      };
+      if abs >= DELTA_LF_SMALL {
+        let bits = msb(abs as i32 - 1) as u32;
+        w.literal(3, bits - 1);
+        w.literal(bits as u8, abs - (1<<bits) - 1);
+      }
+      if abs > 0 {
+        w.bool(delta < 0, 16384);
+      }
+    }
+  }
+
   pub fn reset_counts(&mut self) {
     macro_rules! reset_1d {
       ($field:expr) => (let r = $field.last_mut().unwrap(); *r = 0;)
@@ -872,11 +933,23 @@ impl CDFContext {
     reset_2d!(self.tx_size_cdf[1]);
     reset_2d!(self.tx_size_cdf[2]);
     reset_2d!(self.tx_size_cdf[3]);
+    reset_2d!(self.txfm_partition_cdf);
 
     reset_2d!(self.skip_cdfs);
     reset_2d!(self.intra_inter_cdfs);
     reset_2d!(self.angle_delta_cdf);
     reset_2d!(self.filter_intra_cdfs);
+    reset_1d!(self.filter_intra_mode_cdf);
+    reset_3d!(self.palette_y_mode_cdf);
+    reset_2d!(self.palette_uv_mode_cdf);
+    reset_2d!(self.palette_y_size_cdf);
+    // The color index CDFs of the smaller palettes are padded
+    for (i, cdfs) in self.palette_y_color_index_cdf.iter_mut().enumerate() {
+      for cdf in cdfs.iter_mut() {
+        cdf[i + PALETTE_MIN_SIZE] = 0;
+      }
+    }
+    reset_1d!(self.intrabc_cdf);
     reset_2d!(self.comp_mode_cdf);
     reset_2d!(self.comp_ref_type_cdf);
     reset_3d!(self.comp_ref_cdf);
@@ -884,6 +957,11 @@ impl CDFContext {
     reset_3d!(self.single_ref_cdfs);
     reset_2d!(self.drl_cdfs);
     reset_2d!(self.compound_mode_cdf);
+    reset_2d!(self.compound_idx_cdf);
+    reset_2d!(self.comp_group_idx_cdf);
+    reset_2d!(self.compound_type_cdf);
+    reset_2d!(self.wedge_idx_cdf);
+    reset_1d!(self.delta_q_cdf);
     reset_2d!(self.deblock_delta_multi_cdf);
     reset_1d!(self.deblock_delta_cdf);
     reset_2d!(self.spatial_segmentation_cdfs);
@@ -891,16 +969,18 @@ impl CDFContext {
     reset_1d!(self.lrf_sgrproj_cdf);
     reset_1d!(self.lrf_wiener_cdf);
 
-    reset_1d!(self.nmv_context.joints_cdf);
-    for i in 0..2 {
-      reset_1d!(self.nmv_context.comps[i].classes_cdf);
-      reset_2d!(self.nmv_context.comps[i].class0_fp_cdf);
-      reset_1d!(self.nmv_context.comps[i].fp_cdf);
-      reset_1d!(self.nmv_context.comps[i].sign_cdf);
-      reset_1d!(self.nmv_context.comps[i].class0_hp_cdf);
-      reset_1d!(self.nmv_context.comps[i].hp_cdf);
-      reset_1d!(self.nmv_context.comps[i].class0_cdf);
-      reset_2d!(self.nmv_context.comps[i].bits_cdf);
+    for nmv_context in [&mut self.nmv_context, &mut self.dv_context].iter_mut() {
+      reset_1d!(nmv_context.joints_cdf);
+      for i in 0..2 {
+        reset_1d!(nmv_context.comps[i].classes_cdf);
+        reset_2d!(nmv_context.comps[i].class0_fp_cdf);
+        reset_1d!(nmv_context.comps[i].fp_cdf);
+        reset_1d!(nmv_context.comps[i].sign_cdf);
+        reset_1d!(nmv_context.comps[i].class0_hp_cdf);
+        reset_1d!(nmv_context.comps[i].hp_cdf);
+        reset_1d!(nmv_context.comps[i].class0_cdf);
+        reset_2d!(nmv_context.comps[i].bits_cdf);
+      }
     }
 
     // lv_map
@@ -963,6 +1043,8 @@ impl CDFContext {
       self.filter_intra_cdfs.first().unwrap().as_ptr() as usize;
     let filter_intra_cdfs_end =
       filter_intra_cdfs_start + size_of_val(&self.filter_intra_cdfs);
+    let intrabc_cdf_start = self.intrabc_cdf.as_ptr() as usize;
+    let intrabc_cdf_end = intrabc_cdf_start + size_of_val(&self.intrabc_cdf);
     let comp_mode_cdf_start =
       self.comp_mode_cdf.first().unwrap().as_ptr() as usize;
     let comp_mode_cdf_end =
@@ -979,6 +1061,10 @@ impl CDFContext {
       self.comp_bwd_ref_cdf.first().unwrap().as_ptr() as usize;
     let comp_bwd_ref_cdf_end =
       comp_bwd_ref_cdf_start + size_of_val(&self.comp_bwd_ref_cdf);
+    let delta_q_cdf_start =
+      self.delta_q_cdf.as_ptr() as usize;
+    let delta_q_cdf_end =
+      delta_q_cdf_start + size_of_val(&self.delta_q_cdf);
     let deblock_delta_multi_cdf_start =
       self.deblock_delta_multi_cdf.first().unwrap().as_ptr() as usize;
     let deblock_delta_multi_cdf_end =
@@ -1069,10 +1155,12 @@ impl CDFContext {
       ("intra_inter_cdfs", intra_inter_cdfs_start, intra_inter_cdfs_end),
       ("angle_delta_cdf", angle_delta_cdf_start, angle_delta_cdf_end),
       ("filter_intra_cdfs", filter_intra_cdfs_start, filter_intra_cdfs_end),
+      ("intrabc_cdf", intrabc_cdf_start, intrabc_cdf_end),
       ("comp_mode_cdf", comp_mode_cdf_start, comp_mode_cdf_end),
       ("comp_ref_type_cdf", comp_ref_type_cdf_start, comp_ref_type_cdf_end),
       ("comp_ref_cdf", comp_ref_cdf_start, comp_ref_cdf_end),
       ("comp_bwd_ref_cdf", comp_bwd_ref_cdf_start, comp_bwd_ref_cdf_end),
+      ("delta_q_cdf", delta_q_cdf_start, delta_q_cdf_end),
       ("deblock_delta_multi_cdf", deblock_delta_multi_cdf_start, deblock_delta_multi_cdf_end),
       ("deblock_delta_cdf", deblock_delta_cdf_start, deblock_delta_cdf_end),
       ("spatial_segmentation_cdfs", spatial_segmentation_cdfs_start, spatial_segmentation_cdfs_end),
@@ -1102,6 +1190,46 @@ impl fmt::Debug for CDFContext {
   }
 }
 
+// The CDFs are (de)serialized as the flat array of u16 they are laid out as,
+// serde only handling arrays of up to 32 elements.
+#[cfg(feature = "serialize")]
+impl serde::Serialize for CDFContext {
+  fn serialize<S: serde::Serializer>(
+    &self, serializer: S
+  ) -> Result<S::Ok, S::Error> {
+    let cdfs = unsafe {
+      slice::from_raw_parts(
+        self as *const CDFContext as *const u16,
+        mem::size_of::<CDFContext>() / 2
+      )
+    };
+    serializer.collect_seq(cdfs.iter())
+  }
+}
+
+#[cfg(feature = "serialize")]
+impl<'de> serde::Deserialize<'de> for CDFContext {
+  fn deserialize<D: serde::Deserializer<'de>>(
+    deserializer: D
+  ) -> Result<Self, D::Error> {
+    use serde::de::Error;
+
+    let data = <Vec<u16> as serde::Deserialize>::deserialize(deserializer)?;
+    let mut fc = CDFContext::new(0);
+    let cdfs = unsafe {
+      slice::from_raw_parts_mut(
+        &mut fc as *mut CDFContext as *mut u16,
+        mem::size_of::<CDFContext>() / 2
+      )
+    };
+    if data.len() != cdfs.len() {
+      return Err(D::Error::invalid_length(data.len(), &"a CDFContext"));
+    }
+    cdfs.copy_from_slice(&data);
+    Ok(fc)
+  }
+}
+
 #[cfg(test)]
 mod test {
   #[test]
@@ -1150,6 +1278,79 @@ mod test {
       }
     }
   }
+
+  #[test]
+  fn mv_rates_match_coded_bits() {
+    use super::*;
+    use crate::ec::WriterCounter;
+
+    let precision = MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION;
+    let fc = CDFContext::new(8);
+    let rates = MvRates::new(&fc, precision);
+    for &comp in [1, -3, 17, -130, 1000, -4096].iter() {
+      let mut mvcomp = fc.nmv_context.comps[0];
+      let mut w = WriterCounter::new();
+      let tell = w.tell_frac();
+      encode_mv_component(&mut w, comp, &mut mvcomp, precision);
+      let bits = (w.tell_frac() - tell) as i32;
+      let rate = rates.comps[0].rate(comp, precision) as i32;
+      // Allow for the rounding of the range coder
+      assert!((bits - rate).abs() <= 2 << OD_BITRES, "{}: {} vs {}", comp, bits, rate);
+    }
+  }
+
+  #[test]
+  fn coeff_rates_match_coded_bits() {
+    use super::*;
+    use crate::ec::WriterCounter;
+    use crate::transform::TxSize::*;
+
+    fn coded_bits(
+      tx_size: TxSize, coeffs: &[i32], rates: Option<&CoeffRates>
+    ) -> i32 {
+      let mut fc = CDFContext::new(8);
+      let mut blocks = FrameBlocks::new(16, 16);
+      let mut tile_blocks = blocks.as_tile_blocks_mut();
+      let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tile_blocks));
+      let mut w = WriterCounter::new();
+      let tell = w.tell_frac();
+      let bo = BlockOffset { x: 0, y: 0 };
+      let bsize = tx_size.block_size();
+      let mode = PredictionMode::DC_PRED;
+      match rates {
+        Some(rates) => cw.count_coeffs_lv_map(
+          &mut w, rates, 0, bo, coeffs, mode, tx_size, TxType::DCT_DCT, bsize, 0, 0, false
+        ),
+        None => cw.write_coeffs_lv_map(
+          &mut w, 0, bo, coeffs, mode, tx_size, TxType::DCT_DCT, bsize, 0, 0, false
+        ),
+      };
+      (w.tell_frac() - tell) as i32
+    }
+
+    let rates = CoeffRates::new(&CDFContext::new(8));
+    for &tx_size in &[
+      TX_4X4, TX_8X8, TX_16X16, TX_32X32, TX_64X64, TX_4X8, TX_8X4, TX_8X16,
+      TX_16X8, TX_16X32, TX_32X16, TX_32X64, TX_64X32, TX_4X16, TX_16X4,
+      TX_8X32, TX_32X8, TX_16X64, TX_64X16,
+    ] {
+      let coded = av1_get_coded_tx_size(tx_size);
+      let scan = av1_scan_orders[tx_size as usize][TxType::DCT_DCT as usize].scan;
+      let mut coeffs = vec![0; coded.width() * coded.height()];
+      // A Golomb coded DC, and levels in the base and base range symbols
+      coeffs[scan[0] as usize] = 20;
+      coeffs[scan[1] as usize] = -1;
+      coeffs[scan[3] as usize] = 4;
+      coeffs[scan[6] as usize] = 2;
+      let bits = coded_bits(tx_size, &coeffs, None);
+      let rate = coded_bits(tx_size, &coeffs, Some(&rates));
+      // The CDFs adapt to the symbols written, the rates do not
+      assert!(
+        (bits - rate).abs() <= (2 << OD_BITRES) + bits / 10,
+        "{:?}: {} vs {}", tx_size, bits, rate
+      );
+    }
+  }
 }
 
 const SUPERBLOCK_TO_PLANE_SHIFT: usize = MAX_SB_SIZE_LOG2;
@@ -1241,6 +1442,7 @@ pub struct Block {
   pub skip: bool,
   pub ref_frames: [RefType; 2],
   pub mv: [MotionVector; 2],
+  pub compound: CompoundParams,
   // note: indexes are reflist index, NOT the same as libaom
   pub neighbors_ref_counts: [usize; INTER_REFS_PER_FRAME],
   pub cdef_index: u8,
@@ -1248,11 +1450,15 @@ pub struct Block {
   pub n4_w: usize, /* block width in the unit of mode_info */
   pub n4_h: usize, /* block height in the unit of mode_info */
   pub txsize: TxSize,
+  /// Transform type of the luma transform blocks
+  pub txtype: TxType,
   // The block-level deblock_deltas are left-shifted by
   // fi.deblock.block_delta_shift and added to the frame-configured
   // deltas
   pub deblock_deltas: [i8; FRAME_LF_COUNT],
-  pub segmentation_idx: u8
+  pub segmentation_idx: u8,
+  /// Luma palette, empty unless the block is palette coded
+  pub palette: Palette
 }
 
 impl Block {
@@ -1263,14 +1469,17 @@ impl Block {
       skip: false,
       ref_frames: [INTRA_FRAME; 2],
       mv: [ MotionVector::default(); 2],
+      compound: CompoundParams::default(),
       neighbors_ref_counts: [0; INTER_REFS_PER_FRAME],
       cdef_index: 0,
       bsize: BLOCK_64X64,
       n4_w: BLOCK_64X64.width_mi(),
       n4_h: BLOCK_64X64.height_mi(),
       txsize: TX_64X64,
+      txtype: DCT_DCT,
       deblock_deltas: [0, 0, 0, 0],
       segmentation_idx: 0,
+      palette: Palette::default(),
     }
   }
   pub fn is_inter(&self) -> bool {
@@ -1351,14 +1560,22 @@ const PARTITION_CONTEXT_GRANULARITY: usize = 8;
 const PARTITION_CONTEXT_MAX_WIDTH: usize = MAX_TILE_WIDTH / PARTITION_CONTEXT_GRANULARITY;
 
 const COEFF_CONTEXT_MAX_WIDTH: usize = MAX_TILE_WIDTH / MI_SIZE;
+const TX_CONTEXT_MAX_WIDTH: usize = MAX_TILE_WIDTH / MI_SIZE;
+
+/// Transform size context past the tile edges, the width of the largest
+/// transform
+const TX_CONTEXT_UNAVAILABLE: u8 = 64;
 
 #[derive(Clone)]
 pub struct BlockContextCheckpoint {
   cdef_coded: bool,
+  code_deltas: bool,
+  deltas_pos: Option<(bool, usize)>,
+  current_qindex: u8,
   above_partition_context: [u8; PARTITION_CONTEXT_MAX_WIDTH],
   // left context is also at 8x8 granularity
   left_partition_context: [u8; MAX_MIB_SIZE >> 1],
-  above_tx_context: [u8; PARTITION_CONTEXT_MAX_WIDTH],
+  above_tx_context: [u8; TX_CONTEXT_MAX_WIDTH],
   left_tx_context: [u8; MAX_MIB_SIZE],
   above_coeff_context: [[u8; COEFF_CONTEXT_MAX_WIDTH]; PLANES],
   left_coeff_context: [[u8; MAX_MIB_SIZE]; PLANES],
@@ -1367,11 +1584,17 @@ pub struct BlockContextCheckpoint {
 pub struct BlockContext<'a> {
   pub cdef_coded: bool,
   pub code_deltas: bool,
+  /// Where the superblock's loop filter deltas go, as the token position
+  /// in the pre- (false) or post-CDEF (true) recording
+  pub deltas_pos: Option<(bool, usize)>,
+  /// Quantizer index of the last coded delta q, the base one at the start
+  /// of a tile
+  pub current_qindex: u8,
   pub update_seg: bool,
   pub preskip_segid: bool,
   above_partition_context: [u8; PARTITION_CONTEXT_MAX_WIDTH],
   left_partition_context: [u8; MAX_MIB_SIZE >> 1],
-  above_tx_context: [u8; PARTITION_CONTEXT_MAX_WIDTH],
+  above_tx_context: [u8; TX_CONTEXT_MAX_WIDTH],
   left_tx_context: [u8; MAX_MIB_SIZE],
   above_coeff_context: [[u8; COEFF_CONTEXT_MAX_WIDTH]; PLANES],
   left_coeff_context: [[u8; MAX_MIB_SIZE]; PLANES],
@@ -1383,12 +1606,14 @@ impl<'a> BlockContext<'a> {
     BlockContext {
       cdef_coded: false,
       code_deltas: false,
+      deltas_pos: None,
+      current_qindex: 0,
       update_seg: false,
       preskip_segid: true,
       above_partition_context: [0; PARTITION_CONTEXT_MAX_WIDTH],
       left_partition_context: [0; MAX_MIB_SIZE >> 1],
-      above_tx_context: [0; PARTITION_CONTEXT_MAX_WIDTH],
-      left_tx_context: [0; MAX_MIB_SIZE],
+      above_tx_context: [TX_CONTEXT_UNAVAILABLE; TX_CONTEXT_MAX_WIDTH],
+      left_tx_context: [TX_CONTEXT_UNAVAILABLE; MAX_MIB_SIZE],
       above_coeff_context: [
         [0; COEFF_CONTEXT_MAX_WIDTH],
         [0; COEFF_CONTEXT_MAX_WIDTH],
@@ -1402,6 +1627,9 @@ impl<'a> BlockContext<'a> {
   pub fn checkpoint(&mut self) -> BlockContextCheckpoint {
     BlockContextCheckpoint {
       cdef_coded: self.cdef_coded,
+      code_deltas: self.code_deltas,
+      deltas_pos: self.deltas_pos,
+      current_qindex: self.current_qindex,
       above_partition_context: self.above_partition_context,
       left_partition_context: self.left_partition_context,
       above_tx_context: self.above_tx_context,
@@ -1413,6 +1641,9 @@ impl<'a> BlockContext<'a> {
 
   pub fn rollback(&mut self, checkpoint: &BlockContextCheckpoint) {
     self.cdef_coded = checkpoint.cdef_coded;
+    self.code_deltas = checkpoint.code_deltas;
+    self.deltas_pos = checkpoint.deltas_pos;
+    self.current_qindex = checkpoint.current_qindex;
     self.above_partition_context = checkpoint.above_partition_context;
     self.left_partition_context = checkpoint.left_partition_context;
     self.above_tx_context = checkpoint.above_tx_context;
@@ -1461,7 +1692,7 @@ impl<'a> BlockContext<'a> {
     let n4_h = bsize.height_mi();
 
     let (tx_w, tx_h) = if skip {
-      (n4_w as u8, n4_h as u8)
+      ((n4_w << MI_SIZE_LOG2) as u8, (n4_h << MI_SIZE_LOG2) as u8)
     } else {
       (tx_size.width() as u8, tx_size.height() as u8)
     };
@@ -1482,7 +1713,7 @@ impl<'a> BlockContext<'a> {
 
   fn reset_left_tx_context(&mut self) {
     for c in &mut self.left_tx_context {
-      *c = 0;
+      *c = TX_CONTEXT_UNAVAILABLE;
     }
   }
 
@@ -1554,9 +1785,6 @@ impl<'a> BlockContext<'a> {
   pub fn update_partition_context(
     &mut self, bo: BlockOffset, subsize: BlockSize, bsize: BlockSize
   ) {
-    #[allow(dead_code)]
-    assert!(bsize.is_sqr());
-
     let bw = bsize.width_mi();
     let bh = bsize.height_mi();
 
@@ -1801,6 +2029,11 @@ macro_rules! symbol_with_update {
   };
 }
 
+/// Smallest number of bits to code values below `n`
+fn ceil_log2(n: usize) -> usize {
+  if n < 2 { 0 } else { msb(n as i32 - 1) as usize + 1 }
+}
+
 pub fn av1_get_coded_tx_size(tx_size: TxSize) -> TxSize {
   match tx_size {
     TX_64X64 | TX_64X32 | TX_32X64 => TX_32X32,
@@ -2035,13 +2268,75 @@ impl<'a> ContextWriter<'a> {
         &mut self.fc.tx_size_cdf[tx_size_cat][tx_size_ctx][..=max_depths+1]);
   }
 
+  fn txfm_partition_context(
+    &self, bo: BlockOffset, bsize: BlockSize, tx_size: TxSize
+  ) -> usize {
+    if tx_size == TX_4X4 {
+      return 0;
+    }
+    let above = (self.bc.above_tx_context[bo.x] as usize) < tx_size.width();
+    let left = (self.bc.left_tx_context[bo.y_in_sb()] as usize) < tx_size.height();
+    let max_tx_size = max_txsize_rect_lookup[bsize as usize].sqr_up();
+    let category = (tx_size.sqr_up() != max_tx_size && max_tx_size > TX_8X8) as usize
+      + (TxSize::TX_SIZES - 1 - max_tx_size as usize) * 2;
+    category * 3 + above as usize + left as usize
+  }
+
+  /// Write the transform partitioning of an inter block, which is split
+  /// uniformly down to `tx_size`.
+  pub fn write_tx_size_inter(
+    &mut self, w: &mut dyn Writer, bo: BlockOffset, bsize: BlockSize,
+    tx_size: TxSize
+  ) {
+    debug_assert!(self.bc.blocks[bo].is_inter());
+    debug_assert!(bsize.greater_than(BlockSize::BLOCK_4X4));
+
+    let max_tx_size = max_txsize_rect_lookup[bsize as usize];
+    for by in (0..bsize.height_mi()).step_by(max_tx_size.height_mi()) {
+      for bx in (0..bsize.width_mi()).step_by(max_tx_size.width_mi()) {
+        let tx_bo = BlockOffset { x: bo.x + bx, y: bo.y + by };
+        self.write_var_tx_size(w, tx_bo, bsize, max_tx_size, tx_size, 0);
+      }
+    }
+  }
+
+  fn write_var_tx_size(
+    &mut self, w: &mut dyn Writer, bo: BlockOffset, bsize: BlockSize,
+    cur_tx_size: TxSize, tx_size: TxSize, depth: usize
+  ) {
+    if bo.x >= self.bc.blocks.cols() || bo.y >= self.bc.blocks.rows() {
+      return;
+    }
+
+    let split = cur_tx_size != tx_size;
+    if cur_tx_size == TX_4X4 || depth == MAX_VARTX_DEPTH {
+      debug_assert!(!split);
+    } else {
+      let ctx = self.txfm_partition_context(bo, bsize, cur_tx_size);
+      symbol_with_update!(self, w, split as u32, &mut self.fc.txfm_partition_cdf[ctx]);
+    }
+
+    if split {
+      let sub_tx_size = sub_tx_size_map[cur_tx_size as usize];
+      for by in (0..cur_tx_size.height_mi()).step_by(sub_tx_size.height_mi()) {
+        for bx in (0..cur_tx_size.width_mi()).step_by(sub_tx_size.width_mi()) {
+          let sub_bo = BlockOffset { x: bo.x + bx, y: bo.y + by };
+          self.write_var_tx_size(w, sub_bo, bsize, sub_tx_size, tx_size, depth + 1);
+        }
+      }
+    } else {
+      self.bc.update_tx_size_context(bo, cur_tx_size.block_size(), cur_tx_size, false);
+    }
+  }
+
   pub fn get_cdf_intra_mode_kf(&self, bo: BlockOffset) -> &[u16; INTRA_MODES + 1] {
     static intra_mode_context: [usize; INTRA_MODES] =
       [0, 1, 2, 3, 4, 4, 4, 4, 3, 0, 1, 2, 0];
     let above_mode = if bo.y > 0 { self.bc.blocks.above_of(bo).mode } else { PredictionMode::DC_PRED };
     let left_mode = if bo.x > 0 { self.bc.blocks.left_of(bo).mode } else { PredictionMode::DC_PRED };
-    let above_ctx = intra_mode_context[above_mode as usize];
-    let left_ctx = intra_mode_context[left_mode as usize];
+    // IntraBC blocks count as DC_PRED
+    let above_ctx = if above_mode.is_intra() { intra_mode_context[above_mode as usize] } else { 0 };
+    let left_ctx = if left_mode.is_intra() { intra_mode_context[left_mode as usize] } else { 0 };
     &self.fc.kf_y_cdf[above_ctx][left_ctx]
   }
   pub fn write_intra_mode_kf(
@@ -2051,8 +2346,9 @@ impl<'a> ContextWriter<'a> {
       [0, 1, 2, 3, 4, 4, 4, 4, 3, 0, 1, 2, 0];
     let above_mode = if bo.y > 0 { self.bc.blocks.above_of(bo).mode } else { PredictionMode::DC_PRED };
     let left_mode = if bo.x > 0 { self.bc.blocks.left_of(bo).mode } else { PredictionMode::DC_PRED };
-    let above_ctx = intra_mode_context[above_mode as usize];
-    let left_ctx = intra_mode_context[left_mode as usize];
+    // IntraBC blocks count as DC_PRED
+    let above_ctx = if above_mode.is_intra() { intra_mode_context[above_mode as usize] } else { 0 };
+    let left_ctx = if left_mode.is_intra() { intra_mode_context[left_mode as usize] } else { 0 };
     let cdf = &mut self.fc.kf_y_cdf[above_ctx][left_ctx];
     symbol_with_update!(self, w, mode as u32, cdf);
   }
@@ -2083,6 +2379,15 @@ impl<'a> ContextWriter<'a> {
       }
     }
   }
+  // Like count_lrf_switchable, counts Q8 bits using the current cdfs
+  pub fn count_cfl_signs(&self, w: &dyn Writer, cfl: CFLParams) -> u32 {
+    let nsym = self.fc.cfl_sign_cdf.len() - 1;
+    w.symbol_bits(cfl.joint_sign(), &self.fc.cfl_sign_cdf[..nsym])
+  }
+  pub fn count_cfl_alpha(&self, w: &dyn Writer, cfl: CFLParams, uv: usize) -> u32 {
+    let cdf = &self.fc.cfl_alpha_cdf[cfl.context(uv)];
+    w.symbol_bits(cfl.index(uv), &cdf[..cdf.len() - 1])
+  }
   pub fn write_angle_delta(&mut self, w: &mut dyn Writer, angle: i8, mode: PredictionMode) {
     symbol_with_update!(
       self,
@@ -2092,10 +2397,102 @@ impl<'a> ContextWriter<'a> {
         [mode as usize - PredictionMode::V_PRED as usize]
     );
   }
+  /// Codes whether a DC_PRED block uses a luma palette and, if so, the
+  /// palette colors: first whether each color of the palettes of the
+  /// neighbors is reused, then the other ones as ascending deltas.
+  pub fn write_palette_y(
+    &mut self, w: &mut dyn Writer, bo: BlockOffset, bsize: BlockSize,
+    palette: Option<Palette>, bit_depth: usize
+  ) {
+    let bsize_ctx = bsize.width_log2() + bsize.height_log2() - 6;
+    let ctx = (bo.y > 0 && !self.bc.blocks.above_of(bo).palette.is_empty()) as usize
+      + (bo.x > 0 && !self.bc.blocks.left_of(bo).palette.is_empty()) as usize;
+    symbol_with_update!(self, w, palette.is_some() as u32,
+                        &mut self.fc.palette_y_mode_cdf[bsize_ctx][ctx]);
+    let palette = match palette {
+      Some(palette) => palette,
+      None => return
+    };
+    let colors = palette.colors();
+    let n = colors.len();
+    symbol_with_update!(self, w, (n - PALETTE_MIN_SIZE) as u32,
+                        &mut self.fc.palette_y_size_cdf[bsize_ctx]);
+
+    // The above palette is not kept across 64-pixel rows
+    let above = if bo.y > 0 && (bo.y << MI_SIZE_LOG2) % 64 != 0 {
+      self.bc.blocks.above_of(bo).palette
+    } else {
+      Palette::default()
+    };
+    let left = if bo.x > 0 { self.bc.blocks.left_of(bo).palette } else { Palette::default() };
+    let mut in_cache = [false; PALETTE_MAX_SIZE];
+    let mut found = 0;
+    for &c in palette_cache(above.colors(), left.colors()).iter() {
+      if found == n {
+        break;
+      }
+      let hit = colors.iter().position(|&v| v == c);
+      w.bit(hit.is_some() as u16);
+      if let Some(i) = hit {
+        in_cache[i] = true;
+        found += 1;
+      }
+    }
+
+    let rest: ArrayVec<[u16; PALETTE_MAX_SIZE]> = colors.iter().zip(in_cache.iter())
+      .filter(|&(_, &cached)| !cached).map(|(&c, _)| c).collect();
+    if let Some(&first) = rest.first() {
+      w.literal(bit_depth as u8, first as u32);
+      if rest.len() > 1 {
+        let min_bits = bit_depth - 3;
+        let max_delta = rest.windows(2).map(|c| c[1] - c[0]).max().unwrap();
+        let mut bits = ceil_log2(max_delta as usize).max(min_bits);
+        w.literal(2, (bits - min_bits) as u32);
+        let mut range = (1 << bit_depth) - first as usize - 1;
+        for c in rest.windows(2) {
+          let delta = (c[1] - c[0]) as usize;
+          w.literal(bits as u8, delta as u32 - 1);
+          range -= delta;
+          bits = bits.min(ceil_log2(range));
+        }
+      }
+    }
+  }
+
+  /// Chroma palettes are never used, but are signaled for DC_PRED blocks
+  pub fn write_use_palette_uv(&mut self, w: &mut dyn Writer, has_palette_y: bool) {
+    symbol_with_update!(self, w, 0, &mut self.fc.palette_uv_mode_cdf[has_palette_y as usize]);
+  }
+
+  /// Codes the color indices of the `width`x`height` pixels of a palette
+  /// block within the frame, along anti-diagonals
+  pub fn write_palette_color_map(
+    &mut self, w: &mut dyn Writer, palette: Palette, map: &[u8], width: usize, height: usize
+  ) {
+    let n = palette.len();
+    w.write_quniform(n as u32, map[0] as u32);
+    for i in 1..width + height - 1 {
+      for c in (i.saturating_sub(height - 1)..=i.min(width - 1)).rev() {
+        let r = i - c;
+        let (ctx, order) = color_context(map, width, r, c, n);
+        let index = order.iter().position(|&o| o == map[r * width + c]).unwrap();
+        symbol_with_update!(self, w, index as u32,
+                            &mut self.fc.palette_y_color_index_cdf[n - PALETTE_MIN_SIZE][ctx][..=n]);
+      }
+    }
+  }
+
   pub fn write_use_filter_intra(&mut self, w: &mut dyn Writer, enable: bool, block_size: BlockSize) {
     symbol_with_update!(self, w, enable as u32, &mut self.fc.filter_intra_cdfs[block_size as usize]);
   }
 
+  pub fn write_filter_intra_mode(&mut self, w: &mut dyn Writer, mode: FilterIntraMode) {
+    symbol_with_update!(self, w, mode as u32, &mut self.fc.filter_intra_mode_cdf);
+  }
+  pub fn write_use_intrabc(&mut self, w: &mut dyn Writer, enable: bool) {
+    symbol_with_update!(self, w, enable as u32, &mut self.fc.intrabc_cdf);
+  }
+
   fn find_valid_row_offs(&mut self, row_offset: isize, mi_row: usize, mi_rows: usize) -> isize {
     cmp::min(cmp::max(row_offset, -(mi_row as isize)), (mi_rows - mi_row - 1) as isize)
   }
@@ -2438,7 +2835,7 @@ impl<'a> ContextWriter<'a> {
                                            &mut newmv_count, bsize, is_compound);
       col_match |= found_match;
     }
-    if has_tr(bo, bsize) && bo.y > 0 {
+    if has_tr(bo, bsize, self.bc.blocks[bo].partition) && bo.y > 0 {
       let found_match = self.scan_blk_mbmi(bo.with_offset(target_n4_w as isize, -1), ref_frames, mv_stack,
                                            &mut newmv_count, is_compound);
       row_match |= found_match;
@@ -2615,7 +3012,7 @@ impl<'a> ContextWriter<'a> {
 
     if ref_frames[0] != INTRA_FRAME {
       /* TODO: Set zeromv ref to the converted global motion vector */
-    } else {
+    } else if !fi.allow_intrabc {
       /* TODO: Set the zeromv ref to 0 */
       return 0;
     }
@@ -2928,6 +3325,78 @@ impl<'a> ContextWriter<'a> {
     symbol_with_update!(self, w, val, &mut self.fc.compound_mode_cdf[ctx]);
   }
 
+  fn get_comp_group_idx_ctx(&self, bo: BlockOffset) -> usize {
+    let neighbor_ctx = |block: &Block| {
+      if block.has_second_ref() {
+        block.compound.is_masked() as usize
+      } else if block.ref_frames[0] == ALTREF_FRAME {
+        3
+      } else {
+        0
+      }
+    };
+    let above_ctx =
+      if bo.y > 0 { neighbor_ctx(&self.bc.blocks[bo.with_offset(0, -1)]) } else { 0 };
+    let left_ctx =
+      if bo.x > 0 { neighbor_ctx(&self.bc.blocks[bo.with_offset(-1, 0)]) } else { 0 };
+    (above_ctx + left_ctx).min(COMP_GROUP_IDX_CONTEXTS - 1)
+  }
+
+  pub fn write_comp_group_idx(
+    &mut self, w: &mut dyn Writer, bo: BlockOffset, comp_group_idx: bool
+  ) {
+    let ctx = self.get_comp_group_idx_ctx(bo);
+    symbol_with_update!(self, w, comp_group_idx as u32, &mut self.fc.comp_group_idx_cdf[ctx]);
+  }
+
+  fn get_compound_idx_ctx(&self, bo: BlockOffset, equal_dists: bool) -> usize {
+    let neighbor_ctx = |block: &Block| {
+      if block.has_second_ref() {
+        block.compound.compound_idx() as usize
+      } else if block.ref_frames[0] == ALTREF_FRAME {
+        1
+      } else {
+        0
+      }
+    };
+    let above_ctx =
+      if bo.y > 0 { neighbor_ctx(&self.bc.blocks[bo.with_offset(0, -1)]) } else { 0 };
+    let left_ctx =
+      if bo.x > 0 { neighbor_ctx(&self.bc.blocks[bo.with_offset(-1, 0)]) } else { 0 };
+    (if equal_dists { 3 } else { 0 }) + above_ctx + left_ctx
+  }
+
+  /// `equal_dists` tells whether both references are as far from the
+  /// current frame
+  pub fn write_compound_idx(
+    &mut self, w: &mut dyn Writer, bo: BlockOffset, equal_dists: bool,
+    compound_idx: bool
+  ) {
+    let ctx = self.get_compound_idx_ctx(bo, equal_dists);
+    symbol_with_update!(self, w, compound_idx as u32, &mut self.fc.compound_idx_cdf[ctx]);
+  }
+
+  pub fn write_compound_type(
+    &mut self, w: &mut dyn Writer, bsize: BlockSize, compound: CompoundParams
+  ) {
+    assert!(compound.is_masked());
+
+    // Blocks without wedges can only use the difference-weighted mask
+    if is_wedge_allowed(bsize) {
+      let val = compound.comp_type as u32 - CompoundType::COMPOUND_WEDGE as u32;
+      symbol_with_update!(self, w, val, &mut self.fc.compound_type_cdf[bsize as usize]);
+    } else {
+      assert!(compound.comp_type == CompoundType::COMPOUND_DIFFWTD);
+    }
+
+    if compound.comp_type == CompoundType::COMPOUND_WEDGE {
+      symbol_with_update!(self, w, compound.wedge_index as u32, &mut self.fc.wedge_idx_cdf[bsize as usize]);
+      w.bit(compound.wedge_sign as u16);
+    } else {
+      w.bit(compound.mask_type as u16);
+    }
+  }
+
   pub fn write_inter_mode(&mut self, w: &mut dyn Writer, mode: PredictionMode, ctx: usize) {
     let newmv_ctx = ctx & NEWMV_CTX_MASK;
     symbol_with_update!(self, w, (mode != PredictionMode::NEWMV) as u32, &mut self.fc.newmv_cdf[newmv_ctx]);
@@ -2961,6 +3430,23 @@ impl<'a> ContextWriter<'a> {
     }
   }
 
+  /// Writes an IntraBC displacement vector, which has its own CDFs and is
+  /// always a whole number of pixels.
+  pub fn write_dv(&mut self, w: &mut dyn Writer,
+                  dv: MotionVector, ref_dv: MotionVector) {
+    let diff = MotionVector { row: dv.row - ref_dv.row, col: dv.col - ref_dv.col };
+    let j: MvJointType = av1_get_mv_joint(diff);
+
+    w.symbol_with_update(j as u32, &mut self.fc.dv_context.joints_cdf);
+
+    if mv_joint_vertical(j) {
+      encode_mv_component(w, diff.row as i32, &mut self.fc.dv_context.comps[0], MvSubpelPrecision::MV_SUBPEL_NONE);
+    }
+    if mv_joint_horizontal(j) {
+      encode_mv_component(w, diff.col as i32, &mut self.fc.dv_context.comps[1], MvSubpelPrecision::MV_SUBPEL_NONE);
+    }
+  }
+
   pub fn write_tx_type(
     &mut self, w: &mut dyn Writer, tx_size: TxSize, tx_type: TxType, y_mode: PredictionMode,
     is_inter: bool, use_reduced_tx_set: bool
@@ -2985,10 +3471,8 @@ impl<'a> ContextWriter<'a> {
             [..=num_tx_set[tx_set as usize]]
         );
       } else {
+        // Filter intra blocks pass the intra direction of their filter
         let intra_dir = y_mode;
-        // TODO: Once use_filter_intra is enabled,
-        // intra_dir =
-        // fimode_to_intradir[mbmi->filter_intra_mode_info.filter_intra_mode];
 
         symbol_with_update!(
           self,
@@ -3093,8 +3577,20 @@ impl<'a> ContextWriter<'a> {
       RestorationFilter::None => {
         w.symbol_bits(0, &self.fc.lrf_switchable_cdf[..nsym])
       }
-      RestorationFilter::Wiener{ .. } => {
-        unreachable!() // for now, not permanently
+      RestorationFilter::Wiener{coeffs} => {
+        // Does *not* use 'RESTORE_WIENER' but rather just '1'
+        let rp = &rs.planes[pli];
+        let mut bits = w.symbol_bits(1, &self.fc.lrf_switchable_cdf[..nsym]);
+        for pass in 0..2 {
+          let first_coeff = if pli==0 { 0 } else { 1 };
+          for i in first_coeff..3 {
+            let min = WIENER_TAPS_MIN[i] as i32;
+            let max = WIENER_TAPS_MAX[i] as i32;
+            bits += w.count_signed_subexp_with_ref(coeffs[pass][i] as i32, min, max+1, (i+1) as u8,
+                                                   rp.wiener_ref[pass][i] as i32);
+          }
+        }
+        bits
       }
       RestorationFilter::Sgrproj{set, xqd} => {
         // Does *not* use 'RESTORE_SGRPROJ' but rather just '2'
@@ -3119,7 +3615,7 @@ impl<'a> ContextWriter<'a> {
     &mut self, w: &mut dyn Writer, fi: &FrameInvariants<T>, rs: &mut TileRestorationStateMut, sbo: SuperBlockOffset
   ) {
     if !fi.allow_intrabc { // TODO: also disallow if lossless
-      for pli in 0..PLANES {
+      for pli in 0..fi.sequence.num_planes() {
         let rp = &mut rs.planes[pli];
         if let Some(filter) = rp.restoration_unit(sbo).map(|ru| ru.filter) {
           match filter {
@@ -3207,30 +3703,18 @@ impl<'a> ContextWriter<'a> {
     w.literal(bits, strength_index as u32);
   }
 
-  pub fn write_block_deblock_deltas(&mut self, w: &mut dyn Writer,
-                                    bo: BlockOffset, multi: bool) {
-      let block = &self.bc.blocks[bo];
-      let deltas = if multi { FRAME_LF_COUNT + PLANES - 3 } else { 1 };
-      for i in 0..deltas {
-          let delta = block.deblock_deltas[i];
-          let abs:u32 = delta.abs() as u32;
+  pub fn write_delta_qindex(&mut self, w: &mut dyn Writer, delta_q: i32) {
+    let abs = delta_q.abs() as u32;
 
-          if multi {
-              symbol_with_update!(self, w, cmp::min(abs, DELTA_LF_SMALL),
-                                  &mut self.fc.deblock_delta_multi_cdf[i]);
-          } else {
-              symbol_with_update!(self, w, cmp::min(abs, DELTA_LF_SMALL),
-                                  &mut self.fc.deblock_delta_cdf);
-          };
-          if abs >= DELTA_LF_SMALL {
-              let bits = msb(abs as i32 - 1) as u32;
-              w.literal(3, bits - 1);
-              w.literal(bits as u8, abs - (1<<bits) - 1);
-          }
-          if abs > 0 {
-              w.bool(delta < 0, 16384);
-          }
-      }
+    symbol_with_update!(self, w, cmp::min(abs, DELTA_Q_SMALL), &mut self.fc.delta_q_cdf);
+    if abs >= DELTA_Q_SMALL {
+      let bits = msb(abs as i32 - 1) as u32;
+      w.literal(3, bits - 1);
+      w.literal(bits as u8, abs - (1<<bits) - 1);
+    }
+    if abs > 0 {
+      w.bool(delta_q < 0, 16384);
+    }
   }
 
   pub fn write_is_inter(&mut self, w: &mut dyn Writer, bo: BlockOffset, is_inter: bool) {
@@ -3464,6 +3948,136 @@ impl<'a> ContextWriter<'a> {
     }
   }
 
+  /// Return fractional bits needed to code a coefficient of magnitude
+  /// `level`, including its sign
+  pub fn count_coeff_level(
+    &self, w: &dyn Writer, level: u32, txs_ctx: usize, plane_type: usize,
+    coeff_ctx: usize, br_ctx: usize, is_eob: bool
+  ) -> u32 {
+    let mut bits = if is_eob {
+      let cdf = &self.fc.coeff_base_eob_cdf[txs_ctx][plane_type][coeff_ctx];
+      w.symbol_bits(cmp::min(level, 3) - 1, &cdf[..cdf.len() - 1])
+    } else {
+      let cdf = &self.fc.coeff_base_cdf[txs_ctx][plane_type][coeff_ctx];
+      w.symbol_bits(cmp::min(level, 3), &cdf[..cdf.len() - 1])
+    };
+
+    if level == 0 {
+      return bits;
+    }
+
+    if level > NUM_BASE_LEVELS as u32 {
+      let cdf = &self.fc.coeff_br_cdf
+        [cmp::min(txs_ctx, TxSize::TX_32X32 as usize)][plane_type][br_ctx];
+      let base_range = level - 1 - NUM_BASE_LEVELS as u32;
+      let mut idx = 0;
+      while idx < COEFF_BASE_RANGE as u32 {
+        let k = cmp::min(base_range - idx, BR_CDF_SIZE as u32 - 1);
+        bits += w.symbol_bits(k, &cdf[..cdf.len() - 1]);
+        if k < BR_CDF_SIZE as u32 - 1 {
+          break;
+        }
+        idx += BR_CDF_SIZE as u32 - 1;
+      }
+    }
+
+    if level > (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32 {
+      let x = level - (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32;
+      let length = 32 - x.leading_zeros();
+      bits += (2 * length - 1) << OD_BITRES;
+    }
+
+    // The DC sign is context coded, but a bit is close enough here
+    bits + (1 << OD_BITRES)
+  }
+
+  /// Greedy rate-distortion optimized quantization of a transform block.
+  ///
+  /// Walking the coefficients in reverse scan order, each one is set to
+  /// whichever of its nearest level and the level below is cheaper, with
+  /// the rate taken from the coefficient CDFs in the contexts left by the
+  /// coefficients already decided. Trailing ones that do not pay for their
+  /// rate are then dropped, moving the end of block forward.
+  pub fn rdoq_coeffs(
+    &mut self, w: &dyn Writer, qc: &QuantizationContext, lambda: f64,
+    plane: usize, coeffs: &[i32], qcoeffs: &mut [i32], tx_size: TxSize,
+    tx_type: TxType
+  ) {
+    let scan_order = &av1_scan_orders[tx_size as usize][tx_type as usize];
+    let scan = scan_order.scan;
+    let width = av1_get_coded_tx_size(tx_size).width();
+    let height = av1_get_coded_tx_size(tx_size).height();
+
+    let mut eob = find_eob(&qcoeffs[..width * height], scan_order.iscan);
+    if eob == 0 {
+      return;
+    }
+
+    let mut levels_buf = [0u8; TX_PAD_2D];
+    self.txb_init_levels(qcoeffs, width, height, &mut levels_buf);
+    let levels = &mut levels_buf[TX_PAD_TOP * (width + TX_PAD_HOR)..];
+
+    let tx_class = tx_type_to_class[tx_type as usize];
+    let txs_ctx = self.get_txsize_entropy_ctx(tx_size);
+    let plane_type = if plane == 0 { 0 } else { 1 };
+    let bwl = self.get_txb_bwl(tx_size);
+    let rd_cost = |dist: f64, bits: u32| {
+      dist + lambda * bits as f64 / (1 << OD_BITRES) as f64
+    };
+    let padded = |pos: usize| pos + ((pos >> bwl) << TX_PAD_HOR_LOG2);
+
+    for c in (0..eob).rev() {
+      let pos = scan[c] as usize;
+      let coeff = coeffs[pos];
+      let is_eob = c == eob - 1;
+      // The dead zone only ever rounds down, so the last coefficient
+      // is still nonzero at its nearest level
+      let level = qc.nearest_level(coeff, pos);
+      let best = if level == 0 {
+        0
+      } else {
+        let coeff_ctx = self.get_nz_map_ctx(
+          levels, pos, bwl, height, c, is_eob, tx_size, tx_class
+        );
+        let br_ctx = self.get_br_ctx(levels, pos, bwl, tx_class);
+        let cost = |l: u32| {
+          rd_cost(
+            qc.level_dist(coeff, l, pos),
+            self.count_coeff_level(
+              w, l, txs_ctx, plane_type, coeff_ctx, br_ctx, is_eob
+            )
+          )
+        };
+        if (level > 1 || !is_eob) && cost(level - 1) < cost(level) {
+          level - 1
+        } else {
+          level
+        }
+      };
+      qcoeffs[pos] = if coeff < 0 { -(best as i32) } else { best as i32 };
+      levels[padded(pos)] = cmp::min(best, 127) as u8;
+    }
+
+    while eob > 0 {
+      let c = eob - 1;
+      let pos = scan[c] as usize;
+      if qcoeffs[pos].abs() != 1 {
+        break;
+      }
+      let coeff_ctx =
+        self.get_nz_map_ctx(levels, pos, bwl, height, c, true, tx_size, tx_class);
+      let bits =
+        self.count_coeff_level(w, 1, txs_ctx, plane_type, coeff_ctx, 0, true);
+      let keep = rd_cost(qc.level_dist(coeffs[pos], 1, pos), bits);
+      if keep <= qc.level_dist(coeffs[pos], 0, pos) {
+        break;
+      }
+      qcoeffs[pos] = 0;
+      levels[padded(pos)] = 0;
+      eob = (0..c).rposition(|c| qcoeffs[scan[c] as usize] != 0).map_or(0, |c| c + 1);
+    }
+  }
+
   pub fn write_coeffs_lv_map(
     &mut self, w: &mut dyn Writer, plane: usize, bo: BlockOffset, coeffs_in: &[i32],
     pred_mode: PredictionMode,
@@ -3489,7 +4103,7 @@ impl<'a> ContextWriter<'a> {
     }
 
     let eob = if cul_level == 0 { 0 } else {
-      coeffs.iter().rposition(|&v| v != 0).map(|i| i + 1).unwrap_or(0)
+      find_eob(&coeffs_in[..width * height], scan_order.iscan)
     };
 
     let txs_ctx = self.get_txsize_entropy_ctx(tx_size);
@@ -3697,6 +4311,135 @@ impl<'a> ContextWriter<'a> {
     true
   }
 
+  /// Counts the coefficients of a transform block the way
+  /// write_coeffs_lv_map() codes them, but with the fixed symbol rates of
+  /// `rates` rather than the adapting CDFs.  Only the transform type goes
+  /// through `w`, the rest is added to it as fractional bits.
+  pub fn count_coeffs_lv_map(
+    &mut self, w: &mut dyn Writer, rates: &CoeffRates, plane: usize,
+    bo: BlockOffset, coeffs_in: &[i32], pred_mode: PredictionMode,
+    tx_size: TxSize, tx_type: TxType, plane_bsize: BlockSize, xdec: usize,
+    ydec: usize, use_reduced_tx_set: bool
+  ) -> bool {
+    let is_inter = pred_mode >= PredictionMode::NEARESTMV;
+    let scan_order = &av1_scan_orders[tx_size as usize][tx_type as usize];
+    let scan = scan_order.scan;
+    let width = av1_get_coded_tx_size(tx_size).width();
+    let height = av1_get_coded_tx_size(tx_size).height();
+
+    let eob = find_eob(&coeffs_in[..width * height], scan_order.iscan);
+
+    let txs_ctx = self.get_txsize_entropy_ctx(tx_size);
+    let txb_ctx =
+      self.bc.get_txb_ctx(plane_bsize, tx_size, plane, bo, xdec, ydec);
+
+    let mut bits = rates.txb_skip[txs_ctx][txb_ctx.txb_skip_ctx][(eob == 0) as usize];
+
+    if eob == 0 {
+      w.add_bits_frac(bits);
+      self.bc.set_coeff_context(plane, bo, tx_size, xdec, ydec, 0);
+      return false;
+    }
+
+    let mut levels_buf = [0u8; TX_PAD_2D];
+    self.txb_init_levels(coeffs_in, width, height, &mut levels_buf);
+
+    let tx_class = tx_type_to_class[tx_type as usize];
+    let plane_type = if plane == 0 { 0 } else { 1 };
+
+    if plane == 0 {
+      self.write_tx_type(
+        w, tx_size, tx_type, pred_mode, is_inter, use_reduced_tx_set
+      );
+    }
+
+    let mut eob_extra = 0 as u32;
+    let eob_pt = self.get_eob_pos_token(eob, &mut eob_extra);
+    // The 64-point transforms code their end of block with the CDF of 1024
+    let eob_multi_size = cmp::min(tx_size.area_log2() - 4, 6);
+    let eob_multi_ctx = if tx_class == TX_CLASS_2D { 0 } else { 1 };
+    bits += rates.eob_pt[eob_multi_size][plane_type][eob_multi_ctx][eob_pt as usize - 1];
+
+    let eob_offset_bits = k_eob_offset_bits[eob_pt as usize] as u32;
+    if eob_offset_bits > 0 {
+      let bit = (eob_extra >> (eob_offset_bits - 1)) & 1;
+      bits += rates.eob_extra[txs_ctx][plane_type][(eob_pt - 3) as usize][bit as usize];
+      bits += (eob_offset_bits - 1) << OD_BITRES;
+    }
+
+    let mut coeff_contexts: AlignedArray<[i8; MAX_TX_SQUARE]> =
+      UninitializedAlignedArray();
+    let levels =
+      &mut levels_buf[TX_PAD_TOP * (width + TX_PAD_HOR)..];
+
+    self.get_nz_map_contexts(
+      levels,
+      scan,
+      eob as u16,
+      tx_size,
+      tx_class,
+      &mut coeff_contexts.array
+    );
+
+    let bwl = self.get_txb_bwl(tx_size);
+    let mut cul_level = 0 as u32;
+
+    for c in (0..eob).rev() {
+      let pos = scan[c] as usize;
+      let coeff_ctx = coeff_contexts.array[pos] as usize;
+      let v = coeffs_in[pos];
+      let level = v.abs() as u32;
+      cul_level += level;
+
+      bits += if c == eob - 1 {
+        rates.coeff_base_eob[txs_ctx][plane_type][coeff_ctx][(cmp::min(level, 3) - 1) as usize]
+      } else {
+        rates.coeff_base[txs_ctx][plane_type][coeff_ctx][cmp::min(level, 3) as usize]
+      };
+
+      if level == 0 {
+        continue;
+      }
+
+      if level > NUM_BASE_LEVELS as u32 {
+        let br_ctx = self.get_br_ctx(levels, pos, bwl, tx_class);
+        let br_rates = &rates.coeff_br
+          [cmp::min(txs_ctx, TxSize::TX_32X32 as usize)][plane_type][br_ctx];
+        let base_range = level - 1 - NUM_BASE_LEVELS as u32;
+        let mut idx = 0;
+        while idx < COEFF_BASE_RANGE as u32 {
+          let k = cmp::min(base_range - idx, BR_CDF_SIZE as u32 - 1);
+          bits += br_rates[k as usize];
+          if k < BR_CDF_SIZE as u32 - 1 {
+            break;
+          }
+          idx += BR_CDF_SIZE as u32 - 1;
+        }
+      }
+
+      if level > (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32 {
+        let x = level - (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32;
+        let length = 32 - x.leading_zeros();
+        bits += (2 * length - 1) << OD_BITRES;
+      }
+
+      bits += if c == 0 {
+        rates.dc_sign[plane_type][txb_ctx.dc_sign_ctx][(v < 0) as usize]
+      } else {
+        1 << OD_BITRES
+      };
+    }
+
+    w.add_bits_frac(bits);
+
+    cul_level = cmp::min(COEFF_CONTEXT_MASK as u32, cul_level);
+
+    self.bc.set_dc_sign(&mut cul_level, coeffs_in[scan[0] as usize]);
+
+    self.bc.set_coeff_context(plane, bo, tx_size, xdec, ydec, cul_level as u8);
+    true
+  }
+
   pub fn checkpoint(&mut self) -> ContextWriterCheckpoint {
     ContextWriterCheckpoint {
       fc: *self.fc,
@@ -3828,3 +4571,198 @@ pub fn encode_mv_component(w: &mut dyn Writer, comp: i32,
   }
 }
 
+// Fills `costs` with the cost of each symbol of `cdf`, in 1/8 bits
+fn symbol_costs(cdf: &[u16], costs: &mut [u32]) {
+  let mut prev = 32768;
+  for (cost, &c) in costs.iter_mut().zip(cdf.iter()) {
+    let p = (prev - c as u32).max(1);
+    *cost = ((15.0 - (p as f32).log2()) * (1 << OD_BITRES) as f32).round() as u32;
+    prev = c as u32;
+  }
+}
+
+/// Costs of the symbols coding one motion vector component, in 1/8 bits
+#[derive(Clone, Copy, Default)]
+struct MvComponentCosts {
+  sign: [u32; 2],
+  classes: [u32; MV_CLASSES],
+  class0: [u32; CLASS0_SIZE],
+  bits: [[u32; 2]; MV_OFFSET_BITS],
+  class0_fp: [[u32; MV_FP_SIZE]; CLASS0_SIZE],
+  fp: [u32; MV_FP_SIZE],
+  class0_hp: [u32; 2],
+  hp: [u32; 2],
+}
+
+impl MvComponentCosts {
+  fn new(mvcomp: &NMVComponent) -> Self {
+    let mut costs = Self::default();
+    symbol_costs(&mvcomp.sign_cdf, &mut costs.sign);
+    symbol_costs(&mvcomp.classes_cdf, &mut costs.classes);
+    symbol_costs(&mvcomp.class0_cdf, &mut costs.class0);
+    for (c, cdf) in costs.bits.iter_mut().zip(mvcomp.bits_cdf.iter()) {
+      symbol_costs(cdf, c);
+    }
+    for (c, cdf) in costs.class0_fp.iter_mut().zip(mvcomp.class0_fp_cdf.iter()) {
+      symbol_costs(cdf, c);
+    }
+    symbol_costs(&mvcomp.fp_cdf, &mut costs.fp);
+    symbol_costs(&mvcomp.class0_hp_cdf, &mut costs.class0_hp);
+    symbol_costs(&mvcomp.hp_cdf, &mut costs.hp);
+    costs
+  }
+
+  // Mirrors encode_mv_component()
+  fn rate(&self, comp: i32, precision: MvSubpelPrecision) -> u32 {
+    debug_assert!(comp != 0);
+    let mut offset: u32 = 0;
+    let sign = (comp < 0) as usize;
+    let mv_class = get_mv_class(comp.abs() as u32 - 1, &mut offset);
+    let d = (offset >> 3) as usize;
+    let fr = ((offset >> 1) & 3) as usize;
+    let hp = (offset & 1) as usize;
+
+    let mut rate = self.sign[sign] + self.classes[mv_class];
+    if mv_class == MV_CLASS_0 {
+      rate += self.class0[d];
+    } else {
+      let n = mv_class + CLASS0_BITS - 1;
+      rate += (0..n).map(|i| self.bits[i][(d >> i) & 1]).sum::<u32>();
+    }
+    if precision > MvSubpelPrecision::MV_SUBPEL_NONE {
+      rate += if mv_class == MV_CLASS_0 { self.class0_fp[d][fr] } else { self.fp[fr] };
+    }
+    if precision > MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION {
+      rate += if mv_class == MV_CLASS_0 { self.class0_hp[hp] } else { self.hp[hp] };
+    }
+    rate
+  }
+}
+
+/// Rates of coding motion vectors with the motion vector CDFs of an entropy
+/// coding context.  The CDFs adapt as a tile is coded, so the rates are
+/// meant to be rebuilt from time to time.
+#[derive(Clone, Copy)]
+pub struct MvRates {
+  joints: [u32; MV_JOINTS],
+  comps: [MvComponentCosts; 2],
+  precision: MvSubpelPrecision,
+}
+
+impl MvRates {
+  pub fn new(fc: &CDFContext, precision: MvSubpelPrecision) -> Self {
+    Self::from_nmv_context(&fc.nmv_context, precision)
+  }
+
+  fn from_nmv_context(nmvc: &NMVContext, precision: MvSubpelPrecision) -> Self {
+    let mut joints = [0; MV_JOINTS];
+    symbol_costs(&nmvc.joints_cdf, &mut joints);
+    MvRates {
+      joints,
+      comps: [MvComponentCosts::new(&nmvc.comps[0]), MvComponentCosts::new(&nmvc.comps[1])],
+      precision,
+    }
+  }
+
+  /// Rate of coding `mv` as a difference to `ref_mv`, in 1/8 bits
+  pub fn rate(&self, mv: MotionVector, ref_mv: MotionVector) -> u32 {
+    let diff = MotionVector { row: mv.row - ref_mv.row, col: mv.col - ref_mv.col };
+    let j = av1_get_mv_joint(diff);
+    let mut rate = self.joints[j as usize];
+    if mv_joint_vertical(j) {
+      rate += self.comps[0].rate(diff.row as i32, self.precision);
+    }
+    if mv_joint_horizontal(j) {
+      rate += self.comps[1].rate(diff.col as i32, self.precision);
+    }
+    rate
+  }
+}
+
+impl Default for MvRates {
+  fn default() -> Self {
+    Self::from_nmv_context(&default_nmv_context, MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION)
+  }
+}
+
+/// Rates of the symbols coding the coefficients of transform blocks, in 1/8
+/// bits, taken from the CDFs of an entropy coding context.  Like the CDFs,
+/// they go stale as a tile is coded and are rebuilt for every superblock.
+#[derive(Clone, Copy)]
+pub struct CoeffRates {
+  txb_skip: [[[u32; 2]; TXB_SKIP_CONTEXTS]; TxSize::TX_SIZES],
+  dc_sign: [[[u32; 2]; DC_SIGN_CONTEXTS]; PLANE_TYPES],
+  eob_extra: [[[[u32; 2]; EOB_COEF_CONTEXTS]; PLANE_TYPES]; TxSize::TX_SIZES],
+  // Indexed by the log2 of the transform area minus 4
+  eob_pt: [[[[u32; 11]; 2]; PLANE_TYPES]; 7],
+  coeff_base_eob:
+    [[[[u32; 3]; SIG_COEF_CONTEXTS_EOB]; PLANE_TYPES]; TxSize::TX_SIZES],
+  coeff_base: [[[[u32; 4]; SIG_COEF_CONTEXTS]; PLANE_TYPES]; TxSize::TX_SIZES],
+  coeff_br:
+    [[[[u32; BR_CDF_SIZE]; LEVEL_CONTEXTS]; PLANE_TYPES]; TxSize::TX_SIZES],
+}
+
+impl CoeffRates {
+  pub fn new(fc: &CDFContext) -> Self {
+    let mut rates = CoeffRates {
+      txb_skip: [[[0; 2]; TXB_SKIP_CONTEXTS]; TxSize::TX_SIZES],
+      dc_sign: [[[0; 2]; DC_SIGN_CONTEXTS]; PLANE_TYPES],
+      eob_extra: [[[[0; 2]; EOB_COEF_CONTEXTS]; PLANE_TYPES]; TxSize::TX_SIZES],
+      eob_pt: [[[[0; 11]; 2]; PLANE_TYPES]; 7],
+      coeff_base_eob:
+        [[[[0; 3]; SIG_COEF_CONTEXTS_EOB]; PLANE_TYPES]; TxSize::TX_SIZES],
+      coeff_base: [[[[0; 4]; SIG_COEF_CONTEXTS]; PLANE_TYPES]; TxSize::TX_SIZES],
+      coeff_br:
+        [[[[0; BR_CDF_SIZE]; LEVEL_CONTEXTS]; PLANE_TYPES]; TxSize::TX_SIZES],
+    };
+    rates.update(fc);
+    rates
+  }
+
+  /// Rebuilds the rates from the current CDFs of `fc`
+  pub fn update(&mut self, fc: &CDFContext) {
+    for txs_ctx in 0..TxSize::TX_SIZES {
+      for (r, cdf) in self.txb_skip[txs_ctx].iter_mut().zip(fc.txb_skip_cdf[txs_ctx].iter()) {
+        symbol_costs(cdf, r);
+      }
+      for plane_type in 0..PLANE_TYPES {
+        let cdfs = fc.eob_extra_cdf[txs_ctx][plane_type].iter();
+        for (r, cdf) in self.eob_extra[txs_ctx][plane_type].iter_mut().zip(cdfs) {
+          symbol_costs(cdf, r);
+        }
+        let cdfs = fc.coeff_base_eob_cdf[txs_ctx][plane_type].iter();
+        for (r, cdf) in self.coeff_base_eob[txs_ctx][plane_type].iter_mut().zip(cdfs) {
+          symbol_costs(cdf, r);
+        }
+        let cdfs = fc.coeff_base_cdf[txs_ctx][plane_type].iter();
+        for (r, cdf) in self.coeff_base[txs_ctx][plane_type].iter_mut().zip(cdfs) {
+          symbol_costs(cdf, r);
+        }
+        let cdfs = fc.coeff_br_cdf[txs_ctx][plane_type].iter();
+        for (r, cdf) in self.coeff_br[txs_ctx][plane_type].iter_mut().zip(cdfs) {
+          symbol_costs(cdf, r);
+        }
+      }
+    }
+    for plane_type in 0..PLANE_TYPES {
+      for (r, cdf) in self.dc_sign[plane_type].iter_mut().zip(fc.dc_sign_cdf[plane_type].iter()) {
+        symbol_costs(cdf, r);
+      }
+      for ctx in 0..2 {
+        let eob_pt = &mut self.eob_pt;
+        // The last entry of each CDF is its adaptation counter
+        let mut fill = |eob_multi_size: usize, cdf: &[u16]| {
+          symbol_costs(&cdf[..cdf.len() - 1], &mut eob_pt[eob_multi_size][plane_type][ctx]);
+        };
+        fill(0, &fc.eob_flag_cdf16[plane_type][ctx]);
+        fill(1, &fc.eob_flag_cdf32[plane_type][ctx]);
+        fill(2, &fc.eob_flag_cdf64[plane_type][ctx]);
+        fill(3, &fc.eob_flag_cdf128[plane_type][ctx]);
+        fill(4, &fc.eob_flag_cdf256[plane_type][ctx]);
+        fill(5, &fc.eob_flag_cdf512[plane_type][ctx]);
+        fill(6, &fc.eob_flag_cdf1024[plane_type][ctx]);
+      }
+    }
+  }
+}
+
@@ -13,6 +13,7 @@ use crate::context::*;
 use crate::DeblockState;
 use crate::encoder::FrameInvariants;
 use crate::encoder::FrameState;
+use crate::encoder::SegmentationState;
 use crate::api::FrameType;
 use crate::partition::RefType::*;
 use crate::frame::*;
@@ -23,7 +24,8 @@ use std::cmp;
 use crate::util::{clamp, ILog};
 
 fn deblock_adjusted_level(
-  deblock: &DeblockState, block: &Block, pli: usize, vertical: bool
+  deblock: &DeblockState, segmentation: &SegmentationState, block: &Block,
+  pli: usize, vertical: bool
 ) -> usize {
   let idx = if pli == 0 {
     if vertical {
@@ -50,9 +52,19 @@ fn deblock_adjusted_level(
     deblock.levels[idx]
   };
 
-  // if fi.seg_feaure_active {
-  // rav1e does not yet support segments or segment features
-  // }
+  // Per-segment filter strength offset
+  let level = if segmentation.enabled {
+    let seg_lvl = SegLvl::SEG_LVL_ALT_LF_Y_V as usize + idx;
+    let segment = block.segmentation_idx as usize;
+    if segmentation.features[segment][seg_lvl] {
+      let delta = segmentation.data[segment][seg_lvl];
+      clamp(level as i16 + delta, 0, MAX_LOOP_FILTER as i16) as u8
+    } else {
+      level
+    }
+  } else {
+    level
+  };
 
   // Are delta modifiers for specific references and modes active?  If so, add them too.
   if deblock.deltas_enabled {
@@ -145,12 +157,13 @@ fn deblock_size<T: Pixel>(
 
 // Must be called on a tx edge
 fn deblock_level(
-  deblock: &DeblockState, block: &Block, prev_block: &Block, pli: usize,
-  vertical: bool
+  deblock: &DeblockState, segmentation: &SegmentationState, block: &Block,
+  prev_block: &Block, pli: usize, vertical: bool
 ) -> usize {
-  let level = deblock_adjusted_level(deblock, block, pli, vertical);
+  let level =
+    deblock_adjusted_level(deblock, segmentation, block, pli, vertical);
   if level == 0 {
-    deblock_adjusted_level(deblock, prev_block, pli, vertical)
+    deblock_adjusted_level(deblock, segmentation, prev_block, pli, vertical)
   } else {
     level
   }
@@ -1026,7 +1039,7 @@ fn sse_size14<T: Pixel>(
 }
 
 fn filter_v_edge<T: Pixel>(
-  deblock: &DeblockState, blocks: &FrameBlocks, bo: BlockOffset, p: &mut Plane<T>,
+  deblock: &DeblockState, segmentation: &SegmentationState, blocks: &FrameBlocks, bo: BlockOffset, p: &mut Plane<T>,
   pli: usize, bd: usize, xdec: usize, ydec: usize
 ) {
   let block = &blocks[bo];
@@ -1038,7 +1051,7 @@ fn filter_v_edge<T: Pixel>(
     let filter_size =
       deblock_size(block, prev_block, p, pli, true, block_edge);
     if filter_size > 0 {
-      let level = deblock_level(deblock, block, prev_block, pli, true);
+      let level = deblock_level(deblock, segmentation, block, prev_block, pli, true);
       if level > 0 {
         let po = bo.plane_offset(&p.cfg);
         let mut plane_slice = p.mut_slice(po);
@@ -1131,7 +1144,7 @@ fn sse_v_edge<T: Pixel>(
 }
 
 fn filter_h_edge<T: Pixel>(
-  deblock: &DeblockState, blocks: &FrameBlocks, bo: BlockOffset, p: &mut Plane<T>,
+  deblock: &DeblockState, segmentation: &SegmentationState, blocks: &FrameBlocks, bo: BlockOffset, p: &mut Plane<T>,
   pli: usize, bd: usize, xdec: usize, ydec: usize
 ) {
   let block = &blocks[bo];
@@ -1143,7 +1156,7 @@ fn filter_h_edge<T: Pixel>(
     let filter_size =
       deblock_size(block, prev_block, p, pli, false, block_edge);
     if filter_size > 0 {
-      let level = deblock_level(deblock, block, prev_block, pli, false);
+      let level = deblock_level(deblock, segmentation, block, prev_block, pli, false);
       if level > 0 {
         let po = bo.plane_offset(&p.cfg);
         let mut plane_slice = p.mut_slice(po);
@@ -1237,7 +1250,9 @@ fn sse_h_edge<T: Pixel>(
 
 // Deblocks all edges, vertical and horizontal, in a single plane
 pub fn deblock_plane<T: Pixel>(
-  fi: &FrameInvariants<T>, deblock: &DeblockState, p: &mut Plane<T>, pli: usize, blocks: &FrameBlocks
+  fi: &FrameInvariants<T>, deblock: &DeblockState,
+  segmentation: &SegmentationState, p: &mut Plane<T>, pli: usize,
+  blocks: &FrameBlocks
 ) {
   let xdec = p.cfg.xdec;
   let ydec = p.cfg.ydec;
@@ -1272,12 +1287,13 @@ pub fn deblock_plane<T: Pixel>(
   // edge).  Unroll to avoid corner-cases.
   if rows > 0 {
     for x in (1 << xdec..cols).step_by(1 << xdec) {
-      filter_v_edge(deblock, blocks, BlockOffset { x, y: 0 }, p, pli, bd, xdec, ydec);
+      filter_v_edge(deblock, segmentation, blocks, BlockOffset { x, y: 0 }, p, pli, bd, xdec, ydec);
     }
     if rows > 1 << ydec {
       for x in (1 << xdec..cols).step_by(1 << xdec) {
         filter_v_edge(
           deblock,
+          segmentation,
           blocks,
           BlockOffset { x, y: 1 << ydec },
           p,
@@ -1295,14 +1311,15 @@ pub fn deblock_plane<T: Pixel>(
   for y in ((2 << ydec)..rows).step_by(1 << ydec) {
     // Check for vertical edge at first MI block boundary on this row
     if  cols > 1 << xdec {
-      filter_v_edge(deblock, blocks, BlockOffset { x: 1 << xdec, y }, p, pli, bd, xdec, ydec);
+      filter_v_edge(deblock, segmentation, blocks, BlockOffset { x: 1 << xdec, y }, p, pli, bd, xdec, ydec);
     }
     // run the rest of the row with both vertical and horizontal edge filtering.
     // Horizontal lags vertical edge by one row and two columns.
     for x in (2 << xdec..cols).step_by(1 << xdec) {
-      filter_v_edge(deblock, blocks, BlockOffset { x, y }, p, pli, bd, xdec, ydec);
+      filter_v_edge(deblock, segmentation, blocks, BlockOffset { x, y }, p, pli, bd, xdec, ydec);
       filter_h_edge(
         deblock,
+        segmentation,
         blocks,
         BlockOffset { x: x - (2 << xdec), y: y - (1 << ydec) },
         p,
@@ -1316,6 +1333,7 @@ pub fn deblock_plane<T: Pixel>(
     if cols > 2 << xdec {
       filter_h_edge(
         deblock,
+        segmentation,
         blocks,
         BlockOffset { x: cols - (2 << xdec), y: y - (1 << ydec) },
         p,
@@ -1327,6 +1345,7 @@ pub fn deblock_plane<T: Pixel>(
       if cols > 1 << xdec {
         filter_h_edge(
           deblock,
+          segmentation,
           blocks,
           BlockOffset { x: cols - (1 << xdec), y: y - (1 << ydec) },
           p,
@@ -1344,6 +1363,7 @@ pub fn deblock_plane<T: Pixel>(
     for x in (0..cols).step_by(1 << xdec) {
       filter_h_edge(
         deblock,
+        segmentation,
         blocks,
         BlockOffset { x, y: rows - (1 << ydec) },
         p,
@@ -1357,9 +1377,11 @@ pub fn deblock_plane<T: Pixel>(
 }
 
 // sse count of all edges in a single plane, accumulates into vertical and horizontal counts
+// of the tally picked by sb_index for each edge
 fn sse_plane<T: Pixel>(
-  fi: &FrameInvariants<T>, rec: &Plane<T>, src: &Plane<T>, v_sse: &mut [i64; MAX_LOOP_FILTER + 2],
-  h_sse: &mut [i64; MAX_LOOP_FILTER + 2], pli: usize, blocks: &FrameBlocks
+  fi: &FrameInvariants<T>, rec: &Plane<T>, src: &Plane<T>, v_sse: &mut [[i64; MAX_LOOP_FILTER + 2]],
+  h_sse: &mut [[i64; MAX_LOOP_FILTER + 2]], sb_index: &dyn Fn(BlockOffset) -> usize, pli: usize,
+  blocks: &FrameBlocks
 ) {
   let xdec = rec.cfg.xdec;
   let ydec = rec.cfg.ydec;
@@ -1372,7 +1394,8 @@ fn sse_plane<T: Pixel>(
   let bd = fi.sequence.bit_depth;
   // No horizontal edge filtering along top of frame
   for x in (1 << xdec..cols).step_by(1 << xdec) {
-    sse_v_edge(blocks, BlockOffset { x, y: 0 }, rec, src, v_sse, pli, bd, xdec, ydec);
+    let bo = BlockOffset { x, y: 0 };
+    sse_v_edge(blocks, bo, rec, src, &mut v_sse[sb_index(bo)], pli, bd, xdec, ydec);
   }
 
   // Unlike actual filtering, we're counting horizontal and vertical
@@ -1380,10 +1403,12 @@ fn sse_plane<T: Pixel>(
   // behind vertical.
   for y in (1 << ydec..rows).step_by(1 << ydec) {
     // No vertical filtering along left edge of frame
-    sse_h_edge(blocks, BlockOffset { x: 0, y }, rec, src, h_sse, pli, bd, xdec, ydec);
+    let bo = BlockOffset { x: 0, y };
+    sse_h_edge(blocks, bo, rec, src, &mut h_sse[sb_index(bo)], pli, bd, xdec, ydec);
     for x in (1 << xdec..cols).step_by(1 << xdec) {
-      sse_v_edge(blocks, BlockOffset { x, y }, rec, src, v_sse, pli, bd, xdec, ydec);
-      sse_h_edge(blocks, BlockOffset { x, y }, rec, src, h_sse, pli, bd, xdec, ydec);
+      let bo = BlockOffset { x, y };
+      sse_v_edge(blocks, bo, rec, src, &mut v_sse[sb_index(bo)], pli, bd, xdec, ydec);
+      sse_h_edge(blocks, bo, rec, src, &mut h_sse[sb_index(bo)], pli, bd, xdec, ydec);
     }
   }
 }
@@ -1392,8 +1417,15 @@ fn sse_plane<T: Pixel>(
 pub fn deblock_filter_frame<T: Pixel>(
   fi: &FrameInvariants<T>, fs: &mut FrameState<T>, blocks: &FrameBlocks
 ) {
-  for pli in 0..PLANES {
-    deblock_plane(fi, &fs.deblock, &mut fs.rec.planes[pli], pli, blocks);
+  for pli in 0..fi.sequence.num_planes() {
+    deblock_plane(
+      fi,
+      &fs.deblock,
+      &fs.segmentation,
+      &mut fs.rec.planes[pli],
+      pli,
+      blocks
+    );
   }
 }
 
@@ -1404,19 +1436,22 @@ fn sse_optimize<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>, block
       < 35
   );
 
-  for pli in 0..PLANES {
-    let mut v_tally: [i64; MAX_LOOP_FILTER + 2] = [0; MAX_LOOP_FILTER + 2];
-    let mut h_tally: [i64; MAX_LOOP_FILTER + 2] = [0; MAX_LOOP_FILTER + 2];
+  for pli in 0..fi.sequence.num_planes() {
+    let mut v_tallies = [[0; MAX_LOOP_FILTER + 2]];
+    let mut h_tallies = [[0; MAX_LOOP_FILTER + 2]];
 
     sse_plane(
       fi,
       &fs.rec.planes[pli],
       &fs.input.planes[pli],
-      &mut v_tally,
-      &mut h_tally,
+      &mut v_tallies,
+      &mut h_tallies,
+      &|_| 0,
       pli,
       blocks
     );
+    let mut v_tally = v_tallies[0];
+    let mut h_tally = h_tallies[0];
 
     for i in 1..=MAX_LOOP_FILTER {
       v_tally[i] += v_tally[i - 1];
@@ -1461,19 +1496,19 @@ pub fn deblock_filter_optimize<T: Pixel>(
     let level = clamp(
       match fi.sequence.bit_depth {
         8 =>
-          if fi.frame_type == FrameType::KEY {
+          if fi.intra_only {
             (q * 17563 - 421_574 + (1 << 18 >> 1)) >> 18
           } else {
             (q * 6017 + 650_707 + (1 << 18 >> 1)) >> 18
           },
         10 =>
-          if fi.frame_type == FrameType::KEY {
+          if fi.intra_only {
             ((q * 20723 + 4_060_632 + (1 << 20 >> 1)) >> 20) - 4
           } else {
             (q * 20723 + 4_060_632 + (1 << 20 >> 1)) >> 20
           },
         12 =>
-          if fi.frame_type == FrameType::KEY {
+          if fi.intra_only {
             ((q * 20723 + 16_242_526 + (1 << 22 >> 1)) >> 22) - 4
           } else {
             (q * 20723 + 16_242_526 + (1 << 22 >> 1)) >> 22
@@ -1494,3 +1529,81 @@ pub fn deblock_filter_optimize<T: Pixel>(
     sse_optimize(fi, fs, blocks);
   }
 }
+
+/// Range of the superblock filter level deltas searched, in units of
+/// 1 << block_delta_shift
+const MAX_SB_DEBLOCK_DELTA: i32 = 4;
+
+// Adds the sse of one tally at each searched delta from the frame level
+fn add_delta_costs(
+  costs: &mut [i64], tally: &mut [i64; MAX_LOOP_FILTER + 2], level: u8, shift: u8
+) {
+  for i in 1..=MAX_LOOP_FILTER {
+    tally[i] += tally[i - 1];
+  }
+  for (i, cost) in costs.iter_mut().enumerate() {
+    let delta = i as i32 - MAX_SB_DEBLOCK_DELTA;
+    let l = clamp(level as i32 + (delta << shift), 0, MAX_LOOP_FILTER as i32);
+    *cost += tally[l as usize];
+  }
+}
+
+/// Picks the deblocking filter level delta of each superblock, relative to
+/// the frame levels, that minimizes the sse of the edges in the superblock.
+/// Deltas are shared by all planes and in superblock raster order.
+pub fn deblock_sb_deltas<T: Pixel>(
+  fi: &FrameInvariants<T>, fs: &FrameState<T>, blocks: &FrameBlocks
+) -> Vec<i8> {
+  let deblock = &fs.deblock;
+  let sb_count = fi.sb_width * fi.sb_height;
+  let sb_index = |bo: BlockOffset| {
+    let sbo = bo.sb_offset();
+    sbo.y * fi.sb_width + sbo.x
+  };
+  let mut costs = vec![[0i64; 2 * MAX_SB_DEBLOCK_DELTA as usize + 1]; sb_count];
+
+  for pli in 0..fi.sequence.num_planes() {
+    // Matches the planes deblock_plane() skips
+    let (v_level, h_level) = match pli {
+      0 => (deblock.levels[0], deblock.levels[1]),
+      _ => (deblock.levels[pli + 1], deblock.levels[pli + 1])
+    };
+    if v_level == 0 && h_level == 0 {
+      continue;
+    }
+
+    let mut v_tallies = vec![[0; MAX_LOOP_FILTER + 2]; sb_count];
+    let mut h_tallies = vec![[0; MAX_LOOP_FILTER + 2]; sb_count];
+    sse_plane(
+      fi,
+      &fs.rec.planes[pli],
+      &fs.input.planes[pli],
+      &mut v_tallies,
+      &mut h_tallies,
+      &sb_index,
+      pli,
+      blocks
+    );
+
+    for (cost, (v_tally, h_tally)) in
+      costs.iter_mut().zip(v_tallies.iter_mut().zip(h_tallies.iter_mut()))
+    {
+      add_delta_costs(cost, v_tally, v_level, deblock.block_delta_shift);
+      add_delta_costs(cost, h_tally, h_level, deblock.block_delta_shift);
+    }
+  }
+
+  costs.iter().map(|cost| {
+    let cost_of = |delta: i32| cost[(delta + MAX_SB_DEBLOCK_DELTA) as usize];
+    // Prefer the smallest delta among equal costs
+    let mut best = 0;
+    for magnitude in 1..=MAX_SB_DEBLOCK_DELTA {
+      for &delta in &[-magnitude, magnitude] {
+        if cost_of(delta) < cost_of(best) {
+          best = delta;
+        }
+      }
+    }
+    best as i8
+  }).collect()
+}
@@ -0,0 +1,249 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::film_grain::*;
+use crate::frame::*;
+use crate::util::{CastFromPrimitive, Pixel};
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
+
+use std::sync::Arc;
+
+/// Number of intensity bins the noise level is measured in. Each bin becomes
+/// one point of the film grain scaling function.
+const NUM_INTENSITY_BINS: usize = 8;
+/// Bins with fewer samples than this are too noisy to be trusted.
+const MIN_BIN_SAMPLES: u64 = 64;
+
+/// Removes noise from the source before it is coded and measures what was
+/// removed, so that it can be re-synthesized by the decoder as film grain.
+///
+/// The filter is a 3x3 spatial sigma filter combined with a recursive
+/// temporal filter against the previous denoised frame. Only neighbors
+/// within `strength` (in 8-bit units) of the current pixel contribute, which
+/// preserves edges and keeps motion from smearing across frames.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Denoiser<T: Pixel> {
+  /// Largest pixel difference treated as noise, scaled to the bit depth
+  threshold: i32,
+  bit_depth: usize,
+  prev_frame: Option<Arc<Frame<T>>>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct NoiseBin {
+  count: u64,
+  sum: i64,
+  sum_sq: u64,
+}
+
+impl NoiseBin {
+  fn add(&mut self, noise: i32) {
+    self.count += 1;
+    self.sum += noise as i64;
+    self.sum_sq += (noise * noise) as u64;
+  }
+
+  fn std_dev(&self) -> Option<f64> {
+    if self.count < MIN_BIN_SAMPLES {
+      return None;
+    }
+    let n = self.count as f64;
+    let mean = self.sum as f64 / n;
+    Some((self.sum_sq as f64 / n - mean * mean).max(0.0).sqrt())
+  }
+}
+
+impl<T: Pixel> Denoiser<T> {
+  pub fn new(strength: u8, bit_depth: usize) -> Self {
+    Denoiser {
+      threshold: (strength as i32) << (bit_depth - 8),
+      bit_depth,
+      prev_frame: None,
+    }
+  }
+
+  /// Returns the denoised frame along with grain parameters that model the
+  /// noise that was taken out of it.
+  pub fn denoise(
+    &mut self, frame: &Frame<T>
+  ) -> (Arc<Frame<T>>, FilmGrainParams) {
+    let mut out = frame.clone();
+
+    // Only filter temporally against a frame of the same size.
+    let prev = self.prev_frame.take().filter(|prev| {
+      prev.planes[0].cfg == frame.planes[0].cfg
+    });
+
+    for p in 0..3 {
+      self.denoise_plane(
+        &frame.planes[p],
+        prev.as_ref().map(|prev| &prev.planes[p]),
+        &mut out.planes[p]
+      );
+    }
+
+    let params = self.estimate_grain(frame, &out);
+    let out = Arc::new(out);
+    self.prev_frame = Some(out.clone());
+    (out, params)
+  }
+
+  fn denoise_plane(
+    &self, src: &Plane<T>, prev: Option<&Plane<T>>, dst: &mut Plane<T>
+  ) {
+    let PlaneConfig { width, height, .. } = src.cfg;
+    let threshold = self.threshold;
+    let stride = dst.cfg.stride;
+    let dst_data = dst.data_origin_mut();
+
+    for y in 0..height {
+      let y0 = y.saturating_sub(1);
+      let y1 = (y + 1).min(height - 1);
+      for x in 0..width {
+        let x0 = x.saturating_sub(1);
+        let x1 = (x + 1).min(width - 1);
+        let c = i32::cast_from(src.p(x, y));
+
+        let mut sum = 0;
+        let mut count = 0;
+        for yy in y0..=y1 {
+          for xx in x0..=x1 {
+            let v = i32::cast_from(src.p(xx, yy));
+            if (v - c).abs() <= threshold {
+              sum += v;
+              count += 1;
+            }
+          }
+        }
+
+        // Give the motion-compensation-free temporal neighbor as much weight
+        // as the spatial neighborhood, but only where nothing moved.
+        if let Some(prev) = prev {
+          let v = i32::cast_from(prev.p(x, y));
+          if (v - c).abs() <= threshold {
+            sum += v * count;
+            count *= 2;
+          }
+        }
+
+        dst_data[y * stride + x] = T::cast_from((sum + count / 2) / count);
+      }
+    }
+  }
+
+  /// Measures the standard deviation of the removed noise per luma intensity
+  /// bin and converts it into film grain scaling functions.
+  fn estimate_grain(
+    &self, src: &Frame<T>, denoised: &Frame<T>
+  ) -> FilmGrainParams {
+    let shift = self.bit_depth - 8;
+    let bin_of = |v: T| {
+      (u32::cast_from(v) as usize >> shift) * NUM_INTENSITY_BINS / 256
+    };
+
+    let mut bins = [[NoiseBin::default(); NUM_INTENSITY_BINS]; 3];
+    for p in 0..3 {
+      let PlaneConfig { width, height, xdec, ydec, .. } = src.planes[p].cfg;
+      for y in 0..height {
+        for x in 0..width {
+          // Chroma scaling is indexed by the co-located luma value.
+          let luma = denoised.planes[0].p(x << xdec, y << ydec);
+          let noise = i32::cast_from(src.planes[p].p(x, y))
+            - i32::cast_from(denoised.planes[p].p(x, y));
+          bins[p][bin_of(luma)].add(noise >> shift);
+        }
+      }
+    }
+
+    // With the default grain_scale_shift, the gaussian grain has a standard
+    // deviation of about 32 in 8-bit units, which is then multiplied by
+    // scaling / 2^scaling_shift.
+    let mut params = FilmGrainParams::default();
+    let to_points = |bins: &[NoiseBin; NUM_INTENSITY_BINS]| {
+      let mut points = Vec::new();
+      for (i, bin) in bins.iter().enumerate() {
+        if let Some(sigma) = bin.std_dev() {
+          let intensity = (i * 256 / NUM_INTENSITY_BINS
+            + 128 / NUM_INTENSITY_BINS) as u8;
+          let scaling = (sigma * 8.0).round().min(255.0) as u8;
+          points.push([intensity, scaling]);
+        }
+      }
+      if points.iter().all(|point| point[1] == 0) {
+        points.clear();
+      }
+      points
+    };
+
+    params.scaling_points_y.extend(to_points(&bins[0]));
+    if !params.scaling_points_y.is_empty() {
+      let cb = to_points(&bins[1]);
+      let cr = to_points(&bins[2]);
+      // Both chroma planes must signal grain, or neither.
+      if !cb.is_empty() && !cr.is_empty() {
+        params.scaling_points_cb.extend(cb);
+        params.scaling_points_cr.extend(cr);
+      }
+    }
+
+    params
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::api::ChromaSampling;
+
+  fn fill<T: Pixel>(frame: &mut Frame<T>, f: impl Fn(usize, usize) -> u8) {
+    for plane in frame.planes.iter_mut() {
+      let PlaneConfig { width, height, stride, .. } = plane.cfg;
+      let data = plane.data_origin_mut();
+      for y in 0..height {
+        for x in 0..width {
+          data[y * stride + x] = T::cast_from(f(x, y));
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn flat_frame_is_untouched() {
+    let mut frame = Frame::<u8>::new(64, 64, ChromaSampling::Cs420);
+    fill(&mut frame, |_, _| 100);
+    let mut denoiser = Denoiser::new(10, 8);
+    let (out, params) = denoiser.denoise(&frame);
+    assert!(out.planes[0].iter().all(|v| v == 100));
+    assert!(!params.apply_grain());
+  }
+
+  #[test]
+  fn noise_becomes_grain() {
+    let mut frame = Frame::<u8>::new(64, 64, ChromaSampling::Cs420);
+    fill(&mut frame, |x, y| if (x ^ y) & 1 == 0 { 104 } else { 96 });
+    let mut denoiser = Denoiser::new(10, 8);
+    let (out, params) = denoiser.denoise(&frame);
+    assert!(out.planes[0].iter().all(|v| v >= 99 && v <= 101));
+    assert!(params.apply_grain());
+    assert!(params.is_valid(true));
+    assert_eq!(params.scaling_points_y.len(), 1);
+    assert_eq!(params.scaling_points_cb.len(), 1);
+  }
+
+  #[test]
+  fn edges_are_preserved() {
+    let mut frame = Frame::<u8>::new(64, 64, ChromaSampling::Cs420);
+    fill(&mut frame, |x, _| if x < 16 { 20 } else { 200 });
+    let mut denoiser = Denoiser::new(10, 8);
+    let (out, _) = denoiser.denoise(&frame);
+    assert_eq!(out.planes[0].p(15, 8), 20);
+    assert_eq!(out.planes[0].p(16, 8), 200);
+  }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Block distortion metrics of the motion search and the rate-distortion
+//! decisions
+//!
+//! A new metric only needs an implementation of `DistortionMetric` and a
+//! variant of `Distortion` to be selectable in `EncoderConfig`.
+
+use crate::me::{get_sad, get_satd};
+use crate::rdo::sse_wxh;
+use crate::tiling::*;
+use crate::util::Pixel;
+
+use arg_enum_proc_macro::ArgEnum;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
+
+/// Distortion between a source block and its prediction or reconstruction
+pub trait DistortionMetric {
+  fn distortion<T: Pixel>(
+    &self, org: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>, w: usize,
+    h: usize, bit_depth: usize
+  ) -> u64;
+}
+
+/// Sum of absolute differences
+#[derive(Clone, Copy, Debug)]
+pub struct Sad;
+
+impl DistortionMetric for Sad {
+  fn distortion<T: Pixel>(
+    &self, org: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>, w: usize,
+    h: usize, bit_depth: usize
+  ) -> u64 {
+    get_sad(org, rec, w, h, bit_depth) as u64
+  }
+}
+
+/// Sum of absolute Hadamard-transformed differences, on the scale of the SAD
+#[derive(Clone, Copy, Debug)]
+pub struct Satd;
+
+impl DistortionMetric for Satd {
+  fn distortion<T: Pixel>(
+    &self, org: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>, w: usize,
+    h: usize, _bit_depth: usize
+  ) -> u64 {
+    get_satd(org, rec, w, h) as u64
+  }
+}
+
+/// Sum of squared errors
+#[derive(Clone, Copy, Debug)]
+pub struct Sse;
+
+impl DistortionMetric for Sse {
+  fn distortion<T: Pixel>(
+    &self, org: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>, w: usize,
+    h: usize, _bit_depth: usize
+  ) -> u64 {
+    sse_wxh(org, rec, w, h)
+  }
+}
+
+/// Another metric scaled by `weight`, in 1/8 units, as the metrics of
+/// `EncoderConfig` are
+#[derive(Clone, Copy, Debug)]
+pub struct Weighted<M: DistortionMetric> {
+  pub metric: M,
+  pub weight: u32,
+}
+
+impl<M: DistortionMetric> DistortionMetric for Weighted<M> {
+  fn distortion<T: Pixel>(
+    &self, org: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>, w: usize,
+    h: usize, bit_depth: usize
+  ) -> u64 {
+    self.metric.distortion(org, rec, w, h, bit_depth) * self.weight as u64 / 8
+  }
+}
+
+/// The metrics selectable in `EncoderConfig`
+///
+/// The motion search weighs its distortion against the rate on the scale of
+/// the SAD, and the rate-distortion decisions on the scale of the SSE, so a
+/// metric on another scale shifts the trade-off between rate and quality.
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[repr(C)]
+pub enum Distortion {
+  Sad,
+  Satd,
+  Sse,
+}
+
+impl DistortionMetric for Distortion {
+  #[inline(always)]
+  fn distortion<T: Pixel>(
+    &self, org: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>, w: usize,
+    h: usize, bit_depth: usize
+  ) -> u64 {
+    match self {
+      Distortion::Sad => Sad.distortion(org, rec, w, h, bit_depth),
+      Distortion::Satd => Satd.distortion(org, rec, w, h, bit_depth),
+      Distortion::Sse => Sse.distortion(org, rec, w, h, bit_depth),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::frame::Plane;
+
+  #[test]
+  fn metrics_of_a_flat_residual() {
+    let org = Plane::<u8>::wrap(vec![10; 64], 8);
+    let rec = Plane::<u8>::wrap(vec![7; 64], 8);
+    let (org, rec) = (org.as_region(), rec.as_region());
+    assert_eq!(Sad.distortion(&org, &rec, 8, 8, 8), 3 * 64);
+    assert_eq!(Sse.distortion(&org, &rec, 8, 8, 8), 9 * 64);
+    // A flat residual only has a DC coefficient per 4x4 block
+    assert_eq!(Satd.distortion(&org, &rec, 8, 8, 8), 3 * 16 * 4 / 2);
+    let half = Weighted { metric: Sse, weight: 4 };
+    assert_eq!(half.distortion(&org, &rec, 8, 8, 8), 9 * 32);
+    assert_eq!(Distortion::Sse.distortion(&org, &rec, 8, 8, 8), 9 * 64);
+  }
+}
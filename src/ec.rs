@@ -452,6 +452,38 @@ impl WriterBase<WriterRecorder> {
     self.s.storage.truncate(0);
     self.s.bytes = 0;
   }
+  /// Replays like replay(), but first stores the tokens of each of
+  /// `splices` in front of the token at its position in this Recorder.
+  /// Splices must be sorted by position.
+  pub fn replay_spliced(
+    &mut self, dest: &mut dyn StorageBackend,
+    splices: &[(usize, WriterBase<WriterRecorder>)]
+  ) {
+    let mut j = 0;
+    for i in 0..=self.s.storage.len() {
+      while j < splices.len() && splices[j].0 == i {
+        for &(fl, fh, nms) in splices[j].1.s.storage.iter() {
+          dest.store(fl, fh, nms);
+        }
+        j += 1;
+      }
+      if let Some(&(fl, fh, nms)) = self.s.storage.get(i) {
+        dest.store(fl, fh, nms);
+      }
+    }
+    debug_assert!(j == splices.len());
+    self.rng = 0x8000;
+    self.cnt = -9;
+    self.s.storage.truncate(0);
+    self.s.bytes = 0;
+  }
+}
+
+impl WriterCheckpoint {
+  /// Number of tokens stored to date, for a Recorder checkpoint
+  pub fn recorded_tokens(&self) -> usize {
+    self.backend_var
+  }
 }
 
 /// Done implementation specific to the Encoder
@@ -1094,4 +1126,30 @@ mod test {
     assert_eq!(r.symbol(&cdf), 2);
     assert_eq!(r.symbol(&cdf), 2);
   }
+
+  #[test]
+  fn replay_spliced() {
+    let cdf = [7296, 3819, 1716, 0];
+
+    let mut rec = WriterRecorder::new();
+    rec.symbol(0, &cdf);
+    rec.symbol(1, &cdf);
+    let mut first = WriterRecorder::new();
+    first.bool(true, 2);
+    let mut last = WriterRecorder::new();
+    last.symbol(2, &cdf);
+    last.bool(false, 3);
+
+    let mut w = WriterEncoder::new();
+    rec.replay_spliced(&mut w, &[(0, first), (2, last)]);
+    let b = w.done();
+
+    let mut r = Reader::new(&b);
+
+    assert_eq!(r.bool(2), true);
+    assert_eq!(r.symbol(&cdf), 0);
+    assert_eq!(r.symbol(&cdf), 1);
+    assert_eq!(r.symbol(&cdf), 2);
+    assert_eq!(r.bool(3), false);
+  }
 }
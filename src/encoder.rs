@@ -7,23 +7,33 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use crate::activity::ActivityMask;
 use crate::api::*;
+use crate::block_log::{self, BlockDecision};
 use crate::cdef::*;
 use crate::context::*;
 use crate::deblock::*;
+use crate::dist::Distortion;
 use crate::ec::*;
+use crate::levels::seq_level_idx_and_tier;
 use crate::lrf::*;
 use crate::mc::MotionVector;
 use crate::me::*;
 use crate::partition::*;
-use crate::predict::PredictionMode;
+use crate::predict::{CompoundParams, FilterIntraMode, PredictionMode};
+use crate::film_grain::FilmGrainParams;
+use crate::intrabc::*;
+use crate::palette::Palette;
 use crate::frame::*;
 use crate::quantize::*;
+use crate::rate::reservoir_frame_delay;
 use crate::rate::QuantizerParameters;
 use crate::rate::FRAME_SUBTYPE_I;
 use crate::rate::FRAME_SUBTYPE_P;
 use crate::rdo::*;
 use crate::segmentation::*;
+use crate::threading::*;
+use crate::timing::{Stage, StageTimer, StageTimes};
 use crate::tiling::*;
 use crate::transform::*;
 use crate::util::*;
@@ -34,7 +44,6 @@ use crate::header::*;
 use arg_enum_proc_macro::ArgEnum;
 use bitstream_io::{BitWriter, BigEndian};
 use bincode::{serialize, deserialize};
-use rayon::iter::*;
 use std;
 use std::{fmt, io, mem};
 use std::io::Write;
@@ -42,6 +51,8 @@ use std::io::Read;
 use std::sync::Arc;
 use std::fs::File;
 use arrayvec::*;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 pub static TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
 
@@ -50,17 +61,29 @@ const MAX_NUM_SPATIAL_LAYERS: usize = 4;
 const MAX_NUM_OPERATING_POINTS: usize = MAX_NUM_TEMPORAL_LAYERS * MAX_NUM_SPATIAL_LAYERS;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct ReferenceFrame<T: Pixel> {
   pub order_hint: u32,
+  /// Size of the frame in luma samples, after the superres upscaling
+  pub width: usize,
+  pub height: usize,
   pub frame: Frame<T>,
   pub input_hres: Plane<T>,
   pub input_qres: Plane<T>,
   pub cdfs: CDFContext,
+  /// How the frame was coded, which its saved CDFs are adapted to
+  pub base_q_idx: u8,
+  pub pyramid_level: u64,
+  pub intra_only: bool,
   pub frame_mvs: Vec<FrameMotionVectors>,
 }
 
 #[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct ReferenceFramesSet<T: Pixel> {
+  /// Shared between frames, so a serialized `Context` stores each reference
+  /// frame once and restores these slots itself
+  #[cfg_attr(feature = "serialize", serde(skip))]
   pub frames: [Option<Arc<ReferenceFrame<T>>>; (REF_FRAMES as usize)],
   pub deblock: [DeblockState; (REF_FRAMES as usize)]
 }
@@ -77,10 +100,16 @@ impl<T: Pixel> ReferenceFramesSet<T> {
 
 
 #[derive(ArgEnum, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum Tune {
   Psnr,
-  Psychovisual
+  Psychovisual,
+  /// Computer generated content, such as desktop captures
+  Screen,
+  /// Weight the distortion of each block by its local variance, as SSIM
+  /// does, spending more bits on the flat areas
+  Ssim
 }
 
 impl Default for Tune {
@@ -89,10 +118,42 @@ impl Default for Tune {
   }
 }
 
+/// Quantizer index above which the rate control is considered starved of
+/// bits, and key frames get downscaled with `SuperResMode::Auto`
+const SUPERRES_AUTO_QTHRESH: u8 = 192;
+
+/// Reference slot of the golden frame in low latency encodes, where the
+/// short-term references only cycle through slots 0 to 3
+const GOLDEN_SLOT: u8 = 7;
+
+/// Reference slot of the frames kept as long-term references, which neither
+/// the low latency nor the pyramid structures use
+const LONG_TERM_SLOT: u8 = 6;
+
+/// Temporal layer of the frame at `order_hint` from its key frame, out of
+/// `layers`: one frame out of `1 << (layers - 1)` is in layer 0, the frames
+/// halfway between them are in layer 1, and so on up to the odd frames in
+/// the top layer. Each layer is held in the reference slot of its number.
+fn temporal_id(order_hint: u32, layers: usize) -> u8 {
+  let period = 1 << (layers - 1);
+  if order_hint % period == 0 {
+    0
+  } else {
+    (layers - 1 - order_hint.trailing_zeros() as usize) as u8
+  }
+}
+
+/// Distance in luma pixels the motion vectors constrained to an area of the
+/// reference, by the intra refresh or the tiles, keep from its edges. It
+/// covers the reach of the subpixel filters, and of the loop filters which
+/// spread the pixels from outside the area across its edges.
+pub(crate) const MV_FILTER_MARGIN: usize = 32;
+
 const FRAME_ID_LENGTH: u32 = 15;
 const DELTA_FRAME_ID_LENGTH: u32 = 14;
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Sequence {
   // OBU Sequence header of AV1
   pub profile: u8,
@@ -136,24 +197,67 @@ pub struct Sequence {
   // 1 - enable it
   pub enable_warped_motion: bool,   // 0 - disable warped motion for sequence
   // 1 - enable it for the sequence
-  pub enable_superres: bool,// 0 - Disable superres for the sequence, and disable
-  //     transmitting per-frame superres enabled flag.
-  // 1 - Enable superres for the sequence, and also
-  //     enable per-frame flag to denote if superres is
-  //     enabled for that frame.
+  pub enable_superres: bool,// Set unless `SuperResMode::None` is configured,
+  // transmitting the per-frame superres flag. Only
+  // key frames are ever coded downscaled, see
+  // `FrameInvariants::set_superres`.
   pub enable_cdef: bool,         // To turn on/off CDEF
   pub enable_restoration: bool,  // To turn on/off loop restoration
   pub operating_points_cnt_minus_1: usize,
   pub operating_point_idc: [u16; MAX_NUM_OPERATING_POINTS],
   pub display_model_info_present_flag: bool,
+  /// Timing info, always with an equal picture interval of one display tick
+  pub timing_info: Option<TimingInfo>,
+  /// Set along with the timing info, for operating point 0
   pub decoder_model_info_present_flag: bool,
-  pub level: [[usize; 2]; MAX_NUM_OPERATING_POINTS],	// minor, major
-  pub tier: [usize; MAX_NUM_OPERATING_POINTS],  // seq_tier in the spec. One bit: 0
-  // or 1.
+  pub seq_level_idx: [u8; MAX_NUM_OPERATING_POINTS],
+  pub tier: [bool; MAX_NUM_OPERATING_POINTS],  // seq_tier in the spec, high if set
   pub film_grain_params_present: bool,
   pub separate_uv_delta_q: bool,
 }
 
+/// Sequence timing info and the decoder model of its only operating point
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct TimingInfo {
+  pub num_units_in_display_tick: u32,
+  pub time_scale: u32,
+  /// Time the decoder waits before removing the first frame from the
+  /// smoothing buffer, in units of 1/90000 seconds
+  pub decoder_buffer_delay: u32,
+  /// Time the encoder holds data back, in units of 1/90000 seconds
+  pub encoder_buffer_delay: u32,
+}
+
+impl TimingInfo {
+  /// Derives the buffer delays from the rate control reservoir, which starts
+  /// half full, so that a smoothing buffer of the reservoir size filled at
+  /// the target bitrate neither overflows nor underflows
+  pub fn new(config: &EncoderConfig) -> Option<TimingInfo> {
+    if !config.decoder_model_info || config.bitrate <= 0 {
+      return None;
+    }
+    let time_base = config.time_base;
+    if time_base.num == 0 || time_base.num > u32::max_value() as u64
+      || time_base.den == 0 || time_base.den > u32::max_value() as u64 {
+      return None;
+    }
+    let reservoir_frame_delay = reservoir_frame_delay(
+      config.max_key_frame_interval as i32,
+      config.reservoir_frame_delay
+    ) as u64;
+    let buffer_delay = ((reservoir_frame_delay * 90000)
+      .saturating_mul(time_base.num) / time_base.den)
+      .min(u32::max_value() as u64) as u32;
+    Some(TimingInfo {
+      num_units_in_display_tick: time_base.num as u32,
+      time_scale: time_base.den as u32,
+      decoder_buffer_delay: (buffer_delay + 1) >> 1,
+      encoder_buffer_delay: buffer_delay >> 1,
+    })
+  }
+}
+
 impl Sequence {
   pub fn new(config: &EncoderConfig) -> Sequence {
     let width_bits = 32 - (config.width as u32).leading_zeros();
@@ -170,16 +274,22 @@ impl Sequence {
       0
     };
 
+    // With temporal layers, operating point i decodes all but the top i
+    // layers, of the only spatial layer
     let mut operating_point_idc = [0 as u16; MAX_NUM_OPERATING_POINTS];
-    let mut level = [[1, 2 as usize]; MAX_NUM_OPERATING_POINTS];
-    let mut tier = [0 as usize; MAX_NUM_OPERATING_POINTS];
-
-    for i in 0..MAX_NUM_OPERATING_POINTS {
-      operating_point_idc[i] = 0;
-      level[i][0] = 1;    // minor
-      level[i][1] = 2;    // major
-      tier[i] = 0;
+    let layers = config.temporal_layers;
+    if layers > 1 {
+      for (i, idc) in operating_point_idc[..layers].iter_mut().enumerate() {
+        *idc = (1 << 8) | ((1 << (layers - i)) - 1);
+      }
     }
+    let (level_idx, high_tier) = seq_level_idx_and_tier(config.level);
+    let seq_level_idx = [level_idx; MAX_NUM_OPERATING_POINTS];
+    let tier = [high_tier; MAX_NUM_OPERATING_POINTS];
+
+    // A reduced still picture header has no timing info
+    let timing_info =
+      if config.still_picture { None } else { TimingInfo::new(config) };
 
     Sequence {
       profile,
@@ -199,34 +309,45 @@ impl Sequence {
       delta_frame_id_length: DELTA_FRAME_ID_LENGTH,
       use_128x128_superblock: false,
       order_hint_bits_minus_1: 5,
-      force_screen_content_tools: 0,
+      // Screen content tools are signaled per frame, since they are only
+      // enabled on the segments detected as screen content
+      force_screen_content_tools: 2,
       force_integer_mv: 2,
-      still_picture: false,
-      reduced_still_picture_hdr: false,
-      enable_filter_intra: false,
+      still_picture: config.still_picture,
+      reduced_still_picture_hdr: config.still_picture,
+      enable_filter_intra: config.speed_settings.filter_intra,
       enable_intra_edge_filter: false,
       enable_interintra_compound: false,
-      enable_masked_compound: false,
+      enable_masked_compound: config.speed_settings.masked_compound,
       enable_dual_filter: false,
-      enable_order_hint: true,
-      enable_jnt_comp: false,
+      // Order hints are not coded in still pictures
+      enable_order_hint: !config.still_picture,
+      enable_jnt_comp: config.speed_settings.dist_wtd_compound
+        && !config.still_picture,
       enable_ref_frame_mvs: false,
       enable_warped_motion: false,
-      enable_superres: false,
-      enable_cdef: config.speed_settings.cdef && config.chroma_sampling != ChromaSampling::Cs422,
-      enable_restoration: config.chroma_sampling != ChromaSampling::Cs422 &&
-        config.chroma_sampling != ChromaSampling::Cs444, // FIXME: not working yet
-      operating_points_cnt_minus_1: 0,
+      enable_superres: config.superres != SuperResMode::None,
+      enable_cdef: config.speed_settings.cdef,
+      enable_restoration: true,
+      operating_points_cnt_minus_1: layers.max(1) - 1,
       operating_point_idc,
       display_model_info_present_flag: false,
-      decoder_model_info_present_flag: false,
-      level,
+      timing_info,
+      decoder_model_info_present_flag: timing_info.is_some(),
+      seq_level_idx,
       tier,
-      film_grain_params_present: false,
-      separate_uv_delta_q: true,
+      film_grain_params_present: config.denoise_strength > 0
+        || config.film_grain_table.is_some(),
+      // Monochrome streams never signal separate_uv_delta_q
+      separate_uv_delta_q: config.chroma_sampling != ChromaSampling::Cs400,
     }
   }
 
+  /// Number of coded planes, only luma being coded in monochrome
+  pub fn num_planes(&self) -> usize {
+    if self.chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 }
+  }
+
   pub fn get_relative_dist(&self, a: u32, b: u32) -> i32 {
     let diff = a as i32 - b as i32;
     let m = 1 << self.order_hint_bits_minus_1;
@@ -304,14 +425,34 @@ pub struct FrameState<T: Pixel> {
   pub input_hres: Plane<T>, // half-resolution version of input luma
   pub input_qres: Plane<T>, // quarter-resolution version of input luma
   pub rec: Frame<T>,
+  /// Prediction of every block, if `output_prediction` is set
+  pub pred: Option<Frame<T>>,
   pub cdfs: CDFContext,
   pub context_update_tile_id: usize, // tile id used for the CDFontext
   pub max_tile_size_bytes: u32,
   pub deblock: DeblockState,
   pub segmentation: SegmentationState,
+  /// Segment chosen for each block, coded when segmentation is enabled
+  pub segment_ids: SegmentIds,
+  /// Quantizer offset chosen for each superblock, coded with delta q
+  pub sb_qindex_offsets: SuperBlockQindexOffsets,
+  /// Mean variance of the 8x8 source luma blocks, against which
+  /// `Tune::Ssim` weights the distortion of each block
+  pub mean_block_variance: f64,
+  /// Distortion weight of each block, with activity masking
+  pub activity_mask: ActivityMask,
   pub restoration: RestorationState,
   pub frame_mvs: Vec<FrameMotionVectors>,
   pub t: RDOTracker,
+  /// Lowest cost of the coarse motion search, summed over superblocks, 0
+  /// for intra frames
+  pub me_cost: u64,
+  /// Time spent in each stage, if `time_stages` is set
+  pub stage_times: StageTimes,
+  /// Source blocks indexed by content, for the IntraBC search
+  pub intrabc_hash: Option<BlockHashMap>,
+  /// Coding decisions of every block, if `log_block_decisions` is set
+  pub block_decisions: Vec<BlockDecision>,
 }
 
 impl<T: Pixel> FrameState<T> {
@@ -322,6 +463,22 @@ impl<T: Pixel> FrameState<T> {
   }
 
   pub fn new_with_frame(fi: &FrameInvariants<T>, frame: Arc<Frame<T>>) -> Self {
+    let frame = if fi.superres_denom != SUPERRES_NUM {
+      let mut downscaled =
+        Frame::new(fi.width, fi.height, fi.sequence.chroma_sampling);
+      for (dst, src) in downscaled.planes.iter_mut().zip(frame.planes.iter()) {
+        let xdec = src.cfg.xdec;
+        dst.downscale_width_from(
+          src,
+          (fi.upscaled_width + xdec) >> xdec,
+          (fi.width + xdec) >> xdec
+        );
+      }
+      downscaled.pad(fi.width, fi.height);
+      Arc::new(downscaled)
+    } else {
+      frame
+    };
     let rs = RestorationState::new(fi, &frame);
     let luma_width = frame.planes[0].cfg.width;
     let luma_height = frame.planes[0].cfg.height;
@@ -334,11 +491,20 @@ impl<T: Pixel> FrameState<T> {
       input_hres: Plane::new(luma_width / 2, luma_height / 2, 1, 1, luma_padding_x / 2, luma_padding_y / 2),
       input_qres: Plane::new(luma_width / 4, luma_height / 4, 2, 2, luma_padding_x / 4, luma_padding_y / 4),
       rec: Frame::new(luma_width, luma_height, fi.sequence.chroma_sampling),
+      pred: if fi.config.output_prediction {
+        Some(Frame::new(luma_width, luma_height, fi.sequence.chroma_sampling))
+      } else {
+        None
+      },
       cdfs: CDFContext::new(0),
       context_update_tile_id: 0,
       max_tile_size_bytes: 0,
       deblock: Default::default(),
       segmentation: Default::default(),
+      segment_ids: Default::default(),
+      sb_qindex_offsets: Default::default(),
+      mean_block_variance: 0.0,
+      activity_mask: Default::default(),
       restoration: rs,
       frame_mvs: {
         let mut vec = Vec::with_capacity(REF_FRAMES);
@@ -347,10 +513,43 @@ impl<T: Pixel> FrameState<T> {
         }
         vec
       },
-      t: RDOTracker::new()
+      t: RDOTracker::new(),
+      me_cost: 0,
+      stage_times: StageTimes::default(),
+      intrabc_hash: None,
+      block_decisions: Vec::new(),
     }
   }
 
+  /// Upscales the reconstruction of a frame coded with superres to the
+  /// output size, and puts back the full size source for the analysis of
+  /// the frames that will reference it.
+  pub fn superres_upscale(
+    &mut self, fi: &FrameInvariants<T>, source: Arc<Frame<T>>
+  ) {
+    debug_assert!(fi.superres_denom != SUPERRES_NUM);
+    let mut rec =
+      Frame::new(fi.upscaled_width, fi.height, fi.sequence.chroma_sampling);
+    for (dst, src) in rec.planes.iter_mut().zip(self.rec.planes.iter()) {
+      superres_upscale_plane(
+        src, dst, fi.width, fi.upscaled_width, fi.sequence.bit_depth
+      );
+    }
+    self.rec = rec;
+
+    let mut full_fi = fi.clone();
+    full_fi.set_superres_denom(SUPERRES_NUM);
+    let full = FrameState::new_with_frame(&full_fi, source);
+    self.input = full.input;
+    self.input_hres = full.input_hres;
+    self.input_hres.downsample_from(&self.input.planes[0]);
+    self.input_hres.pad(fi.upscaled_width, fi.height);
+    self.input_qres = full.input_qres;
+    self.input_qres.downsample_from(&self.input_hres);
+    self.input_qres.pad(fi.upscaled_width, fi.height);
+    self.frame_mvs = full.frame_mvs;
+  }
+
   #[inline(always)]
   pub fn as_tile_state_mut(&mut self) -> TileStateMut<'_, T> {
     let PlaneConfig { width, height, .. } = self.rec.planes[0].cfg;
@@ -360,6 +559,7 @@ impl<T: Pixel> FrameState<T> {
 }
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct DeblockState {
   pub levels: [u8; PLANES+1],  // Y vertical edges, Y horizontal, U, V
   pub sharpness: u8,
@@ -416,10 +616,16 @@ impl Default for SegmentationState {
 // Frame Invariants are invariant inside a frame
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FrameInvariants<T: Pixel> {
   pub sequence: Sequence,
+  /// Coded width, which is smaller than the output width with superres
   pub width: usize,
   pub height: usize,
+  /// Output width, which the decoder upscales the coded width to
+  pub upscaled_width: usize,
+  /// Superres denominator, `SUPERRES_NUM` when the frame is not downscaled
+  pub superres_denom: u8,
   pub sb_width: usize,
   pub sb_height: usize,
   pub w_in_b: usize,
@@ -439,6 +645,7 @@ pub struct FrameInvariants<T: Pixel> {
   pub reference_mode: ReferenceMode,
   pub use_prev_frame_mvs: bool,
   pub min_partition_size: BlockSize,
+  pub max_partition_size: BlockSize,
   pub globalmv_transformation_type: [GlobalMVMode; INTER_REFS_PER_FRAME],
   pub num_tg: usize,
   pub large_scale_tile: bool,
@@ -467,6 +674,11 @@ pub struct FrameInvariants<T: Pixel> {
   pub base_q_idx: u8,
   pub dc_delta_q: [i8; 3],
   pub ac_delta_q: [i8; 3],
+  pub using_qmatrix: bool,
+  /// Quantizer matrix levels of the Y, U and V planes when `using_qmatrix`
+  pub qm_y: u8,
+  pub qm_u: u8,
+  pub qm_v: u8,
   pub lambda: f64,
   pub me_lambda: f64,
   pub me_range_scale: u8,
@@ -474,8 +686,16 @@ pub struct FrameInvariants<T: Pixel> {
   pub use_tx_domain_rate: bool,
   pub idx_in_group_output: u64,
   pub pyramid_level: u64,
+  /// Temporal layer, 0 unless `temporal_layers` is set in the configuration
+  pub temporal_id: u8,
   pub enable_early_exit: bool,
   pub tx_mode_select: bool,
+  pub film_grain_params: Option<FilmGrainParams>,
+  /// Parameters supplied with the input frame
+  pub frame_params: Option<Arc<FrameParameters>>,
+  /// Reference holding the previous input frame, when this frame repeats it
+  /// and is coded with skip blocks copying it
+  pub duplicate_ref: Option<RefType>,
 }
 
 pub(crate) fn pos_to_lvl(pos: u64, pyramid_depth: u64) -> u64 {
@@ -498,9 +718,17 @@ impl<T: Pixel> FrameInvariants<T> {
     // At speed = 0, RDO search is exhaustive.
     let min_partition_size = config.speed_settings.min_block_size;
     assert!(min_partition_size.is_sqr());
+    let max_partition_size = config.speed_settings.max_block_size;
+    assert!(max_partition_size.is_sqr());
+    assert!(config.speed_settings.cdef_strengths_log2 <= 3);
     let use_reduced_tx_set = config.speed_settings.reduced_tx_set;
-    let use_tx_domain_distortion = config.tune == Tune::Psnr && config.speed_settings.tx_domain_distortion;
+    // The transform domain distortion is a squared error
+    let use_tx_domain_distortion = config.tune == Tune::Psnr
+      && config.rdo_distortion == Distortion::Sse
+      && config.rdo_distortion_weight == 8
+      && config.speed_settings.tx_domain_distortion;
     let use_tx_domain_rate = config.speed_settings.tx_domain_rate;
+    let tx_mode_select = config.speed_settings.rdo_tx_decision;
 
     let w_in_b = 2 * config.width.align_power_of_two_and_shift(3); // MiCols, ((width+7)/8)<<3 >> MI_SIZE_LOG2
     let h_in_b = 2 * config.height.align_power_of_two_and_shift(3); // MiRows, ((height+7)/8)<<3 >> MI_SIZE_LOG2
@@ -561,6 +789,8 @@ impl<T: Pixel> FrameInvariants<T> {
       sequence,
       width: config.width,
       height: config.height,
+      upscaled_width: config.width,
+      superres_denom: SUPERRES_NUM,
       sb_width: config.width.align_power_of_two_and_shift(6),
       sb_height: config.height.align_power_of_two_and_shift(6),
       w_in_b,
@@ -580,6 +810,7 @@ impl<T: Pixel> FrameInvariants<T> {
       reference_mode: ReferenceMode::SINGLE,
       use_prev_frame_mvs: false,
       min_partition_size,
+      max_partition_size,
       globalmv_transformation_type: [GlobalMVMode::IDENTITY; INTER_REFS_PER_FRAME],
       num_tg: 1,
       large_scale_tile: false,
@@ -595,7 +826,7 @@ impl<T: Pixel> FrameInvariants<T> {
       disable_frame_end_update_cdf: false,
       allow_warped_motion: false,
       cdef_damping: 3,
-      cdef_bits: 3,
+      cdef_bits: config.speed_settings.cdef_strengths_log2,
       cdef_y_strengths: [0*4+0, 1*4+0, 2*4+1, 3*4+1, 5*4+2, 7*4+3, 10*4+3, 13*4+3],
       cdef_uv_strengths: [0*4+0, 1*4+0, 2*4+1, 3*4+1, 5*4+2, 7*4+3, 10*4+3, 13*4+3],
       delta_q_present: false,
@@ -605,6 +836,10 @@ impl<T: Pixel> FrameInvariants<T> {
       base_q_idx: config.quantizer as u8,
       dc_delta_q: [0; 3],
       ac_delta_q: [0; 3],
+      using_qmatrix: config.qm_levels.is_some(),
+      qm_y: config.qm_levels.map_or(QM_LEVEL_FLAT, |levels| levels[0]),
+      qm_u: config.qm_levels.map_or(QM_LEVEL_FLAT, |levels| levels[1]),
+      qm_v: config.qm_levels.map_or(QM_LEVEL_FLAT, |levels| levels[2]),
       lambda: 0.0,
       me_lambda: 0.0,
       me_range_scale: 1,
@@ -612,36 +847,55 @@ impl<T: Pixel> FrameInvariants<T> {
       use_tx_domain_rate,
       idx_in_group_output: 0,
       pyramid_level: 0,
+      temporal_id: 0,
       enable_early_exit: true,
       config,
-      tx_mode_select : false,
+      tx_mode_select,
+      film_grain_params: None,
+      frame_params: None,
+      duplicate_ref: None,
     }
   }
 
   pub fn new_key_frame(previous_fi: &Self,
    segment_input_frameno_start: u64) -> Self {
     let mut fi = previous_fi.clone();
+    fi.set_superres_denom(SUPERRES_NUM);
     fi.frame_type = FrameType::KEY;
     fi.intra_only = true;
     fi.idx_in_group_output = 0;
     fi.pyramid_level = 0;
+    fi.temporal_id = 0;
     fi.order_hint = 0;
     fi.refresh_frame_flags = ALL_REF_FRAMES_MASK;
     fi.show_frame = true;
     fi.show_existing_frame = false;
     fi.frame_to_show_map_idx = 0;
     fi.primary_ref_frame = PRIMARY_REF_NONE;
+    fi.error_resilient = false;
+    fi.duplicate_ref = None;
     fi.input_frameno = segment_input_frameno_start;
     for i in 0..INTER_REFS_PER_FRAME {
       fi.ref_frames[i] = 0;
     }
-
-    // Until has_tr() and has_bl() is fixed to use partition info, disable intra tx partition
-    fi.tx_mode_select = false;
+    let screen_content = fi.config.tune == Tune::Screen;
+    fi.set_screen_content(screen_content);
 
     fi
   }
 
+  /// Enables the coding tools targeting screen content for this key frame
+  /// and the inter frames that follow it. IntraBC is only enabled by the
+  /// screen tune or when requested in the configuration, not by detection.
+  pub(crate) fn set_screen_content(&mut self, screen_content: bool) {
+    assert!(self.frame_type == FrameType::KEY);
+    let screen_content = screen_content || self.config.enable_intrabc;
+    self.allow_screen_content_tools = screen_content as u32;
+    self.allow_intrabc =
+      self.config.enable_intrabc || self.config.tune == Tune::Screen;
+    self.force_integer_mv = screen_content as u32;
+  }
+
   /// Returns the created FrameInvariants along with a bool indicating success.
   /// This interface provides simpler usage, because we always need the produced
   /// FrameInvariants regardless of success or failure.
@@ -651,11 +905,15 @@ impl<T: Pixel> FrameInvariants<T> {
     next_keyframe_input_frameno: u64
   ) -> (Self, bool) {
     let mut fi = previous_fi.clone();
+    fi.set_superres_denom(SUPERRES_NUM);
     fi.frame_type = FrameType::INTER;
     fi.intra_only = false;
+    // Screen content tools stay enabled until the next key frame
+    fi.allow_intrabc = false;
+    fi.force_integer_mv = 0;
+    fi.duplicate_ref = None;
     fi.idx_in_group_output =
      inter_cfg.get_idx_in_group_output(output_frameno_in_segment);
-    fi.tx_mode_select = false;
 
     fi.order_hint = inter_cfg.get_order_hint(output_frameno_in_segment,
      fi.idx_in_group_output);
@@ -688,12 +946,29 @@ impl<T: Pixel> FrameInvariants<T> {
     };
     let ref_in_previous_group = LAST3_FRAME;
 
-    // reuse probability estimates from previous frames only in top level frames
-    fi.primary_ref_frame = if fi.pyramid_level > 0 {
-      PRIMARY_REF_NONE
-    } else {
-      (ref_in_previous_group.to_index()) as u32
-    };
+    // Error resilient frames start from the default probabilities, which
+    // the intra refresh needs for a decoder to recover from a loss
+    fi.error_resilient =
+      fi.config.error_resilient || fi.config.intra_refresh.is_some();
+
+    // The reference to reuse probability estimates from is chosen once the
+    // quantizer is known, in set_cdf_inheritance
+    fi.primary_ref_frame = PRIMARY_REF_NONE;
+
+    let pattern = fi.config.reference_pattern;
+    let low_latency = inter_cfg.pyramid_depth == 0;
+    // The intra refresh only predicts from the previous frame, the area it
+    // refreshed being tracked from one frame to the next
+    let intra_refresh = fi.config.intra_refresh.is_some();
+    let golden = low_latency && pattern.golden_interval > 0 && !intra_refresh;
+    let last_and_golden_only =
+      low_latency && (pattern.last_and_golden_only || intra_refresh);
+    if golden
+      && !fi.show_existing_frame
+      && fi.order_hint as u64 % pattern.golden_interval == 0
+    {
+      fi.refresh_frame_flags |= 1 << GOLDEN_SLOT;
+    }
 
     for i in 0..INTER_REFS_PER_FRAME {
       fi.ref_frames[i] = if fi.pyramid_level == 0 {
@@ -727,16 +1002,12 @@ impl<T: Pixel> FrameInvariants<T> {
           3 + lvl1 as u8
         }
       };
-      fi.ref_frame_sign_bias[i] = if !fi.sequence.enable_order_hint {
-        false
-      } else if let Some(ref rec) =
-        fi.rec_buffer.frames[fi.ref_frames[i] as usize]
-      {
-        let hint = rec.order_hint;
-        fi.sequence.get_relative_dist(hint, fi.order_hint) > 0
-      } else {
-        false
-      };
+      if golden && i == GOLDEN_FRAME.to_index() {
+        fi.ref_frames[i] = GOLDEN_SLOT;
+      } else if last_and_golden_only {
+        fi.ref_frames[i] = (slot_idx + 4 - 1) as u8 % 4;
+      }
+      fi.ref_frame_sign_bias[i] = fi.get_ref_frame_sign_bias(i);
     }
 
     fi.reference_mode = if inter_cfg.multiref && fi.idx_in_group_output != 0 {
@@ -744,13 +1015,175 @@ impl<T: Pixel> FrameInvariants<T> {
     } else {
       ReferenceMode::SINGLE
     };
+
+    // With temporal layers, a frame only predicts from the last frame of
+    // its own or a lower layer, and only refreshes the slot of its layer,
+    // so that the lower layers still decode once the upper ones are dropped
+    let layers = fi.config.temporal_layers;
+    if layers > 1 && low_latency {
+      fi.temporal_id = temporal_id(fi.order_hint, layers);
+      let ref_order_hint =
+        fi.order_hint - (1 << (layers - 1 - fi.temporal_id as usize));
+      let ref_slot = temporal_id(ref_order_hint, layers);
+      fi.refresh_frame_flags = 1 << fi.temporal_id;
+      for i in 0..INTER_REFS_PER_FRAME {
+        fi.ref_frames[i] = ref_slot;
+        fi.ref_frame_sign_bias[i] = fi.get_ref_frame_sign_bias(i);
+      }
+      fi.reference_mode = ReferenceMode::SINGLE;
+    }
     fi.input_frameno = input_frameno;
     fi.me_range_scale = (inter_cfg.group_input_len >> fi.pyramid_level) as u8;
     (fi, true)
   }
 
+  fn get_ref_frame_sign_bias(&self, i: usize) -> bool {
+    if !self.sequence.enable_order_hint {
+      false
+    } else if let Some(ref rec) =
+      self.rec_buffer.frames[self.ref_frames[i] as usize]
+    {
+      let hint = rec.order_hint;
+      self.sequence.get_relative_dist(hint, self.order_hint) > 0
+    } else {
+      false
+    }
+  }
+
+  /// Input frame numbers of the distinct frames held in the reference slots
+  /// this frame predicts from, in increasing order
+  pub(crate) fn reference_framenos(&self) -> Vec<u64> {
+    if self.intra_only || self.show_existing_frame {
+      return Vec::new();
+    }
+    // Order hints grow with the input frame number from the last key frame
+    let mut framenos: Vec<u64> = self
+      .ref_frames
+      .iter()
+      .filter_map(|&slot| self.rec_buffer.frames[slot as usize].as_ref())
+      .map(|rec| {
+        (self.input_frameno as i64 + rec.order_hint as i64
+          - self.order_hint as i64) as u64
+      })
+      .collect();
+    framenos.sort();
+    framenos.dedup();
+    framenos
+  }
+
+  /// Applies the reference control requested along with the input frame of
+  /// an inter frame: keeps it as a long-term reference, and restricts its
+  /// prediction to a single earlier frame still held in a reference slot.
+  pub(crate) fn set_reference_control(&mut self, params: &FrameParameters) {
+    // Temporal layers fix the references of every frame
+    if self.intra_only || self.show_existing_frame
+      || self.config.temporal_layers > 1
+    {
+      return;
+    }
+    if params.long_term_reference {
+      self.refresh_frame_flags |= 1 << LONG_TERM_SLOT;
+    }
+    let ref_frameno = match params.reference_frameno {
+      Some(frameno) if frameno < self.input_frameno => frameno,
+      _ => return
+    };
+    // Order hints grow with the input frame number from the last key frame,
+    // which refreshed all the slots
+    let frames_back = self.input_frameno - ref_frameno;
+    if frames_back > self.order_hint as u64 {
+      return;
+    }
+    let ref_order_hint = self.order_hint - frames_back as u32;
+    let slot = self.rec_buffer.frames.iter().position(|rec| {
+      rec.as_ref().map_or(false, |rec| rec.order_hint == ref_order_hint)
+    });
+    if let Some(slot) = slot {
+      for i in 0..INTER_REFS_PER_FRAME {
+        self.ref_frames[i] = slot as u8;
+        self.ref_frame_sign_bias[i] = self.get_ref_frame_sign_bias(i);
+      }
+      self.reference_mode = ReferenceMode::SINGLE;
+    }
+  }
+
+  /// Codes this inter frame as an intra-only frame, predicting from nothing
+  /// like a key frame but refreshing only its own reference slots, so that
+  /// the frames around it keep their references
+  pub(crate) fn set_intra_only(&mut self) {
+    debug_assert!(
+      self.frame_type == FrameType::INTER && !self.show_existing_frame
+    );
+    self.frame_type = FrameType::INTRA_ONLY;
+    self.intra_only = true;
+    self.primary_ref_frame = PRIMARY_REF_NONE;
+    self.reference_mode = ReferenceMode::SINGLE;
+  }
+
+  /// Span, in luma pixels along the direction of the intra refresh, of the
+  /// superblocks this frame codes intra. The wave has refreshed the area
+  /// before the band.
+  pub(crate) fn intra_refresh_band(
+    &self
+  ) -> Option<(IntraRefreshDirection, usize, usize)> {
+    let refresh = self.config.intra_refresh?;
+    if self.frame_type != FrameType::INTER || self.show_existing_frame {
+      return None;
+    }
+    let size = match refresh.direction {
+      IntraRefreshDirection::Columns => self.width,
+      IntraRefreshDirection::Rows => self.height,
+    };
+    let sb_size_log2 = self.sb_size_log2();
+    let sbs = size.align_power_of_two_and_shift(sb_size_log2) as u64;
+    // Order hints count the frames from the key frame, after which the
+    // first wave starts
+    let pos = (self.order_hint as u64 - 1) % refresh.period;
+    let start = (pos * sbs / refresh.period) as usize;
+    let end = ((pos + 1) * sbs / refresh.period) as usize;
+    Some((
+      refresh.direction,
+      (start << sb_size_log2).min(size),
+      (end << sb_size_log2).min(size),
+    ))
+  }
+
+  /// Codes this frame as a repeat of the previous input frame, if that is
+  /// still held as a reference
+  pub(crate) fn set_duplicate_ref(&mut self) {
+    // A repeated frame would not refresh the band of the intra refresh
+    if self.frame_type != FrameType::INTER || self.show_existing_frame
+      || self.order_hint == 0 || self.config.intra_refresh.is_some()
+    {
+      return;
+    }
+    // Order hints grow with the input frame number from the last key frame
+    let ref_order_hint = self.order_hint - 1;
+    let slot = self.rec_buffer.frames.iter().position(|rec| {
+      rec.as_ref().map_or(false, |rec| rec.order_hint == ref_order_hint)
+    });
+    self.duplicate_ref = slot.and_then(|slot| {
+      ALL_INTER_REFS
+        .iter()
+        .find(|r| self.ref_frames[r.to_index()] as usize == slot)
+        .cloned()
+    });
+  }
+
+  /// Quantizer matrix weights of plane `pli` for a transform of `tx_size`
+  /// and `tx_type`, or `None` if its coefficients are not weighted.
+  pub fn qm_weights(
+    &self, pli: usize, tx_size: TxSize, tx_type: TxType
+  ) -> Option<&'static [u8]> {
+    if !self.using_qmatrix {
+      return None;
+    }
+    let level = [self.qm_y, self.qm_u, self.qm_v][pli];
+    qm_weights(level, pli > 0, tx_size, tx_type)
+  }
+
   pub fn get_frame_subtype(&self) -> usize {
-    if self.frame_type == FrameType::KEY {
+    if self.intra_only {
       FRAME_SUBTYPE_I
     } else {
       FRAME_SUBTYPE_P + (self.pyramid_level as usize)
@@ -759,11 +1192,10 @@ impl<T: Pixel> FrameInvariants<T> {
 
   pub fn set_quantizers(&mut self, qps: &QuantizerParameters) {
     self.base_q_idx = qps.ac_qi[0];
-    if self.frame_type != FrameType::KEY {
-      self.cdef_bits = 3 - ((self.base_q_idx.max(128) - 128) >> 5);
-    } else {
-      self.cdef_bits = 3;
-    }
+    // Superblock deblocking deltas can only be signaled along with delta q
+    self.delta_q_present = (self.config.speed_settings.deblock_deltas
+      || self.config.aq_delta_q)
+      && self.base_q_idx > 0;
     let base_q_idx = self.base_q_idx as i32;
     for pi in 0..3 {
       debug_assert!(qps.dc_qi[pi] as i32 - base_q_idx >= -128);
@@ -778,6 +1210,98 @@ impl<T: Pixel> FrameInvariants<T> {
     self.me_lambda = self.lambda.sqrt();
   }
 
+  /// Chooses the reference whose saved CDFs this frame starts from, and
+  /// whether the CDFs it adapts are saved in turn, once its quantizer is
+  /// known.
+  ///
+  /// Most of the bits go to the coefficients, whose CDFs depend on the
+  /// quantizer, so the reference coded in the closest quantizer context is
+  /// preferred, then an inter frame over an intra one, then one at the same
+  /// pyramid level, whose statistics are alike, then the nearest one. A
+  /// reference more than a context away would cost more than the default
+  /// CDFs of the right context, and is not used.
+  pub fn set_cdf_inheritance(&mut self) {
+    // No later frame starts from the CDFs of an error resilient encode, or
+    // of a frame held in no reference slot
+    self.disable_frame_end_update_cdf =
+      self.config.error_resilient || self.refresh_frame_flags == 0;
+
+    self.primary_ref_frame = PRIMARY_REF_NONE;
+    if self.intra_only || self.error_resilient {
+      return;
+    }
+    let q_ctx = CDFContext::q_ctx(self.base_q_idx) as i32;
+    let mut best = None;
+    for r in ALL_INTER_REFS.iter() {
+      let i = r.to_index();
+      let rec = match self.rec_buffer.frames[self.ref_frames[i] as usize] {
+        Some(ref rec) => rec,
+        None => continue
+      };
+      let q_ctx_dist = (CDFContext::q_ctx(rec.base_q_idx) as i32 - q_ctx).abs();
+      if q_ctx_dist > 1 {
+        continue;
+      }
+      let cost = (
+        q_ctx_dist,
+        rec.intra_only,
+        (rec.pyramid_level as i64 - self.pyramid_level as i64).abs(),
+        self.sequence.get_relative_dist(self.order_hint, rec.order_hint).abs()
+      );
+      if best.map_or(true, |(best_cost, _)| cost < best_cost) {
+        best = Some((cost, i));
+      }
+    }
+    if let Some((_, i)) = best {
+      self.primary_ref_frame = i as u32;
+    }
+  }
+
+  /// Chooses whether a key frame is coded downscaled, once its quantizer is
+  /// known. Inter frames are always coded at full size, since predicting
+  /// from references of a different size is not supported.
+  pub fn set_superres(&mut self) {
+    let denom = if self.frame_type != FrameType::KEY || self.allow_intrabc {
+      SUPERRES_NUM
+    } else {
+      match self.config.superres {
+        SuperResMode::None => SUPERRES_NUM,
+        SuperResMode::Fixed(denom) => denom,
+        SuperResMode::Auto => {
+          // Downscale more as the quantizer gets closer to its maximum
+          if self.base_q_idx <= SUPERRES_AUTO_QTHRESH {
+            SUPERRES_NUM
+          } else {
+            let excess = (self.base_q_idx - SUPERRES_AUTO_QTHRESH) as usize;
+            let range = (SUPERRES_DENOM_MAX - SUPERRES_DENOM_MIN) as usize;
+            SUPERRES_DENOM_MIN
+              + (excess * range / (255 - SUPERRES_AUTO_QTHRESH as usize)) as u8
+          }
+        }
+      }
+    };
+    self.set_superres_denom(denom);
+  }
+
+  fn set_superres_denom(&mut self, denom: u8) {
+    if denom == self.superres_denom {
+      return;
+    }
+    self.superres_denom = denom;
+    self.width = ((self.upscaled_width * SUPERRES_NUM as usize
+      + denom as usize / 2) / denom as usize)
+      .max(self.upscaled_width.min(16));
+    self.w_in_b = 2 * self.width.align_power_of_two_and_shift(3);
+    self.sb_width = self.width.align_power_of_two_and_shift(6);
+    self.tiling = TilingInfo::new(
+      self.sb_size_log2(),
+      self.width,
+      self.height,
+      self.tiling.tile_cols_log2,
+      self.tiling.tile_rows_log2
+    );
+  }
+
   #[inline(always)]
   pub fn sb_size_log2(&self) -> usize {
     self.sequence.sb_size_log2()
@@ -787,6 +1311,29 @@ impl<T: Pixel> FrameInvariants<T> {
   pub fn sb_size(&self) -> usize {
     self.sequence.sb_size()
   }
+
+  /// Distances in frames between this frame and two of its references
+  pub fn ref_frame_dists(&self, ref_frames: [RefType; 2]) -> [u32; 2] {
+    let mut dists = [0; 2];
+    for (dist, rf) in dists.iter_mut().zip(ref_frames.iter()) {
+      let slot = self.ref_frames[rf.to_index()] as usize;
+      if let Some(ref rec) = self.rec_buffer.frames[slot] {
+        *dist = self.sequence.get_relative_dist(rec.order_hint, self.order_hint).abs() as u32;
+      }
+    }
+    dists
+  }
+
+  /// Precision of the motion vectors coded in this frame
+  pub fn mv_precision(&self) -> MvSubpelPrecision {
+    if self.force_integer_mv != 0 {
+      MvSubpelPrecision::MV_SUBPEL_NONE
+    } else if self.allow_high_precision_mv {
+      MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION
+    } else {
+      MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION
+    }
+  }
 }
 
 impl<T: Pixel> fmt::Display for FrameInvariants<T> {
@@ -802,39 +1349,50 @@ pub fn write_temporal_delimiter(
   Ok(())
 }
 
+/// The sequence header OBU, preceded by its size, as written with each key
+/// frame and in the codec configuration record of containers
+pub fn sequence_header_obu<T: Pixel>(
+  fi: &FrameInvariants<T>
+) -> io::Result<Vec<u8>> {
+  let mut buf2 = Vec::new();
+  {
+    let mut bw2 = BitWriter::endian(&mut buf2, BigEndian);
+    bw2.write_sequence_header_obu(fi)?;
+    bw2.write_bit(true)?; // trailing bit
+    bw2.byte_align()?;
+  }
+
+  let mut buf1 = Vec::new();
+  {
+    let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
+    bw1.write_obu_header(ObuType::OBU_SEQUENCE_HEADER, None)?;
+    bw1.write_uleb128(buf2.len() as u64)?;
+  }
+  buf1.append(&mut buf2);
+
+  Ok(buf1)
+}
+
+/// OBU extension of the layer-specific OBUs of a frame, which are only
+/// needed when the sequence has several operating points
+fn obu_extension<T: Pixel>(fi: &FrameInvariants<T>) -> Option<u8> {
+  if fi.sequence.operating_points_cnt_minus_1 > 0 {
+    Some(fi.temporal_id << 5) // spatial_id is 0
+  } else {
+    None
+  }
+}
+
 fn write_obus<T: Pixel>(
   packet: &mut dyn io::Write, fi: &FrameInvariants<T>, fs: &FrameState<T>
 ) -> io::Result<()> {
-  let obu_extension = 0 as u32;
+  let obu_extension = obu_extension(fi);
 
   let mut buf1 = Vec::new();
 
-  // write sequence header obu if KEY_FRAME, preceded by 4-byte size
+  // write sequence header obu if KEY_FRAME
   if fi.frame_type == FrameType::KEY {
-    let mut buf2 = Vec::new();
-    {
-      let mut bw2 = BitWriter::endian(&mut buf2, BigEndian);
-      bw2.write_sequence_header_obu(fi)?;
-      bw2.write_bit(true)?; // trailing bit
-      bw2.byte_align()?;
-    }
-
-    {
-      let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
-      bw1.write_obu_header(ObuType::OBU_SEQUENCE_HEADER, obu_extension)?;
-    }
-    packet.write_all(&buf1).unwrap();
-    buf1.clear();
-
-    {
-      let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
-      bw1.write_uleb128(buf2.len() as u64)?;
-    }
-    packet.write_all(&buf1).unwrap();
-    buf1.clear();
-
-    packet.write_all(&buf2).unwrap();
-    buf2.clear();
+    packet.write_all(&sequence_header_obu(fi)?).unwrap();
 
     if fi.sequence.content_light.is_some() {
       let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
@@ -851,6 +1409,18 @@ fn write_obus<T: Pixel>(
     }
   }
 
+  // T.35 metadata belongs to the temporal unit showing the frame
+  if fi.show_frame || fi.show_existing_frame {
+    if let Some(params) = fi.frame_params.as_ref() {
+      for t35 in params.t35_metadata.iter() {
+        let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
+        bw1.write_t35_metadata_obu(t35)?;
+        packet.write_all(&buf1).unwrap();
+        buf1.clear();
+      }
+    }
+  }
+
   let mut buf2 = Vec::new();
   {
     let mut bw2 = BitWriter::endian(&mut buf2, BigEndian);
@@ -879,7 +1449,7 @@ fn write_obus<T: Pixel>(
 }
 
 /// Write into `dst` the difference between the blocks at `src1` and `src2`
-fn diff<T: Pixel>(
+pub fn diff<T: Pixel>(
   dst: &mut [i16],
   src1: &PlaneRegion<'_, T>,
   src2: &PlaneRegion<'_, T>,
@@ -895,14 +1465,47 @@ fn diff<T: Pixel>(
     }
 }
 
-fn get_qidx<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, cw: &ContextWriter, tile_bo: BlockOffset) -> u8 {
-  let mut qidx = fi.base_q_idx;
-  let sidx = cw.bc.blocks[tile_bo].segmentation_idx as usize;
-  if ts.segmentation.features[sidx][SegLvl::SEG_LVL_ALT_Q as usize] {
-    let delta = ts.segmentation.data[sidx][SegLvl::SEG_LVL_ALT_Q as usize];
-    qidx = clamp((qidx as i16) + delta, 0, 255) as u8;
+/// Quantizer index of the superblock `sbo` of the frame, before the segment
+/// offset
+pub fn sb_qidx<T: Pixel>(
+  fi: &FrameInvariants<T>, sb_qindex_offsets: &SuperBlockQindexOffsets,
+  sbo: SuperBlockOffset
+) -> u8 {
+  if !fi.delta_q_present {
+    return fi.base_q_idx;
+  }
+  let offset = sb_qindex_offsets.get(sbo);
+  clamp(fi.base_q_idx as i16 + offset, 1, 255) as u8
+}
+
+/// `qidx` with the offset of the segment of the block `frame_bo` applied
+pub fn segment_qidx(
+  segmentation: &SegmentationState, segment_ids: &SegmentIds,
+  frame_bo: BlockOffset, qidx: u8
+) -> u8 {
+  // Read the segment from the map rather than from the block, which holds
+  // the predicted segment after a skip block has been tried.
+  let sidx = segment_ids.get(frame_bo) as usize;
+  if segmentation.features[sidx][SegLvl::SEG_LVL_ALT_Q as usize] {
+    let delta = segmentation.data[sidx][SegLvl::SEG_LVL_ALT_Q as usize];
+    clamp((qidx as i16) + delta, 0, 255) as u8
+  } else {
+    qidx
   }
-  qidx
+}
+
+/// Quantizer index of the superblock containing `tile_bo`, before the
+/// segment offset
+fn get_sb_qidx<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, tile_bo: BlockOffset) -> u8 {
+  let sbo = ts.to_frame_super_block_offset(tile_bo.sb_offset());
+  sb_qidx(fi, ts.sb_qindex_offsets, sbo)
+}
+
+pub fn get_qidx<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, tile_bo: BlockOffset) -> u8 {
+  segment_qidx(
+    ts.segmentation, ts.segment_ids, ts.to_frame_block_offset(tile_bo),
+    get_sb_qidx(fi, ts, tile_bo)
+  )
 }
 
 // For a transform block,
@@ -912,9 +1515,11 @@ pub fn encode_tx_block<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
   w: &mut dyn Writer, p: usize, tile_bo: BlockOffset, mode: PredictionMode,
   tx_size: TxSize, tx_type: TxType, plane_bsize: BlockSize, po: PlaneOffset,
-  skip: bool, ac: &[i16], alpha: i16, rdo_type: RDOType, need_recon_pixel: bool
+  skip: bool, ac: &[i16], alpha: i16, filter_intra: Option<FilterIntraMode>,
+  palette: Option<Palette>, rdo_type: RDOType, need_recon_pixel: bool
 ) -> (bool, i64) {
-  let qidx = get_qidx(fi, ts, cw, tile_bo);
+  let qidx = get_qidx(fi, ts, tile_bo);
+  let frame_po = ts.to_frame_block_offset(tile_bo).to_luma_plane_offset();
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[p].cfg;
   let tile_rect = ts.tile_rect().decimated(xdec, ydec);
   let rec = &mut ts.rec.planes[p];
@@ -925,22 +1530,41 @@ pub fn encode_tx_block<T: Pixel>(
     "mode.is_intra()={:#?}, plane={:#?}, tx_size.block_size()={:#?}, plane_bsize={:#?}, need_recon_pixel={:#?}",
     mode.is_intra(), p, tx_size.block_size(), plane_bsize, need_recon_pixel);
 
-  if mode.is_intra() {
+  let partition = cw.bc.blocks[tile_bo].partition;
+  if let Some(palette) = palette {
+    assert_eq!(p, 0);
+    palette.predict(&mut rec.subregion_mut(area), &ts.input.planes[0], frame_po,
+                    tx_size.width(), tx_size.height(),
+                    fi.w_in_b << MI_SIZE_LOG2, fi.h_in_b << MI_SIZE_LOG2);
+  } else if let Some(filter_intra) = filter_intra {
     let bit_depth = fi.sequence.bit_depth;
-    let edge_buf = get_intra_edges(&rec.as_const(), po, tx_size, bit_depth, Some(mode));
+    let edge_buf = get_intra_edges(&rec.as_const(), po, tx_size, plane_bsize, partition, bit_depth, None);
+    filter_intra.predict_intra(&mut rec.subregion_mut(area), tx_size, bit_depth, &edge_buf);
+  } else if mode.is_intra() {
+    let bit_depth = fi.sequence.bit_depth;
+    let edge_buf = get_intra_edges(&rec.as_const(), po, tx_size, plane_bsize, partition, bit_depth, Some(mode));
     mode.predict_intra(tile_rect, &mut rec.subregion_mut(area), tx_size, bit_depth, &ac, alpha, &edge_buf);
   }
 
+  // The final coding of each block comes after all its RDO trials, so the
+  // prediction it leaves is the one of the bitstream
+  if let Some(ref mut pred) = ts.pred {
+    let mut pred = pred.planes[p].subregion_mut(area);
+    for (dst, src) in pred.rows_iter_mut().zip(rec.subregion(area).rows_iter())
+      .take(tx_size.height()) {
+      let w = tx_size.width().min(src.len());
+      dst[..w].copy_from_slice(&src[..w]);
+    }
+  }
+
   if skip { return (false, -1); }
 
-  let mut residual_storage: AlignedArray<[i16; 64 * 64]> = UninitializedAlignedArray();
-  let mut coeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
-  let mut qcoeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
-  let mut rcoeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
-  let residual = &mut residual_storage.array[..tx_size.area()];
-  let coeffs = &mut coeffs_storage.array[..tx_size.area()];
-  let qcoeffs = &mut qcoeffs_storage.array[..tx_size.area()];
-  let rcoeffs = &mut rcoeffs_storage.array[..tx_size.area()];
+  let timer = StageTimer::start(Stage::Transform, fi.config.time_stages, &ts.stage_times);
+  let arena = &mut *ts.arena;
+  let residual = &mut arena.residual.array[..tx_size.area()];
+  let coeffs = &mut arena.coeffs.array[..tx_size.area()];
+  let qcoeffs = &mut arena.qcoeffs.array[..tx_size.area()];
+  let rcoeffs = &mut arena.rcoeffs.array[..tx_size.area()];
 
   diff(
     residual,
@@ -953,23 +1577,38 @@ pub fn encode_tx_block<T: Pixel>(
 
   let coded_tx_size = av1_get_coded_tx_size(tx_size).area();
   ts.qc.quantize(coeffs, qcoeffs, coded_tx_size);
+  if fi.config.speed_settings.rdoq {
+    cw.rdoq_coeffs(&*w, &ts.qc, fi.lambda, p, coeffs, qcoeffs, tx_size, tx_type);
+  }
+  timer.stop(&mut ts.stage_times);
 
+  let timer = StageTimer::start(Stage::EntropyCoding, fi.config.time_stages, &ts.stage_times);
   let tell_coeffs = w.tell_frac();
   let has_coeff = if need_recon_pixel || rdo_type.needs_coeff_rate() {
-    cw.write_coeffs_lv_map(w, p, tile_bo, &qcoeffs, mode, tx_size, tx_type, plane_bsize, xdec, ydec,
+    let tx_mode = filter_intra.map_or(mode, FilterIntraMode::intra_dir);
+    cw.write_coeffs_lv_map(w, p, tile_bo, &qcoeffs, tx_mode, tx_size, tx_type, plane_bsize, xdec, ydec,
                            fi.use_reduced_tx_set)
+  } else if rdo_type == RDOType::TxDistEstRate {
+    let tx_mode = filter_intra.map_or(mode, FilterIntraMode::intra_dir);
+    cw.count_coeffs_lv_map(w, &ts.coeff_rates, p, tile_bo, &qcoeffs, tx_mode, tx_size, tx_type, plane_bsize,
+                           xdec, ydec, fi.use_reduced_tx_set)
   } else {
     true
   };
   let cost_coeffs = w.tell_frac() - tell_coeffs;
+  timer.stop(&mut ts.stage_times);
+
   // Reconstruct
-  dequantize(qidx, qcoeffs, rcoeffs, tx_size, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+  let timer = StageTimer::start(Stage::Transform, fi.config.time_stages, &ts.stage_times);
+  dequantize(qidx, qcoeffs, rcoeffs, tx_size, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p],
+             fi.qm_weights(p, tx_size, tx_type));
 
   let mut tx_dist: i64 = -1;
 
   if !fi.use_tx_domain_distortion || need_recon_pixel {
     inverse_transform_add(rcoeffs, &mut rec.subregion_mut(area), tx_size, tx_type, fi.sequence.bit_depth);
   }
+  timer.stop(&mut ts.stage_times);
   if rdo_type.needs_tx_dist() {
     // Store tx-domain distortion of this block
     tx_dist = coeffs
@@ -988,10 +1627,8 @@ pub fn encode_tx_block<T: Pixel>(
     ts.rdo.add_rate(fi.base_q_idx, tx_size, tx_dist as u64, cost_coeffs as u64);
   }
 
-  if rdo_type == RDOType::TxDistEstRate {
-    // look up rate and distortion in table
-    let estimated_rate = estimate_rate(fi.base_q_idx, tx_size, tx_dist as u64);
-    w.add_bits_frac(estimated_rate as u32);
+  if p > 0 && tx_dist > 0 {
+    tx_dist = chroma_weighted(fi, tx_dist as u64) as i64;
   }
   (has_coeff, tx_dist)
 }
@@ -999,7 +1636,8 @@ pub fn encode_tx_block<T: Pixel>(
 pub fn motion_compensate<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
   luma_mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
-  bsize: BlockSize, tile_bo: BlockOffset, luma_only: bool
+  compound: CompoundParams, bsize: BlockSize, tile_bo: BlockOffset,
+  luma_only: bool
 ) {
   debug_assert!(!luma_mode.is_intra());
 
@@ -1020,7 +1658,10 @@ pub fn motion_compensate<T: Pixel>(
     let tile_rect = luma_tile_rect.decimated(xdec, ydec);
 
     let area = Area::BlockStartingAt { bo: tile_bo };
-    if p > 0 && bsize < BlockSize::BLOCK_8X8 {
+    if luma_mode == PredictionMode::INTRABC {
+      // IntraBC is only used on blocks of 8x8 and larger
+      predict_intrabc(rec, po, plane_bsize.width(), plane_bsize.height(), mvs[0]);
+    } else if p > 0 && bsize < BlockSize::BLOCK_8X8 {
       let mut some_use_intra = false;
       if bsize == BlockSize::BLOCK_4X4 || bsize == BlockSize::BLOCK_4X8 {
         some_use_intra |= cw.bc.blocks[tile_bo.with_offset(-1,0)].mode.is_intra(); };
@@ -1031,7 +1672,7 @@ pub fn motion_compensate<T: Pixel>(
 
       if some_use_intra {
         luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), plane_bsize.width(),
-                                plane_bsize.height(), ref_frames, mvs);
+                                plane_bsize.height(), ref_frames, mvs, compound);
       } else {
         assert!(u_xdec == 1 && u_ydec == 1);
         // TODO: these are absolutely only valid for 4:2:0
@@ -1048,31 +1689,31 @@ pub fn motion_compensate<T: Pixel>(
           let area2 = Area::StartingAt { x: po2.x, y: po2.y };
           let po3 = PlaneOffset { x: po.x+2, y: po.y+2 };
           let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 2, 2, rf0, mv0);
-          luma_mode.predict_inter(fi, tile_rect, p, po1, &mut rec.subregion_mut(area1), 2, 2, rf1, mv1);
-          luma_mode.predict_inter(fi, tile_rect, p, po2, &mut rec.subregion_mut(area2), 2, 2, rf2, mv2);
-          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 2, 2, ref_frames, mvs);
+          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 2, 2, rf0, mv0, CompoundParams::default());
+          luma_mode.predict_inter(fi, tile_rect, p, po1, &mut rec.subregion_mut(area1), 2, 2, rf1, mv1, CompoundParams::default());
+          luma_mode.predict_inter(fi, tile_rect, p, po2, &mut rec.subregion_mut(area2), 2, 2, rf2, mv2, CompoundParams::default());
+          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 2, 2, ref_frames, mvs, compound);
         }
         if bsize == BlockSize::BLOCK_8X4 {
           let mv1 = cw.bc.blocks[tile_bo.with_offset(0,-1)].mv;
           let rf1 = cw.bc.blocks[tile_bo.with_offset(0,-1)].ref_frames;
-          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 4, 2, rf1, mv1);
+          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 4, 2, rf1, mv1, CompoundParams::default());
           let po3 = PlaneOffset { x: po.x, y: po.y+2 };
           let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 4, 2, ref_frames, mvs);
+          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 4, 2, ref_frames, mvs, compound);
         }
         if bsize == BlockSize::BLOCK_4X8 {
           let mv2 = cw.bc.blocks[tile_bo.with_offset(-1,0)].mv;
           let rf2 = cw.bc.blocks[tile_bo.with_offset(-1,0)].ref_frames;
-          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 2, 4, rf2, mv2);
+          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 2, 4, rf2, mv2, CompoundParams::default());
           let po3 = PlaneOffset { x: po.x+2, y: po.y };
           let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 2, 4, ref_frames, mvs);
+          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 2, 4, ref_frames, mvs, compound);
         }
       }
     } else {
       luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), plane_bsize.width(),
-                              plane_bsize.height(), ref_frames, mvs);
+                              plane_bsize.height(), ref_frames, mvs, compound);
     }
   }
 }
@@ -1093,11 +1734,15 @@ pub fn save_block_motion<T: Pixel>(
 }
 
 pub fn encode_block_pre_cdef<T: Pixel>(
-  seq: &Sequence, ts: &TileStateMut<'_, T>,
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>,
   cw: &mut ContextWriter, w: &mut dyn Writer,
   bsize: BlockSize, tile_bo: BlockOffset, skip: bool
 ) -> bool {
   cw.bc.blocks.set_skip(tile_bo, bsize, skip);
+  if ts.segmentation.enabled {
+    let segment = ts.segment_ids.get(ts.to_frame_block_offset(tile_bo));
+    cw.bc.blocks.set_segmentation_idx(tile_bo, bsize, segment);
+  }
   if ts.segmentation.enabled && ts.segmentation.update_map && ts.segmentation.preskip {
     cw.write_segmentation(w, tile_bo, bsize, false, ts.segmentation.last_active_segid);
   }
@@ -1105,7 +1750,7 @@ pub fn encode_block_pre_cdef<T: Pixel>(
   if ts.segmentation.enabled && ts.segmentation.update_map && !ts.segmentation.preskip {
     cw.write_segmentation(w, tile_bo, bsize, skip, ts.segmentation.last_active_segid);
   }
-  if !skip && seq.enable_cdef {
+  if !skip && fi.sequence.enable_cdef && !fi.allow_intrabc {
     cw.bc.cdef_coded = true;
   }
   cw.bc.cdef_coded
@@ -1116,9 +1761,9 @@ pub fn encode_block_post_cdef<T: Pixel>(
   cw: &mut ContextWriter, w: &mut dyn Writer,
   luma_mode: PredictionMode, chroma_mode: PredictionMode,
   ref_frames: [RefType; 2], mvs: [MotionVector; 2],
-  bsize: BlockSize, tile_bo: BlockOffset, skip: bool,
-  cfl: CFLParams, tx_size: TxSize, tx_type: TxType,
-  mode_context: usize, mv_stack: &[CandidateMV],
+  compound: CompoundParams, bsize: BlockSize, tile_bo: BlockOffset, skip: bool,
+  cfl: CFLParams, filter_intra: Option<FilterIntraMode>, palette: Option<Palette>,
+  tx_size: TxSize, tx_type: TxType, mode_context: usize, mv_stack: &[CandidateMV],
   rdo_type: RDOType, need_recon_pixel: bool
 ) -> i64 {
   let is_inter = !luma_mode.is_intra();
@@ -1135,12 +1780,21 @@ pub fn encode_block_post_cdef<T: Pixel>(
   cw.bc.blocks.set_block_size(tile_bo, bsize);
   cw.bc.blocks.set_mode(tile_bo, bsize, luma_mode);
   cw.bc.blocks.set_tx_size(tile_bo, bsize, tx_size);
+  cw.bc.blocks.set_tx_type(tile_bo, bsize, tx_type);
   cw.bc.blocks.set_ref_frames(tile_bo, bsize, ref_frames);
   cw.bc.blocks.set_motion_vectors(tile_bo, bsize, mvs);
-
-  //write_q_deltas();
-  if cw.bc.code_deltas && ts.deblock.block_deltas_enabled && (bsize < sb_size || !skip) {
-    cw.write_block_deblock_deltas(w, tile_bo, ts.deblock.block_delta_multi);
+  cw.bc.blocks.set_compound(tile_bo, bsize, compound);
+  cw.bc.blocks.set_palette(tile_bo, bsize, palette.unwrap_or_default());
+
+  if cw.bc.code_deltas && (bsize < sb_size || !skip) {
+    let qidx = get_sb_qidx(fi, ts, tile_bo);
+    cw.write_delta_qindex(w, qidx as i32 - cw.bc.current_qindex as i32);
+    cw.bc.current_qindex = qidx;
+    if ts.deblock.block_deltas_enabled {
+      // The filter level deltas are only known once the frame is
+      // reconstructed, so remember where they go in the tile
+      cw.bc.deltas_pos = Some((cw.bc.cdef_coded, w.checkpoint().recorded_tokens()));
+    }
   }
   cw.bc.code_deltas = false;
 
@@ -1156,8 +1810,12 @@ pub fn encode_block_post_cdef<T: Pixel>(
         cw.write_inter_mode(w, luma_mode, mode_context);
       }
 
-      let ref_mv_idx = 0;
       let num_mv_found = mv_stack.len();
+      let ref_mv_idx = if luma_mode == PredictionMode::NEWMV || luma_mode == PredictionMode::NEW_NEWMV {
+        newmv_ref_mv_idx(mvs, mv_stack, luma_mode == PredictionMode::NEW_NEWMV, &ts.mv_rates)
+      } else {
+        0
+      };
 
       if luma_mode == PredictionMode::NEWMV || luma_mode == PredictionMode::NEW_NEWMV {
         if luma_mode == PredictionMode::NEW_NEWMV { assert!(num_mv_found >= 2); }
@@ -1178,13 +1836,7 @@ pub fn encode_block_post_cdef<T: Pixel>(
         [MotionVector::default(); 2]
       };
 
-      let mv_precision = if fi.force_integer_mv != 0 {
-        MvSubpelPrecision::MV_SUBPEL_NONE
-      } else if fi.allow_high_precision_mv {
-        MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION
-      } else {
-        MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION
-      };
+      let mv_precision = fi.mv_precision();
 
       if luma_mode == PredictionMode::NEWMV ||
         luma_mode == PredictionMode::NEW_NEWMV ||
@@ -1226,18 +1878,42 @@ pub fn encode_block_post_cdef<T: Pixel>(
           assert_eq!(mvs[0].col, mv_stack[0].this_mv.col);
         }
       }
+
+      if luma_mode >= PredictionMode::NEAREST_NEARESTMV {
+        if fi.sequence.enable_masked_compound {
+          cw.write_comp_group_idx(w, tile_bo, compound.is_masked());
+        } else {
+          assert!(!compound.is_masked());
+        }
+        if compound.is_masked() {
+          cw.write_compound_type(w, bsize, compound);
+        } else if fi.sequence.enable_jnt_comp {
+          let dists = fi.ref_frame_dists(ref_frames);
+          cw.write_compound_idx(w, tile_bo, dists[0] == dists[1], compound.compound_idx());
+        } else {
+          assert!(compound.compound_idx());
+        }
+      }
     } else {
       cw.write_intra_mode(w, bsize, luma_mode);
     }
   } else {
-    cw.write_intra_mode_kf(w, tile_bo, luma_mode);
+    if fi.allow_intrabc {
+      cw.write_use_intrabc(w, luma_mode == PredictionMode::INTRABC);
+    }
+    if luma_mode == PredictionMode::INTRABC {
+      let ref_dv = get_ref_dv(mv_stack, tile_bo, ts.sb_size_log2);
+      cw.write_dv(w, mvs[0], ref_dv);
+    } else {
+      cw.write_intra_mode_kf(w, tile_bo, luma_mode);
+    }
   }
 
   if !is_inter {
     if luma_mode.is_directional() && bsize >= BlockSize::BLOCK_8X8 {
       cw.write_angle_delta(w, 0, luma_mode);
     }
-    if has_chroma(tile_bo, bsize, xdec, ydec) {
+    if fi.sequence.num_planes() > 1 && has_chroma(tile_bo, bsize, xdec, ydec) {
       cw.write_intra_uv_mode(w, chroma_mode, luma_mode, bsize);
       if chroma_mode.is_cfl() {
         assert!(bsize.cfl_allowed());
@@ -1247,32 +1923,61 @@ pub fn encode_block_post_cdef<T: Pixel>(
         cw.write_angle_delta(w, 0, chroma_mode);
       }
     }
-    // TODO: Extra condition related to palette mode, see `read_filter_intra_mode_info` in decodemv.c
-    if fi.sequence.enable_filter_intra &&
-      luma_mode == PredictionMode::DC_PRED && bsize.width() <= 32 && bsize.height() <= 32 {
-      cw.write_use_filter_intra(w,false, bsize); // turn off FILTER_INTRA
+    if fi.allow_screen_content_tools != 0 && bsize.palette_allowed() {
+      if luma_mode == PredictionMode::DC_PRED {
+        cw.write_palette_y(w, tile_bo, bsize, palette, fi.sequence.bit_depth);
+      } else {
+        assert!(palette.is_none());
+      }
+      if fi.sequence.num_planes() > 1 && has_chroma(tile_bo, bsize, xdec, ydec)
+        && chroma_mode == PredictionMode::DC_PRED {
+        cw.write_use_palette_uv(w, palette.is_some());
+      }
+    } else {
+      assert!(palette.is_none());
+    }
+    if fi.sequence.enable_filter_intra && palette.is_none() &&
+      luma_mode == PredictionMode::DC_PRED && bsize.filter_intra_allowed() {
+      cw.write_use_filter_intra(w, filter_intra.is_some(), bsize);
+      if let Some(filter_intra) = filter_intra {
+        cw.write_filter_intra_mode(w, filter_intra);
+      }
+    } else {
+      assert!(filter_intra.is_none());
     }
   }
 
+  if let Some(palette) = palette {
+    // Only the part of the color map within the mode info grid is coded
+    let frame_bo = ts.to_frame_block_offset(tile_bo);
+    let width = bsize.width().min((fi.w_in_b - frame_bo.x) << MI_SIZE_LOG2);
+    let height = bsize.height().min((fi.h_in_b - frame_bo.y) << MI_SIZE_LOG2);
+    let map = palette.color_map(&ts.input.planes[0], frame_bo.to_luma_plane_offset(),
+                                width, height, fi.w_in_b << MI_SIZE_LOG2, fi.h_in_b << MI_SIZE_LOG2);
+    cw.write_palette_color_map(w, palette, &map, width, height);
+  }
+
   // write tx_size here
   if fi.tx_mode_select {
     if bsize.greater_than(BlockSize::BLOCK_4X4) && !(is_inter && skip) {
       if !is_inter {
         cw.write_tx_size_intra(w, tile_bo, bsize, tx_size);
         cw.bc.update_tx_size_context(tile_bo, bsize, tx_size, false);
-      } /*else {  // TODO (yushin): write_tx_size_inter(), i.e. var-tx
-
-      }*/
+      } else {
+        cw.write_tx_size_inter(w, tile_bo, bsize, tx_size);
+      }
     } else {
       cw.bc.update_tx_size_context(tile_bo, bsize, tx_size, is_inter && skip);
     }
   }
 
+  // Monochrome frames have no chroma to predict nor code
+  let luma_only = fi.sequence.num_planes() == 1;
   if is_inter {
-    motion_compensate(fi, ts, cw, luma_mode, ref_frames, mvs, bsize, tile_bo, false);
-    write_tx_tree(fi, ts, cw, w, luma_mode, tile_bo, bsize, tx_size, tx_type, skip, false, rdo_type, need_recon_pixel)
+    motion_compensate(fi, ts, cw, luma_mode, ref_frames, mvs, compound, bsize, tile_bo, luma_only);
+    write_tx_tree(fi, ts, cw, w, luma_mode, tile_bo, bsize, tx_size, tx_type, skip, luma_only, rdo_type, need_recon_pixel)
   } else {
-    write_tx_blocks(fi, ts, cw, w, luma_mode, chroma_mode, tile_bo, bsize, tx_size, tx_type, skip, cfl, false, rdo_type, need_recon_pixel)
+    write_tx_blocks(fi, ts, cw, w, luma_mode, chroma_mode, tile_bo, bsize, tx_size, tx_type, skip, cfl, filter_intra, palette, luma_only, rdo_type, need_recon_pixel)
   }
 }
 
@@ -1323,18 +2028,20 @@ pub fn write_tx_blocks<T: Pixel>(
   cw: &mut ContextWriter, w: &mut dyn Writer,
   luma_mode: PredictionMode, chroma_mode: PredictionMode, tile_bo: BlockOffset,
   bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool,
-  cfl: CFLParams, luma_only: bool, rdo_type: RDOType, need_recon_pixel: bool
+  cfl: CFLParams, filter_intra: Option<FilterIntraMode>, palette: Option<Palette>,
+  luma_only: bool, rdo_type: RDOType, need_recon_pixel: bool
 ) -> i64 {
   let bw = bsize.width_mi() / tx_size.width_mi();
   let bh = bsize.height_mi() / tx_size.height_mi();
-  let qidx = get_qidx(fi, ts, cw, tile_bo);
+  let qidx = get_qidx(fi, ts, tile_bo);
 
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
   let mut ac: AlignedArray<[i16; 32 * 32]> = UninitializedAlignedArray();
   let mut tx_dist: i64 = 0;
   let do_chroma = has_chroma(tile_bo, bsize, xdec, ydec);
 
-  ts.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+  ts.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0,
+               fi.qm_weights(0, tx_size, tx_type));
 
   for by in 0..bh {
     for bx in 0..bw {
@@ -1347,7 +2054,7 @@ pub fn write_tx_blocks<T: Pixel>(
       let (_, dist) =
         encode_tx_block(
           fi, ts, cw, w, 0, tx_bo, luma_mode, tx_size, tx_type, bsize, po,
-          skip, &ac.array, 0, rdo_type, need_recon_pixel
+          skip, &ac.array, 0, filter_intra, palette, rdo_type, need_recon_pixel
         );
       assert!(!fi.use_tx_domain_distortion || need_recon_pixel || skip || dist >= 0);
       tx_dist += dist;
@@ -1390,7 +2097,8 @@ pub fn write_tx_blocks<T: Pixel>(
     };
 
     for p in 1..3 {
-      ts.qc.update(fi.base_q_idx, uv_tx_size, true, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+      ts.qc.update(qidx, uv_tx_size, true, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p],
+                   fi.qm_weights(p, uv_tx_size, uv_tx_type));
       let alpha = cfl.alpha(p - 1);
       for by in 0..bh_uv {
         for bx in 0..bw_uv {
@@ -1407,7 +2115,7 @@ pub fn write_tx_blocks<T: Pixel>(
           po.y += (by * uv_tx_size.height()) as isize;
           let (_, dist) =
             encode_tx_block(fi, ts, cw, w, p, tx_bo, chroma_mode, uv_tx_size, uv_tx_type,
-                            plane_bsize, po, skip, &ac.array, alpha, rdo_type, need_recon_pixel);
+                            plane_bsize, po, skip, &ac.array, alpha, None, None, rdo_type, need_recon_pixel);
           assert!(!fi.use_tx_domain_distortion || need_recon_pixel || skip || dist >= 0);
           tx_dist += dist;
         }
@@ -1428,20 +2136,35 @@ pub fn write_tx_tree<T: Pixel>(
 ) -> i64 {
   let bw = bsize.width_mi() / tx_size.width_mi();
   let bh = bsize.height_mi() / tx_size.height_mi();
-  let qidx = get_qidx(fi, ts, cw, tile_bo);
+  let qidx = get_qidx(fi, ts, tile_bo);
 
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
   let ac = &[0i16; 0];
   let mut tx_dist: i64 = 0;
 
-  ts.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+  ts.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0,
+               fi.qm_weights(0, tx_size, tx_type));
 
-  let po = tile_bo.plane_offset(&ts.input.planes[0].cfg);
-  let (has_coeff, dist) = encode_tx_block(
-    fi, ts, cw, w, 0, tile_bo, luma_mode, tx_size, tx_type, bsize, po, skip, ac, 0, rdo_type, need_recon_pixel
-  );
-  assert!(!fi.use_tx_domain_distortion || need_recon_pixel || skip || dist >= 0);
-  tx_dist += dist;
+  // Chroma takes the transform type of the luma block at its top-left
+  let mut has_coeff = false;
+  for by in 0..bh {
+    for bx in 0..bw {
+      let tx_bo = BlockOffset {
+        x: tile_bo.x + bx * tx_size.width_mi(),
+        y: tile_bo.y + by * tx_size.height_mi()
+      };
+
+      let po = tx_bo.plane_offset(&ts.input.planes[0].cfg);
+      let (tx_has_coeff, dist) = encode_tx_block(
+        fi, ts, cw, w, 0, tx_bo, luma_mode, tx_size, tx_type, bsize, po, skip, ac, 0, None, None, rdo_type, need_recon_pixel
+      );
+      assert!(!fi.use_tx_domain_distortion || need_recon_pixel || skip || dist >= 0);
+      tx_dist += dist;
+      if bx == 0 && by == 0 {
+        has_coeff = tx_has_coeff;
+      }
+    }
+  }
 
   if luma_only { return tx_dist };
 
@@ -1468,10 +2191,16 @@ pub fn write_tx_tree<T: Pixel>(
       debug_assert!(bw_uv == 1, "bw_uv = {}, bh_uv = {}", bw_uv, bh_uv);
       debug_assert!(bh_uv == 1, "bw_uv = {}, bh_uv = {}", bw_uv, bh_uv);
     }*/
-    let uv_tx_type = if has_coeff {tx_type} else {TxType::DCT_DCT}; // if inter mode, uv_tx_type == tx_type
+    let uv_tx_set = get_tx_set(uv_tx_size, true, fi.use_reduced_tx_set);
+    let uv_tx_type = if has_coeff && av1_tx_used[uv_tx_set as usize][tx_type as usize] != 0 {
+      tx_type
+    } else {
+      TxType::DCT_DCT
+    }; // if inter mode, uv_tx_type == tx_type
 
     for p in 1..3 {
-      ts.qc.update(qidx, uv_tx_size, false, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+      ts.qc.update(qidx, uv_tx_size, false, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p],
+                   fi.qm_weights(p, uv_tx_size, uv_tx_type));
 
       for by in 0..bh_uv {
         for bx in 0..bw_uv {
@@ -1488,7 +2217,7 @@ pub fn write_tx_tree<T: Pixel>(
           po.y += (by * uv_tx_size.height()) as isize;
           let (_, dist) =
             encode_tx_block(fi, ts, cw, w, p, tx_bo, luma_mode, uv_tx_size, uv_tx_type,
-                        plane_bsize, po, skip, ac, 0, rdo_type, need_recon_pixel);
+                        plane_bsize, po, skip, ac, 0, None, None, rdo_type, need_recon_pixel);
           assert!(!fi.use_tx_domain_distortion || need_recon_pixel || skip || dist >= 0);
           tx_dist += dist;
         }
@@ -1499,33 +2228,66 @@ pub fn write_tx_tree<T: Pixel>(
   tx_dist
 }
 
+/// Picks which of the first three candidates of the stack a new motion
+/// vector is coded against, as signalled by the drl index
+fn newmv_ref_mv_idx(
+  mvs: [MotionVector; 2], mv_stack: &[CandidateMV], compound: bool,
+  mv_rates: &MvRates
+) -> usize {
+  (0..mv_stack.len().min(3)).min_by_key(|&idx| {
+    let mut rate = mv_rates.rate(mvs[0], mv_stack[idx].this_mv);
+    if compound {
+      rate += mv_rates.rate(mvs[1], mv_stack[idx].comp_mv);
+    }
+    // Each drl index costs about a bit more than the previous one
+    rate + ((idx as u32) << OD_BITRES)
+  }).unwrap_or(0)
+}
+
 pub fn encode_block_with_modes<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
   bsize: BlockSize, tile_bo: BlockOffset, mode_decision: &RDOPartitionOutput,
   rdo_type: RDOType
+) {
+  let md = mode_decision;
+  debug_assert!((md.tx_size, md.tx_type) ==
+                rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, md.pred_mode_luma, md.ref_frames, md.mvs,
+                                 md.compound, md.filter_intra, md.palette, md.skip));
+
+  encode_decided_block(fi, ts, cw, w_pre_cdef, w_post_cdef, bsize, tile_bo,
+                       mode_decision, rdo_type);
+}
+
+/// Same as `encode_block_with_modes`, for modes that may have been decided
+/// with other CDFs than the current ones
+fn encode_decided_block<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
+  cw: &mut ContextWriter, w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
+  bsize: BlockSize, tile_bo: BlockOffset, mode_decision: &RDOPartitionOutput,
+  rdo_type: RDOType
 ) {
   let (mode_luma, mode_chroma) =
     (mode_decision.pred_mode_luma, mode_decision.pred_mode_chroma);
   let cfl = mode_decision.pred_cfl_params;
   let ref_frames = mode_decision.ref_frames;
   let mvs = mode_decision.mvs;
+  let compound = mode_decision.compound;
+  let filter_intra = mode_decision.filter_intra;
+  let palette = mode_decision.palette;
   let skip = mode_decision.skip;
   let mut cdef_coded = cw.bc.cdef_coded;
   let (tx_size, tx_type) = (mode_decision.tx_size, mode_decision.tx_type);
 
-  debug_assert!((tx_size, tx_type) ==
-                rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, ref_frames, mvs, skip));
-
   let mut mv_stack = ArrayVec::<[CandidateMV; 9]>::new();
   let is_compound = ref_frames[1] != NONE_FRAME;
   let mode_context = cw.find_mvrefs(tile_bo, ref_frames, &mut mv_stack, bsize, fi, is_compound);
 
-  cdef_coded = encode_block_pre_cdef(&fi.sequence, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
+  cdef_coded = encode_block_pre_cdef(fi, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
                               bsize, tile_bo, skip);
   encode_block_post_cdef(fi, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
-                 mode_luma, mode_chroma, ref_frames, mvs, bsize, tile_bo, skip, cfl,
-                 tx_size, tx_type, mode_context, &mv_stack, rdo_type, true);
+                 mode_luma, mode_chroma, ref_frames, mvs, compound, bsize, tile_bo, skip, cfl,
+                 filter_intra, palette, tx_size, tx_type, mode_context, &mv_stack, rdo_type, true);
 }
 
 fn encode_partition_bottomup<T: Pixel, W: Writer>(
@@ -1540,7 +2302,8 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
   let mut rdo_output = RDOOutput {
     rd_cost,
     part_type: PartitionType::PARTITION_INVALID,
-    part_modes: Vec::new()
+    part_modes: Vec::new(),
+    part_children: Vec::new()
   };
 
   if tile_bo.x >= cw.bc.blocks.cols() || tile_bo.y >= cw.bc.blocks.rows() {
@@ -1554,12 +2317,13 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
   // Always split if the current partition is too large
   let must_split = (tile_bo.x + bsw as usize > ts.mi_width ||
                     tile_bo.y + bsh as usize > ts.mi_height ||
-                    bsize.greater_than(BlockSize::BLOCK_64X64)) && is_square;
+                    bsize.greater_than(fi.max_partition_size)) && is_square;
 
   // must_split overrides the minimum partition size when applicable
   let can_split = // FIXME: sub-8x8 inter blocks not supported for non-4:2:0 sampling
     if fi.frame_type == FrameType::INTER &&
     fi.config.chroma_sampling != ChromaSampling::Cs420 &&
+    fi.config.chroma_sampling != ChromaSampling::Cs400 &&
     bsize <= BlockSize::BLOCK_8X8 {
     false
   } else {
@@ -1639,6 +2403,7 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
       let hbsw = subsize.width_mi(); // Half the block size width in blocks
       let hbsh = subsize.height_mi(); // Half the block size height in blocks
       let mut child_modes: Vec<RDOPartitionOutput> = Vec::new();
+      let mut child_outputs: Vec<RDOOutput> = Vec::new();
       rd_cost = 0.0;
 
       if bsize.gte(BlockSize::BLOCK_8X8) {
@@ -1686,6 +2451,9 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
             child_modes.push(child_rdo_output.part_modes[0].clone());
           }
         }
+        if partition == PartitionType::PARTITION_SPLIT {
+          child_outputs.push(child_rdo_output);
+        }
       };
 
       if !early_exit && rd_cost < best_rd {
@@ -1694,6 +2462,9 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
         if partition != PartitionType::PARTITION_SPLIT {
           assert!(!child_modes.is_empty());
           rdo_output.part_modes = child_modes;
+          rdo_output.part_children.clear();
+        } else {
+          rdo_output.part_children = child_outputs;
         }
       }
     }
@@ -1742,12 +2513,67 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
   rdo_output.rd_cost = best_rd;
   rdo_output.part_type = best_partition;
 
-  if best_partition != PartitionType::PARTITION_NONE {
+  // Keep the modes of the other partitions for encode_partition_decided
+  if best_partition == PartitionType::PARTITION_SPLIT {
     rdo_output.part_modes.clear();
   }
   rdo_output
 }
 
+/// Codes the partitions and modes decided by `encode_partition_bottomup`
+/// without searching them again
+fn encode_partition_decided<T: Pixel, W: Writer>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
+  w_pre_cdef: &mut W, w_post_cdef: &mut W, bsize: BlockSize,
+  tile_bo: BlockOffset, decision: &RDOOutput
+) {
+  if tile_bo.x >= cw.bc.blocks.cols() || tile_bo.y >= cw.bc.blocks.rows() {
+    return;
+  }
+
+  let partition = decision.part_type;
+  assert!(partition != PartitionType::PARTITION_INVALID);
+  let subsize = bsize.subsize(partition);
+
+  if bsize.gte(BlockSize::BLOCK_8X8) && bsize.is_sqr() {
+    let w: &mut W = if cw.bc.cdef_coded { w_post_cdef } else { w_pre_cdef };
+    cw.write_partition(w, tile_bo, partition, bsize);
+  }
+
+  if partition == PartitionType::PARTITION_SPLIT {
+    let hbs = subsize.width_mi();
+    let offsets = [
+      tile_bo,
+      BlockOffset { x: tile_bo.x + hbs, y: tile_bo.y },
+      BlockOffset { x: tile_bo.x, y: tile_bo.y + hbs },
+      BlockOffset { x: tile_bo.x + hbs, y: tile_bo.y + hbs }
+    ];
+    for (&offset, child) in offsets.iter().zip(decision.part_children.iter()) {
+      encode_partition_decided(fi, ts, cw, w_pre_cdef, w_post_cdef, subsize,
+                               offset, child);
+    }
+  } else {
+    for mode in decision.part_modes.iter() {
+      assert!(subsize == mode.bsize);
+
+      if !mode.pred_mode_luma.is_intra() {
+        save_block_motion(
+          ts, mode.bsize, mode.bo,
+          mode.ref_frames[0].to_index(), mode.mvs[0]
+        );
+      }
+
+      encode_decided_block(fi, ts, cw, w_pre_cdef, w_post_cdef, mode.bsize,
+                           mode.bo, mode, RDOType::PixelDistRealRate);
+    }
+  }
+
+  if bsize.is_sqr() && bsize.gte(BlockSize::BLOCK_8X8) &&
+    (bsize == BlockSize::BLOCK_8X8 || partition != PartitionType::PARTITION_SPLIT) {
+      cw.bc.update_partition_context(tile_bo, subsize, bsize);
+    }
+}
+
 fn encode_partition_topdown<T: Pixel, W: Writer>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, w_pre_cdef: &mut W, w_post_cdef: &mut W,
@@ -1766,12 +2592,13 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
   // Always split if the current partition is too large
   let must_split = (tile_bo.x + bsw as usize > ts.mi_width ||
                     tile_bo.y + bsh as usize > ts.mi_height ||
-                    bsize.greater_than(BlockSize::BLOCK_64X64)) && is_square;
+                    bsize.greater_than(fi.max_partition_size)) && is_square;
 
   let mut rdo_output = block_output.clone().unwrap_or(RDOOutput {
     part_type: PartitionType::PARTITION_INVALID,
     rd_cost: std::f64::MAX,
-    part_modes: Vec::new()
+    part_modes: Vec::new(),
+    part_children: Vec::new()
   });
   let partition: PartitionType;
   let mut split_vert = false;
@@ -1788,10 +2615,18 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
   if must_split && (!split_vert && !split_horz) {
     // Oversized blocks are split automatically
     partition = PartitionType::PARTITION_SPLIT;
+  } else if fi.duplicate_ref.is_some() {
+    // Frames repeating a reference are coded with the largest blocks
+    partition = if must_split {
+      PartitionType::PARTITION_SPLIT
+    } else {
+      PartitionType::PARTITION_NONE
+    };
   } else if (must_split || (bsize > fi.min_partition_size && is_square)) && (
     // FIXME: sub-8x8 inter blocks not supported for non-4:2:0 sampling
     fi.frame_type != FrameType::INTER ||
     fi.config.chroma_sampling == ChromaSampling::Cs420 ||
+    fi.config.chroma_sampling == ChromaSampling::Cs400 ||
     bsize > BlockSize::BLOCK_8X8)
   {
     debug_assert!(bsize.is_sqr());
@@ -1813,6 +2648,16 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
       partition_types.push(PartitionType::PARTITION_NONE);
       partition_types.push(PartitionType::PARTITION_SPLIT);
     }
+    if !must_split && fi.config.speed_settings.ext_partition_types &&
+      fi.sequence.chroma_sampling != ChromaSampling::Cs422 {
+      // Only with blocks of 8x8 and larger, which have their own chroma
+      let min_size = fi.min_partition_size.width().max(8);
+      partition_types.extend(RAV1E_EXT_PARTITION_TYPES.iter().filter(|&&p| {
+        let subsize = bsize.subsize(p);
+        subsize != BlockSize::BLOCK_INVALID &&
+          subsize.width().min(subsize.height()) >= min_size
+      }));
+    }
     rdo_output = rdo_partition_decision(fi, ts, cw,
                                         w_pre_cdef, w_post_cdef, bsize, tile_bo, &rdo_output, pmvs, &partition_types, rdo_type);
     partition = rdo_output.part_type;
@@ -1831,11 +2676,18 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
     cw.write_partition(w, tile_bo, partition, bsize);
   }
 
+  // Split blocks record their own partition
+  if partition != PartitionType::PARTITION_SPLIT {
+    cw.bc.blocks.set_partition(tile_bo, bsize, partition);
+  }
+
   match partition {
     PartitionType::PARTITION_NONE => {
       let part_decision = if !rdo_output.part_modes.is_empty() {
         // The optimal prediction mode is known from a previous iteration
         rdo_output.part_modes[0].clone()
+      } else if let Some(ref_frame) = fi.duplicate_ref {
+        rdo_skip_decision(fi, ts, cw, bsize, tile_bo, ref_frame)
       } else {
         let pmv_idx = if bsize.greater_than(BlockSize::BLOCK_32X32) {
           0
@@ -1855,13 +2707,16 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
       let skip = part_decision.skip;
       let ref_frames = part_decision.ref_frames;
       let mvs = part_decision.mvs;
+      let compound = part_decision.compound;
+      let filter_intra = part_decision.filter_intra;
+      let palette = part_decision.palette;
       let mut cdef_coded = cw.bc.cdef_coded;
 
       // NOTE: Cannot avoid calling rdo_tx_size_type() here again,
       // because, with top-down partition RDO, the neighnoring contexts
       // of current partition can change, i.e. neighboring partitions can split down more.
       let (tx_size, tx_type) =
-        rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, ref_frames, mvs, skip);
+        rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, ref_frames, mvs, compound, filter_intra, palette, skip);
 
       let mut mv_stack = ArrayVec::<[CandidateMV; 9]>::new();
       let is_compound = ref_frames[1] != NONE_FRAME;
@@ -1915,11 +2770,11 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
       }
 
       // FIXME: every final block that has gone through the RDO decision process is encoded twice
-      cdef_coded = encode_block_pre_cdef(&fi.sequence, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
+      cdef_coded = encode_block_pre_cdef(fi, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
                                   bsize, tile_bo, skip);
       encode_block_post_cdef(fi, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
-                     mode_luma, mode_chroma, ref_frames, mvs, bsize, tile_bo, skip, cfl,
-                     tx_size, tx_type, mode_context, &mv_stack, RDOType::PixelDistRealRate, true);
+                     mode_luma, mode_chroma, ref_frames, mvs, compound, bsize, tile_bo, skip, cfl,
+                     filter_intra, palette, tx_size, tx_type, mode_context, &mv_stack, RDOType::PixelDistRealRate, true);
     },
     PARTITION_SPLIT |
     PARTITION_HORZ |
@@ -1934,7 +2789,8 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
                                    &Some(RDOOutput {
                                      rd_cost: mode.rd_cost,
                                      part_type: PartitionType::PARTITION_NONE,
-                                     part_modes: vec![mode] }), pmvs);
+                                     part_modes: vec![mode],
+                                     part_children: Vec::new() }), pmvs);
         }
       }
       else {
@@ -1963,38 +2819,89 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
         });
       }
     },
+    PARTITION_HORZ_A |
+    PARTITION_HORZ_B |
+    PARTITION_VERT_A |
+    PARTITION_VERT_B |
+    PARTITION_HORZ_4 |
+    PARTITION_VERT_4 => {
+      // Only chosen by rdo_partition_decision(), along with the modes
+      debug_assert!(!rdo_output.part_modes.is_empty());
+      for mode in rdo_output.part_modes.iter() {
+        if !mode.pred_mode_luma.is_intra() {
+          save_block_motion(
+            ts, mode.bsize, mode.bo,
+            mode.ref_frames[0].to_index(), mode.mvs[0]
+          );
+        }
+        encode_block_with_modes(fi, ts, cw, w_pre_cdef, w_post_cdef, mode.bsize,
+                                mode.bo, mode, RDOType::PixelDistRealRate);
+      }
+    },
     _ => unreachable!(),
   }
 
   if is_square && bsize.gte(BlockSize::BLOCK_8X8) &&
     (bsize == BlockSize::BLOCK_8X8 || partition != PartitionType::PARTITION_SPLIT) {
-      cw.bc.update_partition_context(tile_bo, subsize, bsize);
+      // The halves of the partitions into three blocks are set apart
+      let hbs = bsize.width_mi() as isize >> 1;
+      let sqr = bsize.subsize(PartitionType::PARTITION_SPLIT);
+      match partition {
+        PARTITION_HORZ_A => {
+          cw.bc.update_partition_context(tile_bo, sqr, subsize);
+          cw.bc.update_partition_context(tile_bo.with_offset(0, hbs), subsize, subsize);
+        }
+        PARTITION_HORZ_B => {
+          cw.bc.update_partition_context(tile_bo, subsize, subsize);
+          cw.bc.update_partition_context(tile_bo.with_offset(0, hbs), sqr, subsize);
+        }
+        PARTITION_VERT_A => {
+          cw.bc.update_partition_context(tile_bo, sqr, subsize);
+          cw.bc.update_partition_context(tile_bo.with_offset(hbs, 0), subsize, subsize);
+        }
+        PARTITION_VERT_B => {
+          cw.bc.update_partition_context(tile_bo, subsize, subsize);
+          cw.bc.update_partition_context(tile_bo.with_offset(hbs, 0), sqr, subsize);
+        }
+        _ => cw.bc.update_partition_context(tile_bo, subsize, bsize)
+      }
     }
 }
 
 #[inline(always)]
-fn build_coarse_pmvs<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>) -> Vec<[Option<MotionVector>; REF_FRAMES]> {
+/// Coarse motion vectors of each superblock, along with the sum of their
+/// lowest costs
+fn build_coarse_pmvs<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>) -> (Vec<[Option<MotionVector>; REF_FRAMES]>, u64) {
   assert!(!fi.sequence.use_128x128_superblock);
   if ts.mi_width >= 16 && ts.mi_height >= 16 {
     let mut frame_pmvs = Vec::with_capacity(ts.sb_width * ts.sb_height);
+    let mut me_cost = 0;
     for sby in 0..ts.sb_height {
       for sbx in 0..ts.sb_width {
         let sbo = SuperBlockOffset { x: sbx, y: sby };
         let bo = sbo.block_offset(0, 0);
         let mut pmvs: [Option<MotionVector>; REF_FRAMES] = [None; REF_FRAMES];
+        let mut lowest_cost = None;
         for i in 0..INTER_REFS_PER_FRAME {
           let r = fi.ref_frames[i] as usize;
           if pmvs[r].is_none() {
-            pmvs[r] = estimate_motion_ss4(fi, ts, BlockSize::BLOCK_64X64, r, bo);
+            if let Some((mv, cost)) =
+              estimate_motion_ss4(fi, ts, BlockSize::BLOCK_64X64, r, bo)
+            {
+              pmvs[r] = Some(mv);
+              lowest_cost = Some(lowest_cost.map_or(cost, |c: u64| c.min(cost)));
+            }
           }
         }
+        // An empty search range leaves the cost at its initial maximum
+        me_cost += lowest_cost.filter(|&c| c != std::u64::MAX).unwrap_or(0);
         frame_pmvs.push(pmvs);
       }
     }
-    frame_pmvs
+    (frame_pmvs, me_cost)
   } else {
     // the block use for motion estimation would be smaller than the whole image
-    vec![[None; REF_FRAMES]; ts.sb_width * ts.sb_height]
+    (vec![[None; REF_FRAMES]; ts.sb_width * ts.sb_height], 0)
   }
 }
 
@@ -2018,34 +2925,92 @@ fn encode_tile_group<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>)
   let initial_cdf = get_initial_cdfcontext(fi);
   let mut cdfs = vec![initial_cdf; ti.tile_count()];
 
-  let (raw_tiles, rdo_trackers): (Vec<_>, Vec<_>) = ti
+  fs.deblock.block_deltas_enabled = fi.delta_q_present
+    && fi.config.speed_settings.deblock_deltas
+    && !fi.allow_intrabc;
+  fs.deblock.block_delta_shift = 1;
+  let deferred = fs.deblock.block_deltas_enabled;
+
+  // A tile only reads the frame state shared by all of them and writes to
+  // its own views, and the results are gathered in tile order, so nothing
+  // the encoder decides depends on how the tiles are spread over threads.
+  let (tiles, tile_stats): (Vec<_>, Vec<_>) = ti
     .tile_iter_mut(fs, &mut blocks)
     .zip(cdfs.iter_mut())
     .collect::<Vec<_>>()
     .into_par_iter()
     .map(|(mut ctx, cdf)| {
-      let raw = encode_tile(fi, &mut ctx.ts, cdf, &mut ctx.tb);
-      (raw, ctx.ts.rdo)
+      let tile = if deferred {
+        let mut w = WriterRecorder::new();
+        let sb_deltas_pos = encode_tile(fi, &mut ctx.ts, cdf, &mut ctx.tb, &mut w);
+        CodedTile::Recorded(w, sb_deltas_pos)
+      } else {
+        let mut w = WriterEncoder::new();
+        encode_tile(fi, &mut ctx.ts, cdf, &mut ctx.tb, &mut w);
+        CodedTile::Done(w.done())
+      };
+      (tile, (ctx.ts.rdo, ctx.ts.me_cost, ctx.ts.stage_times))
     })
     .unzip();
+  let mut rdo_trackers = Vec::with_capacity(tile_stats.len());
+  fs.me_cost = 0;
+  fs.stage_times = StageTimes::default();
+  for (rdo_tracker, me_cost, stage_times) in tile_stats {
+    rdo_trackers.push(rdo_tracker);
+    fs.me_cost += me_cost;
+    fs.stage_times += stage_times;
+  }
+  if fi.config.log_block_decisions {
+    fs.block_decisions = block_log::block_decisions(fi, fs, &blocks);
+  }
+
+  let timer = StageTimer::start(Stage::LoopFilters, fi.config.time_stages, &fs.stage_times);
+  // All loop filters are disabled in frames using IntraBC
+  if fi.allow_intrabc {
+    fs.deblock.levels = [0; 4];
+  } else {
+    /* TODO: Don't apply if lossless */
+    deblock_filter_optimize(fi, fs, &blocks);
+    // Frames repeating a reference are left as they are
+    if fi.duplicate_ref.is_some() {
+      fs.deblock.levels = [0; 4];
+    }
+    if deferred {
+      let sb_deltas = deblock_sb_deltas(fi, fs, &blocks);
+      for tile in tiles.iter() {
+        if let CodedTile::Recorded(_, sb_deltas_pos) = tile {
+          set_sb_deblock_deltas(fi, &mut blocks, sb_deltas_pos, &sb_deltas);
+        }
+      }
+    }
+    if fs.deblock.levels[0] != 0 || fs.deblock.levels[1] != 0 {
+      deblock_filter_frame(fi, fs, &blocks);
+    }
 
-  /* TODO: Don't apply if lossless */
-  deblock_filter_optimize(fi, fs, &blocks);
-  if fs.deblock.levels[0] != 0 || fs.deblock.levels[1] != 0 {
-    deblock_filter_frame(fi, fs, &blocks);
-  }
-
-  // Until the loop filters are pipelined, we'll need to keep
-  // around a copy of both the pre- and post-cdef frame.
-  let pre_cdef_frame = fs.rec.clone();
+    // Until the loop filters are pipelined, we'll need to keep
+    // around a copy of both the pre- and post-cdef frame.
+    let pre_cdef_frame = fs.rec.clone();
 
-  /* TODO: Don't apply if lossless */
-  if fi.sequence.enable_cdef {
-    cdef_filter_frame(fi, &mut fs.rec, &blocks);
+    /* TODO: Don't apply if lossless */
+    if fi.sequence.enable_cdef {
+      cdef_filter_frame(fi, &mut fs.rec, &blocks);
+    }
+    /* TODO: Don't apply if lossless */
+    if fi.sequence.enable_restoration {
+      fs.restoration.lrf_filter_frame(&mut fs.rec, &pre_cdef_frame, &fi);
+    }
   }
-  /* TODO: Don't apply if lossless */
-  if fi.sequence.enable_restoration {
-    fs.restoration.lrf_filter_frame(&mut fs.rec, &pre_cdef_frame, &fi);
+  timer.stop(&mut fs.stage_times);
+
+  // Monochrome frames are reconstructed with neutral chroma, as decoders
+  // output them
+  if fi.sequence.num_planes() == 1 {
+    let neutral = T::cast_from(1u16 << (fi.sequence.bit_depth - 1));
+    for p in 1..3 {
+      for v in fs.rec.planes[p].data.iter_mut() {
+        *v = neutral;
+      }
+    }
   }
 
   if fi.config.train_rdo {
@@ -2063,6 +3028,41 @@ fn encode_tile_group<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>)
     fs.t.print_code();
   }
 
+  let raw_tiles: Vec<Vec<u8>> = tiles
+    .into_iter()
+    .zip(cdfs.iter_mut())
+    .map(|(tile, fc)| match tile {
+      CodedTile::Done(raw) => raw,
+      CodedTile::Recorded(mut rec, sb_deltas_pos) => {
+        let multi = fs.deblock.block_delta_multi;
+        // Monochrome frames have no chroma loop filter levels
+        let lf_count = if fi.sequence.num_planes() > 1 {
+          FRAME_LF_COUNT
+        } else {
+          FRAME_LF_COUNT - 2
+        };
+        let mut prev = [0; FRAME_LF_COUNT];
+        let mut splices = Vec::new();
+        for &(sbo, pos) in sb_deltas_pos.iter() {
+          let deltas = blocks[sbo.block_offset(0, 0)].deblock_deltas;
+          if let Some(pos) = pos {
+            let coded: Vec<i8> =
+              deltas.iter().zip(prev.iter()).map(|(&d, &p)| d - p).collect();
+            let mut splice = WriterRecorder::new();
+            fc.write_deblock_deltas(
+              &mut splice, &coded[..if multi { lf_count } else { 1 }], multi
+            );
+            splices.push((pos, splice));
+          }
+          prev = deltas;
+        }
+        let mut w = WriterEncoder::new();
+        rec.replay_spliced(&mut w, &splices);
+        w.done()
+      }
+    })
+    .collect();
+
   let (idx_max, max_len) = raw_tiles
     .iter()
     .map(Vec::len)
@@ -2070,9 +3070,11 @@ fn encode_tile_group<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>)
     .max_by_key(|&(_, len)| len)
     .unwrap();
 
-  // use the biggest tile (in bytes) for CDF update
+  // use the biggest tile (in bytes) for CDF update, unless the frame keeps
+  // the CDFs it started from
   fs.context_update_tile_id = idx_max;
-  fs.cdfs = cdfs[idx_max];
+  fs.cdfs =
+    if fi.disable_frame_end_update_cdf { initial_cdf } else { cdfs[idx_max] };
   fs.cdfs.reset_counts();
 
   let max_tile_size_bytes = ((max_len as u32).ilog() + 7) / 8;
@@ -2082,6 +3084,34 @@ fn encode_tile_group<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>)
   build_raw_tile_group(ti, &raw_tiles, max_tile_size_bytes)
 }
 
+/// A tile coded either straight to a bitstream, or recorded along with the
+/// positions of its superblock deblocking deltas
+enum CodedTile {
+  Done(Vec<u8>),
+  Recorded(WriterBase<WriterRecorder>, Vec<(SuperBlockOffset, Option<usize>)>),
+}
+
+/// Stores the deblocking deltas of the superblocks of a tile in their
+/// blocks.  Superblocks without coded deltas keep those of the superblock
+/// before them in the tile.
+fn set_sb_deblock_deltas<T: Pixel>(
+  fi: &FrameInvariants<T>, blocks: &mut FrameBlocks,
+  sb_deltas_pos: &[(SuperBlockOffset, Option<usize>)], sb_deltas: &[i8]
+) {
+  let mut delta = 0;
+  for &(sbo, pos) in sb_deltas_pos {
+    if pos.is_some() {
+      delta = sb_deltas[sbo.y * fi.sb_width + sbo.x];
+    }
+    let bo = sbo.block_offset(0, 0);
+    for y in bo.y..(bo.y + MAX_MIB_SIZE).min(blocks.rows) {
+      for x in bo.x..(bo.x + MAX_MIB_SIZE).min(blocks.cols) {
+        blocks[y][x].deblock_deltas = [delta; FRAME_LF_COUNT];
+      }
+    }
+  }
+}
+
 fn build_raw_tile_group(ti: &TilingInfo, raw_tiles: &[Vec<u8>], max_tile_size_bytes: u32) -> Vec<u8> {
   // <https://aomediacodec.github.io/av1-spec/#general-tile-group-obu-syntax>
   let mut raw = Vec::new();
@@ -2102,13 +3132,32 @@ fn build_raw_tile_group(ti: &TilingInfo, raw_tiles: &[Vec<u8>], max_tile_size_by
   raw
 }
 
-fn encode_tile<'a, T: Pixel>(
+// Searches and codes the partitions of a superblock
+fn encode_superblock<T: Pixel, W: Writer>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
+  w_pre_cdef: &mut W, w_post_cdef: &mut W, tile_bo: BlockOffset,
+  pmvs: &mut [[Option<MotionVector>; REF_FRAMES]; 5]
+) {
+  if fi.config.speed_settings.encode_bottomup && fi.duplicate_ref.is_none() {
+    encode_partition_bottomup(fi, ts, cw, w_pre_cdef, w_post_cdef,
+                              BlockSize::BLOCK_64X64, tile_bo, pmvs, std::f64::MAX);
+  } else {
+    encode_partition_topdown(fi, ts, cw, w_pre_cdef, w_post_cdef,
+                             BlockSize::BLOCK_64X64, tile_bo, &None, pmvs);
+  }
+}
+
+/// Encodes a tile into `w`.  When superblock deblocking deltas are
+/// enabled, returns for each superblock where its deltas must be spliced
+/// into the recorded tile, if they are coded at all.
+fn encode_tile<'a, T: Pixel, W: Writer + StorageBackend>(
   fi: &FrameInvariants<T>,
   ts: &mut TileStateMut<'_, T>,
   fc: &'a mut CDFContext,
   blocks: &'a mut TileBlocksMut<'a>,
-) -> Vec<u8> {
-  let mut w = WriterEncoder::new();
+  w: &mut W,
+) -> Vec<(SuperBlockOffset, Option<usize>)> {
+  let mut sb_deltas_pos = Vec::new();
 
   let estimate_motion_ss2 = if fi.config.speed_settings.diamond_me {
     crate::me::DiamondSearch::estimate_motion_ss2
@@ -2116,15 +3165,30 @@ fn encode_tile<'a, T: Pixel>(
     crate::me::FullSearch::estimate_motion_ss2
   };
 
-  let bc = BlockContext::new(blocks);
+  let mut bc = BlockContext::new(blocks);
+  bc.current_qindex = fi.base_q_idx;
   // For now, restoration unit size is locked to superblock size.
   let mut cw = ContextWriter::new(fc, bc);
 
-  let tile_pmvs = build_coarse_pmvs(fi, ts);
+  #[cfg(feature = "tracing")]
+  let _span =
+    tracing::debug_span!("encode_tile", sbx = ts.sbo.x, sby = ts.sbo.y).entered();
+
+  // Intra frames and frames repeating a reference need no motion search
+  let timer = StageTimer::start(Stage::MotionEstimation, fi.config.time_stages, &ts.stage_times);
+  let (tile_pmvs, me_cost) = if fi.intra_only || fi.duplicate_ref.is_some() {
+    (vec![[None; REF_FRAMES]; ts.sb_width * ts.sb_height], 0)
+  } else {
+    build_coarse_pmvs(fi, ts)
+  };
+  ts.me_cost = me_cost;
+  timer.stop(&mut ts.stage_times);
 
   // main loop
   for sby in 0..ts.sb_height {
     cw.bc.reset_left_contexts();
+    // Follow the adaptation of the motion vector CDFs once per superblock row
+    ts.mv_rates = MvRates::new(cw.fc, fi.mv_precision());
 
     for sbx in 0..ts.sb_width {
       let mut w_pre_cdef = WriterRecorder::new();
@@ -2133,10 +3197,16 @@ fn encode_tile<'a, T: Pixel>(
       let tile_bo = tile_sbo.block_offset(0, 0);
       cw.bc.cdef_coded = false;
       cw.bc.code_deltas = fi.delta_q_present;
+      cw.bc.deltas_pos = None;
+      if fi.use_tx_domain_rate {
+        ts.coeff_rates.update(cw.fc);
+      }
+      ts.arena.reset();
 
       // Do subsampled ME
+      let timer = StageTimer::start(Stage::MotionEstimation, fi.config.time_stages, &ts.stage_times);
       let mut pmvs: [[Option<MotionVector>; REF_FRAMES]; 5] = [[None; REF_FRAMES]; 5];
-      if ts.mi_width >= 8 && ts.mi_height >= 8 {
+      if ts.mi_width >= 8 && ts.mi_height >= 8 && fi.duplicate_ref.is_none() {
         for i in 0..INTER_REFS_PER_FRAME {
           let r = fi.ref_frames[i] as usize;
           if pmvs[0][r].is_none() {
@@ -2194,42 +3264,75 @@ fn encode_tile<'a, T: Pixel>(
         }
       }
 
-      // Encode SuperBlock
-      if fi.config.speed_settings.encode_bottomup {
-        encode_partition_bottomup(fi, ts, &mut cw,
-                                  &mut w_pre_cdef, &mut w_post_cdef,
-                                  BlockSize::BLOCK_64X64, tile_bo, &mut pmvs, std::f64::MAX);
-      }
-      else {
-        encode_partition_topdown(fi, ts, &mut cw,
-                                 &mut w_pre_cdef, &mut w_post_cdef,
-                                 BlockSize::BLOCK_64X64, tile_bo, &None, &mut pmvs);
+      timer.stop(&mut ts.stage_times);
+
+      let timer = StageTimer::start(Stage::ModeDecision, fi.config.time_stages, &ts.stage_times);
+      if fi.config.speed_settings.superblock_second_pass
+        && fi.config.speed_settings.encode_bottomup
+        && fi.duplicate_ref.is_none()
+      {
+        // Code the superblock once only to adapt the CDFs, and search it
+        // again from the same block context with the adapted CDFs. The
+        // decisions are then coded from the CDFs the decoder starts with.
+        let checkpoint = cw.checkpoint();
+        let mut first_pass_pmvs = pmvs;
+        encode_superblock(fi, ts, &mut cw, &mut WriterRecorder::new(), &mut WriterRecorder::new(),
+                          tile_bo, &mut first_pass_pmvs);
+        ts.mv_rates = MvRates::new(cw.fc, fi.mv_precision());
+        ts.coeff_rates.update(cw.fc);
+        let adapted_fc = *cw.fc;
+        cw.rollback(&checkpoint);
+        *cw.fc = adapted_fc;
+        let decision = encode_partition_bottomup(
+          fi, ts, &mut cw, &mut WriterRecorder::new(), &mut WriterRecorder::new(),
+          BlockSize::BLOCK_64X64, tile_bo, &mut pmvs, std::f64::MAX
+        );
+        cw.rollback(&checkpoint);
+        encode_partition_decided(fi, ts, &mut cw, &mut w_pre_cdef, &mut w_post_cdef,
+                                 BlockSize::BLOCK_64X64, tile_bo, &decision);
+      } else {
+        encode_superblock(fi, ts, &mut cw, &mut w_pre_cdef, &mut w_post_cdef, tile_bo, &mut pmvs);
       }
+      timer.stop(&mut ts.stage_times);
 
       // CDEF has to be decided before loop restoration, but coded after.
       // loop restoration must be decided last but coded before anything else.
-      if cw.bc.cdef_coded || fi.sequence.enable_restoration {
-        rdo_loop_decision(tile_sbo, fi, ts, &mut cw, &mut w);
+      if (cw.bc.cdef_coded || fi.sequence.enable_restoration) && !fi.allow_intrabc {
+        let timer = StageTimer::start(Stage::LoopFilters, fi.config.time_stages, &ts.stage_times);
+        rdo_loop_decision(tile_sbo, fi, ts, &mut cw, w);
+        timer.stop(&mut ts.stage_times);
       }
 
+      let timer = StageTimer::start(Stage::EntropyCoding, fi.config.time_stages, &ts.stage_times);
       if fi.sequence.enable_restoration {
-        cw.write_lrf(&mut w, fi, &mut ts.restoration, tile_sbo);
+        cw.write_lrf(w, fi, &mut ts.restoration, tile_sbo);
       }
 
       // Once loop restoration is coded, we can replay the initial block bits
-      w_pre_cdef.replay(&mut w);
+      let pre_cdef_start = Writer::checkpoint(w).recorded_tokens();
+      w_pre_cdef.replay(w);
 
+      let mut post_cdef_start = 0;
       if cw.bc.cdef_coded {
         // CDEF index must be written in the middle, we can code it now
         let cdef_index = cw.bc.blocks.get_cdef(tile_sbo);
-        cw.write_cdef(&mut w, cdef_index, fi.cdef_bits);
+        cw.write_cdef(w, cdef_index, fi.cdef_bits);
         // ...and then finally code what comes after the CDEF index
-        w_post_cdef.replay(&mut w);
+        post_cdef_start = Writer::checkpoint(w).recorded_tokens();
+        w_post_cdef.replay(w);
+      }
+      timer.stop(&mut ts.stage_times);
+
+      if ts.deblock.block_deltas_enabled {
+        let pos = cw.bc.deltas_pos.map(|(post_cdef, pos)| {
+          pos + if post_cdef { post_cdef_start } else { pre_cdef_start }
+        });
+        sb_deltas_pos.push((ts.to_frame_super_block_offset(tile_sbo), pos));
       }
     }
   }
 
-  w.done()
+  sb_deltas_pos
 }
 
 #[allow(unused)]
@@ -2268,6 +3371,9 @@ pub fn encode_frame<T: Pixel>(
   fi: &FrameInvariants<T>, fs: &mut FrameState<T>
 ) -> Vec<u8> {
   debug_assert!(!fi.show_existing_frame);
+  #[cfg(feature = "tracing")]
+  let _span =
+    tracing::info_span!("encode_frame", input_frameno = fi.input_frameno).entered();
   let mut packet = Vec::new();
 
   fs.input_hres.downsample_from(&fs.input.planes[0]);
@@ -2277,13 +3383,31 @@ pub fn encode_frame<T: Pixel>(
 
   segmentation_optimize(fi, fs);
 
+  if fi.config.activity_masking {
+    fs.activity_mask = ActivityMask::from_plane(
+      &fs.input.planes[0],
+      fi.width,
+      fi.height,
+      fi.sequence.bit_depth
+    );
+  }
+
+  if fi.config.tune == Tune::Ssim {
+    fs.mean_block_variance =
+      mean_block_variance(&fs.input.planes[0], fi.width, fi.height);
+  }
+
+  if fi.allow_intrabc {
+    fs.intrabc_hash = Some(BlockHashMap::new(&fs.input.planes[0]));
+  }
+
   let tile_group = encode_tile_group(fi, fs);
 
   write_obus(&mut packet, fi, fs).unwrap();
   let mut buf1 = Vec::new();
   {
     let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
-    bw1.write_obu_header(ObuType::OBU_TILE_GROUP, 0).unwrap();
+    bw1.write_obu_header(ObuType::OBU_TILE_GROUP, obu_extension(fi)).unwrap();
   }
   packet.write_all(&buf1).unwrap();
   buf1.clear();
@@ -2303,10 +3427,15 @@ pub fn update_rec_buffer<T: Pixel>(fi: &mut FrameInvariants<T>, fs: FrameState<T
   let rfs = Arc::new(
     ReferenceFrame {
       order_hint: fi.order_hint,
+      width: fi.upscaled_width,
+      height: fi.height,
       frame: fs.rec,
       input_hres: fs.input_hres,
       input_qres: fs.input_qres,
       cdfs: fs.cdfs,
+      base_q_idx: fi.base_q_idx,
+      pyramid_level: fi.pyramid_level,
+      intra_only: fi.intra_only,
       frame_mvs: fs.frame_mvs,
     }
   );
@@ -2327,4 +3456,73 @@ mod test {
     assert_eq!(RAV1E_PARTITION_TYPES[RAV1E_PARTITION_TYPES.len() - 1],
                PartitionType::PARTITION_SPLIT);
   }
+
+  #[test]
+  fn newmv_ref_mv_idx_picks_closest_candidate() {
+    let candidate = |row, col| CandidateMV {
+      this_mv: MotionVector { row, col },
+      comp_mv: MotionVector::default(),
+      weight: 0
+    };
+    let mv_stack = [candidate(0, 0), candidate(64, -32), candidate(64, -24), candidate(64, -28)];
+    let mvs = [MotionVector { row: 64, col: -28 }, MotionVector::default()];
+
+    let mv_rates = MvRates::default();
+    assert_eq!(newmv_ref_mv_idx(mvs, &[], false, &mv_rates), 0);
+    assert_eq!(newmv_ref_mv_idx(mvs, &mv_stack[..1], false, &mv_rates), 0);
+    // The fourth candidate can't be signalled
+    assert_eq!(newmv_ref_mv_idx(mvs, &mv_stack, false, &mv_rates), 1);
+    assert_eq!(newmv_ref_mv_idx(mvs, &mv_stack[..2], false, &mv_rates), 1);
+  }
+
+  #[test]
+  fn cdfs_inherited_from_closest_quantizer() {
+    let enc = EncoderConfig::default();
+    let seq = Sequence::new(&enc);
+    let mut fi = FrameInvariants::<u8>::new(enc, seq);
+    let reference = |order_hint, base_q_idx, pyramid_level| {
+      Some(Arc::new(ReferenceFrame {
+        order_hint,
+        width: 16,
+        height: 16,
+        frame: Frame::new(16, 16, ChromaSampling::Cs420),
+        input_hres: Plane::new(8, 8, 1, 1, 0, 0),
+        input_qres: Plane::new(4, 4, 2, 2, 0, 0),
+        cdfs: CDFContext::new(base_q_idx),
+        base_q_idx,
+        pyramid_level,
+        intra_only: false,
+        frame_mvs: Vec::new()
+      }))
+    };
+    fi.rec_buffer.frames[0] = reference(7, 200, 2);
+    fi.rec_buffer.frames[1] = reference(4, 110, 1);
+    fi.rec_buffer.frames[2] = reference(2, 90, 2);
+    fi.rec_buffer.frames[3] = reference(0, 10, 0);
+    for i in 0..INTER_REFS_PER_FRAME {
+      fi.ref_frames[i] = (i % 4) as u8;
+    }
+    fi.frame_type = FrameType::INTER;
+    fi.intra_only = false;
+    fi.order_hint = 8;
+    fi.pyramid_level = 2;
+    fi.refresh_frame_flags = 1;
+
+    // Same quantizer context and pyramid level
+    fi.base_q_idx = 100;
+    fi.set_cdf_inheritance();
+    assert_eq!(fi.ref_frames[fi.primary_ref_frame as usize], 2);
+    assert!(!fi.disable_frame_end_update_cdf);
+
+    // The others are too far in quantizer
+    fi.base_q_idx = 10;
+    fi.set_cdf_inheritance();
+    assert_eq!(fi.ref_frames[fi.primary_ref_frame as usize], 3);
+
+    fi.error_resilient = true;
+    fi.refresh_frame_flags = 0;
+    fi.set_cdf_inheritance();
+    assert_eq!(fi.primary_ref_frame, PRIMARY_REF_NONE);
+    assert!(fi.disable_frame_end_update_cdf);
+  }
 }
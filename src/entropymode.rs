@@ -14,10 +14,10 @@ use crate::partition::*;
 use crate::predict::*;
 use crate::transform::*;
 
-const PALATTE_BSIZE_CTXS: usize = 7;
-const PALETTE_Y_MODE_CONTEXTS: usize = 3;
-const PALETTE_UV_MODE_CONTEXTS: usize = 2;
-const PALETTE_COLOR_INDEX_CONTEXTS: usize = 5;
+pub const PALATTE_BSIZE_CTXS: usize = 7;
+pub const PALETTE_Y_MODE_CONTEXTS: usize = 3;
+pub const PALETTE_UV_MODE_CONTEXTS: usize = 2;
+pub const PALETTE_COLOR_INDEX_CONTEXTS: usize = 5;
 const CDFMAX: u16 = 32768;
 const BLOCK_SIZE_GROUPS: usize = 4;
 const RESTORE_SWITCHABLE_TYPES: usize = 3;
@@ -957,8 +957,7 @@ pub static default_wedge_interintra_cdf: [[u16; cdf_size!(2)];
 ];
 
 #[allow(unused)]
-pub static default_compound_type_cdf: [[u16;
-  cdf_size!(CompoundType::COMPOUND_TYPES as usize - 1)];
+pub static default_compound_type_cdf: [[u16; cdf_size!(2)];
   BlockSize::BLOCK_SIZES_ALL as usize] = [
   cdf!(16384),
   cdf!(16384),
@@ -1186,7 +1185,6 @@ pub static default_comp_bwdref_cdf: [[[u16; cdf_size!(2)]; BWD_REFS - 1];
   [cdf!(30606), cdf!(30489)]
 ];
 
-#[allow(unused)]
 pub static default_palette_y_size_cdf: [[u16;
   cdf_size!(PaletteSize::PALETTE_SIZES as usize)];
   PALATTE_BSIZE_CTXS] = [
@@ -1212,7 +1210,6 @@ pub static default_palette_uv_size_cdf: [[u16;
   cdf!(1269, 5435, 10433, 18963, 21700, 25865)
 ];
 
-#[allow(unused)]
 pub static default_palette_y_mode_cdf: [[[u16; cdf_size!(2)];
   PALETTE_Y_MODE_CONTEXTS];
   PALATTE_BSIZE_CTXS] = [
@@ -1225,11 +1222,9 @@ pub static default_palette_y_mode_cdf: [[[u16; cdf_size!(2)];
   [cdf!(32450), cdf!(7946), cdf!(129)]
 ];
 
-#[allow(unused)]
 pub static default_palette_uv_mode_cdf: [[u16; cdf_size!(2)];
   PALETTE_UV_MODE_CONTEXTS] = [cdf!(32461), cdf!(21488)];
 
-#[allow(unused)]
 pub static default_palette_y_color_index_cdf: [[[u16;
   cdf_size!(PaletteColor::PALETTE_COLORS as usize)];
   PALETTE_COLOR_INDEX_CONTEXTS];
@@ -1341,7 +1336,6 @@ pub static default_palette_uv_color_index_cdf: [[[u16;
   ]
 ];
 
-#[allow(unused)]
 pub static default_txfm_partition_cdf: [[u16; cdf_size!(2)];
   TXFM_PARTITION_CONTEXTS] = [
   cdf!(28581),
@@ -0,0 +1,544 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::api::TransferCharacteristics;
+use arrayvec::ArrayVec;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
+
+/// Maximum number of luma scaling points allowed by the bitstream
+pub const MAX_NUM_Y_POINTS: usize = 14;
+/// Maximum number of scaling points allowed for each chroma plane
+pub const MAX_NUM_UV_POINTS: usize = 10;
+/// Maximum number of auto-regressive luma coefficients (`ar_coeff_lag` of 3)
+pub const MAX_NUM_POS_LUMA: usize = 24;
+/// Maximum number of auto-regressive chroma coefficients, which have an
+/// extra tap for the co-located luma grain
+pub const MAX_NUM_POS_CHROMA: usize = MAX_NUM_POS_LUMA + 1;
+
+/// A scaling function control point, as `[intensity, scaling]`.
+pub type ScalingPoint = [u8; 2];
+
+/// Film grain synthesis parameters, as signaled in the frame header.
+///
+/// The decoder regenerates the grain from these parameters and adds it to
+/// the reconstructed frame, so only the clean signal needs to be coded.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FilmGrainParams {
+  /// Seed of the pseudo-random grain generator, changed for every frame
+  pub random_seed: u16,
+  /// Piecewise-linear scaling function for luma, with increasing intensities
+  pub scaling_points_y: ArrayVec<[ScalingPoint; MAX_NUM_Y_POINTS]>,
+  /// Reuse the luma scaling function for both chroma planes
+  pub chroma_scaling_from_luma: bool,
+  pub scaling_points_cb: ArrayVec<[ScalingPoint; MAX_NUM_UV_POINTS]>,
+  pub scaling_points_cr: ArrayVec<[ScalingPoint; MAX_NUM_UV_POINTS]>,
+  /// Right shift applied to the scaled grain, in the range 8..=11
+  pub scaling_shift: u8,
+  /// Size of the auto-regressive filter neighborhood, in the range 0..=3
+  pub ar_coeff_lag: u8,
+  pub ar_coeffs_y: ArrayVec<[i8; MAX_NUM_POS_LUMA]>,
+  pub ar_coeffs_cb: ArrayVec<[i8; MAX_NUM_POS_CHROMA]>,
+  pub ar_coeffs_cr: ArrayVec<[i8; MAX_NUM_POS_CHROMA]>,
+  /// Right shift of the auto-regressive coefficients, in the range 6..=9
+  pub ar_coeff_shift: u8,
+  /// Downscaling of the gaussian grain, in the range 0..=3
+  pub grain_scale_shift: u8,
+  pub cb_mult: u8,
+  pub cb_luma_mult: u8,
+  pub cb_offset: u16,
+  pub cr_mult: u8,
+  pub cr_luma_mult: u8,
+  pub cr_offset: u16,
+  /// Blend overlapping grain blocks to hide the block boundaries
+  pub overlap_flag: bool,
+  pub clip_to_restricted_range: bool,
+}
+
+impl Default for FilmGrainParams {
+  fn default() -> Self {
+    FilmGrainParams {
+      random_seed: 0,
+      scaling_points_y: ArrayVec::new(),
+      chroma_scaling_from_luma: false,
+      scaling_points_cb: ArrayVec::new(),
+      scaling_points_cr: ArrayVec::new(),
+      scaling_shift: 8,
+      ar_coeff_lag: 0,
+      ar_coeffs_y: ArrayVec::new(),
+      ar_coeffs_cb: ArrayVec::new(),
+      ar_coeffs_cr: ArrayVec::new(),
+      ar_coeff_shift: 6,
+      grain_scale_shift: 0,
+      // Index the chroma scaling functions with the co-located luma value
+      cb_mult: 128,
+      cb_luma_mult: 192,
+      cb_offset: 256,
+      cr_mult: 128,
+      cr_luma_mult: 192,
+      cr_offset: 256,
+      overlap_flag: true,
+      clip_to_restricted_range: false,
+    }
+  }
+}
+
+impl FilmGrainParams {
+  /// Number of auto-regressive coefficients coded for luma.
+  pub fn num_pos_luma(&self) -> usize {
+    let lag = self.ar_coeff_lag as usize;
+    2 * lag * (lag + 1)
+  }
+
+  /// Number of auto-regressive coefficients coded for each chroma plane.
+  pub fn num_pos_chroma(&self) -> usize {
+    if self.scaling_points_y.is_empty() {
+      self.num_pos_luma()
+    } else {
+      self.num_pos_luma() + 1
+    }
+  }
+
+  /// Whether the parameters would add any grain at all.
+  pub fn apply_grain(&self) -> bool {
+    !self.scaling_points_y.is_empty()
+      || self.chroma_scaling_from_luma
+      || !self.scaling_points_cb.is_empty()
+      || !self.scaling_points_cr.is_empty()
+  }
+
+  /// Seed for the frame with the given input number, so that consecutive
+  /// frames do not repeat the same grain pattern.
+  pub fn seed_for_frame(input_frameno: u64) -> u16 {
+    (input_frameno as u16).wrapping_mul(3381).wrapping_add(7391)
+  }
+
+  /// Luma grain of the photon shot noise and read noise of a full frame
+  /// (36x24 mm) camera sensor shooting at the given ISO sensitivity, for a
+  /// `width` by `height` picture coded with the `transfer` function.
+  ///
+  /// This follows the photon noise tables of libaom and SVT-AV1: the noise
+  /// of each intensity is estimated in linear light, then mapped through
+  /// the slope of the transfer function. Transfer functions other than
+  /// sRGB, PQ and HLG are taken for BT.1886.
+  pub fn photon_noise(
+    iso: u32, width: usize, height: usize, transfer: TransferCharacteristics
+  ) -> Self {
+    // Photons of a daylight-like spectrum, per lux second and square micron
+    const PHOTONS_PER_LX_S_PER_UM2: f64 = 11260.;
+    // Of cameras of the 2010s, taking the color filter array into account
+    const EFFECTIVE_QUANTUM_EFFICIENCY: f64 = 0.2;
+    const PHOTO_RESPONSE_NON_UNIFORMITY: f64 = 0.005;
+    // In electrons
+    const INPUT_REFERRED_READ_NOISE: f64 = 1.5;
+
+    let transfer = PhotonNoiseTransfer::from(transfer);
+    // Focal plane exposure of a mid-tone (an 18% gray card), in lux seconds
+    let mid_tone_exposure = 10. / iso.max(1) as f64;
+    let pixel_area_um2 =
+      (36_000. * 24_000.) / (width.max(1) * height.max(1)) as f64;
+    let mid_tone_electrons = EFFECTIVE_QUANTUM_EFFICIENCY
+      * PHOTONS_PER_LX_S_PER_UM2
+      * mid_tone_exposure
+      * pixel_area_um2;
+    let max_electrons = mid_tone_electrons / transfer.mid_tone();
+
+    let mut params = FilmGrainParams::default();
+    for i in 0..MAX_NUM_Y_POINTS {
+      let x = i as f64 / (MAX_NUM_Y_POINTS - 1) as f64;
+      let linear = transfer.to_linear(x);
+      let electrons = max_electrons * linear;
+      // Sum in quadrature of the read noise, the shot noise (the square
+      // root of the electrons) and the photo response non-uniformity
+      let noise_electrons = (INPUT_REFERRED_READ_NOISE.powi(2)
+        + electrons
+        + (PHOTO_RESPONSE_NON_UNIFORMITY * electrons).powi(2))
+      .sqrt();
+      let linear_noise = noise_electrons / max_electrons;
+      let start = (linear - 2. * linear_noise).max(0.);
+      let end = (linear + 2. * linear_noise).min(1.);
+      let slope = (transfer.to_signal(end) - transfer.to_signal(start))
+        / (end - start);
+      let encoded_noise = linear_noise * slope;
+      params.scaling_points_y.push([
+        (255. * x).round() as u8,
+        (255. * 7.88 * encoded_noise).round().min(255.) as u8,
+      ]);
+    }
+    params.random_seed = 7391;
+    params
+  }
+
+  /// Checks the constraints the bitstream places on the parameters.
+  pub fn is_valid(&self, subsampled: bool) -> bool {
+    fn increasing(points: &[ScalingPoint]) -> bool {
+      points.windows(2).all(|w| w[0][0] < w[1][0])
+    }
+
+    increasing(&self.scaling_points_y)
+      && increasing(&self.scaling_points_cb)
+      && increasing(&self.scaling_points_cr)
+      && self.scaling_shift >= 8 && self.scaling_shift <= 11
+      && self.ar_coeff_lag <= 3
+      && self.ar_coeff_shift >= 6 && self.ar_coeff_shift <= 9
+      && self.grain_scale_shift <= 3
+      && self.cb_offset < 512
+      && self.cr_offset < 512
+      && self.ar_coeffs_y.len() == if self.scaling_points_y.is_empty() {
+        0
+      } else {
+        self.num_pos_luma()
+      }
+      && (!subsampled
+        || self.scaling_points_cb.is_empty()
+          == self.scaling_points_cr.is_empty())
+  }
+}
+
+/// Transfer functions the photon noise is modeled for, on signals and linear
+/// light normalized to 0..=1
+#[derive(Clone, Copy)]
+enum PhotonNoiseTransfer {
+  BT1886,
+  SRGB,
+  PQ,
+  HLG,
+}
+
+impl From<TransferCharacteristics> for PhotonNoiseTransfer {
+  fn from(transfer: TransferCharacteristics) -> Self {
+    match transfer {
+      TransferCharacteristics::SRGB => PhotonNoiseTransfer::SRGB,
+      TransferCharacteristics::PerceptualQuantizer => PhotonNoiseTransfer::PQ,
+      TransferCharacteristics::HybridLogGamma => PhotonNoiseTransfer::HLG,
+      _ => PhotonNoiseTransfer::BT1886,
+    }
+  }
+}
+
+impl PhotonNoiseTransfer {
+  // Constants of SMPTE ST 2084 and ARIB STD-B67
+  const PQ_M1: f64 = 2610. / 16384.;
+  const PQ_M2: f64 = 128. * 2523. / 4096.;
+  const PQ_C1: f64 = 3424. / 4096.;
+  const PQ_C2: f64 = 32. * 2413. / 4096.;
+  const PQ_C3: f64 = 32. * 2392. / 4096.;
+  const HLG_A: f64 = 0.178_832_77;
+  const HLG_B: f64 = 0.284_668_92;
+  const HLG_C: f64 = 0.559_910_73;
+
+  /// Linear light of a mid-tone
+  fn mid_tone(self) -> f64 {
+    match self {
+      PhotonNoiseTransfer::BT1886 | PhotonNoiseTransfer::SRGB => 0.184,
+      // 26 cd/m2, out of 10000 for PQ and 1000 for HLG
+      PhotonNoiseTransfer::PQ => 26. / 10000.,
+      PhotonNoiseTransfer::HLG => 26. / 1000.,
+    }
+  }
+
+  fn to_linear(self, x: f64) -> f64 {
+    match self {
+      PhotonNoiseTransfer::BT1886 => x.powf(2.4),
+      PhotonNoiseTransfer::SRGB => if x <= 0.04045 {
+        x / 12.92
+      } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+      },
+      PhotonNoiseTransfer::PQ => {
+        let p = x.powf(1. / Self::PQ_M2);
+        ((p - Self::PQ_C1).max(0.) / (Self::PQ_C2 - Self::PQ_C3 * p))
+          .powf(1. / Self::PQ_M1)
+      }
+      PhotonNoiseTransfer::HLG => if x <= 0.5 {
+        x * x / 3.
+      } else {
+        (((x - Self::HLG_C) / Self::HLG_A).exp() + Self::HLG_B) / 12.
+      },
+    }
+  }
+
+  fn to_signal(self, y: f64) -> f64 {
+    match self {
+      PhotonNoiseTransfer::BT1886 => y.powf(1. / 2.4),
+      PhotonNoiseTransfer::SRGB => if y <= 0.003_130_8 {
+        y * 12.92
+      } else {
+        1.055 * y.powf(1. / 2.4) - 0.055
+      },
+      PhotonNoiseTransfer::PQ => {
+        let p = y.powf(Self::PQ_M1);
+        ((Self::PQ_C1 + Self::PQ_C2 * p) / (1. + Self::PQ_C3 * p))
+          .powf(Self::PQ_M2)
+      }
+      PhotonNoiseTransfer::HLG => if y <= 1. / 12. {
+        (3. * y).sqrt()
+      } else {
+        Self::HLG_A * (12. * y - Self::HLG_B).ln() + Self::HLG_C
+      },
+    }
+  }
+}
+
+/// Film grain parameters over time, as in the film grain table files of
+/// aomenc (`--film-grain-table`)
+///
+/// The text format starts with a `filmgrn1` line, followed by a segment for
+/// each time range:
+///
+/// ```text
+/// E start_time end_time apply_grain random_seed update_parameters
+/// \tp ar_coeff_lag ar_coeff_shift grain_scale_shift scaling_shift
+///    chroma_scaling_from_luma overlap_flag cb_mult cb_luma_mult cb_offset
+///    cr_mult cr_luma_mult cr_offset
+/// \tsY num_points intensity scaling ...
+/// \tsCb num_points intensity scaling ...
+/// \tsCr num_points intensity scaling ...
+/// \tcY ar_coeffs ...
+/// \tcCb ar_coeffs ...
+/// \tcCr ar_coeffs ...
+/// ```
+///
+/// where times are in units of 1/10,000,000 seconds, and the parameters
+/// are only given when `update_parameters` is set, the segment reusing
+/// those of the previous one otherwise.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FilmGrainTable {
+  pub segments: Vec<FilmGrainSegment>,
+}
+
+/// Film grain of the frames displayed from `start_time` up to, but
+/// excluding, `end_time`
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FilmGrainSegment {
+  pub start_time: u64,
+  pub end_time: u64,
+  /// `None` where no grain is applied
+  pub params: Option<FilmGrainParams>,
+}
+
+impl From<FilmGrainParams> for FilmGrainTable {
+  /// The same grain for all the frames
+  fn from(params: FilmGrainParams) -> Self {
+    FilmGrainTable {
+      segments: vec![FilmGrainSegment {
+        start_time: 0,
+        end_time: std::u64::MAX,
+        params: Some(params),
+      }],
+    }
+  }
+}
+
+impl FilmGrainTable {
+  /// Units per second of the times of the segments
+  pub const TIME_SCALE: u64 = 10_000_000;
+
+  /// Parameters of the frame displayed at `time`, in `TIME_SCALE` units,
+  /// `None` if it has no grain
+  pub fn params_at(&self, time: u64) -> Option<&FilmGrainParams> {
+    self
+      .segments
+      .iter()
+      .find(|segment| segment.start_time <= time && time < segment.end_time)
+      .and_then(|segment| segment.params.as_ref())
+  }
+}
+
+impl std::str::FromStr for FilmGrainTable {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    use std::str::SplitWhitespace;
+
+    fn next<V: std::str::FromStr>(
+      tokens: &mut SplitWhitespace<'_>
+    ) -> Result<V, ()> {
+      tokens.next().ok_or(())?.parse().map_err(|_| ())
+    }
+    fn expect(tokens: &mut SplitWhitespace<'_>, tag: &str) -> Result<(), ()> {
+      if tokens.next() == Some(tag) { Ok(()) } else { Err(()) }
+    }
+    fn points<A: arrayvec::Array<Item = ScalingPoint>>(
+      tokens: &mut SplitWhitespace<'_>, tag: &str
+    ) -> Result<ArrayVec<A>, ()> {
+      expect(tokens, tag)?;
+      let count: usize = next(tokens)?;
+      let mut points = ArrayVec::new();
+      for _ in 0..count {
+        points.try_push([next(tokens)?, next(tokens)?]).map_err(|_| ())?;
+      }
+      Ok(points)
+    }
+    fn coeffs<A: arrayvec::Array<Item = i8>>(
+      tokens: &mut SplitWhitespace<'_>, tag: &str, count: usize
+    ) -> Result<ArrayVec<A>, ()> {
+      expect(tokens, tag)?;
+      (0..count).map(|_| next(tokens)).collect()
+    }
+
+    let mut tokens = s.split_whitespace();
+    expect(&mut tokens, "filmgrn1")?;
+    let mut segments = Vec::new();
+    let mut last_params: Option<FilmGrainParams> = None;
+    while let Some(tag) = tokens.next() {
+      if tag != "E" {
+        return Err(());
+      }
+      let start_time = next(&mut tokens)?;
+      let end_time = next(&mut tokens)?;
+      let apply_grain = next::<u8>(&mut tokens)? != 0;
+      let random_seed = next(&mut tokens)?;
+      let update_parameters = next::<u8>(&mut tokens)? != 0;
+
+      let mut params = if update_parameters {
+        expect(&mut tokens, "p")?;
+        let mut params = FilmGrainParams::default();
+        params.ar_coeff_lag = next(&mut tokens)?;
+        params.ar_coeff_shift = next(&mut tokens)?;
+        params.grain_scale_shift = next(&mut tokens)?;
+        params.scaling_shift = next(&mut tokens)?;
+        params.chroma_scaling_from_luma = next::<u8>(&mut tokens)? != 0;
+        params.overlap_flag = next::<u8>(&mut tokens)? != 0;
+        params.cb_mult = next(&mut tokens)?;
+        params.cb_luma_mult = next(&mut tokens)?;
+        params.cb_offset = next(&mut tokens)?;
+        params.cr_mult = next(&mut tokens)?;
+        params.cr_luma_mult = next(&mut tokens)?;
+        params.cr_offset = next(&mut tokens)?;
+        if params.ar_coeff_lag > 3 {
+          return Err(());
+        }
+        params.scaling_points_y = points(&mut tokens, "sY")?;
+        params.scaling_points_cb = points(&mut tokens, "sCb")?;
+        params.scaling_points_cr = points(&mut tokens, "sCr")?;
+        // The chroma coefficients always have the tap of the luma grain
+        let num_pos_luma = params.num_pos_luma();
+        params.ar_coeffs_y = coeffs(&mut tokens, "cY", num_pos_luma)?;
+        params.ar_coeffs_cb = coeffs(&mut tokens, "cCb", num_pos_luma + 1)?;
+        params.ar_coeffs_cr = coeffs(&mut tokens, "cCr", num_pos_luma + 1)?;
+        if params.scaling_points_y.is_empty() {
+          params.ar_coeffs_y.clear();
+          params.ar_coeffs_cb.pop();
+          params.ar_coeffs_cr.pop();
+        }
+        params
+      } else {
+        last_params.clone().ok_or(())?
+      };
+      params.random_seed = random_seed;
+      last_params = Some(params.clone());
+
+      segments.push(FilmGrainSegment {
+        start_time,
+        end_time,
+        params: if apply_grain { Some(params) } else { None },
+      });
+    }
+    Ok(FilmGrainTable { segments })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn default_params_are_valid() {
+    let params = FilmGrainParams::default();
+    assert!(params.is_valid(true));
+    assert!(!params.apply_grain());
+    assert_eq!(params.num_pos_luma(), 0);
+    assert_eq!(params.num_pos_chroma(), 0);
+  }
+
+  #[test]
+  fn unsorted_points_are_rejected() {
+    let mut params = FilmGrainParams::default();
+    params.scaling_points_y.push([64, 20]);
+    params.scaling_points_y.push([32, 20]);
+    assert!(!params.is_valid(true));
+  }
+
+  #[test]
+  fn lonely_chroma_plane_is_rejected_for_420() {
+    let mut params = FilmGrainParams::default();
+    params.scaling_points_y.push([32, 20]);
+    params.scaling_points_cb.push([32, 20]);
+    assert!(!params.is_valid(true));
+    assert!(params.is_valid(false));
+  }
+
+  #[test]
+  fn aomenc_table_is_parsed() {
+    let text = "filmgrn1\n\
+      E 0 3336667 1 7391 1\n\
+      \tp 1 7 0 10 0 1 128 192 256 128 192 256\n\
+      \tsY 2  0 20 255 40\n\
+      \tsCb 0\n\
+      \tsCr 0\n\
+      \tcY 1 -2 3 -4\n\
+      \tcCb 0 0 0 0 0\n\
+      \tcCr 0 0 0 0 0\n\
+      E 3336667 6673334 1 1234 0\n\
+      E 6673334 10010001 0 0 0\n";
+    let table: FilmGrainTable = text.parse().unwrap();
+    assert_eq!(table.segments.len(), 3);
+
+    let params = table.params_at(0).unwrap();
+    assert_eq!(params.random_seed, 7391);
+    assert_eq!(params.ar_coeff_lag, 1);
+    assert_eq!(params.ar_coeff_shift, 7);
+    assert_eq!(params.scaling_shift, 10);
+    assert_eq!(&params.scaling_points_y[..], &[[0, 20], [255, 40]]);
+    assert!(params.scaling_points_cb.is_empty());
+    assert_eq!(&params.ar_coeffs_y[..], &[1, -2, 3, -4]);
+    assert_eq!(params.ar_coeffs_cb.len(), params.num_pos_chroma());
+    assert!(params.is_valid(true));
+
+    let reused = table.params_at(5_000_000).unwrap();
+    assert_eq!(reused.random_seed, 1234);
+    assert_eq!(reused.scaling_points_y, params.scaling_points_y);
+    assert!(table.params_at(7_000_000).is_none());
+    assert!(table.params_at(20_000_000).is_none());
+
+    assert!("filmgrn1\nE 0 1 1 0 0\n".parse::<FilmGrainTable>().is_err());
+    assert!("filmgrn2\n".parse::<FilmGrainTable>().is_err());
+  }
+
+  #[test]
+  fn photon_noise_grows_with_iso() {
+    for &transfer in &[
+      TransferCharacteristics::BT1886,
+      TransferCharacteristics::SRGB,
+      TransferCharacteristics::PerceptualQuantizer,
+      TransferCharacteristics::HybridLogGamma,
+    ] {
+      let low = FilmGrainParams::photon_noise(100, 1920, 1080, transfer);
+      let high = FilmGrainParams::photon_noise(6400, 1920, 1080, transfer);
+      assert!(low.is_valid(true));
+      assert!(high.is_valid(true));
+      assert_eq!(high.scaling_points_y.len(), MAX_NUM_Y_POINTS);
+      assert_eq!(high.scaling_points_y[0][0], 0);
+      assert_eq!(high.scaling_points_y[MAX_NUM_Y_POINTS - 1][0], 255);
+      let total = |params: &FilmGrainParams| -> u32 {
+        params.scaling_points_y.iter().map(|p| p[1] as u32).sum()
+      };
+      assert!(total(&high) > total(&low));
+    }
+
+    let table = FilmGrainTable::from(FilmGrainParams::photon_noise(
+      800, 1280, 720, TransferCharacteristics::BT1886
+    ));
+    assert!(table.params_at(123_456_789).unwrap().apply_grain());
+  }
+}
@@ -12,6 +12,8 @@ use crate::tiling::*;
 use crate::api::ChromaSampling;
 use crate::context::MAX_SB_SIZE;
 use crate::mc::SUBPEL_FILTER_SIZE;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 mod plane;
 pub use plane::*;
@@ -19,6 +21,7 @@ pub use plane::*;
 const FRAME_MARGIN: usize = 16 + SUBPEL_FILTER_SIZE;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Frame<T: Pixel> {
   pub planes: [Plane<T>; 3]
 }
@@ -17,8 +17,12 @@ use std::ops::{Index, IndexMut, Range};
 use crate::tiling::*;
 use crate::util::*;
 
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
+
 /// Plane-specific configuration.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct PlaneConfig {
   pub stride: usize,
   pub alloc_height: usize,
@@ -132,7 +136,29 @@ impl<T: Pixel> PlaneData<T> {
   }
 }
 
+#[cfg(feature = "serialize")]
+impl<T: Pixel + serde::Serialize> serde::Serialize for PlaneData<T> {
+  fn serialize<S: serde::Serializer>(
+    &self, serializer: S
+  ) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(self.iter())
+  }
+}
+
+#[cfg(feature = "serialize")]
+impl<'de, T: Pixel + serde::Deserialize<'de>> serde::Deserialize<'de>
+  for PlaneData<T>
+{
+  fn deserialize<D: serde::Deserializer<'de>>(
+    deserializer: D
+  ) -> Result<Self, D::Error> {
+    let data = <Vec<T> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(PlaneData::from_slice(&data))
+  }
+}
+
 #[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Plane<T: Pixel> {
   pub data: PlaneData<T>,
   pub cfg: PlaneConfig
@@ -207,8 +233,8 @@ impl<T: Pixel> Plane<T> {
     let yorigin = self.cfg.yorigin;
     let stride = self.cfg.stride;
     let alloc_height = self.cfg.alloc_height;
-    let width = w >> self.cfg.xdec;
-    let height = h >> self.cfg.ydec;
+    let width = (w + (1 << self.cfg.xdec) - 1) >> self.cfg.xdec;
+    let height = (h + (1 << self.cfg.ydec) - 1) >> self.cfg.ydec;
 
     if xorigin > 0 {
       for y in 0..height {
@@ -382,6 +408,37 @@ impl<T: Pixel> Plane<T> {
     }
   }
 
+  /// Downscales the first `src_width` columns of `src` horizontally into the
+  /// first `dst_width` columns, averaging the area covered by each pixel.
+  pub fn downscale_width_from(
+    &mut self, src: &Plane<T>, src_width: usize, dst_width: usize
+  ) {
+    let xorigin = self.cfg.xorigin;
+    let yorigin = self.cfg.yorigin;
+    let stride = self.cfg.stride;
+
+    assert!(dst_width <= src_width && dst_width <= self.cfg.width);
+    assert!(self.cfg.height <= src.cfg.height);
+
+    for row in 0..self.cfg.height {
+      let base = (yorigin + row) * stride + xorigin;
+      let dst = &mut self.data[base..base + dst_width];
+
+      for col in 0..dst_width {
+        // Positions are in units of 1/dst_width source pixels
+        let start = col * src_width;
+        let end = start + src_width;
+        let mut sum = 0;
+        for x in start / dst_width..(end + dst_width - 1) / dst_width {
+          let overlap = end.min((x + 1) * dst_width) - start.max(x * dst_width);
+          sum += u64::from(u32::cast_from(src.p(x, row))) * overlap as u64;
+        }
+        let avg = (sum + src_width as u64 / 2) / src_width as u64;
+        dst[col] = T::cast_from(avg as u32);
+      }
+    }
+  }
+
   /// Iterates over the pixels in the `Plane`, skipping stride data.
   pub fn iter(&self) -> PlaneIter<'_, T> {
     PlaneIter::new(self)
@@ -744,4 +801,48 @@ pub mod test {
       &plane.data[..]
     );
   }
+
+  #[test]
+  fn pad_odd_subsampled() {
+    // 3x3 luma pixels subsample to 2x2 chroma pixels, which must be kept
+    let mut plane = Plane::<u8> {
+      data: PlaneData::from_slice(&[
+        1, 2, 0, 0,
+        3, 4, 0, 0,
+        0, 0, 0, 0,
+      ]),
+      cfg: PlaneConfig {
+        stride: 4,
+        alloc_height: 3,
+        width: 4,
+        height: 3,
+        xdec: 1,
+        ydec: 1,
+        xpad: 0,
+        ypad: 0,
+        xorigin: 0,
+        yorigin: 0,
+      },
+    };
+    plane.pad(3, 3);
+    assert_eq!(
+      &[
+        1u8, 2, 2, 2,
+        3, 4, 4, 4,
+        3, 4, 4, 4,
+      ][..],
+      &plane.data[..]
+    );
+  }
+
+  #[test]
+  fn downscale_width() {
+    let src = Plane::wrap(vec![10u8, 20, 30, 40, 50, 60, 70, 80], 8);
+    let mut dst = Plane::wrap(vec![0u8; 8], 8);
+    dst.downscale_width_from(&src, 8, 4);
+    assert_eq!(&[15u8, 35, 55, 75][..], &dst.data[..4]);
+    // Pixels are split between the output pixels they overlap
+    dst.downscale_width_from(&src, 8, 5);
+    assert_eq!(&[14u8, 29, 45, 61, 76][..], &dst.data[..5]);
+  }
 }
@@ -0,0 +1,162 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Entry points of the cargo-fuzz targets in `fuzz/`
+//!
+//! The fuzzer input is read as a stream of small integers picking the
+//! configuration and the calls made to the API. A panic is a bug: every
+//! configuration must be either rejected by `Config::new_context` or
+//! encodable, and every sequence of calls must be answered with a packet or
+//! an `EncoderStatus`.
+
+use crate::api::*;
+use crate::partition::BlockSize;
+use crate::util::{CastFromPrimitive, Pixel};
+
+use std::sync::Arc;
+
+/// Upper bound on the calls made to the API by one run
+const MAX_CALLS: usize = 64;
+
+/// Reads values out of the fuzzer input, then zeroes once it is exhausted
+struct FuzzReader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> FuzzReader<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    FuzzReader { data }
+  }
+
+  fn u8(&mut self) -> u8 {
+    match self.data.split_first() {
+      Some((&v, rest)) => {
+        self.data = rest;
+        v
+      }
+      None => 0,
+    }
+  }
+
+  fn u16(&mut self) -> u16 {
+    u16::from(self.u8()) << 8 | u16::from(self.u8())
+  }
+
+  fn bool(&mut self) -> bool {
+    self.u8() & 1 != 0
+  }
+
+  /// A value in `lo..=hi`
+  fn range(&mut self, lo: usize, hi: usize) -> usize {
+    lo + self.u16() as usize % (hi - lo + 1)
+  }
+
+  fn chroma_sampling(&mut self) -> ChromaSampling {
+    [
+      ChromaSampling::Cs420,
+      ChromaSampling::Cs422,
+      ChromaSampling::Cs444,
+      ChromaSampling::Cs400,
+    ][self.range(0, 3)]
+  }
+}
+
+/// Pick every field a user would commonly set, over its whole range.
+fn read_config(r: &mut FuzzReader<'_>, max_size: usize) -> EncoderConfig {
+  let mut enc = EncoderConfig::with_speed_preset(r.range(0, 10));
+  enc.width = r.range(1, max_size);
+  enc.height = r.range(1, max_size);
+  enc.bit_depth = [8, 10, 12][r.range(0, 2)];
+  enc.chroma_sampling = r.chroma_sampling();
+  enc.min_key_frame_interval = r.u8() as u64;
+  enc.max_key_frame_interval = r.u8() as u64;
+  enc.low_latency = r.bool();
+  enc.quantizer = r.u8() as usize;
+  enc.min_quantizer = r.u8();
+  enc.bitrate = if r.bool() { i32::from(r.u16()) } else { 0 };
+  enc.still_picture = r.bool();
+  enc.tiles = r.range(0, 16);
+  enc.tile_cols_log2 = r.range(0, 3);
+  enc.tile_rows_log2 = r.range(0, 3);
+  enc.enable_intrabc = r.bool();
+  enc.detect_screen_content = r.bool();
+  enc.error_resilient = r.bool();
+  enc.speed_settings.min_block_size =
+    [BlockSize::BLOCK_4X4, BlockSize::BLOCK_8X8, BlockSize::BLOCK_16X16]
+      [r.range(0, 2)];
+  enc
+}
+
+/// Create a context from an arbitrary configuration, up to the size limit
+/// of the AV1 specification.
+pub fn fuzz_construct_context(data: &[u8]) {
+  let mut r = FuzzReader::new(data);
+  let enc = read_config(&mut r, 65536);
+  let cfg = Config { enc, threads: 1 };
+
+  if cfg.enc.bit_depth == 8 {
+    let _ = cfg.new_context::<u8>();
+  } else {
+    let _ = cfg.new_context::<u16>();
+  }
+}
+
+/// Drive a context with an arbitrary sequence of frames, flushes and
+/// packet requests, on tiny frames so that runs stay short.
+pub fn fuzz_encode(data: &[u8]) {
+  let mut r = FuzzReader::new(data);
+  let enc = read_config(&mut r, 48);
+  let cfg = Config { enc, threads: 1 };
+
+  if cfg.enc.bit_depth == 8 {
+    run_encode::<u8>(&cfg, &mut r);
+  } else {
+    run_encode::<u16>(&cfg, &mut r);
+  }
+}
+
+fn run_encode<T: Pixel>(cfg: &Config, r: &mut FuzzReader<'_>) {
+  let mut ctx: Context<T> = match cfg.new_context() {
+    Ok(ctx) => ctx,
+    Err(_) => return,
+  };
+  let max = (1u32 << cfg.enc.bit_depth) - 1;
+
+  for _ in 0..MAX_CALLS {
+    match r.range(0, 3) {
+      0 => {
+        let mut frame = ctx.new_frame();
+        let seed = r.u16();
+        if let Some(f) = Arc::get_mut(&mut frame) {
+          for plane in f.planes.iter_mut() {
+            for (i, v) in plane.data.iter_mut().enumerate() {
+              let x = u32::from(seed).wrapping_mul(i as u32 | 1);
+              *v = T::cast_from(x % (max + 1));
+            }
+          }
+        }
+        let _ = ctx.send_frame(frame);
+      }
+      1 => {
+        let _ = ctx.receive_packet();
+      }
+      2 => ctx.flush(),
+      _ => break,
+    }
+  }
+
+  // Whatever happened before, a flushed context must run to completion
+  let _ = ctx.send_frame(None);
+  for _ in 0..4 * MAX_CALLS {
+    match ctx.receive_packet() {
+      Ok(_) | Err(EncoderStatus::Encoded) => {}
+      Err(_) => break,
+    }
+  }
+}
@@ -21,6 +21,8 @@ use crate::FrameInvariants;
 use crate::Sequence;
 
 use bitstream_io::{BitWriter, BigEndian, LittleEndian};
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 use std;
 use std::io;
@@ -28,6 +30,11 @@ use std::io;
 pub const PRIMARY_REF_NONE: u32 = 7;
 pub const ALL_REF_FRAMES_MASK: u32 = (1 << REF_FRAMES) - 1;
 
+// Field lengths of the decoder model
+const BUFFER_DELAY_LENGTH: u32 = 32;
+const BUFFER_REMOVAL_TIME_LENGTH: u32 = 10;
+const FRAME_PRESENTATION_TIME_LENGTH: u32 = 10;
+
 const PRIMARY_REF_BITS: u32 = 3;
 
 #[allow(unused)]
@@ -43,6 +50,7 @@ const LEVEL_BITS: usize = LEVEL_MAJOR_BITS + LEVEL_MINOR_BITS;
 
 #[allow(dead_code,non_camel_case_types)]
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum ReferenceMode {
   SINGLE = 0,
   COMPOUND = 1,
@@ -144,11 +152,12 @@ impl<W: io::Write> LEWriter for BitWriter<W, BigEndian> {
 pub trait UncompressedHeader {
   // Start of OBU Headers
   fn write_obu_header(
-    &mut self, obu_type: ObuType, obu_extension: u32
+    &mut self, obu_type: ObuType, obu_extension: Option<u8>
   ) -> io::Result<()>;
   fn write_metadata_obu(
     &mut self, obu_meta_type: ObuMetaType, seq: Sequence
   ) -> io::Result<()>;
+  fn write_t35_metadata_obu(&mut self, t35: &T35) -> io::Result<()>;
   fn write_sequence_header_obu<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>
   ) -> io::Result<()>;
@@ -166,6 +175,12 @@ pub trait UncompressedHeader {
   fn write_frame_size<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>
   ) -> io::Result<()>;
+  fn write_superres_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>
+  ) -> io::Result<()>;
+  fn write_render_size<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>
+  ) -> io::Result<()>;
   fn write_deblock_filter_a<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>, deblock: &DeblockState
   ) -> io::Result<()>;
@@ -184,6 +199,9 @@ pub trait UncompressedHeader {
   fn write_delta_q(
     &mut self, delta_q: i8
   ) -> io::Result<()>;
+  fn write_film_grain_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>
+  ) -> io::Result<()>;
 }
 
 
@@ -191,17 +209,16 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   // Start of OBU Headers
   // Write OBU Header syntax
   fn write_obu_header(
-    &mut self, obu_type: ObuType, obu_extension: u32
+    &mut self, obu_type: ObuType, obu_extension: Option<u8>
   ) -> io::Result<()> {
     self.write_bit(false)?; // forbidden bit.
     self.write(4, obu_type as u32)?;
-    self.write_bit(obu_extension != 0)?;
+    self.write_bit(obu_extension.is_some())?;
     self.write_bit(true)?; // obu_has_payload_length_field
     self.write_bit(false)?; // reserved
 
-    if obu_extension != 0 {
-      unimplemented!();
-      //self.write(8, obu_extension & 0xFF)?; size += 8;
+    if let Some(obu_extension) = obu_extension {
+      self.write(8, obu_extension)?;
     }
 
     Ok(())
@@ -211,7 +228,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     &mut self, obu_meta_type: ObuMetaType, seq: Sequence
   ) -> io::Result<()> {
     // header
-    self.write_obu_header(ObuType::OBU_METADATA, 0)?;
+    self.write_obu_header(ObuType::OBU_METADATA, None)?;
 
     // uleb128() - length
     // we use a constant value to avoid computing the OBU size every time
@@ -251,20 +268,76 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     Ok(())
   }
 
+  fn write_t35_metadata_obu(&mut self, t35: &T35) -> io::Result<()> {
+    // header
+    self.write_obu_header(ObuType::OBU_METADATA, None)?;
+
+    // uleb128() - length
+    // metadata_type, country code (plus extension), payload, trailing bits
+    let ext_size = if t35.country_code == 0xFF { 1 } else { 0 };
+    self.write_uleb128(3 + ext_size + t35.data.len() as u64)?;
+
+    // uleb128() - metadata_type (1 byte)
+    self.write_uleb128(ObuMetaType::OBU_META_ITUT_T35 as u64)?;
+
+    self.write(8, t35.country_code)?;
+    if t35.country_code == 0xFF {
+      self.write(8, t35.country_code_extension_byte)?;
+    }
+    self.write_bytes(&t35.data)?;
+
+    // trailing bits (1 byte)
+    self.write_bit(true)?;
+    self.byte_align()?;
+
+    Ok(())
+  }
+
   fn write_sequence_header_obu<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>
   ) -> io::Result<()> {
     self.write(3, fi.sequence.profile)?; // profile
-    self.write_bit(false)?; // still_picture
-    self.write_bit(false)?; // reduced_still_picture_header
-    self.write_bit(false)?; // timing info present
-    self.write_bit(false)?; // initial display delay present flag
-    self.write(5, 0)?; // one operating point
-    self.write(12, 0)?; // idc
-    self.write(5, 31)?; // level
-    self.write(1, 0)?; // tier
+    self.write_bit(fi.sequence.still_picture)?;
+    self.write_bit(fi.sequence.reduced_still_picture_hdr)?;
     if fi.sequence.reduced_still_picture_hdr {
-      unimplemented!();
+      assert!(fi.sequence.timing_info.is_none());
+      self.write(5, fi.sequence.seq_level_idx[0])?;
+    } else {
+      self.write_bit(fi.sequence.timing_info.is_some())?; // timing info present
+      if let Some(timing_info) = fi.sequence.timing_info {
+        self.write(32, timing_info.num_units_in_display_tick)?;
+        self.write(32, timing_info.time_scale)?;
+        self.write_bit(true)?; // equal picture interval
+        self.write_bit(true)?; // num_ticks_per_picture_minus_1 = 0, uvlc()
+
+        self.write_bit(fi.sequence.decoder_model_info_present_flag)?;
+        if fi.sequence.decoder_model_info_present_flag {
+          self.write(5, BUFFER_DELAY_LENGTH - 1)?;
+          // one decoding tick per display tick
+          self.write(32, timing_info.num_units_in_display_tick)?;
+          self.write(5, BUFFER_REMOVAL_TIME_LENGTH - 1)?;
+          self.write(5, FRAME_PRESENTATION_TIME_LENGTH - 1)?;
+        }
+      }
+      self.write_bit(false)?; // initial display delay present flag
+      self.write(5, fi.sequence.operating_points_cnt_minus_1 as u32)?;
+      for i in 0..=fi.sequence.operating_points_cnt_minus_1 {
+        self.write(12, fi.sequence.operating_point_idc[i])?;
+        self.write(5, fi.sequence.seq_level_idx[i])?;
+        if fi.sequence.seq_level_idx[i] > 7 {
+          self.write_bit(fi.sequence.tier[i])?;
+        }
+        if fi.sequence.decoder_model_info_present_flag {
+          // The buffer delays are those of the whole stream
+          self.write_bit(i == 0)?; // decoder model present for this op
+          if i == 0 {
+            let timing_info = fi.sequence.timing_info.unwrap();
+            self.write(BUFFER_DELAY_LENGTH, timing_info.decoder_buffer_delay)?;
+            self.write(BUFFER_DELAY_LENGTH, timing_info.encoder_buffer_delay)?;
+            self.write_bit(false)?; // low delay mode
+          }
+        }
+      }
     }
 
     self.write_sequence_header(fi)?;
@@ -355,10 +428,6 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       self.write_bit(monochrome)?;
     }
 
-    if monochrome {
-      unimplemented!();
-    }
-
     // color description present
     self.write_bit(seq.color_description.is_some())?;
 
@@ -416,7 +485,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     &mut self, fi: &FrameInvariants<T>, fs: &FrameState<T>
   ) -> io::Result<()> {
     if fi.sequence.reduced_still_picture_hdr {
-      assert!(fi.show_existing_frame);
+      assert!(!fi.show_existing_frame);
       assert!(fi.frame_type == FrameType::KEY);
       assert!(fi.show_frame);
     } else {
@@ -425,12 +494,9 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       if fi.show_existing_frame {
         self.write(3, fi.frame_to_show_map_idx)?;
 
+        // No temporal_point_info(), the picture interval is always equal
         //TODO:
-        /* temporal_point_info();
-        if fi.sequence.decoder_model_info_present_flag &&
-           timing_info.equal_picture_interval == 0 {
-          // write frame_presentation_delay;
-        }
+        /*
         if fi.sequence.frame_id_numbers_present_flag {
           // write display_frame_id;
         }*/
@@ -443,14 +509,9 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       self.write(2, fi.frame_type as u32)?;
       self.write_bit(fi.show_frame)?; // show frame
 
-      if fi.show_frame {
-        //TODO:
-        /* temporal_point_info();
-        if fi.sequence.decoder_model_info_present_flag &&
-           timing_info.equal_picture_interval == 0 {
-          // write frame_presentation_delay;
-        }*/
-      } else {
+      // No temporal_point_info() for shown frames, the picture interval is
+      // always equal
+      if !fi.show_frame {
         self.write_bit(fi.showable_frame)?;
       }
 
@@ -472,17 +533,14 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       );
     }
 
-    if fi.allow_screen_content_tools == 2 {
+    if fi.allow_screen_content_tools != 0 {
       if fi.sequence.force_integer_mv == 2 {
         self.write_bit(fi.force_integer_mv != 0)?;
       } else {
         assert!(fi.force_integer_mv == fi.sequence.force_integer_mv);
       }
     } else {
-      assert!(
-        fi.allow_screen_content_tools
-          == fi.sequence.force_screen_content_tools
-      );
+      assert!(fi.force_integer_mv == 0);
     }
 
     if fi.sequence.frame_id_numbers_present_flag {
@@ -514,7 +572,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
 
     if fi.sequence.decoder_model_info_present_flag {
-      unimplemented!();
+      self.write_bit(false)?; // buffer removal time present
     }
 
     if fi.frame_type == FrameType::KEY {
@@ -539,10 +597,15 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     if (!fi.intra_only || fi.refresh_frame_flags != ALL_REF_FRAMES_MASK) {
       // Write all ref frame order hints if error_resilient_mode == 1
       if (fi.error_resilient && fi.sequence.enable_order_hint) {
-        unimplemented!();
-        //for _ in 0..REF_FRAMES {
-        //  self.write(order_hint_bits_minus_1,ref_order_hint[i])?; // order_hint
-        //}
+        let n = fi.sequence.order_hint_bits_minus_1 + 1;
+        let mask = (1 << n) - 1;
+        for i in 0..REF_FRAMES {
+          let ref_order_hint = match fi.rec_buffer.frames[i] {
+            Some(ref rec) => rec.order_hint & mask,
+            None => 0
+          };
+          self.write(n, ref_order_hint)?; // ref_order_hint[i]
+        }
       }
     }
 
@@ -552,13 +615,12 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       if frame_size_override_flag {
         unimplemented!();
       }
-      if fi.sequence.enable_superres {
-        unimplemented!();
-      }
-      self.write_bit(false)?; // render_and_frame_size_different
-                              // if render_and_frame_size_different { }
-      if fi.allow_screen_content_tools != 0 { // TODO: && UpscaledWidth == FrameWidth.
+      self.write_superres_params(fi)?;
+      self.write_render_size(fi)?;
+      if fi.allow_screen_content_tools != 0 && fi.upscaled_width == fi.width {
         self.write_bit(fi.allow_intrabc)?;
+      } else {
+        assert!(!fi.allow_intrabc);
       }
     }
 
@@ -588,10 +650,8 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         if frame_size_override_flag {
           unimplemented!();
         }
-        if fi.sequence.enable_superres {
-          unimplemented!();
-        }
-        self.write_bit(false)?; // render_and_frame_size_different
+        self.write_superres_params(fi)?;
+        self.write_render_size(fi)?;
       }
 
       if fi.force_integer_mv == 0 {
@@ -647,28 +707,46 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     self.write(8, fi.base_q_idx)?; // base_q_idx
     self.write_delta_q(fi.dc_delta_q[0])?;
     assert!(fi.ac_delta_q[0] == 0);
-    let diff_uv_delta = fi.sequence.separate_uv_delta_q
-      && (fi.dc_delta_q[1] != fi.dc_delta_q[2]
-          || fi.ac_delta_q[1] != fi.ac_delta_q[2]);
-    if fi.sequence.separate_uv_delta_q {
-      self.write_bit(diff_uv_delta)?;
-    } else {
-      assert!(fi.dc_delta_q[1] == fi.dc_delta_q[2]);
-      assert!(fi.ac_delta_q[1] == fi.ac_delta_q[2]);
+    if fi.sequence.num_planes() > 1 {
+      let diff_uv_delta = fi.sequence.separate_uv_delta_q
+        && (fi.dc_delta_q[1] != fi.dc_delta_q[2]
+            || fi.ac_delta_q[1] != fi.ac_delta_q[2]);
+      if fi.sequence.separate_uv_delta_q {
+        self.write_bit(diff_uv_delta)?;
+      } else {
+        assert!(fi.dc_delta_q[1] == fi.dc_delta_q[2]);
+        assert!(fi.ac_delta_q[1] == fi.ac_delta_q[2]);
+      }
+      self.write_delta_q(fi.dc_delta_q[1])?;
+      self.write_delta_q(fi.ac_delta_q[1])?;
+      if diff_uv_delta {
+        self.write_delta_q(fi.dc_delta_q[2])?;
+        self.write_delta_q(fi.ac_delta_q[2])?;
+      }
     }
-    self.write_delta_q(fi.dc_delta_q[1])?;
-    self.write_delta_q(fi.ac_delta_q[1])?;
-    if diff_uv_delta {
-      self.write_delta_q(fi.dc_delta_q[2])?;
-      self.write_delta_q(fi.ac_delta_q[2])?;
+    self.write_bit(fi.using_qmatrix)?;
+    if fi.using_qmatrix {
+      self.write(4, fi.qm_y)?;
+      self.write(4, fi.qm_u)?;
+      if fi.sequence.separate_uv_delta_q {
+        self.write(4, fi.qm_v)?;
+      } else {
+        assert!(fi.qm_u == fi.qm_v);
+      }
     }
-    self.write_bit(false)?; // no qm
 
     // segmentation
     self.write_segment_data(fi, &fs.segmentation)?;
 
     // delta_q
-    self.write_bit(false)?; // delta_q_present_flag: no delta q
+    if fi.base_q_idx > 0 {
+      self.write_bit(fi.delta_q_present)?;
+    } else {
+      assert!(!fi.delta_q_present);
+    }
+    if fi.delta_q_present {
+      self.write(2, 0)?; // delta_q_res
+    }
 
     // delta_lf_params in the spec
     self.write_deblock_filter_a(fi, &fs.deblock)?;
@@ -747,8 +825,10 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       }
     }
 
-    if fi.sequence.film_grain_params_present && fi.show_frame {
-      unimplemented!();
+    if fi.sequence.film_grain_params_present
+      && (fi.show_frame || fi.showable_frame)
+    {
+      self.write_film_grain_params(fi)?;
     }
 
     if fi.large_scale_tile {
@@ -764,17 +844,43 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   fn write_frame_size<T: Pixel>(&mut self, fi: &FrameInvariants<T>) -> io::Result<()> {
     // width_bits and height_bits will have to be moved to the sequence header OBU
     // when we add support for it.
-    let width_bits = 32 - (fi.width as u32).leading_zeros();
+    let width_bits = 32 - (fi.upscaled_width as u32).leading_zeros();
     let height_bits = 32 - (fi.height as u32).leading_zeros();
     assert!(width_bits <= 16);
     assert!(height_bits <= 16);
     self.write(4, width_bits - 1)?;
     self.write(4, height_bits - 1)?;
-    self.write(width_bits, (fi.width - 1) as u16)?;
+    self.write(width_bits, (fi.upscaled_width - 1) as u16)?;
     self.write(height_bits, (fi.height - 1) as u16)?;
     Ok(())
   }
 
+  fn write_superres_params<T: Pixel>(&mut self, fi: &FrameInvariants<T>) -> io::Result<()> {
+    if fi.sequence.enable_superres {
+      let use_superres = fi.superres_denom != SUPERRES_NUM;
+      self.write_bit(use_superres)?;
+      if use_superres {
+        assert!(fi.superres_denom >= SUPERRES_DENOM_MIN);
+        self.write(SUPERRES_DENOM_BITS as u32, fi.superres_denom - SUPERRES_DENOM_MIN)?;
+      }
+    } else {
+      assert!(fi.superres_denom == SUPERRES_NUM);
+    }
+    Ok(())
+  }
+
+  fn write_render_size<T: Pixel>(&mut self, fi: &FrameInvariants<T>) -> io::Result<()> {
+    let (render_width, render_height) = fi.config.render_size();
+    let render_and_frame_size_different =
+      render_width != fi.upscaled_width || render_height != fi.height;
+    self.write_bit(render_and_frame_size_different)?;
+    if render_and_frame_size_different {
+      self.write(16, (render_width - 1) as u16)?;
+      self.write(16, (render_height - 1) as u16)?;
+    }
+    Ok(())
+  }
+
   fn write_deblock_filter_a<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>, deblock: &DeblockState
   ) -> io::Result<()> {
@@ -793,11 +899,15 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   fn write_deblock_filter_b<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>, deblock: &DeblockState
   ) -> io::Result<()> {
+    if fi.allow_intrabc {
+      // The deblocking filter is disabled, nothing is signaled
+      return Ok(());
+    }
     assert!(deblock.levels[0] < 64);
     self.write(6, deblock.levels[0])?; // loop deblocking filter level 0
     assert!(deblock.levels[1] < 64);
     self.write(6, deblock.levels[1])?; // loop deblocking filter level 1
-    if PLANES > 1 && (deblock.levels[0] > 0 || deblock.levels[1] > 0) {
+    if fi.sequence.num_planes() > 1 && (deblock.levels[0] > 0 || deblock.levels[1] > 0) {
       assert!(deblock.levels[2] < 64);
       self.write(6, deblock.levels[2])?; // loop deblocking filter level 2
       assert!(deblock.levels[3] < 64);
@@ -844,7 +954,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   }
 
   fn write_frame_cdef<T: Pixel>(&mut self, fi: &FrameInvariants<T>) -> io::Result<()> {
-    if fi.sequence.enable_cdef {
+    if fi.sequence.enable_cdef && !fi.allow_intrabc {
       assert!(fi.cdef_damping >= 3);
       assert!(fi.cdef_damping <= 6);
       self.write(2, fi.cdef_damping - 3)?;
@@ -855,7 +965,9 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         assert!(fi.cdef_y_strengths[j] < 64);
         assert!(fi.cdef_uv_strengths[j] < 64);
         self.write(6, fi.cdef_y_strengths[j])?; // cdef y strength
-        self.write(6, fi.cdef_uv_strengths[j])?; // cdef uv strength
+        if fi.sequence.num_planes() > 1 {
+          self.write(6, fi.cdef_uv_strengths[j])?; // cdef uv strength
+        }
       }
     }
     Ok(())
@@ -868,7 +980,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       // && !self.lossless
       let mut use_lrf = false;
       let mut use_chroma_lrf = false;
-      for i in 0..PLANES {
+      for i in 0..fi.sequence.num_planes() {
         self.write(2, rs.planes[i].cfg.lrf_type)?; // filter type by plane
         if rs.planes[i].cfg.lrf_type != RESTORE_NONE {
           use_lrf = true;
@@ -940,4 +1052,94 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
     Ok(())
   }
+
+  fn write_film_grain_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>
+  ) -> io::Result<()> {
+    let params = match fi.film_grain_params {
+      Some(ref params) if params.apply_grain() => params,
+      _ => {
+        self.write_bit(false)?; // apply_grain
+        return Ok(());
+      }
+    };
+    let monochrome = fi.sequence.chroma_sampling == ChromaSampling::Cs400;
+    debug_assert!(params.is_valid(
+      fi.sequence.chroma_sampling == ChromaSampling::Cs420
+    ));
+
+    self.write_bit(true)?; // apply_grain
+    self.write(16, params.random_seed)?;
+    if fi.frame_type == FrameType::INTER {
+      // Always send new parameters instead of loading them from a reference
+      self.write_bit(true)?; // update_grain
+    }
+
+    self.write(4, params.scaling_points_y.len() as u8)?;
+    for point in &params.scaling_points_y {
+      self.write(8, point[0])?;
+      self.write(8, point[1])?;
+    }
+
+    let mut num_cb_points = 0;
+    let mut num_cr_points = 0;
+    if !monochrome {
+      self.write_bit(params.chroma_scaling_from_luma)?;
+      if !params.chroma_scaling_from_luma
+        && !(fi.sequence.chroma_sampling == ChromaSampling::Cs420
+          && params.scaling_points_y.is_empty())
+      {
+        num_cb_points = params.scaling_points_cb.len();
+        num_cr_points = params.scaling_points_cr.len();
+        self.write(4, num_cb_points as u8)?;
+        for point in &params.scaling_points_cb {
+          self.write(8, point[0])?;
+          self.write(8, point[1])?;
+        }
+        self.write(4, num_cr_points as u8)?;
+        for point in &params.scaling_points_cr {
+          self.write(8, point[0])?;
+          self.write(8, point[1])?;
+        }
+      }
+    }
+
+    self.write(2, params.scaling_shift - 8)?;
+    self.write(2, params.ar_coeff_lag)?;
+    if !params.scaling_points_y.is_empty() {
+      for i in 0..params.num_pos_luma() {
+        let coeff = params.ar_coeffs_y.get(i).cloned().unwrap_or(0);
+        self.write(8, (coeff as i16 + 128) as u8)?;
+      }
+    }
+    let num_pos_chroma = params.num_pos_chroma();
+    if params.chroma_scaling_from_luma || num_cb_points > 0 {
+      for i in 0..num_pos_chroma {
+        let coeff = params.ar_coeffs_cb.get(i).cloned().unwrap_or(0);
+        self.write(8, (coeff as i16 + 128) as u8)?;
+      }
+    }
+    if params.chroma_scaling_from_luma || num_cr_points > 0 {
+      for i in 0..num_pos_chroma {
+        let coeff = params.ar_coeffs_cr.get(i).cloned().unwrap_or(0);
+        self.write(8, (coeff as i16 + 128) as u8)?;
+      }
+    }
+    self.write(2, params.ar_coeff_shift - 6)?;
+    self.write(2, params.grain_scale_shift)?;
+    if num_cb_points > 0 {
+      self.write(8, params.cb_mult)?;
+      self.write(8, params.cb_luma_mult)?;
+      self.write(9, params.cb_offset)?;
+    }
+    if num_cr_points > 0 {
+      self.write(8, params.cr_mult)?;
+      self.write(8, params.cr_luma_mult)?;
+      self.write(9, params.cr_offset)?;
+    }
+    self.write_bit(params.overlap_flag)?;
+    self.write_bit(params.clip_to_restricted_range)?;
+
+    Ok(())
+  }
 }
@@ -0,0 +1,232 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::context::{BlockOffset, CandidateMV, MI_SIZE};
+use crate::encoder::FrameInvariants;
+use crate::frame::*;
+use crate::mc::MotionVector;
+use crate::me::get_sad;
+use crate::partition::BlockSize;
+use crate::tiling::*;
+use crate::util::{CastFromPrimitive, Pixel};
+
+use std::collections::HashMap;
+
+/// Size of the square luma blocks indexed by the hash table. Larger blocks
+/// are looked up by their top-left corner and then compared in full.
+const HASH_BLOCK_SIZE: usize = 8;
+/// Positions kept for a single hash value, which bounds the search time on
+/// flat content
+const MAX_CANDIDATES: usize = 64;
+/// Number of 64x64 superblocks between the current block and the area it is
+/// allowed to copy from, so that decoders can pipeline the reconstruction
+const INTRABC_DELAY_SB64: isize = 4;
+const INTRABC_DELAY_PIXELS: isize = 256;
+
+/// Positions of the 8x8 luma blocks of the source frame, indexed by content.
+#[derive(Debug, Clone, Default)]
+pub struct BlockHashMap {
+  map: HashMap<u32, Vec<PlaneOffset>>,
+}
+
+impl BlockHashMap {
+  pub fn new<T: Pixel>(plane: &Plane<T>) -> Self {
+    let PlaneConfig { width, height, .. } = plane.cfg;
+    let mut map: HashMap<u32, Vec<PlaneOffset>> = HashMap::new();
+
+    if width >= HASH_BLOCK_SIZE && height >= HASH_BLOCK_SIZE {
+      // Only even positions are indexed, so that the displacement stays an
+      // integer number of pixels in subsampled chroma planes too.
+      for y in (0..=height - HASH_BLOCK_SIZE).step_by(2) {
+        for x in (0..=width - HASH_BLOCK_SIZE).step_by(2) {
+          let po = PlaneOffset { x: x as isize, y: y as isize };
+          let region = plane.region(Area::StartingAt { x: po.x, y: po.y });
+          let positions = map.entry(hash_block(&region)).or_insert_with(Vec::new);
+          if positions.len() < MAX_CANDIDATES {
+            positions.push(po);
+          }
+        }
+      }
+    }
+
+    BlockHashMap { map }
+  }
+
+  fn candidates(&self, hash: u32) -> &[PlaneOffset] {
+    self.map.get(&hash).map_or(&[], |positions| positions.as_slice())
+  }
+}
+
+/// FNV-1a hash of the top-left 8x8 pixels of the region.
+fn hash_block<T: Pixel>(region: &PlaneRegion<'_, T>) -> u32 {
+  region.rows_iter().take(HASH_BLOCK_SIZE).fold(0x811c_9dc5, |hash, row| {
+    row[..HASH_BLOCK_SIZE].iter().fold(hash, |hash, &pixel| {
+      (hash ^ u32::cast_from(pixel)).wrapping_mul(0x0100_0193)
+    })
+  })
+}
+
+/// Checks that a displacement vector only copies from the part of the tile
+/// that is already reconstructed, as required by the bitstream.
+pub fn is_dv_valid(
+  dv: MotionVector, tile_bo: BlockOffset, bsize: BlockSize,
+  tile_mi_width: usize, tile_mi_height: usize, sb_size_log2: usize
+) -> bool {
+  if dv.row & 7 != 0 || dv.col & 7 != 0 {
+    return false;
+  }
+
+  let src_top = (tile_bo.y * MI_SIZE) as isize + (dv.row >> 3) as isize;
+  let src_left = (tile_bo.x * MI_SIZE) as isize + (dv.col >> 3) as isize;
+  let src_bottom = src_top + bsize.height() as isize;
+  let src_right = src_left + bsize.width() as isize;
+  if src_top < 0 || src_left < 0
+    || src_bottom > (tile_mi_height * MI_SIZE) as isize
+    || src_right > (tile_mi_width * MI_SIZE) as isize {
+    return false;
+  }
+
+  // The source must lie in a superblock coded long enough ago
+  let active_sb_row = ((tile_bo.y * MI_SIZE) >> sb_size_log2) as isize;
+  let active_sb64_col = ((tile_bo.x * MI_SIZE) >> 6) as isize;
+  let src_sb_row = (src_bottom - 1) >> sb_size_log2;
+  let src_sb64_col = (src_right - 1) >> 6;
+  let sb64_per_row = (((tile_mi_width - 1) >> 4) + 1) as isize;
+  let active_sb64 = active_sb_row * sb64_per_row + active_sb64_col;
+  let src_sb64 = src_sb_row * sb64_per_row + src_sb64_col;
+  if src_sb64 >= active_sb64 - INTRABC_DELAY_SB64 {
+    return false;
+  }
+
+  // Wavefront constraint: superblock rows above may only be used up to a
+  // column that moves further right the further up they are
+  let gradient = 1 + INTRABC_DELAY_SB64 + if sb_size_log2 > 6 { 1 } else { 0 };
+  let wf_offset = gradient * (active_sb_row - src_sb_row);
+  src_sb_row <= active_sb_row
+    && src_sb64_col < active_sb64_col - INTRABC_DELAY_SB64 + wf_offset
+}
+
+/// The DV predictor the decoder derives from the candidate list, with a
+/// default pointing one superblock back when no neighbor uses IntraBC.
+pub fn get_ref_dv(
+  mv_stack: &[CandidateMV], tile_bo: BlockOffset, sb_size_log2: usize
+) -> MotionVector {
+  let is_nonzero = |cand: &&CandidateMV| cand.this_mv.row != 0 || cand.this_mv.col != 0;
+  if let Some(cand) = mv_stack.iter().take(2).find(is_nonzero) {
+    return cand.this_mv;
+  }
+
+  let sb_size = 1 << sb_size_log2;
+  if (tile_bo.y * MI_SIZE) < sb_size {
+    MotionVector {
+      row: 0,
+      col: (-(sb_size as isize + INTRABC_DELAY_PIXELS) * 8) as i16
+    }
+  } else {
+    MotionVector { row: (-(sb_size as isize) * 8) as i16, col: 0 }
+  }
+}
+
+/// Finds a displacement vector for the block by looking up the source
+/// blocks with identical content, and keeping the position that matches the
+/// reconstruction best.
+pub fn intrabc_search<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, bsize: BlockSize,
+  tile_bo: BlockOffset
+) -> Option<MotionVector> {
+  let hash_map = ts.intrabc_hash?;
+  let (blk_w, blk_h) = (bsize.width(), bsize.height());
+  if blk_w < HASH_BLOCK_SIZE || blk_h < HASH_BLOCK_SIZE {
+    return None;
+  }
+
+  let tile_rect = ts.tile_rect();
+  let po = tile_bo.plane_offset(&ts.input.planes[0].cfg);
+  let frame_po = tile_rect.to_frame_plane_offset(po);
+  if frame_po.x as usize + blk_w > fi.width || frame_po.y as usize + blk_h > fi.height {
+    return None;
+  }
+
+  let org = ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo });
+  let rec = &ts.rec.planes[0];
+  // MotionVector components are in 1/8 pel and stored as i16
+  let max_offset = (std::i16::MAX >> 3) as isize;
+
+  hash_map.candidates(hash_block(&org)).iter().filter_map(|&cand| {
+    let (dy, dx) = (cand.y - frame_po.y, cand.x - frame_po.x);
+    if dy.abs() > max_offset || dx.abs() > max_offset {
+      return None;
+    }
+    let dv = MotionVector { row: 8 * dy as i16, col: 8 * dx as i16 };
+    if !is_dv_valid(dv, tile_bo, bsize, ts.mi_width, ts.mi_height, ts.sb_size_log2) {
+      return None;
+    }
+    let src = rec.subregion(Area::StartingAt { x: po.x + dx, y: po.y + dy });
+    let sad = get_sad(&org, &src, blk_w, blk_h, fi.sequence.bit_depth);
+    Some((sad, dy.abs() + dx.abs(), dv))
+  }).min_by_key(|&(sad, dist, _)| (sad, dist)).map(|(_, _, dv)| dv)
+}
+
+/// Copies the prediction from the reconstruction of the current frame. The
+/// DV is a whole number of pixels in every plane, so no filtering is needed.
+pub fn predict_intrabc<T: Pixel>(
+  rec: &mut PlaneRegionMut<'_, T>, po: PlaneOffset, width: usize,
+  height: usize, dv: MotionVector
+) {
+  let &PlaneConfig { xdec, ydec, .. } = rec.plane_cfg;
+  let pred = {
+    let src = rec.subregion(Area::StartingAt {
+      x: po.x + (dv.col as isize >> (3 + xdec)),
+      y: po.y + (dv.row as isize >> (3 + ydec))
+    });
+    src.rows_iter().take(height)
+      .flat_map(|row| row[..width].iter().cloned())
+      .collect::<Vec<_>>()
+  };
+
+  let mut dst = rec.subregion_mut(Area::StartingAt { x: po.x, y: po.y });
+  for (dst_row, src_row) in dst.rows_iter_mut().zip(pred.chunks(width)) {
+    dst_row[..width].copy_from_slice(src_row);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::partition::BlockSize::*;
+
+  #[test]
+  fn dv_must_be_integer() {
+    let bo = BlockOffset { x: 64, y: 32 };
+    let dv = MotionVector { row: -8 * 128, col: 4 };
+    assert!(!is_dv_valid(dv, bo, BLOCK_8X8, 256, 256, 6));
+  }
+
+  #[test]
+  fn dv_must_point_to_coded_area() {
+    let bo = BlockOffset { x: 128, y: 32 };
+    // Two superblock rows up
+    assert!(is_dv_valid(MotionVector { row: -8 * 128, col: 0 }, bo, BLOCK_8X8, 256, 256, 6));
+    // The block itself and its direct neighborhood
+    assert!(!is_dv_valid(MotionVector { row: 0, col: 0 }, bo, BLOCK_8X8, 256, 256, 6));
+    assert!(!is_dv_valid(MotionVector { row: 0, col: -8 * 64 }, bo, BLOCK_8X8, 256, 256, 6));
+    // Far enough to the left on the same superblock row
+    assert!(is_dv_valid(MotionVector { row: 0, col: -8 * 320 }, bo, BLOCK_8X8, 256, 256, 6));
+    // Outside of the tile
+    assert!(!is_dv_valid(MotionVector { row: -8 * 256, col: 0 }, bo, BLOCK_8X8, 256, 256, 6));
+  }
+
+  #[test]
+  fn default_ref_dv() {
+    let top = BlockOffset { x: 32, y: 4 };
+    assert_eq!(get_ref_dv(&[], top, 6).col, -8 * (64 + 256));
+    let below = BlockOffset { x: 32, y: 20 };
+    assert_eq!(get_ref_dv(&[], below, 6).row, -8 * 64);
+  }
+}
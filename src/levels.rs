@@ -0,0 +1,213 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::api::*;
+use crate::encoder::{FrameInvariants, Sequence};
+
+/// seq_level_idx signaling no level constraints
+pub const SEQ_LEVEL_IDX_MAX_PARAMETERS: u8 = 31;
+
+/// Limits of a level, from the AV1 specification, Annex A.3
+struct LevelLimits {
+  seq_level_idx: u8,
+  max_pic_size: u64,
+  max_h_size: u64,
+  max_v_size: u64,
+  max_display_rate: u64,
+  max_header_rate: u64,
+  /// Maximum bitrate of the main tier, in kbps, for profile 0
+  main_kbps: u64,
+  /// Maximum bitrate of the high tier, in kbps, for profile 0. 0 if the
+  /// level has no high tier.
+  high_kbps: u64,
+  max_tiles: u64,
+  max_tile_cols: u64,
+}
+
+const fn limits(
+  seq_level_idx: u8, max_pic_size: u64, max_h_size: u64, max_v_size: u64,
+  max_display_rate: u64, max_header_rate: u64, main_kbps: u64,
+  high_kbps: u64, max_tiles: u64, max_tile_cols: u64
+) -> LevelLimits {
+  LevelLimits {
+    seq_level_idx, max_pic_size, max_h_size, max_v_size, max_display_rate,
+    max_header_rate, main_kbps, high_kbps, max_tiles, max_tile_cols
+  }
+}
+
+// Levels 2.2, 2.3, 3.2, 3.3, 4.2, 4.3 and 7.x are not defined
+static LEVELS: [LevelLimits; 14] = [
+  limits(0, 147_456, 2048, 1152, 4_423_680, 150, 1_500, 0, 8, 4),
+  limits(1, 278_784, 2816, 1584, 8_363_520, 150, 3_000, 0, 8, 4),
+  limits(4, 665_856, 4352, 2448, 19_975_680, 150, 6_000, 0, 16, 6),
+  limits(5, 1_065_024, 5504, 3096, 31_950_720, 150, 10_000, 0, 16, 6),
+  limits(8, 2_359_296, 6144, 3456, 70_778_880, 300, 12_000, 30_000, 32, 8),
+  limits(9, 2_359_296, 6144, 3456, 141_557_760, 300, 20_000, 50_000, 32, 8),
+  limits(12, 8_912_896, 8192, 4352, 267_386_880, 300, 30_000, 100_000, 64, 8),
+  limits(13, 8_912_896, 8192, 4352, 534_773_760, 300, 40_000, 160_000, 64, 8),
+  limits(14, 8_912_896, 8192, 4352, 1_069_547_520, 300, 60_000, 240_000, 64, 8),
+  limits(15, 8_912_896, 8192, 4352, 1_069_547_520, 300, 60_000, 240_000, 64, 8),
+  limits(16, 35_651_584, 16384, 8704, 1_069_547_520, 300, 60_000, 240_000, 128, 16),
+  limits(17, 35_651_584, 16384, 8704, 2_139_095_040, 300, 100_000, 480_000, 128, 16),
+  limits(18, 35_651_584, 16384, 8704, 4_278_190_080, 300, 160_000, 800_000, 128, 16),
+  limits(19, 35_651_584, 16384, 8704, 4_278_190_080, 300, 160_000, 800_000, 128, 16),
+];
+
+fn level_limits(major: u8, minor: u8) -> Option<&'static LevelLimits> {
+  if major < 2 || minor > 3 {
+    return None;
+  }
+  let seq_level_idx = ((major - 2) << 2) + minor;
+  LEVELS.iter().find(|l| l.seq_level_idx == seq_level_idx)
+}
+
+/// Checks the configuration against a level, returning the first limit it
+/// exceeds
+fn check_level(
+  config: &EncoderConfig, major: u8, minor: u8, high_tier: bool
+) -> Result<(), InvalidConfig> {
+  let limits =
+    level_limits(major, minor).ok_or(InvalidConfig::UndefinedLevel { major, minor })?;
+  if high_tier && limits.high_kbps == 0 {
+    return Err(InvalidConfig::NoHighTier { major, minor });
+  }
+
+  let seq = Sequence::new(config);
+  let tiling = FrameInvariants::<u16>::new(config.clone(), seq).tiling;
+  let time_base = config.time_base;
+  let pic_size = (config.width * config.height) as u64;
+  // Frames per second, rounded up
+  let frame_rate = (time_base.den + time_base.num - 1) / time_base.num;
+  let max_kbps = if high_tier { limits.high_kbps } else { limits.main_kbps };
+  // BitrateProfileFactor
+  let max_bitrate = max_kbps * 1000 * (1 + seq.profile as u64);
+
+  let checks = [
+    (LevelLimit::PictureSize, pic_size, limits.max_pic_size),
+    (LevelLimit::Width, config.width as u64, limits.max_h_size),
+    (LevelLimit::Height, config.height as u64, limits.max_v_size),
+    (LevelLimit::DisplayRate, pic_size * time_base.den / time_base.num,
+      limits.max_display_rate),
+    (LevelLimit::FrameRate, frame_rate, limits.max_header_rate),
+    (LevelLimit::Bitrate, config.bitrate.max(0) as u64, max_bitrate),
+    (LevelLimit::Tiles, (tiling.cols * tiling.rows) as u64, limits.max_tiles),
+    (LevelLimit::TileColumns, tiling.cols as u64, limits.max_tile_cols),
+  ];
+  for &(limit, actual, max) in checks.iter() {
+    if actual > max {
+      return Err(InvalidConfig::LevelLimitExceeded {
+        major, minor, limit, actual, max
+      });
+    }
+  }
+
+  Ok(())
+}
+
+/// Resolves `Level::Auto` to the lowest level the configuration fits in,
+/// preferring the main tier, or checks an explicit level
+pub(crate) fn select_level(config: &EncoderConfig) -> Result<Level, InvalidConfig> {
+  match config.level {
+    Level::Unconstrained => Ok(Level::Unconstrained),
+    Level::Fixed { major, minor, high_tier } => {
+      check_level(config, major, minor, high_tier)?;
+      Ok(config.level)
+    }
+    Level::Auto => {
+      for &high_tier in [false, true].iter() {
+        for limits in LEVELS.iter() {
+          let major = (limits.seq_level_idx >> 2) + 2;
+          let minor = limits.seq_level_idx & 3;
+          if check_level(config, major, minor, high_tier).is_ok() {
+            return Ok(Level::Fixed { major, minor, high_tier });
+          }
+        }
+      }
+      // Nothing fits, e.g. beyond 8K
+      Ok(Level::Unconstrained)
+    }
+  }
+}
+
+/// The seq_level_idx and seq_tier to signal for a resolved level
+pub fn seq_level_idx_and_tier(level: Level) -> (u8, bool) {
+  match level {
+    Level::Fixed { major, minor, high_tier } => {
+      (((major - 2) << 2) + minor, high_tier)
+    }
+    Level::Auto | Level::Unconstrained => {
+      (SEQ_LEVEL_IDX_MAX_PARAMETERS, false)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn config(width: usize, height: usize, fps: u64) -> EncoderConfig {
+    let mut enc = EncoderConfig::default();
+    enc.width = width;
+    enc.height = height;
+    enc.time_base = Rational::new(1, fps);
+    enc
+  }
+
+  #[test]
+  fn auto_level() {
+    let mut enc = config(1920, 1080, 30);
+    enc.level = Level::Auto;
+    assert_eq!(
+      select_level(&enc),
+      Ok(Level::Fixed { major: 4, minor: 0, high_tier: false })
+    );
+
+    let mut enc = config(1920, 1080, 60);
+    enc.level = Level::Auto;
+    assert_eq!(
+      select_level(&enc),
+      Ok(Level::Fixed { major: 4, minor: 1, high_tier: false })
+    );
+
+    let mut enc = config(3840, 2160, 60);
+    enc.level = Level::Auto;
+    assert_eq!(
+      select_level(&enc),
+      Ok(Level::Fixed { major: 5, minor: 1, high_tier: false })
+    );
+  }
+
+  #[test]
+  fn level_limit_exceeded() {
+    let mut enc = config(1920, 1080, 30);
+    enc.level = Level::Fixed { major: 3, minor: 1, high_tier: false };
+    assert_eq!(
+      select_level(&enc),
+      Err(InvalidConfig::LevelLimitExceeded {
+        major: 3,
+        minor: 1,
+        limit: LevelLimit::PictureSize,
+        actual: 1920 * 1080,
+        max: 1_065_024
+      })
+    );
+
+    enc.level = Level::Fixed { major: 4, minor: 0, high_tier: false };
+    enc.bitrate = 20_000_000;
+    assert!(select_level(&enc).is_err());
+    enc.level = Level::Fixed { major: 4, minor: 0, high_tier: true };
+    assert!(select_level(&enc).is_ok());
+
+    enc.level = Level::Fixed { major: 4, minor: 2, high_tier: false };
+    assert_eq!(
+      select_level(&enc),
+      Err(InvalidConfig::UndefinedLevel { major: 4, minor: 2 })
+    );
+  }
+}
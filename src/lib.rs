@@ -18,6 +18,7 @@ extern crate pretty_assertions;
 #[cfg(cargo_c)]
 mod capi;
 
+mod activity;
 mod ec;
 mod partition;
 mod transform;
@@ -33,17 +34,28 @@ mod token_cdfs;
 mod deblock;
 mod segmentation;
 mod cdef;
+mod levels;
 mod lrf;
 mod encoder;
 mod mc;
 mod me;
-mod metrics;
+mod dist;
+mod compound;
+mod intrabc;
+mod screen_content;
+mod palette;
+pub mod metrics;
 mod scan_order;
 mod scenechange;
+mod denoise;
+mod film_grain;
 mod rate;
 mod tiling;
+mod threading;
+mod timing;
 
 mod api;
+mod block_log;
 mod header;
 mod frame;
 
@@ -57,7 +69,10 @@ pub mod prelude {
   pub use crate::api::*;
   pub use crate::frame::Frame;
   pub use crate::encoder::Tune;
+  pub use crate::block_log::{BlockDecision, BlockDecisionLog};
+  pub use crate::dist::{Distortion, DistortionMetric, Sad, Satd, Sse, Weighted};
   pub use crate::partition::BlockSize;
+  pub use crate::timing::{Stage, StageTimes};
   pub use crate::util::{CastFromPrimitive, Pixel};
 }
 
@@ -65,8 +80,13 @@ pub mod prelude {
 pub mod data {
   pub use crate::frame::Frame;
   pub use crate::api::{
-    Packet, Point, Rational, FrameType, EncoderStatus
+    Packet, Point, Rational, FrameType, FrameSubtype, EncoderStatus,
+    FrameParameters, FrameTypeOverride, SegmentationMap, RoiMap, RoiValues,
+    T35, IntoFrame, EncoderObserver, FrameEncodeStats, FrameStatsLog,
+    StatsFormat, filter_temporal_layers
   };
+  pub use crate::block_log::{BlockDecision, BlockDecisionLog};
+  pub use crate::timing::{Stage, StageTimes};
   pub use crate::util::{CastFromPrimitive, Pixel};
 }
 
@@ -89,7 +109,13 @@ pub mod color {
 pub mod config {
   pub use crate::api::{
     Config, EncoderConfig, SpeedSettings, PredictionModesSetting,
+    SuperResMode, Level, InvalidConfig, LevelLimit, RateParameters,
+    QuantizerOffsets, IntraRefresh, IntraRefreshDirection,
   };
+  pub use crate::film_grain::{
+    FilmGrainParams, FilmGrainSegment, FilmGrainTable, ScalingPoint,
+  };
+  pub use crate::dist::{Distortion, DistortionMetric, Sad, Satd, Sse, Weighted};
 }
 
 
@@ -182,6 +208,12 @@ pub mod version {
 #[cfg(all(test, any(feature="decode_test", feature="decode_test_dav1d")))]
 mod test_encode_decode;
 
+#[cfg(feature="check_decode")]
+pub mod check_decode;
+
+#[cfg(fuzzing)]
+pub mod fuzzing;
+
 #[cfg(feature="bench")]
 pub mod bench {
   pub mod api { pub use crate::api::*; }
@@ -192,6 +224,7 @@ pub mod bench {
   pub mod me { pub use crate::me::*; }
   pub mod partition { pub use crate::partition::*; }
   pub mod frame { pub use crate::frame::*; }
+  pub mod mc { pub use crate::mc::*; }
   pub mod predict { pub use crate::predict::*; }
   pub mod rdo { pub use crate::rdo::*; }
   pub mod transform { pub use crate::transform::*; }
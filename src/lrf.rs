@@ -21,6 +21,7 @@ use crate::frame::PlaneConfig;
 use std::cmp;
 use crate::util::clamp;
 use crate::util::CastFromPrimitive;
+use crate::util::Fixed;
 use crate::util::Pixel;
 
 use std::ops::{Index, IndexMut};
@@ -56,6 +57,55 @@ pub const SGRPROJ_PARAMS_S: [[u32; 2]; 1 << SGRPROJ_PARAMS_BITS] = [
   [  0, 1177], [  0,  925], [ 56,    0], [ 22,    0]
 ];
 
+/// Numerator of the superres scale, frames are coded with a width of
+/// `SUPERRES_NUM / denominator` of the output width
+pub const SUPERRES_NUM: u8 = 8;
+pub const SUPERRES_DENOM_MIN: u8 = 9;
+pub const SUPERRES_DENOM_MAX: u8 = 16;
+pub const SUPERRES_DENOM_BITS: u8 = 3;
+const SUPERRES_FILTER_BITS: usize = 6;
+const SUPERRES_FILTER_TAPS: usize = 8;
+const SUPERRES_FILTER_OFFSET: isize = 3;
+const SUPERRES_SCALE_BITS: usize = 14;
+const SUPERRES_SCALE_MASK: isize = (1 << SUPERRES_SCALE_BITS) - 1;
+const SUPERRES_EXTRA_BITS: usize = SUPERRES_SCALE_BITS - SUPERRES_FILTER_BITS;
+
+/// Normative 8-tap filters of the superres upscaler, by 1/64 pixel phase
+const SUPERRES_FILTER: [[i16; SUPERRES_FILTER_TAPS]; 1 << SUPERRES_FILTER_BITS] = [
+  [  0,   0,   0, 128,   0,   0,   0,   0], [  0,   0,  -1, 128,   2,  -1,   0,   0],
+  [  0,   1,  -3, 127,   4,  -2,   1,   0], [  0,   1,  -4, 127,   6,  -3,   1,   0],
+  [  0,   2,  -6, 126,   8,  -3,   1,   0], [  0,   2,  -7, 125,  11,  -4,   1,   0],
+  [ -1,   2,  -8, 125,  13,  -5,   2,   0], [ -1,   3,  -9, 124,  15,  -6,   2,   0],
+  [ -1,   3, -10, 123,  18,  -6,   2,  -1], [ -1,   3, -11, 122,  20,  -7,   3,  -1],
+  [ -1,   4, -12, 121,  22,  -8,   3,  -1], [ -1,   4, -13, 120,  25,  -9,   3,  -1],
+  [ -1,   4, -14, 118,  28,  -9,   3,  -1], [ -1,   4, -15, 117,  30, -10,   4,  -1],
+  [ -1,   5, -16, 116,  32, -11,   4,  -1], [ -1,   5, -16, 114,  35, -12,   4,  -1],
+  [ -1,   5, -17, 112,  38, -12,   4,  -1], [ -1,   5, -18, 111,  40, -13,   5,  -1],
+  [ -1,   5, -18, 109,  43, -14,   5,  -1], [ -1,   6, -19, 107,  45, -14,   5,  -1],
+  [ -1,   6, -19, 105,  48, -15,   5,  -1], [ -1,   6, -19, 103,  51, -16,   5,  -1],
+  [ -1,   6, -20, 101,  53, -16,   6,  -1], [ -1,   6, -20,  99,  56, -17,   6,  -1],
+  [ -1,   6, -20,  97,  58, -17,   6,  -1], [ -1,   6, -20,  95,  61, -18,   6,  -1],
+  [ -2,   7, -20,  93,  64, -18,   6,  -2], [ -2,   7, -20,  91,  66, -19,   6,  -1],
+  [ -2,   7, -20,  88,  69, -19,   6,  -1], [ -2,   7, -20,  86,  71, -19,   6,  -1],
+  [ -2,   7, -20,  84,  74, -20,   7,  -2], [ -2,   7, -20,  81,  76, -20,   7,  -1],
+  [ -2,   7, -20,  79,  79, -20,   7,  -2], [ -1,   7, -20,  76,  81, -20,   7,  -2],
+  [ -2,   7, -20,  74,  84, -20,   7,  -2], [ -1,   6, -19,  71,  86, -20,   7,  -2],
+  [ -1,   6, -19,  69,  88, -20,   7,  -2], [ -1,   6, -19,  66,  91, -20,   7,  -2],
+  [ -2,   6, -18,  64,  93, -20,   7,  -2], [ -1,   6, -18,  61,  95, -20,   6,  -1],
+  [ -1,   6, -17,  58,  97, -20,   6,  -1], [ -1,   6, -17,  56,  99, -20,   6,  -1],
+  [ -1,   6, -16,  53, 101, -20,   6,  -1], [ -1,   5, -16,  51, 103, -19,   6,  -1],
+  [ -1,   5, -15,  48, 105, -19,   6,  -1], [ -1,   5, -14,  45, 107, -19,   6,  -1],
+  [ -1,   5, -14,  43, 109, -18,   5,  -1], [ -1,   5, -13,  40, 111, -18,   5,  -1],
+  [ -1,   4, -12,  38, 112, -17,   5,  -1], [ -1,   4, -12,  35, 114, -16,   5,  -1],
+  [ -1,   4, -11,  32, 116, -16,   5,  -1], [ -1,   4, -10,  30, 117, -15,   4,  -1],
+  [ -1,   3,  -9,  28, 118, -14,   4,  -1], [ -1,   3,  -9,  25, 120, -13,   4,  -1],
+  [ -1,   3,  -8,  22, 121, -12,   4,  -1], [ -1,   3,  -7,  20, 122, -11,   3,  -1],
+  [ -1,   2,  -6,  18, 123, -10,   3,  -1], [  0,   2,  -6,  15, 124,  -9,   3,  -1],
+  [  0,   2,  -5,  13, 125,  -8,   2,  -1], [  0,   1,  -4,  11, 125,  -7,   2,   0],
+  [  0,   1,  -3,   8, 126,  -6,   2,   0], [  0,   1,  -3,   6, 127,  -4,   1,   0],
+  [  0,   1,  -2,   4, 127,  -3,   1,   0], [  0,   0,  -1,   2, 128,  -1,   0,   0],
+];
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RestorationFilter {
   None,
@@ -635,8 +685,8 @@ pub fn sgrproj_solve<T: Pixel>(set: u8, fi: &FrameInvariants<T>,
     }
 
     for yi in 0..cdef_h {
-      let u = i32::cast_from(cdeffed.p(yi,xi)) << SGRPROJ_RST_BITS;
-      let s = i32::cast_from(input.p(yi,xi)) << SGRPROJ_RST_BITS;
+      let u = i32::cast_from(cdeffed.p(xi,yi)) << SGRPROJ_RST_BITS;
+      let s = i32::cast_from(input.p(xi,yi)) << SGRPROJ_RST_BITS;
       let f2 = f_r2[yi] as i32 - u;
       let f1 = f_r1[yi] as i32 - u;
       h[0][0] += f2 as f64 * f2 as f64;
@@ -687,36 +737,157 @@ pub fn sgrproj_solve<T: Pixel>(set: u8, fi: &FrameInvariants<T>,
    clamp(xq1, SGRPROJ_XQD_MIN[1] as i32, SGRPROJ_XQD_MAX[1] as i32) as i8)
 }
 
-fn wiener_stripe_filter<T: Pixel>(coeffs: [[i8; 3]; 2], fi: &FrameInvariants<T>,
+fn wiener_full_taps(taps: [f64; 3]) -> [f64; 7] {
+  [taps[0], taps[1], taps[2], 1. - 2. * (taps[0] + taps[1] + taps[2]), taps[2], taps[1], taps[0]]
+}
+
+// Solves the normal equations of the Wiener taps in use; the outermost tap
+// is fixed at zero for the 5-tap chroma filters.
+fn wiener_solve_taps(a: &[[f64; 3]; 3], b: &[f64; 3], first_tap: usize) -> [f64; 3] {
+  let mut m = *a;
+  let mut v = *b;
+  let mut x = [0.; 3];
+  for i in first_tap..3 {
+    let mut pivot = i;
+    for j in i+1..3 {
+      if m[j][i].abs() > m[pivot][i].abs() {
+        pivot = j;
+      }
+    }
+    if m[pivot][i] == 0. {
+      return [0.; 3];
+    }
+    m.swap(i, pivot);
+    v.swap(i, pivot);
+    for j in i+1..3 {
+      let f = m[j][i] / m[i][i];
+      for k in i..3 {
+        m[j][k] -= f * m[i][k];
+      }
+      v[j] -= f * v[i];
+    }
+  }
+  for i in (first_tap..3).rev() {
+    let mut acc = v[i];
+    for k in i+1..3 {
+      acc -= m[i][k] * x[k];
+    }
+    x[i] = acc / m[i][i];
+  }
+  x
+}
+
+// Finds the separable symmetric Wiener filter that best maps the cdeffed
+// reconstruction onto the input.  The 2D least squares problem is not
+// separable, so the horizontal and vertical taps are fit alternately with
+// the other direction held fixed, starting from an identity vertical filter.
+// Inputs follow the same rules as sgrproj_solve.
+pub fn wiener_solve<T: Pixel>(pli: usize,
+                              input: &PlaneSlice<T>,
+                              cdeffed: &PlaneSlice<T>,
+                              cdef_w: usize, cdef_h: usize) -> [[i8; 3]; 2] {
+  let first_tap = if pli == 0 { 0 } else { 1 };
+  let px = |x: isize, y: isize| {
+    let x = clamp(x, 0, cdef_w as isize - 1) as usize;
+    let y = clamp(y, 0, cdef_h as isize - 1) as usize;
+    f64::from(i32::cast_from(cdeffed.p(x, y)))
+  };
+
+  let mut taps = [[0.; 3]; 2];
+  for &pass in &[1, 0, 1] {
+    let other = wiener_full_taps(taps[1 - pass]);
+    let mut a = [[0.; 3]; 3];
+    let mut b = [0.; 3];
+    for y in 0..cdef_h as isize {
+      for x in 0..cdef_w as isize {
+        // apply the fixed filter at each position under the fitted one
+        let mut line = [0.; 7];
+        for (j, l) in line.iter_mut().enumerate() {
+          let o = j as isize - 3;
+          *l = other.iter().enumerate().map(|(i, &f)| {
+            let t = i as isize - 3;
+            f * if pass == 1 { px(x + o, y + t) } else { px(x + t, y + o) }
+          }).sum();
+        }
+        let mut d = [0.; 3];
+        for (k, dk) in d.iter_mut().enumerate() {
+          *dk = line[k] + line[6 - k] - 2. * line[3];
+        }
+        let t = f64::from(i32::cast_from(input.p(x as usize, y as usize))) - line[3];
+        for ((ai, bi), &di) in a.iter_mut().zip(b.iter_mut()).zip(d.iter()) {
+          for (aij, &dj) in ai.iter_mut().zip(d.iter()) {
+            *aij += di * dj;
+          }
+          *bi += di * t;
+        }
+      }
+    }
+    taps[pass] = wiener_solve_taps(&a, &b, first_tap);
+  }
+
+  let mut coeffs = [[0; 3]; 2];
+  for pass in 0..2 {
+    for i in first_tap..3 {
+      let tap = (taps[pass][i] * (1 << WIENER_BITS) as f64).round() as i32;
+      coeffs[pass][i] = clamp(tap, WIENER_TAPS_MIN[i] as i32, WIENER_TAPS_MAX[i] as i32) as i8;
+    }
+  }
+  coeffs
+}
+
+pub fn wiener_stripe_filter<T: Pixel>(coeffs: [[i8; 3]; 2], fi: &FrameInvariants<T>,
                                   crop_w: usize, crop_h: usize,
                                   stripe_w: usize, stripe_h: usize,
                                   stripe_x: usize, stripe_y: isize,
                                   cdeffed: &Plane<T>, deblocked: &Plane<T>, out: &mut Plane<T>) {
   let bit_depth = fi.sequence.bit_depth;
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("sse4.1") {
+      return unsafe {
+        sse4_1::wiener_stripe_filter(coeffs, bit_depth, crop_w, crop_h, stripe_w, stripe_h,
+                                     stripe_x, stripe_y, cdeffed, deblocked, out)
+      };
+    }
+  }
+  wiener_stripe_filter_rs(coeffs, bit_depth, crop_w, crop_h, stripe_w, stripe_h,
+                          stripe_x, stripe_y, cdeffed, deblocked, out)
+}
+
+// The 7 symmetric taps of a Wiener filter from its 3 coded coefficients
+fn wiener_taps(coeffs: [i8; 3]) -> [i32; 7] {
+  let c = [coeffs[0] as i32, coeffs[1] as i32, coeffs[2] as i32];
+  [c[0], c[1], c[2], 128 - 2 * (c[0] + c[1] + c[2]), c[2], c[1], c[0]]
+}
+
+// The row of the stripe borders or of the stripe that the filter reads for
+// row yi, from the deblocked or the CDEF output respectively
+fn wiener_source_row<'a, T: Pixel>(yi: isize, crop_h: usize, stripe_h: usize,
+                                   stripe_y: isize, cdeffed: &'a Plane<T>,
+                                   deblocked: &'a Plane<T>) -> (&'a Plane<T>, usize) {
+  if yi < stripe_y {
+    (deblocked, cmp::max(clamp(yi, 0, crop_h as isize - 1), stripe_y - 2) as usize)
+  } else if yi < stripe_y+stripe_h as isize {
+    (cdeffed, clamp(yi, 0, crop_h as isize - 1) as usize)
+  } else {
+    (deblocked,
+     cmp::min(clamp(yi, 0, crop_h as isize - 1), stripe_y + stripe_h as isize + 1) as usize)
+  }
+}
+
+fn wiener_stripe_filter_rs<T: Pixel>(coeffs: [[i8; 3]; 2], bit_depth: usize,
+                                     crop_w: usize, crop_h: usize,
+                                     stripe_w: usize, stripe_h: usize,
+                                     stripe_x: usize, stripe_y: isize,
+                                     cdeffed: &Plane<T>, deblocked: &Plane<T>, out: &mut Plane<T>) {
   let round_h = if bit_depth == 12 {5} else {3};
   let round_v = if bit_depth == 12 {9} else {11};
   let offset = 1 << (bit_depth + WIENER_BITS - round_h - 1);
   let limit = (1 << (bit_depth + 1 + WIENER_BITS - round_h)) - 1;
 
   let mut work: [i32; MAX_SB_SIZE+7] = [0; MAX_SB_SIZE+7];
-  let vfilter: [i32; 7] = [ coeffs[0][0] as i32,
-                            coeffs[0][1] as i32,
-                            coeffs[0][2] as i32,
-                            128 - 2 * (coeffs[0][0] as i32 +
-                                       coeffs[0][1] as i32 +
-                                       coeffs[0][2] as i32 ),
-                            coeffs[0][2] as i32,
-                            coeffs[0][1] as i32,
-                            coeffs[0][0] as i32];
-  let hfilter: [i32; 7] = [ coeffs[1][0] as i32,
-                            coeffs[1][1] as i32,
-                            coeffs[1][2] as i32,
-                            128 - 2 * (coeffs[1][0] as i32 +
-                                       coeffs[1][1] as i32 +
-                                       coeffs[1][2] as i32),
-                            coeffs[1][2] as i32,
-                            coeffs[1][1] as i32,
-                            coeffs[1][0] as i32];
+  let vfilter = wiener_taps(coeffs[0]);
+  let hfilter = wiener_taps(coeffs[1]);
 
   // unlike x, our y can be negative to start as the first stripe
   // starts off the top of the frame by 8 pixels, and can also run off the end of the frame
@@ -733,19 +904,9 @@ fn wiener_stripe_filter<T: Pixel>(coeffs: [[i8; 3]; 2], fi: &FrameInvariants<T>,
   for xi in stripe_x..stripe_x+stripe_w {
     let n = cmp::min(7, crop_w as isize + 3 - xi as isize);
     for yi in stripe_y - 3..stripe_y + stripe_h as isize + 4 {
-      let src_plane: &Plane<T>;
+      let (src_plane, ly) =
+        wiener_source_row(yi, crop_h, stripe_h, stripe_y, cdeffed, deblocked);
       let mut acc = 0;
-      let ly;
-      if yi < stripe_y {
-        ly = cmp::max(clamp(yi, 0, crop_h as isize - 1), stripe_y - 2) as usize;
-        src_plane = deblocked;
-      } else if yi < stripe_y+stripe_h as isize {
-        ly = clamp(yi, 0, crop_h as isize - 1) as usize;
-        src_plane = cdeffed;
-      } else {
-        ly = cmp::min(clamp(yi, 0, crop_h as isize - 1), stripe_y + stripe_h as isize + 1) as usize;
-        src_plane = deblocked;
-      }
 
       for i in 0..3 - xi as isize {
         acc += hfilter[i as usize] * i32::cast_from(src_plane.p(0, ly));
@@ -771,6 +932,94 @@ fn wiener_stripe_filter<T: Pixel>(coeffs: [[i8; 3]; 2], fi: &FrameInvariants<T>,
   }
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sse4_1 {
+  use super::*;
+  #[cfg(target_arch = "x86")]
+  use std::arch::x86::*;
+  #[cfg(target_arch = "x86_64")]
+  use std::arch::x86_64::*;
+
+  /// Same as `wiener_stripe_filter_rs`, filtering the rows of the stripe
+  /// into a buffer, then its columns, 4 pixels at a time
+  #[target_feature(enable = "sse4.1")]
+  pub(super) unsafe fn wiener_stripe_filter<T: Pixel>(
+    coeffs: [[i8; 3]; 2], bit_depth: usize, crop_w: usize, crop_h: usize,
+    stripe_w: usize, stripe_h: usize, stripe_x: usize, stripe_y: isize,
+    cdeffed: &Plane<T>, deblocked: &Plane<T>, out: &mut Plane<T>
+  ) {
+    let round_h = if bit_depth == 12 {5} else {3};
+    let round_v = if bit_depth == 12 {9} else {11};
+    let offset = 1 << (bit_depth + WIENER_BITS - round_h - 1);
+    let limit = (1 << (bit_depth + 1 + WIENER_BITS - round_h)) - 1;
+    let vfilter = wiener_taps(coeffs[0]);
+    let hfilter = wiener_taps(coeffs[1]);
+
+    // Rows are filtered in groups of 4 pixels, the last one possibly
+    // running past the stripe
+    let work_stride = (stripe_w + 3) & !3;
+    let mut work = vec![0i32; (stripe_h + 7) * work_stride];
+    let mut row = vec![0i32; work_stride + 6];
+
+    let h_round = _mm_set1_epi32(1 << round_h >> 1);
+    let h_shift = _mm_cvtsi32_si128(round_h as i32);
+    let h_min = _mm_set1_epi32(-offset);
+    let h_max = _mm_set1_epi32(limit - offset);
+    for (wy, yi) in (stripe_y - 3..stripe_y + stripe_h as isize + 4).enumerate() {
+      let (src_plane, ly) =
+        wiener_source_row(yi, crop_h, stripe_h, stripe_y, cdeffed, deblocked);
+      // Pixels left and right of the frame repeat its edges
+      for (i, v) in row[..stripe_w + 6].iter_mut().enumerate() {
+        let x = clamp((stripe_x + i) as isize - 3, 0, crop_w as isize - 1);
+        *v = i32::cast_from(src_plane.p(x as usize, ly));
+      }
+      let dst = &mut work[wy * work_stride..][..work_stride];
+      for x in (0..work_stride).step_by(4) {
+        let mut acc = h_round;
+        for (i, &tap) in hfilter.iter().enumerate() {
+          let src = _mm_loadu_si128(row[x + i..].as_ptr() as *const _);
+          acc = _mm_add_epi32(acc, _mm_mullo_epi32(src, _mm_set1_epi32(tap)));
+        }
+        let acc = _mm_sra_epi32(acc, h_shift);
+        let acc = _mm_min_epi32(_mm_max_epi32(acc, h_min), h_max);
+        _mm_storeu_si128(dst[x..].as_mut_ptr() as *mut _, acc);
+      }
+    }
+
+    // unlike x, our y can be negative to start as the first stripe
+    // starts off the top of the frame by 8 pixels, and can also run off the end of the frame
+    let start_wi = if stripe_y < 0 {-stripe_y} else {0} as usize;
+    let start_yi = if stripe_y < 0 {0} else {stripe_y} as usize;
+    let end_i = cmp::max(0, if stripe_h as isize + stripe_y > crop_h as isize {
+      crop_h as isize - stripe_y - start_wi as isize
+    } else {
+      stripe_h as isize - start_wi as isize
+    }) as usize;
+
+    let v_round = _mm_set1_epi32(1 << round_v >> 1);
+    let v_shift = _mm_cvtsi32_si128(round_v as i32);
+    let v_max = _mm_set1_epi32((1 << bit_depth) - 1);
+    let mut out_slice = out.mut_slice(PlaneOffset{x: 0, y: start_yi as isize});
+    for (wi, out_row) in (start_wi..start_wi+end_i).zip(out_slice.rows_iter_mut().take(end_i)) {
+      for x in (0..stripe_w).step_by(4) {
+        let mut acc = v_round;
+        for (i, &tap) in vfilter.iter().enumerate() {
+          let src = _mm_loadu_si128(work[(wi + i) * work_stride + x..].as_ptr() as *const _);
+          acc = _mm_add_epi32(acc, _mm_mullo_epi32(src, _mm_set1_epi32(tap)));
+        }
+        let acc = _mm_sra_epi32(acc, v_shift);
+        let acc = _mm_min_epi32(_mm_max_epi32(acc, _mm_setzero_si128()), v_max);
+        let mut px = [0i32; 4];
+        _mm_storeu_si128(px.as_mut_ptr() as *mut _, acc);
+        let n = cmp::min(4, stripe_w - x);
+        for (dst, &v) in out_row[stripe_x + x..][..n].iter_mut().zip(px.iter()) {
+          *dst = T::cast_from(v);
+        }
+      }
+    }
+  }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct RestorationUnit {
   pub filter: RestorationFilter,
@@ -820,9 +1069,12 @@ impl IndexMut<usize> for FrameRestorationUnits {
 pub struct RestorationPlaneConfig {
   pub lrf_type: u8,
   pub unit_size: usize,
-  // (1 << sb_shift) gives the number of superblocks both horizontally and
-  // vertically in a restoration unit, not accounting for RU stretching
-  pub sb_shift: usize,
+  // (1 << sb_h_shift) gives the number of superblocks horizontally in a
+  // restoration unit, not accounting for RU stretching
+  pub sb_h_shift: usize,
+  // (1 << sb_v_shift) gives the number of superblocks vertically in a
+  // restoration unit, not accounting for RU stretching
+  pub sb_v_shift: usize,
   // stripe height is 64 in all cases except 4:2:0 chroma planes where
   // it is 32.  This is independent of all other setup parameters
   pub stripe_height: usize,
@@ -843,14 +1095,15 @@ pub struct RestorationPlaneOffset {
 }
 
 impl RestorationPlane {
-  pub fn new(lrf_type: u8, unit_size: usize, sb_shift: usize, stripe_decimate: usize,
-             cols: usize, rows: usize) -> RestorationPlane {
+  pub fn new(lrf_type: u8, unit_size: usize, sb_h_shift: usize, sb_v_shift: usize,
+             stripe_decimate: usize, cols: usize, rows: usize) -> RestorationPlane {
     let stripe_height = if stripe_decimate != 0 {32} else {64};
     RestorationPlane {
       cfg: RestorationPlaneConfig {
         lrf_type,
         unit_size,
-        sb_shift,
+        sb_h_shift,
+        sb_v_shift,
         stripe_height,
         cols,
         rows,
@@ -898,18 +1151,37 @@ impl RestorationState {
     let y_unit_size = 1 << y_unit_log2;
     let uv_unit_size = 1 << uv_unit_log2;
     let y_sb_log2 = if fi.sequence.use_128x128_superblock {7} else {6};
-    let uv_sb_log2 = y_sb_log2 - stripe_uv_decimate;
-    let cols = ((fi.width + (y_unit_size >> 1)) / y_unit_size).max(1);
-    let rows = ((fi.height + (y_unit_size >> 1)) / y_unit_size).max(1);
+    // The chroma units of 4:2:2 frames span more superblocks horizontally
+    // than vertically, as only 4:2:0 chroma units are decimated
+    let uv_sb_h_log2 = y_sb_log2 - xdec;
+    let uv_sb_v_log2 = y_sb_log2 - ydec;
+    // Units are counted in the dimensions of their plane, the last ones
+    // stretching over less than half a unit of leftover pixels
+    let count_units = |size: usize, unit_size: usize| {
+      ((size + (unit_size >> 1)) / unit_size).max(1)
+    };
+    let y_cols = count_units(fi.width, y_unit_size);
+    let y_rows = count_units(fi.height, y_unit_size);
+    let uv_cols = count_units((fi.width + xdec) >> xdec, uv_unit_size);
+    let uv_rows = count_units((fi.height + ydec) >> ydec, uv_unit_size);
+    // Restoration units of superres frames are laid out in the upscaled
+    // frame, which the encoder does not support yet
+    let lrf_type = if fi.superres_denom != SUPERRES_NUM {
+      RESTORE_NONE
+    } else {
+      RESTORE_SWITCHABLE
+    };
 
     RestorationState {
       planes: [
-        RestorationPlane::new(RESTORE_SWITCHABLE, y_unit_size, y_unit_log2 - y_sb_log2,
-                              0, cols, rows),
-        RestorationPlane::new(RESTORE_SWITCHABLE, uv_unit_size, uv_unit_log2 - uv_sb_log2,
-                              stripe_uv_decimate, cols, rows),
-        RestorationPlane::new(RESTORE_SWITCHABLE, uv_unit_size, uv_unit_log2 - uv_sb_log2,
-                              stripe_uv_decimate, cols, rows)
+        RestorationPlane::new(lrf_type, y_unit_size, y_unit_log2 - y_sb_log2,
+                              y_unit_log2 - y_sb_log2, 0, y_cols, y_rows),
+        RestorationPlane::new(lrf_type, uv_unit_size, uv_unit_log2 - uv_sb_h_log2,
+                              uv_unit_log2 - uv_sb_v_log2, stripe_uv_decimate,
+                              uv_cols, uv_rows),
+        RestorationPlane::new(lrf_type, uv_unit_size, uv_unit_log2 - uv_sb_h_log2,
+                              uv_unit_log2 - uv_sb_v_log2, stripe_uv_decimate,
+                              uv_cols, uv_rows)
       ],
     }
   }
@@ -978,3 +1250,150 @@ impl RestorationState {
     }
   }
 }
+
+/// Upscales the `coded_width` columns of a plane of a frame coded with
+/// superres to the `upscaled_width` columns of the output, as specified in
+/// section 7.16 of the AV1 spec. The widths are in luma pixels.
+pub fn superres_upscale_plane<T: Pixel>(
+  src: &Plane<T>, dst: &mut Plane<T>, coded_width: usize,
+  upscaled_width: usize, bit_depth: usize
+) {
+  let PlaneConfig { xdec, ydec, .. } = src.cfg;
+  assert!(dst.cfg.xdec == xdec && dst.cfg.ydec == ydec);
+
+  let downscaled_w = ((coded_width + xdec) >> xdec) as isize;
+  let upscaled_w = ((upscaled_width + xdec) >> xdec) as isize;
+  let step = ((downscaled_w << SUPERRES_SCALE_BITS) + upscaled_w / 2) / upscaled_w;
+  let err = upscaled_w * step - (downscaled_w << SUPERRES_SCALE_BITS);
+  let initial_subpel_x =
+    ((-((upscaled_w - downscaled_w) << (SUPERRES_SCALE_BITS - 1)) + upscaled_w / 2)
+      / upscaled_w + (1 << (SUPERRES_EXTRA_BITS - 1)) - err / 2)
+    & SUPERRES_SCALE_MASK;
+  // Decoded pixels are available up to the end of the last 4x4 block
+  let max_x = (coded_width.align_power_of_two(3) >> xdec) as isize - 1;
+  let max_val = (1 << bit_depth) - 1;
+
+  let height = src.cfg.height.min(dst.cfg.height);
+  let mut dst = dst.as_region_mut();
+  for (src_row, dst_row) in src.as_region().rows_iter()
+    .zip(dst.rows_iter_mut()).take(height) {
+    for x in 0..upscaled_w {
+      let src_x = -(1 << SUPERRES_SCALE_BITS) + initial_subpel_x + x * step;
+      let src_x_px = src_x >> SUPERRES_SCALE_BITS;
+      let filter = &SUPERRES_FILTER
+        [((src_x & SUPERRES_SCALE_MASK) >> SUPERRES_EXTRA_BITS) as usize];
+      let sum: i32 = filter.iter().enumerate().map(|(k, &tap)| {
+        let sample_x = clamp(
+          src_x_px + k as isize - SUPERRES_FILTER_OFFSET, 0, max_x);
+        i32::cast_from(src_row[sample_x as usize]) * tap as i32
+      }).sum();
+      dst_row[x as usize] = T::cast_from(clamp((sum + 64) >> 7, 0, max_val));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn superres_upscale_flat() {
+    let src = Plane::wrap(vec![100u8; 32 * 4], 32);
+    let mut dst = Plane::wrap(vec![0u8; 64 * 4], 64);
+    superres_upscale_plane(&src, &mut dst, 32, 64, 8);
+    assert!(dst.data.iter().all(|&v| v == 100));
+  }
+
+  #[test]
+  fn superres_upscale_ramp() {
+    let ramp: Vec<u8> = (0..48).map(|x| 4 * x as u8).collect();
+    let src = Plane::wrap(ramp, 48);
+    let mut dst = Plane::wrap(vec![0u8; 64], 64);
+    superres_upscale_plane(&src, &mut dst, 48, 64, 8);
+    // Away from the edges, a ramp is upscaled into a ramp
+    assert!(dst.data[4..60].windows(2).all(|w| w[1] > w[0]));
+  }
+
+  #[test]
+  fn wiener_solve_identity() {
+    let data: Vec<u8> = (0..32 * 32).map(|i| (i * 37 % 251) as u8).collect();
+    let plane = Plane::wrap(data, 32);
+    let slice = plane.slice(PlaneOffset { x: 0, y: 0 });
+    assert_eq!(wiener_solve(0, &slice, &slice, 32, 32), [[0; 3]; 2]);
+  }
+
+  #[test]
+  fn wiener_solve_recovers_horizontal_filter() {
+    let mut seed = 12345u32;
+    let noise: Vec<u8> = (0..32 * 32).map(|_| {
+      seed = seed.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fffffff;
+      (seed >> 16) as u8
+    }).collect();
+    let taps = [2, -6, 14, 108, 14, -6, 2];
+    let mut filtered = vec![0u8; 32 * 32];
+    for y in 0..32 {
+      for x in 0..32 {
+        let sum: i32 = taps.iter().enumerate().map(|(i, &t)| {
+          let sx = clamp(x + i as isize - 3, 0, 31) as usize;
+          t * noise[y * 32 + sx] as i32
+        }).sum();
+        filtered[y * 32 + x as usize] = clamp((sum + 64) >> 7, 0, 255) as u8;
+      }
+    }
+    let cdeffed = Plane::wrap(noise, 32);
+    let input = Plane::wrap(filtered, 32);
+    let coeffs = wiener_solve(0, &input.slice(PlaneOffset { x: 0, y: 0 }),
+                              &cdeffed.slice(PlaneOffset { x: 0, y: 0 }), 32, 32);
+    assert_eq!(coeffs, [[0, 0, 0], [2, -6, 14]]);
+  }
+
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  fn check_wiener_sse4_1<T: Pixel>(bit_depth: usize) {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaChaRng;
+
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let (w, h) = (80, 80);
+    let random_plane = |ra: &mut ChaChaRng| {
+      let data = (0..w * h).map(|_| T::cast_from(ra.gen_range(0u16, 1 << bit_depth))).collect();
+      Plane::wrap(data, w)
+    };
+    let cdeffed = random_plane(&mut ra);
+    let deblocked = random_plane(&mut ra);
+    // Stripes at the top, in the middle and at the bottom of the frame,
+    // with widths that are and are not multiples of 4
+    for &(crop_w, crop_h, stripe_w, stripe_x, stripe_y) in
+      &[(80, 80, 64, 0, -8), (80, 80, 37, 5, 56), (61, 75, 47, 13, 56), (80, 70, 80, 0, 8)]
+    {
+      let stripe_h = 64;
+      let coeffs = [0, 1].iter().map(|_| {
+        let mut c = [0i8; 3];
+        for (i, v) in c.iter_mut().enumerate() {
+          *v = ra.gen_range(WIENER_TAPS_MIN[i], WIENER_TAPS_MAX[i] + 1);
+        }
+        c
+      }).collect::<Vec<_>>();
+      let coeffs = [coeffs[0], coeffs[1]];
+      let mut expected = Plane::wrap(vec![T::cast_from(0u8); w * h], w);
+      let mut actual = Plane::wrap(vec![T::cast_from(0u8); w * h], w);
+      wiener_stripe_filter_rs(coeffs, bit_depth, crop_w, crop_h, stripe_w, stripe_h,
+                              stripe_x, stripe_y, &cdeffed, &deblocked, &mut expected);
+      unsafe {
+        sse4_1::wiener_stripe_filter(coeffs, bit_depth, crop_w, crop_h, stripe_w, stripe_h,
+                                     stripe_x, stripe_y, &cdeffed, &deblocked, &mut actual);
+      }
+      assert_eq!(expected.data, actual.data);
+    }
+  }
+
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  #[test]
+  fn wiener_sse4_1_matches_rust() {
+    if !is_x86_feature_detected!("sse4.1") {
+      return;
+    }
+    check_wiener_sse4_1::<u8>(8);
+    check_wiener_sse4_1::<u16>(10);
+    check_wiener_sse4_1::<u16>(12);
+  }
+}
@@ -12,12 +12,45 @@ pub use self::nasm::*;
 #[cfg(any(not(target_arch = "x86_64"), not(feature = "nasm")))]
 pub use self::native::*;
 
+use crate::frame::*;
 use crate::tiling::*;
-use crate::util::Pixel;
+use crate::util::{round_shift, CastFromPrimitive, Pixel};
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 use std::ops;
 
+const MAX_FRAME_DISTANCE: u32 = 31;
+
+const QUANT_DIST_WEIGHT: [[u32; 2]; 4] =
+  [[2, 3], [2, 5], [2, 7], [1, MAX_FRAME_DISTANCE]];
+const QUANT_DIST_LOOKUP: [[i32; 2]; 4] = [[9, 7], [11, 5], [12, 4], [13, 3]];
+
+/// Weights of the two predictions of a distance-weighted compound block,
+/// given the distances in frames to its two references. The weights sum
+/// to 16.
+pub fn dist_wtd_weights(dist: [u32; 2]) -> [i32; 2] {
+  let d0 = dist[1].min(MAX_FRAME_DISTANCE);
+  let d1 = dist[0].min(MAX_FRAME_DISTANCE);
+  let order = (d0 <= d1) as usize;
+
+  let i = if d0 == 0 || d1 == 0 {
+    3
+  } else {
+    (0..3)
+      .find(|&i| {
+        let c0 = QUANT_DIST_WEIGHT[i][order];
+        let c1 = QUANT_DIST_WEIGHT[i][1 - order];
+        if order == 1 { d0 * c0 > d1 * c1 } else { d0 * c0 < d1 * c1 }
+      })
+      .unwrap_or(3)
+  };
+
+  [QUANT_DIST_LOOKUP[i][order], QUANT_DIST_LOOKUP[i][1 - order]]
+}
+
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct MotionVector {
   pub row: i16,
   pub col: i16
@@ -174,6 +207,166 @@ const SUBPEL_FILTERS: [[[i32; SUBPEL_FILTER_SIZE]; 16]; 6] = [
   ]
 ];
 
+const REF_SCALE_SHIFT: i32 = 14;
+const SUBPEL_BITS: i32 = 4;
+const SCALE_SUBPEL_BITS: i32 = 10;
+
+fn round2signed(value: i64, bit: i32) -> i64 {
+  if value >= 0 {
+    (value + (1 << bit >> 1)) >> bit
+  } else {
+    -((-value + (1 << bit >> 1)) >> bit)
+  }
+}
+
+/// Where the prediction of a block is read from in a reference of another
+/// size than the frame: the position of its top left sample and the
+/// distance between consecutive samples, in 1/1024 sample units
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScaledPosition {
+  pub x: i32,
+  pub y: i32,
+  pub x_step: i32,
+  pub y_step: i32,
+  /// Last column and row of the reference plane, repeated past its edges
+  pub last_x: i32,
+  pub last_y: i32
+}
+
+impl ScaledPosition {
+  /// Scale the block at `po`, in a plane subsampled by `xdec` and `ydec`,
+  /// displaced by `mv`, from a frame of `frame_size` to a reference of
+  /// `ref_size`, both in luma samples. This is the motion vector scaling
+  /// process of the AV1 specification.
+  ///
+  /// The reference may be up to twice as large and 16 times as small as
+  /// the frame in each dimension.
+  pub fn new(
+    po: PlaneOffset, mv: MotionVector, xdec: usize, ydec: usize,
+    frame_size: (usize, usize), ref_size: (usize, usize)
+  ) -> Self {
+    assert!(ref_size.0 <= 2 * frame_size.0 && frame_size.0 <= 16 * ref_size.0);
+    assert!(ref_size.1 <= 2 * frame_size.1 && frame_size.1 <= 16 * ref_size.1);
+    let scale = |ref_len: usize, len: usize| {
+      (((ref_len as i64) << REF_SCALE_SHIFT) + len as i64 / 2) / len as i64
+    };
+    let x_scale = scale(ref_size.0, frame_size.0);
+    let y_scale = scale(ref_size.1, frame_size.1);
+
+    let half_sample = 1i64 << (SUBPEL_BITS - 1);
+    let orig_x = ((po.x as i64) << SUBPEL_BITS)
+      + ((2 * i64::from(mv.col)) >> xdec)
+      + half_sample;
+    let orig_y = ((po.y as i64) << SUBPEL_BITS)
+      + ((2 * i64::from(mv.row)) >> ydec)
+      + half_sample;
+    let base_x = orig_x * x_scale - (half_sample << REF_SCALE_SHIFT);
+    let base_y = orig_y * y_scale - (half_sample << REF_SCALE_SHIFT);
+    let off = (1 << (SCALE_SUBPEL_BITS - SUBPEL_BITS)) / 2;
+    let shift = REF_SCALE_SHIFT + SUBPEL_BITS - SCALE_SUBPEL_BITS;
+
+    ScaledPosition {
+      x: (round2signed(base_x, shift) + off) as i32,
+      y: (round2signed(base_y, shift) + off) as i32,
+      x_step: round2signed(x_scale, REF_SCALE_SHIFT - SCALE_SUBPEL_BITS) as i32,
+      y_step: round2signed(y_scale, REF_SCALE_SHIFT - SCALE_SUBPEL_BITS) as i32,
+      last_x: ((ref_size.0 + xdec) >> xdec) as i32 - 1,
+      last_y: ((ref_size.1 + ydec) >> ydec) as i32 - 1
+    }
+  }
+}
+
+fn scaled_filter(
+  mode: FilterMode, pos: i32, length: usize
+) -> [i32; SUBPEL_FILTER_SIZE] {
+  let filter_idx = if mode == FilterMode::BILINEAR || length > 4 {
+    mode as usize
+  } else {
+    (mode as usize).min(1) + 4
+  };
+  SUBPEL_FILTERS[filter_idx][((pos >> 6) & 15) as usize]
+}
+
+/// Run the subpel filters at the positions of `pos`, passing the sums of the
+/// vertical pass to `out` with the row and column they are for
+fn filter_scaled<T: Pixel>(
+  src: &Plane<T>, pos: ScaledPosition, width: usize, height: usize,
+  mode_x: FilterMode, mode_y: FilterMode, bit_depth: usize,
+  mut out: impl FnMut(usize, usize, i32)
+) {
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let intermediate_height = ((((height as i32 - 1) * pos.y_step
+    + (1 << SCALE_SUBPEL_BITS)
+    - 1)
+    >> SCALE_SUBPEL_BITS)
+    + 8) as usize;
+  let mut intermediate = vec![0i32; intermediate_height * width];
+
+  for r in 0..intermediate_height {
+    let y = ((pos.y >> SCALE_SUBPEL_BITS) + r as i32 - 3)
+      .max(0)
+      .min(pos.last_y) as usize;
+    for c in 0..width {
+      let p = pos.x + pos.x_step * c as i32;
+      let x_filter = scaled_filter(mode_x, p, width);
+      let sum: i32 = x_filter
+        .iter()
+        .enumerate()
+        .map(|(t, &f)| {
+          let x = ((p >> SCALE_SUBPEL_BITS) + t as i32 - 3)
+            .max(0)
+            .min(pos.last_x) as usize;
+          f * i32::cast_from(src.p(x, y))
+        })
+        .sum();
+      intermediate[r * width + c] = round_shift(sum, 7 - intermediate_bits);
+    }
+  }
+
+  for r in 0..height {
+    let p = (pos.y & ((1 << SCALE_SUBPEL_BITS) - 1)) + pos.y_step * r as i32;
+    let y_filter = scaled_filter(mode_y, p, height);
+    let base = (p >> SCALE_SUBPEL_BITS) as usize;
+    for c in 0..width {
+      let sum = y_filter
+        .iter()
+        .enumerate()
+        .map(|(t, &f)| f * intermediate[(base + t) * width + c])
+        .sum();
+      out(r, c, sum);
+    }
+  }
+}
+
+/// `put_8tap` from a reference of another size than the frame
+pub fn put_8tap_scaled<T: Pixel>(
+  dst: &mut PlaneRegionMut<'_, T>, src: &Plane<T>, pos: ScaledPosition,
+  width: usize, height: usize, mode_x: FilterMode, mode_y: FilterMode,
+  bit_depth: usize
+) {
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  filter_scaled(
+    src, pos, width, height, mode_x, mode_y, bit_depth, |r, c, sum| {
+      dst[r][c] = T::cast_from(
+        round_shift(sum, 7 + intermediate_bits).max(0).min(max_sample_val)
+      );
+    }
+  );
+}
+
+/// `prep_8tap` from a reference of another size than the frame
+pub fn prep_8tap_scaled<T: Pixel>(
+  tmp: &mut [i16], src: &Plane<T>, pos: ScaledPosition, width: usize,
+  height: usize, mode_x: FilterMode, mode_y: FilterMode, bit_depth: usize
+) {
+  filter_scaled(
+    src, pos, width, height, mode_x, mode_y, bit_depth, |r, c, sum| {
+      tmp[r * width + c] = round_shift(sum, 7) as i16;
+    }
+  );
+}
+
 #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
 mod nasm {
   use super::*;
@@ -363,6 +556,15 @@ mod nasm {
     }
     super::native::mc_avg(dst, tmp1, tmp2, width, height, bit_depth);
   }
+
+  pub fn mc_dist_wtd<T: Pixel>(
+    dst: &mut PlaneRegionMut<'_, T>, tmp1: &[i16], tmp2: &[i16], width: usize,
+    height: usize, weights: [i32; 2], bit_depth: usize
+  ) {
+    super::native::mc_dist_wtd(
+      dst, tmp1, tmp2, width, height, weights, bit_depth
+    );
+  }
 }
 
 mod native {
@@ -399,6 +601,36 @@ mod native {
     dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, width: usize,
     height: usize, col_frac: i32, row_frac: i32, mode_x: FilterMode,
     mode_y: FilterMode, bit_depth: usize
+  ) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      if width % 16 == 0 && is_x86_feature_detected!("avx2") {
+        return unsafe {
+          avx2::put_8tap(
+            dst, src, width, height, col_frac, row_frac, mode_x, mode_y,
+            bit_depth
+          )
+        };
+      }
+      if width % 4 == 0 && is_x86_feature_detected!("sse4.1") {
+        return unsafe {
+          sse4_1::put_8tap(
+            dst, src, width, height, col_frac, row_frac, mode_x, mode_y,
+            bit_depth
+          )
+        };
+      }
+    }
+
+    put_8tap_rs(
+      dst, src, width, height, col_frac, row_frac, mode_x, mode_y, bit_depth
+    );
+  }
+
+  fn put_8tap_rs<T: Pixel>(
+    dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, width: usize,
+    height: usize, col_frac: i32, row_frac: i32, mode_x: FilterMode,
+    mode_y: FilterMode, bit_depth: usize
   ) {
     let ref_stride = src.plane.cfg.stride;
     let y_filter = get_filter(mode_y, row_frac, height);
@@ -487,6 +719,36 @@ mod native {
     tmp: &mut [i16], src: PlaneSlice<'_, T>, width: usize, height: usize,
     col_frac: i32, row_frac: i32, mode_x: FilterMode, mode_y: FilterMode,
     bit_depth: usize
+  ) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      if width % 16 == 0 && is_x86_feature_detected!("avx2") {
+        return unsafe {
+          avx2::prep_8tap(
+            tmp, src, width, height, col_frac, row_frac, mode_x, mode_y,
+            bit_depth
+          )
+        };
+      }
+      if width % 4 == 0 && is_x86_feature_detected!("sse4.1") {
+        return unsafe {
+          sse4_1::prep_8tap(
+            tmp, src, width, height, col_frac, row_frac, mode_x, mode_y,
+            bit_depth
+          )
+        };
+      }
+    }
+
+    prep_8tap_rs(
+      tmp, src, width, height, col_frac, row_frac, mode_x, mode_y, bit_depth
+    );
+  }
+
+  fn prep_8tap_rs<T: Pixel>(
+    tmp: &mut [i16], src: PlaneSlice<'_, T>, width: usize, height: usize,
+    col_frac: i32, row_frac: i32, mode_x: FilterMode, mode_y: FilterMode,
+    bit_depth: usize
   ) {
     let ref_stride = src.plane.cfg.stride;
     let y_filter = get_filter(mode_y, row_frac, height);
@@ -578,4 +840,960 @@ mod native {
       }
     }
   }
+
+  pub fn mc_dist_wtd<T: Pixel>(
+    dst: &mut PlaneRegionMut<'_, T>, tmp1: &[i16], tmp2: &[i16], width: usize,
+    height: usize, weights: [i32; 2], bit_depth: usize
+  ) {
+    let max_sample_val = ((1 << bit_depth) - 1) as i32;
+    let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+    for r in 0..height {
+      let dst_slice = &mut dst[r];
+      for c in 0..width {
+        dst_slice[c] = T::cast_from(round_shift(
+          i32::from(tmp1[r * width + c]) * weights[0]
+            + i32::from(tmp2[r * width + c]) * weights[1],
+          intermediate_bits + 4
+        )
+        .max(0)
+        .min(max_sample_val));
+      }
+    }
+  }
+
+  /// The subpel filters on 8 columns at a time, or 4 for 4-pixel-wide
+  /// blocks, at every bit depth.
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  mod sse4_1 {
+    use super::*;
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+    use std::mem::size_of;
+
+    // 8 pixels widened to i16
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn load8<T: Pixel>(src: *const T) -> __m128i {
+      if size_of::<T>() == 1 {
+        _mm_cvtepu8_epi16(_mm_loadl_epi64(src as *const _))
+      } else {
+        _mm_loadu_si128(src as *const _)
+      }
+    }
+
+    // 4 pixels widened to i16, in the low half
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn load4<T: Pixel>(src: *const T) -> __m128i {
+      if size_of::<T>() == 1 {
+        _mm_cvtepu8_epi16(_mm_cvtsi32_si128((src as *const i32).read_unaligned()))
+      } else {
+        _mm_loadl_epi64(src as *const _)
+      }
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn h_taps(filter: [i32; 8]) -> __m128i {
+      _mm_setr_epi16(
+        filter[0] as i16, filter[1] as i16, filter[2] as i16,
+        filter[3] as i16, filter[4] as i16, filter[5] as i16,
+        filter[6] as i16, filter[7] as i16
+      )
+    }
+
+    // Pairs of taps, to multiply rows interleaved two by two
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn v_taps(filter: [i32; 8]) -> [__m128i; 4] {
+      let pair = |k: usize| (filter[k + 1] << 16) | (filter[k] & 0xffff);
+      [
+        _mm_set1_epi32(pair(0)),
+        _mm_set1_epi32(pair(2)),
+        _mm_set1_epi32(pair(4)),
+        _mm_set1_epi32(pair(6))
+      ]
+    }
+
+    // `src` points 3 pixels left of the first of the 8 output columns
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn filter_h8<T: Pixel>(src: *const T, taps: __m128i) -> [__m128i; 2] {
+      let mut m = [_mm_setzero_si128(); 8];
+      for (i, v) in m.iter_mut().enumerate() {
+        *v = _mm_madd_epi16(load8(src.add(i)), taps);
+      }
+      [
+        _mm_hadd_epi32(_mm_hadd_epi32(m[0], m[1]), _mm_hadd_epi32(m[2], m[3])),
+        _mm_hadd_epi32(_mm_hadd_epi32(m[4], m[5]), _mm_hadd_epi32(m[6], m[7]))
+      ]
+    }
+
+    // Same as `filter_h8`, for 4 output columns
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn filter_h4<T: Pixel>(src: *const T, taps: __m128i) -> __m128i {
+      let mut m = [_mm_setzero_si128(); 4];
+      for (i, v) in m.iter_mut().enumerate() {
+        *v = _mm_madd_epi16(load8(src.add(i)), taps);
+      }
+      _mm_hadd_epi32(_mm_hadd_epi32(m[0], m[1]), _mm_hadd_epi32(m[2], m[3]))
+    }
+
+    // `rows` starts 3 rows above the output row
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn filter_v8(rows: &[__m128i], taps: &[__m128i; 4]) -> [__m128i; 2] {
+      let mut lo = _mm_setzero_si128();
+      let mut hi = _mm_setzero_si128();
+      for (k, &t) in taps.iter().enumerate() {
+        let (a, b) = (rows[2 * k], rows[2 * k + 1]);
+        lo = _mm_add_epi32(lo, _mm_madd_epi16(_mm_unpacklo_epi16(a, b), t));
+        hi = _mm_add_epi32(hi, _mm_madd_epi16(_mm_unpackhi_epi16(a, b), t));
+      }
+      [lo, hi]
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn load_v8<T: Pixel>(src: *const T, stride: usize) -> [__m128i; 8] {
+      let mut rows = [_mm_setzero_si128(); 8];
+      for (k, v) in rows.iter_mut().enumerate() {
+        *v = load8(src.add(k * stride));
+      }
+      rows
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn load_v4<T: Pixel>(src: *const T, stride: usize) -> [__m128i; 8] {
+      let mut rows = [_mm_setzero_si128(); 8];
+      for (k, v) in rows.iter_mut().enumerate() {
+        *v = load4(src.add(k * stride));
+      }
+      rows
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn round_shift4(v: __m128i, bit: i32) -> __m128i {
+      let rounding = _mm_set1_epi32(1 << bit >> 1);
+      _mm_sra_epi32(_mm_add_epi32(v, rounding), _mm_cvtsi32_si128(bit))
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn round_shift8(v: [__m128i; 2], bit: i32) -> [__m128i; 2] {
+      let rounding = _mm_set1_epi32(1 << bit >> 1);
+      let shift = _mm_cvtsi32_si128(bit);
+      [
+        _mm_sra_epi32(_mm_add_epi32(v[0], rounding), shift),
+        _mm_sra_epi32(_mm_add_epi32(v[1], rounding), shift)
+      ]
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn store8<T: Pixel>(dst: *mut T, v: [__m128i; 2], max: __m128i) {
+      if size_of::<T>() == 1 {
+        let packed = _mm_packs_epi32(v[0], v[1]);
+        _mm_storel_epi64(dst as *mut _, _mm_packus_epi16(packed, packed));
+      } else {
+        let packed = _mm_packus_epi32(v[0], v[1]);
+        _mm_storeu_si128(dst as *mut _, _mm_min_epu16(packed, max));
+      }
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn store8_i16(dst: *mut i16, v: [__m128i; 2]) {
+      _mm_storeu_si128(dst as *mut _, _mm_packs_epi32(v[0], v[1]));
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn store4<T: Pixel>(dst: *mut T, v: __m128i, max: __m128i) {
+      if size_of::<T>() == 1 {
+        let packed = _mm_packs_epi32(v, v);
+        let packed = _mm_packus_epi16(packed, packed);
+        (dst as *mut i32).write_unaligned(_mm_cvtsi128_si32(packed));
+      } else {
+        let packed = _mm_packus_epi32(v, v);
+        _mm_storel_epi64(dst as *mut _, _mm_min_epu16(packed, max));
+      }
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn store4_i16(dst: *mut i16, v: __m128i) {
+      _mm_storel_epi64(dst as *mut _, _mm_packs_epi32(v, v));
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub(super) unsafe fn put_8tap<T: Pixel>(
+      dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, width: usize,
+      height: usize, col_frac: i32, row_frac: i32, mode_x: FilterMode,
+      mode_y: FilterMode, bit_depth: usize
+    ) {
+      if width == 4 {
+        return put_8tap_w4(
+          dst, src, height, col_frac, row_frac, mode_x, mode_y, bit_depth
+        );
+      }
+      let ref_stride = src.plane.cfg.stride;
+      let y_filter = get_filter(mode_y, row_frac, height);
+      let x_filter = get_filter(mode_x, col_frac, width);
+      let max = _mm_set1_epi16(((1 << bit_depth) - 1) as i16);
+      let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+      match (col_frac, row_frac) {
+        (0, 0) => {
+          for r in 0..height {
+            dst[r][..width].copy_from_slice(&src[r][..width]);
+          }
+        }
+        (0, _) => {
+          let offset_slice = src.go_up(3);
+          let taps = v_taps(y_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            let dst_row = dst[r][..width].as_mut_ptr();
+            for c in (0..width).step_by(8) {
+              let rows = load_v8(src_row.add(c), ref_stride);
+              store8(dst_row.add(c), round_shift8(filter_v8(&rows, &taps), 7), max);
+            }
+          }
+        }
+        (_, 0) => {
+          let offset_slice = src.go_left(3);
+          let taps = h_taps(x_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            let dst_row = dst[r][..width].as_mut_ptr();
+            for c in (0..width).step_by(8) {
+              let v = round_shift8(filter_h8(src_row.add(c), taps), 7 - intermediate_bits);
+              store8(dst_row.add(c), round_shift8(v, intermediate_bits), max);
+            }
+          }
+        }
+        (_, _) => {
+          let mut intermediate = [_mm_setzero_si128(); 128 + 7];
+          let h = h_taps(x_filter);
+          let v = v_taps(y_filter);
+
+          let offset_slice = src.go_left(3).go_up(3);
+          for cg in (0..width).step_by(8) {
+            for r in 0..height + 7 {
+              let t = round_shift8(
+                filter_h8(offset_slice[r][cg..].as_ptr(), h), 7 - intermediate_bits
+              );
+              intermediate[r] = _mm_packs_epi32(t[0], t[1]);
+            }
+
+            for r in 0..height {
+              let t = round_shift8(
+                filter_v8(&intermediate[r..], &v), 7 + intermediate_bits
+              );
+              store8(dst[r][cg..cg + 8].as_mut_ptr(), t, max);
+            }
+          }
+        }
+      }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub(super) unsafe fn prep_8tap<T: Pixel>(
+      tmp: &mut [i16], src: PlaneSlice<'_, T>, width: usize, height: usize,
+      col_frac: i32, row_frac: i32, mode_x: FilterMode, mode_y: FilterMode,
+      bit_depth: usize
+    ) {
+      if width == 4 {
+        return prep_8tap_w4(
+          tmp, src, height, col_frac, row_frac, mode_x, mode_y, bit_depth
+        );
+      }
+      let ref_stride = src.plane.cfg.stride;
+      let y_filter = get_filter(mode_y, row_frac, height);
+      let x_filter = get_filter(mode_x, col_frac, width);
+      let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+      let tmp = &mut tmp[..width * height];
+      match (col_frac, row_frac) {
+        (0, 0) => {
+          let shift = _mm_cvtsi32_si128(intermediate_bits);
+          for r in 0..height {
+            let src_row = src[r][..width].as_ptr();
+            for c in (0..width).step_by(8) {
+              let v = _mm_sll_epi16(load8(src_row.add(c)), shift);
+              _mm_storeu_si128(tmp[r * width + c..].as_mut_ptr() as *mut _, v);
+            }
+          }
+        }
+        (0, _) => {
+          let offset_slice = src.go_up(3);
+          let taps = v_taps(y_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            for c in (0..width).step_by(8) {
+              let rows = load_v8(src_row.add(c), ref_stride);
+              let v = round_shift8(filter_v8(&rows, &taps), 7 - intermediate_bits);
+              store8_i16(tmp[r * width + c..].as_mut_ptr(), v);
+            }
+          }
+        }
+        (_, 0) => {
+          let offset_slice = src.go_left(3);
+          let taps = h_taps(x_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            for c in (0..width).step_by(8) {
+              let v = round_shift8(filter_h8(src_row.add(c), taps), 7 - intermediate_bits);
+              store8_i16(tmp[r * width + c..].as_mut_ptr(), v);
+            }
+          }
+        }
+        (_, _) => {
+          let mut intermediate = [_mm_setzero_si128(); 128 + 7];
+          let h = h_taps(x_filter);
+          let v = v_taps(y_filter);
+
+          let offset_slice = src.go_left(3).go_up(3);
+          for cg in (0..width).step_by(8) {
+            for r in 0..height + 7 {
+              let t = round_shift8(
+                filter_h8(offset_slice[r][cg..].as_ptr(), h), 7 - intermediate_bits
+              );
+              intermediate[r] = _mm_packs_epi32(t[0], t[1]);
+            }
+
+            for r in 0..height {
+              let t = round_shift8(filter_v8(&intermediate[r..], &v), 7);
+              store8_i16(tmp[r * width + cg..].as_mut_ptr(), t);
+            }
+          }
+        }
+      }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn put_8tap_w4<T: Pixel>(
+      dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, height: usize,
+      col_frac: i32, row_frac: i32, mode_x: FilterMode, mode_y: FilterMode,
+      bit_depth: usize
+    ) {
+      let ref_stride = src.plane.cfg.stride;
+      let y_filter = get_filter(mode_y, row_frac, height);
+      let x_filter = get_filter(mode_x, col_frac, 4);
+      let max = _mm_set1_epi16(((1 << bit_depth) - 1) as i16);
+      let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+      match (col_frac, row_frac) {
+        (0, 0) => {
+          for r in 0..height {
+            dst[r][..4].copy_from_slice(&src[r][..4]);
+          }
+        }
+        (0, _) => {
+          let offset_slice = src.go_up(3);
+          let taps = v_taps(y_filter);
+          for r in 0..height {
+            let rows = load_v4(offset_slice[r].as_ptr(), ref_stride);
+            let v = round_shift4(filter_v8(&rows, &taps)[0], 7);
+            store4(dst[r][..4].as_mut_ptr(), v, max);
+          }
+        }
+        (_, 0) => {
+          let offset_slice = src.go_left(3);
+          let taps = h_taps(x_filter);
+          for r in 0..height {
+            let v = round_shift4(
+              filter_h4(offset_slice[r].as_ptr(), taps), 7 - intermediate_bits
+            );
+            store4(dst[r][..4].as_mut_ptr(), round_shift4(v, intermediate_bits), max);
+          }
+        }
+        (_, _) => {
+          let mut intermediate = [_mm_setzero_si128(); 128 + 7];
+          let h = h_taps(x_filter);
+          let v = v_taps(y_filter);
+
+          let offset_slice = src.go_left(3).go_up(3);
+          for r in 0..height + 7 {
+            let t = round_shift4(
+              filter_h4(offset_slice[r].as_ptr(), h), 7 - intermediate_bits
+            );
+            intermediate[r] = _mm_packs_epi32(t, t);
+          }
+
+          for r in 0..height {
+            let t = round_shift4(
+              filter_v8(&intermediate[r..], &v)[0], 7 + intermediate_bits
+            );
+            store4(dst[r][..4].as_mut_ptr(), t, max);
+          }
+        }
+      }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn prep_8tap_w4<T: Pixel>(
+      tmp: &mut [i16], src: PlaneSlice<'_, T>, height: usize, col_frac: i32,
+      row_frac: i32, mode_x: FilterMode, mode_y: FilterMode, bit_depth: usize
+    ) {
+      let ref_stride = src.plane.cfg.stride;
+      let y_filter = get_filter(mode_y, row_frac, height);
+      let x_filter = get_filter(mode_x, col_frac, 4);
+      let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+      let tmp = &mut tmp[..4 * height];
+      match (col_frac, row_frac) {
+        (0, 0) => {
+          let shift = _mm_cvtsi32_si128(intermediate_bits);
+          for r in 0..height {
+            let v = _mm_sll_epi16(load4(src[r][..4].as_ptr()), shift);
+            _mm_storel_epi64(tmp[r * 4..].as_mut_ptr() as *mut _, v);
+          }
+        }
+        (0, _) => {
+          let offset_slice = src.go_up(3);
+          let taps = v_taps(y_filter);
+          for r in 0..height {
+            let rows = load_v4(offset_slice[r].as_ptr(), ref_stride);
+            let v = round_shift4(filter_v8(&rows, &taps)[0], 7 - intermediate_bits);
+            store4_i16(tmp[r * 4..].as_mut_ptr(), v);
+          }
+        }
+        (_, 0) => {
+          let offset_slice = src.go_left(3);
+          let taps = h_taps(x_filter);
+          for r in 0..height {
+            let v = round_shift4(
+              filter_h4(offset_slice[r].as_ptr(), taps), 7 - intermediate_bits
+            );
+            store4_i16(tmp[r * 4..].as_mut_ptr(), v);
+          }
+        }
+        (_, _) => {
+          let mut intermediate = [_mm_setzero_si128(); 128 + 7];
+          let h = h_taps(x_filter);
+          let v = v_taps(y_filter);
+
+          let offset_slice = src.go_left(3).go_up(3);
+          for r in 0..height + 7 {
+            let t = round_shift4(
+              filter_h4(offset_slice[r].as_ptr(), h), 7 - intermediate_bits
+            );
+            intermediate[r] = _mm_packs_epi32(t, t);
+          }
+
+          for r in 0..height {
+            let t = round_shift4(filter_v8(&intermediate[r..], &v)[0], 7);
+            store4_i16(tmp[r * 4..].as_mut_ptr(), t);
+          }
+        }
+      }
+    }
+
+    #[cfg(test)]
+    mod test {
+      use super::*;
+      use crate::frame::Plane;
+      use rand::random;
+
+      fn check_8tap<T: Pixel>(bit_depth: usize) {
+        let mut plane = Plane::<T>::new(64, 64, 0, 0, 16, 16);
+        for v in plane.data_origin_mut().iter_mut() {
+          *v = T::cast_from(random::<u16>() >> (16 - bit_depth));
+        }
+        plane.pad(64, 64);
+        let mut expected = Plane::<T>::new(32, 32, 0, 0, 0, 0);
+        let mut actual = Plane::<T>::new(32, 32, 0, 0, 0, 0);
+        let mut expected_tmp = [0i16; 32 * 32];
+        let mut actual_tmp = [0i16; 32 * 32];
+        let modes = [FilterMode::REGULAR, FilterMode::SMOOTH, FilterMode::SHARP];
+        let src = plane.slice(PlaneOffset { x: 8, y: 8 });
+        for &(width, height) in
+          &[(4, 4), (4, 8), (4, 16), (8, 4), (8, 8), (16, 8), (32, 32)]
+        {
+          for &mode_x in &modes {
+            for &mode_y in &modes {
+              for &(col_frac, row_frac) in &[(0, 0), (0, 5), (9, 0), (3, 14)] {
+                put_8tap_rs(
+                  &mut expected.as_region_mut(), src, width, height, col_frac,
+                  row_frac, mode_x, mode_y, bit_depth
+                );
+                unsafe {
+                  put_8tap(
+                    &mut actual.as_region_mut(), src, width, height, col_frac,
+                    row_frac, mode_x, mode_y, bit_depth
+                  );
+                }
+                for r in 0..height {
+                  assert_eq!(
+                    &expected.as_slice()[r][..width],
+                    &actual.as_slice()[r][..width]
+                  );
+                }
+
+                prep_8tap_rs(
+                  &mut expected_tmp, src, width, height, col_frac, row_frac,
+                  mode_x, mode_y, bit_depth
+                );
+                unsafe {
+                  prep_8tap(
+                    &mut actual_tmp, src, width, height, col_frac, row_frac,
+                    mode_x, mode_y, bit_depth
+                  );
+                }
+                assert_eq!(
+                  &expected_tmp[..width * height],
+                  &actual_tmp[..width * height]
+                );
+              }
+            }
+          }
+        }
+      }
+
+      #[test]
+      fn put_and_prep_8tap_match_rust() {
+        if !is_x86_feature_detected!("sse4.1") {
+          return;
+        }
+        check_8tap::<u8>(8);
+        check_8tap::<u16>(10);
+        check_8tap::<u16>(12);
+      }
+    }
+  }
+
+  /// The subpel filters on 16 columns at a time, for blocks a multiple of
+  /// 16 pixels wide, at every bit depth.
+  ///
+  /// Filtered columns are kept as two vectors of i32, the first holding
+  /// columns 0-3 and 8-11 and the second columns 4-7 and 12-15, which is the
+  /// layout `_mm256_packs_epi32` puts back in order.
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  mod avx2 {
+    use super::*;
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+    use std::mem::size_of;
+
+    // 16 pixels widened to i16
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load16<T: Pixel>(src: *const T) -> __m256i {
+      if size_of::<T>() == 1 {
+        _mm256_cvtepu8_epi16(_mm_loadu_si128(src as *const _))
+      } else {
+        _mm256_loadu_si256(src as *const _)
+      }
+    }
+
+    // The 8 taps, in both 128-bit lanes
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn h_taps(filter: [i32; 8]) -> __m256i {
+      let t = |k: usize| filter[k] as i16;
+      _mm256_setr_epi16(
+        t(0), t(1), t(2), t(3), t(4), t(5), t(6), t(7),
+        t(0), t(1), t(2), t(3), t(4), t(5), t(6), t(7)
+      )
+    }
+
+    // Pairs of taps, to multiply rows interleaved two by two
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn v_taps(filter: [i32; 8]) -> [__m256i; 4] {
+      let pair = |k: usize| (filter[k + 1] << 16) | (filter[k] & 0xffff);
+      [
+        _mm256_set1_epi32(pair(0)),
+        _mm256_set1_epi32(pair(2)),
+        _mm256_set1_epi32(pair(4)),
+        _mm256_set1_epi32(pair(6))
+      ]
+    }
+
+    // `src` points 3 pixels left of the first of the 16 output columns
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn filter_h16<T: Pixel>(src: *const T, taps: __m256i) -> [__m256i; 2] {
+      let mut m = [_mm256_setzero_si256(); 8];
+      for (i, v) in m.iter_mut().enumerate() {
+        *v = _mm256_madd_epi16(load16(src.add(i)), taps);
+      }
+      [
+        _mm256_hadd_epi32(
+          _mm256_hadd_epi32(m[0], m[1]),
+          _mm256_hadd_epi32(m[2], m[3])
+        ),
+        _mm256_hadd_epi32(
+          _mm256_hadd_epi32(m[4], m[5]),
+          _mm256_hadd_epi32(m[6], m[7])
+        )
+      ]
+    }
+
+    // `rows` starts 3 rows above the output row
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn filter_v16(rows: &[__m256i], taps: &[__m256i; 4]) -> [__m256i; 2] {
+      let mut lo = _mm256_setzero_si256();
+      let mut hi = _mm256_setzero_si256();
+      for (k, &t) in taps.iter().enumerate() {
+        let (a, b) = (rows[2 * k], rows[2 * k + 1]);
+        lo = _mm256_add_epi32(lo, _mm256_madd_epi16(_mm256_unpacklo_epi16(a, b), t));
+        hi = _mm256_add_epi32(hi, _mm256_madd_epi16(_mm256_unpackhi_epi16(a, b), t));
+      }
+      [lo, hi]
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load_v16<T: Pixel>(src: *const T, stride: usize) -> [__m256i; 8] {
+      let mut rows = [_mm256_setzero_si256(); 8];
+      for (k, v) in rows.iter_mut().enumerate() {
+        *v = load16(src.add(k * stride));
+      }
+      rows
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn round_shift16(v: [__m256i; 2], bit: i32) -> [__m256i; 2] {
+      let rounding = _mm256_set1_epi32(1 << bit >> 1);
+      let shift = _mm_cvtsi32_si128(bit);
+      [
+        _mm256_sra_epi32(_mm256_add_epi32(v[0], rounding), shift),
+        _mm256_sra_epi32(_mm256_add_epi32(v[1], rounding), shift)
+      ]
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn store16<T: Pixel>(dst: *mut T, v: [__m256i; 2], max: __m256i) {
+      if size_of::<T>() == 1 {
+        let packed = _mm256_packs_epi32(v[0], v[1]);
+        let packed = _mm256_packus_epi16(packed, packed);
+        let packed = _mm256_permute4x64_epi64(packed, 0b11_01_10_00);
+        _mm_storeu_si128(dst as *mut _, _mm256_castsi256_si128(packed));
+      } else {
+        let packed = _mm256_packus_epi32(v[0], v[1]);
+        _mm256_storeu_si256(dst as *mut _, _mm256_min_epu16(packed, max));
+      }
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn store16_i16(dst: *mut i16, v: [__m256i; 2]) {
+      _mm256_storeu_si256(dst as *mut _, _mm256_packs_epi32(v[0], v[1]));
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn put_8tap<T: Pixel>(
+      dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, width: usize,
+      height: usize, col_frac: i32, row_frac: i32, mode_x: FilterMode,
+      mode_y: FilterMode, bit_depth: usize
+    ) {
+      let ref_stride = src.plane.cfg.stride;
+      let y_filter = get_filter(mode_y, row_frac, height);
+      let x_filter = get_filter(mode_x, col_frac, width);
+      let max = _mm256_set1_epi16(((1 << bit_depth) - 1) as i16);
+      let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+      match (col_frac, row_frac) {
+        (0, 0) => {
+          for r in 0..height {
+            dst[r][..width].copy_from_slice(&src[r][..width]);
+          }
+        }
+        (0, _) => {
+          let offset_slice = src.go_up(3);
+          let taps = v_taps(y_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            let dst_row = dst[r][..width].as_mut_ptr();
+            for c in (0..width).step_by(16) {
+              let rows = load_v16(src_row.add(c), ref_stride);
+              store16(dst_row.add(c), round_shift16(filter_v16(&rows, &taps), 7), max);
+            }
+          }
+        }
+        (_, 0) => {
+          let offset_slice = src.go_left(3);
+          let taps = h_taps(x_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            let dst_row = dst[r][..width].as_mut_ptr();
+            for c in (0..width).step_by(16) {
+              let v = round_shift16(filter_h16(src_row.add(c), taps), 7 - intermediate_bits);
+              store16(dst_row.add(c), round_shift16(v, intermediate_bits), max);
+            }
+          }
+        }
+        (_, _) => {
+          let mut intermediate = [_mm256_setzero_si256(); 128 + 7];
+          let h = h_taps(x_filter);
+          let v = v_taps(y_filter);
+
+          let offset_slice = src.go_left(3).go_up(3);
+          for cg in (0..width).step_by(16) {
+            for r in 0..height + 7 {
+              let t = round_shift16(
+                filter_h16(offset_slice[r][cg..].as_ptr(), h), 7 - intermediate_bits
+              );
+              intermediate[r] = _mm256_packs_epi32(t[0], t[1]);
+            }
+
+            for r in 0..height {
+              let t = round_shift16(
+                filter_v16(&intermediate[r..], &v), 7 + intermediate_bits
+              );
+              store16(dst[r][cg..cg + 16].as_mut_ptr(), t, max);
+            }
+          }
+        }
+      }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn prep_8tap<T: Pixel>(
+      tmp: &mut [i16], src: PlaneSlice<'_, T>, width: usize, height: usize,
+      col_frac: i32, row_frac: i32, mode_x: FilterMode, mode_y: FilterMode,
+      bit_depth: usize
+    ) {
+      let ref_stride = src.plane.cfg.stride;
+      let y_filter = get_filter(mode_y, row_frac, height);
+      let x_filter = get_filter(mode_x, col_frac, width);
+      let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+      let tmp = &mut tmp[..width * height];
+      match (col_frac, row_frac) {
+        (0, 0) => {
+          let shift = _mm_cvtsi32_si128(intermediate_bits);
+          for r in 0..height {
+            let src_row = src[r][..width].as_ptr();
+            for c in (0..width).step_by(16) {
+              let v = _mm256_sll_epi16(load16(src_row.add(c)), shift);
+              _mm256_storeu_si256(tmp[r * width + c..].as_mut_ptr() as *mut _, v);
+            }
+          }
+        }
+        (0, _) => {
+          let offset_slice = src.go_up(3);
+          let taps = v_taps(y_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            for c in (0..width).step_by(16) {
+              let rows = load_v16(src_row.add(c), ref_stride);
+              let v = round_shift16(filter_v16(&rows, &taps), 7 - intermediate_bits);
+              store16_i16(tmp[r * width + c..].as_mut_ptr(), v);
+            }
+          }
+        }
+        (_, 0) => {
+          let offset_slice = src.go_left(3);
+          let taps = h_taps(x_filter);
+          for r in 0..height {
+            let src_row = offset_slice[r].as_ptr();
+            for c in (0..width).step_by(16) {
+              let v = round_shift16(filter_h16(src_row.add(c), taps), 7 - intermediate_bits);
+              store16_i16(tmp[r * width + c..].as_mut_ptr(), v);
+            }
+          }
+        }
+        (_, _) => {
+          let mut intermediate = [_mm256_setzero_si256(); 128 + 7];
+          let h = h_taps(x_filter);
+          let v = v_taps(y_filter);
+
+          let offset_slice = src.go_left(3).go_up(3);
+          for cg in (0..width).step_by(16) {
+            for r in 0..height + 7 {
+              let t = round_shift16(
+                filter_h16(offset_slice[r][cg..].as_ptr(), h), 7 - intermediate_bits
+              );
+              intermediate[r] = _mm256_packs_epi32(t[0], t[1]);
+            }
+
+            for r in 0..height {
+              let t = round_shift16(filter_v16(&intermediate[r..], &v), 7);
+              store16_i16(tmp[r * width + cg..].as_mut_ptr(), t);
+            }
+          }
+        }
+      }
+    }
+
+    #[cfg(test)]
+    mod test {
+      use super::*;
+      use crate::frame::Plane;
+      use rand::random;
+
+      fn check_8tap<T: Pixel>(bit_depth: usize) {
+        let mut plane = Plane::<T>::new(64, 64, 0, 0, 16, 16);
+        for v in plane.data_origin_mut().iter_mut() {
+          *v = T::cast_from(random::<u16>() >> (16 - bit_depth));
+        }
+        plane.pad(64, 64);
+        let mut expected = Plane::<T>::new(32, 32, 0, 0, 0, 0);
+        let mut actual = Plane::<T>::new(32, 32, 0, 0, 0, 0);
+        let mut expected_tmp = [0i16; 32 * 32];
+        let mut actual_tmp = [0i16; 32 * 32];
+        let modes = [FilterMode::REGULAR, FilterMode::SMOOTH, FilterMode::SHARP];
+        let src = plane.slice(PlaneOffset { x: 8, y: 8 });
+        for &(width, height) in &[(16, 4), (16, 16), (32, 8), (32, 32)] {
+          for &mode_x in &modes {
+            for &mode_y in &modes {
+              for &(col_frac, row_frac) in &[(0, 0), (0, 5), (9, 0), (3, 14)] {
+                put_8tap_rs(
+                  &mut expected.as_region_mut(), src, width, height, col_frac,
+                  row_frac, mode_x, mode_y, bit_depth
+                );
+                unsafe {
+                  put_8tap(
+                    &mut actual.as_region_mut(), src, width, height, col_frac,
+                    row_frac, mode_x, mode_y, bit_depth
+                  );
+                }
+                for r in 0..height {
+                  assert_eq!(
+                    &expected.as_slice()[r][..width],
+                    &actual.as_slice()[r][..width]
+                  );
+                }
+
+                prep_8tap_rs(
+                  &mut expected_tmp, src, width, height, col_frac, row_frac,
+                  mode_x, mode_y, bit_depth
+                );
+                unsafe {
+                  prep_8tap(
+                    &mut actual_tmp, src, width, height, col_frac, row_frac,
+                    mode_x, mode_y, bit_depth
+                  );
+                }
+                assert_eq!(
+                  &expected_tmp[..width * height],
+                  &actual_tmp[..width * height]
+                );
+              }
+            }
+          }
+        }
+      }
+
+      #[test]
+      fn put_and_prep_8tap_match_rust() {
+        if !is_x86_feature_detected!("avx2") {
+          return;
+        }
+        check_8tap::<u8>(8);
+        check_8tap::<u16>(10);
+        check_8tap::<u16>(12);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+
+  #[test]
+  fn scaled_matches_unscaled_at_same_size() {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let mut plane = Plane::<u8>::new(64, 64, 0, 0, 48, 48);
+    for v in plane.data_origin_mut().iter_mut() {
+      *v = ra.gen();
+    }
+    plane.pad(64, 64);
+    let mut expected = Plane::<u8>::new(16, 16, 0, 0, 0, 0);
+    let mut actual = Plane::<u8>::new(16, 16, 0, 0, 0, 0);
+    let mut expected_tmp = [0i16; 16 * 16];
+    let mut actual_tmp = [0i16; 16 * 16];
+
+    for _ in 0..32 {
+      let po = PlaneOffset { x: ra.gen_range(0, 48), y: ra.gen_range(0, 48) };
+      let mv = MotionVector { row: ra.gen_range(-64, 64), col: ra.gen_range(-64, 64) };
+      let (width, height) = [(4, 4), (8, 4), (16, 16)][ra.gen_range(0, 3)];
+      let pos = ScaledPosition::new(po, mv, 0, 0, (64, 64), (64, 64));
+      assert_eq!((pos.x_step, pos.y_step), (1024, 1024));
+
+      let row_offset = mv.row as isize >> 3;
+      let col_offset = mv.col as isize >> 3;
+      let row_frac = (mv.row as i32 & 7) << 1;
+      let col_frac = (mv.col as i32 & 7) << 1;
+      let src = plane.slice(PlaneOffset {
+        x: po.x + col_offset - 3,
+        y: po.y + row_offset - 3
+      }).clamp().subslice(3, 3);
+
+      put_8tap(
+        &mut expected.as_region_mut(), src, width, height, col_frac, row_frac,
+        FilterMode::REGULAR, FilterMode::REGULAR, 8
+      );
+      put_8tap_scaled(
+        &mut actual.as_region_mut(), &plane, pos, width, height,
+        FilterMode::REGULAR, FilterMode::REGULAR, 8
+      );
+      for r in 0..height {
+        assert_eq!(
+          &expected.as_slice()[r][..width],
+          &actual.as_slice()[r][..width]
+        );
+      }
+
+      prep_8tap(
+        &mut expected_tmp, src, width, height, col_frac, row_frac,
+        FilterMode::REGULAR, FilterMode::REGULAR, 8
+      );
+      prep_8tap_scaled(
+        &mut actual_tmp, &plane, pos, width, height,
+        FilterMode::REGULAR, FilterMode::REGULAR, 8
+      );
+      assert_eq!(&expected_tmp[..width * height], &actual_tmp[..width * height]);
+    }
+  }
+
+  #[test]
+  fn scaled_position_steps() {
+    // A reference twice as large is read every other sample
+    let pos = ScaledPosition::new(
+      PlaneOffset { x: 8, y: 4 }, MotionVector::default(), 0, 0,
+      (64, 32), (128, 64)
+    );
+    assert_eq!((pos.x_step, pos.y_step), (2048, 2048));
+    assert_eq!((pos.last_x, pos.last_y), (127, 63));
+    // The center of the sample at 8 maps to the one between 16 and 17
+    assert_eq!(pos.x >> 6, (16 << 4) + 8);
+
+    let pos = ScaledPosition::new(
+      PlaneOffset { x: 0, y: 0 }, MotionVector::default(), 1, 1,
+      (64, 64), (32, 32)
+    );
+    assert_eq!((pos.x_step, pos.y_step), (512, 512));
+    assert_eq!((pos.last_x, pos.last_y), (15, 15));
+  }
+
+  #[test]
+  fn dist_wtd_weights_sum_to_16() {
+    for d0 in 0..40 {
+      for d1 in 0..40 {
+        let w = dist_wtd_weights([d0, d1]);
+        assert_eq!(w[0] + w[1], 16);
+      }
+    }
+  }
+
+  #[test]
+  fn dist_wtd_weights_follow_distances() {
+    assert_eq!(dist_wtd_weights([1, 1]), [7, 9]);
+    assert_eq!(dist_wtd_weights([1, 2]), [11, 5]);
+    assert_eq!(dist_wtd_weights([2, 1]), [5, 11]);
+    assert_eq!(dist_wtd_weights([1, 3]), [12, 4]);
+    assert_eq!(dist_wtd_weights([1, 16]), [13, 3]);
+    assert_eq!(dist_wtd_weights([0, 3]), [13, 3]);
+  }
 }
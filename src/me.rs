@@ -11,18 +11,22 @@
 pub use self::nasm::get_sad;
 #[cfg(any(not(target_arch = "x86_64"), not(feature = "nasm")))]
 pub use self::native::get_sad;
-use crate::context::{BlockOffset, BLOCK_TO_PLANE_SHIFT, MI_SIZE};
-use crate::encoder::ReferenceFrame;
+use crate::context::{BlockOffset, MvRates, BLOCK_TO_PLANE_SHIFT, MI_SIZE};
+use crate::dist::DistortionMetric;
+use crate::ec::OD_BITRES;
+use crate::encoder::{ReferenceFrame, MV_FILTER_MARGIN};
 use crate::FrameInvariants;
 use crate::mc::MotionVector;
 use crate::partition::*;
 use crate::partition::RefType::*;
-use crate::predict::PredictionMode;
+use crate::predict::{CompoundParams, PredictionMode};
 use crate::frame::*;
 use crate::tiling::*;
-use crate::util::Pixel;
+use crate::util::{CastFromPrimitive, Pixel};
 
 use arrayvec::*;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 use std::ops::{Index, IndexMut};
 use std::sync::Arc;
@@ -256,6 +260,23 @@ mod native {
 
   #[inline(always)]
   pub fn get_sad<T: Pixel>(
+    plane_org: &PlaneRegion<'_, T>,
+    plane_ref: &PlaneRegion<'_, T>,
+    blk_w: usize,
+    blk_h: usize,
+    bit_depth: usize,
+  ) -> u32 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      if is_x86_feature_detected!("sse2") {
+        return unsafe { sse2::get_sad(plane_org, plane_ref, blk_w, blk_h) };
+      }
+    }
+
+    get_sad_rs(plane_org, plane_ref, blk_w, blk_h, bit_depth)
+  }
+
+  fn get_sad_rs<T: Pixel>(
     plane_org: &PlaneRegion<'_, T>,
     plane_ref: &PlaneRegion<'_, T>,
     blk_w: usize,
@@ -275,9 +296,208 @@ mod native {
 
     sum
   }
+
+  /// The SAD of any block at any bit depth, for builds without the assembly,
+  /// 16 pixels at a time for 8-bit and 8 at a time for high bit depth.
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  mod sse2 {
+    use super::*;
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+    use std::mem::size_of;
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn row_sad_u8(org: &[u8], rf: &[u8]) -> u32 {
+      let mut acc = _mm_setzero_si128();
+      let mut c = 0;
+      while c + 16 <= org.len() {
+        let a = _mm_loadu_si128(org[c..].as_ptr() as *const _);
+        let b = _mm_loadu_si128(rf[c..].as_ptr() as *const _);
+        acc = _mm_add_epi64(acc, _mm_sad_epu8(a, b));
+        c += 16;
+      }
+      if c + 8 <= org.len() {
+        let a = _mm_loadl_epi64(org[c..].as_ptr() as *const _);
+        let b = _mm_loadl_epi64(rf[c..].as_ptr() as *const _);
+        acc = _mm_add_epi64(acc, _mm_sad_epu8(a, b));
+        c += 8;
+      }
+      // Each half of the accumulator holds less than 2^32
+      let mut sum = _mm_cvtsi128_si32(acc) as u32
+        + _mm_cvtsi128_si32(_mm_srli_si128(acc, 8)) as u32;
+      for (&a, &b) in org[c..].iter().zip(&rf[c..]) {
+        sum += (i32::from(a) - i32::from(b)).abs() as u32;
+      }
+      sum
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn row_sad_u16(org: &[u16], rf: &[u16]) -> u32 {
+      let ones = _mm_set1_epi16(1);
+      let mut acc = _mm_setzero_si128();
+      let mut c = 0;
+      while c + 8 <= org.len() {
+        let a = _mm_loadu_si128(org[c..].as_ptr() as *const _);
+        let b = _mm_loadu_si128(rf[c..].as_ptr() as *const _);
+        // Pixels have at most 12 bits, so their differences fit in an i16
+        let diff = _mm_sub_epi16(a, b);
+        let abs = _mm_max_epi16(diff, _mm_sub_epi16(_mm_setzero_si128(), diff));
+        acc = _mm_add_epi32(acc, _mm_madd_epi16(abs, ones));
+        c += 8;
+      }
+      acc = _mm_add_epi32(acc, _mm_srli_si128(acc, 8));
+      acc = _mm_add_epi32(acc, _mm_srli_si128(acc, 4));
+      let mut sum = _mm_cvtsi128_si32(acc) as u32;
+      for (&a, &b) in org[c..].iter().zip(&rf[c..]) {
+        sum += (i32::from(a) - i32::from(b)).abs() as u32;
+      }
+      sum
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn get_sad<T: Pixel>(
+      plane_org: &PlaneRegion<'_, T>,
+      plane_ref: &PlaneRegion<'_, T>,
+      blk_w: usize,
+      blk_h: usize,
+    ) -> u32 {
+      let mut sum = 0;
+      for (slice_org, slice_ref) in
+        plane_org.rows_iter().take(blk_h).zip(plane_ref.rows_iter())
+      {
+        let (org, rf) = (&slice_org[..blk_w], &slice_ref[..blk_w]);
+        sum += if size_of::<T>() == 1 {
+          row_sad_u8(
+            &*(org as *const [T] as *const [u8]),
+            &*(rf as *const [T] as *const [u8]),
+          )
+        } else {
+          row_sad_u16(
+            &*(org as *const [T] as *const [u16]),
+            &*(rf as *const [T] as *const [u16]),
+          )
+        };
+      }
+      sum
+    }
+
+    #[cfg(test)]
+    mod test {
+      use super::*;
+      use crate::frame::Plane;
+      use rand::random;
+
+      fn check_sad<T: Pixel>(bit_depth: usize) {
+        let mut org = Plane::<T>::new(160, 128, 0, 0, 0, 0);
+        let mut rf = Plane::<T>::new(160, 128, 0, 0, 0, 0);
+        for p in [&mut org, &mut rf].iter_mut() {
+          for v in p.data.iter_mut() {
+            *v = T::cast_from(random::<u16>() >> (16 - bit_depth));
+          }
+        }
+        for &(w, h) in
+          &[(4, 4), (8, 4), (12, 8), (16, 16), (24, 8), (64, 32), (128, 128)]
+        {
+          for &x in &[0, 1, 7, 24] {
+            let area = Area::StartingAt { x, y: 0 };
+            let (org, rf) = (org.region(area), rf.region(area));
+            assert_eq!(
+              get_sad_rs(&org, &rf, w, h, bit_depth),
+              unsafe { get_sad(&org, &rf, w, h) }
+            );
+          }
+        }
+      }
+
+      #[test]
+      fn get_sad_sse2_matches_rust() {
+        if !is_x86_feature_detected!("sse2") {
+          return;
+        }
+        check_sad::<u8>(8);
+        check_sad::<u16>(10);
+        check_sad::<u16>(12);
+      }
+    }
+  }
+}
+
+fn hadamard4x4(data: &mut [i32; 16]) {
+  for i in 0..4 {
+    let (a0, a1, a2, a3) = (data[4 * i], data[4 * i + 1], data[4 * i + 2], data[4 * i + 3]);
+    let (b0, b1, b2, b3) = (a0 + a1, a0 - a1, a2 + a3, a2 - a3);
+    data[4 * i] = b0 + b2;
+    data[4 * i + 1] = b1 + b3;
+    data[4 * i + 2] = b0 - b2;
+    data[4 * i + 3] = b1 - b3;
+  }
+  for i in 0..4 {
+    let (a0, a1, a2, a3) = (data[i], data[4 + i], data[8 + i], data[12 + i]);
+    let (b0, b1, b2, b3) = (a0 + a1, a0 - a1, a2 + a3, a2 - a3);
+    data[i] = b0 + b2;
+    data[4 + i] = b1 + b3;
+    data[8 + i] = b0 - b2;
+    data[12 + i] = b1 - b3;
+  }
+}
+
+/// Sum of absolute differences after a 4x4 Hadamard transform of the
+/// residual, halved so that it stays on the scale of the SAD. It tracks
+/// the coding cost of the residual more closely than the SAD does.
+pub fn get_satd<T: Pixel>(
+  plane_org: &PlaneRegion<'_, T>, plane_ref: &PlaneRegion<'_, T>,
+  blk_w: usize, blk_h: usize
+) -> u32 {
+  assert!(blk_w % 4 == 0 && blk_h % 4 == 0);
+  let mut sum = 0;
+
+  for r in (0..blk_h).step_by(4) {
+    for c in (0..blk_w).step_by(4) {
+      let mut diff = [0i32; 16];
+      for i in 0..4 {
+        let (org, rf) = (&plane_org[r + i][c..c + 4], &plane_ref[r + i][c..c + 4]);
+        for j in 0..4 {
+          diff[4 * i + j] = i32::cast_from(org[j]) - i32::cast_from(rf[j]);
+        }
+      }
+      hadamard4x4(&mut diff);
+      sum += diff.iter().map(|&v| v.abs() as u32).sum::<u32>();
+    }
+  }
+
+  sum >> 1
+}
+
+/// Sum of the absolute AC coefficients of a 4x4 Hadamard transform of the
+/// block, halved like the SATD. It measures the texture energy of the block
+/// regardless of the position of its details.
+pub fn get_ac_energy<T: Pixel>(
+  plane: &PlaneRegion<'_, T>, blk_w: usize, blk_h: usize
+) -> u32 {
+  assert!(blk_w % 4 == 0 && blk_h % 4 == 0);
+  let mut sum = 0;
+
+  for r in (0..blk_h).step_by(4) {
+    for c in (0..blk_w).step_by(4) {
+      let mut data = [0i32; 16];
+      for i in 0..4 {
+        let row = &plane[r + i][c..c + 4];
+        for j in 0..4 {
+          data[4 * i + j] = i32::cast_from(row[j]);
+        }
+      }
+      hadamard4x4(&mut data);
+      sum += data[1..].iter().map(|&v| v.abs() as u32).sum::<u32>();
+    }
+  }
+
+  sum >> 1
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FrameMotionVectors {
   mvs: Box<[MotionVector]>,
   pub cols: usize,
@@ -319,15 +539,42 @@ impl IndexMut<usize> for FrameMotionVectors {
   }
 }
 
-fn get_mv_range(
-  w_in_b: usize, h_in_b: usize, bo: BlockOffset, blk_w: usize, blk_h: usize
+/// Range of the motion vectors of the block at `bo` in the frame, which
+/// may point past the edges of the frame up to a border. With
+/// `tile_constrained_mvs`, they stay inside the tile of `ts` on the sides
+/// where other tiles lie.
+pub(crate) fn get_mv_range<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, bo: BlockOffset,
+  blk_w: usize, blk_h: usize
 ) -> (isize, isize, isize, isize) {
   let border_w = 128 + blk_w as isize * 8;
   let border_h = 128 + blk_h as isize * 8;
-  let mvx_min = -(bo.x as isize) * (8 * MI_SIZE) as isize - border_w;
-  let mvx_max = (w_in_b - bo.x - blk_w / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_w;
-  let mvy_min = -(bo.y as isize) * (8 * MI_SIZE) as isize - border_h;
-  let mvy_max = (h_in_b - bo.y - blk_h / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_h;
+  let mut mvx_min = -(bo.x as isize) * (8 * MI_SIZE) as isize - border_w;
+  let mut mvx_max = (fi.w_in_b - bo.x - blk_w / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_w;
+  let mut mvy_min = -(bo.y as isize) * (8 * MI_SIZE) as isize - border_h;
+  let mut mvy_max = (fi.h_in_b - bo.y - blk_h / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_h;
+
+  if fi.config.tile_constrained_mvs {
+    // In 1/8 pixel units, like the motion vectors
+    let rect = ts.tile_rect();
+    let margin = MV_FILTER_MARGIN as isize;
+    let x = (bo.x * MI_SIZE) as isize;
+    let y = (bo.y * MI_SIZE) as isize;
+    if rect.x > 0 {
+      mvx_min = mvx_min.max((rect.x as isize + margin - x) * 8);
+    }
+    if rect.x + rect.width < fi.width {
+      let right = (rect.x + rect.width) as isize - margin;
+      mvx_max = mvx_max.min((right - x - blk_w as isize) * 8);
+    }
+    if rect.y > 0 {
+      mvy_min = mvy_min.max((rect.y as isize + margin - y) * 8);
+    }
+    if rect.y + rect.height < fi.height {
+      let bottom = (rect.y + rect.height) as isize - margin;
+      mvy_max = mvy_max.min((bottom - y - blk_h as isize) * 8);
+    }
+  }
 
   (mvx_min, mvx_max, mvy_min, mvy_max)
 }
@@ -438,7 +685,7 @@ pub trait MotionEstimation {
         let blk_h = bsize.height();
         let frame_bo = ts.to_frame_block_offset(tile_bo);
         let (mvx_min, mvx_max, mvy_min, mvy_max) =
-          get_mv_range(fi.w_in_b, fi.h_in_b, frame_bo, blk_w, blk_h);
+          get_mv_range(fi, ts, frame_bo, blk_w, blk_h);
 
         // 0.5 is a fudge factor
         let lambda = (fi.me_lambda * 256.0 * 0.5) as u32;
@@ -452,9 +699,22 @@ pub trait MotionEstimation {
                            mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h,
                            &mut best_mv, &mut lowest_cost, ref_frame);
 
-        Self::sub_pixel_me(fi, ts, rec, tile_bo, lambda, pmv,
-                           mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h,
-                           &mut best_mv, &mut lowest_cost, ref_frame);
+        if fi.allow_screen_content_tools != 0 {
+          // Screen content moves by whole pixels, if at all, so skip the
+          // sub-pixel search and keep the zero vector unless beaten
+          let zero_cost = get_mv_rd_cost(
+            fi, frame_bo.to_luma_plane_offset(), &ts.input.planes[0],
+            &rec.frame.planes[0], fi.sequence.bit_depth, pmv, lambda,
+            &ts.mv_rates, mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h,
+            MotionVector::default(), &mut None, None, ref_frame);
+          if zero_cost <= lowest_cost {
+            best_mv = MotionVector::default();
+          }
+        } else {
+          Self::sub_pixel_me(fi, ts, rec, tile_bo, lambda, pmv,
+                             mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h,
+                             &mut best_mv, &mut lowest_cost, ref_frame);
+        }
 
         best_mv
       }
@@ -472,7 +732,7 @@ pub trait MotionEstimation {
       let blk_h = bsize.height();
       let tile_bo_adj = adjust_bo(tile_bo, ts.mi_width, ts.mi_height, blk_w, blk_h);
       let frame_bo_adj = ts.to_frame_block_offset(tile_bo_adj);
-      let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(fi.w_in_b, fi.h_in_b, frame_bo_adj, blk_w, blk_h);
+      let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(fi, ts, frame_bo_adj, blk_w, blk_h);
 
       let global_mv = [MotionVector{row: 0, col: 0}; 2];
       let tile_mvs = &ts.mvs[ref_frame].as_const();
@@ -534,6 +794,7 @@ impl MotionEstimation for DiamondSearch {
       fi.sequence.bit_depth,
       pmv,
       lambda,
+      &ts.mv_rates,
       mvx_min,
       mvx_max,
       mvy_min,
@@ -543,6 +804,7 @@ impl MotionEstimation for DiamondSearch {
       best_mv,
       lowest_cost,
       false,
+      None,
       ref_frame
     );
   }
@@ -566,6 +828,7 @@ impl MotionEstimation for DiamondSearch {
       fi.sequence.bit_depth,
       pmv,
       lambda,
+      &ts.mv_rates,
       mvx_min,
       mvx_max,
       mvy_min,
@@ -575,6 +838,7 @@ impl MotionEstimation for DiamondSearch {
       best_mv,
       lowest_cost,
       true,
+      if fi.config.chroma_me { Some(ts.input) } else { None },
       ref_frame
     );
   }
@@ -610,11 +874,11 @@ impl MotionEstimation for DiamondSearch {
           fi, frame_po,
           &ts.input_hres, &rec.input_hres,
           &predictors, fi.sequence.bit_depth,
-          global_mv, lambda,
+          global_mv, lambda, &ts.mv_rates,
           mvx_min >> 1, mvx_max >> 1, mvy_min >> 1, mvy_max >> 1,
           blk_w >> 1, blk_h >> 1,
           best_mv, lowest_cost,
-          false, LAST_FRAME
+          false, None, LAST_FRAME
         );
       }
     }
@@ -655,9 +919,10 @@ impl MotionEstimation for FullSearch {
       frame_po,
       2,
       fi.sequence.bit_depth,
+      &fi.config.me_metric(),
       lambda,
       pmv,
-      fi.allow_high_precision_mv
+      &ts.mv_rates
     );
   }
 
@@ -723,32 +988,158 @@ impl MotionEstimation for FullSearch {
           frame_po,
           1,
           fi.sequence.bit_depth,
+          &fi.config.me_metric(),
           lambda,
           [MotionVector::default(); 2],
-          fi.allow_high_precision_mv
+          &ts.mv_rates
         );
       }
     }
   }
 }
 
+/// Extra pixels predicted on each side of the block by `SubpelCache`
+const SUBPEL_CACHE_MARGIN: usize = 4;
+
+/// Luma predictions of a block made during the sub-pixel search, one per
+/// fractional phase of the motion vector. Each is made over the block
+/// extended by `SUBPEL_CACHE_MARGIN` pixels on each side, so that the
+/// candidates with the same phase whose integer parts are at most that far
+/// apart are read from it instead of being interpolated again.
+///
+/// The extended predictions are made as wide as the next power of two, the
+/// only widths the assembly filters handle.
+pub(crate) struct SubpelCache<T: Pixel> {
+  blk_w: usize,
+  blk_h: usize,
+  /// Integer part of the motion vector each extended prediction was made
+  /// with, by phase
+  entries: Vec<Option<(isize, isize, Plane<T>)>>,
+  /// Blocks whose prediction can't be taken from an extended one
+  tmp_plane: Plane<T>
+}
+
+impl<T: Pixel> SubpelCache<T> {
+  pub(crate) fn new(blk_w: usize, blk_h: usize) -> Self {
+    SubpelCache {
+      blk_w,
+      blk_h,
+      entries: (0..64).map(|_| None).collect(),
+      tmp_plane: Plane::new(blk_w, blk_h, 0, 0, 0, 0)
+    }
+  }
+
+  /// Size of the extended predictions
+  fn extended_size(&self) -> (usize, usize) {
+    (
+      (self.blk_w + 2 * SUBPEL_CACHE_MARGIN).next_power_of_two(),
+      self.blk_h + 2 * SUBPEL_CACHE_MARGIN
+    )
+  }
+
+  // The extended prediction matches the one of the block if both use the
+  // 8-tap filters, and if neither reference position gets clamped to the
+  // padding of the reference plane.
+  fn can_extend(
+    &self, fi: &FrameInvariants<T>, po: PlaneOffset, ref_frame: RefType,
+    row: isize, col: isize
+  ) -> bool {
+    if self.blk_w < 16 || self.blk_h < 16 || self.blk_w > 64 || self.blk_h > 64 {
+      return false;
+    }
+    let idx = fi.ref_frames[ref_frame.to_index()] as usize;
+    let cfg = match fi.rec_buffer.frames[idx] {
+      Some(ref rec) => &rec.frame.planes[0].cfg,
+      None => return false
+    };
+    let m = SUBPEL_CACHE_MARGIN as isize;
+    let (w, h) = self.extended_size();
+    let x = po.x + col - m - 3;
+    let y = po.y + row - m - 3;
+    x >= -(cfg.xorigin as isize)
+      && y >= -(cfg.yorigin as isize)
+      && x + 2 * m <= cfg.width as isize
+      && y + 2 * m <= cfg.height as isize
+      && x + w as isize + 7 <= (cfg.width + cfg.xpad) as isize
+      && y + h as isize + 7 <= (cfg.height + cfg.ypad) as isize
+  }
+
+  /// Luma prediction of the block at `po` from `ref_frame` with `mv`
+  pub(crate) fn predict(
+    &mut self, fi: &FrameInvariants<T>, po: PlaneOffset, ref_frame: RefType,
+    mv: MotionVector
+  ) -> PlaneRegion<'_, T> {
+    let m = SUBPEL_CACHE_MARGIN as isize;
+    let phase = ((mv.row & 7) * 8 + (mv.col & 7)) as usize;
+    let row = (mv.row >> 3) as isize;
+    let col = (mv.col >> 3) as isize;
+
+    let hit = match self.entries[phase] {
+      Some((r, c, _)) => (row - r).abs() <= m && (col - c).abs() <= m,
+      None => false
+    };
+    if !hit {
+      if !self.can_extend(fi, po, ref_frame, row, col) {
+        predict_luma(fi, po, &mut self.tmp_plane, self.blk_w, self.blk_h, ref_frame, mv);
+        return self.tmp_plane.as_region();
+      }
+      let (w, h) = self.extended_size();
+      let mut plane = match self.entries[phase].take() {
+        Some((_, _, plane)) => plane,
+        None => Plane::new(w, h, 0, 0, 0, 0)
+      };
+      let ext_po = PlaneOffset { x: po.x - m, y: po.y - m };
+      predict_luma(fi, ext_po, &mut plane, w, h, ref_frame, mv);
+      self.entries[phase] = Some((row, col, plane));
+    }
+
+    match self.entries[phase] {
+      Some((r, c, ref plane)) => plane.region(Area::StartingAt {
+        x: m + col - c,
+        y: m + row - r
+      }),
+      None => unreachable!()
+    }
+  }
+}
+
+fn predict_luma<T: Pixel>(
+  fi: &FrameInvariants<T>, po: PlaneOffset, dst: &mut Plane<T>,
+  w: usize, h: usize, ref_frame: RefType, mv: MotionVector
+) {
+  let tile_rect = TileRect { x: 0, y: 0, width: w, height: h };
+  PredictionMode::NEWMV.predict_inter(
+    fi,
+    tile_rect,
+    0,
+    po,
+    &mut dst.as_region_mut(),
+    w,
+    h,
+    [ref_frame, NONE_FRAME],
+    [mv, MotionVector { row: 0, col: 0 }],
+    CompoundParams::default()
+  );
+}
+
 fn get_best_predictor<T: Pixel>(
   fi: &FrameInvariants<T>,
   po: PlaneOffset, p_org: &Plane<T>, p_ref: &Plane<T>,
   predictors: &[MotionVector],
-  bit_depth: usize, pmv: [MotionVector; 2], lambda: u32,
+  bit_depth: usize, pmv: [MotionVector; 2], lambda: u32, mv_rates: &MvRates,
   mvx_min: isize, mvx_max: isize, mvy_min: isize, mvy_max: isize,
   blk_w: usize, blk_h: usize,
   center_mv: &mut MotionVector, center_mv_cost: &mut u64,
-  tmp_plane_opt: &mut Option<Plane<T>>, ref_frame: RefType) {
+  subpel_cache: &mut Option<SubpelCache<T>>, chroma_org: Option<&Frame<T>>,
+  ref_frame: RefType) {
   *center_mv = MotionVector::default();
   *center_mv_cost = std::u64::MAX;
 
   for &init_mv in predictors.iter() {
     let cost = get_mv_rd_cost(
       fi, po, p_org, p_ref, bit_depth,
-      pmv, lambda, mvx_min, mvx_max, mvy_min, mvy_max,
-      blk_w, blk_h, init_mv, tmp_plane_opt, ref_frame);
+      pmv, lambda, mv_rates, mvx_min, mvx_max, mvy_min, mvy_max,
+      blk_w, blk_h, init_mv, subpel_cache, chroma_org, ref_frame);
 
     if cost < *center_mv_cost {
       *center_mv = init_mv;
@@ -757,24 +1148,24 @@ fn get_best_predictor<T: Pixel>(
   }
 }
 
-fn diamond_me_search<T: Pixel>(
+pub fn diamond_me_search<T: Pixel>(
   fi: &FrameInvariants<T>,
   po: PlaneOffset, p_org: &Plane<T>, p_ref: &Plane<T>,
   predictors: &[MotionVector],
-  bit_depth: usize, pmv: [MotionVector; 2], lambda: u32,
+  bit_depth: usize, pmv: [MotionVector; 2], lambda: u32, mv_rates: &MvRates,
   mvx_min: isize, mvx_max: isize, mvy_min: isize, mvy_max: isize,
   blk_w: usize, blk_h: usize,
   center_mv: &mut MotionVector, center_mv_cost: &mut u64,
-  subpixel: bool, ref_frame: RefType)
+  subpixel: bool, chroma_org: Option<&Frame<T>>, ref_frame: RefType)
 {
   let diamond_pattern = [(1i16, 0i16), (0, 1), (-1, 0), (0, -1)];
-  let (mut diamond_radius, diamond_radius_end, mut tmp_plane_opt) = {
+  let (mut diamond_radius, diamond_radius_end, mut subpel_cache) = {
     if subpixel {
       // Sub-pixel motion estimation
       (
         4i16,
         if fi.allow_high_precision_mv {1i16} else {2i16},
-        Some(Plane::new(blk_w, blk_h, 0, 0, 0, 0)),
+        Some(SubpelCache::new(blk_w, blk_h)),
       )
     } else {
       // Full pixel motion estimation
@@ -784,9 +1175,9 @@ fn diamond_me_search<T: Pixel>(
 
   get_best_predictor(
     fi, po, p_org, p_ref, &predictors,
-    bit_depth, pmv, lambda, mvx_min, mvx_max, mvy_min, mvy_max,
+    bit_depth, pmv, lambda, mv_rates, mvx_min, mvx_max, mvy_min, mvy_max,
     blk_w, blk_h, center_mv, center_mv_cost,
-    &mut tmp_plane_opt, ref_frame);
+    &mut subpel_cache, chroma_org, ref_frame);
 
   loop {
     let mut best_diamond_rd_cost = std::u64::MAX;
@@ -801,8 +1192,8 @@ fn diamond_me_search<T: Pixel>(
 
         let rd_cost = get_mv_rd_cost(
           fi, po, p_org, p_ref, bit_depth,
-          pmv, lambda, mvx_min, mvx_max, mvy_min, mvy_max,
-          blk_w, blk_h, cand_mv, &mut tmp_plane_opt, ref_frame);
+          pmv, lambda, mv_rates, mvx_min, mvx_max, mvy_min, mvy_max,
+          blk_w, blk_h, cand_mv, &mut subpel_cache, chroma_org, ref_frame);
 
         if rd_cost < best_diamond_rd_cost {
           best_diamond_rd_cost = rd_cost;
@@ -829,11 +1220,11 @@ fn diamond_me_search<T: Pixel>(
 fn get_mv_rd_cost<T: Pixel>(
   fi: &FrameInvariants<T>,
   po: PlaneOffset, p_org: &Plane<T>, p_ref: &Plane<T>, bit_depth: usize,
-  pmv: [MotionVector; 2], lambda: u32,
+  pmv: [MotionVector; 2], lambda: u32, mv_rates: &MvRates,
   mvx_min: isize, mvx_max: isize, mvy_min: isize, mvy_max: isize,
   blk_w: usize, blk_h: usize,
-  cand_mv: MotionVector, tmp_plane_opt: &mut Option<Plane<T>>,
-  ref_frame: RefType) -> u64
+  cand_mv: MotionVector, subpel_cache: &mut Option<SubpelCache<T>>,
+  chroma_org: Option<&Frame<T>>, ref_frame: RefType) -> u64
 {
   if (cand_mv.col as isize) < mvx_min || (cand_mv.col as isize) > mvx_max {
     return std::u64::MAX;
@@ -844,30 +1235,15 @@ fn get_mv_rd_cost<T: Pixel>(
 
   let plane_org = p_org.region(Area::StartingAt { x: po.x, y: po.y });
 
-  if let Some(ref mut tmp_plane) = tmp_plane_opt {
-    let tile_rect = TileRect {
-      x: 0,
-      y: 0,
-      width: tmp_plane.cfg.width,
-      height: tmp_plane.cfg.height
-    };
-
-    PredictionMode::NEWMV.predict_inter(
-      fi,
-      tile_rect,
-      0,
-      po,
-      &mut tmp_plane.as_region_mut(),
-      blk_w,
-      blk_h,
-      [ref_frame, NONE_FRAME],
-      [cand_mv, MotionVector { row: 0, col: 0 }]
-    );
-    let plane_ref = tmp_plane.as_region();
+  if let Some(ref mut subpel_cache) = subpel_cache {
+    let plane_ref = subpel_cache.predict(fi, po, ref_frame, cand_mv);
+    let chroma_sad = chroma_org.map_or(0, |org| {
+      get_chroma_sad(fi, org, po, blk_w, blk_h, ref_frame, cand_mv)
+    });
     compute_mv_rd_cost(
-      fi, pmv, lambda, bit_depth, blk_w, blk_h, cand_mv,
+      &fi.config.me_metric(), pmv, lambda, mv_rates, bit_depth, blk_w, blk_h, cand_mv,
       &plane_org, &plane_ref
-    )
+    ) + 256 * chroma_sad
   } else {
     // Full pixel motion vector
     let plane_ref = p_ref.region(Area::StartingAt {
@@ -875,26 +1251,58 @@ fn get_mv_rd_cost<T: Pixel>(
       y: po.y + (cand_mv.row / 8) as isize
     });
     compute_mv_rd_cost(
-      fi, pmv, lambda, bit_depth, blk_w, blk_h, cand_mv,
+      &fi.config.me_metric(), pmv, lambda, mv_rates, bit_depth, blk_w, blk_h, cand_mv,
       &plane_org, &plane_ref
     )
   }
 }
 
-fn compute_mv_rd_cost<T: Pixel>(
-  fi: &FrameInvariants<T>,
-  pmv: [MotionVector; 2], lambda: u32,
+fn compute_mv_rd_cost<T: Pixel, D: DistortionMetric>(
+  metric: &D, pmv: [MotionVector; 2], lambda: u32, mv_rates: &MvRates,
   bit_depth: usize, blk_w: usize, blk_h: usize, cand_mv: MotionVector,
   plane_org: &PlaneRegion<'_, T>, plane_ref: &PlaneRegion<'_, T>
 ) -> u64
 {
-  let sad = get_sad(&plane_org, &plane_ref, blk_w, blk_h, bit_depth);
+  let dist = metric.distortion(&plane_org, &plane_ref, blk_w, blk_h, bit_depth);
+
+  256 * dist + get_mv_cost(mv_rates, cand_mv, pmv, lambda)
+}
 
-  let rate1 = get_mv_rate(cand_mv, pmv[0], fi.allow_high_precision_mv);
-  let rate2 = get_mv_rate(cand_mv, pmv[1], fi.allow_high_precision_mv);
-  let rate = rate1.min(rate2 + 1);
+// SAD of the chroma planes of a block predicted with a motion vector,
+//  weighted relative to the luma SAD like the chroma distortion in the RDO
+fn get_chroma_sad<T: Pixel>(
+  fi: &FrameInvariants<T>, org: &Frame<T>, po: PlaneOffset,
+  blk_w: usize, blk_h: usize, ref_frame: RefType, cand_mv: MotionVector
+) -> u64 {
+  let PlaneConfig { xdec, ydec, .. } = org.planes[1].cfg;
+  let (w, h) = (blk_w >> xdec, blk_h >> ydec);
+  if w < 4 || h < 4 {
+    return 0;
+  }
+  let po = PlaneOffset { x: po.x >> xdec, y: po.y >> ydec };
+  let mut tmp_plane = Plane::new(w, h, 0, 0, 0, 0);
+  let tile_rect = TileRect { x: 0, y: 0, width: w, height: h };
 
-  256 * sad as u64 + rate as u64 * lambda as u64
+  let mut sad = 0;
+  for p in 1..3 {
+    PredictionMode::NEWMV.predict_inter(
+      fi,
+      tile_rect,
+      p,
+      po,
+      &mut tmp_plane.as_region_mut(),
+      w,
+      h,
+      [ref_frame, NONE_FRAME],
+      [cand_mv, MotionVector { row: 0, col: 0 }],
+      CompoundParams::default()
+    );
+    let plane_org = org.planes[p].region(Area::StartingAt { x: po.x, y: po.y });
+    sad += get_sad(
+      &plane_org, &tmp_plane.as_region(), w, h, fi.sequence.bit_depth
+    ) as u64;
+  }
+  sad * fi.config.chroma_distortion_weight as u64 / 8
 }
 
 fn telescopic_subpel_search<T: Pixel>(
@@ -904,20 +1312,19 @@ fn telescopic_subpel_search<T: Pixel>(
   blk_w: usize, blk_h: usize,
   best_mv: &mut MotionVector, lowest_cost: &mut u64
 ) {
-  let mode = PredictionMode::NEWMV;
-
   let mut steps = vec![8, 4, 2];
   if fi.allow_high_precision_mv {
     steps.push(1);
   }
 
-  let mut tmp_plane = Plane::new(blk_w, blk_h, 0, 0, 0, 0);
-  let tile_rect = TileRect {
-    x: 0,
-    y: 0,
-    width: tmp_plane.cfg.width,
-    height: tmp_plane.cfg.height
-  };
+  let mut subpel_cache = SubpelCache::new(blk_w, blk_h);
+
+  // The full-pixel cost of the best vector is luma only
+  if fi.config.chroma_me {
+    *lowest_cost = lowest_cost.saturating_add(
+      256 * get_chroma_sad(fi, ts.input, po, blk_w, blk_h, ref_frame, *best_mv)
+    );
+  }
 
   for step in steps {
     let center_mv_h = *best_mv;
@@ -940,29 +1347,17 @@ fn telescopic_subpel_search<T: Pixel>(
           continue;
         }
 
-        {
-          mode.predict_inter(
-            fi,
-            tile_rect,
-            0,
-            po,
-            &mut tmp_plane.as_region_mut(),
-            blk_w,
-            blk_h,
-            [ref_frame, NONE_FRAME],
-            [cand_mv, MotionVector { row: 0, col: 0 }]
-          );
-        }
-
         let plane_org = ts.input.planes[0].region(Area::StartingAt { x: po.x, y: po.y });
-        let plane_ref = tmp_plane.as_region();
+        let plane_ref = subpel_cache.predict(fi, po, ref_frame, cand_mv);
 
         let sad = get_sad(&plane_org, &plane_ref, blk_w, blk_h, fi.sequence.bit_depth);
 
-        let rate1 = get_mv_rate(cand_mv, pmv[0], fi.allow_high_precision_mv);
-        let rate2 = get_mv_rate(cand_mv, pmv[1], fi.allow_high_precision_mv);
-        let rate = rate1.min(rate2 + 1);
-        let cost = 256 * sad as u64 + rate as u64 * lambda as u64;
+        let mut cost = 256 * sad as u64 + get_mv_cost(&ts.mv_rates, cand_mv, pmv, lambda);
+        if fi.config.chroma_me {
+          cost += 256 * get_chroma_sad(
+            fi, ts.input, po, blk_w, blk_h, ref_frame, cand_mv
+          );
+        }
 
         if cost < *lowest_cost {
           *lowest_cost = cost;
@@ -973,11 +1368,11 @@ fn telescopic_subpel_search<T: Pixel>(
   }
 }
 
-fn full_search<T: Pixel>(
+fn full_search<T: Pixel, D: DistortionMetric>(
   x_lo: isize, x_hi: isize, y_lo: isize, y_hi: isize, blk_h: usize,
   blk_w: usize, p_org: &Plane<T>, p_ref: &Plane<T>, best_mv: &mut MotionVector,
   lowest_cost: &mut u64, po: PlaneOffset, step: usize, bit_depth: usize,
-  lambda: u32, pmv: [MotionVector; 2], allow_high_precision_mv: bool
+  metric: &D, lambda: u32, pmv: [MotionVector; 2], mv_rates: &MvRates
 ) {
     let search_range_y = (y_lo..=y_hi).step_by(step);
     let search_range_x = (x_lo..=x_hi).step_by(step);
@@ -986,17 +1381,14 @@ fn full_search<T: Pixel>(
     let (cost, mv) = search_area.map(|(y, x)| {
       let plane_org = p_org.region(Area::StartingAt { x: po.x, y: po.y });
       let plane_ref = p_ref.region(Area::StartingAt { x, y });
-      let sad = get_sad(&plane_org, &plane_ref, blk_w, blk_h, bit_depth);
+      let dist = metric.distortion(&plane_org, &plane_ref, blk_w, blk_h, bit_depth);
 
       let mv = MotionVector {
         row: 8 * (y as i16 - po.y as i16),
         col: 8 * (x as i16 - po.x as i16)
       };
 
-      let rate1 = get_mv_rate(mv, pmv[0], allow_high_precision_mv);
-      let rate2 = get_mv_rate(mv, pmv[1], allow_high_precision_mv);
-      let rate = rate1.min(rate2 + 1);
-      let cost = 256 * sad as u64 + rate as u64 * lambda as u64;
+      let cost = 256 * dist + get_mv_cost(mv_rates, mv, pmv, lambda);
 
       (cost, mv)
   }).min_by_key(|(c, _)| *c).unwrap();
@@ -1013,25 +1405,23 @@ fn adjust_bo(bo: BlockOffset, mi_width: usize, mi_height: usize, blk_w: usize, b
   }
 }
 
+// Lambda-weighted rate of a motion vector coded against the cheaper of two
+// predictors, where picking the second one costs another bit
 #[inline(always)]
-fn get_mv_rate(a: MotionVector, b: MotionVector, allow_high_precision_mv: bool) -> u32 {
-  #[inline(always)]
-  fn diff_to_rate(diff: i16, allow_high_precision_mv: bool) -> u32 {
-    let d = if allow_high_precision_mv { diff } else { diff >> 1 };
-    if d == 0 {
-      0
-    } else {
-      2 * (16 - d.abs().leading_zeros())
-    }
-  }
-
-  diff_to_rate(a.row - b.row, allow_high_precision_mv) + diff_to_rate(a.col - b.col, allow_high_precision_mv)
+fn get_mv_cost(
+  mv_rates: &MvRates, mv: MotionVector, pmv: [MotionVector; 2], lambda: u32
+) -> u64 {
+  let rate1 = mv_rates.rate(mv, pmv[0]);
+  let rate2 = mv_rates.rate(mv, pmv[1]) + (1 << OD_BITRES);
+  (rate1.min(rate2) as u64 * lambda as u64) >> OD_BITRES
 }
 
+/// Searches the quarter resolution reference for the motion of a block,
+/// returning its motion vector and cost
 pub fn estimate_motion_ss4<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, bsize: BlockSize, ref_idx: usize,
   tile_bo: BlockOffset
-) -> Option<MotionVector> {
+) -> Option<(MotionVector, u64)> {
   if let Some(ref rec) = fi.rec_buffer.frames[ref_idx] {
     let blk_w = bsize.width();
     let blk_h = bsize.height();
@@ -1044,7 +1434,7 @@ pub fn estimate_motion_ss4<T: Pixel>(
 
     let range_x = 192 * fi.me_range_scale as isize;
     let range_y = 64 * fi.me_range_scale as isize;
-    let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(fi.w_in_b, fi.h_in_b, frame_bo_adj, blk_w, blk_h);
+    let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(fi, ts, frame_bo_adj, blk_w, blk_h);
     let x_lo = po.x + (((-range_x).max(mvx_min / 8)) >> 2);
     let x_hi = po.x + (((range_x).min(mvx_max / 8)) >> 2);
     let y_lo = po.y + (((-range_y).max(mvy_min / 8)) >> 2);
@@ -1070,12 +1460,13 @@ pub fn estimate_motion_ss4<T: Pixel>(
       po,
       1,
       fi.sequence.bit_depth,
+      &fi.config.me_metric(),
       lambda,
       [MotionVector::default(); 2],
-      fi.allow_high_precision_mv
+      &ts.mv_rates
     );
 
-    Some(MotionVector { row: best_mv.row * 4, col: best_mv.col * 4 })
+    Some((MotionVector { row: best_mv.row * 4, col: best_mv.col * 4 }, lowest_cost))
   } else {
     None
   }
@@ -1087,6 +1478,59 @@ pub mod test {
   use crate::partition::BlockSize;
   use crate::partition::BlockSize::*;
 
+  #[test]
+  fn subpel_cache_matches_prediction() {
+    use crate::api::{ChromaSampling, EncoderConfig};
+    use crate::context::CDFContext;
+    use crate::encoder::Sequence;
+
+    let config = EncoderConfig { width: 128, height: 128, ..Default::default() };
+    let sequence = Sequence::new(&Default::default());
+    let mut fi = FrameInvariants::<u8>::new(config, sequence);
+    let mut frame = Frame::<u8>::new(128, 128, ChromaSampling::Cs420);
+    {
+      let mut slice = frame.planes[0].as_mut_slice();
+      for y in 0..128 {
+        for x in 0..128 {
+          slice[y][x] = ((x * 7 + y * 13) ^ (x * y)) as u8;
+        }
+      }
+    }
+    frame.pad(128, 128);
+    fi.rec_buffer.frames[0] = Some(Arc::new(ReferenceFrame {
+      order_hint: 0,
+      width: 128,
+      height: 128,
+      frame,
+      input_hres: Plane::new(64, 64, 1, 1, 0, 0),
+      input_qres: Plane::new(32, 32, 2, 2, 0, 0),
+      cdfs: CDFContext::new(0),
+      base_q_idx: 0,
+      pyramid_level: 0,
+      intra_only: true,
+      frame_mvs: Vec::new()
+    }));
+    fi.ref_frames[LAST_FRAME.to_index()] = 0;
+
+    let po = PlaneOffset { x: 32, y: 32 };
+    // Extended predictions are 32, 64 and 128 pixels wide
+    for &(blk_w, blk_h) in &[(16, 16), (32, 16), (64, 64)] {
+      let mut subpel_cache = SubpelCache::new(blk_w, blk_h);
+      let mut expected = Plane::new(blk_w, blk_h, 0, 0, 0, 0);
+      // The first four share a phase and are read from the same prediction
+      for &(row, col) in &[
+        (3, 5), (11, 13), (-5, 29), (3, -3), (40, 0), (0, 0), (4, 4), (-260, 8)
+      ] {
+        let mv = MotionVector { row, col };
+        predict_luma(&fi, po, &mut expected, blk_w, blk_h, LAST_FRAME, mv);
+        let actual = subpel_cache.predict(&fi, po, LAST_FRAME, mv);
+        for r in 0..blk_h {
+          assert_eq!(&expected.as_region()[r][..blk_w], &actual[r][..blk_w]);
+        }
+      }
+    }
+  }
+
   // Generate plane data for get_sad_same()
   fn setup_sad<T: Pixel>() -> (Plane<T>, Plane<T>) {
     let mut input_plane = Plane::new(640, 480, 0, 0, 128 + 8, 128 + 8);
@@ -1169,4 +1613,31 @@ pub mod test {
   fn get_sad_same_u16() {
     get_sad_same_inner::<u16>();
   }
+
+  #[test]
+  fn satd_of_flat_and_impulse_residuals() {
+    let org = Plane::wrap(vec![10u8; 8 * 8], 8);
+    let mut rf = Plane::wrap(vec![9u8; 8 * 8], 8);
+
+    // A DC offset only has energy in one coefficient per 4x4
+    assert_eq!(get_satd(&org.as_region(), &rf.as_region(), 8, 8), 4 * 8);
+    assert_eq!(get_satd(&org.as_region(), &org.as_region(), 8, 8), 0);
+
+    // An impulse spreads evenly over all coefficients
+    for v in rf.data.iter_mut() {
+      *v = 10;
+    }
+    rf.data[8 * 5 + 6] = 6;
+    assert_eq!(get_satd(&org.as_region(), &rf.as_region(), 8, 8), 16 * 4 / 2);
+  }
+
+  #[test]
+  fn ac_energy_ignores_the_dc() {
+    let mut plane = Plane::wrap(vec![10u8; 8 * 8], 8);
+    assert_eq!(get_ac_energy(&plane.as_region(), 8, 8), 0);
+
+    // An impulse spreads evenly over all coefficients but the DC
+    plane.data[8 * 5 + 6] = 14;
+    assert_eq!(get_ac_energy(&plane.as_region(), 8, 8), 15 * 4 / 2);
+  }
 }
@@ -33,6 +33,117 @@ fn calculate_plane_psnr<T: Pixel>(original: &Plane<T>, compressed: &Plane<T>, bi
   20.0 * max.log10() - 10.0 * mse.log10()
 }
 
+/// Calculates the SSIM of each plane of a `Frame` by comparing the original (uncompressed) to
+/// the compressed version, over 8x8 windows spaced 4 pixels apart. 1 means identical planes.
+///
+/// See https://en.wikipedia.org/wiki/Structural_similarity for more details.
+pub fn calculate_frame_ssim<T: Pixel>(original: &Frame<T>, compressed: &Frame<T>, bit_depth: usize) -> (f64, f64, f64) {
+  let ssim = |p: usize| {
+    let (a, b, w, h) = plane_samples(&original.planes[p], &compressed.planes[p]);
+    ssim_components(&a, &b, w, h, bit_depth).0
+  };
+  (ssim(0), ssim(1), ssim(2))
+}
+
+/// Calculates the multi-scale SSIM of each plane of a `Frame`, over up to 5 scales each half the
+/// size of the previous one, with the weights of Wang et al.
+pub fn calculate_frame_ms_ssim<T: Pixel>(original: &Frame<T>, compressed: &Frame<T>, bit_depth: usize) -> (f64, f64, f64) {
+  let ms_ssim = |p: usize| {
+    let (a, b, w, h) = plane_samples(&original.planes[p], &compressed.planes[p]);
+    calculate_plane_ms_ssim(a, b, w, h, bit_depth)
+  };
+  (ms_ssim(0), ms_ssim(1), ms_ssim(2))
+}
+
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// The visible samples of two planes, with their width and height
+fn plane_samples<T: Pixel>(original: &Plane<T>, compressed: &Plane<T>) -> (Vec<f64>, Vec<f64>, usize, usize) {
+  let samples = |plane: &Plane<T>| plane.iter().map(|v| i32::cast_from(v) as f64).collect();
+  (samples(original), samples(compressed), original.cfg.width, original.cfg.height)
+}
+
+/// Mean SSIM and mean contrast-structure term over the windows of two planes
+fn ssim_components(a: &[f64], b: &[f64], w: usize, h: usize, bit_depth: usize) -> (f64, f64) {
+  const WINDOW: usize = 8;
+  const STEP: usize = 4;
+  if w < WINDOW || h < WINDOW {
+    return (1.0, 1.0);
+  }
+  let max = ((1 << bit_depth) - 1) as f64;
+  let c1 = (0.01 * max) * (0.01 * max);
+  let c2 = (0.03 * max) * (0.03 * max);
+  let n = (WINDOW * WINDOW) as f64;
+
+  let mut ssim_sum = 0.0;
+  let mut cs_sum = 0.0;
+  let mut count = 0;
+  for y in (0..=h - WINDOW).step_by(STEP) {
+    for x in (0..=w - WINDOW).step_by(STEP) {
+      let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+      for row in y..y + WINDOW {
+        let i = row * w + x;
+        for (&va, &vb) in a[i..i + WINDOW].iter().zip(&b[i..i + WINDOW]) {
+          sa += va;
+          sb += vb;
+          saa += va * va;
+          sbb += vb * vb;
+          sab += va * vb;
+        }
+      }
+      let (mu_a, mu_b) = (sa / n, sb / n);
+      let var_a = saa / n - mu_a * mu_a;
+      let var_b = sbb / n - mu_b * mu_b;
+      let cov = sab / n - mu_a * mu_b;
+      let luminance = (2.0 * mu_a * mu_b + c1) / (mu_a * mu_a + mu_b * mu_b + c1);
+      let cs = (2.0 * cov + c2) / (var_a + var_b + c2);
+      ssim_sum += luminance * cs;
+      cs_sum += cs;
+      count += 1;
+    }
+  }
+  (ssim_sum / count as f64, cs_sum / count as f64)
+}
+
+/// Halves both dimensions of a plane, averaging 2x2 blocks
+fn downscale(src: &[f64], w: usize, h: usize) -> (Vec<f64>, usize, usize) {
+  let (dw, dh) = (w / 2, h / 2);
+  let mut dst = Vec::with_capacity(dw * dh);
+  for y in 0..dh {
+    for x in 0..dw {
+      let i = 2 * y * w + 2 * x;
+      dst.push((src[i] + src[i + 1] + src[i + w] + src[i + w + 1]) / 4.0);
+    }
+  }
+  (dst, dw, dh)
+}
+
+fn calculate_plane_ms_ssim(mut a: Vec<f64>, mut b: Vec<f64>, mut w: usize, mut h: usize, bit_depth: usize) -> f64 {
+  // Small planes get fewer scales, the weights of which are renormalized
+  let mut scales = 1;
+  while scales < MS_SSIM_WEIGHTS.len() && (w >> scales) >= 8 && (h >> scales) >= 8 {
+    scales += 1;
+  }
+  let weight_sum: f64 = MS_SSIM_WEIGHTS[..scales].iter().sum();
+
+  let mut ms_ssim = 1.0;
+  for (scale, &weight) in MS_SSIM_WEIGHTS[..scales].iter().enumerate() {
+    let (ssim, cs) = ssim_components(&a, &b, w, h, bit_depth);
+    let weight = weight / weight_sum;
+    if scale == scales - 1 {
+      ms_ssim *= ssim.max(0.0).powf(weight);
+    } else {
+      ms_ssim *= cs.max(0.0).powf(weight);
+      let (da, dw, dh) = downscale(&a, w, h);
+      b = downscale(&b, w, h).0;
+      a = da;
+      w = dw;
+      h = dh;
+    }
+  }
+  ms_ssim
+}
+
 /// Calculate the mean squared error for a `Plane` by comparing the original (uncompressed)
 /// to the compressed version.
 fn calculate_plane_mse<T: Pixel>(original: &Plane<T>, compressed: &Plane<T>) -> f64 {
@@ -41,3 +152,48 @@ fn calculate_plane_mse<T: Pixel>(original: &Plane<T>, compressed: &Plane<T>) ->
     .map(|err| err * err)
     .sum::<u64>() as f64 / (original.cfg.width * original.cfg.height) as f64
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::api::ChromaSampling;
+
+  fn gradient_frame(offset: u8) -> Frame<u8> {
+    let mut frame = Frame::new(64, 64, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let (width, height, stride) = (plane.cfg.width, plane.cfg.height, plane.cfg.stride);
+      for (y, row) in plane.data_origin_mut().chunks_mut(stride).take(height).enumerate() {
+        for (x, v) in row[..width].iter_mut().enumerate() {
+          let base = ((x * 3 + y * 2) % 200) as u8;
+          *v = base.saturating_add(offset * ((x + y) % 2) as u8);
+        }
+      }
+    }
+    frame
+  }
+
+  #[test]
+  fn identical_frames() {
+    let frame = gradient_frame(0);
+    assert_eq!(calculate_frame_psnr(&frame, &frame, 8).0, 100.0);
+    let ssim = calculate_frame_ssim(&frame, &frame, 8);
+    assert!((ssim.0 - 1.0).abs() < 1e-9 && (ssim.1 - 1.0).abs() < 1e-9);
+    let ms_ssim = calculate_frame_ms_ssim(&frame, &frame, 8);
+    assert!((ms_ssim.0 - 1.0).abs() < 1e-9 && (ms_ssim.2 - 1.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn distortion_lowers_metrics() {
+    let original = gradient_frame(0);
+    let slightly = gradient_frame(4);
+    let heavily = gradient_frame(40);
+
+    let ssim_slight = calculate_frame_ssim(&original, &slightly, 8).0;
+    let ssim_heavy = calculate_frame_ssim(&original, &heavily, 8).0;
+    assert!(ssim_slight < 1.0 && ssim_heavy < ssim_slight);
+
+    let ms_ssim_slight = calculate_frame_ms_ssim(&original, &slightly, 8).0;
+    let ms_ssim_heavy = calculate_frame_ms_ssim(&original, &heavily, 8).0;
+    assert!(ms_ssim_slight < 1.0 && ms_ssim_heavy < ms_ssim_slight);
+  }
+}
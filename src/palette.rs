@@ -0,0 +1,292 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::frame::*;
+use crate::tiling::*;
+use crate::util::{CastFromPrimitive, Pixel};
+
+use arrayvec::ArrayVec;
+use std::collections::BTreeMap;
+
+pub const PALETTE_MIN_SIZE: usize = 2;
+pub const PALETTE_MAX_SIZE: usize = 8;
+/// Colors of the above and left blocks that can be reused by a palette
+pub const PALETTE_CACHE_SIZE: usize = 2 * PALETTE_MAX_SIZE;
+
+const PALETTE_NUM_NEIGHBORS: usize = 3;
+const PALETTE_COLOR_HASH_MULTIPLIERS: [usize; PALETTE_NUM_NEIGHBORS] = [1, 2, 2];
+const PALETTE_COLOR_CONTEXT: [i8; 9] = [-1, -1, 0, -1, -1, 4, 3, 2, 1];
+
+/// Refinement passes of the k-means clustering of blocks with more colors
+/// than a palette holds
+const KMEANS_ITERATIONS: usize = 8;
+
+/// Luma palette of a block, in ascending order of color. An empty palette
+/// means the block is not palette coded.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Palette {
+  colors: [u16; PALETTE_MAX_SIZE],
+  len: u8
+}
+
+impl Palette {
+  pub fn new(colors: &[u16]) -> Palette {
+    assert!(colors.len() >= PALETTE_MIN_SIZE && colors.len() <= PALETTE_MAX_SIZE);
+    assert!(colors.windows(2).all(|c| c[0] < c[1]));
+    let mut palette = Palette { len: colors.len() as u8, ..Default::default() };
+    palette.colors[..colors.len()].copy_from_slice(colors);
+    palette
+  }
+
+  /// Palette of the top-left `width`x`height` pixels of `region`: all their
+  /// values if there are few enough of them, cluster centers otherwise.
+  /// Flat blocks get no palette.
+  pub fn from_block<T: Pixel>(
+    region: &PlaneRegion<'_, T>, width: usize, height: usize
+  ) -> Option<Palette> {
+    let width = width.min(region.rect().width);
+    let height = height.min(region.rect().height);
+    let mut histogram = BTreeMap::new();
+    for row in region.rows_iter().take(height) {
+      for &v in &row[..width] {
+        *histogram.entry(u16::cast_from(v)).or_insert(0u32) += 1;
+      }
+    }
+    if histogram.len() < PALETTE_MIN_SIZE {
+      return None;
+    }
+    let values: Vec<(u16, u32)> = histogram.into_iter().collect();
+    if values.len() <= PALETTE_MAX_SIZE {
+      let colors: ArrayVec<[u16; PALETTE_MAX_SIZE]> =
+        values.iter().map(|&(v, _)| v).collect();
+      return Some(Palette::new(&colors));
+    }
+
+    // Start from colors spread over the sorted values
+    let mut centers = [0u32; PALETTE_MAX_SIZE];
+    for (i, c) in centers.iter_mut().enumerate() {
+      *c = values[i * (values.len() - 1) / (PALETTE_MAX_SIZE - 1)].0 as u32;
+    }
+    for _ in 0..KMEANS_ITERATIONS {
+      let mut sums = [0u64; PALETTE_MAX_SIZE];
+      let mut counts = [0u64; PALETTE_MAX_SIZE];
+      for &(v, n) in &values {
+        let i = nearest(&centers, v as u32);
+        sums[i] += v as u64 * n as u64;
+        counts[i] += n as u64;
+      }
+      let mut changed = false;
+      for ((c, &sum), &count) in centers.iter_mut().zip(&sums).zip(&counts) {
+        if count > 0 {
+          let center = ((sum + count / 2) / count) as u32;
+          changed |= center != *c;
+          *c = center;
+        }
+      }
+      if !changed {
+        break;
+      }
+    }
+
+    let mut colors: ArrayVec<[u16; PALETTE_MAX_SIZE]> =
+      centers.iter().map(|&c| c as u16).collect();
+    colors.sort();
+    let mut colors = colors.to_vec();
+    colors.dedup();
+    if colors.len() < PALETTE_MIN_SIZE {
+      return None;
+    }
+    Some(Palette::new(&colors))
+  }
+
+  pub fn len(&self) -> usize {
+    self.len as usize
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn colors(&self) -> &[u16] {
+    &self.colors[..self.len()]
+  }
+
+  /// Index of the color closest to `v`
+  pub fn color_index<T: Pixel>(&self, v: T) -> u8 {
+    let v = u32::cast_from(v);
+    let colors = self.colors();
+    let mut best = 0;
+    for (i, &c) in colors.iter().enumerate().skip(1) {
+      if (c as i32 - v as i32).abs() < (colors[best] as i32 - v as i32).abs() {
+        best = i;
+      }
+    }
+    best as u8
+  }
+
+  /// Color indices of the `width`x`height` area at `po` of the source
+  /// plane. The decoder extends the map past the mode info grid, which ends
+  /// at `grid_width`x`grid_height` pixels, by repeating its last column and
+  /// row, so the indices there are those of the last pixels in the grid.
+  pub fn color_map<T: Pixel>(
+    &self, plane: &Plane<T>, po: PlaneOffset, width: usize, height: usize,
+    grid_width: usize, grid_height: usize
+  ) -> Vec<u8> {
+    let mut map = Vec::with_capacity(width * height);
+    for y in 0..height {
+      let src_y = (po.y as usize + y).min(grid_height - 1);
+      for x in 0..width {
+        let src_x = (po.x as usize + x).min(grid_width - 1);
+        map.push(self.color_index(plane.p(src_x, src_y)));
+      }
+    }
+    map
+  }
+
+  /// Fills `dst` with the colors of the `width`x`height` area at `po` of
+  /// the color map of the source plane (see `color_map`).
+  pub fn predict<T: Pixel>(
+    &self, dst: &mut PlaneRegionMut<'_, T>, plane: &Plane<T>, po: PlaneOffset,
+    width: usize, height: usize, grid_width: usize, grid_height: usize
+  ) {
+    let map = self.color_map(plane, po, width, height, grid_width, grid_height);
+    for (row, indices) in dst.rows_iter_mut().zip(map.chunks(width)).take(height) {
+      for (v, &i) in row[..width].iter_mut().zip(indices) {
+        *v = T::cast_from(self.colors[i as usize]);
+      }
+    }
+  }
+}
+
+fn nearest(centers: &[u32], v: u32) -> usize {
+  let mut best = 0;
+  for (i, &c) in centers.iter().enumerate().skip(1) {
+    if (c as i64 - v as i64).abs() < (centers[best] as i64 - v as i64).abs() {
+      best = i;
+    }
+  }
+  best
+}
+
+/// Colors a block can copy from the palettes of its above and left
+/// neighbors instead of coding them, merged in ascending order without
+/// repeats.
+pub fn palette_cache(above: &[u16], left: &[u16]) -> ArrayVec<[u16; PALETTE_CACHE_SIZE]> {
+  let mut cache = ArrayVec::new();
+  let push = |cache: &mut ArrayVec<[u16; PALETTE_CACHE_SIZE]>, v: u16| {
+    if cache.last() != Some(&v) {
+      cache.push(v);
+    }
+  };
+  let (mut ai, mut li) = (0, 0);
+  while ai < above.len() && li < left.len() {
+    let (a, l) = (above[ai], left[li]);
+    if l < a {
+      push(&mut cache, l);
+      li += 1;
+    } else {
+      push(&mut cache, a);
+      ai += 1;
+      if l == a {
+        li += 1;
+      }
+    }
+  }
+  for &v in above[ai..].iter().chain(&left[li..]) {
+    push(&mut cache, v);
+  }
+  cache
+}
+
+/// Context of the color index at row `r` and column `c` of a color map of
+/// `n` colors, and the order of the colors by score of the already coded
+/// neighbors, which is the order the index is coded in.
+pub fn color_context(
+  map: &[u8], stride: usize, r: usize, c: usize, n: usize
+) -> (usize, [u8; PALETTE_MAX_SIZE]) {
+  let mut scores = [0usize; PALETTE_MAX_SIZE];
+  let mut order = [0u8; PALETTE_MAX_SIZE];
+  for (i, o) in order.iter_mut().enumerate() {
+    *o = i as u8;
+  }
+  if c > 0 {
+    scores[map[r * stride + c - 1] as usize] += 2;
+  }
+  if r > 0 && c > 0 {
+    scores[map[(r - 1) * stride + c - 1] as usize] += 1;
+  }
+  if r > 0 {
+    scores[map[(r - 1) * stride + c] as usize] += 2;
+  }
+  for i in 0..PALETTE_NUM_NEIGHBORS {
+    let mut max_idx = i;
+    for j in i + 1..n {
+      if scores[j] > scores[max_idx] {
+        max_idx = j;
+      }
+    }
+    if max_idx != i {
+      let max_score = scores[max_idx];
+      let max_order = order[max_idx];
+      for k in (i + 1..=max_idx).rev() {
+        scores[k] = scores[k - 1];
+        order[k] = order[k - 1];
+      }
+      scores[i] = max_score;
+      order[i] = max_order;
+    }
+  }
+  let hash: usize = scores.iter().zip(PALETTE_COLOR_HASH_MULTIPLIERS.iter())
+    .map(|(&s, &m)| s * m).sum();
+  let ctx = PALETTE_COLOR_CONTEXT[hash];
+  debug_assert!(ctx >= 0);
+  (ctx as usize, order)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn few_colors_are_kept_and_many_are_clustered() {
+    let mut plane = Plane::<u8>::new(16, 16, 0, 0, 0, 0);
+    for (y, row) in plane.as_region_mut().rows_iter_mut().enumerate().take(16) {
+      for (x, v) in row[..16].iter_mut().enumerate() {
+        *v = if (x + y) % 3 == 0 { 20 } else { 200 };
+      }
+    }
+    let palette = Palette::from_block(&plane.as_region(), 16, 16).unwrap();
+    assert_eq!(palette.colors(), &[20, 200]);
+    assert_eq!(palette.color_index(30u8), 0);
+
+    for (y, row) in plane.as_region_mut().rows_iter_mut().enumerate().take(16) {
+      for (x, v) in row[..16].iter_mut().enumerate() {
+        *v = (x * 16 + y) as u8;
+      }
+    }
+    let palette = Palette::from_block(&plane.as_region(), 16, 16).unwrap();
+    assert_eq!(palette.len(), PALETTE_MAX_SIZE);
+  }
+
+  #[test]
+  fn cache_merges_neighbor_palettes() {
+    assert_eq!(&palette_cache(&[10, 20, 30], &[5, 20, 40])[..], &[5, 10, 20, 30, 40]);
+    assert_eq!(&palette_cache(&[], &[7, 9])[..], &[7, 9]);
+  }
+
+  #[test]
+  fn color_context_ranks_neighbors() {
+    // Left and above agree on color 2, the above left one is color 1
+    let map = [1, 2, 2, 0];
+    let (ctx, order) = color_context(&map, 2, 1, 1, 3);
+    assert_eq!(&order[..3], &[2, 1, 0]);
+    // Scores 4, 1 and 0: hash 4 + 2
+    assert_eq!(ctx, PALETTE_COLOR_CONTEXT[6] as usize);
+  }
+}
@@ -18,9 +18,12 @@ use crate::predict::*;
 use crate::tiling::*;
 use crate::transform::TxSize;
 use crate::util::*;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 // LAST_FRAME through ALTREF_FRAME correspond to slots 0-6.
 #[derive(PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum RefType {
   INTRA_FRAME = 0,
   LAST_FRAME = 1,
@@ -107,6 +110,7 @@ pub enum PartitionType {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum BlockSize {
   BLOCK_4X4,
   BLOCK_4X8,
@@ -169,6 +173,16 @@ impl BlockSize {
     self <= BlockSize::BLOCK_32X32
   }
 
+  pub fn filter_intra_allowed(self) -> bool {
+    self.width() <= 32 && self.height() <= 32
+  }
+
+  /// Palettes are allowed from 8x8, which by the order of the sizes
+  /// includes 4x16 and 16x4, up to 64x64
+  pub fn palette_allowed(self) -> bool {
+    self >= BlockSize::BLOCK_8X8 && self.width() <= 64 && self.height() <= 64
+  }
+
   pub fn width(self) -> usize {
     1 << self.width_log2()
   }
@@ -386,7 +400,15 @@ pub static RAV1E_PARTITION_TYPES: &'static [PartitionType] =
   &[PartitionType::PARTITION_NONE, PartitionType::PARTITION_HORZ,
     PartitionType::PARTITION_VERT, PartitionType::PARTITION_SPLIT];
 
+/// Partitions into three blocks or four strips, whose blocks are not split
+/// any further
+pub static RAV1E_EXT_PARTITION_TYPES: &'static [PartitionType] =
+  &[PartitionType::PARTITION_HORZ_A, PartitionType::PARTITION_HORZ_B,
+    PartitionType::PARTITION_VERT_A, PartitionType::PARTITION_VERT_B,
+    PartitionType::PARTITION_HORZ_4, PartitionType::PARTITION_VERT_4];
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum GlobalMVMode {
   IDENTITY = 0,    // identity transformation, 0-parameter
   TRANSLATION = 1, // translational motion 2-parameter
@@ -416,6 +438,8 @@ pub fn get_intra_edges<T: Pixel>(
   dst: &PlaneRegion<'_, T>,
   po: PlaneOffset,
   tx_size: TxSize,
+  plane_bsize: BlockSize,
+  partition: PartitionType,
   bit_depth: usize,
   opt_mode: Option<PredictionMode>
 ) -> AlignedArray<[T; 4 * MAX_TX_SIZE + 1]> {
@@ -433,6 +457,21 @@ pub fn get_intra_edges<T: Pixel>(
     let x = po.x as usize;
     let y = po.y as usize;
 
+    // Position of the transform block within its prediction block, which
+    // is always aligned to its own size
+    let plane_bw = plane_bsize.width();
+    let plane_bh = plane_bsize.height();
+    let col_off = x & (plane_bw - 1);
+    let row_off = y & (plane_bh - 1);
+    let bo = BlockOffset {
+      x: ((x - col_off) << plane_cfg.xdec) >> MI_SIZE_LOG2,
+      y: ((y - row_off) << plane_cfg.ydec) >> MI_SIZE_LOG2
+    };
+    let bsize = BlockSize::from_width_and_height(
+      plane_bw << plane_cfg.xdec,
+      plane_bh << plane_cfg.ydec
+    );
+
     let mut needs_left = true;
     let mut needs_topleft = true;
     let mut needs_top = true;
@@ -502,17 +541,16 @@ pub fn get_intra_edges<T: Pixel>(
     if needs_topright {
       debug_assert!(plane_cfg.xdec <= 1 && plane_cfg.ydec <= 1);
 
-      let bo = BlockOffset {
-        x: x >> (2 - plane_cfg.xdec),
-        y: y >> (2 - plane_cfg.ydec)
+      // Inside the block, the pixels above and to the right belong to a
+      // transform block already coded unless it lies past the block edge
+      let tr_in_block = col_off + tx_size.width() < plane_bw;
+      let tr_avail = if row_off > 0 {
+        tr_in_block
+      } else {
+        tr_in_block || has_tr(bo, bsize, partition)
       };
 
-      let bsize = BlockSize::from_width_and_height(
-          tx_size.width() << plane_cfg.xdec,
-          tx_size.height() << plane_cfg.ydec
-        );
-
-      let num_avail = if y != 0 && has_tr(bo, bsize) {
+      let num_avail = if y != 0 && tr_avail {
         tx_size.width().min(dst.rect().width - x - tx_size.width())
       } else {
         0
@@ -533,17 +571,13 @@ pub fn get_intra_edges<T: Pixel>(
     if needs_bottomleft {
       debug_assert!(plane_cfg.xdec <= 1 && plane_cfg.ydec <= 1);
 
-      let bo = BlockOffset {
-        x: x >> (2 - plane_cfg.xdec),
-        y: y >> (2 - plane_cfg.ydec)
-        };
-
-      let bsize = BlockSize::from_width_and_height(
-        tx_size.width() << plane_cfg.xdec,
-        tx_size.height() << plane_cfg.ydec
-        );
+      // Only the left column of transform blocks can reach below into the
+      // block to the left, which is coded; the others would need pixels of
+      // this block that are not reconstructed yet
+      let bl_avail = col_off == 0 &&
+        (row_off + tx_size.height() < plane_bh || has_bl(bo, bsize, partition));
 
-      let num_avail = if x != 0 && has_bl(bo, bsize) {
+      let num_avail = if x != 0 && bl_avail {
         tx_size.height().min(dst.rect().height - y - tx_size.height())
       } else {
         0
@@ -568,7 +602,12 @@ pub fn get_intra_edges<T: Pixel>(
   edge_buf
 }
 
-pub fn has_tr(bo: BlockOffset, bsize: BlockSize) -> bool {
+/// Whether the pixels above and to the right of a block are coded before
+/// it. `partition` is the partition the block comes from, since some of
+/// them code their blocks out of the usual order.
+pub fn has_tr(
+  bo: BlockOffset, bsize: BlockSize, partition: PartitionType
+) -> bool {
   let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
   let mask_row = bo.y & LOCAL_BLOCK_MASK;
   let mask_col = bo.x & LOCAL_BLOCK_MASK;
@@ -609,23 +648,39 @@ pub fn has_tr(bo: BlockOffset, bsize: BlockSize) -> bool {
     has_tr = false;
   }
 
-  /* The bottom left square of a Vertical A (in the old format) does
-    * not have a top right as it is decoded before the right hand
-    * rectangle of the partition */
-/*
-  if blk.partition == PartitionType::PARTITION_VERT_A {
-    if blk.n4_w == blk.n4_h {
-      if (mask_row & bs) != 0 {
+  match partition {
+    /* The bottom left square of a Vertical A does not have a top right as
+      * it is decoded before the right hand rectangle of the partition */
+    PartitionType::PARTITION_VERT_A => {
+      if target_n4_w == target_n4_h && (bo.y & target_n4_h) != 0 {
         has_tr = false;
       }
     }
+    /* Only the top strip of a horizontal 4-way partition has its top right
+      * coded, above the partition */
+    PartitionType::PARTITION_HORZ_4 => {
+      if (bo.y / target_n4_h) & 3 != 0 {
+        has_tr = false;
+      }
+    }
+    /* All but the right strip of a vertical 4-way partition have their top
+      * right above the partition */
+    PartitionType::PARTITION_VERT_4 => {
+      if (bo.x / target_n4_w) & 3 != 3 {
+        has_tr = true;
+      }
+    }
+    _ => {}
   }
-*/
 
   has_tr
 }
 
-pub fn has_bl(bo: BlockOffset, bsize: BlockSize) -> bool {
+/// Whether the pixels below and to the left of a block are coded before it.
+/// See `has_tr()` for `partition`.
+pub fn has_bl(
+  bo: BlockOffset, bsize: BlockSize, partition: PartitionType
+) -> bool {
   let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
   let mask_row = bo.y & LOCAL_BLOCK_MASK;
   let mask_col = bo.x & LOCAL_BLOCK_MASK;
@@ -666,18 +721,32 @@ pub fn has_bl(bo: BlockOffset, bsize: BlockSize) -> bool {
     has_bl = true;
   }
 
-  /* The bottom left square of a Vertical A (in the old format) does
-    * not have a top right as it is decoded before the right hand
-    * rectangle of the partition */
-/*
-  if blk.partition == PartitionType::PARTITION_VERT_A {
-    if blk.n4_w == blk.n4_h {
-      if (mask_row & bs) != 0 {
-        has_tr = false;
+  match partition {
+    /* The top right square of a Vertical B has its bottom left in the left
+      * hand rectangle of the partition, which is decoded before it */
+    PartitionType::PARTITION_VERT_B => {
+      if target_n4_w == target_n4_h && (bo.x & target_n4_w) != 0
+        && (bo.y & target_n4_h) == 0
+      {
+        has_bl = true;
+      }
+    }
+    /* All but the bottom strip of a horizontal 4-way partition have their
+      * bottom left to the left of the partition */
+    PartitionType::PARTITION_HORZ_4 => {
+      if (bo.y / target_n4_h) & 3 != 3 {
+        has_bl = true;
+      }
+    }
+    /* Only the left strip of a vertical 4-way partition has its bottom left
+      * coded, below the partition */
+    PartitionType::PARTITION_VERT_4 => {
+      if (bo.x / target_n4_w) & 3 != 0 {
+        has_bl = false;
       }
     }
+    _ => {}
   }
-*/
 
   has_bl
 }
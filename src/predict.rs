@@ -11,8 +11,9 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
+use crate::compound::*;
 use crate::context::{INTRA_MODES, MAX_TX_SIZE};
-use crate::encoder::FrameInvariants;
+use crate::encoder::{FrameInvariants, ReferenceFrame};
 use crate::mc::*;
 use crate::partition::*;
 use crate::frame::*;
@@ -88,7 +89,9 @@ pub enum PredictionMode {
   NEAR_NEWMV,
   NEW_NEARMV,
   GLOBAL_GLOBALMV,
-  NEW_NEWMV
+  NEW_NEWMV,
+  // Copy from an already coded area of the current frame
+  INTRABC
 }
 
 impl PredictionMode {
@@ -252,7 +255,8 @@ impl PredictionMode {
   pub fn predict_inter<T: Pixel>(
     self, fi: &FrameInvariants<T>, tile_rect: TileRect, p: usize, po: PlaneOffset,
     dst: &mut PlaneRegionMut<'_, T>, width: usize, height: usize,
-    ref_frames: [RefType; 2], mvs: [MotionVector; 2]
+    ref_frames: [RefType; 2], mvs: [MotionVector; 2],
+    compound: CompoundParams
   ) {
     assert!(!self.is_intra());
     let frame_po = tile_rect.to_frame_plane_offset(po);
@@ -280,8 +284,36 @@ impl PredictionMode {
       (row_frac, col_frac, rec_plane.slice(qo).clamp().subslice(3, 3))
     };
 
+    // References of another size than the frame are read through the scaled
+    // filters
+    fn get_scaled_position<T: Pixel>(
+      fi: &FrameInvariants<T>, rec: &ReferenceFrame<T>, p: usize,
+      po: PlaneOffset, mv: MotionVector
+    ) -> Option<ScaledPosition> {
+      if rec.width == fi.width && rec.height == fi.height {
+        return None;
+      }
+      let PlaneConfig { xdec, ydec, .. } = rec.frame.planes[p].cfg;
+      Some(ScaledPosition::new(
+        po, mv, xdec, ydec, (fi.width, fi.height), (rec.width, rec.height)
+      ))
+    }
+
     if !is_compound {
       if let Some(ref rec) = fi.rec_buffer.frames[fi.ref_frames[ref_frames[0].to_index()] as usize] {
+        if let Some(pos) = get_scaled_position(fi, rec, p, frame_po, mvs[0]) {
+          put_8tap_scaled(
+            dst,
+            &rec.frame.planes[p],
+            pos,
+            width,
+            height,
+            mode,
+            mode,
+            fi.sequence.bit_depth
+          );
+          return;
+        }
         let (row_frac, col_frac, src) = get_params(&rec.frame.planes[p], frame_po, mvs[0]);
         put_8tap(
           dst,
@@ -300,6 +332,19 @@ impl PredictionMode {
         [UninitializedAlignedArray(), UninitializedAlignedArray()];
       for i in 0..2 {
         if let Some(ref rec) = fi.rec_buffer.frames[fi.ref_frames[ref_frames[i].to_index()] as usize] {
+          if let Some(pos) = get_scaled_position(fi, rec, p, frame_po, mvs[i]) {
+            prep_8tap_scaled(
+              &mut tmp[i].array,
+              &rec.frame.planes[p],
+              pos,
+              width,
+              height,
+              mode,
+              mode,
+              fi.sequence.bit_depth
+            );
+            continue;
+          }
           let (row_frac, col_frac, src) = get_params(&rec.frame.planes[p], frame_po, mvs[i]);
           prep_8tap(
             &mut tmp[i].array,
@@ -314,12 +359,103 @@ impl PredictionMode {
           );
         }
       }
-      mc_avg(
+      if compound.comp_type == CompoundType::COMPOUND_AVERAGE {
+        mc_avg(
+          dst,
+          &tmp[0].array,
+          &tmp[1].array,
+          width,
+          height,
+          fi.sequence.bit_depth
+        );
+        return;
+      }
+      if compound.comp_type == CompoundType::COMPOUND_DISTANCE {
+        mc_dist_wtd(
+          dst,
+          &tmp[0].array,
+          &tmp[1].array,
+          width,
+          height,
+          dist_wtd_weights(fi.ref_frame_dists(ref_frames)),
+          fi.sequence.bit_depth
+        );
+        return;
+      }
+
+      // Masks are defined on the luma block, and averaged for chroma
+      let &PlaneConfig { xdec, ydec, .. } = dst.plane_cfg;
+      let (luma_width, luma_height) = (width << xdec, height << ydec);
+      let mut mask: AlignedArray<[u8; 128 * 128]> = UninitializedAlignedArray();
+      if compound.comp_type == CompoundType::COMPOUND_WEDGE {
+        wedge_mask(
+          &mut mask.array,
+          BlockSize::from_width_and_height(luma_width, luma_height),
+          compound.wedge_index as usize,
+          compound.wedge_sign
+        );
+      } else if p == 0 {
+        diffwtd_mask(
+          &mut mask.array,
+          &tmp[0].array,
+          &tmp[1].array,
+          width,
+          height,
+          compound.mask_type,
+          fi.sequence.bit_depth
+        );
+      } else {
+        // The difference of the luma predictions drives the chroma blend
+        let luma_po = PlaneOffset { x: frame_po.x << xdec, y: frame_po.y << ydec };
+        let mut luma_tmp: [AlignedArray<[i16; 128 * 128]>; 2] =
+          [UninitializedAlignedArray(), UninitializedAlignedArray()];
+        for i in 0..2 {
+          if let Some(ref rec) = fi.rec_buffer.frames[fi.ref_frames[ref_frames[i].to_index()] as usize] {
+            if let Some(pos) = get_scaled_position(fi, rec, 0, luma_po, mvs[i]) {
+              prep_8tap_scaled(
+                &mut luma_tmp[i].array,
+                &rec.frame.planes[0],
+                pos,
+                luma_width,
+                luma_height,
+                mode,
+                mode,
+                fi.sequence.bit_depth
+              );
+              continue;
+            }
+            let (row_frac, col_frac, src) = get_params(&rec.frame.planes[0], luma_po, mvs[i]);
+            prep_8tap(
+              &mut luma_tmp[i].array,
+              src,
+              luma_width,
+              luma_height,
+              col_frac,
+              row_frac,
+              mode,
+              mode,
+              fi.sequence.bit_depth
+            );
+          }
+        }
+        diffwtd_mask(
+          &mut mask.array,
+          &luma_tmp[0].array,
+          &luma_tmp[1].array,
+          luma_width,
+          luma_height,
+          compound.mask_type,
+          fi.sequence.bit_depth
+        );
+      }
+      mask_blend(
         dst,
         &tmp[0].array,
         &tmp[1].array,
         width,
         height,
+        &mask.array,
+        luma_width,
         fi.sequence.bit_depth
       );
     }
@@ -340,9 +476,70 @@ pub enum CompoundType {
   COMPOUND_AVERAGE,
   COMPOUND_WEDGE,
   COMPOUND_DIFFWTD,
+  COMPOUND_DISTANCE,
   COMPOUND_TYPES,
 }
 
+/// How the two predictions of a compound block are combined
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CompoundParams {
+  pub comp_type: CompoundType,
+  /// Shape in the wedge codebook of the block size
+  pub wedge_index: u8,
+  /// Swaps the predictions on the two sides of the wedge
+  pub wedge_sign: bool,
+  /// Favors the second prediction rather than the first where they differ
+  pub mask_type: bool,
+}
+
+impl Default for CompoundParams {
+  fn default() -> Self {
+    CompoundParams {
+      comp_type: CompoundType::COMPOUND_AVERAGE,
+      wedge_index: 0,
+      wedge_sign: false,
+      mask_type: false,
+    }
+  }
+}
+
+impl CompoundParams {
+  pub fn wedge(wedge_index: u8, wedge_sign: bool) -> Self {
+    CompoundParams {
+      comp_type: CompoundType::COMPOUND_WEDGE,
+      wedge_index,
+      wedge_sign,
+      ..Default::default()
+    }
+  }
+
+  pub fn diffwtd(mask_type: bool) -> Self {
+    CompoundParams {
+      comp_type: CompoundType::COMPOUND_DIFFWTD,
+      mask_type,
+      ..Default::default()
+    }
+  }
+
+  pub fn distance() -> Self {
+    CompoundParams {
+      comp_type: CompoundType::COMPOUND_DISTANCE,
+      ..Default::default()
+    }
+  }
+
+  /// Value of comp_group_idx, which tells masked compound modes apart
+  pub fn is_masked(self) -> bool {
+    self.comp_type == CompoundType::COMPOUND_WEDGE
+      || self.comp_type == CompoundType::COMPOUND_DIFFWTD
+  }
+
+  /// Value of compound_idx, which is only cleared for distance weighting
+  pub fn compound_idx(self) -> bool {
+    self.comp_type != CompoundType::COMPOUND_DISTANCE
+  }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum MotionMode {
   SIMPLE_TRANSLATION,
@@ -386,6 +583,133 @@ pub enum FilterIntraMode {
   FILTER_INTRA_MODES
 }
 
+pub static RAV1E_FILTER_INTRA_MODES: &'static [FilterIntraMode] = &[
+  FilterIntraMode::FILTER_DC_PRED,
+  FilterIntraMode::FILTER_V_PRED,
+  FilterIntraMode::FILTER_H_PRED,
+  FilterIntraMode::FILTER_D157_PRED,
+  FilterIntraMode::FILTER_PAETH_PRED,
+];
+
+const INTRA_FILTER_SCALE_BITS: usize = 4;
+
+// Taps applied to the 7 neighbors of each pixel of a 4x2 patch, in order
+// top-left, above 0..3, left 0..1
+const INTRA_FILTER_TAPS: [[[i32; 7]; 8]; FilterIntraMode::FILTER_INTRA_MODES as usize] = [
+  [
+    [-6, 10, 0, 0, 0, 12, 0],
+    [-5, 2, 10, 0, 0, 9, 0],
+    [-3, 1, 1, 10, 0, 7, 0],
+    [-3, 1, 1, 2, 10, 5, 0],
+    [-4, 6, 0, 0, 0, 2, 12],
+    [-3, 2, 6, 0, 0, 2, 9],
+    [-3, 2, 2, 6, 0, 2, 7],
+    [-3, 1, 2, 2, 6, 3, 5],
+  ],
+  [
+    [-10, 16, 0, 0, 0, 10, 0],
+    [-6, 0, 16, 0, 0, 6, 0],
+    [-4, 0, 0, 16, 0, 4, 0],
+    [-2, 0, 0, 0, 16, 2, 0],
+    [-10, 16, 0, 0, 0, 0, 10],
+    [-6, 0, 16, 0, 0, 0, 6],
+    [-4, 0, 0, 16, 0, 0, 4],
+    [-2, 0, 0, 0, 16, 0, 2],
+  ],
+  [
+    [-8, 8, 0, 0, 0, 16, 0],
+    [-8, 0, 8, 0, 0, 16, 0],
+    [-8, 0, 0, 8, 0, 16, 0],
+    [-8, 0, 0, 0, 8, 16, 0],
+    [-4, 4, 0, 0, 0, 0, 16],
+    [-4, 0, 4, 0, 0, 0, 16],
+    [-4, 0, 0, 4, 0, 0, 16],
+    [-4, 0, 0, 0, 4, 0, 16],
+  ],
+  [
+    [-2, 8, 0, 0, 0, 10, 0],
+    [-1, 3, 8, 0, 0, 6, 0],
+    [-1, 2, 3, 8, 0, 4, 0],
+    [0, 1, 2, 3, 8, 2, 0],
+    [-1, 4, 0, 0, 0, 3, 10],
+    [-1, 3, 4, 0, 0, 4, 6],
+    [-1, 2, 3, 4, 0, 4, 4],
+    [-1, 2, 2, 3, 4, 3, 3],
+  ],
+  [
+    [-12, 14, 0, 0, 0, 14, 0],
+    [-10, 0, 14, 0, 0, 12, 0],
+    [-9, 0, 0, 14, 0, 11, 0],
+    [-8, 0, 0, 0, 14, 10, 0],
+    [-10, 12, 0, 0, 0, 0, 14],
+    [-9, 1, 12, 0, 0, 0, 12],
+    [-8, 0, 0, 12, 0, 1, 11],
+    [-7, 0, 0, 1, 12, 1, 9],
+  ],
+];
+
+impl FilterIntraMode {
+  /// Intra direction whose transform type contexts the mode shares
+  pub fn intra_dir(self) -> PredictionMode {
+    match self {
+      FilterIntraMode::FILTER_DC_PRED => PredictionMode::DC_PRED,
+      FilterIntraMode::FILTER_V_PRED => PredictionMode::V_PRED,
+      FilterIntraMode::FILTER_H_PRED => PredictionMode::H_PRED,
+      FilterIntraMode::FILTER_D157_PRED => PredictionMode::D153_PRED,
+      FilterIntraMode::FILTER_PAETH_PRED => PredictionMode::DC_PRED,
+      FilterIntraMode::FILTER_INTRA_MODES => unreachable!()
+    }
+  }
+
+  /// Recursive intra prediction: each 4x2 patch is filtered from the 7
+  /// pixels above and to its left, some of which were themselves predicted.
+  pub fn predict_intra<T: Pixel>(
+    self, dst: &mut PlaneRegionMut<'_, T>, tx_size: TxSize, bit_depth: usize,
+    edge_buf: &AlignedArray<[T; 4 * MAX_TX_SIZE + 1]>
+  ) {
+    let width = tx_size.width();
+    let height = tx_size.height();
+    assert!(width <= 32 && height <= 32);
+
+    // left pixels are order from bottom to top and right-aligned
+    let (left, not_left) = edge_buf.array.split_at(2 * MAX_TX_SIZE);
+    let (top_left, above) = not_left.split_at(1);
+    let left_col = |i: usize| -> i32 { left[2 * MAX_TX_SIZE - 1 - i].into() };
+    let above_row = |j: usize| -> i32 {
+      if j == 0 { top_left[0].into() } else { above[j - 1].into() }
+    };
+
+    let sample_max = (1 << bit_depth) - 1;
+    let taps = &INTRA_FILTER_TAPS[self as usize];
+
+    for i2 in (0..height).step_by(2) {
+      for j4 in (0..width).step_by(4) {
+        let mut p = [0i32; 7];
+        for (i, v) in p.iter_mut().enumerate() {
+          *v = if i < 5 {
+            if i2 == 0 {
+              above_row(j4 + i)
+            } else if j4 == 0 && i == 0 {
+              left_col(i2 - 1)
+            } else {
+              dst[i2 - 1][j4 + i - 1].into()
+            }
+          } else if j4 == 0 {
+            left_col(i2 + i - 5)
+          } else {
+            dst[i2 + i - 5][j4 - 1].into()
+          };
+        }
+        for (k, tap) in taps.iter().enumerate() {
+          let pr: i32 = tap.iter().zip(p.iter()).map(|(&t, &v)| t * v).sum();
+          let v = round_shift(pr, INTRA_FILTER_SCALE_BITS).max(0).min(sample_max);
+          dst[i2 + (k >> 2)][j4 + (k & 3)] = T::cast_from(v);
+        }
+      }
+    }
+  }
+}
+
 // Weights are quadratic from '1' to '1 / block_size', scaled by 2^sm_weight_log2_scale.
 const sm_weight_log2_scale: u8 = 8;
 
@@ -1221,6 +1545,82 @@ mod test {
   use super::*;
   use num_traits::*;
 
+  #[test]
+  fn filter_intra_keeps_flat_areas_flat() {
+    let mut edge_buf: AlignedArray<[u16; 4 * MAX_TX_SIZE + 1]> =
+      UninitializedAlignedArray();
+    for v in edge_buf.array.iter_mut() {
+      *v = 300;
+    }
+    let mut output = Plane::wrap(vec![0u16; 16 * 8], 16);
+
+    for &mode in RAV1E_FILTER_INTRA_MODES {
+      mode.predict_intra(&mut output.as_region_mut(), TxSize::TX_16X8, 10, &edge_buf);
+      assert!(output.data.iter().all(|&v| v == 300));
+    }
+  }
+
+  #[test]
+  fn filter_intra_follows_edges() {
+    let mut edge_buf: AlignedArray<[u8; 4 * MAX_TX_SIZE + 1]> =
+      UninitializedAlignedArray();
+    for v in edge_buf.array.iter_mut() {
+      *v = 50;
+    }
+    // Bright above row, dark left column
+    for v in edge_buf.array[2 * MAX_TX_SIZE + 1..].iter_mut() {
+      *v = 200;
+    }
+    let mut output = Plane::wrap(vec![0u8; 8 * 8], 8);
+
+    FilterIntraMode::FILTER_V_PRED.predict_intra(&mut output.as_region_mut(), TxSize::TX_8X8, 8, &edge_buf);
+    assert!(output.data[..8].iter().all(|&v| v > 150));
+    FilterIntraMode::FILTER_H_PRED.predict_intra(&mut output.as_region_mut(), TxSize::TX_8X8, 8, &edge_buf);
+    assert!(output.data[7 * 8..].iter().all(|&v| v < 100));
+  }
+
+  #[test]
+  fn intra_edges_of_split_blocks() {
+    let mut data = vec![0u8; 64 * 64];
+    for (i, v) in data.iter_mut().enumerate() {
+      *v = ((i % 64) + 2 * (i / 64)) as u8;
+    }
+    let plane = Plane::wrap(data, 64);
+    let dst = plane.as_region();
+    let above = 2 * MAX_TX_SIZE + 1;
+
+    // The lower left 8x8 of a 16x16 block reads its top right from the
+    // transform block coded just before it
+    let po = PlaneOffset { x: 16, y: 24 };
+    let edge_buf = get_intra_edges(
+      &dst,
+      po,
+      TxSize::TX_8X8,
+      BlockSize::BLOCK_16X16,
+      PartitionType::PARTITION_NONE,
+      8,
+      Some(PredictionMode::D45_PRED)
+    );
+    for i in 0..8 {
+      assert_eq!(edge_buf.array[above + 8 + i], dst[23][24 + i]);
+    }
+
+    // The lower right 8x8 has no coded pixels to its top right
+    let po = PlaneOffset { x: 24, y: 24 };
+    let edge_buf = get_intra_edges(
+      &dst,
+      po,
+      TxSize::TX_8X8,
+      BlockSize::BLOCK_16X16,
+      PartitionType::PARTITION_NONE,
+      8,
+      Some(PredictionMode::D45_PRED)
+    );
+    for i in 0..8 {
+      assert_eq!(edge_buf.array[above + 8 + i], dst[23][31]);
+    }
+  }
+
   #[test]
   fn pred_matches_u8() {
     let mut edge_buf: AlignedArray<[u8; 2 * MAX_TX_SIZE + 1]> =
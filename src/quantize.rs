@@ -9,10 +9,14 @@
 
 #![allow(non_upper_case_globals)]
 
-use crate::transform::TxSize;
+use crate::transform::{TxSize, TxType};
 use crate::util::*;
 
 use num_traits::*;
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
 use std::convert::Into;
 use std::mem;
 use std::ops::AddAssign;
@@ -22,6 +26,32 @@ pub trait Coefficient:
 impl Coefficient for i16 {}
 impl Coefficient for i32 {}
 
+/// Quantizer matrix level weighing all the coefficients equally
+pub const QM_LEVEL_FLAT: u8 = 15;
+/// Precision of the quantizer matrix weights, which are 32 for a weight of 1
+const AOM_QM_BITS: u32 = 5;
+
+/// Weights in coefficient order of quantizer matrix `level` for a transform
+/// of `tx_size` and `tx_type`, or `None` if its coefficients are all weighed
+/// equally. Transforms with an identity direction are never weighted.
+///
+/// The weighted levels are not available yet: they need the normative
+/// weight tables, so `EncoderConfig::validate` only accepts the flat level.
+pub fn qm_weights(
+  level: u8, _is_chroma: bool, _tx_size: TxSize, tx_type: TxType
+) -> Option<&'static [u8]> {
+  if level == QM_LEVEL_FLAT || tx_type as usize >= TxType::IDTX as usize {
+    return None;
+  }
+  unimplemented!()
+}
+
+/// Quantizer `quant` scaled by quantizer matrix weight `weight`
+#[inline]
+fn weighted_quant(quant: u32, weight: u8) -> u32 {
+  (quant * weight as u32 + (1 << (AOM_QM_BITS - 1))) >> AOM_QM_BITS
+}
+
 pub fn get_log_tx_scale(tx_size: TxSize) -> usize {
   let num_pixels = tx_size.area();
 
@@ -104,7 +134,10 @@ pub struct QuantizationContext {
 
   ac_quant: u32,
   ac_offset: i32,
-  ac_mul_add: (u32, u32, u32)
+  ac_mul_add: (u32, u32, u32),
+
+  is_intra: bool,
+  qm: Option<&'static [u8]>
 }
 
 fn divu_gen(d: u32) -> (u32, u32, u32) {
@@ -145,6 +178,73 @@ mod test {
   use super::*;
   use crate::transform::TxSize::*;
 
+  #[test]
+  fn quantize_matches_rust() {
+    let mut qc = QuantizationContext::default();
+    let mut coeffs = [0i32; 32 * 32];
+    let mut expected = [0i32; 32 * 32];
+    let mut actual = [0i32; 32 * 32];
+    let mut dequantized = [0i32; 32 * 32];
+    for (i, c) in coeffs.iter_mut().enumerate() {
+      *c = ((i * 7919) % 4001) as i32 - 2000;
+    }
+    for &(tx_size, bit_depth) in
+      &[(TX_4X4, 8), (TX_8X8, 10), (TX_16X16, 12), (TX_32X32, 8)]
+    {
+      for &qindex in &[1, 60, 128, 255] {
+        qc.update(qindex, tx_size, true, bit_depth, 0, 0, None);
+        for &coded_tx_size in &[1, 7, tx_size.area()] {
+          let n = tx_size.area();
+          qc.quantize_rs(&coeffs[..n], &mut expected[..n], coded_tx_size);
+          qc.quantize(&coeffs[..n], &mut actual[..n], coded_tx_size);
+          assert_eq!(&expected[..n], &actual[..n]);
+
+          let ac_quant = ac_q(qindex, 0, bit_depth) as i32;
+          let dc_quant = dc_q(qindex, 0, bit_depth) as i32;
+          let log_tx_scale = get_log_tx_scale(tx_size) as i32;
+          dequantize_rs(
+            dc_quant, ac_quant, log_tx_scale, bit_depth, &expected[..n],
+            &mut dequantized[..n]
+          );
+          dequantize(qindex, &expected[..n], &mut actual[..n], tx_size, bit_depth, 0, 0, None);
+          assert_eq!(&dequantized[..n], &actual[..n]);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn dequantize_8bit_fits_i16() {
+    let coeffs = [i32::from(i16::max_value()), -i32::from(i16::max_value()) - 1]
+      .iter().cycle().take(64).cloned().collect::<Vec<_>>();
+    let mut rcoeffs = [0i32; 64];
+    for &qindex in &[0, 128, 255] {
+      dequantize(qindex, &coeffs, &mut rcoeffs, TX_8X8, 8, 0, 0, None);
+      for &r in rcoeffs.iter() {
+        assert_eq!(r, i32::from(r as i16));
+      }
+    }
+  }
+
+  #[test]
+  fn find_eob_matches_scan() {
+    use crate::scan_order::av1_scan_orders;
+    use crate::transform::TxType;
+
+    for &tx_size in &[TX_4X4, TX_8X4, TX_16X16, TX_32X32] {
+      let scan_order = &av1_scan_orders[tx_size as usize][TxType::DCT_DCT as usize];
+      let n = tx_size.area();
+      let mut coeffs = [0i32; 32 * 32];
+      assert_eq!(find_eob(&coeffs[..n], scan_order.iscan), 0);
+      for &last in &[0, 1, n / 2 + 3, n - 1] {
+        coeffs[scan_order.scan[last / 2] as usize] = -3;
+        coeffs[scan_order.scan[last] as usize] = 1;
+        assert_eq!(find_eob(&coeffs[..n], scan_order.iscan), last + 1);
+        assert_eq!(find_eob_rs(&coeffs[..n], scan_order.iscan), last + 1);
+      }
+    }
+  }
+
   #[test]
   fn test_divu_pair() {
     for d in 1..1024 {
@@ -188,14 +288,60 @@ mod test {
       assert!(tx_size.1 == get_log_tx_scale(tx_size.0));
     }
   }
+  #[test]
+  fn test_nearest_level() {
+    let mut qc = QuantizationContext::default();
+    let q = ac_q(100, 0, 8) as i32;
+
+    qc.update(100, TX_8X8, true, 8, 0, 0, None);
+    assert_eq!(qc.nearest_level(3 * q, 1), 3);
+    assert_eq!(qc.nearest_level(-3 * q, 1), 3);
+    assert_eq!(qc.nearest_level(3 * q + q / 2 + 1, 1), 4);
+    assert_eq!(qc.level_dist(3 * q, 3, 1), 0.);
+    assert_eq!(qc.level_dist(3 * q, 2, 1), (q * q) as f64 / 64.);
+
+    qc.update(100, TX_32X32, true, 8, 0, 0, None);
+    assert_eq!(qc.nearest_level(3 * q, 1), 6);
+    assert_eq!(qc.level_dist(3 * q, 6, 1), 0.);
+  }
+
+  #[test]
+  fn quantizer_matrix_weights_each_coefficient() {
+    let mut weights = vec![32u8; 64];
+    weights[1] = 48;
+    weights[8] = 48;
+    weights[63] = 64;
+    let weights: &'static [u8] = Box::leak(weights.into_boxed_slice());
+    let mut qc = QuantizationContext::default();
+    qc.update(100, TX_8X8, true, 8, 0, 0, Some(weights));
+    let dc = dc_q(100, 0, 8) as i32;
+    let ac = ac_q(100, 0, 8) as i32;
+    let quant = |pos: usize| {
+      let q = if pos == 0 { dc } else { ac };
+      (q * weights[pos] as i32 + 16) >> 5
+    };
+
+    let coeffs: Vec<i32> = (0..64).map(|pos| 5 * quant(pos)).collect();
+    let mut qcoeffs = [0i32; 64];
+    qc.quantize(&coeffs, &mut qcoeffs, 64);
+    assert!(qcoeffs.iter().all(|&c| c == 5));
+    assert_eq!(qc.nearest_level(coeffs[63], 63), 5);
+    assert_eq!(qc.level_dist(coeffs[63], 4, 63), (quant(63) * quant(63)) as f64 / 64.);
+
+    let mut rcoeffs = [0i32; 64];
+    dequantize(100, &qcoeffs, &mut rcoeffs, TX_8X8, 8, 0, 0, Some(weights));
+    assert_eq!(&rcoeffs[..], &coeffs[..]);
+  }
 }
 
 impl QuantizationContext {
   pub fn update(
     &mut self, qindex: u8, tx_size: TxSize, is_intra: bool, bit_depth: usize,
-    dc_delta_q: i8, ac_delta_q: i8
+    dc_delta_q: i8, ac_delta_q: i8, qm: Option<&'static [u8]>
   ) {
     self.log_tx_scale = get_log_tx_scale(tx_size);
+    self.is_intra = is_intra;
+    self.qm = qm;
 
     self.dc_quant = dc_q(qindex, dc_delta_q, bit_depth) as u32;
     self.dc_mul_add = divu_gen(self.dc_quant);
@@ -212,6 +358,31 @@ impl QuantizationContext {
   #[inline]
   pub fn quantize<T>(&self, coeffs: &[T], qcoeffs: &mut [T], coded_tx_size: usize)
     where T: Coefficient
+  {
+    if self.qm.is_some() {
+      return self.quantize_weighted(coeffs, qcoeffs, coded_tx_size);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      // The only 32-bit Coefficient is i32
+      if mem::size_of::<T>() == 4 && is_x86_feature_detected!("sse4.1") {
+        return unsafe {
+          let coeffs =
+            std::slice::from_raw_parts(coeffs.as_ptr() as *const i32, coeffs.len());
+          let qcoeffs = std::slice::from_raw_parts_mut(
+            qcoeffs.as_mut_ptr() as *mut i32, qcoeffs.len()
+          );
+          self.quantize_sse4_1(coeffs, qcoeffs, coded_tx_size)
+        };
+      }
+    }
+
+    self.quantize_rs(coeffs, qcoeffs, coded_tx_size);
+  }
+
+  fn quantize_rs<T>(&self, coeffs: &[T], qcoeffs: &mut [T], coded_tx_size: usize)
+    where T: Coefficient
   {
     qcoeffs[0] = coeffs[0] << (self.log_tx_scale as usize);
     qcoeffs[0] += qcoeffs[0].signum() * T::cast_from(self.dc_offset);
@@ -229,22 +400,243 @@ impl QuantizationContext {
       }
     }
   }
+
+  fn quantize_weighted<T>(&self, coeffs: &[T], qcoeffs: &mut [T], coded_tx_size: usize)
+    where T: Coefficient
+  {
+    let rounding = if self.is_intra { 21 } else { 15 };
+    for (pos, (qc, &c)) in
+      qcoeffs.iter_mut().zip(coeffs.iter()).enumerate().take(coded_tx_size)
+    {
+      let quant = self.quant(pos).max(1) as i32;
+      let x: i32 = (c << self.log_tx_scale).as_();
+      *qc = T::cast_from((x + x.signum() * (quant * rounding / 64)) / quant);
+    }
+
+    if qcoeffs.len() > coded_tx_size {
+      for qc in qcoeffs[coded_tx_size..].iter_mut() {
+        *qc = T::cast_from(0);
+      }
+    }
+  }
+
+  #[target_feature(enable = "sse4.1")]
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  unsafe fn quantize_sse4_1(
+    &self, coeffs: &[i32], qcoeffs: &mut [i32], coded_tx_size: usize
+  ) {
+    let coeffs = &coeffs[..coded_tx_size];
+    let (a, b, div_shift) = self.ac_mul_add;
+    let log_tx_scale = _mm_cvtsi32_si128(self.log_tx_scale as i32);
+    let ac_offset = _mm_set1_epi32(self.ac_offset);
+    let a = _mm_set1_epi32(a as i32);
+    let b = _mm_set1_epi64x(b as i64);
+    let div_shift = _mm_cvtsi32_si128(32 + div_shift as i32);
+
+    // All the coefficients are quantized as AC, and the DC is redone after
+    let mut i = 0;
+    while i + 4 <= coded_tx_size {
+      let c = _mm_loadu_si128(coeffs[i..].as_ptr() as *const _);
+      let x = _mm_sll_epi32(c, log_tx_scale);
+      let y = _mm_abs_epi32(x);
+      let nonzero = _mm_andnot_si128(_mm_cmpeq_epi32(y, _mm_setzero_si128()), ac_offset);
+      let y = _mm_add_epi32(y, nonzero);
+      // divu_pair() on the even and the odd lanes
+      let even = _mm_srl_epi64(_mm_add_epi64(_mm_mul_epu32(y, a), b), div_shift);
+      let odd = _mm_srl_epi64(
+        _mm_add_epi64(_mm_mul_epu32(_mm_srli_epi64(y, 32), a), b), div_shift
+      );
+      let q = _mm_blend_epi16(even, _mm_slli_epi64(odd, 32), 0b1100_1100);
+      _mm_storeu_si128(qcoeffs[i..].as_mut_ptr() as *mut _, _mm_sign_epi32(q, x));
+      i += 4;
+    }
+    for (qc, &c) in qcoeffs[i..coded_tx_size].iter_mut().zip(coeffs[i..].iter()) {
+      let x = c << self.log_tx_scale;
+      *qc = divu_pair(x + x.signum() * self.ac_offset, self.ac_mul_add);
+    }
+
+    let x = coeffs[0] << self.log_tx_scale;
+    qcoeffs[0] = divu_pair(x + x.signum() * self.dc_offset, self.dc_mul_add);
+
+    if qcoeffs.len() > coded_tx_size {
+      for qc in qcoeffs[coded_tx_size..].iter_mut() {
+        *qc = 0;
+      }
+    }
+  }
+
+  /// Quantizer of the coefficient at `pos`, with its quantizer matrix weight
+  #[inline]
+  fn quant(&self, pos: usize) -> u32 {
+    let quant = if pos == 0 { self.dc_quant } else { self.ac_quant };
+    self.qm.map_or(quant, |weights| weighted_quant(quant, weights[pos]))
+  }
+
+  /// Magnitude of the coefficient `coeff` at `pos` rounded to the nearest
+  /// quantizer step, without the dead zone applied by `quantize`.
+  #[inline]
+  pub fn nearest_level(&self, coeff: i32, pos: usize) -> u32 {
+    let quant = self.quant(pos) as u64;
+    let scaled = (coeff.abs() as u64) << self.log_tx_scale;
+    ((scaled + (quant >> 1)) / quant) as u32
+  }
+
+  /// Squared error of reconstructing `coeff` from magnitude `level`,
+  /// in the same units as the transform-domain distortion.
+  #[inline]
+  pub fn level_dist(&self, coeff: i32, level: u32, pos: usize) -> f64 {
+    let quant = self.quant(pos) as i64;
+    let err = ((coeff.abs() as i64) << self.log_tx_scale) - level as i64 * quant;
+    // Shifting by log_tx_scale brings the transform output of every size to
+    // 8 times the scale of the residual.
+    (err * err) as f64 / 64.
+  }
 }
 
+/// Reconstruct the transform coefficients from `coeffs`, weighted by the
+/// quantizer matrix `qm` returned by `qm_weights` if there is one.
 pub fn dequantize(
   qindex: u8, coeffs: &[i32], rcoeffs: &mut [i32], tx_size: TxSize,
-  bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8
+  bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8, qm: Option<&[u8]>
 ) {
   let log_tx_scale = get_log_tx_scale(tx_size) as i32;
-  let offset = (1 << log_tx_scale) - 1;
 
   let dc_quant = dc_q(qindex, dc_delta_q, bit_depth) as i32;
   let ac_quant = ac_q(qindex, ac_delta_q, bit_depth) as i32;
 
+  if let Some(weights) = qm {
+    return dequantize_weighted(
+      dc_quant, ac_quant, log_tx_scale, bit_depth, weights, coeffs, rcoeffs
+    );
+  }
+
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("sse4.1") {
+      return unsafe {
+        dequantize_sse4_1(
+          dc_quant, ac_quant, log_tx_scale, bit_depth, coeffs, rcoeffs
+        )
+      };
+    }
+  }
+
+  dequantize_rs(dc_quant, ac_quant, log_tx_scale, bit_depth, coeffs, rcoeffs);
+}
+
+/// Largest magnitude a dequantized coefficient may take. Decoders clamp to
+/// it before the inverse transform, so the reconstruction only matches
+/// theirs if it does too.
+fn dequant_limit(bit_depth: usize) -> i32 {
+  1 << (7 + bit_depth)
+}
+
+fn dequantize_rs(
+  dc_quant: i32, ac_quant: i32, log_tx_scale: i32, bit_depth: usize,
+  coeffs: &[i32], rcoeffs: &mut [i32]
+) {
+  let offset = (1 << log_tx_scale) - 1;
+  let limit = dequant_limit(bit_depth);
+
   for (i, (r, &c)) in rcoeffs.iter_mut().zip(coeffs.iter()).enumerate() {
     let quant = if i == 0 { dc_quant } else { ac_quant };
-    *r = (c * quant + ((c >> 31) & offset)) >> log_tx_scale;
+    let dq = (c * quant + ((c >> 31) & offset)) >> log_tx_scale;
+    *r = dq.max(-limit).min(limit - 1);
+  }
+}
+
+fn dequantize_weighted(
+  dc_quant: i32, ac_quant: i32, log_tx_scale: i32, bit_depth: usize,
+  weights: &[u8], coeffs: &[i32], rcoeffs: &mut [i32]
+) {
+  let offset = (1 << log_tx_scale) - 1;
+  let limit = dequant_limit(bit_depth);
+
+  for (pos, (r, &c)) in rcoeffs.iter_mut().zip(coeffs.iter()).enumerate() {
+    let quant = if pos == 0 { dc_quant } else { ac_quant };
+    // The coefficients past the coded area of 64-point transforms are zero
+    let quant = weights.get(pos)
+      .map_or(quant, |&weight| weighted_quant(quant as u32, weight) as i32);
+    let dq = (c * quant + ((c >> 31) & offset)) >> log_tx_scale;
+    *r = dq.max(-limit).min(limit - 1);
+  }
+}
+
+#[target_feature(enable = "sse4.1")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn dequantize_sse4_1(
+  dc_quant: i32, ac_quant: i32, log_tx_scale: i32, bit_depth: usize,
+  coeffs: &[i32], rcoeffs: &mut [i32]
+) {
+  let n = coeffs.len().min(rcoeffs.len());
+  let quant = _mm_set_epi32(ac_quant, ac_quant, ac_quant, dc_quant);
+  let ac = _mm_set1_epi32(ac_quant);
+  let offset = _mm_set1_epi32((1 << log_tx_scale) - 1);
+  let shift = _mm_cvtsi32_si128(log_tx_scale);
+  let limit = dequant_limit(bit_depth);
+  let lo = _mm_set1_epi32(-limit);
+  let hi = _mm_set1_epi32(limit - 1);
+
+  let mut i = 0;
+  while i + 4 <= n {
+    let c = _mm_loadu_si128(coeffs[i..].as_ptr() as *const _);
+    let q = if i == 0 { quant } else { ac };
+    let rounding = _mm_and_si128(_mm_srai_epi32(c, 31), offset);
+    let r = _mm_sra_epi32(_mm_add_epi32(_mm_mullo_epi32(c, q), rounding), shift);
+    let r = _mm_min_epi32(_mm_max_epi32(r, lo), hi);
+    _mm_storeu_si128(rcoeffs[i..].as_mut_ptr() as *mut _, r);
+    i += 4;
   }
+  if i < n {
+    let dc_quant = if i == 0 { dc_quant } else { ac_quant };
+    dequantize_rs(
+      dc_quant, ac_quant, log_tx_scale, bit_depth, &coeffs[i..n],
+      &mut rcoeffs[i..n]
+    );
+  }
+}
+
+/// End of block of `coeffs`: one past the position in scan order of the last
+/// nonzero coefficient, given the inverse scan of the transform block.
+pub fn find_eob(coeffs: &[i32], iscan: &[u16]) -> usize {
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("sse4.1") {
+      return unsafe { find_eob_sse4_1(coeffs, iscan) };
+    }
+  }
+
+  find_eob_rs(coeffs, iscan)
+}
+
+fn find_eob_rs(coeffs: &[i32], iscan: &[u16]) -> usize {
+  coeffs.iter().zip(iscan.iter())
+    .filter(|&(&c, _)| c != 0)
+    .map(|(_, &pos)| pos as usize + 1)
+    .max()
+    .unwrap_or(0)
+}
+
+#[target_feature(enable = "sse4.1")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn find_eob_sse4_1(coeffs: &[i32], iscan: &[u16]) -> usize {
+  let n = coeffs.len().min(iscan.len());
+  let one = _mm_set1_epi32(1);
+  let mut eob = _mm_setzero_si128();
+
+  let mut i = 0;
+  while i + 4 <= n {
+    let c = _mm_loadu_si128(coeffs[i..].as_ptr() as *const _);
+    let pos = _mm_cvtepu16_epi32(_mm_loadl_epi64(iscan[i..].as_ptr() as *const _));
+    let zero = _mm_cmpeq_epi32(c, _mm_setzero_si128());
+    eob = _mm_max_epi32(eob, _mm_andnot_si128(zero, _mm_add_epi32(pos, one)));
+    i += 4;
+  }
+  eob = _mm_max_epi32(eob, _mm_shuffle_epi32(eob, 0b01_00_11_10));
+  eob = _mm_max_epi32(eob, _mm_shuffle_epi32(eob, 0b10_11_00_01));
+  let eob = _mm_cvtsi128_si32(eob) as usize;
+
+  eob.max(find_eob_rs(&coeffs[i..n], &iscan[i..n]))
 }
 
 // LUTS --------------------------------------------------------------------
@@ -8,6 +8,7 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::api::ContextInner;
+use crate::api::QuantizerOffsets;
 use crate::encoder::TEMPORAL_DELIMITER;
 use crate::quantize::ac_q;
 use crate::quantize::dc_q;
@@ -15,6 +16,8 @@ use crate::quantize::select_ac_qi;
 use crate::quantize::select_dc_qi;
 use crate::util::clamp;
 use crate::util::Pixel;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 // The number of frame sub-types for which we track distinct parameters.
 // This does not include FRAME_SUBTYPE_SEF, because we don't need to do any
@@ -44,6 +47,17 @@ const TWOPASS_HEADER_SZ: usize = 16 + FRAME_NSUBTYPES*(4 + 1 + 8);
 // 4 byte frame type (show_frame and fti jointly coded) + 4 byte log_scale_q24
 const TWOPASS_PACKET_SZ: usize = 8;
 
+// Magic value at the start of a rate control summary
+const SUMMARY_MAGIC: i32 = 0x53524156;
+// Version number for the rate control summary
+const SUMMARY_VERSION: i32 = 1;
+// 4 byte magic + 4 byte version + 8 byte reservoir fullness + 8 byte rate bias
+//  + FRAME_NSUBTYPES*(8 byte log_scale + 1 byte exp + 4*4 byte filter state)
+//  + (FRAME_NSUBTYPES + 1)*4 byte frame count
+//  + (FRAME_NSUBTYPES - 1)*4 byte inter delay
+const SUMMARY_SZ: usize = 24 + FRAME_NSUBTYPES*(8 + 1 + 16)
+  + (FRAME_NSUBTYPES + 1)*4 + (FRAME_NSUBTYPES - 1)*4;
+
 const SEF_BITS: i64 = 24;
 
 // The scale of AV1 quantizer tables (relative to the pixel domain), i.e., Q3.
@@ -55,6 +69,10 @@ const QSCALE: i32 = 3;
 // 10 is not an exact choice, but rather a good working trade-off.
 const INTER_DELAY_TARGET_MIN: i32 = 10;
 
+// The largest change in log base quantizer from one frame to the next in an
+//  all-intra encode, log2(1.0625) in Q57.
+pub(crate) const ALL_INTRA_MAX_LOG_BASE_Q_CHANGE: i64 = 0x2C_C7ED_F592_262C;
+
 // The base quantizer for a frame is adjusted based on the frame type using the
 //  formula (log_qp*mqp + dqp), where log_qp is the base-2 logarithm of the
 //  "linear" quantizer (the actual factor by which coefficients are divided).
@@ -73,12 +91,21 @@ const MQP_Q12: &[i32; FRAME_NSUBTYPES] = &[
 
 // The ratio 33_810_170.0 / 86_043_287.0 was derived by approximating the median
 // of a change of 15 quantizer steps in the quantizer tables.
-const DQP_Q57: &[i64; FRAME_NSUBTYPES] = &[
-  (-(33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64,
-  (0.0 * (1i64 << 57) as f64) as i64,
-  ((33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64,
-  (2.0 * (33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64
-];
+// It spaces the default dqp of the frame types, which the configuration can
+//  override through EncoderConfig::quantizer_offsets.
+pub(crate) const DQP_STEP: f64 = 33_810_170.0 / 86_043_287.0;
+
+// The dqp of each frame type as Q57, from the log2 offsets of the
+//  configuration.
+fn dqp_q57(offsets: &QuantizerOffsets) -> [i64; FRAME_NSUBTYPES] {
+  let to_q57 = |offset: f64| (offset * (1i64 << 57) as f64) as i64;
+  [
+    to_q57(offsets.key_frame),
+    to_q57(offsets.alt_ref),
+    to_q57(offsets.pyramid_levels[0]),
+    to_q57(offsets.pyramid_levels[1])
+  ]
+}
 
 // Integer binary logarithm of a 64-bit value.
 // v: A 64-bit value.
@@ -312,6 +339,7 @@ const ROUGH_TAN_LOOKUP: &[u16; 18] = &[
 // A digital approximation of a 2nd-order low-pass Bessel follower.
 // We use this for rate control because it has fast reaction time, but is
 //  critically damped.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct IIRBessel2 {
   c: [i32; 2],
   g: i32,
@@ -401,6 +429,7 @@ impl IIRBessel2 {
 }
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 struct RCFrameMetrics {
   // The log base 2 of the scale factor for this frame in Q24 format.
   log_scale_q24: i32,
@@ -423,6 +452,7 @@ impl RCFrameMetrics {
   }
 }
 
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct RCState {
   // The target bit-rate in bits per second.
   target_bitrate: i32,
@@ -493,6 +523,7 @@ pub struct RCState {
   // The number of Show Existing Frames that have been emitted.
   nsef_frames: i64,
   // Buffer for current frame metrics in pass 1.
+  #[cfg_attr(feature = "serialize", serde(with = "header_buffer"))]
   pass1_buffer: [u8; TWOPASS_HEADER_SZ],
   // Whether or not the user has retrieved the pass 1 data for the last frame.
   // For PASS_1 or PASS_2_PLUS_1 encoding, this is set to false after each
@@ -503,6 +534,7 @@ pub struct RCState {
   //  the encode.
   pass1_summary_retrieved: bool,
   // Buffer for current frame metrics in pass 2.
+  #[cfg_attr(feature = "serialize", serde(with = "header_buffer"))]
   pass2_buffer: [u8; TWOPASS_HEADER_SZ],
   // Whether or not the user has provided enough data to encode in the second
   //  pass.
@@ -550,6 +582,33 @@ pub struct RCState {
   scale_window_sum: [i64; FRAME_NSUBTYPES],
 }
 
+// serde only implements its traits for arrays of up to 32 elements, so the
+//  two-pass header buffers are (de)serialized as sequences.
+#[cfg(feature = "serialize")]
+mod header_buffer {
+  use super::TWOPASS_HEADER_SZ;
+  use serde::de::Error;
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(
+    buf: &[u8; TWOPASS_HEADER_SZ], serializer: S
+  ) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(buf.iter())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D
+  ) -> Result<[u8; TWOPASS_HEADER_SZ], D::Error> {
+    let data = Vec::<u8>::deserialize(deserializer)?;
+    if data.len() != TWOPASS_HEADER_SZ {
+      return Err(D::Error::invalid_length(data.len(), &"a two-pass header"));
+    }
+    let mut buf = [0; TWOPASS_HEADER_SZ];
+    buf.copy_from_slice(&data);
+    Ok(buf)
+  }
+}
+
 // TODO: Separate qi values for each color plane.
 pub struct QuantizerParameters {
   // The full-precision, unmodulated log quantizer upon which our modulated
@@ -607,6 +666,20 @@ impl QuantizerParameters {
         * ((log_target_q as f64) * Q57_SQUARE_EXP_SCALE).exp()
     }
   }
+
+  // Limit the target quantizer to the AC quantizers of the indices min_qi
+  //  to max_qi.
+  fn bounded(self, min_qi: u8, max_qi: u8, bit_depth: usize) -> Self {
+    let scale = q57(QSCALE + bit_depth as i32 - 8);
+    let log_qlo = blog64(ac_q(min_qi, 0, bit_depth) as i64) - scale;
+    let log_qhi = blog64(ac_q(max_qi, 0, bit_depth) as i64) - scale;
+    let log_q = clamp(self.log_target_q, log_qlo, log_qhi);
+    if log_q == self.log_target_q {
+      self
+    } else {
+      QuantizerParameters::new_from_log_q(self.log_base_q, log_q, bit_depth)
+    }
+  }
 }
 
 // The parameters that are required by twopass_out().
@@ -623,6 +696,24 @@ pub(crate) struct TwoPassOutParams {
   done_processing: bool
 }
 
+// The default buffer size is set equal to 1.5x the keyframe interval, or 240
+//  frames; whichsever is smaller.
+// For user set values, we enforce a minimum of 12.
+// The interval is short enough to allow reaction, but long enough to allow
+//  looking into the next GOP (avoiding the case where the last frames
+//  before an I-frame get starved), in most cases.
+// The 12 frame minimum gives us some chance to distribute bit estimation
+//  errors in the worst case.
+pub(crate) fn reservoir_frame_delay(
+  max_key_frame_interval: i32, maybe_reservoir_frame_delay: Option<i32>
+) -> i32 {
+  if let Some(reservoir_frame_delay) = maybe_reservoir_frame_delay {
+    reservoir_frame_delay.max(12)
+  } else {
+    ((max_key_frame_interval*3) >> 1).max(240)
+  }
+}
+
 impl RCState {
   pub fn new(
     frame_width: i32, frame_height: i32, framerate_num: i64,
@@ -630,19 +721,9 @@ impl RCState {
     ac_qi_min: u8, max_key_frame_interval: i32,
     maybe_reservoir_frame_delay: Option<i32>
   ) -> RCState {
-    // The default buffer size is set equal to 1.5x the keyframe interval, or 240
-    //  frames; whichsever is smaller.
-    // For user set values, we enforce a minimum of 12.
-    // The interval is short enough to allow reaction, but long enough to allow
-    //  looking into the next GOP (avoiding the case where the last frames
-    //  before an I-frame get starved), in most cases.
-    // The 12 frame minimum gives us some chance to distribute bit estimation
-    //  errors in the worst case.
-    let reservoir_frame_delay = if maybe_reservoir_frame_delay.is_some() {
-        maybe_reservoir_frame_delay.unwrap().max(12)
-    } else {
-        ((max_key_frame_interval*3) >> 1).max(240)
-    };
+    let reservoir_frame_delay = reservoir_frame_delay(
+      max_key_frame_interval, maybe_reservoir_frame_delay
+    );
     // TODO: What are the limits on these?
     let npixels = (frame_width as i64)*(frame_height as i64);
     // Insane framerates or frame sizes mean insane bitrates.
@@ -748,9 +829,22 @@ impl RCState {
   }
 
   // TODO: Separate quantizers for Cb and Cr.
+  // Select the quantizers of the next frame, within the quantizer index
+  //  bounds of the configuration.
   pub(crate) fn select_qi<T: Pixel>(
     &self, ctx: &ContextInner<T>, fti: usize, maybe_prev_log_base_q: Option<i64>
   ) -> QuantizerParameters {
+    self.select_unbounded_qi(ctx, fti, maybe_prev_log_base_q).bounded(
+      ctx.config.min_quantizer,
+      ctx.config.max_quantizer,
+      ctx.config.bit_depth
+    )
+  }
+
+  fn select_unbounded_qi<T: Pixel>(
+    &self, ctx: &ContextInner<T>, fti: usize, maybe_prev_log_base_q: Option<i64>
+  ) -> QuantizerParameters {
+    let dqp_q57 = dqp_q57(&ctx.config.quantizer_offsets);
     // Is rate control active?
     if self.target_bitrate <= 0 {
       // Rate control is not active.
@@ -773,7 +867,7 @@ impl RCState {
       let log_base_q = (log_ac_q + log_dc_q + 1) >> 1;
       // Adjust the quantizer for the frame type, result is Q57:
       let log_q = ((log_base_q + (1i64 << 11)) >> 12) * (MQP_Q12[fti] as i64)
-        + DQP_Q57[fti];
+        + dqp_q57[fti];
       QuantizerParameters::new_from_log_q(log_base_q, log_q, bit_depth)
     } else {
       let mut nframes: [i32; FRAME_NSUBTYPES + 1] = [0; FRAME_NSUBTYPES + 1];
@@ -786,7 +880,7 @@ impl RCState {
         PASS_1 => {
           // Adjust the quantizer for the frame type, result is Q57:
           let log_q = ((self.pass1_log_base_q
-           + (1i64 << 11)) >> 12)*(MQP_Q12[fti] as i64) + DQP_Q57[fti];
+           + (1i64 << 11)) >> 12)*(MQP_Q12[fti] as i64) + dqp_q57[fti];
           return QuantizerParameters::new_from_log_q(self.pass1_log_base_q,
            log_q, ctx.config.bit_depth);
         },
@@ -907,6 +1001,15 @@ impl RCState {
           }
         },
         // Single pass.
+        _ if ctx.config.all_intra => {
+          // Every frame of an all-intra encode is a keyframe, and they
+          //  should all look alike, so each one gets the same share of the
+          //  rate: the buffer window holds nothing but keyframes, and the
+          //  reservoir only makes up for the errors of the rate model.
+          reservoir_tus = self.reservoir_frame_delay;
+          reservoir_frames = reservoir_tus;
+          nframes[FRAME_SUBTYPE_I] = reservoir_frames;
+        }
         _ => {
           // Figure out how to re-distribute bits so that we hit our fullness
           //  target before the last keyframe in our current buffer window
@@ -956,7 +1059,7 @@ impl RCState {
           // Modulate base quantizer by frame type.
           let log_q =
             ((log_base_q + (1i64 << 11)) >> 12)*(MQP_Q12[ftj] as i64)
-            + DQP_Q57[ftj];
+            + dqp_q57[ftj];
           // All the fields here are Q57 except for the exponent, which is
           //  Q6.
           bits += (nframes[ftj] as i64)*
@@ -978,17 +1081,24 @@ impl RCState {
       // If this was not one of the initial frames, limit the change in
       //  base quantizer to within [0.8*Q, 1.2*Q] where Q is the previous
       //  frame's base quantizer.
+      // All-intra encodes, whose neighboring frames should not visibly
+      //  differ in quality, only allow [0.94*Q, 1.06*Q].
       if let Some(prev_log_base_q) = maybe_prev_log_base_q {
+        let max_change = if ctx.config.all_intra {
+          ALL_INTRA_MAX_LOG_BASE_Q_CHANGE
+        } else {
+          0xA4_D3C2_5E68_DC58
+        };
         log_base_q = clamp(
           log_base_q,
-          prev_log_base_q - 0xA4_D3C2_5E68_DC58,
-          prev_log_base_q + 0xA4_D3C2_5E68_DC58
+          prev_log_base_q - max_change,
+          prev_log_base_q + max_change
         );
       }
       // Modulate base quantizer by frame type.
       let mut log_q =
         ((log_base_q + (1i64 << 11)) >> 12)*(MQP_Q12[fti] as i64)
-        + DQP_Q57[fti];
+        + dqp_q57[fti];
       // The above allocation looks only at the total rate we'll accumulate
       //  in the next reservoir_frame_delay frames.
       // However, we could overflow the bit reservoir on the very next
@@ -1048,6 +1158,40 @@ impl RCState {
     }
   }
 
+  // Pick a higher quantizer to re-encode a frame that used far more bits
+  //  than the rate model predicted for its quantizer.
+  // Returns None if the frame stayed within the allowed overshoot, or if its
+  //  quantizer cannot be raised any further, up to max_qi.
+  pub(crate) fn reencode_qi(
+    &self, qps: &QuantizerParameters, fti: usize, bits: i64,
+    overshoot_pct: u32, max_qi: u8, bit_depth: usize
+  ) -> Option<QuantizerParameters> {
+    if self.target_bitrate <= 0 || fti >= FRAME_NSUBTYPES || bits <= 0 {
+      return None;
+    }
+    let exp = self.exp[fti] as i64;
+    let log_q_exp = ((qps.log_target_q + 32) >> 6)*exp;
+    let estimated_bits =
+      bexp64(self.log_scale[fti] + self.log_npixels - log_q_exp);
+    if bits*100 <= estimated_bits*(100 + overshoot_pct as i64) {
+      return None;
+    }
+    // Solve for the quantizer at which the scale measured on this frame
+    //  gives the estimated size.
+    let log_q_exp = log_q_exp + blog64(bits) - blog64(estimated_bits);
+    let max_qi = self.maybe_ac_qi_max.unwrap_or(255).min(max_qi);
+    let log_qhi = blog64(ac_q(max_qi, 0, bit_depth) as i64)
+      - q57(QSCALE + bit_depth as i32 - 8);
+    let log_q = (((log_q_exp + (exp >> 1))/exp) << 6).min(log_qhi);
+    let reencode_qps =
+      QuantizerParameters::new_from_log_q(qps.log_base_q, log_q, bit_depth);
+    if reencode_qps.ac_qi[0] > qps.ac_qi[0] {
+      Some(reencode_qps)
+    } else {
+      None
+    }
+  }
+
   pub fn update_state(
     &mut self, bits: i64, fti: usize, show_frame: bool, log_target_q: i64,
     trial: bool, droppable: bool
@@ -1209,6 +1353,71 @@ impl RCState {
     dropped
   }
 
+  // Credits the bits of a temporal unit whose source frame was dropped
+  //  before reaching the encoder to the frames that follow.
+  // Two-pass encodes follow the temporal units of their first pass instead.
+  pub(crate) fn skip_tu(&mut self) {
+    if self.target_bitrate > 0 && self.twopass_state == PASS_SINGLE {
+      self.reservoir_fullness += self.bits_per_tu;
+      if self.cap_overflow {
+        self.reservoir_fullness =
+          self.reservoir_fullness.min(self.reservoir_max);
+      }
+    }
+  }
+
+  // The fraction of the bit reservoir that is currently available, or None
+  //  when no target bitrate was set.
+  pub(crate) fn buffer_fullness(&self) -> Option<f64> {
+    if self.target_bitrate <= 0 || self.reservoir_max <= 0 {
+      None
+    } else {
+      Some(self.reservoir_fullness as f64 / self.reservoir_max as f64)
+    }
+  }
+
+  // Changes the target bit-rate for the frames selecting their quantizer
+  //  from now on.
+  // A target of 0 disables rate control, while enabling it starts over with
+  //  trial encodes since the frame statistics were not tracked without it.
+  // The reservoir keeps its relative fullness, so that a change does not
+  //  read as a sudden over- or undershoot.
+  // Only single-pass encodes can be retargeted, as two-pass ones follow
+  //  their first pass.
+  pub(crate) fn set_target_bitrate(
+    &mut self, target_bitrate: i32, framerate_num: i64, framerate_den: i64
+  ) -> Result<(), ()> {
+    if target_bitrate < 0 || self.twopass_state != PASS_SINGLE {
+      return Err(());
+    }
+    if self.target_bitrate <= 0 && target_bitrate > 0 {
+      self.nframes = [0; FRAME_NSUBTYPES + 1];
+    }
+    let bits_per_tu = clamp(
+      (target_bitrate as i64)*framerate_den/framerate_num, 40, 0x4000_0000_0000
+    ) - (TEMPORAL_DELIMITER.len()*8) as i64;
+    let reservoir_max = bits_per_tu*(self.reservoir_frame_delay as i64);
+    self.reservoir_fullness = if self.reservoir_max > 0 {
+      ((self.reservoir_fullness as i128)*(reservoir_max as i128)
+       /(self.reservoir_max as i128)) as i64
+    } else {
+      (reservoir_max + 1) >> 1
+    };
+    self.target_bitrate = target_bitrate;
+    self.bits_per_tu = bits_per_tu;
+    self.reservoir_max = reservoir_max;
+    self.reservoir_target = (reservoir_max + 1) >> 1;
+    Ok(())
+  }
+
+  // Changes the range of quantizer indices allowed to rate control.
+  pub(crate) fn set_ac_qi_range(
+    &mut self, ac_qi_min: u8, maybe_ac_qi_max: Option<u8>
+  ) {
+    self.ac_qi_min = ac_qi_min;
+    self.maybe_ac_qi_max = maybe_ac_qi_max;
+  }
+
   pub fn needs_trial_encode(&self, fti: usize) -> bool {
       self.target_bitrate > 0 && self.nframes[fti] == 0
   }
@@ -1570,11 +1779,92 @@ impl RCState {
     }
     Ok(consumed)
   }
+
+  // Serialize the state the rate control has adapted during the encode, for
+  //  the encode of the next chunk of the same video to start from it.
+  pub(crate) fn summary(&self) -> Vec<u8> {
+    fn put(buf: &mut Vec<u8>, val: i64, bytes: usize) {
+      for i in 0..bytes {
+        buf.push((val >> (8*i)) as u8);
+      }
+    }
+    let mut buf = Vec::with_capacity(SUMMARY_SZ);
+    put(&mut buf, SUMMARY_MAGIC as i64, 4);
+    put(&mut buf, SUMMARY_VERSION as i64, 4);
+    put(&mut buf, self.reservoir_fullness, 8);
+    put(&mut buf, self.rate_bias, 8);
+    for fti in 0..FRAME_NSUBTYPES {
+      put(&mut buf, self.log_scale[fti], 8);
+      put(&mut buf, self.exp[fti] as i64, 1);
+      let f = &self.scalefilter[fti];
+      for &v in f.x.iter().chain(f.y.iter()) {
+        put(&mut buf, v as i64, 4);
+      }
+    }
+    for fti in 0..=FRAME_NSUBTYPES {
+      put(&mut buf, self.nframes[fti] as i64, 4);
+    }
+    for &delay in self.inter_delay.iter() {
+      put(&mut buf, delay as i64, 4);
+    }
+    debug_assert!(buf.len() == SUMMARY_SZ);
+    buf
+  }
+
+  // Prime the rate control of a new single-pass encode with a summary from
+  //  the encode of the previous chunk.
+  pub(crate) fn restore_summary(&mut self, buf: &[u8]) -> Result<(), ()> {
+    fn get(buf: &[u8], pos: &mut usize, bytes: usize) -> i64 {
+      let mut ret = 0;
+      for i in 0..bytes {
+        ret |= (buf[*pos + i] as i64) << (8*i);
+      }
+      *pos += bytes;
+      // Sign extend the values narrower than 64 bits.
+      let shift = 64 - 8*bytes;
+      ret << shift >> shift
+    }
+    if self.twopass_state != PASS_SINGLE
+     || self.nencoded_frames + self.nsef_frames > 0
+     || buf.len() != SUMMARY_SZ {
+      Err(())?;
+    }
+    let mut pos = 0;
+    if get(buf, &mut pos, 4) != SUMMARY_MAGIC as i64
+     || get(buf, &mut pos, 4) != SUMMARY_VERSION as i64 {
+      Err(())?;
+    }
+    // The reservoir of the new chunk may be smaller.
+    self.reservoir_fullness = get(buf, &mut pos, 8).min(self.reservoir_max);
+    self.rate_bias = get(buf, &mut pos, 8);
+    for fti in 0..FRAME_NSUBTYPES {
+      self.log_scale[fti] = get(buf, &mut pos, 8);
+      self.exp[fti] = get(buf, &mut pos, 1) as u8;
+      let f = &mut self.scalefilter[fti];
+      for v in f.x.iter_mut().chain(f.y.iter_mut()) {
+        *v = get(buf, &mut pos, 4) as i32;
+      }
+    }
+    for fti in 0..=FRAME_NSUBTYPES {
+      self.nframes[fti] = get(buf, &mut pos, 4) as i32;
+    }
+    for fti in 1..FRAME_NSUBTYPES {
+      let delay = (get(buf, &mut pos, 4) as i32)
+        .max(INTER_DELAY_TARGET_MIN).min(self.inter_delay_target);
+      self.inter_delay[fti - 1] = delay;
+      self.scalefilter[fti].reinit(delay);
+    }
+    debug_assert!(pos == SUMMARY_SZ);
+    Ok(())
+  }
 }
 
 #[cfg(test)]
 mod test {
-  use super::{bexp64, blog64};
+  use super::{
+    bexp64, blog64, QuantizerParameters, RCState, FRAME_SUBTYPE_I,
+    FRAME_SUBTYPE_P
+  };
 
   #[test]
   fn blog64_vectors() -> () {
@@ -1599,4 +1889,46 @@ mod test {
       assert!((bexp64(log_ab) - a * b).abs() < 128);
     }
   }
+
+  #[test]
+  fn summary_round_trip() {
+    let new_state = || {
+      RCState::new(640, 480, 30, 1, 500_000, None, 0, 240, None)
+    };
+    let mut rc = new_state();
+    let log_target_q = 0x2A_0000_0000_0000;
+    rc.update_state(200_000, FRAME_SUBTYPE_I, true, log_target_q, false, false);
+    for _ in 0..10 {
+      rc.update_state(10_000, FRAME_SUBTYPE_P, true, log_target_q, false, false);
+    }
+    let summary = rc.summary();
+
+    let mut primed = new_state();
+    assert!(primed.restore_summary(&summary[1..]).is_err());
+    assert!(primed.restore_summary(&summary).is_ok());
+    assert_eq!(primed.reservoir_fullness, rc.reservoir_fullness);
+    assert_eq!(primed.log_scale, rc.log_scale);
+    assert_eq!(primed.nframes, rc.nframes);
+    assert_eq!(primed.summary(), summary);
+
+    // Only fresh single-pass encodes can be primed.
+    assert!(rc.restore_summary(&summary).is_err());
+  }
+
+  #[test]
+  fn reencode_overshooting_frame() {
+    let rc = RCState::new(640, 480, 30, 1, 500_000, None, 0, 240, None);
+    let log_q = 0x2A_0000_0000_0000;
+    let qps = QuantizerParameters::new_from_log_q(log_q, log_q, 8);
+    let exp = rc.exp[FRAME_SUBTYPE_I] as i64;
+    let estimated_bits = bexp64(
+      rc.log_scale[FRAME_SUBTYPE_I] + rc.log_npixels - ((log_q + 32) >> 6)*exp
+    );
+
+    let fti = FRAME_SUBTYPE_I;
+    assert!(rc.reencode_qi(&qps, fti, estimated_bits, 100, 255, 8).is_none());
+    let reencode_qps =
+      rc.reencode_qi(&qps, fti, estimated_bits*4, 100, 255, 8).unwrap();
+    assert!(reencode_qps.ac_qi[0] > qps.ac_qi[0]);
+  }
 }
@@ -12,14 +12,19 @@
 
 use crate::api::*;
 use crate::cdef::*;
+use crate::compound::{is_wedge_allowed, WEDGE_TYPES};
 use crate::lrf::*;
 use crate::context::*;
+use crate::dist::DistortionMetric;
 use crate::ec::{OD_BITRES, Writer, WriterCounter};
+use crate::entropymode::MAX_TX_DEPTH;
 use crate::header::ReferenceMode;
 use crate::{encode_block_pre_cdef, encode_block_post_cdef};
 use crate::encode_block_with_modes;
-use crate::encoder::FrameInvariants;
+use crate::encoder::{diff, get_qidx, FrameInvariants, MV_FILTER_MARGIN};
 use crate::frame::Frame;
+use crate::intrabc::intrabc_search;
+use crate::palette::Palette;
 use crate::luma_ac;
 use crate::mc::MotionVector;
 use crate::me::*;
@@ -27,12 +32,14 @@ use crate::motion_compensate;
 use crate::partition::*;
 use crate::partition::RefType::*;
 use crate::frame::*;
-use crate::predict::{RAV1E_INTRA_MODES, RAV1E_INTER_MODES_MINIMAL, RAV1E_INTER_COMPOUND_MODES, PredictionMode};
+use crate::predict::{RAV1E_INTRA_MODES, RAV1E_INTER_MODES_MINIMAL, RAV1E_INTER_COMPOUND_MODES, RAV1E_FILTER_INTRA_MODES};
+use crate::predict::{CompoundParams, FilterIntraMode, PredictionMode};
 use crate::Tune;
 use crate::write_tx_blocks;
 use crate::write_tx_tree;
 use crate::tiling::*;
-use crate::transform::{RAV1E_TX_TYPES, TxSet, TxSize, TxType};
+use crate::quantize::QuantizationContext;
+use crate::transform::{forward_transform, RAV1E_TX_TYPES, TX_TYPES, TxSet, TxSize, TxType};
 use crate::util::{AlignedArray, CastFromPrimitive, Pixel, UninitializedAlignedArray};
 use crate::rdo_tables::*;
 
@@ -77,7 +84,10 @@ impl RDOType {
 pub struct RDOOutput {
   pub rd_cost: f64,
   pub part_type: PartitionType,
-  pub part_modes: Vec<RDOPartitionOutput>
+  pub part_modes: Vec<RDOPartitionOutput>,
+  /// The output of each quarter of a `PARTITION_SPLIT`, from the bottom-up
+  /// search
+  pub part_children: Vec<RDOOutput>
 }
 
 #[derive(Clone)]
@@ -88,8 +98,11 @@ pub struct RDOPartitionOutput {
   pub pred_mode_luma: PredictionMode,
   pub pred_mode_chroma: PredictionMode,
   pub pred_cfl_params: CFLParams,
+  pub filter_intra: Option<FilterIntraMode>,
+  pub palette: Option<Palette>,
   pub ref_frames: [RefType; 2],
   pub mvs: [MotionVector; 2],
+  pub compound: CompoundParams,
   pub skip: bool,
   pub tx_size: TxSize,
   pub tx_type: TxType,
@@ -162,19 +175,6 @@ impl RDOTracker {
   }
 }
 
-pub fn estimate_rate(qindex: u8, ts: TxSize, fast_distortion: u64) -> u64 {
-  let bs_index = ts as usize;
-  let q_bin_idx = (qindex as usize)/RDO_QUANT_DIV;
-  let bin_idx_down = ((fast_distortion) / RATE_EST_BIN_SIZE).min((RDO_NUM_BINS - 2) as u64);
-  let bin_idx_up = (bin_idx_down + 1).min((RDO_NUM_BINS - 1) as u64);
-  let x0 = (bin_idx_down * RATE_EST_BIN_SIZE) as i64;
-  let x1 = (bin_idx_up * RATE_EST_BIN_SIZE) as i64;
-  let y0 = RDO_RATE_TABLE[q_bin_idx][bs_index][bin_idx_down as usize] as i64;
-  let y1 = RDO_RATE_TABLE[q_bin_idx][bs_index][bin_idx_up as usize] as i64;
-  let slope = ((y1 - y0) << 8) / (x1 - x0);
-  (y0 + (((fast_distortion as i64 - x0) * slope) >> 8)).max(0) as u64
-}
-
 #[allow(unused)]
 fn cdef_dist_wxh_8x8<T: Pixel>(
   src1: &PlaneRegion<'_, T>, src2: &PlaneRegion<'_, T>, bit_depth: usize
@@ -236,6 +236,19 @@ pub fn sse_wxh<T: Pixel>(
   assert!(w & (MI_SIZE - 1) == 0);
   assert!(h & (MI_SIZE - 1) == 0);
 
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("sse2") {
+      return unsafe { sse2::sse_wxh(src1, src2, w, h) };
+    }
+  }
+
+  sse_wxh_rs(src1, src2, w, h)
+}
+
+fn sse_wxh_rs<T: Pixel>(
+  src1: &PlaneRegion<'_, T>, src2: &PlaneRegion<'_, T>, w: usize, h: usize
+) -> u64 {
   let mut sse: u64 = 0;
   for j in 0..h {
     let s1 = &src1[j][..w];
@@ -253,6 +266,214 @@ pub fn sse_wxh<T: Pixel>(
   sse
 }
 
+/// The SSE of any block at any bit depth, 8 pixels at a time
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sse2 {
+  use super::*;
+  #[cfg(target_arch = "x86")]
+  use std::arch::x86::*;
+  #[cfg(target_arch = "x86_64")]
+  use std::arch::x86_64::*;
+  use std::mem::size_of;
+
+  // Sums the squares of 8 differences of at most 12 bits into 4 lanes, which
+  // add up to less than 2^32 for rows of up to 128 pixels
+  #[target_feature(enable = "sse2")]
+  unsafe fn sum_squares(diff: __m128i, acc: __m128i) -> __m128i {
+    _mm_add_epi32(acc, _mm_madd_epi16(diff, diff))
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn row_sse_u8(s1: &[u8], s2: &[u8]) -> u64 {
+    let zero = _mm_setzero_si128();
+    let mut acc = zero;
+    let mut c = 0;
+    while c + 8 <= s1.len() {
+      let a = _mm_loadl_epi64(s1[c..].as_ptr() as *const _);
+      let b = _mm_loadl_epi64(s2[c..].as_ptr() as *const _);
+      let diff =
+        _mm_sub_epi16(_mm_unpacklo_epi8(a, zero), _mm_unpacklo_epi8(b, zero));
+      acc = sum_squares(diff, acc);
+      c += 8;
+    }
+    row_sse_finish(acc, &s1[c..], &s2[c..])
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn row_sse_u16(s1: &[u16], s2: &[u16]) -> u64 {
+    let mut acc = _mm_setzero_si128();
+    let mut c = 0;
+    while c + 8 <= s1.len() {
+      let a = _mm_loadu_si128(s1[c..].as_ptr() as *const _);
+      let b = _mm_loadu_si128(s2[c..].as_ptr() as *const _);
+      acc = sum_squares(_mm_sub_epi16(a, b), acc);
+      c += 8;
+    }
+    row_sse_finish(acc, &s1[c..], &s2[c..])
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn row_sse_finish<T: Pixel>(acc: __m128i, s1: &[T], s2: &[T]) -> u64 {
+    let acc = _mm_add_epi32(acc, _mm_srli_si128(acc, 8));
+    let acc = _mm_add_epi32(acc, _mm_srli_si128(acc, 4));
+    let mut sse = _mm_cvtsi128_si32(acc) as u32 as u64;
+    for (&a, &b) in s1.iter().zip(s2) {
+      let c = i32::cast_from(a) - i32::cast_from(b);
+      sse += (c * c) as u64;
+    }
+    sse
+  }
+
+  #[target_feature(enable = "sse2")]
+  pub(super) unsafe fn sse_wxh<T: Pixel>(
+    src1: &PlaneRegion<'_, T>, src2: &PlaneRegion<'_, T>, w: usize, h: usize
+  ) -> u64 {
+    assert!(w <= 128);
+    let mut sse = 0;
+    for j in 0..h {
+      let (s1, s2) = (&src1[j][..w], &src2[j][..w]);
+      sse += if size_of::<T>() == 1 {
+        row_sse_u8(
+          &*(s1 as *const [T] as *const [u8]),
+          &*(s2 as *const [T] as *const [u8])
+        )
+      } else {
+        row_sse_u16(
+          &*(s1 as *const [T] as *const [u16]),
+          &*(s2 as *const [T] as *const [u16])
+        )
+      };
+    }
+    sse
+  }
+
+  #[cfg(test)]
+  mod test {
+    use super::*;
+    use rand::random;
+
+    fn check_sse<T: Pixel>(bit_depth: usize) {
+      let mut src1 = Plane::<T>::new(160, 128, 0, 0, 0, 0);
+      let mut src2 = Plane::<T>::new(160, 128, 0, 0, 0, 0);
+      for p in [&mut src1, &mut src2].iter_mut() {
+        for v in p.data.iter_mut() {
+          *v = T::cast_from(random::<u16>() >> (16 - bit_depth));
+        }
+      }
+      // The largest differences, which fill the 32-bit lanes the most
+      let max = T::cast_from((1u16 << bit_depth) - 1);
+      for (i, (a, b)) in
+        src1.data.iter_mut().zip(src2.data.iter_mut()).enumerate().take(160)
+      {
+        *a = if i % 2 == 0 { max } else { T::cast_from(0u8) };
+        *b = if i % 2 == 0 { T::cast_from(0u8) } else { max };
+      }
+      for &(w, h) in &[(4, 4), (8, 8), (12, 4), (32, 16), (64, 64), (128, 128)]
+      {
+        for &x in &[0, 1, 7, 24] {
+          let area = Area::StartingAt { x, y: 0 };
+          let (src1, src2) = (src1.region(area), src2.region(area));
+          assert_eq!(sse_wxh_rs(&src1, &src2, w, h), unsafe {
+            sse_wxh(&src1, &src2, w, h)
+          });
+        }
+      }
+    }
+
+    #[test]
+    fn sse_wxh_sse2_matches_rust() {
+      if !is_x86_feature_detected!("sse2") {
+        return;
+      }
+      check_sse::<u8>(8);
+      check_sse::<u16>(10);
+      check_sse::<u16>(12);
+    }
+  }
+}
+
+// Variance of a wxh block, in squared pixel units
+pub fn variance_wxh<T: Pixel>(
+  src: &PlaneRegion<'_, T>, w: usize, h: usize
+) -> f64 {
+  let mut sum: i64 = 0;
+  let mut sum_sq: i64 = 0;
+  for j in 0..h {
+    for &p in &src[j][..w] {
+      let v: i32 = p.as_();
+      sum += v as i64;
+      sum_sq += (v * v) as i64;
+    }
+  }
+  let n = (w * h) as f64;
+  (sum_sq as f64 - (sum * sum) as f64 / n) / n
+}
+
+// Mean variance of the 8x8 blocks of a plane
+pub fn mean_block_variance<T: Pixel>(
+  plane: &Plane<T>, width: usize, height: usize
+) -> f64 {
+  let region = plane.as_region();
+  let (cols, rows) = (width / 8, height / 8);
+  if cols == 0 || rows == 0 {
+    return 0.0;
+  }
+  let mut sum = 0.0;
+  for by in 0..rows {
+    for bx in 0..cols {
+      let block = region.subregion(Area::StartingAt {
+        x: (bx * 8) as isize,
+        y: (by * 8) as isize
+      });
+      sum += variance_wxh(&block, 8, 8);
+    }
+  }
+  sum / (cols * rows) as f64
+}
+
+// Squared error weighted per 8x8 block like SSIM weights it: errors in flat
+//  blocks are amplified and errors in textured blocks attenuated, relative to
+//  a block of the mean variance of the frame.
+fn ssim_weighted_sse<T: Pixel>(
+  mean_variance: f64, src: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>,
+  w: usize, h: usize, bit_depth: usize
+) -> u64 {
+  // The C2 constant of SSIM, (0.03*255)^2, at the coding bit depth
+  let c2 = 58.5 * (1 << (2 * (bit_depth - 8))) as f64;
+  let (step_w, step_h) = (w.min(8), h.min(8));
+  let mut sum = 0.0;
+  for y in (0..h).step_by(step_h) {
+    for x in (0..w).step_by(step_w) {
+      let area = Area::StartingAt { x: x as isize, y: y as isize };
+      let src_block = src.subregion(area);
+      let sse = sse_wxh(&src_block, &rec.subregion(area), step_w, step_h);
+      let variance = variance_wxh(&src_block, step_w, step_h);
+      sum += sse as f64 * (mean_variance + c2) / (variance + c2);
+    }
+  }
+  (sum + 0.5) as u64
+}
+
+// Penalty for the texture energy the reconstruction lost or gained, which
+//  the squared error underrates: it favors smoothing the details out over
+//  keeping them with the right energy at slightly wrong positions, which
+//  looks better.
+fn psy_distortion<T: Pixel>(
+  fi: &FrameInvariants<T>, src: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>,
+  w: usize, h: usize
+) -> u64 {
+  let strength = fi.config.psy_rd_strength;
+  if strength == 0 {
+    return 0;
+  }
+  let src_energy = get_ac_energy(src, w, h) as f64;
+  let rec_energy = get_ac_energy(rec, w, h) as f64;
+  // Weight the energy difference, in pixel units, by the quantizer step
+  //  implied by lambda to bring it to the scale of the squared error
+  let scale = (strength as f64 / 8.0) * fi.lambda.sqrt();
+  ((src_energy - rec_energy).abs() * scale + 0.5) as u64
+}
+
 // Compute the pixel-domain distortion for an encode
 fn compute_distortion<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, w_y: usize, h_y: usize,
@@ -269,19 +490,30 @@ fn compute_distortion<T: Pixel>(
         w_y,
         h_y,
         fi.sequence.bit_depth
+      ) + psy_distortion(fi, &input_region, &rec_region, w_y, h_y)
+    }
+    Tune::Ssim => {
+      ssim_weighted_sse(
+        ts.mean_block_variance,
+        &input_region,
+        &rec_region,
+        w_y,
+        h_y,
+        fi.sequence.bit_depth
       )
     }
-    Tune::Psnr | Tune::Psychovisual => {
-      sse_wxh(
+    Tune::Psnr | Tune::Psychovisual | Tune::Screen => {
+      fi.config.rdo_metric().distortion(
         &input_region,
         &rec_region,
         w_y,
-        h_y
+        h_y,
+        fi.sequence.bit_depth
       )
     }
   };
 
-  if !luma_only {
+  if !luma_only && fi.sequence.num_planes() > 1 {
     let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
 
     let mask = !(MI_SIZE - 1);
@@ -295,17 +527,20 @@ fn compute_distortion<T: Pixel>(
 
     // Add chroma distortion only when it is available
     if w_uv > 0 && h_uv > 0 {
+      let mut chroma_distortion = 0;
       for p in 1..3 {
-        distortion += sse_wxh(
+        chroma_distortion += fi.config.rdo_metric().distortion(
           &ts.input_tile.planes[p].subregion(Area::BlockStartingAt { bo: tile_bo }),
           &ts.rec.planes[p].subregion(Area::BlockStartingAt { bo: tile_bo }),
           w_uv,
-          h_uv
+          h_uv,
+          fi.sequence.bit_depth
         );
       }
+      distortion += chroma_weighted(fi, chroma_distortion);
     };
   }
-  distortion
+  activity_weighted(ts, tile_bo, w_y, h_y, distortion)
 }
 
 // Compute the transform-domain distortion for an encode
@@ -327,7 +562,7 @@ fn compute_tx_distortion<T: Pixel>(
     tx_dist as u64
   };
 
-  if !luma_only && skip {
+  if !luma_only && skip && fi.sequence.num_planes() > 1 {
     let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
 
     let mask = !(MI_SIZE - 1);
@@ -341,17 +576,36 @@ fn compute_tx_distortion<T: Pixel>(
 
     // Add chroma distortion only when it is available
     if w_uv > 0 && h_uv > 0 {
+      let mut chroma_distortion = 0;
       for p in 1..3 {
-        distortion += sse_wxh(
+        chroma_distortion += sse_wxh(
           &ts.input_tile.planes[p].subregion(Area::BlockStartingAt { bo: tile_bo }),
           &ts.rec.planes[p].subregion(Area::BlockStartingAt { bo: tile_bo }),
           w_uv,
           h_uv
         );
       }
+      distortion += chroma_weighted(fi, chroma_distortion);
     }
   }
-  distortion
+  activity_weighted(ts, tile_bo, w_y, h_y, distortion)
+}
+
+// Weight the distortion of the chroma planes relative to the luma one
+pub(crate) fn chroma_weighted<T: Pixel>(
+  fi: &FrameInvariants<T>, distortion: u64
+) -> u64 {
+  distortion * fi.config.chroma_distortion_weight as u64 / 8
+}
+
+// Weight the distortion of a block by the activity mask, which is
+//  equivalent to scaling lambda for the block
+fn activity_weighted<T: Pixel>(
+  ts: &TileStateMut<'_, T>, tile_bo: BlockOffset, w: usize, h: usize,
+  distortion: u64
+) -> u64 {
+  let bo = ts.to_frame_block_offset(tile_bo);
+  (distortion as f64 * ts.activity_mask.weight(bo, w, h) + 0.5) as u64
 }
 
 fn compute_rd_cost<T: Pixel>(fi: &FrameInvariants<T>, rate: u32, distortion: u64) -> f64 {
@@ -362,7 +616,9 @@ fn compute_rd_cost<T: Pixel>(fi: &FrameInvariants<T>, rate: u32, distortion: u64
 pub fn rdo_tx_size_type<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, bsize: BlockSize, tile_bo: BlockOffset,
-  luma_mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2], skip: bool
+  luma_mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
+  compound: CompoundParams, filter_intra: Option<FilterIntraMode>,
+  palette: Option<Palette>, skip: bool
 ) -> (TxSize, TxType) {
   let mut tx_size = max_txsize_rect_lookup[bsize as usize];
   let mut best_tx_type = TxType::DCT_DCT;
@@ -370,20 +626,36 @@ pub fn rdo_tx_size_type<T: Pixel>(
   let mut best_rd = std::f64::MAX;
   let is_inter = !luma_mode.is_intra();
 
+  // Inter blocks are split uniformly, as far as their transform partition
+  // can signal; skipped ones code no transform size at all
   let do_rdo_tx_size = fi.tx_mode_select && fi.config.speed_settings.rdo_tx_decision &&
-                luma_mode.is_intra();
-  let rdo_tx_depth = if do_rdo_tx_size { 2 } else { 0 };
+                !(is_inter && skip);
+  let max_tx_depth = if is_inter { MAX_VARTX_DEPTH } else { MAX_TX_DEPTH };
+  let rdo_tx_depth = if do_rdo_tx_size {
+    fi.config.speed_settings.tx_search_depth.min(max_tx_depth)
+  } else {
+    0
+  };
   let cw_checkpoint = cw.checkpoint();
 
   for _ in 0..=rdo_tx_depth {
     let tx_set = get_tx_set(tx_size, is_inter, fi.use_reduced_tx_set);
 
+    // The identity transform is always tried on screen content, where it
+    // suits sharp edges better than the DCT
+    let screen_content = fi.allow_screen_content_tools != 0;
     let do_rdo_tx_type = tx_set > TxSet::TX_SET_DCTONLY &&
-        fi.config.speed_settings.rdo_tx_decision && !skip;
+        (fi.config.speed_settings.rdo_tx_decision || screen_content) && !skip;
 
     if !do_rdo_tx_size && !do_rdo_tx_type { return (best_tx_size, best_tx_type) };
 
-    let tx_types = if do_rdo_tx_type { RAV1E_TX_TYPES } else { &[TxType::DCT_DCT] };
+    let tx_types = if do_rdo_tx_type && fi.config.speed_settings.rdo_tx_decision {
+      RAV1E_TX_TYPES
+    } else if do_rdo_tx_type {
+      &[TxType::DCT_DCT, TxType::IDTX]
+    } else {
+      &[TxType::DCT_DCT]
+    };
 
     // Luma plane transform type decision
     let (tx_type, rd_cost) =
@@ -394,6 +666,9 @@ pub fn rdo_tx_size_type<T: Pixel>(
           luma_mode,
           ref_frames,
           mvs,
+          compound,
+          filter_intra,
+          palette,
           bsize,
           tile_bo,
           tx_size,
@@ -424,10 +699,13 @@ struct EncodingSettings {
   mode_luma: PredictionMode,
   mode_chroma: PredictionMode,
   cfl_params: CFLParams,
+  filter_intra: Option<FilterIntraMode>,
+  palette: Option<Palette>,
   skip: bool,
   rd: f64,
   ref_frames: [RefType; 2],
   mvs: [MotionVector; 2],
+  compound: CompoundParams,
   tx_size: TxSize,
   tx_type: TxType
 }
@@ -438,10 +716,13 @@ impl Default for EncodingSettings {
       mode_luma: PredictionMode::DC_PRED,
       mode_chroma: PredictionMode::DC_PRED,
       cfl_params: CFLParams::default(),
+      filter_intra: None,
+      palette: None,
       skip: false,
       rd: std::f64::MAX,
       ref_frames: [INTRA_FRAME, NONE_FRAME],
       mvs: [MotionVector::default(); 2],
+      compound: CompoundParams::default(),
       tx_size: TxSize::TX_4X4,
       tx_type: TxType::DCT_DCT
     }
@@ -460,12 +741,15 @@ fn luma_chroma_mode_rdo<T: Pixel> (luma_mode: PredictionMode,
   best: &mut EncodingSettings,
   mvs: [MotionVector; 2],
   ref_frames: [RefType; 2],
+  compound: CompoundParams,
+  filter_intra: Option<FilterIntraMode>,
+  palette: Option<Palette>,
   mode_set_chroma: &[PredictionMode],
   luma_mode_is_intra: bool,
   mode_context: usize,
   mv_stack: &ArrayVec<[CandidateMV; 9]>) {
-    let (tx_size, mut tx_type) = rdo_tx_size_type(
-      fi, ts, cw, bsize, tile_bo, luma_mode, ref_frames, mvs, false,
+    let (mut tx_size, mut tx_type) = rdo_tx_size_type(
+      fi, ts, cw, bsize, tile_bo, luma_mode, ref_frames, mvs, compound, filter_intra, palette, false,
     );
 
     // Get block luma and chroma dimensions
@@ -474,7 +758,8 @@ fn luma_chroma_mode_rdo<T: Pixel> (luma_mode: PredictionMode,
 
     let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
 
-    let is_chroma_block = has_chroma(tile_bo, bsize, xdec, ydec);
+    let is_chroma_block =
+      fi.sequence.num_planes() > 1 && has_chroma(tile_bo, bsize, xdec, ydec);
     
     // Find the best chroma prediction mode for the current luma prediction mode
     let mut chroma_rdo = |skip: bool| {
@@ -482,7 +767,10 @@ fn luma_chroma_mode_rdo<T: Pixel> (luma_mode: PredictionMode,
         let wr = &mut WriterCounter::new();
         let tell = wr.tell_frac();
 
-        if skip { tx_type = TxType::DCT_DCT; };
+        if skip {
+          tx_size = max_txsize_rect_lookup[bsize as usize];
+          tx_type = TxType::DCT_DCT;
+        };
 
         if bsize >= BlockSize::BLOCK_8X8 && bsize.is_sqr() {
           cw.write_partition(wr, tile_bo, PartitionType::PARTITION_NONE, bsize);
@@ -491,7 +779,7 @@ fn luma_chroma_mode_rdo<T: Pixel> (luma_mode: PredictionMode,
         // TODO(yushin): luma and chroma would have different decision based on chroma format
         let need_recon_pixel = luma_mode_is_intra && tx_size.block_size() != bsize;
 
-        encode_block_pre_cdef(&fi.sequence, ts, cw, wr, bsize, tile_bo, skip);
+        encode_block_pre_cdef(fi, ts, cw, wr, bsize, tile_bo, skip);
         let tx_dist =
           encode_block_post_cdef(
             fi,
@@ -502,10 +790,13 @@ fn luma_chroma_mode_rdo<T: Pixel> (luma_mode: PredictionMode,
             chroma_mode,
             ref_frames,
             mvs,
+            compound,
             bsize,
             tile_bo,
             skip,
             CFLParams::default(),
+            filter_intra,
+            palette,
             tx_size,
             tx_type,
             mode_context,
@@ -546,6 +837,9 @@ fn luma_chroma_mode_rdo<T: Pixel> (luma_mode: PredictionMode,
           best.mode_chroma = chroma_mode;
           best.ref_frames = ref_frames;
           best.mvs = mvs;
+          best.compound = compound;
+          best.filter_intra = filter_intra;
+          best.palette = palette;
           best.skip = skip;
           best.tx_size = tx_size;
           best.tx_type = tx_type;
@@ -562,6 +856,30 @@ fn luma_chroma_mode_rdo<T: Pixel> (luma_mode: PredictionMode,
     };
   }
 
+/// Whether the prediction of a block at `frame_bo` of the area refreshed by
+/// the intra refresh wave stays `MV_FILTER_MARGIN` pixels before the
+/// band at `band_start` in its references
+fn within_refreshed_area(
+  frame_bo: BlockOffset, bsize: BlockSize, ref_frames: [RefType; 2],
+  mvs: [MotionVector; 2], direction: IntraRefreshDirection, band_start: usize
+) -> bool {
+  let limit = band_start as isize - MV_FILTER_MARGIN as isize;
+  ref_frames.iter().zip(mvs.iter()).filter(|&(&r, _)| r != NONE_FRAME).all(
+    |(_, mv)| {
+      let (pos, len, offset) = match direction {
+        IntraRefreshDirection::Columns => {
+          (frame_bo.x << MI_SIZE_LOG2, bsize.width(), mv.col)
+        }
+        IntraRefreshDirection::Rows => {
+          (frame_bo.y << MI_SIZE_LOG2, bsize.height(), mv.row)
+        }
+      };
+      // Motion vectors are in 1/8 pixel units
+      (pos + len) as isize + (offset as isize >> 3) <= limit
+    }
+  )
+}
+
 // RDO-based mode decision
 pub fn rdo_mode_decision<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
@@ -575,7 +893,8 @@ pub fn rdo_mode_decision<T: Pixel>(
   let h = bsize.height();
 
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
-  let is_chroma_block = has_chroma(tile_bo, bsize, xdec, ydec);
+  let is_chroma_block =
+    fi.sequence.num_planes() > 1 && has_chroma(tile_bo, bsize, xdec, ydec);
 
   let cw_checkpoint = cw.checkpoint();
 
@@ -598,7 +917,28 @@ pub fn rdo_mode_decision<T: Pixel>(
     RDOType::PixelDistRealRate
   };
 
-  if fi.frame_type == FrameType::INTER {
+  // With the intra refresh, the blocks of the band are coded intra, and the
+  // ones the wave has passed predict from the refreshed area only
+  let frame_bo = ts.to_frame_block_offset(tile_bo);
+  let mut in_refresh_band = false;
+  let mut refreshed = None;
+  let mut no_top_right = false;
+  if let Some((direction, start, end)) = fi.intra_refresh_band() {
+    let (pos, len, size) = match direction {
+      IntraRefreshDirection::Columns => (frame_bo.x << MI_SIZE_LOG2, w, fi.width),
+      IntraRefreshDirection::Rows => (frame_bo.y << MI_SIZE_LOG2, h, fi.height),
+    };
+    if pos >= start && pos < end {
+      in_refresh_band = true;
+      // The pixels above and to the right of the band are not refreshed yet
+      no_top_right = direction == IntraRefreshDirection::Columns
+        && pos + len == end && end < size;
+    } else if pos < start {
+      refreshed = Some((direction, start));
+    }
+  }
+
+  if fi.frame_type == FrameType::INTER && !in_refresh_band {
     for i in ALL_INTER_REFS.iter() {
       // Don't search LAST3 since it's used only for probs
       if *i == LAST3_FRAME { continue; }
@@ -617,7 +957,7 @@ pub fn rdo_mode_decision<T: Pixel>(
     assert!(!ref_frames_set.is_empty());
   }
 
-  let mut inter_mode_set = ArrayVec::<[(PredictionMode, usize); 20]>::new();
+  let mut inter_mode_set = ArrayVec::<[(PredictionMode, usize); 48]>::new();
   let mut mv_stacks = ArrayVec::<[_; 20]>::new();
   let mut mode_contexts = ArrayVec::<[_; 7]>::new();
 
@@ -653,20 +993,14 @@ pub fn rdo_mode_decision<T: Pixel>(
       for &x in RAV1E_INTER_MODES_MINIMAL {
         inter_mode_set.push((x, i));
       }
-      if !mv_stack.is_empty() {
-        inter_mode_set.push((PredictionMode::NEAR0MV, i));
-      }
-      if mv_stack.len() >= 2 {
-        inter_mode_set.push((PredictionMode::GLOBALMV, i));
-      }
+      inter_mode_set.push((PredictionMode::GLOBALMV, i));
+      // Every candidate after the nearest one can be coded as a near motion
+      // vector through its drl index
       let include_near_mvs = fi.config.speed_settings.include_near_mvs;
-      if include_near_mvs {
-        if mv_stack.len() >= 3 {
-          inter_mode_set.push((PredictionMode::NEAR1MV, i));
-        }
-        if mv_stack.len() >= 4 {
-          inter_mode_set.push((PredictionMode::NEAR2MV, i));
-        }
+      let num_near_mvs = if include_near_mvs { 3 } else { 1 };
+      for &x in [PredictionMode::NEAR0MV, PredictionMode::NEAR1MV, PredictionMode::NEAR2MV]
+        .iter().take(num_near_mvs.min(mv_stack.len().saturating_sub(1))) {
+        inter_mode_set.push((x, i));
       }
       if !mv_stack.iter().take(if include_near_mvs {4} else {2})
         .any(|ref x| x.this_mv.row == mvs_from_me[i][0].row && x.this_mv.col == mvs_from_me[i][0].col)
@@ -702,6 +1036,13 @@ pub fn rdo_mode_decision<T: Pixel>(
     assert!(inter_mode_set.is_empty());
   }
 
+  // Tiles decoded independently do not predict from the other tiles
+  let mv_range = if fi.config.tile_constrained_mvs {
+    Some(get_mv_range(fi, ts, frame_bo, w, h))
+  } else {
+    None
+  };
+
   inter_mode_set.iter().for_each(|&(luma_mode, i)| {
     let mvs = match luma_mode {
       PredictionMode::NEWMV | PredictionMode::NEW_NEWMV => mvs_from_me[i],
@@ -722,17 +1063,69 @@ pub fn rdo_mode_decision<T: Pixel>(
       PredictionMode::NEW_NEARESTMV => [mvs_from_me[i][0], mv_stacks[i][0].comp_mv],
       _ => [MotionVector::default(); 2]
     };
+    if let Some((direction, start)) = refreshed {
+      if !within_refreshed_area(frame_bo, bsize, ref_frames_set[i], mvs, direction, start) {
+        return;
+      }
+    }
+    if let Some((mvx_min, mvx_max, mvy_min, mvy_max)) = mv_range {
+      let outside = ref_frames_set[i].iter().zip(mvs.iter())
+        .filter(|&(&r, _)| r != NONE_FRAME)
+        .any(|(_, mv)| {
+          (mv.col as isize) < mvx_min || (mv.col as isize) > mvx_max
+            || (mv.row as isize) < mvy_min || (mv.row as isize) > mvy_max
+        });
+      if outside {
+        return;
+      }
+    }
     let mode_set_chroma = ArrayVec::from([luma_mode]);
 
-    luma_chroma_mode_rdo(luma_mode, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best, mvs, ref_frames_set[i], &mode_set_chroma, false,
-             mode_contexts[i], &mv_stacks[i]);
+    luma_chroma_mode_rdo(luma_mode, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best, mvs, ref_frames_set[i],
+             CompoundParams::default(), None, None, &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i]);
   });
 
+  // Try other blendings of the best compound prediction than the average
+  if best.ref_frames[1] != NONE_FRAME {
+    let i = ref_frames_set.iter().position(|&r| r == best.ref_frames).unwrap();
+    let (luma_mode, ref_frames, mvs) = (best.mode_luma, best.ref_frames, best.mvs);
+    let mut compounds = ArrayVec::<[_; 4]>::new();
+    if fi.sequence.enable_jnt_comp {
+      compounds.push(CompoundParams::distance());
+    }
+    if fi.sequence.enable_masked_compound {
+      compounds.push(CompoundParams::diffwtd(false));
+      compounds.push(CompoundParams::diffwtd(true));
+      if is_wedge_allowed(bsize) {
+        compounds.push(rdo_wedge_search(fi, ts, cw, luma_mode, ref_frames, mvs, bsize, tile_bo));
+      }
+    }
+    let mode_set_chroma = ArrayVec::from([luma_mode]);
+
+    for &compound in compounds.iter() {
+      luma_chroma_mode_rdo(luma_mode, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best, mvs, ref_frames,
+                           compound, None, None, &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i]);
+    }
+  }
+
+  if fi.allow_intrabc {
+    if let Some(dv) = intrabc_search(fi, ts, bsize, tile_bo) {
+      let ref_frames = [INTRA_FRAME, NONE_FRAME];
+      let mut mv_stack = ArrayVec::<[CandidateMV; 9]>::new();
+      let mode_context = cw.find_mvrefs(tile_bo, ref_frames, &mut mv_stack, bsize, fi, false);
+      let mode_set_chroma = ArrayVec::from([PredictionMode::INTRABC]);
+
+      luma_chroma_mode_rdo(PredictionMode::INTRABC, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best,
+                           [dv, MotionVector::default()], ref_frames, CompoundParams::default(), None, None, &mode_set_chroma, false,
+                           mode_context, &mv_stack);
+    }
+  }
+
   if !best.skip {
     let tx_size = bsize.tx_size();
 
     // Reduce number of prediction modes at higher speed levels
-    let num_modes_rdo = if (fi.frame_type == FrameType::KEY
+    let num_modes_rdo = if (fi.intra_only
                             && fi.config.speed_settings.prediction_modes
                             >= PredictionModesSetting::ComplexKeyframes)
       || (fi.frame_type == FrameType::INTER
@@ -744,12 +1137,21 @@ pub fn rdo_mode_decision<T: Pixel>(
       3
     };
 
-    let intra_mode_set = RAV1E_INTRA_MODES;
+    // The directional modes below the vertical predict from above right
+    let intra_mode_set = RAV1E_INTRA_MODES
+      .iter()
+      .cloned()
+      .filter(|&mode| {
+        !no_top_right
+          || (mode != PredictionMode::D45_PRED && mode != PredictionMode::D63_PRED)
+      })
+      .collect::<ArrayVec<[_; INTRA_MODES]>>();
     let mut sads = {
       let edge_buf = {
         let rec = &ts.rec.planes[0].as_const();
         let po = tile_bo.plane_offset(&rec.plane_cfg);
-        get_intra_edges(rec, po, tx_size, fi.sequence.bit_depth, None)
+        let partition = cw.bc.blocks[tile_bo].partition;
+        get_intra_edges(rec, po, tx_size, bsize, partition, fi.sequence.bit_depth, None)
       };
       intra_mode_set
         .iter()
@@ -816,9 +1218,36 @@ pub fn rdo_mode_decision<T: Pixel>(
       if is_chroma_block && luma_mode != PredictionMode::DC_PRED {
         mode_set_chroma.push(PredictionMode::DC_PRED);
       }
-      luma_chroma_mode_rdo(luma_mode, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best, mvs, ref_frames, &mode_set_chroma, true,
-                           0, &ArrayVec::<[CandidateMV; 9]>::new());
+      luma_chroma_mode_rdo(luma_mode, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best, mvs, ref_frames,
+                           CompoundParams::default(), None, None, &mode_set_chroma, true, 0, &ArrayVec::<[CandidateMV; 9]>::new());
     });
+
+    // Filter intra modes are signaled as variants of DC_PRED
+    if fi.sequence.enable_filter_intra && bsize.filter_intra_allowed() {
+      let luma_mode = PredictionMode::DC_PRED;
+      let mvs = [MotionVector::default(); 2];
+      let ref_frames = [INTRA_FRAME, NONE_FRAME];
+      let mode_set_chroma = [PredictionMode::DC_PRED];
+      for &filter_intra in RAV1E_FILTER_INTRA_MODES {
+        luma_chroma_mode_rdo(luma_mode, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best, mvs, ref_frames,
+                             CompoundParams::default(), Some(filter_intra), None, &mode_set_chroma, true, 0,
+                             &ArrayVec::<[CandidateMV; 9]>::new());
+      }
+    }
+
+    // So are palettes, made of the colors of the source block
+    if fi.allow_screen_content_tools != 0 && bsize.palette_allowed() {
+      let input = ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo });
+      if let Some(palette) = Palette::from_block(&input, bsize.width(), bsize.height()) {
+        let luma_mode = PredictionMode::DC_PRED;
+        let mvs = [MotionVector::default(); 2];
+        let ref_frames = [INTRA_FRAME, NONE_FRAME];
+        let mode_set_chroma = [PredictionMode::DC_PRED];
+        luma_chroma_mode_rdo(luma_mode, fi, bsize, tile_bo, ts, cw, rdo_type, &cw_checkpoint, &mut best, mvs, ref_frames,
+                             CompoundParams::default(), None, Some(palette), &mode_set_chroma, true, 0,
+                             &ArrayVec::<[CandidateMV; 9]>::new());
+      }
+    }
   }
 
   if best.mode_luma.is_intra() && is_chroma_block && bsize.cfl_allowed() {
@@ -838,16 +1267,18 @@ pub fn rdo_mode_decision<T: Pixel>(
       best.tx_type,
       false,
       CFLParams::default(),
+      best.filter_intra,
+      best.palette,
       true,
       rdo_type,
       true
     );
     cw.rollback(&cw_checkpoint);
-    if let Some(cfl) = rdo_cfl_alpha(ts, tile_bo, bsize, fi.sequence.bit_depth) {
+    if let Some(cfl) = rdo_cfl_alpha(fi, ts, cw, tile_bo, bsize) {
       let wr: &mut dyn Writer = &mut WriterCounter::new();
       let tell = wr.tell_frac();
 
-      encode_block_pre_cdef(&fi.sequence, ts, cw, wr, bsize, tile_bo, best.skip);
+      encode_block_pre_cdef(fi, ts, cw, wr, bsize, tile_bo, best.skip);
       let _ = encode_block_post_cdef(
         fi,
         ts,
@@ -857,10 +1288,13 @@ pub fn rdo_mode_decision<T: Pixel>(
         chroma_mode,
         best.ref_frames,
         best.mvs,
+        best.compound,
         bsize,
         tile_bo,
         best.skip,
         cfl,
+        best.filter_intra,
+        best.palette,
         best.tx_size,
         best.tx_type,
         0,
@@ -896,6 +1330,7 @@ pub fn rdo_mode_decision<T: Pixel>(
   cw.bc.blocks.set_mode(tile_bo, bsize, best.mode_luma);
   cw.bc.blocks.set_ref_frames(tile_bo, bsize, best.ref_frames);
   cw.bc.blocks.set_motion_vectors(tile_bo, bsize, best.mvs);
+  cw.bc.blocks.set_compound(tile_bo, bsize, best.compound);
 
   assert!(best.rd >= 0_f64);
 
@@ -905,8 +1340,11 @@ pub fn rdo_mode_decision<T: Pixel>(
     pred_mode_luma: best.mode_luma,
     pred_mode_chroma: best.mode_chroma,
     pred_cfl_params: best.cfl_params,
+    filter_intra: best.filter_intra,
+    palette: best.palette,
     ref_frames: best.ref_frames,
     mvs: best.mvs,
+    compound: best.compound,
     rd_cost: best.rd,
     skip: best.skip,
     tx_size: best.tx_size,
@@ -914,62 +1352,259 @@ pub fn rdo_mode_decision<T: Pixel>(
   }
 }
 
+/// Mode decision for a block of a frame repeating the reference `ref_frame`:
+/// no motion and no residual
+pub fn rdo_skip_decision<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
+  cw: &mut ContextWriter, bsize: BlockSize, tile_bo: BlockOffset,
+  ref_frame: RefType
+) -> RDOPartitionOutput {
+  let mode = PredictionMode::GLOBALMV;
+  let ref_frames = [ref_frame, NONE_FRAME];
+  let mvs = [MotionVector::default(); 2];
+  let compound = CompoundParams::default();
+  let (tx_size, tx_type) = rdo_tx_size_type(
+    fi, ts, cw, bsize, tile_bo, mode, ref_frames, mvs, compound, None, None, true
+  );
+
+  cw.bc.blocks.set_mode(tile_bo, bsize, mode);
+  cw.bc.blocks.set_ref_frames(tile_bo, bsize, ref_frames);
+  cw.bc.blocks.set_motion_vectors(tile_bo, bsize, mvs);
+  cw.bc.blocks.set_compound(tile_bo, bsize, compound);
+
+  RDOPartitionOutput {
+    bo: tile_bo,
+    bsize,
+    pred_mode_luma: mode,
+    pred_mode_chroma: mode,
+    pred_cfl_params: CFLParams::default(),
+    filter_intra: None,
+    palette: None,
+    ref_frames,
+    mvs,
+    compound,
+    rd_cost: 0.0,
+    skip: true,
+    tx_size,
+    tx_type,
+  }
+}
+
+/// Finds the wedge whose luma prediction is closest to the source. Wedges
+/// of a block size all cost about the same to signal, so the full RDO only
+/// has to be run on that one.
+fn rdo_wedge_search<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
+  cw: &mut ContextWriter, luma_mode: PredictionMode,
+  ref_frames: [RefType; 2], mvs: [MotionVector; 2], bsize: BlockSize,
+  tile_bo: BlockOffset
+) -> CompoundParams {
+  let mut best_sse = std::u64::MAX;
+  let mut best = CompoundParams::default();
+
+  for wedge_index in 0..WEDGE_TYPES as u8 {
+    for &wedge_sign in &[false, true] {
+      let compound = CompoundParams::wedge(wedge_index, wedge_sign);
+      motion_compensate(fi, ts, cw, luma_mode, ref_frames, mvs, compound, bsize, tile_bo, true);
+      let sse = sse_wxh(
+        &ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo }),
+        &ts.rec.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo }),
+        bsize.width(),
+        bsize.height()
+      );
+      if sse < best_sse {
+        best_sse = sse;
+        best = compound;
+      }
+    }
+  }
+
+  best
+}
+
+/// Joint search of the CfL signs and magnitudes. The distortion of every
+/// alpha is measured once per chroma plane with the SATD; once the signs
+/// are fixed, the magnitude of each plane can be chosen on its own.
 pub fn rdo_cfl_alpha<T: Pixel>(
-  ts: &mut TileStateMut<'_, T>, tile_bo: BlockOffset, bsize: BlockSize, bit_depth: usize
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &ContextWriter,
+  tile_bo: BlockOffset, bsize: BlockSize
 ) -> Option<CFLParams> {
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
   let uv_tx_size = bsize.largest_chroma_tx_size(xdec, ydec);
+  let bit_depth = fi.sequence.bit_depth;
 
   let mut ac: AlignedArray<[i16; 32 * 32]> = UninitializedAlignedArray();
   luma_ac(&mut ac.array, ts, tile_bo, bsize);
-  let best_alpha: Vec<i16> = (1..3)
-    .map(|p| {
-      let &PlaneConfig { xdec, ydec, .. } = ts.rec.planes[p].plane_cfg;
-      let tile_rect = ts.tile_rect().decimated(xdec, ydec);
-      let rec = &mut ts.rec.planes[p];
-      let input = &ts.input_tile.planes[p];
-      let po = tile_bo.plane_offset(rec.plane_cfg);
-      (-16i16..17i16)
-        .min_by_key(|&alpha| {
-          let edge_buf = get_intra_edges(
-            &rec.as_const(),
-            po,
-            uv_tx_size,
-            bit_depth,
-            Some(PredictionMode::UV_CFL_PRED)
-          );
 
-          let mut rec_region = rec.subregion_mut(Area::BlockStartingAt { bo: tile_bo });
-          PredictionMode::UV_CFL_PRED.predict_intra(
-            tile_rect,
-            &mut rec_region,
-            uv_tx_size,
-            bit_depth,
-            &ac.array,
-            alpha,
-            &edge_buf
-          );
-          sse_wxh(
-            &input.subregion(Area::BlockStartingAt { bo: tile_bo }),
-            &rec_region.as_const(),
-            uv_tx_size.width(),
-            uv_tx_size.height()
-          )
-        }).unwrap()
-    }).collect();
+  // On flat luma, CfL gives the same prediction as DC_PRED for more bits.
+  // The AC is in Q3, so this checks for a mean below 1/8 of a pixel.
+  let ac_sum: u32 = ac.array[..uv_tx_size.area()].iter().map(|&a| a.abs() as u32).sum();
+  if ac_sum < uv_tx_size.area() as u32 {
+    return None;
+  }
 
-  if best_alpha[0] == 0 && best_alpha[1] == 0 {
-    None
-  } else {
-    Some(CFLParams::from_alpha(best_alpha[0], best_alpha[1]))
+  let mut dists = [[0u32; 33]; 2];
+  for p in 1..3 {
+    let &PlaneConfig { xdec, ydec, .. } = ts.rec.planes[p].plane_cfg;
+    let tile_rect = ts.tile_rect().decimated(xdec, ydec);
+    let rec = &mut ts.rec.planes[p];
+    let input = &ts.input_tile.planes[p];
+    let po = tile_bo.plane_offset(rec.plane_cfg);
+    let edge_buf = get_intra_edges(
+      &rec.as_const(),
+      po,
+      uv_tx_size,
+      bsize.subsampled_size(xdec, ydec),
+      cw.bc.blocks[tile_bo].partition,
+      bit_depth,
+      Some(PredictionMode::UV_CFL_PRED)
+    );
+
+    for (alpha, dist) in (-16i16..17i16).zip(dists[p - 1].iter_mut()) {
+      let mut rec_region = rec.subregion_mut(Area::BlockStartingAt { bo: tile_bo });
+      PredictionMode::UV_CFL_PRED.predict_intra(
+        tile_rect,
+        &mut rec_region,
+        uv_tx_size,
+        bit_depth,
+        &ac.array,
+        alpha,
+        &edge_buf
+      );
+      *dist = get_satd(
+        &input.subregion(Area::BlockStartingAt { bo: tile_bo }),
+        &rec_region.as_const(),
+        uv_tx_size.width(),
+        uv_tx_size.height()
+      );
+    }
+  }
+
+  // The SATD is on the scale of the SAD, so it is traded off against the
+  // rate with the motion estimation lambda
+  let wr = WriterCounter::new();
+  let rd = |dist: u32, rate: u32| {
+    dist as f64 + fi.me_lambda * (rate as f64) / ((1 << OD_BITRES) as f64)
+  };
+
+  let mut best_rd = std::f64::MAX;
+  let mut best_cfl = None;
+  for &sign_u in &[0i16, -1, 1] {
+    for &sign_v in &[0i16, -1, 1] {
+      if sign_u == 0 && sign_v == 0 {
+        continue;
+      }
+      let mut alpha = [0i16; 2];
+      for (uv, &sign) in [sign_u, sign_v].iter().enumerate() {
+        if sign == 0 {
+          continue;
+        }
+        alpha[uv] = (1..17)
+          .map(|scale| {
+            // Only the signs of the other plane matter to the context
+            let mut alphas = [sign_u, sign_v];
+            alphas[uv] = sign * scale;
+            let cfl = CFLParams::from_alpha(alphas[0], alphas[1]);
+            let rate = cw.count_cfl_alpha(&wr, cfl, uv);
+            (alphas[uv], rd(dists[uv][(alphas[uv] + 16) as usize], rate))
+          })
+          .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+          .unwrap()
+          .0;
+      }
+
+      let cfl = CFLParams::from_alpha(alpha[0], alpha[1]);
+      let rate = cw.count_cfl_signs(&wr, cfl)
+        + (0..2).filter(|&uv| alpha[uv] != 0).map(|uv| cw.count_cfl_alpha(&wr, cfl, uv)).sum::<u32>();
+      let cost = rd(dists[0][(alpha[0] + 16) as usize] + dists[1][(alpha[1] + 16) as usize], rate);
+      if cost < best_rd {
+        best_rd = cost;
+        best_cfl = Some(cfl);
+      }
+    }
   }
+
+  best_cfl
 }
 
 // RDO-based transform type decision
+/// Number of transform types kept for full RDO by the SATD pre-screen
+const TX_TYPE_SEARCH_CANDIDATES: usize = 4;
+
+/// Rank the transform types allowed for an inter block by the sum of the
+/// absolute transform coefficients of its luma residual, and keep the most
+/// compact ones. A block whose DCT coefficients all quantize to zero is
+/// classified as DCT-only, since no other type can code it for less.
+fn prescreen_tx_types<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, bsize: BlockSize,
+  tile_bo: BlockOffset, tx_size: TxSize, tx_set: TxSet, tx_types: &[TxType]
+) -> ArrayVec<[TxType; TX_TYPES]> {
+  let bw = bsize.width_mi() / tx_size.width_mi();
+  let bh = bsize.height_mi() / tx_size.height_mi();
+  let bit_depth = fi.sequence.bit_depth;
+  let coded_tx_size = av1_get_coded_tx_size(tx_size).area();
+
+  let mut qc = QuantizationContext::default();
+  let qidx = get_qidx(fi, ts, tile_bo);
+  // Only DCT_DCT is quantized
+  qc.update(qidx, tx_size, false, bit_depth, fi.dc_delta_q[0], 0,
+            fi.qm_weights(0, tx_size, TxType::DCT_DCT));
+
+  let arena = &mut *ts.arena;
+  let residual = &mut arena.residual.array[..tx_size.area()];
+  let coeffs = &mut arena.coeffs.array[..tx_size.area()];
+  let qcoeffs = &mut arena.qcoeffs.array[..tx_size.area()];
+
+  let mut candidates: ArrayVec<[(u64, TxType); TX_TYPES]> = ArrayVec::new();
+  for &tx_type in tx_types {
+    if av1_tx_used[tx_set as usize][tx_type as usize] == 0 {
+      continue;
+    }
+
+    let mut satd = 0;
+    let mut has_coeff = false;
+    for by in 0..bh {
+      for bx in 0..bw {
+        let tx_bo = BlockOffset {
+          x: tile_bo.x + bx * tx_size.width_mi(),
+          y: tile_bo.y + by * tx_size.height_mi()
+        };
+        let area = Area::BlockStartingAt { bo: tx_bo };
+
+        diff(
+          residual,
+          &ts.input_tile.planes[0].subregion(area),
+          &ts.rec.planes[0].subregion(area),
+          tx_size.width(),
+          tx_size.height()
+        );
+        forward_transform(residual, coeffs, tx_size.width(), tx_size, tx_type, bit_depth);
+
+        if tx_type == TxType::DCT_DCT && !has_coeff {
+          qc.quantize(coeffs, qcoeffs, coded_tx_size);
+          has_coeff = qcoeffs.iter().any(|&c| c != 0);
+        }
+
+        satd += coeffs[..coded_tx_size].iter().map(|&c| c.abs() as u64).sum::<u64>();
+      }
+    }
+
+    if tx_type == TxType::DCT_DCT && !has_coeff {
+      return [TxType::DCT_DCT].iter().cloned().collect();
+    }
+
+    candidates.push((satd, tx_type));
+  }
+
+  candidates.sort_by_key(|&(satd, _)| satd);
+  candidates.iter().take(TX_TYPE_SEARCH_CANDIDATES).map(|&(_, tx_type)| tx_type).collect()
+}
+
 pub fn rdo_tx_type_decision<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
   mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
-  bsize: BlockSize, tile_bo: BlockOffset, tx_size: TxSize, tx_set: TxSet,
+  compound: CompoundParams, filter_intra: Option<FilterIntraMode>,
+  palette: Option<Palette>, bsize: BlockSize, tile_bo: BlockOffset, tx_size: TxSize, tx_set: TxSet,
   tx_types: &[TxType]
 ) -> (TxType, f64) {
   let mut best_type = TxType::DCT_DCT;
@@ -980,7 +1615,8 @@ pub fn rdo_tx_type_decision<T: Pixel>(
   let h = bsize.height();
 
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
-  let is_chroma_block = has_chroma(tile_bo, bsize, xdec, ydec);
+  let is_chroma_block =
+    fi.sequence.num_planes() > 1 && has_chroma(tile_bo, bsize, xdec, ydec);
 
   let is_inter = !mode.is_intra();
 
@@ -993,6 +1629,15 @@ pub fn rdo_tx_type_decision<T: Pixel>(
   };
   let need_recon_pixel = tx_size.block_size() != bsize;
 
+  let prescreened: ArrayVec<[TxType; TX_TYPES]>;
+  let tx_types = if is_inter && tx_types.len() > TX_TYPE_SEARCH_CANDIDATES {
+    motion_compensate(fi, ts, cw, mode, ref_frames, mvs, compound, bsize, tile_bo, true);
+    prescreened = prescreen_tx_types(fi, ts, bsize, tile_bo, tx_size, tx_set, tx_types);
+    &prescreened[..]
+  } else {
+    tx_types
+  };
+
   for &tx_type in tx_types {
     // Skip unsupported transform types
     if av1_tx_used[tx_set as usize][tx_type as usize] == 0 {
@@ -1000,7 +1645,7 @@ pub fn rdo_tx_type_decision<T: Pixel>(
     }
 
     if is_inter {
-      motion_compensate(fi, ts, cw, mode, ref_frames, mvs, bsize, tile_bo, true);
+      motion_compensate(fi, ts, cw, mode, ref_frames, mvs, compound, bsize, tile_bo, true);
     }
 
     let wr: &mut dyn Writer = &mut WriterCounter::new();
@@ -1023,6 +1668,8 @@ pub fn rdo_tx_type_decision<T: Pixel>(
         tx_type,
         false,
         CFLParams::default(), // Unused.
+        filter_intra,
+        palette,
         true,
         rdo_type,
         need_recon_pixel
@@ -1124,6 +1771,26 @@ pub fn get_sub_partitions_with_border_check(
   partitions
 }
 
+// Blocks of a partition into three blocks or four strips, in coding order
+pub fn get_ext_sub_partitions(
+  tile_bo: BlockOffset, bsize: BlockSize, partition: PartitionType
+) -> Vec<(BlockOffset, BlockSize)> {
+  let hbs = bsize.width_mi() >> 1;
+  let sqr = bsize.subsize(PARTITION_SPLIT);
+  let subsize = bsize.subsize(partition);
+  let at = |x: usize, y: usize| BlockOffset { x: tile_bo.x + x, y: tile_bo.y + y };
+
+  match partition {
+    PARTITION_HORZ_A => vec![(at(0, 0), sqr), (at(hbs, 0), sqr), (at(0, hbs), subsize)],
+    PARTITION_HORZ_B => vec![(at(0, 0), subsize), (at(0, hbs), sqr), (at(hbs, hbs), sqr)],
+    PARTITION_VERT_A => vec![(at(0, 0), sqr), (at(0, hbs), sqr), (at(hbs, 0), subsize)],
+    PARTITION_VERT_B => vec![(at(0, 0), subsize), (at(hbs, 0), sqr), (at(hbs, hbs), sqr)],
+    PARTITION_HORZ_4 => (0..4).map(|i| (at(0, i * subsize.height_mi()), subsize)).collect(),
+    PARTITION_VERT_4 => (0..4).map(|i| (at(i * subsize.width_mi(), 0), subsize)).collect(),
+    _ => unreachable!()
+  }
+}
+
 // RDO-based single level partitioning decision
 pub fn rdo_partition_decision<T: Pixel, W: Writer>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
@@ -1140,15 +1807,36 @@ pub fn rdo_partition_decision<T: Pixel, W: Writer>(
   let w_pre_checkpoint = w_pre_cdef.checkpoint();
   let w_post_checkpoint = w_post_cdef.checkpoint();
 
+  // Blocks that must be split are never pruned
+  let prune = fi.config.speed_settings.partition_pruning &&
+    partition_types.contains(&PartitionType::PARTITION_NONE);
+  let uniform = prune && is_uniform_block(fi, ts, bsize, tile_bo);
+
   for &partition in partition_types {
     // Do not re-encode results we already have
     if partition == cached_block.part_type {
       continue;
     }
+    if prune && partition != PartitionType::PARTITION_NONE {
+      if best_partition == PartitionType::PARTITION_NONE &&
+        best_pred_modes[0].skip && neighbors_not_split(cw, bsize, tile_bo) {
+        continue;
+      }
+      if uniform && partition != PartitionType::PARTITION_SPLIT {
+        continue;
+      }
+    }
     let mut cost: f64 = 0.0;
-    let mut child_modes = std::vec::Vec::new();
+    let mut child_modes = ts.arena.take_modes();
     let mut early_exit = false;
 
+    // Split blocks record their own partition
+    cw.bc.blocks.set_partition(tile_bo, bsize, if partition == PARTITION_SPLIT {
+      PARTITION_NONE
+    } else {
+      partition
+    });
+
     match partition {
       PartitionType::PARTITION_NONE => {
         if bsize > BlockSize::BLOCK_64X64 {
@@ -1226,6 +1914,41 @@ pub fn rdo_partition_decision<T: Pixel, W: Writer>(
           child_modes.push(mode_decision);
         }
       }
+      PARTITION_HORZ_A |
+      PARTITION_HORZ_B |
+      PARTITION_VERT_A |
+      PARTITION_VERT_B |
+      PARTITION_HORZ_4 |
+      PARTITION_VERT_4 => {
+        let w: &mut W = if cw.bc.cdef_coded { w_post_cdef } else { w_pre_cdef };
+        let tell = w.tell_frac();
+        cw.write_partition(w, tile_bo, partition, bsize);
+        cost = (w.tell_frac() - tell) as f64 * fi.lambda
+          / ((1 << OD_BITRES) as f64);
+        let mut rd_cost_sum = 0.0;
+
+        // The blocks are not split any further, so they code no partition
+        for (offset, subsize) in get_ext_sub_partitions(tile_bo, bsize, partition) {
+          let pmv_idx = if subsize.greater_than(BlockSize::BLOCK_32X32) {
+            0
+          } else {
+            ((offset.x & 32) >> 5) + ((offset.y & 32) >> 4) + 1
+          };
+          let mode_decision =
+            rdo_mode_decision(fi, ts, cw, subsize, offset, &mut pmvs[pmv_idx]);
+
+          rd_cost_sum += mode_decision.rd_cost;
+
+          if fi.enable_early_exit && rd_cost_sum > best_rd {
+            early_exit = true;
+            break;
+          }
+
+          encode_block_with_modes(fi, ts, cw, w_pre_cdef, w_post_cdef, subsize,
+                                  offset, &mode_decision, rdo_type);
+          child_modes.push(mode_decision);
+        }
+      }
       _ => {
         unreachable!();
       }
@@ -1237,9 +1960,10 @@ pub fn rdo_partition_decision<T: Pixel, W: Writer>(
       if rd < best_rd {
         best_rd = rd;
         best_partition = partition;
-        best_pred_modes = child_modes.clone();
+        std::mem::swap(&mut best_pred_modes, &mut child_modes);
       }
     }
+    ts.arena.give_modes(child_modes);
     cw.rollback(&cw_checkpoint);
     w_pre_cdef.rollback(&w_pre_checkpoint);
     w_post_cdef.rollback(&w_post_checkpoint);
@@ -1250,8 +1974,66 @@ pub fn rdo_partition_decision<T: Pixel, W: Writer>(
   RDOOutput {
     rd_cost: best_rd,
     part_type: best_partition,
-    part_modes: best_pred_modes
+    part_modes: best_pred_modes,
+    part_children: Vec::new()
+  }
+}
+
+// Whether the blocks above and to the left, when available, are at least as
+//  large as a block of size bsize at tile_bo
+fn neighbors_not_split(
+  cw: &ContextWriter, bsize: BlockSize, tile_bo: BlockOffset
+) -> bool {
+  let covers = |b: &Block| {
+    b.bsize.width_log2() >= bsize.width_log2() &&
+      b.bsize.height_log2() >= bsize.height_log2()
+  };
+  let above = if tile_bo.y > 0 {
+    Some(covers(cw.bc.blocks.above_of(tile_bo)))
+  } else {
+    None
+  };
+  let left = if tile_bo.x > 0 {
+    Some(covers(cw.bc.blocks.left_of(tile_bo)))
+  } else {
+    None
+  };
+  match (above, left) {
+    (None, None) => false,
+    (above, left) => above.unwrap_or(true) && left.unwrap_or(true)
+  }
+}
+
+// Whether the four quadrants of the source luma block deviate about as much
+//  from the mean of the whole block, in which case no half of it stands out
+//  for a rectangular partition
+fn is_uniform_block<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, bsize: BlockSize,
+  tile_bo: BlockOffset
+) -> bool {
+  let w = 1 << (bsize.width_log2() - 1);
+  let h = 1 << (bsize.height_log2() - 1);
+  let region =
+    ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo });
+  let mut sums = [0i64; 4];
+  let mut sums_sq = [0i64; 4];
+  for y in 0..2 * h {
+    for (x, &p) in region[y][..2 * w].iter().enumerate() {
+      let v: i32 = p.as_();
+      let q = (y / h) * 2 + x / w;
+      sums[q] += v as i64;
+      sums_sq[q] += (v * v) as i64;
+    }
   }
+  let n = (w * h) as f64;
+  let mean = sums.iter().sum::<i64>() as f64 / (4.0 * n);
+  let energies = sums.iter().zip(sums_sq.iter()).map(|(&sum, &sum_sq)| {
+    (sum_sq as f64 - 2.0 * mean * sum as f64) / n + mean * mean
+  }).collect::<Vec<_>>();
+  let min = energies.iter().cloned().fold(std::f64::MAX, f64::min);
+  let max = energies.iter().cloned().fold(0.0, f64::max);
+  let offset = (16 << (2 * (fi.sequence.bit_depth - 8))) as f64;
+  max <= min * 1.25 + offset
 }
 
 fn rdo_loop_plane_error<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvariants<T>,
@@ -1280,7 +2062,7 @@ fn rdo_loop_plane_error<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvarian
           let test_bo = sbo_0.block_offset(bx << 1, by << 1);
           let test_region = test_plane.region(Area::BlockStartingAt { bo: test_bo });
 
-          if pli==0 {
+          if pli == 0 && fi.config.tune == Tune::Psychovisual {
             err += cdef_dist_wxh_8x8(&in_region, &test_region, fi.sequence.bit_depth);
           } else {
             err += sse_wxh(&in_region, &test_region, 8 >> xdec, 8 >> ydec);
@@ -1292,6 +2074,29 @@ fn rdo_loop_plane_error<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvarian
   err
 }
 
+// Applies a restoration filter to one plane of the superblock-sized frame
+// built for loop filter RDO, treating the whole superblock as one stripe.
+fn rdo_lrf_filter_plane<T: Pixel>(fi: &FrameInvariants<T>, lrf: RestorationFilter,
+                                  lrf_input: &Frame<T>, lrf_output: &mut Frame<T>,
+                                  pli: usize) {
+  let input = &lrf_input.planes[pli];
+  let width = input.cfg.width;
+  let height = input.cfg.height;
+  match lrf {
+    RestorationFilter::Sgrproj{set, xqd} => {
+      sgrproj_stripe_filter(set, xqd, fi, width, height, width, height,
+                            &input.slice(PlaneOffset{x: 0, y: 0}),
+                            &input.slice(PlaneOffset{x: 0, y: 0}),
+                            &mut lrf_output.planes[pli].mut_slice(PlaneOffset{x: 0, y: 0}));
+    }
+    RestorationFilter::Wiener{coeffs} => {
+      wiener_stripe_filter(coeffs, fi, width, height, width, height, 0, 0,
+                           input, input, &mut lrf_output.planes[pli]);
+    }
+    RestorationFilter::None{} => {}
+  }
+}
+
 pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvariants<T>,
                                    ts: &mut TileStateMut<'_, T>,
                                    cw: &mut ContextWriter, w: &mut dyn Writer) {
@@ -1302,6 +2107,7 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
   let mut best_lrf = [RestorationFilter::None{}; PLANES];
   let mut best_cost_acc = -1.;
   let mut best_cost = [-1.; PLANES];
+  let planes = fi.sequence.num_planes();
   let sbo_0 = SuperBlockOffset { x: 0, y: 0 };
 
   // all stages; reconstruction goes to cdef so it must be additionally padded
@@ -1340,6 +2146,19 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
   let cdef_data = cdef_input.as_ref().map(|input| {
     (input, cdef_analyze_superblock(input, &cw.bc.blocks.as_const(), sbo_0, tile_sbo, bd))
   });
+  // The strength index is only coded for superblocks with at least one
+  // non-skip block. Its cost is part of the cost of each strength, which is
+  // also compared against the restoration filter choices below.
+  let cdef_index_costs: Vec<f64> = (0..1 << fi.cdef_bits).map(|cdef_index| {
+    if fi.sequence.enable_cdef && cw.bc.cdef_coded {
+      let wr: &mut dyn Writer = &mut WriterCounter::new();
+      let tell = wr.tell_frac();
+      cw.write_cdef(wr, cdef_index as u8, fi.cdef_bits);
+      fi.lambda * (wr.tell_frac() - tell) as f64 / ((1<<OD_BITRES) as f64)
+    } else {
+      0.
+    }
+  }).collect();
   let mut first_loop = true;
   loop {
     // check for [new] cdef index if cdef is enabled.
@@ -1349,37 +2168,26 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
       for cdef_index in 0..(1<<fi.cdef_bits) {
         if cdef_index != prev_best_index {
           let mut cost = [0.; PLANES];
-          let mut cost_acc = 0.;
+          let mut cost_acc = cdef_index_costs[cdef_index as usize];
           cdef_filter_superblock(fi, &cdef_input, &mut lrf_input,
                                  &cw.bc.blocks.as_const(), sbo_0, tile_sbo, cdef_index as u8, &cdef_dirs);
-          for pli in 0..3 {
-            match best_lrf[pli] {
+          for pli in 0..planes {
+            let err = match best_lrf[pli] {
               RestorationFilter::None{} => {
-                let err = rdo_loop_plane_error(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_input, pli);
-                let rate = if fi.sequence.enable_restoration {
-                  cw.count_lrf_switchable(w, &ts.restoration.as_const(), best_lrf[pli], pli)
-                } else {
-                  0 // no relative cost differeneces to different CDEF params.  If cdef is on, it's a wash.
-                };
-                cost[pli] = err as f64 + fi.lambda * rate as f64 / ((1<<OD_BITRES) as f64);
-                cost_acc += cost[pli];
+                rdo_loop_plane_error(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_input, pli)
               }
-              RestorationFilter::Sgrproj{set, xqd} => {
-                sgrproj_stripe_filter(set, xqd, fi,
-                                      lrf_input.planes[pli].cfg.width,
-                                      lrf_input.planes[pli].cfg.height,
-                                      lrf_input.planes[pli].cfg.width,
-                                      lrf_input.planes[pli].cfg.height,
-                                      &lrf_input.planes[pli].slice(PlaneOffset{x:0, y:0}),
-                                      &lrf_input.planes[pli].slice(PlaneOffset{x:0, y:0}),
-                                      &mut lrf_output.planes[pli].mut_slice(PlaneOffset{x:0, y:0}));
-                let err = rdo_loop_plane_error(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_output, pli);
-                let rate = cw.count_lrf_switchable(w, &ts.restoration.as_const(), best_lrf[pli], pli);
-                cost[pli] = err as f64 + fi.lambda * rate as f64 / ((1<<OD_BITRES) as f64);
-                cost_acc += cost[pli];
+              lrf => {
+                rdo_lrf_filter_plane(fi, lrf, &lrf_input, &mut lrf_output, pli);
+                rdo_loop_plane_error(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_output, pli)
               }
-              RestorationFilter::Wiener{..} => unreachable!() // coming soon
-            }
+            };
+            let rate = if fi.sequence.enable_restoration {
+              cw.count_lrf_switchable(w, &ts.restoration.as_const(), best_lrf[pli], pli)
+            } else {
+              0 // no relative cost differeneces to different CDEF params.  If cdef is on, it's a wash.
+            };
+            cost[pli] = err as f64 + fi.lambda * rate as f64 / ((1<<OD_BITRES) as f64);
+            cost_acc += cost[pli];
           }
           if best_cost_acc < 0. || cost_acc < best_cost_acc {
             cdef_change = true;
@@ -1397,38 +2205,51 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
     // check for new best restoration filter if enabled
     let mut lrf_change = false;
 
-    if fi.sequence.enable_restoration && ts.restoration.has_restoration_unit(tile_sbo) {
+    if fi.sequence.enable_restoration && ts.restoration.has_restoration_unit(tile_sbo)
+      && ts.restoration.planes[0].rp_cfg.lrf_type != RESTORE_NONE {
       // need cdef output from best index, not just last iteration
       if let Some((cdef_input, cdef_dirs)) = cdef_data.as_ref() {
         cdef_filter_superblock(fi, &cdef_input, &mut lrf_input,
                                &cw.bc.blocks.as_const(), sbo_0, tile_sbo, best_index as u8, &cdef_dirs);
       }
 
-      // Wiener LRF decision coming soon
-
-      // SgrProj LRF decision
-      for pli in 0..3 {
+      // Per-unit LRF decision between none, Wiener and each SgrProj set
+      for pli in 0..planes {
+        if ts.restoration.planes[pli].restoration_unit(tile_sbo).is_none() {
+          continue;
+        }
         let in_plane = &ts.input.planes[pli];  // reference
+        let PlaneConfig { xdec, ydec, .. } = in_plane.cfg;
         let frame_sbo = ts.to_frame_super_block_offset(tile_sbo);
         let ipo = frame_sbo.plane_offset(&in_plane.cfg);
         let cdef_plane = &lrf_input.planes[pli];
+        let crop_w = (fi.width + xdec) >> xdec;
+        let crop_h = (fi.height + ydec) >> ydec;
+        let cdef_w = cmp::min(cdef_plane.cfg.width, crop_w - ipo.x as usize);
+        let cdef_h = cmp::min(cdef_plane.cfg.height, crop_h - ipo.y as usize);
+        let mut candidates = vec![
+          RestorationFilter::None{},
+          RestorationFilter::Wiener{
+            coeffs: wiener_solve(pli, &in_plane.slice(ipo),
+                                 &cdef_plane.slice(PlaneOffset{x: 0, y: 0}),
+                                 cdef_w, cdef_h)
+          }
+        ];
         for set in 0..16 {
           let (xqd0, xqd1) = sgrproj_solve(set, fi,
                                            &in_plane.slice(ipo),
                                            &cdef_plane.slice(PlaneOffset{x: 0, y: 0}),
-                                           cmp::min(cdef_plane.cfg.width, fi.width - ipo.x as usize),
-                                           cmp::min(cdef_plane.cfg.height, fi.height - ipo.y as usize));
-          let current_lrf = RestorationFilter::Sgrproj{set, xqd: [xqd0, xqd1]};
-          if let RestorationFilter::Sgrproj{set, xqd} = current_lrf {
+                                           cdef_w, cdef_h);
+          candidates.push(RestorationFilter::Sgrproj{set, xqd: [xqd0, xqd1]});
+        }
+        for current_lrf in candidates {
+          let err = if let RestorationFilter::None{} = current_lrf {
+            rdo_loop_plane_error(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_input, pli)
+          } else {
             // At present, this is a gross simplification
-            sgrproj_stripe_filter(set, xqd, fi,
-                                  lrf_input.planes[pli].cfg.width, lrf_input.planes[pli].cfg.height,
-                                  lrf_input.planes[pli].cfg.width, lrf_input.planes[pli].cfg.height,
-                                  &lrf_input.planes[pli].slice(PlaneOffset{x:0, y:0}),
-                                  &lrf_input.planes[pli].slice(PlaneOffset{x:0, y:0}),
-                                  &mut lrf_output.planes[pli].mut_slice(PlaneOffset{x:0, y:0}));
-          }
-          let err = rdo_loop_plane_error(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_output, pli);
+            rdo_lrf_filter_plane(fi, current_lrf, &lrf_input, &mut lrf_output, pli);
+            rdo_loop_plane_error(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_output, pli)
+          };
           let rate = cw.count_lrf_switchable(w, &ts.restoration.as_const(), current_lrf, pli);
           let cost = err as f64 + fi.lambda * rate as f64 / ((1<<OD_BITRES) as f64);
           if best_cost[pli] < 0. || cost < best_cost[pli] {
@@ -1442,8 +2263,9 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
       // we tried all LRF possibilities for this cdef index; is the local result for this
       // cdef index better than the best previous result?
       if lrf_change {
-        let mut cost_acc = 0.;
-        for pli in 0..3 {
+        let mut cost_acc =
+          if best_index >= 0 { cdef_index_costs[best_index as usize] } else { 0. };
+        for pli in 0..planes {
           cost_acc += best_cost[pli];
         }
         best_cost_acc = cost_acc;
@@ -1468,6 +2290,57 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
 }
 
 #[test]
-fn estimate_rate_test() {
-  assert_eq!(estimate_rate(0, TxSize::TX_4X4, 0), RDO_RATE_TABLE[0][0][0]);
+fn ext_sub_partitions_cover_block() {
+  let bo = BlockOffset { x: 16, y: 32 };
+  for &partition in RAV1E_EXT_PARTITION_TYPES {
+    let mut covered = [[false; 16]; 16];
+    for (sub_bo, subsize) in get_ext_sub_partitions(bo, BlockSize::BLOCK_64X64, partition) {
+      for y in sub_bo.y - bo.y..sub_bo.y - bo.y + subsize.height_mi() {
+        for x in sub_bo.x - bo.x..sub_bo.x - bo.x + subsize.width_mi() {
+          assert!(!covered[y][x]);
+          covered[y][x] = true;
+        }
+      }
+    }
+    assert!(covered.iter().all(|row| row.iter().all(|&c| c)));
+  }
+}
+
+#[test]
+fn ssim_weights_flat_blocks_above_textured() {
+  // A flat 8x8 block next to a checkerboard of variance 64^2
+  let mut src = Plane::<u8>::new(16, 8, 0, 0, 0, 0);
+  for (y, row) in src.as_region_mut().rows_iter_mut().take(8).enumerate() {
+    for (x, v) in row[..16].iter_mut().enumerate() {
+      *v = match (x < 8, (x + y) % 2) {
+        (true, _) => 128,
+        (false, 0) => 192,
+        _ => 64
+      };
+    }
+  }
+  let mean_variance = mean_block_variance(&src, 16, 8);
+  assert_eq!(mean_variance, 64.0 * 64.0 / 2.0);
+
+  // The same error of 2 on every pixel
+  let mut rec = src.clone();
+  for row in rec.as_region_mut().rows_iter_mut().take(8) {
+    for v in row[..16].iter_mut() {
+      *v += 2;
+    }
+  }
+  let sse = 4 * 64;
+  let weighted = |x: isize| {
+    let area = Area::StartingAt { x, y: 0 };
+    ssim_weighted_sse(
+      mean_variance,
+      &src.as_region().subregion(area),
+      &rec.as_region().subregion(area),
+      8,
+      8,
+      8
+    )
+  };
+  let (flat, textured) = (weighted(0), weighted(8));
+  assert!(flat > sse && sse > textured);
 }
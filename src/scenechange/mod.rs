@@ -9,15 +9,21 @@
 
 use crate::frame::Frame;
 use crate::util::{CastFromPrimitive, Pixel};
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 use std::sync::Arc;
 
+/// The sensitivity at which the threshold is the default one
+pub const DEFAULT_SENSITIVITY: u8 = 50;
+
 /// Detects fast cuts using changes in colour and intensity between frames.
 /// Since the difference between frames is used, only fast cuts are detected
 /// with this method. This is probably fine for the purpose of choosing keyframes.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct SceneChangeDetector<T: Pixel> {
   /// Minimum average difference between YUV deltas that will trigger a scene change.
-  threshold: u8,
+  threshold: u16,
   /// Frame number and frame reference of the last frame analyzed
   last_frame: Option<(usize, Arc<Frame<T>>)>,
 }
@@ -41,9 +47,13 @@ impl<T: Pixel> Default for SceneChangeDetector<T> {
 }
 
 impl<T: Pixel> SceneChangeDetector<T> {
-  pub fn new(bit_depth: usize) -> Self {
+  /// `sensitivity` scales the threshold, from 1 for cuts 50 times as marked
+  /// as the default ones to 100 for cuts half as marked
+  pub fn new(bit_depth: usize, sensitivity: u8) -> Self {
     let mut detector = Self::default();
-    detector.threshold = detector.threshold * bit_depth as u8 / 8;
+    let sensitivity = sensitivity.max(1) as u16;
+    detector.threshold = detector.threshold * DEFAULT_SENSITIVITY as u16
+      / sensitivity * bit_depth as u16 / 8;
     detector
   }
 
@@ -68,7 +78,7 @@ impl<T: Pixel> SceneChangeDetector<T> {
           (delta_yuv.1 / len as u64) as u16,
           (delta_yuv.2 / len as u64) as u16
         );
-        let delta_avg = ((delta_yuv.0 + delta_yuv.1 + delta_yuv.2) / 3) as u8;
+        let delta_avg = (delta_yuv.0 + delta_yuv.1 + delta_yuv.2) / 3;
         is_change = delta_avg >= self.threshold;
       }
       _ => ()
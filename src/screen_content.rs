@@ -0,0 +1,97 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::frame::*;
+use crate::tiling::*;
+use crate::util::{CastFromPrimitive, Pixel};
+
+use arrayvec::ArrayVec;
+
+/// Size of the square blocks the frame is analyzed in
+const BLOCK_SIZE: usize = 16;
+/// Blocks with at most this many distinct values look like text or graphics
+const MAX_COLORS: usize = 4;
+/// Smallest range of values, in 8-bit units, for a few-color block to count
+/// as a sharp edge rather than a flat area with some noise
+const MIN_EDGE_CONTRAST: i32 = 32;
+
+/// Guesses whether a frame is computer generated content, such as a desktop
+/// capture, from the share of its blocks made of a few colors separated by
+/// sharp edges. Natural video hardly ever has such blocks.
+pub fn is_screen_content<T: Pixel>(plane: &Plane<T>, bit_depth: usize) -> bool {
+  let PlaneConfig { width, height, .. } = plane.cfg;
+  let min_contrast = MIN_EDGE_CONTRAST << (bit_depth - 8);
+
+  let mut blocks = 0;
+  let mut screen_blocks = 0;
+  for y in (0..height / BLOCK_SIZE).map(|y| y * BLOCK_SIZE) {
+    for x in (0..width / BLOCK_SIZE).map(|x| x * BLOCK_SIZE) {
+      blocks += 1;
+      let region = plane.region(Area::StartingAt { x: x as isize, y: y as isize });
+      if let Some(colors) = block_colors(&region) {
+        let min = colors.iter().map(|&v| i32::cast_from(v)).min().unwrap();
+        let max = colors.iter().map(|&v| i32::cast_from(v)).max().unwrap();
+        if max - min >= min_contrast {
+          screen_blocks += 1;
+        }
+      }
+    }
+  }
+
+  // Same proportion as the libaom detector: one block out of ten
+  blocks > 0 && screen_blocks * 10 > blocks
+}
+
+/// Distinct values of the top-left block of the region, or `None` if there
+/// are more than `MAX_COLORS` of them.
+fn block_colors<T: Pixel>(
+  region: &PlaneRegion<'_, T>
+) -> Option<ArrayVec<[T; MAX_COLORS]>> {
+  let mut colors = ArrayVec::new();
+  for row in region.rows_iter().take(BLOCK_SIZE) {
+    for &v in &row[..BLOCK_SIZE] {
+      if !colors.contains(&v) && colors.try_push(v).is_err() {
+        return None;
+      }
+    }
+  }
+  Some(colors)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn plane_from_fn(width: usize, height: usize, f: impl Fn(usize, usize) -> u8) -> Plane<u8> {
+    let mut plane = Plane::new(width, height, 0, 0, 0, 0);
+    let stride = plane.cfg.stride;
+    let data = plane.data_origin_mut();
+    for y in 0..height {
+      for x in 0..width {
+        data[y * stride + x] = f(x, y);
+      }
+    }
+    plane
+  }
+
+  #[test]
+  fn text_is_screen_content() {
+    // Black glyph-like strokes on a white background
+    let plane = plane_from_fn(128, 128, |x, y| if x % 7 == 0 || y % 11 == 0 { 0 } else { 255 });
+    assert!(is_screen_content(&plane, 8));
+  }
+
+  #[test]
+  fn natural_content_is_not() {
+    let gradient = plane_from_fn(128, 128, |x, y| (x + y) as u8);
+    assert!(!is_screen_content(&gradient, 8));
+    let flat = plane_from_fn(128, 128, |_, _| 128);
+    assert!(!is_screen_content(&flat, 8));
+  }
+}
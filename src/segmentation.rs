@@ -9,18 +9,273 @@
 
 #![allow(safe_extern_statics)]
 
+use crate::api::{RoiMap, RoiValues, SegmentationMap};
 use crate::context::*;
 use crate::FrameInvariants;
 use crate::FrameState;
-use crate::util::Pixel;
+use crate::tiling::*;
+use crate::util::{clamp, CastFromPrimitive, Pixel};
 
-pub fn segmentation_optimize<T: Pixel>(_fi: &FrameInvariants<T>, fs: &mut FrameState<T>) {
-    fs.segmentation.enabled = false;
-    fs.segmentation.update_data = false;
-    fs.segmentation.update_map = false;
+/// Variance thresholds between the adaptive quantization classes, for 8-bit
+/// content. Blocks are classified from the flattest (segment 0) to the
+/// busiest (segment 3).
+const AQ_VARIANCE_THRESHOLDS: [u32; 3] = [16, 128, 1024];
 
-    fs.segmentation.features[0][SegLvl::SEG_LVL_ALT_Q as usize] = false;
-    fs.segmentation.data[0][SegLvl::SEG_LVL_ALT_Q as usize] = 0;
+/// Quantizer index offset of each adaptive quantization class, in 1/16 of
+/// the base quantizer index. Flat areas show banding and blocking first, so
+/// they take the bits saved on textured ones.
+const AQ_QINDEX_SCALE: [i32; 4] = [-3, -1, 0, 2];
+
+/// Segment index of each 4x4 luma block of a frame
+#[derive(Clone, Debug, Default)]
+pub struct SegmentIds {
+    cols: usize,
+    rows: usize,
+    ids: Vec<u8>,
+}
+
+impl SegmentIds {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        SegmentIds { cols, rows, ids: vec![0; cols * rows] }
+    }
+
+    /// Segment of the block at `bo`, 0 outside of the map
+    #[inline(always)]
+    pub fn get(&self, bo: BlockOffset) -> u8 {
+        if bo.x < self.cols && bo.y < self.rows {
+            self.ids[bo.y * self.cols + bo.x]
+        } else {
+            0
+        }
+    }
+
+    fn max_id(&self) -> u8 {
+        self.ids.iter().cloned().max().unwrap_or(0)
+    }
+}
+
+/// Quantizer index offset of each superblock of a frame, signaled with
+/// delta q
+#[derive(Clone, Debug, Default)]
+pub struct SuperBlockQindexOffsets {
+    cols: usize,
+    rows: usize,
+    offsets: Vec<i16>,
+}
+
+impl SuperBlockQindexOffsets {
+    /// Offset of the superblock at `sbo`, 0 outside of the map
+    #[inline(always)]
+    pub fn get(&self, sbo: SuperBlockOffset) -> i16 {
+        if sbo.x < self.cols && sbo.y < self.rows {
+            self.offsets[sbo.y * self.cols + sbo.x]
+        } else {
+            0
+        }
+    }
+}
+
+/// Average the offsets of the segments of the blocks of each superblock
+fn sb_qindex_offsets(ids: &SegmentIds, qindex_offsets: &[i16; 8]) -> SuperBlockQindexOffsets {
+    let sb_mi = MAX_MIB_SIZE;
+    let cols = (ids.cols + sb_mi - 1) / sb_mi;
+    let rows = (ids.rows + sb_mi - 1) / sb_mi;
+    let mut offsets = Vec::with_capacity(cols * rows);
+    for sby in 0..rows {
+        for sbx in 0..cols {
+            let mut sum = 0i32;
+            let mut count = 0i32;
+            for y in sby * sb_mi..((sby + 1) * sb_mi).min(ids.rows) {
+                for x in sbx * sb_mi..((sbx + 1) * sb_mi).min(ids.cols) {
+                    sum += qindex_offsets[ids.ids[y * ids.cols + x] as usize] as i32;
+                    count += 1;
+                }
+            }
+            offsets.push((sum / count) as i16);
+        }
+    }
+    SuperBlockQindexOffsets { cols, rows, offsets }
+}
+
+/// Stretch an explicit segmentation grid over the 4x4 blocks of the frame
+fn segment_ids_from_map(map: &SegmentationMap, cols: usize, rows: usize) -> SegmentIds {
+    let mut ids = SegmentIds::new(cols, rows);
+    for y in 0..rows {
+        let map_y = y * map.rows / rows;
+        for x in 0..cols {
+            let map_x = x * map.cols / cols;
+            ids.ids[y * cols + x] = map.segments[map_y * map.cols + map_x].min(7);
+        }
+    }
+    ids
+}
+
+/// Group the cells of a region of interest map into segments by their
+/// quantizer index offset, binning the offsets if there are more than 8
+fn segmentation_from_roi(roi: &RoiMap, base_q_idx: u8) -> SegmentationMap {
+    let offsets: Vec<i16> = match roi.values {
+        RoiValues::Importance(ref importance) => importance.iter()
+            .map(|&i| ((128 - i as i32) * base_q_idx as i32 / 512) as i16)
+            .collect(),
+        RoiValues::QindexOffsets(ref offsets) => offsets.clone(),
+    };
+    let mut levels = offsets.clone();
+    levels.sort();
+    levels.dedup();
+
+    let mut qindex_offsets = [0; 8];
+    let segments = if levels.len() <= 8 {
+        qindex_offsets[..levels.len()].copy_from_slice(&levels);
+        offsets.iter()
+            .map(|o| levels.binary_search(o).unwrap() as u8)
+            .collect()
+    } else {
+        let min = levels[0] as i32;
+        let range = levels[levels.len() - 1] as i32 - min + 1;
+        for (s, offset) in qindex_offsets.iter_mut().enumerate() {
+            *offset = (min + (2 * s as i32 + 1) * range / 16) as i16;
+        }
+        offsets.iter()
+            .map(|&o| ((o as i32 - min) * 8 / range) as u8)
+            .collect()
+    };
+
+    SegmentationMap {
+        cols: roi.cols,
+        rows: roi.rows,
+        segments,
+        qindex_offsets,
+        deblock_offsets: [0; 8],
+    }
+}
+
+/// Classify each 8x8 luma block of the source by its variance
+fn segment_ids_from_variance<T: Pixel>(fi: &FrameInvariants<T>, fs: &FrameState<T>) -> SegmentIds {
+    let mut ids = SegmentIds::new(fi.w_in_b, fi.h_in_b);
+    let shift = 2 * (fi.sequence.bit_depth - 8);
+    let plane = &fs.input.planes[0];
+    for by in (0..fi.h_in_b).step_by(2) {
+        for bx in (0..fi.w_in_b).step_by(2) {
+            let x0 = bx << MI_SIZE_LOG2;
+            let y0 = by << MI_SIZE_LOG2;
+            let w = (fi.width - x0).min(8);
+            let h = (fi.height - y0).min(8);
+            let region = plane.region(Area::Rect { x: x0 as isize, y: y0 as isize, width: w, height: h });
+
+            let mut sum = 0u64;
+            let mut sum_sq = 0u64;
+            for row in region.rows_iter().take(h) {
+                for &p in &row[..w] {
+                    let p = u64::from(u32::cast_from(p));
+                    sum += p;
+                    sum_sq += p * p;
+                }
+            }
+            let n = (w * h) as u64;
+            let variance = ((sum_sq - sum * sum / n) / n) >> shift;
+            let class = AQ_VARIANCE_THRESHOLDS.iter()
+                .filter(|&&t| variance >= u64::from(t)).count() as u8;
+
+            for y in by..(by + 2).min(fi.h_in_b) {
+                for x in bx..(bx + 2).min(fi.w_in_b) {
+                    ids.ids[y * fi.w_in_b + x] = class;
+                }
+            }
+        }
+    }
+    ids
+}
+
+/// `offset` reduced so that the quantizer index of the blocks it applies to
+/// stays within the bounds of the configuration
+fn bounded_qindex_offset<T: Pixel>(fi: &FrameInvariants<T>, offset: i32) -> i32 {
+    // Keep every block lossy, rav1e cannot mix lossless blocks in a frame
+    let min = (fi.config.min_quantizer as i32).max(1);
+    let max = (fi.config.max_quantizer as i32).max(min);
+    let base = fi.base_q_idx as i32;
+    clamp(base + offset, min, max) - base
+}
+
+fn set_qindex_offset<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>, segment: usize, offset: i32) {
+    let delta = bounded_qindex_offset(fi, offset);
+    if delta != 0 {
+        fs.segmentation.features[segment][SegLvl::SEG_LVL_ALT_Q as usize] = true;
+        fs.segmentation.data[segment][SegLvl::SEG_LVL_ALT_Q as usize] = delta as i16;
+    }
+}
+
+fn set_deblock_offset<T: Pixel>(fs: &mut FrameState<T>, segment: usize, offset: i8) {
+    if offset != 0 {
+        let max = MAX_LOOP_FILTER as i16;
+        for j in SegLvl::SEG_LVL_ALT_LF_Y_V as usize..=SegLvl::SEG_LVL_ALT_LF_V as usize {
+            fs.segmentation.features[segment][j] = true;
+            fs.segmentation.data[segment][j] = clamp(offset as i16, -max, max);
+        }
+    }
+}
+
+pub fn segmentation_optimize<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>) {
+    fs.segmentation = Default::default();
+    fs.segment_ids = Default::default();
+    fs.sb_qindex_offsets = Default::default();
+
+    // Segments cannot make a lossless frame lossy
+    if fi.base_q_idx > 0 {
+        let params = fi.frame_params.as_ref();
+        if let Some(map) = params.and_then(|p| p.segmentation.as_ref()) {
+            let offsets = map.qindex_offsets.iter().zip(map.deblock_offsets.iter());
+            for (i, (&qindex_offset, &deblock_offset)) in offsets.enumerate() {
+                set_qindex_offset(fi, fs, i, qindex_offset as i32);
+                set_deblock_offset(fs, i, deblock_offset);
+            }
+            fs.segment_ids = segment_ids_from_map(map, fi.w_in_b, fi.h_in_b);
+        } else {
+            let adaptive = if let Some(roi) = params.and_then(|p| p.roi_map.as_ref()) {
+                let map = segmentation_from_roi(roi, fi.base_q_idx);
+                Some((segment_ids_from_map(&map, fi.w_in_b, fi.h_in_b), map.qindex_offsets))
+            } else if fi.config.variance_aq {
+                let mut qindex_offsets = [0; 8];
+                for (offset, &scale) in qindex_offsets.iter_mut().zip(AQ_QINDEX_SCALE.iter()) {
+                    *offset = (scale * fi.base_q_idx as i32 / 16) as i16;
+                }
+                Some((segment_ids_from_variance(fi, fs), qindex_offsets))
+            } else {
+                None
+            };
+
+            if let Some((ids, qindex_offsets)) = adaptive {
+                if fi.config.aq_delta_q {
+                    // Averages of bounded offsets stay within the bounds
+                    let mut qindex_offsets = qindex_offsets;
+                    for offset in qindex_offsets.iter_mut() {
+                        *offset = bounded_qindex_offset(fi, *offset as i32) as i16;
+                    }
+                    fs.sb_qindex_offsets = sb_qindex_offsets(&ids, &qindex_offsets);
+                } else {
+                    for (i, &offset) in qindex_offsets.iter().enumerate() {
+                        set_qindex_offset(fi, fs, i, offset as i32);
+                    }
+                    fs.segment_ids = ids;
+                }
+            }
+        }
+    }
+
+    fs.segmentation.enabled =
+        fs.segmentation.features.iter().any(|f| f.iter().any(|&f| f));
+    fs.segmentation.update_data = fs.segmentation.enabled;
+    fs.segmentation.update_map = fs.segmentation.enabled;
+    if fs.segmentation.enabled {
+        // Only segments up to the last one with a feature can be coded, so
+        // give the highest segment in use a null feature if it has none.
+        let max_id = fs.segment_ids.max_id() as usize;
+        if !fs.segmentation.features[max_id].iter().any(|&f| f) {
+            fs.segmentation.features[max_id][SegLvl::SEG_LVL_ALT_Q as usize] = true;
+            fs.segmentation.data[max_id][SegLvl::SEG_LVL_ALT_Q as usize] = 0;
+        }
+    } else {
+        fs.segment_ids = Default::default();
+    }
 
     /* Figure out parameters */
     fs.segmentation.preskip = false;
@@ -38,3 +293,74 @@ pub fn segmentation_optimize<T: Pixel>(_fi: &FrameInvariants<T>, fs: &mut FrameS
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn explicit_map_is_stretched_over_the_frame() {
+        let map = SegmentationMap {
+            cols: 2,
+            rows: 2,
+            segments: vec![0, 1, 2, 9],
+            qindex_offsets: [0; 8],
+            deblock_offsets: [0; 8],
+        };
+        let ids = segment_ids_from_map(&map, 6, 4);
+        let at = |x, y| ids.get(BlockOffset { x, y });
+        assert_eq!((at(0, 0), at(2, 1), at(3, 0), at(5, 1)), (0, 0, 1, 1));
+        assert_eq!((at(0, 2), at(2, 3), at(3, 2), at(5, 3)), (2, 2, 7, 7));
+        assert_eq!(at(6, 0), 0);
+    }
+
+    #[test]
+    fn sb_offsets_average_their_blocks() {
+        // Two superblocks and a half, the left one split between 2 segments
+        let mut ids = SegmentIds::new(40, 16);
+        for y in 0..16 {
+            for x in 0..8 {
+                ids.ids[y * 40 + x] = 1;
+            }
+            for x in 32..40 {
+                ids.ids[y * 40 + x] = 2;
+            }
+        }
+        let offsets = sb_qindex_offsets(&ids, &[10, -30, 7, 0, 0, 0, 0, 0]);
+        let at = |x| offsets.get(SuperBlockOffset { x, y: 0 });
+        assert_eq!((at(0), at(1), at(2), at(3)), (-10, 10, 7, 0));
+    }
+
+    #[test]
+    fn roi_offsets_are_grouped_into_segments() {
+        let roi = RoiMap {
+            cols: 2,
+            rows: 2,
+            values: RoiValues::QindexOffsets(vec![-20, 0, 30, 0]),
+        };
+        let map = segmentation_from_roi(&roi, 100);
+        assert_eq!(map.segments, vec![0, 1, 2, 1]);
+        assert_eq!(&map.qindex_offsets[..3], &[-20, 0, 30]);
+
+        let roi = RoiMap {
+            cols: 16,
+            rows: 1,
+            values: RoiValues::QindexOffsets((0..16).collect()),
+        };
+        let map = segmentation_from_roi(&roi, 100);
+        assert_eq!(map.segments, (0..16).map(|o| o / 2).collect::<Vec<u8>>());
+        assert_eq!(map.qindex_offsets, [1, 3, 5, 7, 9, 11, 13, 15]);
+    }
+
+    #[test]
+    fn roi_importance_scales_the_quantizer() {
+        let roi = RoiMap {
+            cols: 3,
+            rows: 1,
+            values: RoiValues::Importance(vec![255, 128, 0]),
+        };
+        let map = segmentation_from_roi(&roi, 200);
+        assert_eq!(map.segments, vec![0, 1, 2]);
+        assert_eq!(&map.qindex_offsets[..3], &[-49, 0, 50]);
+    }
+}
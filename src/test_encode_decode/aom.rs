@@ -129,8 +129,8 @@ fn compare_img<T: Pixel>(img: *const aom_image_t, frame: &Frame<T>, bit_depth: u
   let img_iter = img.planes.iter().zip(img.stride.iter());
 
   for (img_plane, frame_plane) in img_iter.zip(frame.planes.iter()) {
-    let w = width >> frame_plane.cfg.xdec;
-    let h = height >> frame_plane.cfg.ydec;
+    let w = (width + frame_plane.cfg.xdec) >> frame_plane.cfg.xdec;
+    let h = (height + frame_plane.cfg.ydec) >> frame_plane.cfg.ydec;
     let rec_stride = frame_plane.cfg.stride;
 
     if bit_depth > 8 {
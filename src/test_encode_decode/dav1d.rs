@@ -89,8 +89,8 @@ fn compare_pic<T: Pixel>(pic: &Dav1dPicture, frame: &Frame<T>, bit_depth: usize,
   use crate::frame::Plane;
 
   let cmp_plane = |data, stride, frame_plane: &Plane<T>| {
-    let w = width >> frame_plane.cfg.xdec;
-    let h = height >> frame_plane.cfg.ydec;
+    let w = (width + frame_plane.cfg.xdec) >> frame_plane.cfg.xdec;
+    let h = (height + frame_plane.cfg.ydec) >> frame_plane.cfg.ydec;
     let rec_stride = frame_plane.cfg.stride;
 
     if bit_depth > 8 {
@@ -125,6 +125,9 @@ fn compare_pic<T: Pixel>(pic: &Dav1dPicture, frame: &Frame<T>, bit_depth: usize,
   let cstride = pic.stride[1] as usize;
 
   cmp_plane(pic.data[0], lstride, &frame.planes[0]);
-  cmp_plane(pic.data[1], cstride, &frame.planes[1]);
-  cmp_plane(pic.data[2], cstride, &frame.planes[2]);
+  // Monochrome pictures have no chroma planes
+  if !pic.data[1].is_null() {
+    cmp_plane(pic.data[1], cstride, &frame.planes[1]);
+    cmp_plane(pic.data[2], cstride, &frame.planes[2]);
+  }
 }
@@ -8,8 +8,9 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::*;
-use crate::color::ChromaSampling;
+use crate::color::{ChromaSampling, PixelRange};
 use crate::config::*;
+use crate::quantize::QM_LEVEL_FLAT;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaChaRng;
 use std::sync::Arc;
@@ -64,17 +65,25 @@ pub(crate) trait TestDecoder<T: Pixel> {
     min_keyint: u64, max_keyint: u64, low_latency: bool, bitrate: i32,
     tile_cols_log2: usize, tile_rows_log2: usize,
   ) {
-    let mut ra = ChaChaRng::from_seed([0; 32]);
-
-    let mut ctx: Context<T> =
-      setup_encoder(w, h, speed, quantizer, bit_depth, chroma_sampling,
-                    min_keyint, max_keyint, low_latency, bitrate,
-                    tile_cols_log2, tile_rows_log2);
+    let enc =
+      encoder_config::<T>(w, h, speed, quantizer, bit_depth, chroma_sampling,
+                          min_keyint, max_keyint, low_latency, bitrate,
+                          tile_cols_log2, tile_rows_log2);
 
     println!("Encoding {}x{} speed {} quantizer {} bit-depth {}", w, h, speed, quantizer, bit_depth);
+    self.encode_decode_config(enc, limit);
+  }
+
+  /// Encode `limit` frames with `enc`, checking that every packet decodes
+  /// to the reconstruction of the encoder
+  fn encode_decode_config(&mut self, enc: EncoderConfig, limit: usize) {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let (w, h, bit_depth) = (enc.width, enc.height, enc.bit_depth);
+
     #[cfg(feature="dump_ivf")]
-    let mut out = std::fs::File::create(&format!("out-{}x{}-s{}-q{}-{:?}.ivf",
-                                                   w, h, speed, quantizer, chroma_sampling)).unwrap();
+    let mut out = std::fs::File::create(&format!("out-{}x{}-q{}-{:?}.ivf",
+                                                   w, h, enc.quantizer, enc.chroma_sampling)).unwrap();
+    let mut ctx: Context<T> = Config { enc, threads: 0 }.new_context().unwrap();
     #[cfg(feature="dump_ivf")]
     ivf::write_ivf_header(&mut out, w, h, 30, 1);
 
@@ -121,11 +130,11 @@ pub(crate) fn compare_plane<T: Ord + std::fmt::Debug>(
   }
 }
 
-pub(crate) fn setup_encoder<T: Pixel>(
+pub(crate) fn encoder_config<T: Pixel>(
   w: usize, h: usize, speed: usize, quantizer: usize, bit_depth: usize,
   chroma_sampling: ChromaSampling, min_keyint: u64, max_keyint: u64,
   low_latency: bool, bitrate: i32, tile_cols_log2: usize, tile_rows_log2: usize,
-) -> Context<T> {
+) -> EncoderConfig {
   assert!(bit_depth == 8 || std::mem::size_of::<T>() > 1);
   let mut enc = EncoderConfig::with_speed_preset(speed);
   enc.quantizer = quantizer;
@@ -139,13 +148,7 @@ pub(crate) fn setup_encoder<T: Pixel>(
   enc.bitrate = bitrate;
   enc.tile_cols_log2 = tile_cols_log2;
   enc.tile_rows_log2 = tile_rows_log2;
-
-  let cfg = Config {
-    enc,
-    threads: 0,
-  };
-
-  cfg.new_context()
+  enc
 }
 
 // TODO: support non-multiple-of-16 dimensions
@@ -227,6 +230,54 @@ mod tiny_dimension {
   }
 }
 
+macro_rules! test_odd_dimensions {
+  ($(($W:expr, $H:expr, $S:expr, $I:expr)),+) => {
+    $(
+        paste::item!{
+            #[cfg_attr(feature = "decode_test", interpolate_name::interpolate_test(aom, "aom"))]
+            #[cfg_attr(feature = "decode_test_dav1d", interpolate_name::interpolate_test(dav1d, "dav1d"))]
+            fn [<dimension_ $W x $H _ $S>](decoder: &str) {
+                super::odd_dimension($W, $H, $I, decoder)
+            }
+        }
+    )*
+  }
+}
+
+mod odd_dimension {
+  use crate::color::ChromaSampling;
+
+  test_odd_dimensions!{
+    (67, 33, 420, ChromaSampling::Cs420),
+    (67, 33, 422, ChromaSampling::Cs422),
+    (67, 33, 444, ChromaSampling::Cs444),
+    (67, 33, 400, ChromaSampling::Cs400),
+    (399, 301, 420, ChromaSampling::Cs420),
+    (399, 301, 422, ChromaSampling::Cs422),
+    (399, 301, 444, ChromaSampling::Cs444)
+  }
+}
+
+#[cfg(not(feature="quick_test"))]
+mod large_odd_dimension {
+  use crate::color::ChromaSampling;
+
+  test_odd_dimensions!{
+    (1920, 817, 420, ChromaSampling::Cs420)
+  }
+}
+
+// Includes inter frames, which predict from beyond the right and bottom
+// edges of their references
+fn odd_dimension(w: usize, h: usize, cs: ChromaSampling, decoder: &str) {
+  let quantizer = 100;
+  let limit = 3;
+  let speed = 10;
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode(w, h, speed, quantizer, limit, 8, cs, 15, 15, true, 0, 0, 0);
+}
+
 fn dimension(w: usize, h: usize, decoder: &str) {
   let quantizer = 100;
   let limit = 1;
@@ -403,7 +454,7 @@ macro_rules! test_chroma_sampling {
   }
 }
 
-test_chroma_sampling!{(420, ChromaSampling::Cs420), (422, ChromaSampling::Cs422), (444, ChromaSampling::Cs444)}
+test_chroma_sampling!{(400, ChromaSampling::Cs400), (420, ChromaSampling::Cs420), (422, ChromaSampling::Cs422), (444, ChromaSampling::Cs444)}
 
 #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
 #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
@@ -420,6 +471,69 @@ fn tile_encoding_with_stretched_restoration_units(decoder: &str) {
   dec.encode_decode(w, h, speed, q, limit, 8, Default::default(), 15, 15, true, 0, 2, 2);
 }
 
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn quantizer_matrix(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = encoder_config::<u8>(w, h, 10, 100, 8, Default::default(), 15, 15, true, 0, 0, 0);
+  enc.qm_levels = Some([QM_LEVEL_FLAT; 3]);
+
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  dec.encode_decode_config(enc, limit);
+}
+
+// The second search decides with other CDFs than the ones the superblock
+// is coded with
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn superblock_second_pass(decoder: &str) {
+  let limit = 3;
+  let w = 96;
+  let h = 80;
+
+  let mut enc = encoder_config::<u8>(w, h, 10, 100, 8, Default::default(), 15, 15, true, 0, 0, 0);
+  enc.speed_settings.encode_bottomup = true;
+  enc.speed_settings.superblock_second_pass = true;
+
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  dec.encode_decode_config(enc, limit);
+}
+
+// Same setup as the alpha plane of an AVIF, which needs the frame header
+// to leave out the chroma quantizer deltas of a monochrome stream
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn monochrome_still_picture(decoder: &str) {
+  let limit = 1;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = encoder_config::<u8>(w, h, 10, 100, 8, ChromaSampling::Cs400, 15, 15, true, 0, 0, 0);
+  enc.still_picture = true;
+  enc.pixel_range = PixelRange::Full;
+
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  dec.encode_decode_config(enc, limit);
+}
+
+// The decoder model adds buffer removal times to every frame header
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn decoder_model_info(decoder: &str) {
+  let limit = 5;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = encoder_config::<u8>(w, h, 10, 100, 8, Default::default(), 15, 15, true, 500_000, 0, 0);
+  enc.decoder_model_info = true;
+
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  dec.encode_decode_config(enc, limit);
+}
+
 fn get_decoder<T: Pixel>(decoder: &str, w: usize, h: usize) -> Box<dyn TestDecoder<T>> {
   match decoder {
     #[cfg(feature="decode_test")]
@@ -0,0 +1,87 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! The thread pool of the encoder and its parallel iterators
+//!
+//! Without the `threading` feature, e.g. on wasm32-unknown-unknown where
+//! threads cannot be spawned, they are replaced by stand-ins running
+//! everything on the calling thread.
+
+#[cfg(feature = "threading")]
+pub use rayon::iter::{IntoParallelIterator, ParallelIterator};
+#[cfg(feature = "threading")]
+pub use rayon::{ThreadPool, ThreadPoolBuilder};
+
+#[cfg(not(feature = "threading"))]
+pub use self::sequential::*;
+
+#[cfg(not(feature = "threading"))]
+mod sequential {
+  use std::{error, fmt};
+
+  /// Runs everything on the calling thread
+  #[derive(Debug)]
+  pub struct ThreadPool;
+
+  impl ThreadPool {
+    pub fn install<OP: FnOnce() -> R, R>(&self, op: OP) -> R {
+      op()
+    }
+
+    pub fn current_num_threads(&self) -> usize {
+      1
+    }
+  }
+
+  #[derive(Debug, Default)]
+  pub struct ThreadPoolBuilder;
+
+  impl ThreadPoolBuilder {
+    pub fn new() -> Self {
+      ThreadPoolBuilder
+    }
+
+    /// Ignored, there is only ever the calling thread
+    pub fn num_threads(self, _num_threads: usize) -> Self {
+      self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, ThreadPoolBuildError> {
+      Ok(ThreadPool)
+    }
+  }
+
+  /// Never returned, for the signature of `ThreadPoolBuilder::build` to
+  /// match the one of rayon
+  #[derive(Debug)]
+  pub struct ThreadPoolBuildError;
+
+  impl fmt::Display for ThreadPoolBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      write!(f, "Cannot build the thread pool")
+    }
+  }
+
+  impl error::Error for ThreadPoolBuildError {}
+
+  /// Iterates in order where rayon would iterate in parallel
+  pub trait IntoParallelIterator {
+    type Iter: Iterator;
+
+    fn into_par_iter(self) -> Self::Iter;
+  }
+
+  impl<I: IntoIterator> IntoParallelIterator for I {
+    type Iter = I::IntoIter;
+
+    fn into_par_iter(self) -> I::IntoIter {
+      self.into_iter()
+    }
+  }
+}
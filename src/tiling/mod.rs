@@ -9,6 +9,7 @@
 
 mod plane_region;
 mod tile;
+mod tile_arena;
 mod tile_blocks;
 mod tile_motion_vectors;
 mod tile_restoration_state;
@@ -17,6 +18,7 @@ mod tiler;
 
 pub use self::plane_region::*;
 pub use self::tile::*;
+pub use self::tile_arena::*;
 pub use self::tile_blocks::*;
 pub use self::tile_motion_vectors::*;
 pub use self::tile_restoration_state::*;
@@ -0,0 +1,95 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::rdo::RDOPartitionOutput;
+use crate::util::*;
+
+use std::fmt;
+
+/// Number of mode decision vectors kept across superblocks
+const MAX_POOLED_MODES: usize = 32;
+
+/// Scratch memory for the transient buffers of the RDO of a tile
+///
+/// Transform blocks are coded one at a time, so a single set of coefficient
+/// buffers serves every candidate of the tile, and stays hot in cache.
+///
+/// The partition search recurses, so the vectors collecting the decisions of
+/// its children are handed out from a free list and given back once a
+/// candidate has been evaluated. The list is trimmed when a superblock
+/// starts, so that an unusually deep search does not pin memory for the
+/// rest of the tile.
+pub struct TileArena {
+  pub residual: AlignedArray<[i16; 64 * 64]>,
+  pub coeffs: AlignedArray<[i32; 64 * 64]>,
+  pub qcoeffs: AlignedArray<[i32; 64 * 64]>,
+  pub rcoeffs: AlignedArray<[i32; 64 * 64]>,
+  modes: Vec<Vec<RDOPartitionOutput>>,
+}
+
+impl TileArena {
+  pub fn new() -> Box<Self> {
+    Box::new(Self {
+      residual: AlignedArray([0; 64 * 64]),
+      coeffs: AlignedArray([0; 64 * 64]),
+      qcoeffs: AlignedArray([0; 64 * 64]),
+      rcoeffs: AlignedArray([0; 64 * 64]),
+      modes: Vec::new(),
+    })
+  }
+
+  /// Prepare the arena for a new superblock.
+  pub fn reset(&mut self) {
+    self.modes.truncate(MAX_POOLED_MODES);
+  }
+
+  /// An empty vector of mode decisions, reusing a returned one if possible.
+  pub fn take_modes(&mut self) -> Vec<RDOPartitionOutput> {
+    self.modes.pop().unwrap_or_default()
+  }
+
+  /// Return a vector obtained from `take_modes` to the arena.
+  pub fn give_modes(&mut self, mut modes: Vec<RDOPartitionOutput>) {
+    modes.clear();
+    self.modes.push(modes);
+  }
+}
+
+impl fmt::Debug for TileArena {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("TileArena")
+      .field("pooled_modes", &self.modes.len())
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn modes_are_recycled() {
+    let mut arena = TileArena::new();
+    let mut modes = arena.take_modes();
+    modes.reserve(4);
+    let ptr = modes.as_ptr();
+    arena.give_modes(modes);
+
+    let modes = arena.take_modes();
+    assert!(modes.is_empty());
+    assert_eq!(modes.as_ptr(), ptr);
+    arena.give_modes(modes);
+
+    for _ in 0..2 * MAX_POOLED_MODES {
+      arena.give_modes(Vec::new());
+    }
+    arena.reset();
+    assert_eq!(arena.modes.len(), MAX_POOLED_MODES);
+  }
+}
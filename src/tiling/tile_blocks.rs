@@ -9,8 +9,9 @@
 
 use crate::context::*;
 use crate::mc::MotionVector;
+use crate::palette::Palette;
 use crate::partition::*;
-use crate::predict::PredictionMode;
+use crate::predict::{CompoundParams, PredictionMode};
 use crate::transform::*;
 
 use std::cmp;
@@ -181,6 +182,16 @@ impl TileBlocksMut<'_> {
     self.for_each(bo, bsize, |block| block.mode = mode);
   }
 
+  #[inline(always)]
+  pub fn set_partition(
+    &mut self,
+    bo: BlockOffset,
+    bsize: BlockSize,
+    partition: PartitionType,
+  ) {
+    self.for_each(bo, bsize, |block| block.partition = partition);
+  }
+
   #[inline(always)]
   pub fn set_block_size(&mut self, bo: BlockOffset, bsize: BlockSize) {
     let n4_w = bsize.width_mi();
@@ -202,6 +213,16 @@ impl TileBlocksMut<'_> {
     self.for_each(bo, bsize, |block| block.txsize = tx_size);
   }
 
+  #[inline(always)]
+  pub fn set_tx_type(
+    &mut self,
+    bo: BlockOffset,
+    bsize: BlockSize,
+    tx_type: TxType,
+  ) {
+    self.for_each(bo, bsize, |block| block.txtype = tx_type);
+  }
+
   #[inline(always)]
   pub fn set_skip(&mut self, bo: BlockOffset, bsize: BlockSize, skip: bool) {
     self.for_each(bo, bsize, |block| block.skip = skip);
@@ -237,6 +258,26 @@ impl TileBlocksMut<'_> {
     self.for_each(bo, bsize, |block| block.mv = mvs);
   }
 
+  #[inline(always)]
+  pub fn set_compound(
+    &mut self,
+    bo: BlockOffset,
+    bsize: BlockSize,
+    compound: CompoundParams,
+  ) {
+    self.for_each(bo, bsize, |block| block.compound = compound);
+  }
+
+  #[inline(always)]
+  pub fn set_palette(
+    &mut self,
+    bo: BlockOffset,
+    bsize: BlockSize,
+    palette: Palette,
+  ) {
+    self.for_each(bo, bsize, |block| block.palette = palette);
+  }
+
   #[inline(always)]
   pub fn set_cdef(&mut self, sbo: SuperBlockOffset, cdef_index: u8) {
     let bo = sbo.block_offset(0, 0);
@@ -184,12 +184,14 @@ macro_rules! tile_restoration_plane_common {
       }
 
       fn restoration_unit_index(&self, sbo: SuperBlockOffset) -> Option<(usize, usize)> {
-        // there is 1 restoration unit for (1 << sb_shift) super-blocks
-        let mask = (1 << self.rp_cfg.sb_shift) - 1;
-        let first_sbo = sbo.x & mask == 0 && sbo.y & mask == 0;
+        // there is 1 restoration unit for (1 << sb_h_shift) super-blocks
+        // horizontally and (1 << sb_v_shift) vertically
+        let h_mask = (1 << self.rp_cfg.sb_h_shift) - 1;
+        let v_mask = (1 << self.rp_cfg.sb_v_shift) - 1;
+        let first_sbo = sbo.x & h_mask == 0 && sbo.y & v_mask == 0;
         if first_sbo {
-          let x = sbo.x >> self.rp_cfg.sb_shift;
-          let y = sbo.y >> self.rp_cfg.sb_shift;
+          let x = sbo.x >> self.rp_cfg.sb_h_shift;
+          let y = sbo.y >> self.rp_cfg.sb_v_shift;
           if x < self.units.cols && y < self.units.rows {
             Some((x, y))
           } else {
@@ -266,23 +268,26 @@ macro_rules! tile_restoration_state_common {
         sb_width: usize,
         sb_height: usize,
       ) -> Self {
-        let (units_x, units_y, units_cols, units_rows) =
-          Self::get_units_region(rs, sbo, sb_width, sb_height);
-
         // we cannot retrieve &mut of slice items directly and safely
         let mut planes_iter = rs.planes.$iter();
         Self {
           planes: [
             {
               let plane = planes_iter.next().unwrap();
+              let (units_x, units_y, units_cols, units_rows) =
+                Self::get_units_region(plane, sbo, sb_width, sb_height);
               $trp_type::new(plane, units_x, units_y, units_cols, units_rows)
             },
             {
               let plane = planes_iter.next().unwrap();
+              let (units_x, units_y, units_cols, units_rows) =
+                Self::get_units_region(plane, sbo, sb_width, sb_height);
               $trp_type::new(plane, units_x, units_y, units_cols, units_rows)
             },
             {
               let plane = planes_iter.next().unwrap();
+              let (units_x, units_y, units_cols, units_rows) =
+                Self::get_units_region(plane, sbo, sb_width, sb_height);
               $trp_type::new(plane, units_x, units_y, units_cols, units_rows)
             },
           ],
@@ -291,23 +296,23 @@ macro_rules! tile_restoration_state_common {
 
       #[inline(always)]
       fn get_units_region(
-        rs: &RestorationState,
+        rp: &RestorationPlane,
         sbo: SuperBlockOffset,
         sb_width: usize,
         sb_height: usize,
       ) -> (usize, usize, usize, usize) {
-        let sb_shift = rs.planes[0].cfg.sb_shift;
+        let RestorationPlaneConfig { sb_h_shift, sb_v_shift, .. } = rp.cfg;
         // there may be several super-blocks per restoration unit
         // the given super-block offset must match the start of a restoration unit
-        debug_assert!(sbo.x % (1 << sb_shift) == 0);
-        debug_assert!(sbo.y % (1 << sb_shift) == 0);
+        debug_assert!(sbo.x % (1 << sb_h_shift) == 0);
+        debug_assert!(sbo.y % (1 << sb_v_shift) == 0);
 
-        let units_x = sbo.x >> sb_shift;
-        let units_y = sbo.y >> sb_shift;
-        let units_cols = sb_width >> sb_shift;
-        let units_rows = sb_height >> sb_shift;
+        let units_x = sbo.x >> sb_h_shift;
+        let units_y = sbo.y >> sb_v_shift;
+        let units_cols = (sb_width + (1 << sb_h_shift) - 1) >> sb_h_shift;
+        let units_rows = (sb_height + (1 << sb_v_shift) - 1) >> sb_v_shift;
 
-        let FrameRestorationUnits { cols: rs_cols, rows: rs_rows, .. } = rs.planes[0].units;
+        let FrameRestorationUnits { cols: rs_cols, rows: rs_rows, .. } = rp.units;
         // +1 because the last super-block may use the "stretched" restoration unit
         // from its neighbours
         // <https://github.com/xiph/rav1e/issues/631#issuecomment-454419152>
@@ -323,12 +328,12 @@ macro_rules! tile_restoration_state_common {
         (units_x, units_y, units_cols, units_rows)
       }
 
+      /// Whether a restoration unit of any plane starts at the super-block.
+      /// The chroma units of 4:2:2 and 4:2:0 frames do not always start
+      /// with the luma ones.
       #[inline(always)]
       pub fn has_restoration_unit(&self, sbo: SuperBlockOffset) -> bool {
-        let is_some = self.planes[0].restoration_unit(sbo).is_some();
-        debug_assert_eq!(is_some, self.planes[1].restoration_unit(sbo).is_some());
-        debug_assert_eq!(is_some, self.planes[2].restoration_unit(sbo).is_some());
-        is_some
+        self.planes.iter().any(|plane| plane.restoration_unit(sbo).is_some())
       }
     }
   }
@@ -9,11 +9,15 @@
 
 use super::*;
 
+use crate::activity::ActivityMask;
 use crate::context::*;
 use crate::encoder::*;
 use crate::frame::*;
+use crate::intrabc::BlockHashMap;
 use crate::quantize::*;
 use crate::rdo::*;
+use crate::segmentation::{SegmentIds, SuperBlockQindexOffsets};
+use crate::timing::StageTimes;
 use crate::util::*;
 
 /// Tiled view of FrameState
@@ -52,11 +56,27 @@ pub struct TileStateMut<'a, T: Pixel> {
   pub input_qres: &'a Plane<T>,
   pub deblock: &'a DeblockState,
   pub rec: TileMut<'a, T>,
+  pub pred: Option<TileMut<'a, T>>,
   pub qc: QuantizationContext,
   pub segmentation: &'a SegmentationState,
+  pub segment_ids: &'a SegmentIds,
+  pub sb_qindex_offsets: &'a SuperBlockQindexOffsets,
+  pub mean_block_variance: f64,
+  pub activity_mask: &'a ActivityMask,
   pub restoration: TileRestorationStateMut<'a>,
   pub mvs: Vec<TileMotionVectorsMut<'a>>,
   pub rdo: RDOTracker,
+  /// Lowest cost of the coarse motion search, summed over superblocks
+  pub me_cost: u64,
+  /// Time spent in each stage, if `time_stages` is set
+  pub stage_times: StageTimes,
+  /// Rates of the motion vectors, from the CDFs of the tile
+  pub mv_rates: MvRates,
+  /// Rates of the coefficients, from the CDFs of the tile
+  pub coeff_rates: CoeffRates,
+  pub intrabc_hash: Option<&'a BlockHashMap>,
+  /// Scratch memory for the transient buffers of RDO
+  pub arena: Box<TileArena>,
 }
 
 impl<'a, T: Pixel> TileStateMut<'a, T> {
@@ -92,8 +112,13 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
       input_qres: &fs.input_qres,
       deblock: &fs.deblock,
       rec: TileMut::new(&mut fs.rec, luma_rect),
+      pred: fs.pred.as_mut().map(|pred| TileMut::new(pred, luma_rect)),
       qc: Default::default(),
       segmentation: &fs.segmentation,
+      segment_ids: &fs.segment_ids,
+      sb_qindex_offsets: &fs.sb_qindex_offsets,
+      mean_block_variance: fs.mean_block_variance,
+      activity_mask: &fs.activity_mask,
       restoration: TileRestorationStateMut::new(
         &mut fs.restoration,
         sbo,
@@ -114,6 +139,12 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
         })
         .collect(),
       rdo: RDOTracker::new(),
+      me_cost: 0,
+      stage_times: StageTimes::default(),
+      mv_rates: MvRates::default(),
+      coeff_rates: CoeffRates::new(&CDFContext::new(0)),
+      intrabc_hash: fs.intrabc_hash.as_ref(),
+      arena: TileArena::new(),
     }
   }
 
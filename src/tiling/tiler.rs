@@ -12,6 +12,8 @@ use super::*;
 use crate::context::*;
 use crate::encoder::*;
 use crate::util::*;
+#[cfg(feature = "serialize")]
+use serde_derive::{Serialize, Deserialize};
 
 use std::marker::PhantomData;
 
@@ -28,6 +30,7 @@ pub const MAX_TILE_ROWS: usize = 64;
 /// The method tile_iter_mut() actually provides tiled views of FrameState
 /// and FrameBlocks.
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct TilingInfo {
   pub frame_width: usize,
   pub frame_height: usize,
@@ -515,6 +518,29 @@ pub mod test {
     }
   }
 
+  #[test]
+  fn test_tile_restoration_422() {
+    let fi = create_frame_invariants(256, 256, ChromaSampling::Cs422);
+    let mut fs = FrameState::new(&fi);
+    let mut fb = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+    // 2x2 tiles, each one containing 2x2 super-blocks
+    let ti = TilingInfo::new(fi.sb_size_log2(), fi.width, fi.height, 1, 1);
+    let iter = ti.tile_iter_mut(&mut fs, &mut fb);
+    let tile_states = iter.map(|ctx| ctx.ts).collect::<Vec<_>>();
+
+    // chroma restoration units span 2 super-blocks horizontally
+    let trs = &tile_states[1].restoration;
+    let units = &trs.planes[1].units;
+    assert_eq!((units.x(), units.y()), (1, 0));
+    assert_eq!((units.cols(), units.rows()), (1, 2));
+    let sbo = SuperBlockOffset { x: 1, y: 1 };
+    assert!(trs.planes[0].restoration_unit(sbo).is_some());
+    assert!(trs.planes[1].restoration_unit(sbo).is_none());
+    assert!(trs.has_restoration_unit(sbo));
+    let sbo = SuperBlockOffset { x: 0, y: 1 };
+    assert!(trs.planes[1].restoration_unit(sbo).is_some());
+  }
+
   #[test]
   fn test_tile_restoration_write() {
     let fi = create_frame_invariants(256, 256, ChromaSampling::Cs420);
@@ -0,0 +1,160 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Time spent in each stage of the encoder
+//!
+//! With `EncoderConfig::time_stages`, every frame accumulates the time of
+//! its stages into `FrameEncodeStats::stage_times`. With the `tracing`
+//! feature, the stages are also `tracing` spans, whatever the configuration.
+
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+/// The current time, `None` where there is no clock
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> Option<Instant> {
+  Some(Instant::now())
+}
+
+/// `Instant::now` panics on wasm32-unknown-unknown
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> Option<Instant> {
+  None
+}
+
+/// A stage of the coding of a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+  /// Motion search of the superblocks, ahead of the mode decisions
+  MotionEstimation,
+  /// Partition and mode decisions, less the stages they go through
+  ModeDecision,
+  /// Forward and inverse transforms and quantization
+  Transform,
+  /// Coding of the coefficients and of the final bitstream
+  EntropyCoding,
+  /// Decision and application of the deblocking, CDEF and loop restoration
+  /// filters
+  LoopFilters,
+}
+
+/// Time spent in each stage, summed over the tiles of a frame, so that it
+/// adds up to more than the time of the frame when tiles run in parallel
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageTimes {
+  pub motion_estimation: Duration,
+  pub mode_decision: Duration,
+  pub transform: Duration,
+  pub entropy_coding: Duration,
+  pub loop_filters: Duration,
+}
+
+impl StageTimes {
+  pub fn get(&self, stage: Stage) -> Duration {
+    match stage {
+      Stage::MotionEstimation => self.motion_estimation,
+      Stage::ModeDecision => self.mode_decision,
+      Stage::Transform => self.transform,
+      Stage::EntropyCoding => self.entropy_coding,
+      Stage::LoopFilters => self.loop_filters,
+    }
+  }
+
+  fn get_mut(&mut self, stage: Stage) -> &mut Duration {
+    match stage {
+      Stage::MotionEstimation => &mut self.motion_estimation,
+      Stage::ModeDecision => &mut self.mode_decision,
+      Stage::Transform => &mut self.transform,
+      Stage::EntropyCoding => &mut self.entropy_coding,
+      Stage::LoopFilters => &mut self.loop_filters,
+    }
+  }
+
+  fn total(&self) -> Duration {
+    self.motion_estimation
+      + self.mode_decision
+      + self.transform
+      + self.entropy_coding
+      + self.loop_filters
+  }
+}
+
+impl AddAssign for StageTimes {
+  fn add_assign(&mut self, rhs: StageTimes) {
+    self.motion_estimation += rhs.motion_estimation;
+    self.mode_decision += rhs.mode_decision;
+    self.transform += rhs.transform;
+    self.entropy_coding += rhs.entropy_coding;
+    self.loop_filters += rhs.loop_filters;
+  }
+}
+
+/// Times a stage from its creation until `stop`, excluding the stages timed
+/// in between so that nested stages are not counted twice
+pub(crate) struct StageTimer {
+  stage: Stage,
+  start: Option<(Instant, Duration)>,
+  #[cfg(feature = "tracing")]
+  _span: tracing::span::EnteredSpan,
+}
+
+impl StageTimer {
+  /// Starts timing `stage` if `enabled`, `times` being where it will be
+  /// accumulated
+  #[inline(always)]
+  pub fn start(stage: Stage, enabled: bool, times: &StageTimes) -> Self {
+    StageTimer {
+      stage,
+      start: if enabled {
+        now().map(|start| (start, times.total()))
+      } else {
+        None
+      },
+      #[cfg(feature = "tracing")]
+      _span: match stage {
+        Stage::MotionEstimation => tracing::debug_span!("motion_estimation"),
+        Stage::ModeDecision => tracing::debug_span!("mode_decision"),
+        Stage::Transform => tracing::trace_span!("transform"),
+        Stage::EntropyCoding => tracing::trace_span!("entropy_coding"),
+        Stage::LoopFilters => tracing::debug_span!("loop_filters"),
+      }
+      .entered(),
+    }
+  }
+
+  #[inline(always)]
+  pub fn stop(self, times: &mut StageTimes) {
+    if let Some((start, nested_before)) = self.start {
+      let nested = times.total() - nested_before;
+      *times.get_mut(self.stage) += start.elapsed() - nested;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn nested_stages_are_excluded() {
+    let mut times = StageTimes::default();
+    let outer = StageTimer::start(Stage::ModeDecision, true, &times);
+    let inner = StageTimer::start(Stage::Transform, true, &times);
+    std::thread::sleep(Duration::from_millis(10));
+    inner.stop(&mut times);
+    outer.stop(&mut times);
+
+    assert!(times.transform >= Duration::from_millis(10));
+    assert!(times.mode_decision < times.transform);
+
+    let disabled = StageTimer::start(Stage::LoopFilters, false, &times);
+    disabled.stop(&mut times);
+    assert_eq!(times.loop_filters, Duration::default());
+  }
+}
@@ -57,7 +57,7 @@ const FWD_TXFM_SHIFT_LS: [TxfmShifts; TxSize::TX_SIZES_ALL] = [
   FWD_SHIFT_64X16
 ];
 
-type TxfmFunc = dyn Fn(&[i32], &mut [i32]);
+type TxfmFunc<T> = fn(&[T], &mut [T]);
 
 use std::ops::*;
 
@@ -1650,21 +1650,21 @@ impl TxfmType {
     [TxfmType::DCT64, TxfmType::Invalid, TxfmType::Invalid, TxfmType::Invalid]
   ];
 
-  fn get_func(self) -> &'static TxfmFunc {
+  fn get_func<T: TxOperations>(self) -> TxfmFunc<T> {
     use self::TxfmType::*;
     match self {
-      DCT4 => &daala_fdct4,
-      DCT8 => &daala_fdct8,
-      DCT16 => &daala_fdct16,
-      DCT32 => &daala_fdct32,
-      DCT64 => &daala_fdct64,
-      ADST4 => &daala_fdst_vii_4,
-      ADST8 => &daala_fdst8,
-      ADST16 => &daala_fdst16,
-      Identity4 => &fidentity4,
-      Identity8 => &fidentity8,
-      Identity16 => &fidentity16,
-      Identity32 => &fidentity32,
+      DCT4 => daala_fdct4,
+      DCT8 => daala_fdct8,
+      DCT16 => daala_fdct16,
+      DCT32 => daala_fdct32,
+      DCT64 => daala_fdct64,
+      ADST4 => daala_fdst_vii_4,
+      ADST8 => daala_fdst8,
+      ADST16 => daala_fdst16,
+      Identity4 => fidentity4,
+      Identity8 => fidentity8,
+      Identity16 => fidentity16,
+      Identity32 => fidentity32,
       _ => unreachable!()
     }
   }
@@ -1731,73 +1731,90 @@ trait FwdTxfm2D: Dim {
     input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
     bd: usize
   ) {
-    let mut tmp: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
-    let buf = &mut tmp.array[..Self::W * Self::H];
     let cfg =
       Txfm2DFlipCfg::fwd(tx_type, TxSize::by_dims(Self::W, Self::H), bd);
 
-    // Note when assigning txfm_size_col, we use the txfm_size from the
-    // row configuration and vice versa. This is intentionally done to
-    // accurately perform rectangular transforms. When the transform is
-    // rectangular, the number of columns will be the same as the
-    // txfm_size stored in the row cfg struct. It will make no difference
-    // for square transforms.
-    let txfm_size_col = TxSize::width(cfg.tx_size);
-    let txfm_size_row = TxSize::height(cfg.tx_size);
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      if is_x86_feature_detected!("sse2") {
+        if sse2::fwd_txfm2d_i16_exact(cfg.tx_size, bd) {
+          return unsafe { sse2::fwd_txfm2d_i16(input, output, stride, &cfg) };
+        }
+        return unsafe { sse2::fwd_txfm2d(input, output, stride, &cfg) };
+      }
+    }
 
-    let txfm_func_col = cfg.txfm_type_col.get_func();
-    let txfm_func_row = cfg.txfm_type_row.get_func();
+    fwd_txfm2d_rs(input, output, stride, &cfg);
+  }
+}
 
-    // Columns
-    for c in 0..txfm_size_col {
-      let mut col_flip_backing: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
-      let col_flip = &mut col_flip_backing.array[..txfm_size_row];
-      if cfg.ud_flip {
-        // flip upside down
-        for r in 0..txfm_size_row {
-          col_flip[r] = (input[(txfm_size_row - r - 1) * stride + c]).into();
-        }
-      } else {
-        for r in 0..txfm_size_row {
-          col_flip[r] = (input[r * stride + c]).into();
-        }
+fn fwd_txfm2d_rs(
+  input: &[i16], output: &mut [i32], stride: usize, cfg: &Txfm2DFlipCfg
+) {
+  let mut tmp: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
+  let buf = &mut tmp.array[..cfg.tx_size.area()];
+
+  // Note when assigning txfm_size_col, we use the txfm_size from the
+  // row configuration and vice versa. This is intentionally done to
+  // accurately perform rectangular transforms. When the transform is
+  // rectangular, the number of columns will be the same as the
+  // txfm_size stored in the row cfg struct. It will make no difference
+  // for square transforms.
+  let txfm_size_col = TxSize::width(cfg.tx_size);
+  let txfm_size_row = TxSize::height(cfg.tx_size);
+
+  let txfm_func_col = cfg.txfm_type_col.get_func::<i32>();
+  let txfm_func_row = cfg.txfm_type_row.get_func::<i32>();
+
+  // Columns
+  for c in 0..txfm_size_col {
+    let mut col_flip_backing: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
+    let col_flip = &mut col_flip_backing.array[..txfm_size_row];
+    if cfg.ud_flip {
+      // flip upside down
+      for r in 0..txfm_size_row {
+        col_flip[r] = (input[(txfm_size_row - r - 1) * stride + c]).into();
       }
-      av1_round_shift_array(col_flip, txfm_size_row, -cfg.shift[0]);
-      txfm_func_col(
-        &col_flip,
-        &mut output[txfm_size_row..]
-      );
-      av1_round_shift_array(
-        &mut output[txfm_size_row..],
-        txfm_size_row,
-        -cfg.shift[1]
-      );
-      if cfg.lr_flip {
-        for r in 0..txfm_size_row {
-          // flip from left to right
-          buf[r * txfm_size_col + (txfm_size_col - c - 1)] =
-            output[txfm_size_row + r];
-        }
-      } else {
-        for r in 0..txfm_size_row {
-          buf[r * txfm_size_col + c] = output[txfm_size_row + r];
-        }
+    } else {
+      for r in 0..txfm_size_row {
+        col_flip[r] = (input[r * stride + c]).into();
       }
     }
-
-    // Rows
-    for r in 0..txfm_size_row {
-      txfm_func_row(
-        &buf[r * txfm_size_col..],
-        &mut output[r * txfm_size_col..]
-      );
-      av1_round_shift_array(
-        &mut output[r * txfm_size_col..],
-        txfm_size_col,
-        -cfg.shift[2]
-      );
+    av1_round_shift_array(col_flip, txfm_size_row, -cfg.shift[0]);
+    txfm_func_col(
+      &col_flip,
+      &mut output[txfm_size_row..]
+    );
+    av1_round_shift_array(
+      &mut output[txfm_size_row..],
+      txfm_size_row,
+      -cfg.shift[1]
+    );
+    if cfg.lr_flip {
+      for r in 0..txfm_size_row {
+        // flip from left to right
+        buf[r * txfm_size_col + (txfm_size_col - c - 1)] =
+          output[txfm_size_row + r];
+      }
+    } else {
+      for r in 0..txfm_size_row {
+        buf[r * txfm_size_col + c] = output[txfm_size_row + r];
+      }
     }
   }
+
+  // Rows
+  for r in 0..txfm_size_row {
+    txfm_func_row(
+      &buf[r * txfm_size_col..],
+      &mut output[r * txfm_size_col..]
+    );
+    av1_round_shift_array(
+      &mut output[r * txfm_size_col..],
+      txfm_size_col,
+      -cfg.shift[2]
+    );
+  }
 }
 
 macro_rules! impl_fwd_txs {
@@ -2003,3 +2020,459 @@ pub fn fht64x16(
     }
   }
 }
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sse2 {
+  use super::*;
+  #[cfg(target_arch = "x86")]
+  use std::arch::x86::*;
+  #[cfg(target_arch = "x86_64")]
+  use std::arch::x86_64::*;
+
+  /// Four lanes of the 1D transforms, one per column (or row) of the block
+  #[derive(Copy, Clone)]
+  struct I32X4(__m128i);
+
+  impl Default for I32X4 {
+    fn default() -> Self {
+      I32X4(unsafe { _mm_setzero_si128() })
+    }
+  }
+
+  impl Add for I32X4 {
+    type Output = Self;
+    fn add(self, b: Self) -> Self {
+      I32X4(unsafe { _mm_add_epi32(self.0, b.0) })
+    }
+  }
+
+  impl Sub for I32X4 {
+    type Output = Self;
+    fn sub(self, b: Self) -> Self {
+      I32X4(unsafe { _mm_sub_epi32(self.0, b.0) })
+    }
+  }
+
+  impl TxOperations for I32X4 {
+    fn tx_mul(self, mul: (i32, i32)) -> Self {
+      unsafe {
+        // SSE2 has no 32-bit mullo, so multiply the even and odd lanes
+        // separately and interleave the low halves of the products.
+        let a = self.0;
+        let b = _mm_set1_epi32(mul.0);
+        let even = _mm_mul_epu32(a, b);
+        let odd = _mm_mul_epu32(_mm_srli_epi64(a, 32), b);
+        let prod = _mm_unpacklo_epi32(
+          _mm_shuffle_epi32(even, 0b00_00_10_00),
+          _mm_shuffle_epi32(odd, 0b00_00_10_00)
+        );
+        let rounded = _mm_add_epi32(prod, _mm_set1_epi32(1 << mul.1 >> 1));
+        I32X4(_mm_sra_epi32(rounded, _mm_cvtsi32_si128(mul.1)))
+      }
+    }
+
+    fn rshift1(self) -> Self {
+      unsafe {
+        let neg = _mm_srli_epi32(self.0, 31);
+        I32X4(_mm_srai_epi32(_mm_add_epi32(self.0, neg), 1))
+      }
+    }
+
+    fn add_avg(self, b: Self) -> Self {
+      I32X4(unsafe { _mm_srai_epi32(_mm_add_epi32(self.0, b.0), 1) })
+    }
+
+    fn sub_avg(self, b: Self) -> Self {
+      I32X4(unsafe { _mm_srai_epi32(_mm_sub_epi32(self.0, b.0), 1) })
+    }
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn round_shift_array(arr: &mut [I32X4], bit: i8) {
+    if bit == 0 {
+      return;
+    }
+    if bit > 0 {
+      let rounding = _mm_set1_epi32(1 << bit >> 1);
+      let shift = _mm_cvtsi32_si128(bit as i32);
+      for v in arr.iter_mut() {
+        v.0 = _mm_sra_epi32(_mm_add_epi32(v.0, rounding), shift);
+      }
+    } else {
+      let shift = _mm_cvtsi32_si128(-bit as i32);
+      for v in arr.iter_mut() {
+        v.0 = _mm_sll_epi32(v.0, shift);
+      }
+    }
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn transpose4x4(v: &[I32X4]) -> [I32X4; 4] {
+    let t0 = _mm_unpacklo_epi32(v[0].0, v[1].0);
+    let t1 = _mm_unpacklo_epi32(v[2].0, v[3].0);
+    let t2 = _mm_unpackhi_epi32(v[0].0, v[1].0);
+    let t3 = _mm_unpackhi_epi32(v[2].0, v[3].0);
+    [
+      I32X4(_mm_unpacklo_epi64(t0, t1)),
+      I32X4(_mm_unpackhi_epi64(t0, t1)),
+      I32X4(_mm_unpacklo_epi64(t2, t3)),
+      I32X4(_mm_unpackhi_epi64(t2, t3))
+    ]
+  }
+
+  /// Same as `fwd_txfm2d_rs`, running the 1D transforms on four columns,
+  /// then four rows, at a time.
+  #[target_feature(enable = "sse2")]
+  pub(super) unsafe fn fwd_txfm2d(
+    input: &[i16], output: &mut [i32], stride: usize, cfg: &Txfm2DFlipCfg
+  ) {
+    let txfm_size_col = cfg.tx_size.width();
+    let txfm_size_row = cfg.tx_size.height();
+    assert!(input.len() >= (txfm_size_row - 1) * stride + txfm_size_col);
+    assert!(output.len() >= txfm_size_col * txfm_size_row);
+
+    let txfm_func_col = cfg.txfm_type_col.get_func::<I32X4>();
+    let txfm_func_row = cfg.txfm_type_row.get_func::<I32X4>();
+
+    // Column outputs, 4 columns of a row per vector, grouped by columns
+    let mut tmp: AlignedArray<[I32X4; 64 * 64 / 4]> =
+      UninitializedAlignedArray();
+    let mut line_in: AlignedArray<[I32X4; 64]> = UninitializedAlignedArray();
+    let mut line_out: AlignedArray<[I32X4; 64]> = UninitializedAlignedArray();
+
+    // Columns
+    for cg in 0..txfm_size_col / 4 {
+      let col_in = &mut line_in.array[..txfm_size_row];
+      for (r, v) in col_in.iter_mut().enumerate() {
+        let src_r = if cfg.ud_flip { txfm_size_row - r - 1 } else { r };
+        let ptr = input[src_r * stride + cg * 4..].as_ptr();
+        let x = _mm_loadl_epi64(ptr as *const _);
+        v.0 = _mm_srai_epi32(_mm_unpacklo_epi16(x, x), 16);
+      }
+      round_shift_array(col_in, -cfg.shift[0]);
+      let col_out = &mut line_out.array[..txfm_size_row];
+      txfm_func_col(col_in, col_out);
+      round_shift_array(col_out, -cfg.shift[1]);
+      let dst_cg = if cfg.lr_flip { txfm_size_col / 4 - cg - 1 } else { cg };
+      let dst = &mut tmp.array[dst_cg * txfm_size_row..][..txfm_size_row];
+      for (d, v) in dst.iter_mut().zip(col_out.iter()) {
+        // flip from left to right
+        *d = if cfg.lr_flip {
+          I32X4(_mm_shuffle_epi32(v.0, 0b00_01_10_11))
+        } else {
+          *v
+        };
+      }
+    }
+
+    // Rows
+    for rg in 0..txfm_size_row / 4 {
+      let row_in = &mut line_in.array[..txfm_size_col];
+      for cg in 0..txfm_size_col / 4 {
+        let t = transpose4x4(&tmp.array[cg * txfm_size_row + rg * 4..]);
+        row_in[cg * 4..cg * 4 + 4].copy_from_slice(&t);
+      }
+      let row_out = &mut line_out.array[..txfm_size_col];
+      txfm_func_row(row_in, row_out);
+      round_shift_array(row_out, -cfg.shift[2]);
+      for cg in 0..txfm_size_col / 4 {
+        let t = transpose4x4(&row_out[cg * 4..]);
+        for (i, v) in t.iter().enumerate() {
+          let dst = output[(rg * 4 + i) * txfm_size_col + cg * 4..].as_mut_ptr();
+          _mm_storeu_si128(dst as *mut _, v.0);
+        }
+      }
+    }
+  }
+
+  /// Eight 16-bit lanes of the 1D transforms. Only exact for inputs whose
+  /// intermediates fit in 16 bits, see `fwd_txfm2d_i16`.
+  #[derive(Copy, Clone)]
+  struct I16X8(__m128i);
+
+  impl Default for I16X8 {
+    fn default() -> Self {
+      I16X8(unsafe { _mm_setzero_si128() })
+    }
+  }
+
+  impl Add for I16X8 {
+    type Output = Self;
+    fn add(self, b: Self) -> Self {
+      I16X8(unsafe { _mm_add_epi16(self.0, b.0) })
+    }
+  }
+
+  impl Sub for I16X8 {
+    type Output = Self;
+    fn sub(self, b: Self) -> Self {
+      I16X8(unsafe { _mm_sub_epi16(self.0, b.0) })
+    }
+  }
+
+  impl TxOperations for I16X8 {
+    fn tx_mul(self, mul: (i32, i32)) -> Self {
+      unsafe {
+        // Widen the products to 32 bits. Multipliers above i16::MAX wrap
+        // when splatted, so add back the missing self << 16.
+        let a = self.0;
+        let m = _mm_set1_epi16(mul.0 as i16);
+        let lo = _mm_mullo_epi16(a, m);
+        let hi = _mm_mulhi_epi16(a, m);
+        let mut p0 = _mm_unpacklo_epi16(lo, hi);
+        let mut p1 = _mm_unpackhi_epi16(lo, hi);
+        if mul.0 > i16::max_value() as i32 {
+          let zero = _mm_setzero_si128();
+          p0 = _mm_add_epi32(p0, _mm_unpacklo_epi16(zero, a));
+          p1 = _mm_add_epi32(p1, _mm_unpackhi_epi16(zero, a));
+        }
+        let rounding = _mm_set1_epi32(1 << mul.1 >> 1);
+        let shift = _mm_cvtsi32_si128(mul.1);
+        I16X8(_mm_packs_epi32(
+          _mm_sra_epi32(_mm_add_epi32(p0, rounding), shift),
+          _mm_sra_epi32(_mm_add_epi32(p1, rounding), shift)
+        ))
+      }
+    }
+
+    fn rshift1(self) -> Self {
+      unsafe {
+        let neg = _mm_srli_epi16(self.0, 15);
+        I16X8(_mm_srai_epi16(_mm_add_epi16(self.0, neg), 1))
+      }
+    }
+
+    fn add_avg(self, b: Self) -> Self {
+      // (a + b) >> 1 without overflowing the sum
+      unsafe {
+        let (a, b) = (self.0, b.0);
+        let carry = _mm_and_si128(_mm_and_si128(a, b), _mm_set1_epi16(1));
+        I16X8(_mm_add_epi16(
+          _mm_add_epi16(_mm_srai_epi16(a, 1), _mm_srai_epi16(b, 1)),
+          carry
+        ))
+      }
+    }
+
+    fn sub_avg(self, b: Self) -> Self {
+      // (a - b) >> 1 without overflowing the difference
+      unsafe {
+        let (a, b) = (self.0, b.0);
+        let borrow = _mm_and_si128(_mm_andnot_si128(a, b), _mm_set1_epi16(1));
+        I16X8(_mm_sub_epi16(
+          _mm_sub_epi16(_mm_srai_epi16(a, 1), _mm_srai_epi16(b, 1)),
+          borrow
+        ))
+      }
+    }
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn round_shift_array_i16(arr: &mut [I16X8], bit: i8) {
+    if bit == 0 {
+      return;
+    }
+    if bit > 0 {
+      let rounding = _mm_set1_epi16(1 << bit >> 1);
+      let shift = _mm_cvtsi32_si128(bit as i32);
+      for v in arr.iter_mut() {
+        v.0 = _mm_sra_epi16(_mm_add_epi16(v.0, rounding), shift);
+      }
+    } else {
+      let shift = _mm_cvtsi32_si128(-bit as i32);
+      for v in arr.iter_mut() {
+        v.0 = _mm_sll_epi16(v.0, shift);
+      }
+    }
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn transpose8x8(v: &[I16X8; 8]) -> [I16X8; 8] {
+    let a0 = _mm_unpacklo_epi16(v[0].0, v[1].0);
+    let a1 = _mm_unpacklo_epi16(v[2].0, v[3].0);
+    let a2 = _mm_unpacklo_epi16(v[4].0, v[5].0);
+    let a3 = _mm_unpacklo_epi16(v[6].0, v[7].0);
+    let a4 = _mm_unpackhi_epi16(v[0].0, v[1].0);
+    let a5 = _mm_unpackhi_epi16(v[2].0, v[3].0);
+    let a6 = _mm_unpackhi_epi16(v[4].0, v[5].0);
+    let a7 = _mm_unpackhi_epi16(v[6].0, v[7].0);
+    let b0 = _mm_unpacklo_epi32(a0, a1);
+    let b1 = _mm_unpacklo_epi32(a2, a3);
+    let b2 = _mm_unpackhi_epi32(a0, a1);
+    let b3 = _mm_unpackhi_epi32(a2, a3);
+    let b4 = _mm_unpacklo_epi32(a4, a5);
+    let b5 = _mm_unpacklo_epi32(a6, a7);
+    let b6 = _mm_unpackhi_epi32(a4, a5);
+    let b7 = _mm_unpackhi_epi32(a6, a7);
+    [
+      I16X8(_mm_unpacklo_epi64(b0, b1)),
+      I16X8(_mm_unpackhi_epi64(b0, b1)),
+      I16X8(_mm_unpacklo_epi64(b2, b3)),
+      I16X8(_mm_unpackhi_epi64(b2, b3)),
+      I16X8(_mm_unpacklo_epi64(b4, b5)),
+      I16X8(_mm_unpackhi_epi64(b4, b5)),
+      I16X8(_mm_unpacklo_epi64(b6, b7)),
+      I16X8(_mm_unpackhi_epi64(b6, b7))
+    ]
+  }
+
+  /// Whether `fwd_txfm2d_i16` gives the same output as `fwd_txfm2d_rs`.
+  /// With 8-bit residuals, the intermediates of transforms up to 8x8 stay
+  /// within 16 bits; larger ones can overflow.
+  pub(super) fn fwd_txfm2d_i16_exact(tx_size: TxSize, bd: usize) -> bool {
+    bd == 8 && tx_size.width() <= 8 && tx_size.height() <= 8
+  }
+
+  /// Same as `fwd_txfm2d_rs` with 16-bit intermediates, running the whole
+  /// block at once, eight columns then eight rows per vector.
+  #[target_feature(enable = "sse2")]
+  pub(super) unsafe fn fwd_txfm2d_i16(
+    input: &[i16], output: &mut [i32], stride: usize, cfg: &Txfm2DFlipCfg
+  ) {
+    let txfm_size_col = cfg.tx_size.width();
+    let txfm_size_row = cfg.tx_size.height();
+    assert!(txfm_size_col <= 8 && txfm_size_row <= 8);
+    assert!(input.len() >= (txfm_size_row - 1) * stride + txfm_size_col);
+    assert!(output.len() >= txfm_size_col * txfm_size_row);
+
+    let txfm_func_col = cfg.txfm_type_col.get_func::<I16X8>();
+    let txfm_func_row = cfg.txfm_type_row.get_func::<I16X8>();
+
+    // Columns, one row of the block per vector
+    let mut col_in = [I16X8::default(); 8];
+    for (r, v) in col_in[..txfm_size_row].iter_mut().enumerate() {
+      let src_r = if cfg.ud_flip { txfm_size_row - r - 1 } else { r };
+      let ptr = input[src_r * stride..].as_ptr() as *const _;
+      v.0 = if txfm_size_col == 8 {
+        _mm_loadu_si128(ptr)
+      } else {
+        _mm_loadl_epi64(ptr)
+      };
+    }
+    round_shift_array_i16(&mut col_in[..txfm_size_row], -cfg.shift[0]);
+    let mut col_out = [I16X8::default(); 8];
+    txfm_func_col(&col_in[..txfm_size_row], &mut col_out[..txfm_size_row]);
+    round_shift_array_i16(&mut col_out[..txfm_size_row], -cfg.shift[1]);
+    if cfg.lr_flip {
+      for v in col_out[..txfm_size_row].iter_mut() {
+        // flip from left to right
+        let x = _mm_shufflelo_epi16(v.0, 0b00_01_10_11);
+        v.0 = if txfm_size_col == 8 {
+          let x = _mm_shufflehi_epi16(x, 0b00_01_10_11);
+          _mm_shuffle_epi32(x, 0b01_00_11_10)
+        } else {
+          x
+        };
+      }
+    }
+
+    // Rows, one column of the block per vector
+    let row_in = transpose8x8(&col_out);
+    let mut row_out = [I16X8::default(); 8];
+    txfm_func_row(&row_in[..txfm_size_col], &mut row_out[..txfm_size_col]);
+    round_shift_array_i16(&mut row_out[..txfm_size_col], -cfg.shift[2]);
+
+    let t = transpose8x8(&row_out);
+    for (r, v) in t[..txfm_size_row].iter().enumerate() {
+      let dst = output[r * txfm_size_col..].as_mut_ptr();
+      let lo = _mm_srai_epi32(_mm_unpacklo_epi16(v.0, v.0), 16);
+      _mm_storeu_si128(dst as *mut _, lo);
+      if txfm_size_col == 8 {
+        let hi = _mm_srai_epi32(_mm_unpackhi_epi16(v.0, v.0), 16);
+        _mm_storeu_si128(dst.add(4) as *mut _, hi);
+      }
+    }
+  }
+
+  #[cfg(test)]
+  mod test {
+    use super::*;
+    use rand::random;
+
+    #[test]
+    fn fwd_txfm2d_matches_rust() {
+      if !is_x86_feature_detected!("sse2") {
+        return;
+      }
+      let tx_types = [
+        TxType::DCT_DCT, TxType::ADST_DCT, TxType::DCT_ADST,
+        TxType::ADST_ADST, TxType::FLIPADST_DCT, TxType::DCT_FLIPADST,
+        TxType::FLIPADST_FLIPADST, TxType::ADST_FLIPADST,
+        TxType::FLIPADST_ADST, TxType::IDTX, TxType::V_DCT, TxType::H_DCT,
+        TxType::V_ADST, TxType::H_ADST, TxType::V_FLIPADST,
+        TxType::H_FLIPADST
+      ];
+      use crate::transform::TxSize::*;
+      let tx_sizes = [
+        TX_4X4, TX_8X8, TX_16X16, TX_32X32, TX_64X64, TX_4X8, TX_8X4,
+        TX_8X16, TX_16X8, TX_16X32, TX_32X16, TX_32X64, TX_64X32, TX_4X16,
+        TX_16X4, TX_8X32, TX_32X8, TX_16X64, TX_64X16
+      ];
+      let mut input = [0i16; 64 * 64];
+      let mut expected = [0i32; 64 * 64];
+      let mut actual = [0i32; 64 * 64];
+      for &bd in &[8, 10, 12] {
+        for &tx_size in tx_sizes.iter() {
+          for &tx_type in tx_types.iter() {
+            let txfm_type_col =
+              TxfmType::AV1_TXFM_TYPE_LS[tx_size.height_index()]
+                [VTX_TAB[tx_type as usize] as usize];
+            let txfm_type_row =
+              TxfmType::AV1_TXFM_TYPE_LS[tx_size.width_index()]
+                [HTX_TAB[tx_type as usize] as usize];
+            if txfm_type_col == TxfmType::Invalid
+              || txfm_type_row == TxfmType::Invalid
+            {
+              continue;
+            }
+            for v in input.iter_mut() {
+              *v = random::<i16>() >> (15 - bd);
+            }
+            let cfg = Txfm2DFlipCfg::fwd(tx_type, tx_size, bd);
+            let stride = tx_size.width();
+            fwd_txfm2d_rs(&input, &mut expected, stride, &cfg);
+            unsafe { fwd_txfm2d(&input, &mut actual, stride, &cfg) };
+            let area = tx_size.area();
+            assert_eq!(&expected[..area], &actual[..area]);
+          }
+        }
+      }
+    }
+
+    #[test]
+    fn fwd_txfm2d_i16_matches_rust() {
+      if !is_x86_feature_detected!("sse2") {
+        return;
+      }
+      use crate::transform::TxSize::*;
+      let tx_sizes = [TX_4X4, TX_8X8, TX_4X8, TX_8X4];
+      let mut input = [0i16; 8 * 8];
+      let mut expected = [0i32; 8 * 8];
+      let mut actual = [0i32; 8 * 8];
+      for &tx_size in tx_sizes.iter() {
+        assert!(fwd_txfm2d_i16_exact(tx_size, 8));
+        for &tx_type in RAV1E_TX_TYPES.iter() {
+          // Full-scale residuals with random signs stress the 16-bit
+          // intermediates the most, then flat and random blocks.
+          for i in 0..256 {
+            for (j, v) in input.iter_mut().enumerate() {
+              *v = match i {
+                0 => 255,
+                1 => -255,
+                2 => if j % 2 == 0 { 255 } else { -255 },
+                3..=127 => if random::<bool>() { 255 } else { -255 },
+                _ => random::<i16>() % 256
+              };
+            }
+            let cfg = Txfm2DFlipCfg::fwd(tx_type, tx_size, 8);
+            let stride = tx_size.width();
+            fwd_txfm2d_rs(&input, &mut expected, stride, &cfg);
+            unsafe { fwd_txfm2d_i16(&input, &mut actual, stride, &cfg) };
+            let area = tx_size.area();
+            assert_eq!(&expected[..area], &actual[..area]);
+          }
+        }
+      }
+    }
+  }
+}
@@ -29,7 +29,57 @@ static SINPI_INV: [i32; 5] = [0, 1321, 2482, 3344, 3803];
 
 const INV_COS_BIT: usize = 12;
 
-pub fn av1_idct4(input: &[i32], output: &mut [i32], range: usize) {
+use std::ops::*;
+
+/// Arithmetic of the 1D inverse transforms, on a single coefficient or on
+/// the coefficients of several lines transformed at once
+pub trait InvTxOperations:
+  Copy + Default + Add<Output = Self> + Sub<Output = Self> + Neg<Output = Self>
+{
+  fn mul_const(self, c: i32) -> Self;
+  fn half_btf(w0: i32, in0: Self, w1: i32, in1: Self, bit: usize) -> Self;
+  fn clamp_value(self, bit: usize) -> Self;
+  fn round_shift(self, bit: usize) -> Self;
+}
+
+impl InvTxOperations for i32 {
+  fn mul_const(self, c: i32) -> Self {
+    self * c
+  }
+
+  fn half_btf(w0: i32, in0: Self, w1: i32, in1: Self, bit: usize) -> Self {
+    super::half_btf(w0, in0, w1, in1, bit)
+  }
+
+  fn clamp_value(self, bit: usize) -> Self {
+    super::clamp_value(self, bit)
+  }
+
+  fn round_shift(self, bit: usize) -> Self {
+    crate::util::round_shift(self, bit)
+  }
+}
+
+#[inline(always)]
+fn half_btf<T: InvTxOperations>(
+  w0: i32, in0: T, w1: i32, in1: T, bit: usize
+) -> T {
+  T::half_btf(w0, in0, w1, in1, bit)
+}
+
+#[inline(always)]
+fn clamp_value<T: InvTxOperations>(value: T, bit: usize) -> T {
+  value.clamp_value(bit)
+}
+
+#[inline(always)]
+fn round_shift<T: InvTxOperations>(value: T, bit: usize) -> T {
+  value.round_shift(bit)
+}
+
+pub fn av1_idct4<T: InvTxOperations>(
+  input: &[T], output: &mut [T], range: usize
+) {
   // stage 1
   let stg1 = [input[0], input[2], input[1], input[3]];
 
@@ -48,7 +98,9 @@ pub fn av1_idct4(input: &[i32], output: &mut [i32], range: usize) {
   output[3] = clamp_value(stg2[0] - stg2[3], range);
 }
 
-fn av1_iadst4(input: &[i32], output: &mut [i32], _range: usize) {
+fn av1_iadst4<T: InvTxOperations>(
+  input: &[T], output: &mut [T], _range: usize
+) {
   let bit = 12;
 
   let x0 = input[0];
@@ -57,13 +109,13 @@ fn av1_iadst4(input: &[i32], output: &mut [i32], _range: usize) {
   let x3 = input[3];
 
   // stage 1
-  let s0 = SINPI_INV[1] * x0;
-  let s1 = SINPI_INV[2] * x0;
-  let s2 = SINPI_INV[3] * x1;
-  let s3 = SINPI_INV[4] * x2;
-  let s4 = SINPI_INV[1] * x2;
-  let s5 = SINPI_INV[2] * x3;
-  let s6 = SINPI_INV[4] * x3;
+  let s0 = x0.mul_const(SINPI_INV[1]);
+  let s1 = x0.mul_const(SINPI_INV[2]);
+  let s2 = x1.mul_const(SINPI_INV[3]);
+  let s3 = x2.mul_const(SINPI_INV[4]);
+  let s4 = x2.mul_const(SINPI_INV[1]);
+  let s5 = x3.mul_const(SINPI_INV[2]);
+  let s6 = x3.mul_const(SINPI_INV[4]);
 
   // stage 2
   let s7 = (x0 - x2) + x3;
@@ -72,7 +124,7 @@ fn av1_iadst4(input: &[i32], output: &mut [i32], _range: usize) {
   let s0 = s0 + s3;
   let s1 = s1 - s4;
   let s3 = s2;
-  let s2 = SINPI_INV[3] * s7;
+  let s2 = s7.mul_const(SINPI_INV[3]);
 
   // stage 4
   let s0 = s0 + s5;
@@ -93,16 +145,20 @@ fn av1_iadst4(input: &[i32], output: &mut [i32], _range: usize) {
   output[3] = round_shift(x3, bit);
 }
 
-fn av1_iidentity4(input: &[i32], output: &mut [i32], _range: usize) {
+fn av1_iidentity4<T: InvTxOperations>(
+  input: &[T], output: &mut [T], _range: usize
+) {
   for i in 0..4 {
-    output[i] = round_shift(SQRT2 * input[i], 12);
+    output[i] = round_shift(input[i].mul_const(SQRT2), 12);
   }
 }
 
-pub fn av1_idct8(input: &[i32], output: &mut [i32], range: usize) {
+pub fn av1_idct8<T: InvTxOperations>(
+  input: &[T], output: &mut [T], range: usize
+) {
   // call idct4
   let temp_in = [input[0], input[2], input[4], input[6]];
-  let mut temp_out: [i32; 4] = [0; 4];
+  let mut temp_out = [T::default(); 4];
   av1_idct4(&temp_in, &mut temp_out, range);
 
   // stage 0
@@ -145,7 +201,9 @@ pub fn av1_idct8(input: &[i32], output: &mut [i32], range: usize) {
   output[7] = clamp_value(temp_out[0] - stg4[3], range);
 }
 
-fn av1_iadst8(input: &[i32], output: &mut [i32], range: usize) {
+fn av1_iadst8<T: InvTxOperations>(
+  input: &[T], output: &mut [T], range: usize
+) {
   // stage 1
   let stg1 = [
     input[7], input[0], input[5], input[2], input[3], input[4], input[1],
@@ -223,19 +281,23 @@ fn av1_iadst8(input: &[i32], output: &mut [i32], range: usize) {
   output[7] = -stg6[1];
 }
 
-fn av1_iidentity8(input: &[i32], output: &mut [i32], _range: usize) {
+fn av1_iidentity8<T: InvTxOperations>(
+  input: &[T], output: &mut [T], _range: usize
+) {
   for i in 0..8 {
-    output[i] = 2 * input[i];
+    output[i] = input[i] + input[i];
   }
 }
 
-fn av1_idct16(input: &[i32], output: &mut [i32], range: usize) {
+fn av1_idct16<T: InvTxOperations>(
+  input: &[T], output: &mut [T], range: usize
+) {
   // call idct8
   let temp_in = [
     input[0], input[2], input[4], input[6], input[8], input[10], input[12],
     input[14],
   ];
-  let mut temp_out: [i32; 8] = [0; 8];
+  let mut temp_out = [T::default(); 8];
   av1_idct8(&temp_in, &mut temp_out, range);
 
   // stage 1
@@ -323,7 +385,9 @@ fn av1_idct16(input: &[i32], output: &mut [i32], range: usize) {
   output[15] = clamp_value(temp_out[0] - stg6[7], range);
 }
 
-fn av1_iadst16(input: &[i32], output: &mut [i32], range: usize) {
+fn av1_iadst16<T: InvTxOperations>(
+  input: &[T], output: &mut [T], range: usize
+) {
   // stage 1
   let stg1 = [
     input[15], input[0], input[13], input[2], input[11], input[4], input[9],
@@ -490,13 +554,17 @@ fn av1_iadst16(input: &[i32], output: &mut [i32], range: usize) {
   output[15] = -stg8[1];
 }
 
-fn av1_iidentity16(input: &[i32], output: &mut [i32], _range: usize) {
+fn av1_iidentity16<T: InvTxOperations>(
+  input: &[T], output: &mut [T], _range: usize
+) {
   for i in 0..16 {
-    output[i] = round_shift(SQRT2 * 2 * input[i], 12);
+    output[i] = round_shift(input[i].mul_const(SQRT2 * 2), 12);
   }
 }
 
-fn av1_idct32(input: &[i32], output: &mut [i32], range: usize) {
+fn av1_idct32<T: InvTxOperations>(
+  input: &[T], output: &mut [T], range: usize
+) {
   // stage 1;
   let stg1 = [
     input[0], input[16], input[8], input[24], input[4], input[20], input[12],
@@ -793,13 +861,17 @@ fn av1_idct32(input: &[i32], output: &mut [i32], range: usize) {
   output[31] = clamp_value(stg8[0] - stg8[31], range);
 }
 
-fn av1_iidentity32(input: &[i32], output: &mut [i32], _range: usize) {
+fn av1_iidentity32<T: InvTxOperations>(
+  input: &[T], output: &mut [T], _range: usize
+) {
   for i in 0..32 {
-    output[i] = input[i] * 4;
+    output[i] = input[i].mul_const(4);
   }
 }
 
-fn av1_idct64(input: &[i32], output: &mut [i32], range: usize) {
+fn av1_idct64<T: InvTxOperations>(
+  input: &[T], output: &mut [T], range: usize
+) {
     // stage 1;
   let stg1 = [
     input[0], input[32], input[16], input[48], input[8], input[40], input[24], input[56],
@@ -1491,20 +1563,30 @@ fn av1_idct64(input: &[i32], output: &mut [i32], range: usize) {
   output[63] = clamp_value(stg10[0] - stg10[63], range);
 }
 
-type InvTxfmFn = fn(input: &[i32], output: &mut [i32], range: usize);
-
-static INV_TXFM_FNS: [[InvTxfmFn; 5]; 4] = [
-  [av1_idct4, av1_idct8, av1_idct16, av1_idct32, av1_idct64],
-  [av1_iadst4, av1_iadst8, av1_iadst16, |_, _, _| unimplemented!(), |_, _, _| unimplemented!()],
-  [
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!()
-  ],
-  [av1_iidentity4, av1_iidentity8, av1_iidentity16, av1_iidentity32, |_, _, _| unimplemented!()]
-];
+type InvTxfmFn<T> = fn(input: &[T], output: &mut [T], range: usize);
+
+/// 1D inverse transform of type `tx_type` over `1 << (size_log2 + 2)` points.
+/// FLIPADST is the ADST with its output reversed by the caller.
+fn get_inv_txfm_fn<T: InvTxOperations>(
+  tx_type: TxType1D, size_log2: usize
+) -> InvTxfmFn<T> {
+  use self::TxType1D::*;
+  match (tx_type, size_log2) {
+    (DCT, 0) => av1_idct4,
+    (DCT, 1) => av1_idct8,
+    (DCT, 2) => av1_idct16,
+    (DCT, 3) => av1_idct32,
+    (DCT, 4) => av1_idct64,
+    (ADST, 0) | (FLIPADST, 0) => av1_iadst4,
+    (ADST, 1) | (FLIPADST, 1) => av1_iadst8,
+    (ADST, 2) | (FLIPADST, 2) => av1_iadst16,
+    (IDTX, 0) => av1_iidentity4,
+    (IDTX, 1) => av1_iidentity8,
+    (IDTX, 2) => av1_iidentity16,
+    (IDTX, 3) => av1_iidentity32,
+    _ => unimplemented!()
+  }
+}
 
 #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
 mod nasm {
@@ -1531,7 +1613,8 @@ mod nasm {
         let mut coeff16: AlignedArray<[i16; 32 * 32]> =
           UninitializedAlignedArray();
 
-        // Transpose the input.
+        // Transpose the input. Dequantized coefficients of 8-bit content
+        // are clamped to the i16 range, so the narrowing below is lossless.
         // TODO: should be possible to remove changing how coeffs are written
         for j in 0..coeff_h {
           for i in 0..coeff_w {
@@ -1664,57 +1747,97 @@ mod native {
     ) where
       T: Pixel,
     {
-      // For 64 point transforms, rely on the last 32 columns being initialized
-      //   to zero for filling out missing input coeffs.
-      let buffer = &mut [0i32; 64 * 64][..Self::W * Self::H];
-      let rect_type = get_rect_tx_log_ratio(Self::W, Self::H);
-      let tx_types_1d = get_1d_tx_types(tx_type)
-        .expect("TxType not supported by rust txfm code.");
-
-      // perform inv txfm on every row
-      let range = bd + 8;
-      let txfm_fn = INV_TXFM_FNS[tx_types_1d.1 as usize][Self::W.ilog() - 3];
-      for (input_slice, buffer_slice) in
-        // 64 point transforms only signal 32 coeffs. We only take chunks of 32
-        //   and skip over the last 32 transforms here.
-        input.chunks(Self::W.min(32)).take(Self::H.min(32)).
-          zip(buffer.chunks_mut(Self::W))
+      let tx_size = TxSize::by_dims(Self::W, Self::H);
+
+      #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
       {
-        // For 64 point transforms, rely on the last 32 elements being
-        //   initialized to zero for filling out the missing coeffs.
-        let mut temp_in: [i32; 64] = [0; 64];
-        for (raw, clamped) in input_slice.iter().zip(temp_in.iter_mut()) {
-          let val = if rect_type.abs() == 1 {
-            round_shift(*raw * INV_SQRT2, SQRT2_BITS)
-          } else {
-            *raw
+        if is_x86_feature_detected!("sse2") {
+          return unsafe {
+            sse2::inv_txfm2d_add(
+              input,
+              output,
+              tx_type,
+              tx_size,
+              Self::INTERMEDIATE_SHIFT,
+              bd
+            )
           };
-          *clamped = clamp_value(val, range);
         }
-        txfm_fn(&temp_in, buffer_slice, range);
       }
 
-      // perform inv txfm on every col
-      let range = cmp::max(bd + 6, 16);
-      let txfm_fn = INV_TXFM_FNS[tx_types_1d.0 as usize][Self::H.ilog() - 3];
-      for c in 0..Self::W {
-        let mut temp_in: [i32; 64] = [0; 64];
-        let mut temp_out: [i32; 64] = [0; 64];
-        for (raw, clamped) in
-          buffer[c..].iter().step_by(Self::W).zip(temp_in.iter_mut())
-        {
-          *clamped =
-            clamp_value(round_shift(*raw, Self::INTERMEDIATE_SHIFT), range);
-        }
-        txfm_fn(&temp_in, &mut temp_out, range);
-        for (temp, out) in temp_out
-          .iter()
-          .zip(output.rows_iter_mut().map(|row| &mut row[c]).take(Self::H))
-        {
-          let v: i32 = (*out).as_();
-          let v = clamp(v + round_shift(*temp, 4), 0, (1 << bd) - 1);
-          *out = T::cast_from(v);
-        }
+      inv_txfm2d_add_rs(
+        input,
+        output,
+        tx_type,
+        tx_size,
+        Self::INTERMEDIATE_SHIFT,
+        bd
+      );
+    }
+  }
+
+  pub(super) fn inv_txfm2d_add_rs<T: Pixel>(
+    input: &[i32], output: &mut PlaneRegionMut<'_, T>, tx_type: TxType,
+    tx_size: TxSize, intermediate_shift: usize, bd: usize
+  ) {
+    let w = tx_size.width();
+    let h = tx_size.height();
+    // For 64 point transforms, rely on the last 32 columns being initialized
+    //   to zero for filling out missing input coeffs.
+    let buffer = &mut [0i32; 64 * 64][..w * h];
+    let rect_type = get_rect_tx_log_ratio(w, h);
+    let tx_type_1d_col = VTX_TAB[tx_type as usize];
+    let tx_type_1d_row = HTX_TAB[tx_type as usize];
+    let ud_flip = tx_type_1d_col == TxType1D::FLIPADST;
+    let lr_flip = tx_type_1d_row == TxType1D::FLIPADST;
+
+    // perform inv txfm on every row
+    let range = bd + 8;
+    let txfm_fn = get_inv_txfm_fn::<i32>(tx_type_1d_row, w.ilog() - 3);
+    for (input_slice, buffer_slice) in
+      // 64 point transforms only signal 32 coeffs. We only take chunks of 32
+      //   and skip over the last 32 transforms here.
+      input.chunks(w.min(32)).take(h.min(32)).zip(buffer.chunks_mut(w))
+    {
+      // For 64 point transforms, rely on the last 32 elements being
+      //   initialized to zero for filling out the missing coeffs.
+      let mut temp_in: [i32; 64] = [0; 64];
+      for (raw, clamped) in input_slice.iter().zip(temp_in.iter_mut()) {
+        let val = if rect_type.abs() == 1 {
+          round_shift(*raw * INV_SQRT2, SQRT2_BITS)
+        } else {
+          *raw
+        };
+        *clamped = clamp_value(val, range);
+      }
+      txfm_fn(&temp_in, buffer_slice, range);
+      if lr_flip {
+        buffer_slice.reverse();
+      }
+    }
+
+    // perform inv txfm on every col
+    let range = cmp::max(bd + 6, 16);
+    let txfm_fn = get_inv_txfm_fn::<i32>(tx_type_1d_col, h.ilog() - 3);
+    for c in 0..w {
+      let mut temp_in: [i32; 64] = [0; 64];
+      let mut temp_out: [i32; 64] = [0; 64];
+      for (raw, clamped) in
+        buffer[c..].iter().step_by(w).zip(temp_in.iter_mut())
+      {
+        *clamped = clamp_value(round_shift(*raw, intermediate_shift), range);
+      }
+      txfm_fn(&temp_in, &mut temp_out, range);
+      if ud_flip {
+        temp_out[..h].reverse();
+      }
+      for (temp, out) in temp_out
+        .iter()
+        .zip(output.rows_iter_mut().map(|row| &mut row[c]).take(h))
+      {
+        let v: i32 = (*out).as_();
+        let v = clamp(v + round_shift(*temp, 4), 0, (1 << bd) - 1);
+        *out = T::cast_from(v);
       }
     }
   }
@@ -1749,6 +1872,281 @@ mod native {
   impl_inv_txs! { (32, 32), (8, 32), (32, 8) 2 }
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sse2 {
+  use super::*;
+  use crate::util::clamp;
+
+  use std::cmp;
+  #[cfg(target_arch = "x86")]
+  use std::arch::x86::*;
+  #[cfg(target_arch = "x86_64")]
+  use std::arch::x86_64::*;
+
+  /// Four lanes of the 1D transforms, one per row (or column) of the block
+  #[derive(Copy, Clone)]
+  struct I32X4(__m128i);
+
+  impl Default for I32X4 {
+    fn default() -> Self {
+      I32X4(unsafe { _mm_setzero_si128() })
+    }
+  }
+
+  impl Add for I32X4 {
+    type Output = Self;
+    fn add(self, b: Self) -> Self {
+      I32X4(unsafe { _mm_add_epi32(self.0, b.0) })
+    }
+  }
+
+  impl Sub for I32X4 {
+    type Output = Self;
+    fn sub(self, b: Self) -> Self {
+      I32X4(unsafe { _mm_sub_epi32(self.0, b.0) })
+    }
+  }
+
+  impl Neg for I32X4 {
+    type Output = Self;
+    fn neg(self) -> Self {
+      I32X4(unsafe { _mm_sub_epi32(_mm_setzero_si128(), self.0) })
+    }
+  }
+
+  impl InvTxOperations for I32X4 {
+    fn mul_const(self, c: i32) -> Self {
+      unsafe {
+        // SSE2 has no 32-bit mullo, so multiply the even and odd lanes
+        // separately and interleave the low halves of the products.
+        let b = _mm_set1_epi32(c);
+        let even = _mm_mul_epu32(self.0, b);
+        let odd = _mm_mul_epu32(_mm_srli_epi64(self.0, 32), b);
+        I32X4(_mm_unpacklo_epi32(
+          _mm_shuffle_epi32(even, 0b00_00_10_00),
+          _mm_shuffle_epi32(odd, 0b00_00_10_00)
+        ))
+      }
+    }
+
+    fn half_btf(w0: i32, in0: Self, w1: i32, in1: Self, bit: usize) -> Self {
+      let result = in0.mul_const(w0) + in1.mul_const(w1);
+      if bit == 0 {
+        result
+      } else {
+        unsafe {
+          let rounded = _mm_add_epi32(result.0, _mm_set1_epi32(1 << (bit - 1)));
+          I32X4(_mm_sra_epi32(rounded, _mm_cvtsi32_si128(bit as i32)))
+        }
+      }
+    }
+
+    fn clamp_value(self, bit: usize) -> Self {
+      let max_value: i32 = ((1i64 << (bit - 1)) - 1) as i32;
+      let min_value: i32 = (-(1i64 << (bit - 1))) as i32;
+      unsafe {
+        // SSE2 has no 32-bit min and max, so select with comparison masks
+        let min = _mm_set1_epi32(min_value);
+        let max = _mm_set1_epi32(max_value);
+        let below = _mm_cmplt_epi32(self.0, min);
+        let v = _mm_or_si128(
+          _mm_and_si128(below, min),
+          _mm_andnot_si128(below, self.0)
+        );
+        let above = _mm_cmpgt_epi32(v, max);
+        I32X4(_mm_or_si128(
+          _mm_and_si128(above, max),
+          _mm_andnot_si128(above, v)
+        ))
+      }
+    }
+
+    fn round_shift(self, bit: usize) -> Self {
+      unsafe {
+        let rounded = _mm_add_epi32(self.0, _mm_set1_epi32(1 << bit >> 1));
+        I32X4(_mm_sra_epi32(rounded, _mm_cvtsi32_si128(bit as i32)))
+      }
+    }
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn transpose4x4(v: &[I32X4]) -> [I32X4; 4] {
+    let t0 = _mm_unpacklo_epi32(v[0].0, v[1].0);
+    let t1 = _mm_unpacklo_epi32(v[2].0, v[3].0);
+    let t2 = _mm_unpackhi_epi32(v[0].0, v[1].0);
+    let t3 = _mm_unpackhi_epi32(v[2].0, v[3].0);
+    [
+      I32X4(_mm_unpacklo_epi64(t0, t1)),
+      I32X4(_mm_unpackhi_epi64(t0, t1)),
+      I32X4(_mm_unpacklo_epi64(t2, t3)),
+      I32X4(_mm_unpackhi_epi64(t2, t3))
+    ]
+  }
+
+  /// Same as `inv_txfm2d_add_rs`, running the 1D transforms on four rows,
+  /// then four columns, at a time. Works for all bit depths.
+  #[target_feature(enable = "sse2")]
+  pub(super) unsafe fn inv_txfm2d_add<T: Pixel>(
+    input: &[i32], output: &mut PlaneRegionMut<'_, T>, tx_type: TxType,
+    tx_size: TxSize, intermediate_shift: usize, bd: usize
+  ) {
+    let w = tx_size.width();
+    let h = tx_size.height();
+    // 64 point transforms only signal 32 coeffs
+    let coeff_w = w.min(32);
+    let coeff_h = h.min(32);
+    assert!(input.len() >= coeff_w * coeff_h);
+
+    let rect_type = get_rect_tx_log_ratio(w, h);
+    let tx_type_1d_col = VTX_TAB[tx_type as usize];
+    let tx_type_1d_row = HTX_TAB[tx_type as usize];
+    let ud_flip = tx_type_1d_col == TxType1D::FLIPADST;
+    let lr_flip = tx_type_1d_row == TxType1D::FLIPADST;
+
+    // Row outputs, 4 columns of a row per vector, grouped by columns
+    let mut tmp: AlignedArray<[I32X4; 64 * 64 / 4]> =
+      UninitializedAlignedArray();
+    let mut line_in: AlignedArray<[I32X4; 64]> = UninitializedAlignedArray();
+    let mut line_out: AlignedArray<[I32X4; 64]> = UninitializedAlignedArray();
+
+    // perform inv txfm on every row
+    let range = bd + 8;
+    let txfm_fn = get_inv_txfm_fn::<I32X4>(tx_type_1d_row, w.ilog() - 3);
+    for rg in 0..coeff_h / 4 {
+      let row_in = &mut line_in.array[..w];
+      for cg in 0..coeff_w / 4 {
+        let mut rows = [I32X4::default(); 4];
+        for (i, v) in rows.iter_mut().enumerate() {
+          let ptr = input[(rg * 4 + i) * coeff_w + cg * 4..].as_ptr();
+          v.0 = _mm_loadu_si128(ptr as *const _);
+        }
+        let t = transpose4x4(&rows);
+        row_in[cg * 4..cg * 4 + 4].copy_from_slice(&t);
+      }
+      for v in row_in[..coeff_w].iter_mut() {
+        let val = if rect_type.abs() == 1 {
+          round_shift(v.mul_const(INV_SQRT2), SQRT2_BITS)
+        } else {
+          *v
+        };
+        *v = clamp_value(val, range);
+      }
+      // For 64 point transforms, the missing coeffs are zero
+      for v in row_in[coeff_w..].iter_mut() {
+        *v = I32X4::default();
+      }
+      let row_out = &mut line_out.array[..w];
+      txfm_fn(row_in, row_out, range);
+      if lr_flip {
+        row_out.reverse();
+      }
+      for cg in 0..w / 4 {
+        let t = transpose4x4(&row_out[cg * 4..]);
+        tmp.array[cg * h + rg * 4..][..4].copy_from_slice(&t);
+      }
+    }
+    // The rows past the signaled coeffs of 64 point transforms are zero
+    for cg in 0..w / 4 {
+      for v in tmp.array[cg * h + coeff_h..cg * h + h].iter_mut() {
+        *v = I32X4::default();
+      }
+    }
+
+    // perform inv txfm on every col
+    let range = cmp::max(bd + 6, 16);
+    let txfm_fn = get_inv_txfm_fn::<I32X4>(tx_type_1d_col, h.ilog() - 3);
+    for cg in 0..w / 4 {
+      let col_in = &mut line_in.array[..h];
+      for (clamped, raw) in col_in.iter_mut().zip(&tmp.array[cg * h..]) {
+        *clamped = clamp_value(round_shift(*raw, intermediate_shift), range);
+      }
+      let col_out = &mut line_out.array[..h];
+      txfm_fn(col_in, col_out, range);
+      if ud_flip {
+        col_out.reverse();
+      }
+      for (v, row) in col_out.iter().zip(output.rows_iter_mut().take(h)) {
+        let mut res = [0i32; 4];
+        _mm_storeu_si128(res.as_mut_ptr() as *mut _, round_shift(*v, 4).0);
+        for (out, &r) in row[cg * 4..cg * 4 + 4].iter_mut().zip(res.iter()) {
+          let v: i32 = (*out).as_();
+          *out = T::cast_from(clamp(v + r, 0, (1 << bd) - 1));
+        }
+      }
+    }
+  }
+
+  #[cfg(test)]
+  mod test {
+    use super::*;
+    use rand::random;
+    use crate::frame::*;
+
+    #[test]
+    fn inv_txfm2d_add_matches_rust() {
+      if !is_x86_feature_detected!("sse2") {
+        return;
+      }
+      use crate::transform::TxSize::*;
+      let tx_sizes = [
+        TX_4X4, TX_8X8, TX_16X16, TX_32X32, TX_64X64, TX_4X8, TX_8X4,
+        TX_8X16, TX_16X8, TX_16X32, TX_32X16, TX_32X64, TX_64X32, TX_4X16,
+        TX_16X4, TX_8X32, TX_32X8, TX_16X64, TX_64X16
+      ];
+      let mut input = [0i32; 32 * 32];
+      for &bd in &[8, 10, 12] {
+        for &tx_size in tx_sizes.iter() {
+          let (w, h) = (tx_size.width(), tx_size.height());
+          for &tx_type in RAV1E_TX_TYPES.iter() {
+            // ADST is only defined up to 16 points, identity up to 32
+            let valid = |t: TxType1D, n: usize| match t {
+              TxType1D::DCT => true,
+              TxType1D::IDTX => n <= 32,
+              _ => n <= 16
+            };
+            if !valid(VTX_TAB[tx_type as usize], h)
+              || !valid(HTX_TAB[tx_type as usize], w)
+            {
+              continue;
+            }
+            for v in input.iter_mut() {
+              *v = (random::<i16>() >> (15 - bd)) as i32;
+            }
+            let mut expected = Plane::<u16>::new(w, h, 0, 0, 0, 0);
+            for row in expected.as_region_mut().rows_iter_mut().take(h) {
+              for v in row[..w].iter_mut() {
+                *v = random::<u16>() >> (16 - bd);
+              }
+            }
+            let mut actual = expected.clone();
+            for &intermediate_shift in &[0, 1, 2] {
+              native::inv_txfm2d_add_rs(
+                &input,
+                &mut expected.as_region_mut(),
+                tx_type,
+                tx_size,
+                intermediate_shift,
+                bd
+              );
+              unsafe {
+                inv_txfm2d_add(
+                  &input,
+                  &mut actual.as_region_mut(),
+                  tx_type,
+                  tx_size,
+                  intermediate_shift,
+                  bd
+                );
+              }
+              assert!(expected == actual);
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
 macro_rules! impl_iht_fns {
   ($(($W:expr, $H:expr)),+) => {
     $(
@@ -1789,3 +2187,40 @@ impl_iht_fns!(
   (4, 8),
   (4, 4)
 );
+
+/// `inverse_transform_add` without the SIMD kernels, for the benchmarks to
+/// compare them against
+#[cfg(feature = "bench")]
+pub fn inverse_transform_add_rs<T: Pixel>(
+  input: &[i32], output: &mut PlaneRegionMut<'_, T>, tx_size: TxSize,
+  tx_type: TxType, bit_depth: usize
+) {
+  macro_rules! intermediate_shift {
+    ($(($W:expr, $H:expr)),+) => {
+      paste::item! {
+        match (tx_size.width(), tx_size.height()) {
+          $(
+            ($W, $H) => {
+              <[<Block $W x $H>] as native::InvTxfm2D>::INTERMEDIATE_SHIFT
+            }
+          )*
+          _ => unreachable!()
+        }
+      }
+    }
+  }
+
+  let intermediate_shift = intermediate_shift!(
+    (64, 64), (64, 32), (32, 64), (16, 64), (64, 16), (32, 32), (32, 16),
+    (16, 32), (32, 8), (8, 32), (16, 16), (16, 8), (8, 16), (16, 4),
+    (4, 16), (8, 8), (8, 4), (4, 8), (4, 4)
+  );
+  native::inv_txfm2d_add_rs(
+    input,
+    output,
+    tx_type,
+    tx_size,
+    intermediate_shift,
+    bit_depth
+  );
+}
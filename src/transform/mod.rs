@@ -30,9 +30,18 @@ pub static RAV1E_TX_TYPES: &'static [TxType] = &[
   TxType::ADST_DCT,
   TxType::DCT_ADST,
   TxType::ADST_ADST,
+  TxType::FLIPADST_DCT,
+  TxType::DCT_FLIPADST,
+  TxType::FLIPADST_FLIPADST,
+  TxType::ADST_FLIPADST,
+  TxType::FLIPADST_ADST,
   TxType::IDTX,
   TxType::V_DCT,
-  TxType::H_DCT
+  TxType::H_DCT,
+  TxType::V_ADST,
+  TxType::H_ADST,
+  TxType::V_FLIPADST,
+  TxType::H_FLIPADST
 ];
 
 static SQRT2_BITS: usize = 12;
@@ -305,7 +314,7 @@ fn av1_round_shift_array_rs(arr: &mut [i32], size: usize, bit: i8) {
   }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum TxType1D {
   DCT,
   ADST,
@@ -313,22 +322,6 @@ enum TxType1D {
   IDTX
 }
 
-// Option can be removed when the table is completely filled
-fn get_1d_tx_types(tx_type: TxType) -> Option<(TxType1D, TxType1D)> {
-  match tx_type {
-    TxType::DCT_DCT => Some((TxType1D::DCT, TxType1D::DCT)),
-    TxType::ADST_DCT => Some((TxType1D::ADST, TxType1D::DCT)),
-    TxType::DCT_ADST => Some((TxType1D::DCT, TxType1D::ADST)),
-    TxType::ADST_ADST => Some((TxType1D::ADST, TxType1D::ADST)),
-    TxType::IDTX => Some((TxType1D::IDTX, TxType1D::IDTX)),
-    TxType::V_DCT => Some((TxType1D::DCT, TxType1D::IDTX)),
-    TxType::H_DCT => Some((TxType1D::IDTX, TxType1D::DCT)),
-    TxType::V_ADST => Some((TxType1D::ADST, TxType1D::IDTX)),
-    TxType::H_ADST => Some((TxType1D::IDTX, TxType1D::ADST)),
-    _ => None
-  }
-}
-
 const VTX_TAB: [TxType1D; TX_TYPES] = [
   TxType1D::DCT,
   TxType1D::ADST,
@@ -498,6 +491,11 @@ mod test {
       (TX_4X4, H_DCT, 0),
       (TX_4X4, V_ADST, 0),
       (TX_4X4, H_ADST, 0),
+      (TX_4X4, FLIPADST_DCT, 0),
+      (TX_4X4, DCT_FLIPADST, 0),
+      (TX_4X4, FLIPADST_FLIPADST, 0),
+      (TX_4X4, V_FLIPADST, 0),
+      (TX_4X4, H_FLIPADST, 0),
       (TX_8X8, DCT_DCT, 1),
       (TX_8X8, ADST_DCT, 1),
       (TX_8X8, DCT_ADST, 1),
@@ -507,6 +505,8 @@ mod test {
       (TX_8X8, H_DCT, 0),
       (TX_8X8, V_ADST, 0),
       (TX_8X8, H_ADST, 1),
+      (TX_8X8, ADST_FLIPADST, 1),
+      (TX_8X8, FLIPADST_ADST, 1),
       (TX_16X16, DCT_DCT, 1),
       (TX_16X16, ADST_DCT, 1),
       (TX_16X16, DCT_ADST, 1),
@@ -514,6 +514,7 @@ mod test {
       (TX_16X16, IDTX, 0),
       (TX_16X16, V_DCT, 1),
       (TX_16X16, H_DCT, 1),
+      (TX_16X16, FLIPADST_FLIPADST, 1),
       // 32x tranforms only use DCT_DCT and IDTX
       (TX_32X32, DCT_DCT, 2),
       (TX_32X32, IDTX, 0),